@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pipeline::cdx::try_parse_cdx_line;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = try_parse_cdx_line(data);
+});
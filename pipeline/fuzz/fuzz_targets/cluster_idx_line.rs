@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pipeline::cdx::try_parse_cluster_idx;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = try_parse_cluster_idx(data);
+});
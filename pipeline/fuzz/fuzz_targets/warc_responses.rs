@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pipeline::warc_response::parse_warc_responses;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = parse_warc_responses(data);
+});
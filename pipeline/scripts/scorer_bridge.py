@@ -0,0 +1,20 @@
+"""Example bridge for the worker's `--scorer-command`.
+
+Reads one JSON object `{"id": ..., "texts": [...]}` per line from stdin and answers
+each with `{"id": ..., "scores": [...]}` on stdout, one score per text in the same
+order. Replace `score` with a real model; batching the texts is what lets a GPU-backed
+classifier keep up.
+"""
+import json
+import sys
+
+
+def score(texts):
+    # Placeholder model: the length of the text.
+    return [float(len(text)) for text in texts]
+
+
+for line in sys.stdin:
+    request = json.loads(line)
+    sys.stdout.write(json.dumps({"id": request["id"], "scores": score(request["texts"])}) + "\n")
+    sys.stdout.flush()
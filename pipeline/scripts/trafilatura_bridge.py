@@ -0,0 +1,17 @@
+"""Bridge for the worker's `--extractor subprocess` mode.
+
+Reads one JSON object `{"html": ...}` per line from stdin and answers each with
+`{"text": ...}` on stdout, where `text` is null if trafilatura found no content.
+"""
+import json
+import sys
+
+from trafilatura import extract
+
+for line in sys.stdin:
+    html = json.loads(line)["html"]
+    text = extract(html, include_comments=False, include_tables=False, deduplicate=True)
+    if text is not None and not isinstance(text, str):
+        text = None
+    sys.stdout.write(json.dumps({"text": text}) + "\n")
+    sys.stdout.flush()
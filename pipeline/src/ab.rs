@@ -0,0 +1,124 @@
+use std::{collections::HashSet, path::Path};
+
+use serde::Serialize;
+
+use crate::output::{OutputFile, WriteOptions};
+
+/// Side-by-side result of running two extractors on the same document.
+#[derive(Debug, Serialize)]
+pub struct AbComparison {
+    pub id: String,
+    pub url: String,
+    pub a_extractor: String,
+    pub b_extractor: String,
+    pub a_text: Option<String>,
+    pub b_text: Option<String>,
+    /// Length of B's text minus length of A's text, in characters.
+    pub length_delta: i64,
+    /// Jaccard similarity of the whitespace-separated token sets, 1.0 if both are empty.
+    pub token_overlap: f64,
+}
+
+impl AbComparison {
+    pub fn new(
+        id: &str,
+        url: &str,
+        (a_extractor, a_text): (&str, Option<String>),
+        (b_extractor, b_text): (&str, Option<String>),
+    ) -> Self {
+        let a = a_text.as_deref().unwrap_or_default();
+        let b = b_text.as_deref().unwrap_or_default();
+        Self {
+            id: id.to_string(),
+            url: url.to_string(),
+            a_extractor: a_extractor.to_string(),
+            b_extractor: b_extractor.to_string(),
+            length_delta: b.chars().count() as i64 - a.chars().count() as i64,
+            token_overlap: token_overlap(a, b),
+            a_text,
+            b_text,
+        }
+    }
+}
+
+pub fn token_overlap(a: &str, b: &str) -> f64 {
+    let a = a.split_whitespace().collect::<HashSet<_>>();
+    let b = b.split_whitespace().collect::<HashSet<_>>();
+    let union = a.union(&b).count();
+    if union == 0 {
+        return 1.0;
+    }
+    a.intersection(&b).count() as f64 / union as f64
+}
+
+/// Samples documents by their ID, so the same documents are compared on every run.
+pub fn is_sampled(document_id: &str, sample_rate: f64) -> bool {
+    let bucket = document_id
+        .get(..8)
+        .and_then(|prefix| u32::from_str_radix(prefix, 16).ok())
+        .unwrap_or_default();
+    (bucket as f64 + 1.0) <= sample_rate * (u32::MAX as f64 + 1.0)
+}
+
+/// Writes comparisons as JSON lines and keeps running statistics over them.
+pub struct AbWriter {
+    file: OutputFile,
+    num_compared: usize,
+    sum_length_delta: i64,
+    sum_token_overlap: f64,
+}
+
+impl AbWriter {
+    pub fn create(filename: &str, options: &WriteOptions) -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            file: OutputFile::append(Path::new(filename), options)?,
+            num_compared: 0,
+            sum_length_delta: 0,
+            sum_token_overlap: 0.0,
+        })
+    }
+
+    pub fn write(&mut self, comparison: &AbComparison) -> Result<(), anyhow::Error> {
+        self.file.write_line(comparison)?;
+        self.num_compared += 1;
+        self.sum_length_delta += comparison.length_delta;
+        self.sum_token_overlap += comparison.token_overlap;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), anyhow::Error> {
+        self.file.flush()?;
+        if self.num_compared > 0 {
+            tracing::info!(
+                "A/B extraction: {} documents compared, mean length delta {:.1}, mean token overlap {:.3}",
+                self.num_compared,
+                self.sum_length_delta as f64 / self.num_compared as f64,
+                self.sum_token_overlap / self.num_compared as f64
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ab::{is_sampled, token_overlap, AbComparison};
+
+    #[test]
+    fn compares_texts() {
+        assert_eq!(token_overlap("a b c", "a b c"), 1.0);
+        assert_eq!(token_overlap("a b", "c d"), 0.0);
+        assert_eq!(token_overlap("a b c", "b c d"), 0.5);
+        let comparison =
+            AbComparison::new("id", "url", ("a", Some("one two".to_string())), ("b", None));
+        assert_eq!(comparison.length_delta, -7);
+        assert_eq!(comparison.token_overlap, 0.0);
+    }
+
+    #[test]
+    fn samples_by_id() {
+        assert!(is_sampled("00000000-0000-5000-8000-000000000000", 0.01));
+        assert!(!is_sampled("ffffffff-0000-5000-8000-000000000000", 0.01));
+        assert!(is_sampled("ffffffff-0000-5000-8000-000000000000", 1.0));
+    }
+}
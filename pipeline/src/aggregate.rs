@@ -0,0 +1,91 @@
+use std::{collections::HashMap, path::Path};
+
+use serde::Serialize;
+
+use crate::output::{output_files, read_documents, Document};
+
+/// Per-domain summary of an output corpus.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct DomainRollup {
+    pub domain: String,
+    pub num_documents: usize,
+    pub total_tokens: usize,
+    pub mean_quality_score: f64,
+}
+
+#[derive(Default)]
+pub struct DomainAggregator {
+    rollups: HashMap<String, DomainRollup>,
+}
+
+impl DomainAggregator {
+    pub fn add(&mut self, document: &Document) {
+        let domain = document.domain().unwrap_or_default();
+        let rollup = self
+            .rollups
+            .entry(domain.clone())
+            .or_insert_with(|| DomainRollup {
+                domain,
+                ..Default::default()
+            });
+        // Running mean, so we never have to keep all scores around.
+        rollup.num_documents += 1;
+        rollup.total_tokens += document.token_count;
+        rollup.mean_quality_score +=
+            (document.quality_score - rollup.mean_quality_score) / rollup.num_documents as f64;
+    }
+
+    /// Rollups ordered by number of documents, largest domains first.
+    pub fn finish(self) -> Vec<DomainRollup> {
+        let mut rollups = self.rollups.into_values().collect::<Vec<_>>();
+        rollups.sort_by(|a, b| {
+            b.num_documents
+                .cmp(&a.num_documents)
+                .then_with(|| a.domain.cmp(&b.domain))
+        });
+        rollups
+    }
+}
+
+/// Aggregates all output files below `output_dir`.
+pub fn aggregate_by_domain(output_dir: &Path) -> Result<Vec<DomainRollup>, anyhow::Error> {
+    let mut aggregator = DomainAggregator::default();
+    for path in output_files(output_dir)? {
+        for document in read_documents(&path)? {
+            aggregator.add(&document?);
+        }
+    }
+    Ok(aggregator.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{aggregate::DomainAggregator, output::Document};
+
+    fn document(url: &str, token_count: usize, quality_score: f64) -> Document {
+        Document {
+            id: "id".to_string(),
+            url: url.to_string(),
+            timestamp: "20240722120756".to_string(),
+            language: "eng".to_string(),
+            token_count,
+            quality_score,
+            text: String::new(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn rolls_up_per_domain() {
+        let mut aggregator = DomainAggregator::default();
+        aggregator.add(&document("https://a.com/1", 10, 0.2));
+        aggregator.add(&document("https://a.com/2", 30, 0.6));
+        aggregator.add(&document("https://b.com/", 5, 1.0));
+        let rollups = aggregator.finish();
+        assert_eq!(rollups.len(), 2);
+        assert_eq!(rollups[0].domain, "a.com");
+        assert_eq!(rollups[0].num_documents, 2);
+        assert_eq!(rollups[0].total_tokens, 40);
+        assert!((rollups[0].mean_quality_score - 0.4).abs() < 1e-9);
+    }
+}
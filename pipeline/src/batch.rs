@@ -0,0 +1,529 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use futures_util::StreamExt;
+use uuid::Uuid;
+
+use crate::cdx::{download_and_unzip_lines, CdxEntry, Utf8Policy, CC_DATA_URL};
+
+/// Identifies a batch by its payload, so a redelivered message has the same ID as the
+/// original delivery.
+pub fn batch_id(payload: &[u8]) -> String {
+    Uuid::new_v5(&Uuid::NAMESPACE_OID, payload).to_string()
+}
+
+/// The content type of protobuf batches.
+pub const PROTOBUF_CONTENT_TYPE: &str = "application/x-protobuf";
+
+/// How a batch of CDX entries is serialized into a message. The encoding travels as the
+/// message's content type, so workers can decode batches from any batcher. `docs head`
+/// writes documents in the same encodings.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum BatchEncoding {
+    /// Readable with any RabbitMQ tool, handy for debugging.
+    #[default]
+    Json,
+    /// The `Batch` message of `proto/pipeline.proto`, about half the size of JSON.
+    #[cfg(feature = "protobuf")]
+    Protobuf,
+}
+
+impl BatchEncoding {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            BatchEncoding::Json => "application/json",
+            #[cfg(feature = "protobuf")]
+            BatchEncoding::Protobuf => PROTOBUF_CONTENT_TYPE,
+        }
+    }
+
+    /// Messages without a content type come from batchers that only knew JSON.
+    pub fn from_content_type(content_type: Option<&str>) -> Result<Self, anyhow::Error> {
+        match content_type {
+            None | Some("application/json") => Ok(BatchEncoding::Json),
+            #[cfg(feature = "protobuf")]
+            Some(PROTOBUF_CONTENT_TYPE) => Ok(BatchEncoding::Protobuf),
+            Some(other) => anyhow::bail!("Unsupported batch content type {other}"),
+        }
+    }
+
+    pub fn encode(self, batch: &[CdxEntry]) -> Result<Vec<u8>, anyhow::Error> {
+        match self {
+            BatchEncoding::Json => Ok(serde_json::to_vec(batch)?),
+            #[cfg(feature = "protobuf")]
+            BatchEncoding::Protobuf => {
+                use prost::Message;
+                let batch = crate::proto::Batch {
+                    entries: batch.iter().map(Into::into).collect(),
+                };
+                Ok(batch.encode_to_vec())
+            }
+        }
+    }
+
+    pub fn decode(self, payload: &[u8]) -> Result<Vec<CdxEntry>, anyhow::Error> {
+        match self {
+            BatchEncoding::Json => Ok(serde_json::from_slice(payload)?),
+            #[cfg(feature = "protobuf")]
+            BatchEncoding::Protobuf => {
+                use prost::Message;
+                crate::proto::Batch::decode(payload)?
+                    .entries
+                    .into_iter()
+                    .map(TryInto::try_into)
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Where a batch came from: its position among the batches the batcher cut from one
+/// CDX shard. Unlike the batch ID it does not depend on the payload, so it names the
+/// same batch across batcher runs as long as the filters stay the same.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BatchKey {
+    pub crawl: String,
+    pub shard: String,
+    pub batch_index: usize,
+}
+
+impl BatchKey {
+    /// Only the `batch_index` header, `crawl` and `shard` are sent anyway.
+    pub fn to_header(&self) -> (String, String) {
+        ("batch_index".to_string(), self.batch_index.to_string())
+    }
+
+    /// `None` for batches from batchers that did not stamp them yet.
+    pub fn from_headers(header: impl Fn(&str) -> Option<String>) -> Option<Self> {
+        Some(Self {
+            crawl: header("crawl")?,
+            shard: header("shard")?,
+            batch_index: header("batch_index")?.parse().ok()?,
+        })
+    }
+}
+
+/// Summary of a batch that the batcher attaches as message headers and the worker checks
+/// on receipt, to catch payloads that were truncated or corrupted on the way.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchManifest {
+    pub num_entries: usize,
+    pub min_offset: u64,
+    pub max_offset: u64,
+    /// XXH3-64 of the payload as 16 hex digits.
+    pub payload_xxh3: String,
+}
+
+pub const MANIFEST_HEADERS: [&str; 4] = [
+    "manifest_entries",
+    "manifest_min_offset",
+    "manifest_max_offset",
+    "manifest_xxh3",
+];
+
+fn payload_xxh3(payload: &[u8]) -> String {
+    format!("{:016x}", xxhash_rust::xxh3::xxh3_64(payload))
+}
+
+impl BatchManifest {
+    pub fn new(batch: &[CdxEntry], payload: &[u8]) -> Self {
+        let offsets = batch.iter().map(|entry| entry.metadata.offset);
+        Self {
+            num_entries: batch.len(),
+            min_offset: offsets.clone().min().unwrap_or_default(),
+            max_offset: offsets.max().unwrap_or_default(),
+            payload_xxh3: payload_xxh3(payload),
+        }
+    }
+
+    pub fn to_headers(&self) -> Vec<(String, String)> {
+        let values = [
+            self.num_entries.to_string(),
+            self.min_offset.to_string(),
+            self.max_offset.to_string(),
+            self.payload_xxh3.clone(),
+        ];
+        MANIFEST_HEADERS
+            .iter()
+            .map(|key| key.to_string())
+            .zip(values)
+            .collect()
+    }
+
+    /// Reads the manifest back from message headers, `None` if the batch has none, e.g.
+    /// because an older batcher published it.
+    pub fn from_headers(
+        header: impl Fn(&str) -> Option<String>,
+    ) -> Result<Option<Self>, anyhow::Error> {
+        let [Some(num_entries), Some(min_offset), Some(max_offset), Some(payload_xxh3)] =
+            MANIFEST_HEADERS.map(header)
+        else {
+            return Ok(None);
+        };
+        Ok(Some(Self {
+            num_entries: num_entries.parse()?,
+            min_offset: min_offset.parse()?,
+            max_offset: max_offset.parse()?,
+            payload_xxh3,
+        }))
+    }
+
+    /// Decodes `payload` after checking that it is what the batcher sent.
+    pub fn verify(
+        &self,
+        payload: &[u8],
+        encoding: BatchEncoding,
+    ) -> Result<Vec<CdxEntry>, anyhow::Error> {
+        let received_xxh3 = payload_xxh3(payload);
+        if received_xxh3 != self.payload_xxh3 {
+            anyhow::bail!(
+                "Batch payload hashes to {} instead of {}",
+                received_xxh3,
+                self.payload_xxh3
+            );
+        }
+        let batch = encoding.decode(payload)?;
+        let received = BatchManifest::new(&batch, payload);
+        if received != *self {
+            anyhow::bail!(
+                "Batch does not match its manifest, expected {self:?} but got {received:?}"
+            );
+        }
+        Ok(batch)
+    }
+}
+
+/// Where in the CDX index the entries of a batch come from: the gzip member of `cdx_path`
+/// at `offset` and `length` given by the cluster.idx, and the numbers of its lines that
+/// ended up in the batch. Refetching the member and reading these lines replays the batch
+/// exactly, whatever the filters, the dedup or the sampling left out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchSource {
+    /// Path within the Common Crawl bucket, e.g. `cc-index/collections/.../cdx-00000.gz`.
+    pub cdx_path: String,
+    pub offset: usize,
+    pub length: usize,
+    /// Line numbers within the member, counting from 1, as sorted inclusive ranges.
+    pub lines: Vec<(usize, usize)>,
+}
+
+const SOURCE_HEADERS: [&str; 3] = ["source_cdx_path", "source_offset", "source_length"];
+
+/// Sorted, inclusive ranges of `numbers` in which consecutive numbers are merged.
+pub fn line_ranges(numbers: &[usize]) -> Vec<(usize, usize)> {
+    let mut numbers = numbers.to_vec();
+    numbers.sort_unstable();
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for number in numbers {
+        match ranges.last_mut() {
+            Some((_, last)) if number <= *last + 1 => *last = number.max(*last),
+            _ => ranges.push((number, number)),
+        }
+    }
+    ranges
+}
+
+/// Line ranges as in the `source_lines` header, e.g. `12-15,17,20-511`.
+pub fn parse_line_ranges(s: &str) -> Result<Vec<(usize, usize)>, anyhow::Error> {
+    s.split(',')
+        .map(|range| match range.split_once('-') {
+            Some((first, last)) => Ok((first.trim().parse()?, last.trim().parse()?)),
+            None => {
+                let line = range.trim().parse()?;
+                Ok((line, line))
+            }
+        })
+        .collect()
+}
+
+fn format_line_ranges(ranges: &[(usize, usize)]) -> String {
+    ranges
+        .iter()
+        .map(|(first, last)| match first == last {
+            true => first.to_string(),
+            false => format!("{first}-{last}"),
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+impl BatchSource {
+    /// The lines go into `source_lines`. `source_first_line` and `source_last_line` are
+    /// written as well, for readers of batchers that only knew the outer range.
+    pub fn to_headers(&self) -> Vec<(String, String)> {
+        let values = [
+            self.cdx_path.clone(),
+            self.offset.to_string(),
+            self.length.to_string(),
+        ];
+        let mut headers = SOURCE_HEADERS
+            .iter()
+            .map(|key| key.to_string())
+            .zip(values)
+            .collect::<Vec<_>>();
+        if let (Some((first, _)), Some((_, last))) = (self.lines.first(), self.lines.last()) {
+            headers.push(("source_first_line".to_string(), first.to_string()));
+            headers.push(("source_last_line".to_string(), last.to_string()));
+        }
+        headers.push(("source_lines".to_string(), format_line_ranges(&self.lines)));
+        headers
+    }
+
+    /// Reads the source back from message headers, `None` if the batch has none, e.g.
+    /// because it was built from a URL list. Batches of older batchers without
+    /// `source_lines` replay their outer range.
+    pub fn from_headers(
+        header: impl Fn(&str) -> Option<String>,
+    ) -> Result<Option<Self>, anyhow::Error> {
+        let [Some(cdx_path), Some(offset), Some(length)] = SOURCE_HEADERS.map(&header) else {
+            return Ok(None);
+        };
+        let lines = match (
+            header("source_lines"),
+            header("source_first_line"),
+            header("source_last_line"),
+        ) {
+            (Some(lines), _, _) => parse_line_ranges(&lines)?,
+            (None, Some(first), Some(last)) => vec![(first.parse()?, last.parse()?)],
+            _ => return Ok(None),
+        };
+        Ok(Some(Self {
+            cdx_path,
+            offset: offset.parse()?,
+            length: length.parse()?,
+            lines,
+        }))
+    }
+
+    /// Fetches the source member again and returns the lines of the batch, in order.
+    pub async fn replay(&self, client: &reqwest::Client) -> Result<Vec<String>, anyhow::Error> {
+        let Some(&(_, last_line)) = self.lines.last() else {
+            return Ok(Vec::new());
+        };
+        let url = format!("{CC_DATA_URL}/{}", self.cdx_path);
+        let lines =
+            download_and_unzip_lines(client, &url, self.offset, self.length, Utf8Policy::Lossy)
+                .await?;
+        let mut lines = std::pin::pin!(lines);
+        let mut replayed = Vec::new();
+        while let Some(line) = lines.next().await {
+            let (number, line) = line?;
+            if number > last_line {
+                break;
+            }
+            if self
+                .lines
+                .iter()
+                .any(|(first, last)| (*first..=*last).contains(&number))
+            {
+                replayed.push(line);
+            }
+        }
+        Ok(replayed)
+    }
+}
+
+/// Remembers the IDs of batches that were fully processed within the last `ttl`, so
+/// a redelivery of a batch whose ack got lost can be acked again without reprocessing.
+/// With the `redis` feature, the window can be shared by all workers through Redis.
+pub struct RecentBatches {
+    ttl: Duration,
+    processed: HashMap<String, Instant>,
+    #[cfg(feature = "redis")]
+    redis: Option<redis::aio::MultiplexedConnection>,
+}
+
+impl RecentBatches {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            processed: HashMap::new(),
+            #[cfg(feature = "redis")]
+            redis: None,
+        }
+    }
+
+    #[cfg(feature = "redis")]
+    pub async fn with_redis(mut self, redis_url: &str) -> Result<Self, anyhow::Error> {
+        let client = redis::Client::open(redis_url)?;
+        self.redis = Some(client.get_multiplexed_async_connection().await?);
+        Ok(self)
+    }
+
+    pub async fn contains(&mut self, batch_id: &str) -> Result<bool, anyhow::Error> {
+        if self
+            .processed
+            .get(batch_id)
+            .is_some_and(|at| at.elapsed() < self.ttl)
+        {
+            return Ok(true);
+        }
+        #[cfg(feature = "redis")]
+        if let Some(redis) = self.redis.as_mut() {
+            return Ok(redis::cmd("EXISTS")
+                .arg(redis_key(batch_id))
+                .query_async::<bool>(redis)
+                .await?);
+        }
+        Ok(false)
+    }
+
+    pub async fn insert(&mut self, batch_id: &str) -> Result<(), anyhow::Error> {
+        let ttl = self.ttl;
+        self.processed.retain(|_, at| at.elapsed() < ttl);
+        self.processed.insert(batch_id.to_string(), Instant::now());
+        #[cfg(feature = "redis")]
+        if let Some(redis) = self.redis.as_mut() {
+            redis::cmd("SET")
+                .arg(redis_key(batch_id))
+                .arg(1)
+                .arg("EX")
+                .arg(ttl.as_secs().max(1))
+                .query_async::<()>(redis)
+                .await?;
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.processed.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.processed.is_empty()
+    }
+}
+
+#[cfg(feature = "redis")]
+fn redis_key(batch_id: &str) -> String {
+    format!("pipeline:processed-batch:{batch_id}")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        batch::{
+            batch_id, line_ranges, BatchEncoding, BatchKey, BatchManifest, BatchSource,
+            RecentBatches,
+        },
+        cdx::parse_cdx_line,
+    };
+
+    #[test]
+    fn verifies_batch_manifests() {
+        let batch = vec![parse_cdx_line(
+            r#"0,100,59,139)/ 20240723213521 {"url": "https://139.59.100.0/", "status": "200", "length": "16650", "offset": "64016172", "filename": "crawl-data/CC-MAIN-2024-30/segments/1720763518115.82/warc/CC-MAIN-20240723194208-20240723224208-00279.warc.gz"}"#,
+        ).unwrap()];
+        let payload = serde_json::to_vec(&batch).unwrap();
+        let manifest = BatchManifest::new(&batch, &payload);
+        assert_eq!(manifest.min_offset, 64016172);
+
+        let headers = manifest.to_headers();
+        let header = |key: &str| {
+            headers
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.clone())
+        };
+        let received = BatchManifest::from_headers(header).unwrap().unwrap();
+        let json = BatchEncoding::Json;
+        assert_eq!(received.verify(&payload, json).unwrap().len(), 1);
+        assert!(received
+            .verify(&payload[..payload.len() - 1], json)
+            .is_err());
+        let mut wrong_count = received.clone();
+        wrong_count.num_entries = 2;
+        assert!(wrong_count.verify(&payload, json).is_err());
+        assert_eq!(BatchManifest::from_headers(|_| None).unwrap(), None);
+
+        let key = BatchKey {
+            crawl: "CC-MAIN-2024-30".to_string(),
+            shard: "cdx-00000.gz".to_string(),
+            batch_index: 7,
+        };
+        let headers = [
+            ("crawl".to_string(), key.crawl.clone()),
+            ("shard".to_string(), key.shard.clone()),
+            key.to_header(),
+        ];
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.clone())
+        };
+        assert_eq!(BatchKey::from_headers(header), Some(key));
+        assert_eq!(BatchKey::from_headers(|_| None), None);
+    }
+
+    #[test]
+    fn round_trips_batch_sources() {
+        let source = BatchSource {
+            cdx_path: "cc-index/collections/CC-MAIN-2024-30/indexes/cdx-00000.gz".to_string(),
+            offset: 186839,
+            length: 180000,
+            lines: line_ranges(&[17, 12, 13, 14, 15, 20, 511]),
+        };
+        assert_eq!(source.lines, [(12, 15), (17, 17), (20, 20), (511, 511)]);
+        let headers = source.to_headers();
+        let header = |key: &str| {
+            headers
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.clone())
+        };
+        assert_eq!(header("source_lines").unwrap(), "12-15,17,20,511");
+        assert_eq!(header("source_first_line").unwrap(), "12");
+        assert_eq!(BatchSource::from_headers(header).unwrap(), Some(source));
+        assert_eq!(BatchSource::from_headers(|_| None).unwrap(), None);
+        assert!(BatchSource::from_headers(|_| Some("x".to_string())).is_err());
+
+        // Batchers before `source_lines` only sent the outer range.
+        let old = |key: &str| {
+            headers
+                .iter()
+                .find(|(k, _)| k == key && k != "source_lines")
+                .map(|(_, v)| v.clone())
+        };
+        assert_eq!(
+            BatchSource::from_headers(old).unwrap().unwrap().lines,
+            [(12, 511)]
+        );
+    }
+
+    #[cfg(feature = "protobuf")]
+    #[test]
+    fn round_trips_protobuf_batches() {
+        let batch = vec![parse_cdx_line(
+            r#"0,100,59,139)/ 20240723213521 {"url": "https://139.59.100.0/", "mime": "text/html", "status": "200", "length": "16650", "offset": "64016172", "filename": "crawl-data/CC-MAIN-2024-30/segments/1720763518115.82/warc/CC-MAIN-20240723194208-20240723224208-00279.warc.gz", "languages": "ind,eng"}"#,
+        ).unwrap()];
+        let encoding = BatchEncoding::from_content_type(Some("application/x-protobuf")).unwrap();
+        let payload = encoding.encode(&batch).unwrap();
+        assert!(payload.len() < BatchEncoding::Json.encode(&batch).unwrap().len());
+        let decoded = encoding.decode(&payload).unwrap();
+        assert_eq!(
+            serde_json::to_value(&decoded).unwrap(),
+            serde_json::to_value(&batch).unwrap()
+        );
+        assert!(BatchEncoding::from_content_type(Some("text/plain")).is_err());
+    }
+
+    #[tokio::test]
+    async fn remembers_processed_batches_within_ttl() {
+        let id = batch_id(b"[]");
+        assert_eq!(id, batch_id(b"[]"));
+
+        let mut recent = RecentBatches::new(Duration::from_secs(60));
+        assert!(!recent.contains(&id).await.unwrap());
+        recent.insert(&id).await.unwrap();
+        assert!(recent.contains(&id).await.unwrap());
+
+        let mut expired = RecentBatches::new(Duration::ZERO);
+        expired.insert(&id).await.unwrap();
+        assert!(!expired.contains(&id).await.unwrap());
+    }
+}
@@ -1,12 +1,53 @@
-use anyhow::Context;
 use clap::Parser;
-use lapin::{options::BasicPublishOptions, BasicProperties};
+use futures_util::{stream, Stream, StreamExt};
+#[cfg(feature = "sqs")]
+use pipeline::sqs::SqsClient;
 use pipeline::{
-    cdx::{download_and_unzip, CdxEntry},
-    rabbitmq::{rabbitmq_channel_with_queue, rabbitmq_connection, BATCH_SIZE, CC_QUEUE_NAME},
+    batch::{line_ranges, BatchEncoding, BatchKey, BatchManifest, BatchSource},
+    budget::{parse_duration, RunLimits},
+    cdx::{
+        cdx_chunk_path, cdx_chunk_url, download_and_unzip_lines, parse_cdx_line_or_skip,
+        parse_cluster_idx, retry_delay, CdxEntry, ClusterIdxEntry, Utf8Policy, CDX_DECODING,
+    },
+    checkpoint::{Checkpoint, CheckpointWriter},
+    config_check::ConfigCheck,
+    digest_dedup::DigestDedup,
+    dlq,
+    feedback::{DomainSampler, FeedbackSummary, ADAPTIVE_SAMPLING},
+    filter::{
+        CdxFilter, LanguageSelection, MimeTypes, StatusCodes, StatusExclusions, SurtRules,
+        UrlFilter,
+    },
+    handoff::{Handoff, OverflowPolicy, BATCHER_HANDOFF},
+    histogram::PAYLOAD_SIZES,
+    http_client::{build_http_client, HttpOptions},
+    index_api::{crawl_range, resolve_redirect, IndexApiClient, Politeness, CC_INDEX_API_URL},
+    metrics::PIPELINE_METRICS,
+    queue::{QueueBackend, QueueConsumer, QueueMessage, QueueOptions, QueueProducer},
+    rabbitmq::{
+        domain_hash_bucket, parse_header, rabbitmq_bind_queue_by_headers, rabbitmq_channel,
+        rabbitmq_channel_with_queue, rabbitmq_connection, rabbitmq_consumer,
+        rabbitmq_declare_headers_exchange, wait_while_blocked, RabbitConsumer, RabbitProducer,
+        ReliablePublisher, BATCH_SIZE, BROKER_FLOW_CONTROL, CC_FEEDBACK_QUEUE_NAME,
+        CC_PRIORITY_QUEUE_NAME, CC_QUEUE_NAME,
+    },
+    resources,
+    robots::is_robotstxt_capture,
+    spool::Spool,
+    state_store::{StateStore, StateStoreOptions},
     tracing_and_metrics::{run_metrics_server, setup_tracing},
+    traffic::TrafficFlusher,
+    url_score::{batches_by_score, DomainRankScorer, UrlScorer},
 };
-use std::fs;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+const CRAWL: &str = "CC-MAIN-2024-30";
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -14,126 +55,1042 @@ struct Args {
     #[arg(short, long, default_value = "cluster.idx")]
     cluster_idx_filename: String,
 
+    /// The crawl the cluster.idx belongs to, e.g. `CC-MAIN-2024-18`.
+    #[arg(long, default_value = CRAWL)]
+    crawl: String,
+
+    /// Entries per published batch.
+    #[arg(long, default_value_t = BATCH_SIZE)]
+    batch_size: usize,
+
+    #[arg(long, default_value = CC_QUEUE_NAME)]
+    queue_name: String,
+
+    /// Only enqueue entries whose CDX languages include one of these ISO 639-3 codes,
+    /// e.g. `eng,deu,fra`, or `any` to not filter by language.
+    #[arg(long, alias = "language", default_value_t = CdxFilter::default().languages)]
+    languages: LanguageSelection,
+
+    /// Require the first, i.e. main, CDX language to be one of `--languages`.
+    #[arg(long)]
+    primary_language_only: bool,
+
+    /// Only enqueue entries with these HTTP status codes, a comma separated list of codes
+    /// and ranges like `200-299`. Redirects followed with `--follow-redirects` are kept too.
+    #[arg(long, default_value_t = StatusCodes::default())]
+    status_codes: StatusCodes,
+
+    /// Only enqueue entries of these MIME types, a comma separated list like
+    /// `text/html,text/*`, or `*` for any. It is checked against the type Common Crawl
+    /// detected and the declared one where there is none.
+    #[arg(long, default_value_t = MimeTypes::html())]
+    mime_types: MimeTypes,
+
+    /// Leave out entries of these MIME types, even if `--mime-types` selects them.
+    #[arg(long)]
+    exclude_mime_types: Option<MimeTypes>,
+
+    /// What to do with CDX lines that are not valid UTF-8.
+    #[arg(long, value_enum, default_value_t = Utf8Policy::default())]
+    utf8_policy: Utf8Policy,
+
+    /// Stop after this many CDX chunks, all chunks of all CDX files in the cluster.idx
+    /// by default.
     #[arg(short, long)]
     num_cdx_chunks_to_process: Option<usize>,
+
+    /// How many CDX chunks are downloaded at the same time. Batches are still enqueued
+    /// in cluster.idx order. One per CPU of the container by default, between 2 and 16.
+    #[arg(long, default_value_t = resources::limits().concurrent_downloads())]
+    max_concurrent_downloads: usize,
+
+    /// Attempts per CDX chunk and index API query before giving up, retrying throttling
+    /// and server errors with the backoff of `--retry-base-delay-ms`.
+    #[arg(long, default_value_t = 5)]
+    max_fetch_attempts: usize,
+
+    /// Publishes of a batch before giving up, when RabbitMQ nacks it or does not confirm
+    /// it within `--confirm-timeout-secs`.
+    #[arg(long, default_value_t = 5)]
+    max_publish_attempts: usize,
+
+    #[arg(long, default_value_t = 20)]
+    confirm_timeout_secs: u64,
+
+    /// Log the progress through the cluster.idx at most this often.
+    #[arg(long, default_value_t = 10)]
+    progress_interval_secs: u64,
+
+    /// Where the batcher keeps track of the chunks and batches of the cluster.idx it has
+    /// published, rewritten after every batch. With `--state-store` it is the name of the
+    /// checkpoint in the store.
+    #[arg(long, default_value = "batcher_checkpoint.json")]
+    checkpoint_filename: String,
+
+    /// Continue from `--checkpoint-filename` after a crash instead of starting over,
+    /// without downloading the chunks or publishing the batches it has as done.
+    #[arg(long, conflicts_with = "url_list")]
+    resume: bool,
+
+    /// Enqueue the entries of each CDX chunk by the popularity of their domain, read from
+    /// this file of domains, most popular first, or of `rank,domain` lines like the Tranco
+    /// list. Without it entries stay in SURT order.
+    #[arg(long)]
+    domain_ranks: Option<String>,
+
+    /// Only enqueue entries of the domains in this file, one per line like `example.com`
+    /// or `edu`. A domain also covers its subdomains. Can be given multiple times.
+    #[arg(long = "allow-domains")]
+    allow_domains: Vec<String>,
+
+    /// Only enqueue entries whose SURT URL matches one of the regexes in this file, one
+    /// per line. Combined with `--allow-domains`, matching either is enough.
+    #[arg(long = "allow-surt-regexes")]
+    allow_surt_regexes: Vec<String>,
+
+    /// Leave out the entries of the domains in this file, even if they are allowed.
+    #[arg(long = "block-domains")]
+    block_domains: Vec<String>,
+
+    /// Leave out the entries whose SURT URL matches one of the regexes in this file.
+    #[arg(long = "block-surt-regexes")]
+    block_surt_regexes: Vec<String>,
+
+    /// Select the robots.txt captures instead of documents, for `worker --robotstxt`.
+    #[arg(long)]
+    robotstxt: bool,
+
+    /// Instead of the cluster.idx, look up every URL in this file (one per line) in each
+    /// of the crawls through the index API and enqueue all captures, oldest first.
+    #[arg(long)]
+    url_list: Option<String>,
+
+    /// Crawls searched for `--url-list`, comma-separated.
+    #[arg(long, value_delimiter = ',', default_value = CRAWL)]
+    crawls: Vec<String>,
+
+    /// Search all crawls from this one to `--to-crawl` for `--url-list` instead.
+    #[arg(long, requires_all = ["url_list", "to_crawl"])]
+    from_crawl: Option<String>,
+
+    #[arg(long, requires = "from_crawl")]
+    to_crawl: Option<String>,
+
+    /// Look up the capture a 3xx entry redirects to and enqueue that one instead.
+    #[arg(long)]
+    follow_redirects: bool,
+
+    #[arg(long, default_value_t = 3)]
+    max_redirect_hops: usize,
+
+    /// Drop entries whose payload digest an earlier entry of the run had, keeping the
+    /// first capture of each exact duplicate.
+    #[arg(long)]
+    dedup: bool,
+
+    /// Keep the digests in this SQLite file instead of memory once there are
+    /// `--dedup-max-in-memory` of them, a file in the temp directory by default. The file
+    /// is started over on every run.
+    #[arg(long, requires = "dedup")]
+    dedup_spill_filename: Option<String>,
+
+    /// One digest per 512 bytes of the memory limit by default.
+    #[arg(long, default_value_t = resources::limits().digest_capacity())]
+    dedup_max_in_memory: usize,
+
+    /// Keep the digests in the state store instead, so they survive a restart with
+    /// `--resume` and batchers sharing the store know each other's.
+    #[arg(long, requires = "dedup", conflicts_with = "dedup_spill_filename")]
+    dedup_in_state_store: bool,
+
+    /// Publish to this headers exchange instead of directly to the queue, so consumers
+    /// can bind their own queues to the batches they are interested in.
+    #[arg(long)]
+    exchange: Option<String>,
+
+    /// Publish to the priority queue, which workers drain first. Meant for small targeted
+    /// jobs, not whole crawls.
+    #[arg(long, conflicts_with = "exchange")]
+    priority: bool,
+
+    /// Declare the batch queues as quorum queues that move a batch to `<queue>.dlq` after
+    /// this many deliveries without an ack, e.g. because it keeps crashing the worker.
+    /// Batcher and workers have to agree, RabbitMQ refuses to redeclare a queue otherwise.
+    #[arg(long)]
+    dead_letter_after: Option<u32>,
+
+    /// Extra `key=value` header attached to every message, can be given multiple times.
+    #[arg(long = "header", value_parser = parse_header)]
+    headers: Vec<(String, String)>,
+
+    #[arg(long, default_value_t = 16)]
+    domain_hash_buckets: u32,
+
+    /// Consume the summaries of `worker --feedback` from `--feedback-queue-name` and
+    /// enqueue only `--down-sample-rate` of the entries of domains whose fetched captures
+    /// are mostly rejected.
+    #[arg(long)]
+    adaptive_sampling: bool,
+
+    #[arg(long, default_value = CC_FEEDBACK_QUEUE_NAME)]
+    feedback_queue_name: String,
+
+    /// Fetched captures of a domain needed before it can be down-sampled.
+    #[arg(long, default_value_t = 100)]
+    min_feedback_fetches: u64,
+
+    /// Down-sample domains with a larger share of fetched captures that did not become
+    /// documents.
+    #[arg(long, default_value_t = 0.95)]
+    max_rejection_rate: f64,
+
+    #[arg(long, default_value_t = 0.1)]
+    down_sample_rate: f64,
+
+    #[arg(long, value_enum, default_value_t = BatchEncoding::default())]
+    encoding: BatchEncoding,
+
+    /// Do not publish batches whose shard and batch index the run DB already has as
+    /// complete, so launching the batcher twice does not redo work.
+    #[arg(long)]
+    skip_completed_batches: bool,
+
+    /// How many batches may wait between CDX parsing and publishing. One per 64 MiB of
+    /// the container's memory by default, between 4 and 256.
+    #[arg(long, default_value_t = resources::limits().handoff_capacity())]
+    handoff_capacity: usize,
+
+    /// What to do with new batches when publishing falls behind. `block` loses nothing,
+    /// the drop policies keep latency bounded for streaming use.
+    #[arg(long, value_enum, default_value_t = OverflowPolicy::default())]
+    overflow_policy: OverflowPolicy,
+
+    #[command(flatten)]
+    queue: QueueOptions,
+
+    #[command(flatten)]
+    http: HttpOptions,
+
+    #[command(flatten)]
+    state: StateStoreOptions,
+
+    #[arg(long, default_value = CRAWL)]
+    run_id: String,
+
+    /// Stop publishing after this long, e.g. `12h`. Workers drain what is in flight and
+    /// the run is marked partially complete in the run DB.
+    #[arg(long, value_parser = parse_duration)]
+    deadline: Option<Duration>,
+
+    /// Stop once the run, batcher and all workers together, downloaded this many bytes.
+    #[arg(long)]
+    max_cost_bytes: Option<u64>,
+}
+
+type Headers = Vec<(String, String)>;
+
+/// Reads a CDX chunk of `url` to the end and keeps the entries `is_candidate` accepts, in
+/// order and with their line numbers. Malformed lines are counted and left out.
+async fn read_candidates(
+    url: &str,
+    mut lines: impl Stream<Item = Result<(usize, String), anyhow::Error>> + Unpin,
+    is_candidate: impl Fn(&CdxEntry) -> bool,
+) -> Result<Vec<(usize, CdxEntry)>, anyhow::Error> {
+    let mut candidates = Vec::new();
+    let mut num_parsed = 0;
+    while let Some(line) = lines.next().await {
+        num_parsed += 1;
+        let (number, line) = line?;
+        let Some(entry) = parse_cdx_line_or_skip(url, number, &line) else {
+            continue;
+        };
+        if is_candidate(&entry) {
+            candidates.push((number, entry));
+        }
+    }
+    PIPELINE_METRICS.record_cdx_lines(num_parsed, candidates.len() as u64);
+    Ok(candidates)
+}
+
+/// Everything wrong with `args` and the environment, so it is reported before connecting.
+fn check_config(args: &Args) -> ConfigCheck {
+    let mut check = ConfigCheck::default();
+    args.http.check(&mut check);
+    args.queue.check(&mut check);
+    match args.queue.queue_backend {
+        QueueBackend::Fs => check.require(
+            args.encoding == BatchEncoding::Json,
+            "--queue-backend fs only takes --encoding json",
+        ),
+        #[cfg(feature = "kafka")]
+        QueueBackend::Kafka => check.require(
+            args.queue.kafka_schema_registry_url.is_none() || args.encoding != BatchEncoding::Json,
+            "--kafka-schema-registry-url needs --encoding protobuf",
+        ),
+        _ => {}
+    }
+    check.require(
+        args.queue.is_rabbitmq() || (args.dead_letter_after.is_none() && args.exchange.is_none()),
+        "--dead-letter-after and --exchange need --queue-backend rabbitmq",
+    );
+    check.at_least("--batch-size", args.batch_size, 1);
+    check.at_least(
+        "--max-concurrent-downloads",
+        args.max_concurrent_downloads,
+        1,
+    );
+    check.at_least("--max-fetch-attempts", args.max_fetch_attempts, 1);
+    if let Some(dead_letter_after) = args.dead_letter_after {
+        check.at_least("--dead-letter-after", dead_letter_after, 2);
+    }
+    check.at_least("--max-publish-attempts", args.max_publish_attempts, 1);
+    check.at_least("--dedup-max-in-memory", args.dedup_max_in_memory, 1);
+    check.at_least("--confirm-timeout-secs", args.confirm_timeout_secs, 1);
+    check.at_least("--handoff-capacity", args.handoff_capacity, 1);
+    check.at_least("--domain-hash-buckets", args.domain_hash_buckets, 1);
+    if args.follow_redirects {
+        check.at_least("--max-redirect-hops", args.max_redirect_hops, 1);
+    }
+    check.in_range("--max-rejection-rate", args.max_rejection_rate, 0.0..=1.0);
+    check.in_range("--down-sample-rate", args.down_sample_rate, 0.0..=1.0);
+    let crawls = [&args.from_crawl, &args.to_crawl].into_iter().flatten();
+    for crawl in std::iter::once(&args.crawl)
+        .chain(&args.crawls)
+        .chain(crawls)
+    {
+        check.require(
+            crawl.starts_with("CC-MAIN-"),
+            format!("{crawl} is not a crawl ID like CC-MAIN-2024-30"),
+        );
+    }
+    if args.resume && !args.state.is_shared() {
+        check.file_exists("--checkpoint-filename", Some(&args.checkpoint_filename));
+    }
+    check.require(
+        !(args.resume && args.dedup) || args.dedup_in_state_store,
+        "--dedup with --resume needs --dedup-in-state-store, the digests of the last run \
+         are gone otherwise",
+    );
+    check.file_exists("--domain-ranks", args.domain_ranks.as_deref());
+    for (flag, filenames) in [
+        ("--allow-domains", &args.allow_domains),
+        ("--block-domains", &args.block_domains),
+    ] {
+        for filename in filenames {
+            check.file_exists(flag, Some(filename));
+        }
+    }
+    for (flag, filenames) in [
+        ("--allow-surt-regexes", &args.allow_surt_regexes),
+        ("--block-surt-regexes", &args.block_surt_regexes),
+    ] {
+        for filename in filenames {
+            check.file_exists(flag, Some(filename));
+            if Path::new(filename).is_file() {
+                check.parses(flag, SurtRules::default().with_regexes(Path::new(filename)));
+            }
+        }
+    }
+    match args.url_list.as_deref() {
+        Some(url_list) => check.file_exists("--url-list", Some(url_list)),
+        None => check.file_exists("--cluster-idx-filename", Some(&args.cluster_idx_filename)),
+    }
+    check
+}
+
+/// Logs how far the batcher got through the cluster.idx, at most once per interval.
+struct Progress {
+    num_chunks: usize,
+    num_chunks_done: usize,
+    num_entries: usize,
+    cdx_filename: String,
+    start: Instant,
+    last_log: Instant,
+    interval: Duration,
+}
+
+impl Progress {
+    fn new(num_chunks: usize, interval: Duration) -> Self {
+        Self {
+            num_chunks,
+            num_chunks_done: 0,
+            num_entries: 0,
+            cdx_filename: String::new(),
+            start: Instant::now(),
+            last_log: Instant::now(),
+            interval,
+        }
+    }
+
+    fn chunk_done(&mut self, cdx_filename: &str, num_entries: usize) {
+        self.num_chunks_done += 1;
+        self.num_entries += num_entries;
+        cdx_filename.clone_into(&mut self.cdx_filename);
+        if self.last_log.elapsed() >= self.interval {
+            self.log();
+        }
+    }
+
+    fn log(&mut self) {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let chunks_per_sec = self.num_chunks_done as f64 / elapsed.max(1e-3);
+        let remaining_secs =
+            (self.num_chunks - self.num_chunks_done) as f64 / chunks_per_sec.max(1e-9);
+        tracing::info!(
+            "Processed {}/{} CDX chunks ({:.1}%), now in {}, {} entries enqueued, {:.1} chunks/s, about {:.0}s left",
+            self.num_chunks_done,
+            self.num_chunks,
+            100.0 * self.num_chunks_done as f64 / self.num_chunks.max(1) as f64,
+            self.cdx_filename,
+            self.num_entries,
+            chunks_per_sec,
+            remaining_secs
+        );
+        self.last_log = Instant::now();
+    }
+}
+
+/// Cuts the entries of a shard into batches and hands them to the publisher, stamping
+/// each with its index within the shard.
+struct Enqueuer<'a> {
+    args: &'a Args,
+    run_db: &'a dyn StateStore,
+    handoff: &'a Handoff<(Vec<CdxEntry>, Headers)>,
+    checkpoint: Option<&'a Mutex<CheckpointWriter>>,
+    scorer: Option<&'a dyn UrlScorer>,
+    language: String,
+    num_batches_per_shard: HashMap<String, usize>,
+}
+
+impl Enqueuer<'_> {
+    /// Enqueues `entries` with their line numbers in `chunk`, if they come from a CDX
+    /// chunk. Returns `false` once the run reached its deadline or budget, nothing may be
+    /// enqueued after that.
+    async fn enqueue(
+        &mut self,
+        crawl: &str,
+        shard: &str,
+        chunk: Option<&ClusterIdxEntry>,
+        entries: Vec<(usize, CdxEntry)>,
+    ) -> bool {
+        let args = self.args;
+        let batches = match self.scorer {
+            // Ranked entries mix domains, so each batch gets those of one bucket.
+            Some(scorer) => batches_by_score(
+                entries,
+                args.batch_size,
+                scorer,
+                |(_, entry)| entry,
+                |entry| domain_hash_bucket(&host_of(entry), args.domain_hash_buckets),
+            ),
+            None => {
+                let mut entries = entries.into_iter().peekable();
+                let mut batches = Vec::new();
+                while entries.peek().is_some() {
+                    batches.push(entries.by_ref().take(args.batch_size).collect::<Vec<_>>());
+                }
+                batches
+            }
+        };
+        for batch in batches {
+            let (line_numbers, batch): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
+            let batch_index = self
+                .num_batches_per_shard
+                .entry(shard.to_string())
+                .or_default();
+            let key = BatchKey {
+                crawl: crawl.to_string(),
+                shard: shard.to_string(),
+                batch_index: *batch_index,
+            };
+            *batch_index += 1;
+            if let Some(checkpoint) = self.checkpoint {
+                if checkpoint.lock().unwrap().checkpoint().is_published(&key) {
+                    tracing::debug!(
+                        "Skipping batch {} of {} that was published before the restart",
+                        key.batch_index,
+                        key.shard
+                    );
+                    continue;
+                }
+            }
+            if let Some(reason) = self.run_db.check_limits(&args.run_id).unwrap() {
+                tracing::warn!(
+                    "Run reached its {}, stopping before batch {} of {}",
+                    reason.as_str(),
+                    key.batch_index,
+                    key.shard
+                );
+                self.run_db
+                    .mark_partial(&args.run_id, reason, Some(&key))
+                    .unwrap();
+                return false;
+            }
+            if args.skip_completed_batches && self.run_db.is_batch_complete(&key).unwrap() {
+                tracing::info!(
+                    "Skipping batch {} of {} that is already complete",
+                    key.batch_index,
+                    key.shard
+                );
+                if let Some(checkpoint) = self.checkpoint {
+                    checkpoint.lock().unwrap().batch_done(&key).unwrap();
+                }
+                continue;
+            }
+            // Entries are sorted by SURT or cut per bucket, so the first one is
+            // representative.
+            let domain = host_of(&batch[0]);
+            let mut headers = vec![
+                ("crawl".to_string(), crawl.to_string()),
+                ("language".to_string(), self.language.clone()),
+                ("shard".to_string(), shard.to_string()),
+                (
+                    "domain_hash".to_string(),
+                    domain_hash_bucket(&domain, args.domain_hash_buckets).to_string(),
+                ),
+            ];
+            headers.push(key.to_header());
+            if let Some(chunk) = chunk {
+                let source = BatchSource {
+                    cdx_path: cdx_chunk_path(crawl, &chunk.cdx_filename),
+                    offset: chunk.cdx_offset,
+                    length: chunk.cdx_length,
+                    lines: line_ranges(&line_numbers),
+                };
+                headers.extend(source.to_headers());
+            }
+            headers.extend(args.headers.iter().cloned());
+            let timestamps = batch.iter().map(|entry| entry.timestamp.as_str());
+            if let (Some(min), Some(max)) = (timestamps.clone().min(), timestamps.max()) {
+                self.run_db.register_batch(&key, min, max).unwrap();
+            }
+            if let Some((_, dropped)) = self.handoff.push((batch, headers)).await {
+                let dropped_key = BatchKey::from_headers(|name| {
+                    dropped
+                        .iter()
+                        .find(|(key, _)| key == name)
+                        .map(|(_, value)| value.clone())
+                });
+                if let (Some(checkpoint), Some(dropped_key)) = (self.checkpoint, dropped_key) {
+                    checkpoint
+                        .lock()
+                        .unwrap()
+                        .batch_dropped(&dropped_key)
+                        .unwrap();
+                }
+            }
+        }
+        true
+    }
+}
+
+fn host_of(entry: &CdxEntry) -> String {
+    url::Url::parse(&entry.metadata.url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+/// The allow- and blocklists of the command line.
+fn url_filter(args: &Args) -> Result<UrlFilter, anyhow::Error> {
+    let rules = |domains: &[String], regexes: &[String]| {
+        let mut rules = SurtRules::default();
+        for filename in domains {
+            rules = rules.with_domains(Path::new(filename))?;
+        }
+        for filename in regexes {
+            rules = rules.with_regexes(Path::new(filename))?;
+        }
+        Ok::<_, anyhow::Error>(rules)
+    };
+    Ok(UrlFilter::new(
+        rules(&args.allow_domains, &args.allow_surt_regexes)?,
+        rules(&args.block_domains, &args.block_surt_regexes)?,
+    ))
+}
+
+/// Publishes batches to `queue_name` of the broker of `--queue-backend`. For RabbitMQ
+/// the queues and the exchange are declared first.
+async fn producer(
+    args: &Args,
+    rabbit_conn: Option<&lapin::Connection>,
+    queue_name: &str,
+) -> Result<Box<dyn QueueProducer>, anyhow::Error> {
+    let producer: Box<dyn QueueProducer> = match args.queue.queue_backend {
+        QueueBackend::Rabbitmq => {
+            let channel = rabbitmq_channel(rabbit_conn.unwrap()).await?;
+            dlq::declare_queue(&channel, queue_name, args.dead_letter_after).await?;
+            if let Some(exchange) = args.exchange.as_deref() {
+                rabbitmq_declare_headers_exchange(&channel, exchange).await?;
+                rabbitmq_bind_queue_by_headers(&channel, &args.queue_name, exchange, &[]).await?;
+            }
+            let publisher = ReliablePublisher::new(channel)
+                .await?
+                .with_max_attempts(args.max_publish_attempts)
+                .with_confirm_timeout(Duration::from_secs(args.confirm_timeout_secs));
+            let producer = RabbitProducer::new(publisher);
+            match args.exchange.as_deref() {
+                Some(exchange) => Box::new(producer.with_exchange(exchange)),
+                None => Box::new(producer),
+            }
+        }
+        QueueBackend::Fs => Box::new(Spool::new(&args.queue.spool_dir)),
+        #[cfg(feature = "kafka")]
+        QueueBackend::Kafka => Box::new(
+            args.queue
+                .kafka_broker(&build_http_client(&args.http)?)
+                .producer()?,
+        ),
+        #[cfg(feature = "sqs")]
+        QueueBackend::Sqs => Box::new(
+            SqsClient::from_env(build_http_client(&args.http)?)?
+                .with_retries(args.http.backoff(), args.max_fetch_attempts),
+        ),
+    };
+    Ok(producer)
+}
+
+/// The summaries of `worker --feedback`, for `--adaptive-sampling`.
+async fn feedback_consumer(
+    args: &Args,
+    rabbit_conn: Option<&lapin::Connection>,
+) -> Result<Box<dyn QueueConsumer>, anyhow::Error> {
+    let consumer: Box<dyn QueueConsumer> = match args.queue.queue_backend {
+        QueueBackend::Rabbitmq => {
+            let (channel, _queue) =
+                rabbitmq_channel_with_queue(rabbit_conn.unwrap(), &args.feedback_queue_name)
+                    .await?;
+            Box::new(RabbitConsumer(
+                rabbitmq_consumer(&channel, &args.feedback_queue_name, "batcher").await?,
+            ))
+        }
+        QueueBackend::Fs => {
+            Box::new(Spool::new(&args.queue.spool_dir).consumer(&args.feedback_queue_name)?)
+        }
+        #[cfg(feature = "kafka")]
+        QueueBackend::Kafka => Box::new(
+            args.queue
+                .kafka_broker(&build_http_client(&args.http)?)
+                .consumer(&args.feedback_queue_name)?,
+        ),
+        #[cfg(feature = "sqs")]
+        QueueBackend::Sqs => Box::new(
+            SqsClient::from_env(build_http_client(&args.http)?)?
+                .with_retries(args.http.backoff(), args.max_fetch_attempts)
+                .consumer(&args.feedback_queue_name),
+        ),
+    };
+    Ok(consumer)
 }
 
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
+    check_config(&args).exit_on_problems();
     setup_tracing();
     tokio::task::spawn(run_metrics_server(9000));
+    tracing::info!("Running with {}", resources::limits());
 
-    let rabbit_conn = rabbitmq_connection().await.unwrap();
-    let (channel, _queue) = rabbitmq_channel_with_queue(&rabbit_conn, CC_QUEUE_NAME)
+    let rabbit_conn = match args.queue.is_rabbitmq() {
+        true => Some(rabbitmq_connection().await.unwrap()),
+        false => None,
+    };
+    let queue_name = if args.priority {
+        CC_PRIORITY_QUEUE_NAME
+    } else {
+        &args.queue_name
+    };
+    let publisher = producer(&args, rabbit_conn.as_ref(), queue_name)
         .await
         .unwrap();
 
-    let idx = fs::read_to_string(args.cluster_idx_filename)
-        .expect("Should have been able to read the file")
-        .lines()
-        .filter_map(parse_cluster_idx)
-        .collect::<Vec<_>>();
-
-    let mut num_cdx_chunks_processed: usize = 0;
-    for cdx_chunk in idx {
-        print!(".");
-        let english_cdx_entries = String::from_utf8(
-            download_and_unzip(
-                &format!(
-                    "https://data.commoncrawl.org/cc-index/collections/CC-MAIN-2024-30/indexes/{}",
-                    cdx_chunk.cdx_filename
-                ),
-                cdx_chunk.cdx_offset,
-                cdx_chunk.cdx_length,
-            )
-            .await
-            .unwrap(),
+    let scorer = args.domain_ranks.as_deref().map(|filename| {
+        let scorer = DomainRankScorer::read(Path::new(filename)).unwrap();
+        tracing::info!("Ordering entries by the rank of {} domains", scorer.len());
+        scorer
+    });
+    let filter = CdxFilter {
+        languages: args.languages.clone(),
+        primary_language_only: args.primary_language_only,
+        status_codes: args.status_codes.clone(),
+        mime_types: args.mime_types.clone(),
+        excluded_mime_types: args.exclude_mime_types.clone(),
+    };
+    let status_exclusions = StatusExclusions::default();
+    let has_status = |entry: &CdxEntry| {
+        let status = entry.metadata.status;
+        let is_followed_redirect = args.follow_redirects && (300..400).contains(&status);
+        if !is_followed_redirect && !filter.status_codes.contains(status) {
+            status_exclusions.record(status);
+            return false;
+        }
+        true
+    };
+    let url_filter = url_filter(&args).unwrap();
+    let http_client = build_http_client(&args.http).unwrap();
+    let client = IndexApiClient::new(
+        CC_INDEX_API_URL,
+        Politeness {
+            max_attempts: args.max_fetch_attempts,
+            ..Default::default()
+        },
+        &args.http,
+    )
+    .unwrap();
+    let run_db = args.state.open().unwrap();
+    run_db
+        .record_config(
+            &args.run_id,
+            "batcher",
+            &pipeline::state_store::command_line(),
         )
+        .unwrap();
+    let mut digest_dedup = args.dedup.then(|| {
+        if args.dedup_in_state_store {
+            return DigestDedup::in_store(run_db.clone());
+        }
+        let dedup = DigestDedup::in_memory();
+        match args.dedup_spill_filename.as_deref() {
+            Some(filename) => dedup.with_spill(Path::new(filename), args.dedup_max_in_memory),
+            None => dedup.with_temp_spill(args.dedup_max_in_memory),
+        }
         .unwrap()
-        .lines()
-        .map(parse_cdx_line)
-        .filter(|e| {
-            if let Some(languages) = e.metadata.languages.as_ref() {
-                languages.contains("eng") && e.metadata.status == 200
+    });
+    let limits = RunLimits::new(args.deadline, args.max_cost_bytes);
+    if !limits.is_empty() {
+        run_db.set_limits(&args.run_id, &limits).unwrap();
+    }
+    let mut traffic = TrafficFlusher::default();
+    let handoff = Handoff::new(
+        args.handoff_capacity,
+        args.overflow_policy,
+        &BATCHER_HANDOFF,
+    );
+    let sampler = Mutex::new(DomainSampler::new(
+        &args.run_id,
+        args.min_feedback_fetches,
+        args.max_rejection_rate,
+        args.down_sample_rate,
+    ));
+    // Parsing and publishing run concurrently, so a slow broker only holds up parsing
+    // once the handoff is full, and the batches in between stay bounded.
+    let checkpoint = args.url_list.is_none().then(|| {
+        let checkpoint = if args.resume {
+            let checkpoint = if args.state.is_shared() {
+                Checkpoint::read_from(run_db.as_ref(), &args.checkpoint_filename)
             } else {
-                false
-            }
-        })
-        .collect::<Vec<_>>();
-        for batch in english_cdx_entries.as_slice().chunks(BATCH_SIZE) {
-            tracing::info!("Sending a batch of {} entries", batch.len());
-            channel
-                .basic_publish(
-                    "",
-                    CC_QUEUE_NAME,
-                    BasicPublishOptions::default(),
-                    &serde_json::to_vec(&batch).unwrap(),
-                    BasicProperties::default(),
-                )
+                Checkpoint::read(Path::new(&args.checkpoint_filename))
+            };
+            let checkpoint = checkpoint
+                .unwrap()
+                .expect("Should have been able to read the checkpoint");
+            assert_eq!(
+                checkpoint.crawl, args.crawl,
+                "The checkpoint belongs to another crawl"
+            );
+            checkpoint
+        } else {
+            Checkpoint::new(&args.crawl)
+        };
+        let writer = if args.state.is_shared() {
+            CheckpointWriter::create_in(run_db.clone(), &args.checkpoint_filename, checkpoint)
+        } else {
+            CheckpointWriter::create(&args.checkpoint_filename, checkpoint)
+        };
+        Mutex::new(writer.unwrap())
+    });
+    let produce = async {
+        let mut enqueuer = Enqueuer {
+            args: &args,
+            run_db: run_db.as_ref(),
+            handoff: &handoff,
+            checkpoint: checkpoint.as_ref(),
+            scorer: scorer.as_ref().map(|scorer| scorer as &dyn UrlScorer),
+            language: filter.languages.to_string(),
+            num_batches_per_shard: HashMap::new(),
+        };
+        if let Some(url_list) = args.url_list.as_deref() {
+            let crawls = match (args.from_crawl.as_deref(), args.to_crawl.as_deref()) {
+                (Some(from), Some(to)) => crawl_range(&client.crawls().await.unwrap(), from, to),
+                _ => args.crawls.clone(),
+            };
+            let urls = fs::read_to_string(url_list)
+                .expect("Should have been able to read the URL list")
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(str::to_string)
+                .collect::<Vec<_>>();
+            tracing::info!("Looking up {} URLs in {} crawls", urls.len(), crawls.len());
+            let captures = client
+                .captures_across_crawls(&crawls, &urls)
                 .await
-                .context("rabbitmq basic publish")
+                .unwrap()
+                .into_iter()
+                .filter(|entry| {
+                    has_status(entry) && filter.matches(entry) && url_filter.matches(entry)
+                })
+                .collect::<Vec<_>>();
+            tracing::info!("Found {} matching captures", captures.len());
+            let mut captures = captures.into_iter().map(|entry| (0, entry)).collect();
+            if let Some(digest_dedup) = digest_dedup.as_mut() {
+                captures = digest_dedup.retain_new(captures).unwrap();
+            }
+            enqueuer
+                .enqueue(&crawls.join(","), url_list, None, captures)
+                .await;
+            run_db
+                .add_traffic(&args.run_id, &traffic.take_deltas())
                 .unwrap();
+        } else {
+            let idx = fs::read_to_string(&args.cluster_idx_filename)
+                .expect("Should have been able to read the file")
+                .lines()
+                .filter_map(parse_cluster_idx)
+                .collect::<Vec<_>>();
+            let num_cdx_chunks = args
+                .num_cdx_chunks_to_process
+                .map_or(idx.len(), |n| n.min(idx.len()));
+            let num_cdx_files = idx
+                .iter()
+                .take(num_cdx_chunks)
+                .map(|chunk| chunk.cdx_filename.as_str())
+                .collect::<HashSet<_>>()
+                .len();
+            tracing::info!(
+                "Processing {} CDX chunks of {} CDX files",
+                num_cdx_chunks,
+                num_cdx_files
+            );
+            let chunks_done = match checkpoint.as_ref() {
+                Some(checkpoint) => {
+                    let checkpoint = checkpoint.lock().unwrap();
+                    let checkpoint = checkpoint.checkpoint();
+                    enqueuer.num_batches_per_shard = checkpoint
+                        .batches_done
+                        .iter()
+                        .map(|(shard, num_batches)| (shard.clone(), *num_batches))
+                        .collect();
+                    checkpoint.chunks_done.min(num_cdx_chunks)
+                }
+                None => 0,
+            };
+            if chunks_done > 0 {
+                tracing::info!("Resuming after {} CDX chunks", chunks_done);
+            }
+            let http_client = &http_client;
+            let crawl = args.crawl.as_str();
+            let utf8_policy = args.utf8_policy;
+            let open_chunk = |cdx_chunk: &ClusterIdxEntry| {
+                let url = cdx_chunk_url(crawl, &cdx_chunk.cdx_filename);
+                let (offset, length) = (cdx_chunk.cdx_offset, cdx_chunk.cdx_length);
+                async move {
+                    download_and_unzip_lines(http_client, &url, offset, length, utf8_policy)
+                        .await
+                        .map(Box::pin)
+                }
+            };
+            let open_chunk = &open_chunk;
+            // `buffered` keeps the downloads in order, so batch indices stay stable. Only
+            // the requests are made ahead, each body is decompressed as it is read.
+            let mut downloads =
+                stream::iter(idx.into_iter().take(num_cdx_chunks).skip(chunks_done))
+                    .map(|cdx_chunk| async move {
+                        let lines = open_chunk(&cdx_chunk).await;
+                        (cdx_chunk, lines)
+                    })
+                    .buffered(args.max_concurrent_downloads.max(1));
+            let is_selected = |entry: &CdxEntry| {
+                if args.robotstxt {
+                    is_robotstxt_capture(entry)
+                        && filter.status_codes.contains(entry.metadata.status)
+                } else {
+                    filter.matches(entry)
+                }
+            };
+            // Checked last, so the rules only count entries they really leave out.
+            let is_candidate = |entry: &CdxEntry| {
+                has_status(entry)
+                    && (is_selected(entry)
+                        || (args.follow_redirects && (300..400).contains(&entry.metadata.status)))
+                    && url_filter.matches(entry)
+            };
+            let backoff = args.http.backoff();
+            let mut progress = Progress::new(
+                num_cdx_chunks - chunks_done,
+                Duration::from_secs(args.progress_interval_secs),
+            );
+            while let Some((cdx_chunk, mut lines)) = downloads.next().await {
+                let url = cdx_chunk_url(crawl, &cdx_chunk.cdx_filename);
+                // Nothing of a chunk is enqueued before it was read to the end, so a
+                // connection that fails midway restarts the chunk from the beginning.
+                let mut attempt = 1;
+                let candidates = loop {
+                    let result = match lines {
+                        Ok(lines) => read_candidates(&url, lines, is_candidate).await,
+                        Err(e) => Err(e),
+                    };
+                    match result {
+                        Ok(candidates) => break candidates,
+                        Err(e) => {
+                            match retry_delay(&url, &e, attempt, args.max_fetch_attempts, &backoff)
+                            {
+                                Some(delay) => tokio::time::sleep(delay).await,
+                                None => panic!("Failed to read {}: {e:?}", cdx_chunk.cdx_filename),
+                            }
+                        }
+                    }
+                    attempt += 1;
+                    lines = open_chunk(&cdx_chunk).await;
+                };
+                let mut english_cdx_entries = Vec::new();
+                for (number, entry) in candidates {
+                    if is_selected(&entry) {
+                        english_cdx_entries.push((number, entry));
+                    } else {
+                        match resolve_redirect(&client, &args.crawl, &entry, args.max_redirect_hops)
+                            .await
+                        {
+                            Ok(Some(target))
+                                if filter.matches(&target) && url_filter.matches(&target) =>
+                            {
+                                english_cdx_entries.push((number, target))
+                            }
+                            Ok(_) => {}
+                            Err(e) => tracing::warn!(err.msg = %e, "Failed to resolve redirect"),
+                        }
+                    }
+                }
+                if args.adaptive_sampling {
+                    let sampler = sampler.lock().unwrap();
+                    english_cdx_entries.retain(|(_, entry)| sampler.keep(entry));
+                }
+                if let Some(digest_dedup) = digest_dedup.as_mut() {
+                    english_cdx_entries = digest_dedup.retain_new(english_cdx_entries).unwrap();
+                }
+                let num_entries = english_cdx_entries.len();
+                let proceed = enqueuer
+                    .enqueue(
+                        &args.crawl,
+                        &cdx_chunk.cdx_filename,
+                        Some(&cdx_chunk),
+                        english_cdx_entries,
+                    )
+                    .await;
+                if let (true, Some(checkpoint)) = (proceed, checkpoint.as_ref()) {
+                    let shard = &cdx_chunk.cdx_filename;
+                    let num_batches = enqueuer.num_batches_per_shard.get(shard);
+                    checkpoint
+                        .lock()
+                        .unwrap()
+                        .chunk_enqueued(shard, num_batches.copied().unwrap_or(0))
+                        .unwrap();
+                }
+                run_db
+                    .add_traffic(&args.run_id, &traffic.take_deltas())
+                    .unwrap();
+                progress.chunk_done(&cdx_chunk.cdx_filename, num_entries);
+                if !proceed {
+                    break;
+                }
+            }
+            progress.log();
+        }
+        handoff.close();
+    };
+    let publish = async {
+        while let Some((batch, mut headers)) = handoff.pop().await {
+            let key = BatchKey::from_headers(|name| {
+                headers
+                    .iter()
+                    .find(|(key, _)| key == name)
+                    .map(|(_, value)| value.clone())
+            });
+            if let Some(rabbit_conn) = rabbit_conn.as_ref() {
+                wait_while_blocked(rabbit_conn).await;
+            }
+            tracing::info!("Sending a batch of {} entries", batch.len());
+            let payload = args.encoding.encode(&batch).unwrap();
+            PAYLOAD_SIZES.batch_payload.observe(payload.len() as u64);
+            headers.extend(BatchManifest::new(&batch, &payload).to_headers());
+            let start = Instant::now();
+            let message = QueueMessage::new(payload)
+                .with_content_type(args.encoding.content_type())
+                .with_headers(headers);
+            publisher.publish(queue_name, &message).await.unwrap();
+            PIPELINE_METRICS.record_published(start.elapsed());
+            if let (Some(checkpoint), Some(key)) = (checkpoint.as_ref(), key.as_ref()) {
+                checkpoint.lock().unwrap().batch_done(key).unwrap();
+            }
+        }
+    };
+    let consume_feedback = async {
+        if !args.adaptive_sampling {
+            return;
         }
-        num_cdx_chunks_processed += 1;
-        if let Some(to_process) = args.num_cdx_chunks_to_process {
-            if to_process == num_cdx_chunks_processed {
-                break;
+        let mut consumer = feedback_consumer(&args, rabbit_conn.as_ref())
+            .await
+            .unwrap();
+        while let Some(delivery) = consumer.next().await {
+            let delivery = match delivery {
+                Ok(delivery) => delivery,
+                Err(e) => {
+                    tracing::warn!(err.msg = %e, "Failed to receive feedback from the broker");
+                    continue;
+                }
+            };
+            match serde_json::from_slice::<FeedbackSummary>(&delivery.message().payload) {
+                Ok(summary) => sampler.lock().unwrap().add(&summary),
+                Err(e) => tracing::warn!(err.msg = %e, "Ignoring invalid feedback summary"),
             }
+            delivery.ack().await.unwrap();
         }
+    };
+    // Feedback is only consumed while batches are produced, it never ends the run.
+    tokio::select! {
+        _ = async { tokio::join!(produce, publish) } => {}
+        _ = async {
+            consume_feedback.await;
+            std::future::pending::<()>().await
+        } => {}
     }
-}
-
-fn parse_cdx_line(line: &str) -> CdxEntry {
-    let mut parts = line.splitn(3, ' ');
-    CdxEntry {
-        surt_url: parts.next().unwrap().to_string(),
-        timestamp: parts.next().unwrap().to_string(),
-        metadata: serde_json::from_str(parts.next().unwrap()).unwrap(),
+    let digest_counters = digest_dedup.map(|digest_dedup| digest_dedup.counters());
+    for counters in [url_filter.counters(), status_exclusions.counters()]
+        .into_iter()
+        .chain(digest_counters)
+    {
+        for (name, count) in counters.iter() {
+            tracing::info!("{}: {} entries", name, count);
+        }
+        run_db.add_counters(&args.run_id, &counters).unwrap();
     }
-}
-
-struct ClusterIdxEntry {
-    _surt_url: String,
-    _timestamp: String,
-    cdx_filename: String,
-    cdx_offset: usize,
-    cdx_length: usize,
-    _cluster_id: String,
-}
-
-fn parse_cluster_idx(line: &str) -> Option<ClusterIdxEntry> {
-    let mut idx = line.split_whitespace();
-    Some(ClusterIdxEntry {
-        _surt_url: idx.next()?.to_string(),
-        _timestamp: idx.next()?.to_string(),
-        cdx_filename: idx.next()?.to_string(),
-        cdx_offset: idx.next()?.parse().unwrap(),
-        cdx_length: idx.next()?.parse().unwrap(),
-        _cluster_id: idx.next()?.to_string(),
-    })
-}
-
-#[cfg(test)]
-mod tests {
-    use crate::{parse_cdx_line, parse_cluster_idx};
-
-    #[test]
-    fn can_parse_cdx_file() {
-        let content = r#"0,100,22,165)/ 20240722120756 {"url": "http://165.22.100.0/", "mime": "text/html", "mime-detected": "text/html", "status": "301", "digest": "DCNYNIFG5SBRCVS5PCUY4YY2UM2WAQ4R", "length": "689", "offset": "3499", "filename": "crawl-data/CC-MAIN-2024-30/segments/1720763517846.73/crawldiagnostics/CC-MAIN-20240722095039-20240722125039-00443.warc.gz", "redirect": "https://157.245.55.71/"}
-0,100,22,165)/robots.txt 20240722120755 {"url": "http://165.22.100.0/robots.txt", "mime": "text/html", "mime-detected": "text/html", "status": "301", "digest": "LYEE2BXON4MCQCP5FDVDNILOWBKCZZ6G", "length": "700", "offset": "4656", "filename": "crawl-data/CC-MAIN-2024-30/segments/1720763517846.73/robotstxt/CC-MAIN-20240722095039-20240722125039-00410.warc.gz", "redirect": "https://157.245.55.71/robots.txt"}
-0,100,59,139)/ 20240723213521 {"url": "https://139.59.100.0/", "mime": "text/html", "mime-detected": "text/html", "status": "200", "digest": "5JOQMMSNM6N7UCLGGYXDSPSB3FYAQS2C", "length": "16650", "offset": "64016172", "filename": "crawl-data/CC-MAIN-2024-30/segments/1720763518115.82/warc/CC-MAIN-20240723194208-20240723224208-00279.warc.gz", "charset": "UTF-8", "languages": "ind,eng"}"#;
-        let cdx: Vec<_> = content.lines().map(parse_cdx_line).collect();
-        assert_eq!(cdx.len(), 3);
-    }
-
-    #[test]
-    fn can_parse_cluster_idx_file() {
-        let content = r#"0,100,22,165)/ 20240722120756   cdx-00000.gz    0       188224  1
-101,141,199,66)/robots.txt 20240714155331       cdx-00000.gz    188224  178351  2
-104,223,1,100)/ 20240714230020  cdx-00000.gz    366575  178055  3
-107,128,254,23)/sites.asp?domain=hydrogenheaters.com 20240725183414     cdx-00000.gz    544630  181599  4"#;
-        let cdx_parts: Vec<_> = content.lines().map(parse_cluster_idx).collect();
-        assert_eq!(cdx_parts.len(), 4);
+    if args.adaptive_sampling {
+        tracing::info!(
+            "Down-sampled {} domains, leaving out {} entries",
+            sampler.lock().unwrap().num_down_sampled_domains(),
+            ADAPTIVE_SAMPLING.down_sampled_entries()
+        );
+    }
+    if BROKER_FLOW_CONTROL.times_blocked() > 0 {
+        tracing::warn!(
+            "RabbitMQ blocked publishing {} times",
+            BROKER_FLOW_CONTROL.times_blocked()
+        );
+    }
+    if CDX_DECODING.replaced_bytes() > 0 || CDX_DECODING.skipped_lines() > 0 {
+        tracing::warn!(
+            "Replaced {} invalid UTF-8 bytes and skipped {} CDX lines",
+            CDX_DECODING.replaced_bytes(),
+            CDX_DECODING.skipped_lines()
+        );
+    }
+    if let Some(summary) = CDX_DECODING.malformed_summary() {
+        tracing::warn!("{}", summary);
+    }
+    if BATCHER_HANDOFF.dropped() > 0 {
+        tracing::warn!(
+            "Dropped {} batches because publishing could not keep up",
+            BATCHER_HANDOFF.dropped()
+        );
     }
 }
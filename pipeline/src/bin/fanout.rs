@@ -0,0 +1,121 @@
+use std::time::Duration;
+
+use clap::Parser;
+use futures_util::StreamExt;
+use lapin::options::{BasicAckOptions, BasicRejectOptions};
+use pipeline::{
+    batch::batch_id,
+    config_check::ConfigCheck,
+    dlq,
+    fanout::entry_messages,
+    rabbitmq::{
+        rabbitmq_channel, rabbitmq_connection, rabbitmq_consumer, rabbitmq_declare_queue,
+        ReliablePublisher, CC_QUEUE_NAME, RABBITMQ_CONNECTION_STRING,
+    },
+    tracing_and_metrics::setup_tracing,
+};
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+/// Republishes the entries of each batch as messages of their own, for consumers that
+/// expect one URL per message.
+struct Args {
+    #[arg(long, default_value = CC_QUEUE_NAME)]
+    queue_name: String,
+
+    /// Where the single entries go.
+    #[arg(long, default_value = "entries")]
+    output_queue_name: String,
+
+    /// See the batcher's `--dead-letter-after`, it has to match.
+    #[arg(long)]
+    dead_letter_after: Option<u32>,
+
+    /// Publishes of an entry before giving up, when RabbitMQ nacks it or does not confirm
+    /// it within `--confirm-timeout-secs`.
+    #[arg(long, default_value_t = 5)]
+    max_publish_attempts: usize,
+
+    #[arg(long, default_value_t = 20)]
+    confirm_timeout_secs: u64,
+}
+
+/// Everything wrong with `args` and the environment, so it is reported before connecting.
+fn check_config(args: &Args) -> ConfigCheck {
+    let mut check = ConfigCheck::default();
+    check.env_var(RABBITMQ_CONNECTION_STRING);
+    if let Some(dead_letter_after) = args.dead_letter_after {
+        check.at_least("--dead-letter-after", dead_letter_after, 2);
+    }
+    check.at_least("--max-publish-attempts", args.max_publish_attempts, 1);
+    check.at_least("--confirm-timeout-secs", args.confirm_timeout_secs, 1);
+    check.require(
+        args.queue_name != args.output_queue_name,
+        "--output-queue-name must differ from --queue-name",
+    );
+    check
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    check_config(&args).exit_on_problems();
+    setup_tracing();
+
+    let rabbit_conn = rabbitmq_connection().await.unwrap();
+    let channel = rabbitmq_channel(&rabbit_conn).await.unwrap();
+    dlq::declare_queue(&channel, &args.queue_name, args.dead_letter_after)
+        .await
+        .unwrap();
+    let publish_channel = rabbitmq_channel(&rabbit_conn).await.unwrap();
+    rabbitmq_declare_queue(
+        &publish_channel,
+        &args.output_queue_name,
+        Default::default(),
+    )
+    .await
+    .unwrap();
+    let publisher = ReliablePublisher::new(publish_channel)
+        .await
+        .unwrap()
+        .with_max_attempts(args.max_publish_attempts)
+        .with_confirm_timeout(Duration::from_secs(args.confirm_timeout_secs));
+    let mut consumer = rabbitmq_consumer(&channel, &args.queue_name, "fanout")
+        .await
+        .unwrap();
+    while let Some(delivery) = consumer.next().await {
+        let delivery = match delivery {
+            Ok(delivery) => delivery,
+            Err(e) => {
+                tracing::warn!(err.msg = %e, err.details = ?e, "Failed to receive message from RabbitMQ. Reconnecting.");
+                continue;
+            }
+        };
+        let messages = match entry_messages(&delivery.data, &delivery.properties) {
+            Ok(messages) => messages,
+            Err(e) => {
+                // Redelivering the same bytes cannot help, so do not requeue.
+                tracing::error!(err.msg = %e, "Rejecting corrupt batch {}", batch_id(&delivery.data));
+                delivery
+                    .reject(BasicRejectOptions { requeue: false })
+                    .await
+                    .unwrap();
+                continue;
+            }
+        };
+        // The batch is only acked once every entry is confirmed, a crash in between
+        // publishes the first entries again.
+        for (payload, properties) in &messages {
+            publisher
+                .publish("", &args.output_queue_name, payload, properties.clone())
+                .await
+                .unwrap();
+        }
+        delivery.ack(BasicAckOptions::default()).await.unwrap();
+        tracing::info!(
+            "Republished {} entries to {}",
+            messages.len(),
+            args.output_queue_name
+        );
+    }
+}
@@ -1,78 +1,2214 @@
-use futures_util::StreamExt;
-use lapin::options::BasicAckOptions;
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    mem,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use clap::Parser;
+#[cfg(feature = "grpc")]
+use pipeline::control::{run_control_server, ControlState};
+#[cfg(feature = "s3")]
+use pipeline::sigv4::utc_date;
+#[cfg(any(feature = "s3", feature = "sqs"))]
+use pipeline::sigv4::{AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY};
+#[cfg(feature = "sqs")]
+use pipeline::sqs::SqsClient;
 use pipeline::{
-    cdx::{download_and_unzip, CdxEntry},
+    ab::{is_sampled, AbComparison, AbWriter},
+    batch::{batch_id, BatchEncoding, BatchKey, BatchManifest, RecentBatches},
+    budget::unix_now,
+    cdx::{fetch_error, gunzip, CdxEntry, CC_DATA_URL},
+    compare::RunCounters,
+    compliance::{DropCounts, DropReason},
+    compression::Compression,
+    config_check::ConfigCheck,
+    dedup::{ContentDedup, DedupKey, NearDedupAction, NearDedupMethod},
+    dlq::{self, dead_letter_copy, dlq_name, is_last_delivery},
+    endpoints::DataEndpoints,
+    error_code::ErrorCode,
+    extractor::{build_extractor, extract_with_fallback, ExtractorKind, HtmlExtractor},
+    feedback::RejectionTally,
+    filter::{
+        DomainList, HeaderFilter, HeaderRule, IpFilter, OutputFilter, RecordList, RecordSelection,
+    },
+    histogram::PAYLOAD_SIZES,
+    http_client::{build_http_client, HttpOptions},
+    journal::Journal,
+    loadtest,
+    metrics::PIPELINE_METRICS,
+    output::{
+        detect_language, document_id, Document, LanguageRouter, OutputFormat, OutputSink,
+        RecordWriter, WriteOptions,
+    },
+    parquet_output::{recover_staged, write_parquet, MetadataRecord, ParquetSink},
+    provenance::{AsnDb, IpRanges, Provenance},
+    quality::{quality_score, token_count},
+    queue::{
+        QueueBackend, QueueConsumer, QueueDelivery, QueueMessage, QueueOptions, QueueProducer,
+    },
+    quota::DomainQuota,
     rabbitmq::{
-        rabbitmq_channel_with_queue, rabbitmq_connection, rabbitmq_consumer, CC_QUEUE_NAME,
+        parse_header, rabbitmq_bind_queue_by_headers, rabbitmq_channel, rabbitmq_connection,
+        rabbitmq_consumer, rabbitmq_declare_headers_exchange, rabbitmq_declare_queue,
+        RabbitConsumer, RabbitProducer, ReliablePublisher, CC_FEEDBACK_QUEUE_NAME,
+        CC_PRIORITY_QUEUE_NAME, CC_QUEUE_NAME,
     },
-    tracing_and_metrics::{run_metrics_server, setup_tracing},
-    trafilatura,
+    range_cache::RangeCache,
+    resources,
+    robots::{is_noindex, parse_robotstxt, RobotsRecord},
+    run_db::RunDb,
+    scorer::BatchScorer,
+    sniff::{decode_body, sniff, ContentKind},
+    spool::Spool,
+    stage_timings::{millis, StageTimings, StageTimingsWriter},
+    state_store::{StateStore, StateStoreOptions},
+    tracing_and_metrics::{run_metrics_server, setup_tracing, LogFilterHandle},
+    traffic::TrafficFlusher,
+    tunables::{watch_tunables, Tunables},
+    warc_response::{http_header, parse_warc_responses, ResponseHeaders},
+    warm_start::{manifest_path, read_manifests, CompletedBatch, CompletedBatches},
 };
-use warc::WarcHeader;
+#[cfg(feature = "extraction")]
+use pipeline::{
+    media::{extract_media, MediaRecord},
+    structured::extract_structured_data,
+    web_graph::HostGraph,
+};
+#[cfg(feature = "s3")]
+use pipeline::{
+    s3::{crawl_placeholder, PendingUpload, PendingUploads, S3Client, S3Destination},
+    state_file::{read_state_file, write_state_file},
+};
+use serde::Serialize;
+#[cfg(feature = "s3")]
+use std::collections::{BTreeSet, HashMap, HashSet};
+use tokio::{sync::watch, task::JoinSet};
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Entries whose WARC record can never be fetched are appended to this file.
+    #[arg(long, default_value = "permanent_failures.jsonl")]
+    permanent_failures_filename: String,
+
+    #[arg(long, default_value_t = 3)]
+    max_fetch_attempts: usize,
+
+    /// Keep up to this many MiB of recently fetched WARC ranges in memory, shared by all
+    /// consumer tasks, so records requested again by nearby batches are not downloaded
+    /// twice. Off by default.
+    #[arg(long, default_value_t = 0)]
+    range_cache_mib: usize,
+
+    /// Independent consumer tasks in this process, each with its own channel and output
+    /// files. Per-task files like the journal get the task index appended. One per CPU
+    /// of the container by default.
+    #[arg(long, default_value_t = resources::limits().workers())]
+    workers: usize,
+
+    /// Documents are written to `<output-dir>/<language>/worker-<pid>.jsonl`, or
+    /// `worker-<pid>-<task>.jsonl` with several `--workers`.
+    #[arg(short, long, default_value = "output")]
+    output_dir: String,
+
+    /// Start a new output shard per language once this many bytes of document text were
+    /// written to it, shards are then named `worker-<pid>-00000.jsonl` and so on. Parquet
+    /// files roll over at 1 GiB of text by default.
+    #[arg(long)]
+    shard_text_bytes: Option<u64>,
+
+    /// Start a new output shard per language once this many documents were written to it,
+    /// like `--shard-text-bytes`. Parquet files roll over at a million by default.
+    #[arg(long)]
+    shard_documents: Option<u64>,
+
+    /// Reorder each full shard by document length, shortest first.
+    #[arg(long, requires = "shard_text_bytes")]
+    sort_shards_by_length: bool,
+
+    /// Output files of languages that received no document for this long are closed.
+    #[arg(long, default_value_t = 300)]
+    idle_writer_timeout_secs: u64,
+
+    #[arg(short, long, value_enum, default_value_t = ExtractorKind::default())]
+    extractor: ExtractorKind,
+
+    /// Fall back to tag stripping for HTML pages the extractor returns fewer characters of,
+    /// unless tag stripping returns even less. 0 turns the fallback off.
+    #[arg(long, default_value_t = 50)]
+    extractor_fallback_min_chars: usize,
+
+    /// Command started by the `subprocess` extractor.
+    #[arg(long, default_value = "python3 scripts/trafilatura_bridge.py")]
+    extractor_command: String,
+
+    /// Also run this extractor on a sample of documents and write both results side by side.
+    #[arg(long, value_enum)]
+    ab_extractor: Option<ExtractorKind>,
+
+    #[arg(long, default_value_t = 0.01)]
+    ab_sample_rate: f64,
+
+    #[arg(long, default_value = "ab_comparison.jsonl")]
+    ab_output_filename: String,
+
+    /// Also extract the JSON-LD and microdata items of HTML pages into `structured_data`.
+    #[cfg(feature = "extraction")]
+    #[arg(long)]
+    structured_data: bool,
+
+    /// Only keep structured data items of these schema.org types, comma-separated, e.g.
+    /// `Product,Article,Recipe`.
+    #[cfg(feature = "extraction")]
+    #[arg(long, value_delimiter = ',', requires = "structured_data")]
+    structured_data_types: Vec<String>,
+
+    /// Parse every record as robots.txt and write the rules per host to
+    /// `<output-dir>/robotstxt/` instead of documents, for `batcher --robotstxt`.
+    #[arg(long, conflicts_with = "metadata_only")]
+    robotstxt: bool,
+
+    /// Write the image and video URLs of HTML pages with their alt text and context to
+    /// `<output-dir>/media/` instead of documents. The media is not downloaded.
+    #[cfg(feature = "extraction")]
+    #[arg(long, conflicts_with_all = ["metadata_only", "robotstxt"])]
+    media: bool,
+
+    /// Also count the links between hosts of the HTML pages and write them to
+    /// `<output-dir>/web_graph/<run-id>-<worker>-<part>.parquet`, one part per
+    /// `--web-graph-every-batches` batches and one when the worker stops.
+    #[cfg(feature = "extraction")]
+    #[arg(long, conflicts_with_all = ["metadata_only", "robotstxt"])]
+    web_graph: bool,
+
+    #[cfg(feature = "extraction")]
+    #[arg(long, default_value_t = 20, requires = "web_graph")]
+    web_graph_every_batches: usize,
+
+    /// Do not fetch any WARC records, only write the index metadata of each batch to
+    /// `<output-dir>/metadata/` as Parquet.
+    #[arg(long)]
+    metadata_only: bool,
+
+    #[arg(long, default_value = CC_QUEUE_NAME)]
+    queue_name: String,
+
+    /// Batches waiting in this queue are always processed before those in `--queue-name`.
+    #[arg(long, default_value = CC_PRIORITY_QUEUE_NAME)]
+    priority_queue_name: String,
+
+    /// Serve the gRPC control API here, e.g. `127.0.0.1:50051`, for `pipeline control`
+    /// to pause and resume the worker and to submit targeted jobs.
+    #[cfg(feature = "grpc")]
+    #[arg(long)]
+    control_addr: Option<std::net::SocketAddr>,
+
+    /// Declare the batch queues as quorum queues that move a batch to `<queue>.dlq` after
+    /// this many deliveries without an ack, e.g. because it keeps crashing the worker.
+    /// Batcher and workers have to agree, RabbitMQ refuses to redeclare a queue otherwise.
+    #[arg(long)]
+    dead_letter_after: Option<u32>,
+
+    /// Bind `--queue-name` to this headers exchange of the batcher.
+    #[arg(long)]
+    exchange: Option<String>,
+
+    /// After each batch, publish how many fetched captures of each domain became
+    /// documents to `--feedback-queue-name`, for `batcher --adaptive-sampling`.
+    #[arg(long)]
+    feedback: bool,
+
+    #[arg(long, default_value = CC_FEEDBACK_QUEUE_NAME)]
+    feedback_queue_name: String,
+
+    /// Only receive batches carrying this `key=value` header, can be given multiple times.
+    #[arg(long = "bind-header", value_parser = parse_header, requires = "exchange")]
+    bind_headers: Vec<(String, String)>,
+
+    /// Journal of the batch in flight, used to clean up partial outputs after a crash.
+    /// Every worker sharing an output directory needs its own journal.
+    #[arg(long, default_value = "worker_journal.json")]
+    journal_filename: String,
+
+    /// How long processed batch IDs are remembered to drop redeliveries of them.
+    #[arg(long, default_value_t = 3600)]
+    duplicate_window_secs: u64,
+
+    #[command(flatten)]
+    state: StateStoreOptions,
+
+    #[arg(long, default_value = "CC-MAIN-2024-30")]
+    run_id: String,
+
+    /// Share the window of processed batch IDs with other workers through Redis.
+    #[cfg(feature = "redis")]
+    #[arg(long)]
+    redis_url: Option<String>,
+
+    /// Drop documents whose exact text was already written by this worker, or by any
+    /// worker with `--dedup-redis-url` or `--dedup-in-state-store`.
+    #[arg(long)]
+    dedup_content: bool,
+
+    /// In-memory dedup partitions, split by hash prefix to spread the lookups of the
+    /// consumer tasks. Four per CPU by default, at least 16.
+    #[arg(long, default_value_t = resources::limits().dedup_partitions())]
+    dedup_partitions: usize,
+
+    /// Keys the in-memory dedup stores remember, each forgetting its oldest keys beyond
+    /// that. One per KiB of the memory limit by default.
+    #[arg(long, default_value_t = resources::limits().dedup_capacity())]
+    dedup_capacity: usize,
+
+    /// Also drop documents with the same template skeleton as an earlier one: the lines
+    /// with words collapsed and numbers and URLs masked, which machine-translated copies
+    /// of a template page share across languages. Off by default, since distinct pages
+    /// with the same layout match as well.
+    #[arg(long)]
+    dedup_templates: bool,
+
+    /// Also find near duplicates, documents whose text mostly matches an earlier one, by
+    /// MinHash or SimHash signatures. They share the store of the exact dedup.
+    #[arg(long, value_enum)]
+    near_dedup: Option<NearDedupMethod>,
+
+    /// Leave near duplicates out, or write them with the ID of the earlier document in
+    /// `near_duplicate_of`.
+    #[arg(long, value_enum, default_value_t = NearDedupAction::default(), requires = "near_dedup")]
+    near_dedup_action: NearDedupAction,
+
+    /// Write at most this many documents per domain, counted across consumer tasks,
+    /// workers and runs in the state store or `--domain-quota-filename`. Applied after
+    /// dedup and the output filter, when a batch is committed.
+    #[arg(long)]
+    max_documents_per_domain: Option<u64>,
+
+    /// A SQLite file counting the documents per domain instead of the state store, for
+    /// runs with their own state stores writing to the same corpus.
+    #[arg(long, requires = "max_documents_per_domain")]
+    domain_quota_filename: Option<String>,
+
+    /// Redis instance owning one hash prefix range of the content and template dedup
+    /// keyspaces, given once per instance in the same order on every worker. Needs Redis 7.
+    #[cfg(feature = "redis")]
+    #[arg(long = "dedup-redis-url")]
+    dedup_redis_urls: Vec<String>,
+
+    /// Keep the content and template dedup keys in the state store, the run DB or
+    /// `--state-store`, so all workers sharing it drop each other's duplicates.
+    #[arg(long)]
+    dedup_in_state_store: bool,
+
+    /// Where the WARC records are fetched from, e.g. the fixture server of
+    /// `pipeline loadtest --serve-fixtures`. Given several times, e.g. for CloudFront,
+    /// S3 in another region and a mirror, fetches go to the fastest one that answers.
+    #[arg(long = "data-url", default_value = CC_DATA_URL)]
+    data_urls: Vec<String>,
+
+    /// How often the `--data-url`s are probed for the fastest one.
+    #[arg(long, default_value_t = 300)]
+    probe_interval_secs: u64,
+
+    #[command(flatten)]
+    queue: QueueOptions,
+
+    #[command(flatten)]
+    http: HttpOptions,
+
+    #[command(flatten)]
+    write: WriteOptions,
+
+    /// Compression of the document files: none, gzip[:level], zstd[:level], lz4 or auto,
+    /// which picks one by the CPU headroom.
+    #[arg(long, default_value_t = Compression::None)]
+    compression: Compression,
+
+    /// Compression of the Parquet files of `--metadata-only` and `--output-format parquet`,
+    /// Snappy if not given.
+    #[arg(long)]
+    metadata_compression: Option<Compression>,
+
+    /// `jsonl` writes JSON lines files per language, `parquet` Parquet files per language
+    /// with the columns id, url, timestamp, language, digest, near_duplicate_of,
+    /// token_count and text.
+    #[arg(long, value_enum, default_value_t = OutputFormat::Jsonl)]
+    output_format: OutputFormat,
+
+    /// Rows per row group of `--output-format parquet` files.
+    #[arg(long, default_value_t = 10_000)]
+    parquet_row_group_size: usize,
+
+    /// Upload the document files to `s3://<bucket>/<prefix>` once they are finished: the
+    /// Parquet files and JSONL shards when they are full and everything else at the end
+    /// of the run. Credentials, region and endpoint, e.g. of MinIO, come
+    /// from the `AWS_*` environment variables.
+    #[cfg(feature = "s3")]
+    #[arg(long)]
+    s3_url: Option<String>,
+
+    /// Object keys below the prefix of `--s3-url`. `{date}` is the UTC day of the upload
+    /// and `{shard}` the path of the file in the output directory.
+    #[cfg(feature = "s3")]
+    #[arg(long, default_value = "{crawl}/{date}/{shard}")]
+    s3_key_template: String,
+
+    /// Files larger than this are uploaded in parts of this size.
+    #[cfg(feature = "s3")]
+    #[arg(long, default_value_t = 64)]
+    s3_part_size_mib: usize,
+
+    /// Delete the local files after they are uploaded.
+    #[cfg(feature = "s3")]
+    #[arg(long)]
+    s3_delete_uploaded: bool,
+
+    /// Where the worker keeps the finished files it did not upload yet, so a restart
+    /// uploads them first. Per-task like the journal.
+    #[cfg(feature = "s3")]
+    #[arg(long, default_value = "s3_pending.json")]
+    s3_pending_filename: String,
+
+    /// Drop records whose `Last-Modified` header is more than this many years (of 365
+    /// days) before the fetch. Records without the header are kept.
+    #[arg(long)]
+    max_last_modified_age_years: Option<u64>,
+
+    /// Only keep records whose HTTP response has this header, `Name` for any value or
+    /// `Name=value`, e.g. `Content-Type=text/html`. Can be repeated, all have to match.
+    /// Checked on the start of the record as fetched, before it is decompressed in full.
+    #[arg(long = "require-header")]
+    require_headers: Vec<HeaderRule>,
+
+    /// Drop records whose HTTP response has this header, e.g. `Content-Encoding=br`. Can
+    /// be repeated, checked like `--require-header`.
+    #[arg(long = "drop-header")]
+    drop_headers: Vec<HeaderRule>,
+
+    /// Only write the documents matching this expression over `language`, `token_count`,
+    /// `quality_score`, `model_score` and `domain`, e.g.
+    /// `language == eng && token_count >= 200`. All stages still run on every document.
+    /// Can be changed through the `output_filter` tunable.
+    #[arg(long, default_value = "")]
+    output_filter: OutputFilter,
+
+    /// Only keep records fetched from an IP address in one of these CIDR ranges, one per
+    /// line.
+    #[arg(long)]
+    ip_ranges: Option<String>,
+
+    /// Drop records fetched from an IP address in one of these CIDR ranges.
+    #[arg(long)]
+    exclude_ip_ranges: Option<String>,
+
+    /// iptoasn.com style TSV database to annotate documents with the AS number, name and
+    /// country of the address they were fetched from.
+    #[arg(long)]
+    asn_db: Option<String>,
+
+    /// Never process the records whose digest or document ID is listed in this file.
+    #[arg(long)]
+    skip_list: Option<String>,
+
+    /// Only process the records whose digest or document ID is listed in this file.
+    #[arg(long)]
+    include_list: Option<String>,
+
+    /// Never process pages from the domains listed in this file, one per line, or their
+    /// subdomains.
+    #[arg(long)]
+    opt_out_domains: Option<String>,
+
+    /// Drop pages that ask not to be indexed with a robots meta tag or `X-Robots-Tag`.
+    #[arg(long)]
+    respect_robots_meta: bool,
+
+    /// Acknowledge batches whose shard and batch index the run DB already has as complete
+    /// without processing them, e.g. after the batcher was started twice by accident.
+    #[arg(long)]
+    skip_completed_batches: bool,
+
+    /// On startup, read the batches the run DB and the output manifests in `--output-dir`
+    /// have as complete, and acknowledge them without processing when they are delivered
+    /// again, e.g. after requeueing a dead letter queue that held finished batches.
+    #[arg(long)]
+    warm_start: bool,
+
+    /// Debug mode: append how long fetch, decompress, extract, filter and write took for
+    /// each document to this file.
+    #[arg(long)]
+    stage_timings_filename: Option<String>,
+
+    /// JSON file of tunables that is read again on SIGHUP, see `Tunables` for the fields.
+    #[arg(long)]
+    tunables_filename: Option<String>,
+
+    /// Score documents with this external model before writing them, e.g.
+    /// `python3 scripts/scorer_bridge.py`. The score lands in `model_score`.
+    #[arg(long)]
+    scorer_command: Option<String>,
+
+    /// Documents sent to the scorer in one request.
+    #[arg(long, default_value_t = 64)]
+    scoring_batch_size: usize,
+
+    /// Send a partial scoring batch once its first document waited this long.
+    #[arg(long, default_value_t = 500)]
+    scoring_max_latency_ms: u64,
+}
+
+/// The dedup by `key` if it is `enabled`, on the Redis instances of `--dedup-redis-url`
+/// if any are given, or in the state store with `--dedup-in-state-store`.
+async fn dedup_store(args: &Args, enabled: bool, key: DedupKey) -> Option<ContentDedup> {
+    if !enabled {
+        return None;
+    }
+    if args.dedup_in_state_store {
+        let dedup = ContentDedup::in_store(args.state.open().unwrap());
+        return Some(dedup.with_key(key));
+    }
+    #[cfg(feature = "redis")]
+    if !args.dedup_redis_urls.is_empty() {
+        let dedup = ContentDedup::redis(&args.dedup_redis_urls).await.unwrap();
+        return Some(dedup.with_key(key));
+    }
+    Some(
+        ContentDedup::in_memory(args.dedup_partitions)
+            .with_capacity(args.dedup_capacity)
+            .with_key(key),
+    )
+}
+
+/// Everything wrong with `args` and the environment, so it is reported before connecting.
+fn check_config(args: &Args) -> ConfigCheck {
+    let mut check = ConfigCheck::default();
+    args.http.check(&mut check);
+    args.queue.check(&mut check);
+    check.require(
+        args.queue.is_rabbitmq() || (args.dead_letter_after.is_none() && args.exchange.is_none()),
+        "--dead-letter-after and --exchange need --queue-backend rabbitmq",
+    );
+    check.at_least("--workers", args.workers, 1);
+    check.at_least("--max-fetch-attempts", args.max_fetch_attempts, 1);
+    if let Some(dead_letter_after) = args.dead_letter_after {
+        check.at_least("--dead-letter-after", dead_letter_after, 2);
+    }
+    check.at_least("--dedup-partitions", args.dedup_partitions, 1);
+    check.at_least("--dedup-capacity", args.dedup_capacity, 1);
+    check.at_least("--probe-interval-secs", args.probe_interval_secs, 1);
+    if let Some(max_documents) = args.max_documents_per_domain {
+        check.at_least("--max-documents-per-domain", max_documents, 1);
+    }
+    if let Some(memory_bytes) = resources::limits().memory_bytes {
+        check.require(
+            ((args.range_cache_mib as u64) << 20) <= memory_bytes / 2,
+            format!(
+                "--range-cache-mib {} is more than half of the {} MiB memory limit",
+                args.range_cache_mib,
+                memory_bytes >> 20
+            ),
+        );
+    }
+    check.at_least("--scoring-batch-size", args.scoring_batch_size, 1);
+    check.at_least("--write-buffer-kib", args.write.write_buffer_kib, 1);
+    #[cfg(feature = "extraction")]
+    check.at_least("--web-graph-every-batches", args.web_graph_every_batches, 1);
+    check.in_range("--ab-sample-rate", args.ab_sample_rate, 0.0..=1.0);
+    let any_dedup = args.dedup_content || args.dedup_templates || args.near_dedup.is_some();
+    #[cfg(feature = "redis")]
+    check.require(
+        args.dedup_redis_urls.is_empty() || any_dedup,
+        "--dedup-redis-url needs --dedup-content, --dedup-templates or --near-dedup",
+    );
+    #[cfg(feature = "redis")]
+    check.require(
+        args.dedup_redis_urls.is_empty() || !args.dedup_in_state_store,
+        "--dedup-redis-url and --dedup-in-state-store are alternatives",
+    );
+    check.require(
+        !args.dedup_in_state_store || any_dedup,
+        "--dedup-in-state-store needs --dedup-content, --dedup-templates or --near-dedup",
+    );
+    if let Some(text_bytes) = args.shard_text_bytes {
+        check.at_least("--shard-text-bytes", text_bytes, 1);
+    }
+    if let Some(documents) = args.shard_documents {
+        check.at_least("--shard-documents", documents, 1);
+    }
+    check.at_least("--parquet-row-group-size", args.parquet_row_group_size, 1);
+    #[cfg(feature = "s3")]
+    if let Some(url) = args.s3_url.as_deref() {
+        check.parses("--s3-url", S3Destination::parse(url, &args.s3_key_template));
+        check.env_var(AWS_ACCESS_KEY_ID);
+        check.env_var(AWS_SECRET_ACCESS_KEY);
+        check.at_least("--s3-part-size-mib", args.s3_part_size_mib, 5);
+        check.require(
+            !args.metadata_only,
+            "--s3-url uploads document files, --metadata-only writes none",
+        );
+    }
+    check.require(
+        args.output_format == OutputFormat::Jsonl
+            || (!args.sort_shards_by_length && args.compression == Compression::None),
+        "--sort-shards-by-length and --compression only apply to --output-format jsonl, \
+         Parquet files are compressed with --metadata-compression",
+    );
+    check.require(
+        args.ab_extractor.is_none_or(|ab| ab != args.extractor),
+        "--ab-extractor compares the extractor with itself",
+    );
+    let uses_subprocess = args.extractor == ExtractorKind::Subprocess
+        || args.ab_extractor == Some(ExtractorKind::Subprocess);
+    check.require(
+        !uses_subprocess || !args.extractor_command.trim().is_empty(),
+        "The subprocess extractor needs an --extractor-command",
+    );
+    check.require(
+        args.scorer_command
+            .as_deref()
+            .is_none_or(|command| !command.trim().is_empty()),
+        "--scorer-command is empty",
+    );
+    for (flag, path) in [
+        ("--skip-list", &args.skip_list),
+        ("--include-list", &args.include_list),
+        ("--opt-out-domains", &args.opt_out_domains),
+        ("--ip-ranges", &args.ip_ranges),
+        ("--exclude-ip-ranges", &args.exclude_ip_ranges),
+        ("--asn-db", &args.asn_db),
+        ("--tunables-filename", &args.tunables_filename),
+    ] {
+        check.file_exists(flag, path.as_deref());
+    }
+    if let Some(filename) = args.tunables_filename.as_deref() {
+        if Path::new(filename).is_file() {
+            check.parses("--tunables-filename", Tunables::read(Path::new(filename)));
+        }
+    }
+    let writes_documents = !args.metadata_only && !args.robotstxt;
+    #[cfg(feature = "extraction")]
+    let writes_documents = writes_documents && !args.media;
+    for (flag, given) in [
+        ("--dedup-content", args.dedup_content),
+        ("--dedup-templates", args.dedup_templates),
+        (
+            "--max-documents-per-domain",
+            args.max_documents_per_domain.is_some(),
+        ),
+        ("--scorer-command", args.scorer_command.is_some()),
+        ("--shard-text-bytes", args.shard_text_bytes.is_some()),
+        ("--shard-documents", args.shard_documents.is_some()),
+        ("--ab-extractor", args.ab_extractor.is_some()),
+        ("--feedback", args.feedback),
+    ] {
+        check.require(
+            writes_documents || !given,
+            format!("{flag} has no effect when the worker writes no documents"),
+        );
+    }
+    check.require(
+        !Path::new(&args.output_dir).is_file(),
+        format!("--output-dir {} is a file", args.output_dir),
+    );
+    check
+}
+
+struct AbTest {
+    extractor: Box<dyn HtmlExtractor>,
+    writer: AbWriter,
+    sample_rate: f64,
+}
+
+#[cfg(feature = "extraction")]
+struct WebGraphOutput {
+    graph: HostGraph,
+    dir: std::path::PathBuf,
+    /// `<run-id>-<worker>`, the parts are numbered after it.
+    name: String,
+    every_batches: usize,
+    /// Batches committed since the graph was last written.
+    num_batches: usize,
+}
+
+#[cfg(feature = "extraction")]
+impl WebGraphOutput {
+    fn write(&mut self) -> Result<(), anyhow::Error> {
+        let num_edges = self.graph.len();
+        if let Some(path) = self.graph.write_part(&self.dir, &self.name)? {
+            tracing::info!("Wrote {} host edges to {}", num_edges, path.display());
+        }
+        self.num_batches = 0;
+        Ok(())
+    }
+}
+
+/// Uploads of finished output files, with `--s3-url`. Finished files are recorded in
+/// the pending file before they are uploaded, so none is lost to a crash or a failed
+/// upload.
+#[cfg(feature = "s3")]
+struct S3Upload {
+    client: S3Client,
+    destination: S3Destination,
+    output_dir: PathBuf,
+    part_size: usize,
+    delete_uploaded: bool,
+    pending_filename: PathBuf,
+    pending: PendingUploads,
+    /// The crawls of the documents in the open file of each language, for `{crawl}`.
+    crawls: HashMap<String, BTreeSet<String>>,
+    /// The crawl of the current batch and the languages it wrote.
+    batch: (Option<String>, HashSet<String>),
+}
+
+#[cfg(feature = "s3")]
+impl S3Upload {
+    fn begin_batch(&mut self, crawl: Option<&str>) {
+        self.batch = (crawl.map(str::to_string), HashSet::new());
+    }
+
+    fn wrote(&mut self, language: &str) {
+        if let Some(crawl) = self.batch.0.as_ref() {
+            let crawls = self.crawls.entry(language.to_string()).or_default();
+            crawls.insert(crawl.clone());
+        }
+        self.batch.1.insert(language.to_string());
+    }
+
+    /// The path of `path` in the output directory, `{shard}` of the key.
+    fn shard(&self, path: &Path) -> String {
+        path.strip_prefix(&self.output_dir)
+            .unwrap_or(path)
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// Adds the finished `paths` to the pending uploads. Files are per language, so the
+    /// crawls written to a language since its last finished file are all in this one.
+    fn finished(&mut self, paths: Vec<PathBuf>) -> Result<(), anyhow::Error> {
+        if paths.is_empty() {
+            return Ok(());
+        }
+        for path in paths {
+            let shard = self.shard(&path);
+            let language = shard.split('/').next().unwrap_or_default();
+            let crawls = self.crawls.remove(language).unwrap_or_default();
+            // The current batch may have gone on writing to the next file already.
+            if let (Some(crawl), true) = (self.batch.0.as_ref(), self.batch.1.contains(language)) {
+                let next = self.crawls.entry(language.to_string()).or_default();
+                next.insert(crawl.clone());
+            }
+            self.pending.files.push(PendingUpload {
+                path,
+                crawl: crawl_placeholder(&crawls),
+            });
+        }
+        write_state_file(&self.pending_filename, &self.pending, true)
+    }
+
+    /// Uploads the pending files in order, recording each that is done. Whatever is left
+    /// after a failure is tried again on the next call.
+    async fn upload_pending(&mut self) -> Result<(), anyhow::Error> {
+        let date = utc_date(std::time::SystemTime::now());
+        while let Some(file) = self.pending.files.first().cloned() {
+            // Gone if it was uploaded and deleted just before a crash.
+            if file.path.exists() {
+                let key = self
+                    .destination
+                    .key(&file.crawl, &date, &self.shard(&file.path));
+                self.client
+                    .upload_file(&file.path, &self.destination.bucket, &key, self.part_size)
+                    .await?;
+                tracing::info!(
+                    "Uploaded {} to s3://{}/{}",
+                    file.path.display(),
+                    self.destination.bucket,
+                    key
+                );
+                if self.delete_uploaded {
+                    fs::remove_file(&file.path)?;
+                }
+            }
+            self.pending.files.remove(0);
+            write_state_file(&self.pending_filename, &self.pending, true)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "s3")]
+fn s3_upload(
+    args: &Args,
+    url: &str,
+    http_client: &reqwest::Client,
+    pending_filename: PathBuf,
+) -> S3Upload {
+    S3Upload {
+        client: S3Client::from_env(http_client.clone())
+            .unwrap()
+            .with_retries(args.http.backoff(), args.max_fetch_attempts),
+        destination: S3Destination::parse(url, &args.s3_key_template).unwrap(),
+        output_dir: args.output_dir.clone().into(),
+        part_size: args.s3_part_size_mib << 20,
+        delete_uploaded: args.s3_delete_uploaded,
+        pending_filename,
+        pending: PendingUploads::default(),
+        crawls: HashMap::new(),
+        batch: (None, HashSet::new()),
+    }
+}
+
+/// Uploads the files the tasks of an earlier run left pending, with any number of
+/// `--workers`, and the `recovered` Parquet files converted from its staged documents,
+/// before the tasks of this run start their own pending files.
+#[cfg(feature = "s3")]
+async fn upload_leftovers(
+    args: &Args,
+    url: &str,
+    http_client: &reqwest::Client,
+    recovered: Vec<PathBuf>,
+) -> Result<(), anyhow::Error> {
+    for filename in all_task_filenames(&args.s3_pending_filename) {
+        let Some(pending) = read_state_file::<PendingUploads>(&filename)? else {
+            continue;
+        };
+        tracing::info!(
+            "Uploading {} files left pending in {}",
+            pending.files.len(),
+            filename.display()
+        );
+        let mut upload = s3_upload(args, url, http_client, filename.clone());
+        upload.pending = pending;
+        upload.upload_pending().await?;
+        fs::remove_file(&filename)?;
+    }
+    if !recovered.is_empty() {
+        let filename = PathBuf::from(&args.s3_pending_filename);
+        let mut upload = s3_upload(args, url, http_client, filename.clone());
+        upload.finished(recovered)?;
+        upload.upload_pending().await?;
+        fs::remove_file(&filename)?;
+    }
+    Ok(())
+}
+
+struct Worker {
+    name: String,
+    extractor: Box<dyn HtmlExtractor>,
+    extractor_fallback_min_chars: usize,
+    sink: Box<dyn OutputSink>,
+    ab_test: Option<AbTest>,
+    stage_timings: Option<StageTimingsWriter>,
+    /// The output manifest, one line per committed batch, see [`CompletedBatch`].
+    manifest: RecordWriter<CompletedBatch>,
+    scorer: Option<BatchScorer>,
+    header_filter: HeaderFilter,
+    output_filter: OutputFilter,
+    /// With `--max-documents-per-domain`.
+    domain_quota: Option<DomainQuota>,
+    respect_robots_meta: bool,
+    /// Records dropped for compliance reasons that are not in the run DB yet.
+    drops: DropCounts,
+    /// Outcomes for `pipeline compare-runs` that are not in the run DB yet.
+    counters: RunCounters,
+    /// Fetched and kept captures per domain for the batcher, with `--feedback`.
+    feedback: Option<RejectionTally>,
+    robots: Option<RecordWriter<RobotsRecord>>,
+    /// Schema.org types to keep, all if empty, if structured data is extracted at all.
+    #[cfg(feature = "extraction")]
+    structured_data_types: Option<Vec<String>>,
+    #[cfg(feature = "extraction")]
+    media: Option<RecordWriter<MediaRecord>>,
+    #[cfg(feature = "extraction")]
+    web_graph: Option<WebGraphOutput>,
+    #[cfg(feature = "s3")]
+    s3_upload: Option<S3Upload>,
+    ip_filter: Arc<IpFilter>,
+    asn_db: Option<Arc<AsnDb>>,
+    /// Documents of the current batch waiting for the content or template dedup, if one
+    /// is enabled.
+    unchecked: Option<Vec<Document>>,
+    max_fetch_attempts: usize,
+}
+
+/// What the consumer tasks of one worker process share.
+struct Shared {
+    args: Args,
+    http_client: reqwest::Client,
+    selection: RecordSelection,
+    opt_out: Option<DomainList>,
+    ip_filter: Arc<IpFilter>,
+    asn_db: Option<Arc<AsnDb>>,
+    recent_batches: tokio::sync::Mutex<RecentBatches>,
+    /// Batches complete before the worker started, with `--warm-start`.
+    completed: CompletedBatches,
+    dedup: Option<ContentDedup>,
+    template_dedup: Option<ContentDedup>,
+    near_dedup: Option<ContentDedup>,
+    data_endpoints: Arc<DataEndpoints>,
+    range_cache: Option<Mutex<RangeCache>>,
+    /// One flusher for all tasks, the traffic counters are process-wide.
+    traffic: Mutex<TrafficFlusher>,
+    log_filter: LogFilterHandle,
+    /// Only with `--queue-backend rabbitmq`, each task opens its channels on it.
+    rabbit_conn: Option<lapin::Connection>,
+    /// Only with `--control-addr`.
+    #[cfg(feature = "grpc")]
+    control: Option<Arc<ControlState>>,
+}
+
+impl Shared {
+    fn flush_traffic(&self, run_db: &dyn StateStore) {
+        let deltas = self.traffic.lock().unwrap().take_deltas();
+        run_db.add_traffic(&self.args.run_id, &deltas).unwrap();
+    }
+}
+
+/// The broker side of one consumer task.
+struct Queues {
+    batches: Box<dyn QueueConsumer>,
+    /// Always drained before `batches`.
+    priority: Box<dyn QueueConsumer>,
+    /// Feedback, receipts and the batches the worker dead-letters itself.
+    producer: Box<dyn QueueProducer>,
+}
+
+impl Queues {
+    async fn connect(shared: &Shared, name: &str) -> Result<Self, anyhow::Error> {
+        let args = &shared.args;
+        let queues = match args.queue.queue_backend {
+            QueueBackend::Rabbitmq => {
+                let conn = shared.rabbit_conn.as_ref().unwrap();
+                let channel = rabbitmq_channel(conn).await?;
+                dlq::declare_queue(&channel, &args.queue_name, args.dead_letter_after).await?;
+                if let Some(exchange) = args.exchange.as_deref() {
+                    rabbitmq_declare_headers_exchange(&channel, exchange).await?;
+                    rabbitmq_bind_queue_by_headers(
+                        &channel,
+                        &args.queue_name,
+                        exchange,
+                        &args.bind_headers,
+                    )
+                    .await?;
+                }
+                if args.feedback {
+                    rabbitmq_declare_queue(&channel, &args.feedback_queue_name, Default::default())
+                        .await?;
+                }
+                dlq::declare_queue(&channel, &args.priority_queue_name, args.dead_letter_after)
+                    .await?;
+                let batches = rabbitmq_consumer(&channel, &args.queue_name, name).await?;
+                let priority = rabbitmq_consumer(
+                    &channel,
+                    &args.priority_queue_name,
+                    &format!("{name}-priority"),
+                )
+                .await?;
+                Self {
+                    batches: Box::new(RabbitConsumer(batches)),
+                    priority: Box::new(RabbitConsumer(priority)),
+                    producer: producer(args, Some(conn), &shared.http_client).await?,
+                }
+            }
+            QueueBackend::Fs => {
+                let spool = Spool::new(&args.queue.spool_dir);
+                Self {
+                    batches: Box::new(spool.consumer(&args.queue_name)?),
+                    priority: Box::new(spool.consumer(&args.priority_queue_name)?),
+                    producer: Box::new(spool),
+                }
+            }
+            #[cfg(feature = "kafka")]
+            QueueBackend::Kafka => {
+                let broker = args.queue.kafka_broker(&shared.http_client);
+                Self {
+                    batches: Box::new(broker.consumer(&args.queue_name)?),
+                    priority: Box::new(broker.consumer(&args.priority_queue_name)?),
+                    producer: Box::new(broker.producer()?),
+                }
+            }
+            #[cfg(feature = "sqs")]
+            QueueBackend::Sqs => {
+                let client = sqs_client(args, &shared.http_client)?;
+                Self {
+                    batches: Box::new(client.consumer(&args.queue_name)),
+                    priority: Box::new(client.consumer(&args.priority_queue_name)),
+                    producer: Box::new(client),
+                }
+            }
+        };
+        Ok(queues)
+    }
+}
+
+/// A producer on the queue backend of `args`, which needs `rabbit_conn` for RabbitMQ.
+async fn producer(
+    args: &Args,
+    rabbit_conn: Option<&lapin::Connection>,
+    http_client: &reqwest::Client,
+) -> Result<Box<dyn QueueProducer>, anyhow::Error> {
+    #[cfg(not(any(feature = "kafka", feature = "sqs")))]
+    let _ = http_client;
+    let producer: Box<dyn QueueProducer> = match args.queue.queue_backend {
+        QueueBackend::Rabbitmq => {
+            let conn = rabbit_conn.context("RabbitMQ is not connected")?;
+            let publisher = ReliablePublisher::new(rabbitmq_channel(conn).await?).await?;
+            Box::new(RabbitProducer::new(publisher))
+        }
+        QueueBackend::Fs => Box::new(Spool::new(&args.queue.spool_dir)),
+        #[cfg(feature = "kafka")]
+        QueueBackend::Kafka => Box::new(args.queue.kafka_broker(http_client).producer()?),
+        #[cfg(feature = "sqs")]
+        QueueBackend::Sqs => Box::new(sqs_client(args, http_client)?),
+    };
+    Ok(producer)
+}
+
+#[cfg(feature = "sqs")]
+fn sqs_client(args: &Args, http_client: &reqwest::Client) -> Result<SqsClient, anyhow::Error> {
+    Ok(SqsClient::from_env(http_client.clone())?
+        .with_retries(args.http.backoff(), args.max_fetch_attempts)
+        .with_visibility_timeout(args.queue.sqs_visibility_timeout_secs))
+}
+
+/// Sends `delivery` to the dead letter queue of `queue_name` with `code` in its
+/// headers and acks it. Without `--dead-letter-after` it is rejected instead, which
+/// drops it.
+async fn dead_letter(
+    producer: &dyn QueueProducer,
+    args: &Args,
+    queue_name: &str,
+    delivery: Box<dyn QueueDelivery>,
+    code: ErrorCode,
+) -> Result<(), anyhow::Error> {
+    if args.dead_letter_after.is_none() {
+        return delivery.reject().await;
+    }
+    let copy = dead_letter_copy(delivery.message(), code);
+    producer.publish(&dlq_name(queue_name), &copy).await?;
+    delivery.ack().await
+}
+
+/// Drops the exact and template duplicates among `documents`, and drops or flags the
+/// near duplicates, with the dedup stores of the worker.
+async fn dedup_documents(
+    shared: &Shared,
+    counters: &mut RunCounters,
+    mut documents: Vec<Document>,
+) -> Result<Vec<Document>, anyhow::Error> {
+    for (counter, duplicate, dedup) in [
+        ("dedup", "content", shared.dedup.as_ref()),
+        ("template_dedup", "template", shared.template_dedup.as_ref()),
+    ] {
+        let Some(dedup) = dedup else {
+            continue;
+        };
+        let num_documents = documents.len();
+        documents = dedup.retain_new(documents).await?;
+        let num_dropped = (num_documents - documents.len()) as u64;
+        counters.add(&format!("{counter}.checked"), num_documents as u64);
+        counters.add(&format!("{counter}.dropped"), num_dropped);
+        tracing::info!("Dropped {num_dropped} documents with duplicate {duplicate}");
+    }
+    if let Some(near_dedup) = shared.near_dedup.as_ref() {
+        let originals = near_dedup.originals(&documents).await?;
+        let num_near = originals.iter().flatten().count() as u64;
+        counters.add("near_dedup.checked", documents.len() as u64);
+        match shared.args.near_dedup_action {
+            NearDedupAction::Drop => {
+                documents = documents
+                    .into_iter()
+                    .zip(originals)
+                    .filter_map(|(document, original)| original.is_none().then_some(document))
+                    .collect();
+                counters.add("near_dedup.dropped", num_near);
+            }
+            NearDedupAction::Flag => {
+                for (document, original) in documents.iter_mut().zip(originals) {
+                    document.near_duplicate_of = original;
+                }
+                counters.add("near_dedup.flagged", num_near);
+            }
+        }
+        tracing::info!("Found {num_near} near duplicates");
+    }
+    Ok(documents)
+}
+
+/// Gives up on the batch of `delivery` by failing the consumer task, which ends the
+/// worker process, so the broker delivers the batch again. If this was its last delivery
+/// with `--dead-letter-after`, the batch is dead-lettered with `code` first, as the
+/// broker would not say why.
+async fn give_up(
+    producer: &dyn QueueProducer,
+    args: &Args,
+    queue_name: &str,
+    delivery: Box<dyn QueueDelivery>,
+    code: ErrorCode,
+    error: anyhow::Error,
+) -> ! {
+    let last = args
+        .dead_letter_after
+        .zip(delivery.previous_deliveries())
+        .is_some_and(|(max_deliveries, previous)| is_last_delivery(previous, max_deliveries));
+    if last {
+        tracing::error!("Dead-lettering batch with {}", code);
+        dead_letter(producer, args, queue_name, delivery, code)
+            .await
+            .unwrap();
+    }
+    panic!("Giving up on batch with {code}: {error:?}");
+}
+
+/// Gives each of several consumer tasks its own file, `journal.json` becomes
+/// `journal-2.json` for task 2. A single task keeps the name as it is.
+fn per_task_filename(filename: &str, index: usize, num_workers: usize) -> String {
+    if num_workers == 1 {
+        return filename.to_string();
+    }
+    let path = Path::new(filename);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{stem}-{index}.{}", ext.to_string_lossy()),
+        None => format!("{stem}-{index}"),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+/// The files next to `filename` that [`per_task_filename`] gives any number of tasks, so
+/// journals of an earlier run with more `--workers` than this one are recovered too.
+fn all_task_filenames(filename: &str) -> Vec<PathBuf> {
+    let path = Path::new(filename);
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = path
+        .extension()
+        .map_or(String::new(), |ext| format!(".{}", ext.to_string_lossy()));
+    let is_task_file = |name: &str| {
+        name.strip_prefix(&format!("{stem}-"))
+            .and_then(|rest| rest.strip_suffix(&ext))
+            .is_some_and(|index| !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()))
+    };
+    let mut filenames = vec![path.to_path_buf()];
+    if let Ok(entries) = fs::read_dir(dir) {
+        filenames.extend(
+            entries
+                .filter_map(Result::ok)
+                .filter(|entry| is_task_file(&entry.file_name().to_string_lossy()))
+                .map(|entry| dir.join(entry.file_name())),
+        );
+    }
+    filenames
+}
 
 #[tokio::main]
 async fn main() {
-    setup_tracing();
+    let args = Args::parse();
+    check_config(&args).exit_on_problems();
+    let log_filter = setup_tracing();
     tokio::task::spawn(run_metrics_server(9001));
+    tracing::info!("Running with {}", resources::limits());
 
-    let rabbit_conn = rabbitmq_connection().await.unwrap();
-    let (channel, _queue) = rabbitmq_channel_with_queue(&rabbit_conn, CC_QUEUE_NAME)
-        .await
+    args.state
+        .open()
+        .unwrap()
+        .record_config(
+            &args.run_id,
+            "worker",
+            &pipeline::state_store::command_line(),
+        )
         .unwrap();
-    let mut consumer = rabbitmq_consumer(&channel, CC_QUEUE_NAME, "worker")
-        .await
-        .unwrap();
-    while let Some(delivery) = consumer.next().await {
+    let http_client = build_http_client(&args.http).unwrap();
+    let read_list = |filename: &Option<String>| {
+        filename.as_deref().map(|filename| {
+            let list = RecordList::read(Path::new(filename)).unwrap();
+            tracing::info!("Read {} records from {}", list.len(), filename);
+            list
+        })
+    };
+    let selection = RecordSelection {
+        skip: read_list(&args.skip_list),
+        include: read_list(&args.include_list),
+    };
+    let opt_out = args.opt_out_domains.as_deref().map(|filename| {
+        let list = DomainList::read(Path::new(filename)).unwrap();
+        tracing::info!("Read {} opted out domains from {}", list.len(), filename);
+        list
+    });
+    let read_ip_ranges = |filename: &Option<String>| {
+        filename.as_deref().map(|filename| {
+            let ranges = IpRanges::read(Path::new(filename)).unwrap();
+            tracing::info!("Read {} IP ranges from {}", ranges.len(), filename);
+            ranges
+        })
+    };
+    let ip_filter = IpFilter {
+        include: read_ip_ranges(&args.ip_ranges),
+        exclude: read_ip_ranges(&args.exclude_ip_ranges),
+    };
+    let asn_db = args.asn_db.as_deref().map(|filename| {
+        let asn_db = AsnDb::read(Path::new(filename)).unwrap();
+        tracing::info!("Read {} AS ranges from {}", asn_db.len(), filename);
+        Arc::new(asn_db)
+    });
+    let recent_batches = RecentBatches::new(Duration::from_secs(args.duplicate_window_secs));
+    #[cfg(feature = "redis")]
+    let recent_batches = match args.redis_url.as_deref() {
+        Some(redis_url) => recent_batches.with_redis(redis_url).await.unwrap(),
+        None => recent_batches,
+    };
+    let mut completed = CompletedBatches::default();
+    if args.warm_start {
+        let run_db = args.state.open().unwrap();
+        for key in run_db.completed_batches().unwrap() {
+            completed.add_key(key);
+        }
+        for batch in read_manifests(Path::new(&args.output_dir)).unwrap() {
+            completed.add(batch);
+        }
+        tracing::info!("Warm start with {} completed batches", completed.len());
+    }
+    let tunables = args
+        .tunables_filename
+        .as_deref()
+        .map(|filename| watch_tunables(Path::new(filename)).unwrap());
+    for journal_filename in all_task_filenames(&args.journal_filename) {
+        if let Some(entry) = Journal::recover(&journal_filename).unwrap() {
+            tracing::warn!("Cleaned up partial outputs of batch {}", entry.batch_id);
+        }
+    }
+    let recovered = match args.output_format {
+        OutputFormat::Jsonl => Vec::new(),
+        OutputFormat::Parquet => recover_staged(
+            Path::new(&args.output_dir),
+            args.parquet_row_group_size,
+            args.metadata_compression,
+        )
+        .unwrap(),
+    };
+    if !recovered.is_empty() {
+        tracing::warn!(
+            "Converted the staged documents of earlier workers into {} files",
+            recovered.len()
+        );
+    }
+    #[cfg(feature = "s3")]
+    if let Some(url) = args.s3_url.as_deref() {
+        upload_leftovers(&args, url, &http_client, recovered)
+            .await
+            .expect("Should have been able to upload the files left pending by the last run");
+    }
+    let dedup = dedup_store(&args, args.dedup_content, DedupKey::Text).await;
+    let template_dedup = dedup_store(&args, args.dedup_templates, DedupKey::Template).await;
+    let near_dedup = match args.near_dedup {
+        Some(method) => dedup_store(&args, true, method.into()).await,
+        None => None,
+    };
+    let data_endpoints = Arc::new(DataEndpoints::new(args.data_urls.clone()));
+    if args.data_urls.len() > 1 {
+        tokio::task::spawn(data_endpoints.clone().probe_every(
+            http_client.clone(),
+            Duration::from_secs(args.probe_interval_secs),
+        ));
+    }
+    let rabbit_conn = match args.queue.is_rabbitmq() {
+        true => Some(rabbitmq_connection().await.unwrap()),
+        false => None,
+    };
+    let shared = Arc::new(Shared {
+        selection,
+        opt_out,
+        ip_filter: Arc::new(ip_filter),
+        asn_db,
+        recent_batches: tokio::sync::Mutex::new(recent_batches),
+        completed,
+        dedup,
+        template_dedup,
+        near_dedup,
+        data_endpoints,
+        range_cache: (args.range_cache_mib > 0)
+            .then(|| Mutex::new(RangeCache::new(args.range_cache_mib << 20))),
+        traffic: Mutex::new(TrafficFlusher::default()),
+        log_filter,
+        #[cfg(feature = "grpc")]
+        control: match args.control_addr {
+            Some(addr) => {
+                let producer = producer(&args, rabbit_conn.as_ref(), &http_client)
+                    .await
+                    .unwrap();
+                let control = Arc::new(ControlState::new(
+                    &format!("worker-{}", std::process::id()),
+                    args.workers.max(1),
+                    producer,
+                    &args.priority_queue_name,
+                ));
+                let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+                tracing::info!("Serving the control API on {}", addr);
+                tokio::task::spawn(run_control_server(listener, control.clone()));
+                Some(control)
+            }
+            None => None,
+        },
+        http_client,
+        rabbit_conn,
+        args,
+    });
+    let mut tasks = JoinSet::new();
+    for index in 0..shared.args.workers.max(1) {
+        tasks.spawn(consume(shared.clone(), index, tunables.clone()));
+    }
+    while let Some(result) = tasks.join_next().await {
+        // The batches a failed task did not ack stay with the connection the tasks share,
+        // only ending the process has the broker deliver them again.
+        if let Err(e) = result {
+            tracing::error!(err.msg = %e, "A consumer task failed, stopping the worker");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Runs one consumer with its own channel, extractor and output files until the
+/// channel closes.
+async fn consume(
+    shared: Arc<Shared>,
+    index: usize,
+    mut tunables: Option<watch::Receiver<Tunables>>,
+) {
+    let args = &shared.args;
+    let num_workers = args.workers.max(1);
+    let name = match num_workers {
+        1 => format!("worker-{}", std::process::id()),
+        _ => format!("worker-{}-{index}", std::process::id()),
+    };
+    let extractor = build_extractor(args.extractor, &args.extractor_command).unwrap();
+    tracing::info!("{} uses the {} extractor", name, extractor.name());
+    let ab_test = args.ab_extractor.map(|kind| AbTest {
+        extractor: build_extractor(kind, &args.extractor_command).unwrap(),
+        writer: AbWriter::create(
+            &per_task_filename(&args.ab_output_filename, index, num_workers),
+            &args.write,
+        )
+        .unwrap(),
+        sample_rate: args.ab_sample_rate,
+    });
+    let journal_filename = per_task_filename(&args.journal_filename, index, num_workers);
+    #[cfg(feature = "s3")]
+    let s3_pending_filename = per_task_filename(&args.s3_pending_filename, index, num_workers);
+    let sink: Box<dyn OutputSink> = match args.output_format {
+        OutputFormat::Jsonl => {
+            let mut router = LanguageRouter::new(
+                &args.output_dir,
+                &name,
+                Duration::from_secs(args.idle_writer_timeout_secs),
+            )
+            .with_journal(Journal::new(&journal_filename))
+            .with_compression(args.compression)
+            .with_write_options(args.write.clone());
+            if let Some(text_bytes) = args.shard_text_bytes {
+                router = router
+                    .with_shard_text_bytes(text_bytes)
+                    .with_sort_shards_by_length(args.sort_shards_by_length);
+            }
+            if let Some(documents) = args.shard_documents {
+                router = router.with_shard_documents(documents);
+            }
+            Box::new(router)
+        }
+        OutputFormat::Parquet => {
+            let mut sink = ParquetSink::new(&args.output_dir, &name)
+                .unwrap()
+                .with_journal(Journal::new(&journal_filename))
+                .with_row_group_size(args.parquet_row_group_size)
+                .with_compression(args.metadata_compression);
+            if let Some(text_bytes) = args.shard_text_bytes {
+                sink = sink.with_max_text_bytes(text_bytes);
+            }
+            if let Some(documents) = args.shard_documents {
+                sink = sink.with_max_documents(documents);
+            }
+            Box::new(sink)
+        }
+    };
+    let robots = args.robotstxt.then(|| {
+        let path = Path::new(&args.output_dir)
+            .join("robotstxt")
+            .join(format!("{name}.jsonl"));
+        RecordWriter::create(&path, &args.write).unwrap()
+    });
+    #[cfg(feature = "extraction")]
+    let media = args.media.then(|| {
+        let path = Path::new(&args.output_dir)
+            .join("media")
+            .join(format!("{name}.jsonl"));
+        RecordWriter::create(&path, &args.write).unwrap()
+    });
+    #[cfg(feature = "extraction")]
+    let web_graph = args.web_graph.then(|| WebGraphOutput {
+        graph: HostGraph::default(),
+        dir: Path::new(&args.output_dir).join("web_graph"),
+        name: format!("{}-{name}", args.run_id),
+        every_batches: args.web_graph_every_batches,
+        num_batches: 0,
+    });
+    let manifest = RecordWriter::create(
+        &manifest_path(Path::new(&args.output_dir), &name),
+        &args.write,
+    )
+    .unwrap();
+    let run_db = args.state.open().unwrap();
+    let mut worker = Worker {
+        sink,
+        manifest,
+        name,
+        extractor,
+        extractor_fallback_min_chars: args.extractor_fallback_min_chars,
+        ab_test,
+        stage_timings: args.stage_timings_filename.as_deref().map(|filename| {
+            StageTimingsWriter::create(
+                &per_task_filename(filename, index, num_workers),
+                &args.write,
+            )
+            .unwrap()
+        }),
+        scorer: args.scorer_command.as_deref().map(|command| {
+            BatchScorer::spawn(
+                command,
+                args.scoring_batch_size,
+                Duration::from_millis(args.scoring_max_latency_ms),
+            )
+            .unwrap()
+        }),
+        header_filter: HeaderFilter {
+            max_last_modified_age: args
+                .max_last_modified_age_years
+                .map(|years| Duration::from_secs(years * 365 * 86400)),
+            required: args.require_headers.clone(),
+            dropped: args.drop_headers.clone(),
+        },
+        output_filter: args.output_filter.clone(),
+        domain_quota: args.max_documents_per_domain.map(|max_documents| {
+            let store: Arc<dyn StateStore> = match args.domain_quota_filename.as_deref() {
+                Some(filename) => Arc::new(RunDb::open(Path::new(filename)).unwrap()),
+                None => run_db.clone(),
+            };
+            DomainQuota::new(store, max_documents)
+        }),
+        respect_robots_meta: args.respect_robots_meta,
+        drops: DropCounts::default(),
+        counters: RunCounters::default(),
+        feedback: args.feedback.then(RejectionTally::default),
+        robots,
+        #[cfg(feature = "extraction")]
+        structured_data_types: args
+            .structured_data
+            .then(|| args.structured_data_types.clone()),
+        #[cfg(feature = "extraction")]
+        media,
+        #[cfg(feature = "extraction")]
+        web_graph,
+        #[cfg(feature = "s3")]
+        s3_upload: args
+            .s3_url
+            .as_deref()
+            .map(|url| s3_upload(args, url, &shared.http_client, s3_pending_filename.into())),
+        ip_filter: shared.ip_filter.clone(),
+        asn_db: shared.asn_db.clone(),
+        unchecked: (shared.dedup.is_some()
+            || shared.template_dedup.is_some()
+            || shared.near_dedup.is_some())
+        .then(Vec::new),
+        max_fetch_attempts: args.max_fetch_attempts,
+    };
+    let mut queues = Queues::connect(&shared, &worker.name).await.unwrap();
+    let mut num_batches_received: usize = 0;
+    loop {
+        #[cfg(feature = "grpc")]
+        if let Some(control) = shared.control.as_ref() {
+            control.wait_until_resumed().await;
+        }
+        let (queue_name, delivery) = tokio::select! {
+            biased;
+            Some(delivery) = queues.priority.next() => (&args.priority_queue_name, delivery),
+            Some(delivery) = queues.batches.next() => (&args.queue_name, delivery),
+            else => break,
+        };
+        // Reloads only take effect between batches, so a batch runs with one set of values.
+        if let Some(tunables) = tunables.as_mut() {
+            if tunables.has_changed().unwrap_or(false) {
+                let tunables = tunables.borrow_and_update().clone();
+                worker.apply_tunables(&tunables, &shared.log_filter);
+            }
+        }
         match delivery {
             Ok(delivery) => {
-                let batch = serde_json::from_slice::<Vec<CdxEntry>>(&delivery.data);
-                tracing::info!(
-                    "Received a batch of {} entries",
-                    batch.as_ref().unwrap().len()
-                );
-                for entry in batch.unwrap() {
-                    let data = download_and_unzip(
-                        &format!("https://data.commoncrawl.org/{}", entry.metadata.filename),
-                        entry.metadata.offset,
-                        entry.metadata.length,
+                // The batch stays in the queue for a later run to pick up.
+                if let Some(reason) = run_db.check_limits(&args.run_id).unwrap() {
+                    tracing::warn!("Run reached its {}, {} stops", reason.as_str(), worker.name);
+                    run_db.mark_partial(&args.run_id, reason, None).unwrap();
+                    delivery.requeue().await.unwrap();
+                    break;
+                }
+                let message = delivery.message();
+                let batch_id = batch_id(&message.payload);
+                let header = |key: &str| message.header(key).map(str::to_string);
+                let batch = BatchEncoding::from_content_type(message.content_type.as_deref())
+                    .and_then(|encoding| match BatchManifest::from_headers(header)? {
+                        Some(manifest) => manifest.verify(&message.payload, encoding),
+                        None => encoding.decode(&message.payload),
+                    });
+                let batch_key = BatchKey::from_headers(header);
+                let receipt = loadtest::receipt(message, &worker.name);
+                let mut batch = match batch {
+                    Ok(batch) => batch,
+                    Err(e) => {
+                        // Redelivering the same bytes cannot help, so do not requeue.
+                        tracing::error!(err.msg = %e, "Rejecting corrupt batch {}", batch_id);
+                        worker.fail(ErrorCode::BatchCorrupt);
+                        worker.flush_counts(run_db.as_ref(), &args.run_id).unwrap();
+                        dead_letter(
+                            queues.producer.as_ref(),
+                            args,
+                            queue_name,
+                            delivery,
+                            ErrorCode::BatchCorrupt,
+                        )
+                        .await
+                        .unwrap();
+                        continue;
+                    }
+                };
+                tracing::info!("Received a batch of {} entries", batch.len());
+                if delivery.redelivered()
+                    && shared
+                        .recent_batches
+                        .lock()
+                        .await
+                        .contains(&batch_id)
+                        .await
+                        .unwrap()
+                {
+                    tracing::info!(
+                        "Dropping redelivery of already processed batch {}",
+                        batch_id
+                    );
+                    delivery.ack().await.unwrap();
+                    continue;
+                }
+                if shared.completed.contains(&batch_id, batch_key.as_ref()) {
+                    tracing::info!(
+                        "Skipping batch {} that was complete before the warm start",
+                        batch_id
+                    );
+                    worker.counters.add("warm_start.skipped", 1);
+                    delivery.ack().await.unwrap();
+                    continue;
+                }
+                if let Some(key) = batch_key.as_ref().filter(|_| args.skip_completed_batches) {
+                    if run_db.is_batch_complete(key).unwrap() {
+                        tracing::info!(
+                            "Skipping batch {} of {} that is already complete",
+                            key.batch_index,
+                            key.shard
+                        );
+                        delivery.ack().await.unwrap();
+                        continue;
+                    }
+                }
+                num_batches_received += 1;
+                PIPELINE_METRICS.record_consumed();
+                let num_entries = batch.len();
+                let num_taken_down = batch
+                    .iter()
+                    .filter(|entry| shared.selection.skips(entry))
+                    .count();
+                batch.retain(|entry| shared.selection.selects(entry));
+                if batch.len() < num_entries {
+                    tracing::info!(
+                        "Left out {} entries by the skip or include list",
+                        num_entries - batch.len()
+                    );
+                }
+                worker
+                    .drops
+                    .add(DropReason::Takedown, num_taken_down as u64);
+                if let Some(opt_out) = shared.opt_out.as_ref() {
+                    let num_entries = batch.len();
+                    batch.retain(|entry| !opt_out.contains(&entry.metadata.url));
+                    worker
+                        .drops
+                        .add(DropReason::OptOut, (num_entries - batch.len()) as u64);
+                }
+                if args.metadata_only {
+                    write_metadata(
+                        Path::new(&args.output_dir),
+                        &worker.name,
+                        num_batches_received,
+                        &batch,
+                        args.metadata_compression,
                     )
-                    .await
                     .unwrap();
-                    for warc_entry in warc::WarcReader::new(data.as_slice()).iter_records() {
-                        let warc_entry = warc_entry.unwrap();
-                        if warc_entry.header(WarcHeader::WarcType).unwrap() != "response" {
-                            continue;
+                    shared
+                        .recent_batches
+                        .lock()
+                        .await
+                        .insert(&batch_id)
+                        .await
+                        .unwrap();
+                    worker
+                        .mark_complete(run_db.as_ref(), &args.run_id, &batch_id, batch_key.as_ref())
+                        .unwrap();
+                    shared.flush_traffic(run_db.as_ref());
+                    worker.flush_counts(run_db.as_ref(), &args.run_id).unwrap();
+                    delivery.ack().await.unwrap();
+                    if let Some((reply_to, receipt)) = receipt {
+                        queues.producer.publish(&reply_to, &receipt).await.unwrap();
+                    }
+                    continue;
+                }
+                #[cfg(feature = "s3")]
+                if let Some(s3_upload) = worker.s3_upload.as_mut() {
+                    s3_upload.begin_batch(batch_key.as_ref().map(|key| key.crawl.as_str()));
+                }
+                let mut failure = worker
+                    .sink
+                    .begin_batch(&batch_id)
+                    .err()
+                    .map(|e| (ErrorCode::OutputIo, e));
+                for entry in batch.iter().filter(|_| failure.is_none()) {
+                    let url = shared.data_endpoints.url(&entry.metadata.filename);
+                    let mut timings = StageTimings::default();
+                    let start = Instant::now();
+                    let (filename, offset, length) = (
+                        &entry.metadata.filename,
+                        entry.metadata.offset as usize,
+                        entry.metadata.length as usize,
+                    );
+                    let cached = shared
+                        .range_cache
+                        .as_ref()
+                        .and_then(|cache| cache.lock().unwrap().get(filename, offset, length));
+                    let body = match cached {
+                        Some(body) => {
+                            worker.counters.add("range_cache.hits", 1);
+                            Ok(body)
                         }
-                        tracing::info!(
-                            "Successfully read WARC entry with URL {}",
-                            warc_entry.header(WarcHeader::TargetURI).unwrap()
-                        );
-                        let raw_content = String::from_utf8_lossy(warc_entry.body());
-                        let html_begin_index = raw_content.find("\n\n");
-                        let Some(html_begin_index) = html_begin_index else {
-                            tracing::warn!("Failed to find HTML content in WARC entry");
+                        None => shared
+                            .data_endpoints
+                            .download_range_with_retries(
+                                &shared.http_client,
+                                filename,
+                                offset,
+                                length,
+                                worker.max_fetch_attempts,
+                                &args.http.backoff(),
+                            )
+                            .await
+                            .inspect(|body| {
+                                if let Some(cache) = shared.range_cache.as_ref() {
+                                    worker.counters.add("range_cache.misses", 1);
+                                    cache.lock().unwrap().insert(filename, offset, body);
+                                }
+                            }),
+                    };
+                    if body
+                        .as_ref()
+                        .is_ok_and(|body| !worker.header_filter.admits_record(body))
+                    {
+                        tracing::info!("Dropping record by its response headers");
+                        worker.counters.add("header_filter.dropped", 1);
+                        continue;
+                    }
+                    let data = match body.and_then(|body| {
+                        timings.fetch_ms = millis(start.elapsed());
+                        PAYLOAD_SIZES.warc_record.observe(body.len() as u64);
+                        let start = Instant::now();
+                        let data = gunzip(&url, &body);
+                        timings.decompress_ms = millis(start.elapsed());
+                        data
+                    }) {
+                        Ok(data) => data,
+                        Err(e) if fetch_error(&e).is_some_and(|e| e.is_permanent()) => {
+                            tracing::warn!(err.msg = %e, "Skipping entry that cannot be fetched");
+                            let code = fetch_error(&e)
+                                .map_or(ErrorCode::FetchPermanent, ErrorCode::of_fetch);
+                            worker.counters.add("errors.fetch_permanent", 1);
+                            worker.fail(code);
+                            record_permanent_failure(
+                                &args.permanent_failures_filename,
+                                entry,
+                                code,
+                            )
+                            .unwrap();
                             continue;
-                        };
-                        tracing::debug!(
-                            "First 2000 characters of raw content: {}",
-                            &raw_content[..2000]
-                        );
-                        let content =
-                            trafilatura::extract(&raw_content[html_begin_index..]).unwrap();
-                        if let Some(content) = content {
-                            tracing::info!("Extracted content of length {}", content.len());
-                            tracing::debug!("Extracted content: {}", &content);
-                        } else {
-                            tracing::warn!("Failed to extract content from WARC entry");
+                        }
+                        Err(e) => {
+                            let code = fetch_error(&e)
+                                .map_or(ErrorCode::FetchTransient, ErrorCode::of_fetch);
+                            failure = Some((code, e.context("Failed to fetch WARC record")));
+                            break;
+                        }
+                    };
+                    if let Err(e) = worker.process_warc_record(entry, &data, timings) {
+                        failure = Some(e);
+                        break;
+                    }
+                }
+                let unchecked = worker.unchecked.as_mut().map(mem::take);
+                if let Some(mut documents) = unchecked.filter(|_| failure.is_none()) {
+                    match dedup_documents(&shared, &mut worker.counters, documents).await {
+                        Ok(kept) => documents = kept,
+                        Err(e) => {
+                            failure = Some((ErrorCode::DedupStore, e));
+                            documents = Vec::new();
                         }
                     }
+                    for document in documents {
+                        if let Err(e) = worker.emit(document) {
+                            failure = Some((ErrorCode::OutputIo, e));
+                            break;
+                        }
+                    }
+                }
+                if failure.is_none() {
+                    failure = worker
+                        .commit_batch()
+                        .err()
+                        .map(|e| (ErrorCode::OutputIo, e));
+                }
+                if let Some((code, e)) = failure {
+                    worker.fail(code);
+                    worker.flush_counts(run_db.as_ref(), &args.run_id).unwrap();
+                    give_up(
+                        queues.producer.as_ref(),
+                        args,
+                        queue_name,
+                        delivery,
+                        code,
+                        e,
+                    )
+                    .await;
+                }
+                #[cfg(feature = "s3")]
+                if let Some(s3_upload) = worker.s3_upload.as_mut() {
+                    // The batch is committed, so a failed upload is kept pending for after
+                    // the next batch instead of giving up on this one.
+                    let finished = worker.sink.take_finished_files();
+                    let uploaded = match s3_upload.finished(finished) {
+                        Ok(()) => s3_upload.upload_pending().await,
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = uploaded {
+                        tracing::error!(err.msg = %e, err.details = ?e, "Failed to upload finished files");
+                        worker.fail(ErrorCode::OutputIo);
+                    }
+                }
+                shared
+                    .recent_batches
+                    .lock()
+                    .await
+                    .insert(&batch_id)
+                    .await
+                    .unwrap();
+                worker
+                    .mark_complete(run_db.as_ref(), &args.run_id, &batch_id, batch_key.as_ref())
+                    .unwrap();
+                shared.flush_traffic(run_db.as_ref());
+                worker.flush_counts(run_db.as_ref(), &args.run_id).unwrap();
+                let summary = worker
+                    .feedback
+                    .as_mut()
+                    .and_then(|feedback| feedback.take(&args.run_id, &worker.name));
+                if let Some(summary) = summary {
+                    let message = QueueMessage::new(serde_json::to_vec(&summary).unwrap())
+                        .with_content_type("application/json");
+                    queues
+                        .producer
+                        .publish(&args.feedback_queue_name, &message)
+                        .await
+                        .unwrap();
+                }
+                delivery.ack().await.unwrap();
+                #[cfg(feature = "grpc")]
+                if let Some(control) = shared.control.as_ref() {
+                    control.batch_completed();
+                }
+                if let Some((reply_to, receipt)) = receipt {
+                    queues.producer.publish(&reply_to, &receipt).await.unwrap();
+                }
+            }
+            Err(e) => {
+                tracing::warn!(err.msg = %e, err.details = ?e, "Failed to receive message from the broker. Reconnecting.");
+                continue;
+            }
+        }
+    }
+    #[cfg(feature = "extraction")]
+    if let Some(web_graph) = worker.web_graph.as_mut() {
+        web_graph.write().unwrap();
+    }
+    #[cfg(feature = "s3")]
+    if let Some(s3_upload) = worker.s3_upload.as_mut() {
+        let finished = worker
+            .sink
+            .finish()
+            .and_then(|()| s3_upload.finished(worker.sink.take_finished_files()));
+        if let Err(e) = finished {
+            tracing::error!(err.msg = %e, err.details = ?e, "Failed to finish the output files");
+        } else if let Err(e) = s3_upload.upload_pending().await {
+            tracing::error!(
+                err.msg = %e,
+                "Failed to upload finished files, they are uploaded on the next start"
+            );
+        }
+    }
+}
+
+fn write_metadata(
+    output_dir: &Path,
+    name: &str,
+    batch_number: usize,
+    batch: &[CdxEntry],
+    compression: Option<Compression>,
+) -> Result<(), anyhow::Error> {
+    let dir = output_dir.join("metadata");
+    fs::create_dir_all(&dir)?;
+    let records = batch.iter().map(MetadataRecord::from).collect::<Vec<_>>();
+    let path = dir.join(format!("{name}-{batch_number:06}.parquet"));
+    write_parquet(&path, &records, records.len(), compression)?;
+    tracing::info!(
+        "Wrote metadata of {} entries to {}",
+        records.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+/// A line of `--permanent-failures-filename`, the CDX entry with the code of the failure.
+#[derive(Serialize)]
+struct PermanentFailure<'a> {
+    #[serde(flatten)]
+    entry: &'a CdxEntry,
+    error_code: &'static str,
+}
+
+fn record_permanent_failure(
+    filename: &str,
+    entry: &CdxEntry,
+    code: ErrorCode,
+) -> Result<(), anyhow::Error> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(filename)?;
+    let failure = PermanentFailure {
+        entry,
+        error_code: code.as_str(),
+    };
+    serde_json::to_writer(&mut file, &failure)?;
+    writeln!(file)?;
+    Ok(())
+}
+
+impl Worker {
+    fn apply_tunables(&mut self, tunables: &Tunables, log_filter: &LogFilterHandle) {
+        if let Some(directives) = tunables.log_filter.as_deref() {
+            if let Err(e) = log_filter.set(directives) {
+                tracing::warn!(err.msg = %e, "Ignoring invalid log filter");
+            }
+        }
+        if let Some(sample_rate) = tunables.ab_sample_rate {
+            if let Some(ab_test) = self.ab_test.as_mut() {
+                ab_test.sample_rate = sample_rate;
+            }
+        }
+        if let Some(max_fetch_attempts) = tunables.max_fetch_attempts {
+            self.max_fetch_attempts = max_fetch_attempts;
+        }
+        if let Some(secs) = tunables.idle_writer_timeout_secs {
+            self.sink.set_idle_timeout(Duration::from_secs(secs));
+        }
+        if let Some(expression) = tunables.output_filter.as_deref() {
+            match expression.parse() {
+                Ok(output_filter) => self.output_filter = output_filter,
+                Err(e) => tracing::warn!(err.msg = %e, "Ignoring invalid output filter"),
+            }
+        }
+        tracing::info!("Applied tunables {:?}", tunables);
+    }
+
+    /// Writes `document`, through the scorer if there is one.
+    fn emit(&mut self, document: Document) -> Result<(), anyhow::Error> {
+        match self.scorer.as_mut() {
+            Some(scorer) => {
+                for document in scorer.push(document)? {
+                    self.write_output(&document)?;
+                }
+            }
+            None => self.write_output(&document)?,
+        }
+        Ok(())
+    }
+
+    /// Writes `document` to its shard unless the output filter drops it. With a domain
+    /// quota, it is held back until the batch is committed.
+    fn write_output(&mut self, document: &Document) -> Result<(), anyhow::Error> {
+        if !self.output_filter.matches(document) {
+            tracing::debug!("Output filter dropped document {}", document.id);
+            self.counters.add("output_filter.dropped", 1);
+        } else if let Some(quota) = self.domain_quota.as_mut() {
+            quota.push(document.clone());
+        } else {
+            self.write_admitted(document)?;
+        }
+        Ok(())
+    }
+
+    fn write_admitted(&mut self, document: &Document) -> Result<(), anyhow::Error> {
+        self.sink.write(document)?;
+        #[cfg(feature = "s3")]
+        if let Some(s3_upload) = self.s3_upload.as_mut() {
+            s3_upload.wrote(&document.language);
+        }
+        if let Some(feedback) = self.feedback.as_mut() {
+            feedback.kept(&document.url);
+        }
+        self.counters
+            .add(&format!("documents.{}", document.language), 1);
+        Ok(())
+    }
+
+    /// Stops writing stage timings, which are only for debugging, instead of failing batches.
+    fn disable_stage_timings(&mut self, error: anyhow::Error) {
+        tracing::error!(
+            err.msg = %error,
+            "Failed to write stage timings, no longer writing them"
+        );
+        self.stage_timings = None;
+    }
+
+    /// Counts a failure under its code, `failures.<code>` in the run DB.
+    fn fail(&mut self, code: ErrorCode) {
+        self.counters.add(&code.counter(), 1);
+    }
+
+    /// Adds the compliance drops and outcome counters since the last call to the run DB.
+    fn flush_counts(&mut self, run_db: &dyn StateStore, run_id: &str) -> Result<(), anyhow::Error> {
+        run_db.add_drops(run_id, &self.drops.take())?;
+        run_db.add_counters(run_id, &self.counters.take())?;
+        Ok(())
+    }
+
+    /// Records the committed batch in the run DB, if it has a key, and in the manifest.
+    fn mark_complete(
+        &mut self,
+        run_db: &dyn StateStore,
+        run_id: &str,
+        batch_id: &str,
+        key: Option<&BatchKey>,
+    ) -> Result<(), anyhow::Error> {
+        if let Some(key) = key {
+            run_db.mark_batch_complete(key, run_id)?;
+        }
+        self.manifest.write(&CompletedBatch::new(batch_id, key))?;
+        self.manifest.flush()
+    }
+
+    fn commit_batch(&mut self) -> Result<(), anyhow::Error> {
+        if let Some(scorer) = self.scorer.as_mut() {
+            for document in scorer.finish()? {
+                self.write_output(&document)?;
+            }
+        }
+        if let Some(quota) = self.domain_quota.as_mut() {
+            for (document, admitted) in quota.commit()? {
+                if admitted {
+                    self.write_admitted(&document)?;
+                } else {
+                    tracing::debug!("Domain quota dropped document {}", document.id);
+                    self.counters.add("domain_quota.dropped", 1);
                 }
-                delivery.ack(BasicAckOptions::default()).await.unwrap();
             }
+        }
+        self.sink.commit_batch()?;
+        if let Some(ab_test) = self.ab_test.as_mut() {
+            ab_test.writer.flush()?;
+        }
+        if let Some(stage_timings) = self.stage_timings.as_mut() {
+            if let Err(e) = stage_timings.flush() {
+                self.disable_stage_timings(e);
+            }
+        }
+        if let Some(robots) = self.robots.as_mut() {
+            robots.flush()?;
+        }
+        #[cfg(feature = "extraction")]
+        if let Some(media) = self.media.as_mut() {
+            media.flush()?;
+        }
+        #[cfg(feature = "extraction")]
+        if let Some(web_graph) = self.web_graph.as_mut() {
+            web_graph.num_batches += 1;
+            if web_graph.num_batches >= web_graph.every_batches {
+                web_graph.write()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Extracts and writes the documents of a WARC record. Records that cannot be used are
+    /// counted and skipped, while an error means the batch has to be given up.
+    fn process_warc_record(
+        &mut self,
+        entry: &CdxEntry,
+        data: &[u8],
+        timings: StageTimings,
+    ) -> Result<(), (ErrorCode, anyhow::Error)> {
+        let id = document_id(
+            entry.metadata.crawl().unwrap_or_default(),
+            entry.metadata.digest.as_deref().unwrap_or_default(),
+            &entry.metadata.url,
+        );
+        let _span = tracing::info_span!("document", document.id = %id).entered();
+        if let Some(feedback) = self.feedback.as_mut() {
+            feedback.fetched(&entry.metadata.url);
+        }
+        let responses = match parse_warc_responses(data) {
+            Ok(responses) => responses,
             Err(e) => {
-                tracing::warn!(err.msg = %e, err.details = ?e, "Failed to receive message from RabbitMQ. Reconnecting.");
+                tracing::warn!(err.msg = %e, "Failed to parse WARC record");
+                self.counters.add("errors.warc_parse", 1);
+                self.fail(ErrorCode::WarcParse);
+                return Ok(());
+            }
+        };
+        for response in responses {
+            tracing::info!(
+                "Successfully read WARC entry with URL {}",
+                response.target_uri.as_deref().unwrap_or_default()
+            );
+            let ip = response
+                .ip_address
+                .as_deref()
+                .and_then(|ip| ip.parse::<IpAddr>().ok());
+            if !self.ip_filter.matches(ip) {
+                tracing::info!(
+                    "Dropping record fetched from {}",
+                    response.ip_address.as_deref().unwrap_or("unknown address")
+                );
                 continue;
             }
+            let provenance = ip.map(|ip| match self.asn_db.as_ref() {
+                Some(asn_db) => asn_db.annotate(ip),
+                None => Provenance {
+                    ip: ip.to_string(),
+                    ..Default::default()
+                },
+            });
+            let headers = ResponseHeaders::parse(&response.http_headers);
+            if !self.header_filter.matches(&headers, unix_now()) {
+                tracing::info!(
+                    "Dropping record last modified {}",
+                    headers.last_modified.as_deref().unwrap_or_default()
+                );
+                continue;
+            }
+            let http_body = &response.http_body[..];
+            if let Some(robots) = self.robots.as_mut() {
+                let (groups, sitemaps) = parse_robotstxt(&String::from_utf8_lossy(http_body));
+                let record = RobotsRecord {
+                    host: url::Url::parse(&entry.metadata.url)
+                        .ok()
+                        .and_then(|url| url.host_str().map(str::to_string))
+                        .unwrap_or_default(),
+                    url: entry.metadata.url.clone(),
+                    timestamp: entry.timestamp.clone(),
+                    groups,
+                    sitemaps,
+                };
+                robots
+                    .write(&record)
+                    .map_err(|e| (ErrorCode::OutputIo, e))?;
+                continue;
+            }
+            let mut timings = StageTimings {
+                id: id.clone(),
+                url: entry.metadata.url.clone(),
+                mime: entry.metadata.mime_detected.clone(),
+                ..timings.clone()
+            };
+            let start = Instant::now();
+            let kind = match entry
+                .metadata
+                .mime_detected
+                .as_deref()
+                .map(ContentKind::from_mime)
+            {
+                Some(kind) if kind != ContentKind::Unknown => kind,
+                _ => sniff(http_body),
+            };
+            let body = match kind {
+                ContentKind::Html | ContentKind::Text => decode_body(
+                    http_body,
+                    headers.content_type.as_deref(),
+                    entry.metadata.charset.as_deref(),
+                ),
+                _ => Default::default(),
+            };
+            let mut filter_time = start.elapsed();
+            if self.respect_robots_meta {
+                let html = match kind {
+                    ContentKind::Html => &body[..],
+                    _ => "",
+                };
+                let x_robots_tag = http_header(&response.http_headers, "X-Robots-Tag");
+                if is_noindex(html, x_robots_tag.as_deref()) {
+                    tracing::info!("Dropping page that asks not to be indexed");
+                    self.drops.add(DropReason::RobotsMeta, 1);
+                    continue;
+                }
+            }
+            #[cfg(feature = "extraction")]
+            if let Some(web_graph) = self.web_graph.as_mut() {
+                if kind == ContentKind::Html {
+                    web_graph.graph.add_page(&entry.metadata.url, &body);
+                }
+            }
+            #[cfg(feature = "extraction")]
+            if let Some(media) = self.media.as_mut() {
+                if kind == ContentKind::Html {
+                    for item in extract_media(&body, &entry.metadata.url) {
+                        let record = MediaRecord {
+                            kind: item.kind,
+                            src: item.src,
+                            alt: item.alt,
+                            context: item.context,
+                            page_url: entry.metadata.url.clone(),
+                            page_id: id.clone(),
+                            timestamp: entry.timestamp.clone(),
+                        };
+                        media.write(&record).map_err(|e| (ErrorCode::OutputIo, e))?;
+                    }
+                }
+                continue;
+            }
+            #[cfg(feature = "extraction")]
+            let structured_data = match (self.structured_data_types.as_ref(), kind) {
+                (Some(types), ContentKind::Html) => {
+                    Some(extract_structured_data(&body, types)).filter(|items| !items.is_empty())
+                }
+                _ => None,
+            };
+            #[cfg(not(feature = "extraction"))]
+            let structured_data = None;
+            let content = match kind {
+                ContentKind::Html => {
+                    let start = Instant::now();
+                    let extraction = match extract_with_fallback(
+                        self.extractor.as_ref(),
+                        &body,
+                        self.extractor_fallback_min_chars,
+                    ) {
+                        Ok(extraction) => extraction,
+                        Err(e) => {
+                            tracing::warn!(err.msg = %e, "Failed to extract content");
+                            self.counters.add("errors.extract_failed", 1);
+                            self.fail(ErrorCode::ExtractFailed);
+                            continue;
+                        }
+                    };
+                    timings.extract_ms = millis(start.elapsed());
+                    if extraction
+                        .as_ref()
+                        .is_some_and(|(_, name)| *name != self.extractor.name())
+                    {
+                        self.counters.add("extract.fallback", 1);
+                    }
+                    let (content, extractor) = extraction.unzip();
+                    // The comparison is only a diagnostic, so its errors do not fail the batch.
+                    if let Err(e) = self.compare_extractors(&id, entry, &body, &content) {
+                        tracing::warn!(err.msg = %e, "Failed to compare the extractors");
+                        self.counters.add("ab_test.failed", 1);
+                    }
+                    content.map(|content| (content, extractor))
+                }
+                ContentKind::Text => Some((body.into_owned(), None)),
+                _ => {
+                    tracing::debug!("Skipping WARC entry with content kind {:?}", kind);
+                    continue;
+                }
+            };
+            if let Some((content, extractor)) = content {
+                tracing::info!("Extracted content of length {}", content.len());
+                PAYLOAD_SIZES
+                    .extracted_text
+                    .observe(content.chars().count() as u64);
+                tracing::debug!("Extracted content: {}", &content);
+                let start = Instant::now();
+                let document = Document {
+                    id: id.clone(),
+                    url: entry.metadata.url.clone(),
+                    timestamp: entry.timestamp.clone(),
+                    language: detect_language(&content, entry.metadata.languages.as_deref()),
+                    token_count: token_count(&content),
+                    quality_score: quality_score(&content),
+                    model_score: None,
+                    headers: Some(headers),
+                    provenance,
+                    structured_data,
+                    extractor: extractor.map(str::to_string),
+                    digest: entry.metadata.digest.clone(),
+                    near_duplicate_of: None,
+                    text: content,
+                };
+                filter_time += start.elapsed();
+                timings.filter_ms = millis(filter_time);
+                let start = Instant::now();
+                match self.unchecked.as_mut() {
+                    Some(unchecked) => unchecked.push(document),
+                    None => self.emit(document).map_err(|e| (ErrorCode::OutputIo, e))?,
+                }
+                timings.write_ms = millis(start.elapsed());
+                if let Some(stage_timings) = self.stage_timings.as_mut() {
+                    if let Err(e) = stage_timings.write(&timings) {
+                        self.disable_stage_timings(e);
+                    }
+                }
+            } else {
+                tracing::warn!("Failed to extract content from WARC entry");
+                self.counters.add("errors.extract_empty", 1);
+                self.fail(ErrorCode::ExtractEmpty);
+            }
+        }
+        Ok(())
+    }
+
+    fn compare_extractors(
+        &mut self,
+        id: &str,
+        entry: &CdxEntry,
+        html: &str,
+        content: &Option<String>,
+    ) -> Result<(), anyhow::Error> {
+        let Some(ab_test) = self.ab_test.as_mut() else {
+            return Ok(());
+        };
+        if !is_sampled(id, ab_test.sample_rate) {
+            return Ok(());
         }
+        let comparison = AbComparison::new(
+            id,
+            &entry.metadata.url,
+            (self.extractor.name(), content.clone()),
+            (ab_test.extractor.name(), ab_test.extractor.extract(html)?),
+        );
+        ab_test.writer.write(&comparison)
     }
 }
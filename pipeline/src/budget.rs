@@ -0,0 +1,121 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+
+/// Limits of a run that every process of it enforces, stored in the run DB by the batcher.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RunLimits {
+    /// Seconds since the Unix epoch.
+    pub deadline_unix: Option<u64>,
+    /// Index, WARC and index API bytes of the whole run, as summed up in the `cost` view.
+    pub max_cost_bytes: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Deadline,
+    Budget,
+}
+
+impl StopReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StopReason::Deadline => "deadline",
+            StopReason::Budget => "budget",
+        }
+    }
+}
+
+impl RunLimits {
+    /// Limits for a run starting now with `deadline` to go.
+    pub fn new(deadline: Option<Duration>, max_cost_bytes: Option<u64>) -> Self {
+        Self {
+            deadline_unix: deadline.map(|deadline| unix_now() + deadline.as_secs()),
+            max_cost_bytes,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deadline_unix.is_none() && self.max_cost_bytes.is_none()
+    }
+
+    /// Why the run has to stop at `now_unix` after `cost_bytes`, if it has to. The
+    /// deadline wins if both are reached.
+    pub fn exceeded(&self, now_unix: u64, cost_bytes: u64) -> Option<StopReason> {
+        if self
+            .deadline_unix
+            .is_some_and(|deadline| now_unix >= deadline)
+        {
+            Some(StopReason::Deadline)
+        } else if self.max_cost_bytes.is_some_and(|max| cost_bytes >= max) {
+            Some(StopReason::Budget)
+        } else {
+            None
+        }
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Days since 1970-01-01 as year, month and day, after Howard Hinnant's `civil_from_days`.
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Parses durations like `90s`, `45m`, `12h` or `2d`, a plain number is seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, anyhow::Error> {
+    let s = s.trim();
+    let (number, unit_secs) = match s.char_indices().last() {
+        Some((i, 's')) => (&s[..i], 1),
+        Some((i, 'm')) => (&s[..i], 60),
+        Some((i, 'h')) => (&s[..i], 3600),
+        Some((i, 'd')) => (&s[..i], 86400),
+        _ => (s, 1),
+    };
+    let number = number
+        .parse::<u64>()
+        .with_context(|| format!("Invalid duration {s}, expected e.g. 12h"))?;
+    Ok(Duration::from_secs(number * unit_secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::budget::{parse_duration, RunLimits, StopReason};
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("12h").unwrap(), Duration::from_secs(43200));
+        assert_eq!(parse_duration("45m").unwrap(), Duration::from_secs(2700));
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("12w").is_err());
+    }
+
+    #[test]
+    fn deadline_wins_over_budget() {
+        let limits = RunLimits {
+            deadline_unix: Some(1000),
+            max_cost_bytes: Some(100),
+        };
+        assert_eq!(limits.exceeded(999, 99), None);
+        assert_eq!(limits.exceeded(999, 100), Some(StopReason::Budget));
+        assert_eq!(limits.exceeded(1000, 100), Some(StopReason::Deadline));
+        assert_eq!(RunLimits::default().exceeded(u64::MAX, u64::MAX), None);
+    }
+}
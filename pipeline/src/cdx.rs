@@ -1,60 +1,937 @@
-use std::io::Read;
+use std::{
+    borrow::Cow,
+    io::Read,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
 
-use autometrics::autometrics;
+use anyhow::Context;
+use async_compression::tokio::bufread::GzipDecoder;
+use futures_util::{stream, Stream, StreamExt};
 use serde::{Deserialize, Serialize};
 use serde_aux::prelude::deserialize_number_from_string;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, BufReader},
+    sync::OwnedSemaphorePermit,
+};
+use tokio_util::io::StreamReader;
 
-#[derive(Debug, Deserialize, Serialize)]
+use crate::{
+    histogram::PAYLOAD_SIZES,
+    metrics::{ExportMetrics, HttpErrorKind, PrometheusText, PIPELINE_METRICS},
+    rate_limit::{send_limited, SendError},
+    retry::{retry_after, Backoff},
+    traffic::{TrafficKind, TRAFFIC},
+};
+
+pub const CC_DATA_URL: &str = "https://data.commoncrawl.org";
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct CdxMetadata {
     pub url: String,
+    pub mime: Option<String>,
+    #[serde(rename = "mime-detected")]
+    pub mime_detected: Option<String>,
+    /// Common Crawl writes the numbers as strings, batches built by the batcher have them
+    /// as numbers. Both are read.
     #[serde(deserialize_with = "deserialize_number_from_string")]
-    pub status: usize,
+    pub status: u16,
+    /// Of the compressed WARC record, in bytes.
     #[serde(deserialize_with = "deserialize_number_from_string")]
-    pub length: usize,
+    pub length: u64,
     #[serde(deserialize_with = "deserialize_number_from_string")]
-    pub offset: usize,
+    pub offset: u64,
     pub filename: String,
+    pub digest: Option<String>,
+    /// The charset the crawler detected, e.g. `UTF-8`.
+    pub charset: Option<String>,
     pub languages: Option<String>,
+    pub redirect: Option<String>,
+    /// Why the payload was cut off, e.g. `length` for pages over the crawler's size limit.
+    pub truncated: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct CdxEntry {
     pub surt_url: String,
     pub timestamp: String,
     pub metadata: CdxMetadata,
 }
 
-#[autometrics]
-pub async fn download_and_unzip(
+impl CdxMetadata {
+    /// The crawl this capture belongs to, taken from the WARC path
+    /// (`crawl-data/CC-MAIN-2024-30/segments/...`).
+    pub fn crawl(&self) -> Option<&str> {
+        self.filename
+            .strip_prefix("crawl-data/")?
+            .split('/')
+            .next()
+            .filter(|crawl| !crawl.is_empty())
+    }
+}
+
+/// The path of a CDX file within the Common Crawl bucket.
+pub fn cdx_chunk_path(crawl: &str, cdx_filename: &str) -> String {
+    format!("cc-index/collections/{crawl}/indexes/{cdx_filename}")
+}
+
+pub fn cdx_chunk_url(crawl: &str, cdx_filename: &str) -> String {
+    format!("{CC_DATA_URL}/{}", cdx_chunk_path(crawl, cdx_filename))
+}
+
+/// Why a range request to Common Crawl failed, and whether trying again can help.
+#[derive(Debug)]
+pub enum FetchError {
+    /// The object is gone or the request can never succeed (404, 410, other 4xx, corrupt data).
+    /// `status` is the one of the response, if there was one.
+    Permanent {
+        url: String,
+        reason: String,
+        status: Option<u16>,
+    },
+    /// The server or the network had a hiccup (5xx, 429, timeouts), a retry may succeed,
+    /// not before `retry_after` if the server said so.
+    Transient {
+        url: String,
+        reason: String,
+        status: Option<u16>,
+        retry_after: Option<Duration>,
+    },
+}
+
+impl FetchError {
+    pub fn from_status(url: &str, status: reqwest::StatusCode) -> Self {
+        let url = url.to_string();
+        let reason = status.to_string();
+        if status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            FetchError::Transient {
+                url,
+                reason,
+                status: Some(status.as_u16()),
+                retry_after: None,
+            }
+        } else {
+            FetchError::Permanent {
+                url,
+                reason,
+                status: Some(status.as_u16()),
+            }
+        }
+    }
+
+    /// Like [`FetchError::from_status`], taking the `Retry-After` of `response` into account.
+    pub fn from_response(url: &str, response: &reqwest::Response) -> Self {
+        match FetchError::from_status(url, response.status()) {
+            FetchError::Transient {
+                url,
+                reason,
+                status,
+                ..
+            } => FetchError::Transient {
+                url,
+                reason,
+                status,
+                retry_after: retry_after(response),
+            },
+            permanent => permanent,
+        }
+    }
+
+    pub fn is_permanent(&self) -> bool {
+        matches!(self, FetchError::Permanent { .. })
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            FetchError::Transient { retry_after, .. } => *retry_after,
+            FetchError::Permanent { .. } => None,
+        }
+    }
+
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            FetchError::Permanent { status, .. } | FetchError::Transient { status, .. } => *status,
+        }
+    }
+
+    fn transient(url: &str, e: reqwest::Error) -> Self {
+        FetchError::Transient {
+            url: url.to_string(),
+            reason: e.to_string(),
+            status: None,
+            retry_after: None,
+        }
+    }
+}
+
+impl std::fmt::Display for FetchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FetchError::Permanent { url, reason, .. } => {
+                write!(f, "Permanently failed to fetch {url}: {reason}")
+            }
+            FetchError::Transient { url, reason, .. } => {
+                write!(f, "Transiently failed to fetch {url}: {reason}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for FetchError {}
+
+/// Returns the [`FetchError`] inside `err`, if the error came from a fetch.
+pub fn fetch_error(err: &anyhow::Error) -> Option<&FetchError> {
+    err.downcast_ref::<FetchError>()
+}
+
+/// A request to `url` that got no answer or broke off, counted on `/metrics`.
+fn network_error(url: &str, e: reqwest::Error) -> FetchError {
+    PIPELINE_METRICS.record_http_error(HttpErrorKind::Network);
+    FetchError::transient(url, e)
+}
+
+/// Sends a range request for `length` bytes at `offset` of `url`, and fails unless it is
+/// answered with the partial content. Failures are recorded as requests without bytes.
+async fn request_range(
+    client: &reqwest::Client,
     url: &str,
     offset: usize,
     length: usize,
-) -> Result<Vec<u8>, anyhow::Error> {
-    let client = reqwest::Client::new();
-    let res = client
+) -> Result<(reqwest::Response, Option<OwnedSemaphorePermit>), anyhow::Error> {
+    if length == 0 {
+        return Err(FetchError::Permanent {
+            url: url.to_string(),
+            reason: format!("empty range at {offset}"),
+            status: None,
+        }
+        .into());
+    }
+    let request = client
         .get(url)
-        .header("Range", format!("bytes={}-{}", offset, offset + length - 1))
-        .send()
-        .await
-        .unwrap();
+        .header("Range", format!("bytes={}-{}", offset, offset + length - 1));
+    let (res, permit) = send_limited(request).await.map_err(|e| match e {
+        SendError::Request(e) => network_error(url, e),
+        SendError::Limiter(_) => FetchError::Transient {
+            url: url.to_string(),
+            reason: e.to_string(),
+            status: None,
+            retry_after: None,
+        },
+    })?;
     match res.status() {
-        reqwest::StatusCode::PARTIAL_CONTENT => {
-            let body = res.bytes().await.unwrap();
-            tracing::info!(
-                "Successfully fetched the URL {} from {} to {}",
-                url,
-                offset,
-                offset + length - 1
+        reqwest::StatusCode::PARTIAL_CONTENT => Ok((res, permit)),
+        _ => {
+            TRAFFIC.record_request(TrafficKind::of_data_url(url), 0);
+            PIPELINE_METRICS.record_http_error(HttpErrorKind::of_status(res.status()));
+            Err(FetchError::from_response(url, &res).into())
+        }
+    }
+}
+
+/// Fetches `length` bytes at `offset` of `url` with a range request, as they are stored.
+#[cfg_attr(feature = "metrics", autometrics::autometrics)]
+pub async fn download_range(
+    client: &reqwest::Client,
+    url: &str,
+    offset: usize,
+    length: usize,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let (res, _permit) = request_range(client, url, offset, length).await?;
+    let body = res.bytes().await.map_err(|e| network_error(url, e))?;
+    TRAFFIC.record_request(TrafficKind::of_data_url(url), body.len() as u64);
+    tracing::info!(
+        "Successfully fetched the URL {} from {} to {}",
+        url,
+        offset,
+        offset + length - 1
+    );
+    Ok(body.into())
+}
+
+/// Decompresses gzip data fetched from `url`, all members of it if there are several in
+/// a row, as a plain `GzDecoder` would silently stop after the first one. Corrupt data
+/// is a permanent [`FetchError`], fetching the same bytes again would not help.
+pub fn gunzip(url: &str, body: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    let mut decoder = flate2::read::MultiGzDecoder::new(body);
+    let mut buffer = Vec::new();
+    decoder
+        .read_to_end(&mut buffer)
+        .map_err(|e| FetchError::Permanent {
+            url: url.to_string(),
+            reason: format!("failed to decompress: {e}"),
+            status: None,
+        })?;
+    Ok(buffer)
+}
+
+/// Like [`download_and_unzip`] followed by [`decode_lines`], but decompresses the body
+/// while it arrives and yields one line at a time, so memory stays bounded by the longest
+/// line however large the range is. A connection that breaks off midway ends the stream
+/// with a transient [`FetchError`].
+pub async fn download_and_unzip_lines(
+    client: &reqwest::Client,
+    url: &str,
+    offset: usize,
+    length: usize,
+    policy: Utf8Policy,
+) -> Result<impl Stream<Item = Result<(usize, String), anyhow::Error>>, anyhow::Error> {
+    let (res, permit) = request_range(client, url, offset, length).await?;
+    let kind = TrafficKind::of_data_url(url);
+    TRAFFIC.record_request(kind, 0);
+    tracing::info!(
+        "Streaming the URL {} from {} to {}",
+        url,
+        offset,
+        offset + length - 1
+    );
+    let source = url.to_string();
+    // The request stays open, and counts against the connection limit, while it streams.
+    let body = res.bytes_stream().map(move |chunk| {
+        let _permit = &permit;
+        let chunk = chunk.map_err(|e| std::io::Error::other(network_error(&source, e)))?;
+        TRAFFIC.record_bytes(kind, chunk.len() as u64);
+        Ok::<_, std::io::Error>(chunk)
+    });
+    Ok(unzip_lines(url, StreamReader::new(body), policy))
+}
+
+/// The lines of the gzip data read from `reader`, decoded with `policy`, each with its
+/// line number counting from 1, which also counts the empty and skipped lines. Read errors
+/// that are not [`FetchError`]s mean the data is corrupt, which is permanent as in
+/// [`gunzip`].
+pub fn unzip_lines(
+    url: &str,
+    reader: impl AsyncBufRead + Unpin,
+    policy: Utf8Policy,
+) -> impl Stream<Item = Result<(usize, String), anyhow::Error>> {
+    let mut decoder = GzipDecoder::new(reader);
+    decoder.multiple_members(true);
+    let url = url.to_string();
+    let state = (Some(BufReader::new(decoder)), Vec::new(), 0);
+    stream::unfold(state, move |(reader, mut line, mut index)| {
+        let url = url.clone();
+        async move {
+            let mut reader = reader?;
+            loop {
+                line.clear();
+                match reader.read_until(b'\n', &mut line).await {
+                    Ok(0) => return None,
+                    Ok(_) => {
+                        index += 1;
+                        let line_without_newline = line.strip_suffix(b"\n").unwrap_or(&line);
+                        let decoded = decode_line(index, line_without_newline, policy)
+                            .map(|decoded| decoded.map(|line| (index, line.into_owned())));
+                        if let Some(decoded) = decoded {
+                            return Some((decoded, (Some(reader), line, index)));
+                        }
+                    }
+                    Err(e) => {
+                        let error = match e.into_inner().map(|e| e.downcast::<FetchError>()) {
+                            Some(Ok(fetch_error)) => anyhow::Error::from(*fetch_error),
+                            Some(Err(e)) => FetchError::Permanent {
+                                url,
+                                reason: format!("failed to decompress: {e}"),
+                                status: None,
+                            }
+                            .into(),
+                            None => FetchError::Permanent {
+                                url,
+                                reason: "failed to decompress".to_string(),
+                                status: None,
+                            }
+                            .into(),
+                        };
+                        return Some((Err(error), (None, line, index)));
+                    }
+                }
+            }
+        }
+    })
+}
+
+#[cfg_attr(feature = "metrics", autometrics::autometrics)]
+pub async fn download_and_unzip(
+    client: &reqwest::Client,
+    url: &str,
+    offset: usize,
+    length: usize,
+) -> Result<Vec<u8>, anyhow::Error> {
+    gunzip(url, &download_range(client, url, offset, length).await?)
+}
+
+/// How long to wait before trying `url` again after failed attempt number `attempt`, or
+/// `None` if `err` is permanent or that was the last of `max_attempts`. Records the retry.
+pub fn retry_delay(
+    url: &str,
+    err: &anyhow::Error,
+    attempt: usize,
+    max_attempts: usize,
+    backoff: &Backoff,
+) -> Option<Duration> {
+    let fetch_error = fetch_error(err);
+    if attempt >= max_attempts || fetch_error.is_none_or(|e| e.is_permanent()) {
+        return None;
+    }
+    let delay = backoff.delay(attempt, fetch_error.and_then(FetchError::retry_after));
+    tracing::warn!(
+        err.msg = %err,
+        "Retrying fetch in {:?} (attempt {} of {})",
+        delay,
+        attempt,
+        max_attempts
+    );
+    TRAFFIC.record_retry(TrafficKind::of_data_url(url));
+    Some(delay)
+}
+
+/// Like [`download_range`], but retries transient failures up to `max_attempts` times,
+/// waiting as `backoff` and the server's `Retry-After` say. Errors that are not fetch
+/// errors and permanent failures are returned right away.
+pub async fn download_range_with_retries(
+    client: &reqwest::Client,
+    url: &str,
+    offset: usize,
+    length: usize,
+    max_attempts: usize,
+    backoff: &Backoff,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let mut attempt = 1;
+    loop {
+        match download_range(client, url, offset, length).await {
+            Ok(data) => return Ok(data),
+            Err(e) => match retry_delay(url, &e, attempt, max_attempts, backoff) {
+                Some(delay) => tokio::time::sleep(delay).await,
+                None => return Err(e),
+            },
+        }
+        attempt += 1;
+    }
+}
+
+/// Like [`download_and_unzip`], but retries transient failures up to `max_attempts` times.
+pub async fn download_and_unzip_with_retries(
+    client: &reqwest::Client,
+    url: &str,
+    offset: usize,
+    length: usize,
+    max_attempts: usize,
+    backoff: &Backoff,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let body =
+        download_range_with_retries(client, url, offset, length, max_attempts, backoff).await?;
+    gunzip(url, &body)
+}
+
+/// Sort-friendly URI Reordering Transform as used for CDX keys, e.g.
+/// `https://www.Example.com/a?b=c` becomes `com,example)/a?b=c`.
+pub fn surt_url(url: &str) -> Option<String> {
+    let url = url::Url::parse(url).ok()?;
+    let host = url.host_str()?.to_ascii_lowercase();
+    let host = host.strip_prefix("www.").unwrap_or(&host);
+    let mut surt = host.split('.').rev().collect::<Vec<_>>().join(",");
+    if let Some(port) = url.port() {
+        surt.push_str(&format!(":{port}"));
+    }
+    surt.push(')');
+    surt.push_str(&url.path().to_ascii_lowercase());
+    if let Some(query) = url.query() {
+        surt.push('?');
+        surt.push_str(&query.to_ascii_lowercase());
+    }
+    Some(surt)
+}
+
+/// What to do with a CDX line that is not valid UTF-8.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Utf8Policy {
+    /// Stop with an error naming the line.
+    #[default]
+    Fail,
+    /// Replace the invalid bytes with U+FFFD and keep the line.
+    Lossy,
+    /// Leave the line out.
+    Skip,
+}
+
+/// Counters of [`decode_lines`], exported on `/metrics`.
+pub struct DecodeStats {
+    replaced_bytes: AtomicU64,
+    skipped_lines: AtomicU64,
+    /// Lines [`parse_cdx_line_or_skip`] left out, per [`CdxParseError::REASONS`].
+    malformed_lines: [AtomicU64; 4],
+}
+
+pub static CDX_DECODING: DecodeStats = DecodeStats {
+    replaced_bytes: AtomicU64::new(0),
+    skipped_lines: AtomicU64::new(0),
+    malformed_lines: [const { AtomicU64::new(0) }; 4],
+};
+
+impl DecodeStats {
+    pub fn replaced_bytes(&self) -> u64 {
+        self.replaced_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn skipped_lines(&self) -> u64 {
+        self.skipped_lines.load(Ordering::Relaxed)
+    }
+
+    /// Malformed lines left out so far, per reason.
+    pub fn malformed_lines(&self) -> [(&'static str, u64); 4] {
+        std::array::from_fn(|i| {
+            (
+                CdxParseError::REASONS[i],
+                self.malformed_lines[i].load(Ordering::Relaxed),
+            )
+        })
+    }
+
+    /// A line for the end of a run if any malformed lines were left out, e.g. `Skipped 3
+    /// malformed CDX lines (invalid_utf8: 0, missing_fields: 2, invalid_metadata: 1,
+    /// zero_length: 0)`.
+    pub fn malformed_summary(&self) -> Option<String> {
+        let malformed = self.malformed_lines();
+        let total = malformed.iter().map(|(_, count)| count).sum::<u64>();
+        let reasons = malformed
+            .iter()
+            .map(|(reason, count)| format!("{reason}: {count}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        (total > 0).then(|| format!("Skipped {total} malformed CDX lines ({reasons})"))
+    }
+}
+
+impl ExportMetrics for DecodeStats {
+    fn export(&self, text: &mut PrometheusText) {
+        text.counter(
+            "pipeline_cdx_replaced_bytes_total",
+            "Invalid UTF-8 bytes in CDX lines replaced by U+FFFD.",
+            self.replaced_bytes(),
+        );
+        text.counter(
+            "pipeline_cdx_skipped_lines_total",
+            "CDX lines left out because they are not valid UTF-8.",
+            self.skipped_lines(),
+        );
+        text.labeled_counter(
+            "pipeline_cdx_malformed_lines_total",
+            "CDX lines left out because they cannot be parsed.",
+            "reason",
+            self.malformed_lines(),
+        );
+    }
+}
+
+/// Splits decompressed CDX data into lines and decodes them one at a time, so a single
+/// invalid byte only affects its own line under the lossy and skip policies.
+pub fn decode_lines(
+    data: &[u8],
+    policy: Utf8Policy,
+) -> impl Iterator<Item = Result<Cow<'_, str>, anyhow::Error>> {
+    data.split(|b| *b == b'\n')
+        .enumerate()
+        .filter_map(move |(index, line)| decode_line(index + 1, line, policy))
+}
+
+/// Decodes line `number` of a CDX shard, `None` if it is empty or skipped by `policy`.
+fn decode_line(
+    number: usize,
+    line: &[u8],
+    policy: Utf8Policy,
+) -> Option<Result<Cow<'_, str>, anyhow::Error>> {
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    if line.is_empty() {
+        return None;
+    }
+    PAYLOAD_SIZES.cdx_entry.observe(line.len() as u64);
+    match std::str::from_utf8(line) {
+        Ok(line) => Some(Ok(Cow::Borrowed(line))),
+        Err(e) => match policy {
+            Utf8Policy::Fail => Some(Err(anyhow::anyhow!("CDX line {number}: {e}"))),
+            Utf8Policy::Lossy => {
+                let replaced = line
+                    .utf8_chunks()
+                    .map(|chunk| chunk.invalid().len() as u64)
+                    .sum::<u64>();
+                CDX_DECODING
+                    .replaced_bytes
+                    .fetch_add(replaced, Ordering::Relaxed);
+                Some(Ok(String::from_utf8_lossy(line)))
+            }
+            Utf8Policy::Skip => {
+                CDX_DECODING.skipped_lines.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        },
+    }
+}
+
+/// Why a CDX line could not be parsed. Real shards occasionally hold truncated or odd
+/// records, which the batcher skips and counts instead of stopping.
+#[derive(Debug)]
+pub enum CdxParseError {
+    InvalidUtf8(std::str::Utf8Error),
+    /// Less than the three fields SURT URL, timestamp and metadata, e.g. a truncated line.
+    MissingFields,
+    /// The metadata is not the JSON of a capture, e.g. cut off or missing a field.
+    InvalidMetadata(serde_json::Error),
+    /// A capture or cluster.idx chunk of length 0, which has no byte range to fetch.
+    ZeroLength,
+}
+
+impl CdxParseError {
+    pub const REASONS: [&'static str; 4] = [
+        "invalid_utf8",
+        "missing_fields",
+        "invalid_metadata",
+        "zero_length",
+    ];
+
+    /// One of [`CdxParseError::REASONS`], e.g. as a metric label.
+    pub fn reason(&self) -> &'static str {
+        Self::REASONS[self.index()]
+    }
+
+    fn index(&self) -> usize {
+        match self {
+            CdxParseError::InvalidUtf8(_) => 0,
+            CdxParseError::MissingFields => 1,
+            CdxParseError::InvalidMetadata(_) => 2,
+            CdxParseError::ZeroLength => 3,
+        }
+    }
+}
+
+impl std::fmt::Display for CdxParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CdxParseError::InvalidUtf8(e) => write!(f, "CDX line is not valid UTF-8: {e}"),
+            CdxParseError::MissingFields => write!(f, "CDX line has less than three fields"),
+            CdxParseError::InvalidMetadata(e) => write!(f, "CDX line has invalid metadata: {e}"),
+            CdxParseError::ZeroLength => write!(f, "CDX line has a length of 0"),
+        }
+    }
+}
+
+impl std::error::Error for CdxParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CdxParseError::InvalidUtf8(e) => Some(e),
+            CdxParseError::MissingFields => None,
+            CdxParseError::InvalidMetadata(e) => Some(e),
+            CdxParseError::ZeroLength => None,
+        }
+    }
+}
+
+/// Parses one line of a CDX shard. Never panics, so it can be fed arbitrary input.
+pub fn try_parse_cdx_line(line: &[u8]) -> Result<CdxEntry, CdxParseError> {
+    parse_cdx_line(std::str::from_utf8(line).map_err(CdxParseError::InvalidUtf8)?)
+}
+
+/// Parses one decoded line of a CDX shard.
+pub fn parse_cdx_line(line: &str) -> Result<CdxEntry, CdxParseError> {
+    let mut parts = line.splitn(3, ' ');
+    let (Some(surt_url), Some(timestamp), Some(metadata)) =
+        (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(CdxParseError::MissingFields);
+    };
+    let metadata: CdxMetadata =
+        serde_json::from_str(metadata).map_err(CdxParseError::InvalidMetadata)?;
+    if metadata.length == 0 {
+        return Err(CdxParseError::ZeroLength);
+    }
+    Ok(CdxEntry {
+        surt_url: surt_url.to_string(),
+        timestamp: timestamp.to_string(),
+        metadata,
+    })
+}
+
+/// Parses line `number` of `url`, or counts it in [`CDX_DECODING`] and leaves it out if it
+/// is malformed.
+pub fn parse_cdx_line_or_skip(url: &str, number: usize, line: &str) -> Option<CdxEntry> {
+    match parse_cdx_line(line) {
+        Ok(entry) => Some(entry),
+        Err(e) => {
+            CDX_DECODING.malformed_lines[e.index()].fetch_add(1, Ordering::Relaxed);
+            tracing::warn!(err.msg = %e, "Skipping line {} of {}", number, url);
+            None
+        }
+    }
+}
+
+/// Inverse of [`parse_cdx_line`].
+pub fn format_cdx_line(entry: &CdxEntry) -> Result<String, anyhow::Error> {
+    Ok(format!(
+        "{} {} {}",
+        entry.surt_url,
+        entry.timestamp,
+        serde_json::to_string(&entry.metadata)?
+    ))
+}
+
+#[derive(Debug)]
+pub struct ClusterIdxEntry {
+    pub surt_url: String,
+    pub timestamp: String,
+    pub cdx_filename: String,
+    pub cdx_offset: usize,
+    pub cdx_length: usize,
+    pub cluster_id: String,
+}
+
+/// Parses one line of a `cluster.idx` file. Never panics, so it can be fed arbitrary input.
+pub fn try_parse_cluster_idx(line: &[u8]) -> Result<ClusterIdxEntry, anyhow::Error> {
+    let line = std::str::from_utf8(line)?;
+    let mut idx = line.split_whitespace();
+    let mut field = |name: &str| {
+        idx.next()
+            .with_context(|| format!("cluster.idx line has no {name}"))
+    };
+    let entry = ClusterIdxEntry {
+        surt_url: field("SURT URL")?.to_string(),
+        timestamp: field("timestamp")?.to_string(),
+        cdx_filename: field("CDX filename")?.to_string(),
+        cdx_offset: field("offset")?.parse()?,
+        cdx_length: field("length")?.parse()?,
+        cluster_id: field("cluster ID")?.to_string(),
+    };
+    if entry.cdx_length == 0 {
+        return Err(CdxParseError::ZeroLength.into());
+    }
+    Ok(entry)
+}
+
+pub fn parse_cluster_idx(line: &str) -> Option<ClusterIdxEntry> {
+    try_parse_cluster_idx(line.as_bytes()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use std::io::Write;
+
+    use flate2::write::GzEncoder;
+    use futures_util::StreamExt;
+
+    use crate::{
+        cdx::{
+            decode_lines, fetch_error, format_cdx_line, gunzip, parse_cdx_line,
+            parse_cdx_line_or_skip, parse_cluster_idx, surt_url, try_parse_cdx_line,
+            try_parse_cluster_idx, unzip_lines, CdxParseError, FetchError, Utf8Policy,
+            CDX_DECODING,
+        },
+        metrics::prometheus_text,
+    };
+
+    fn gzip_member(text: &str) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(text.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn can_parse_cdx_file() {
+        let content = r#"0,100,22,165)/ 20240722120756 {"url": "http://165.22.100.0/", "mime": "text/html", "mime-detected": "text/html", "status": "301", "digest": "DCNYNIFG5SBRCVS5PCUY4YY2UM2WAQ4R", "length": "689", "offset": "3499", "filename": "crawl-data/CC-MAIN-2024-30/segments/1720763517846.73/crawldiagnostics/CC-MAIN-20240722095039-20240722125039-00443.warc.gz", "redirect": "https://157.245.55.71/"}
+0,100,22,165)/robots.txt 20240722120755 {"url": "http://165.22.100.0/robots.txt", "mime": "text/html", "mime-detected": "text/html", "status": "301", "digest": "LYEE2BXON4MCQCP5FDVDNILOWBKCZZ6G", "length": "700", "offset": "4656", "filename": "crawl-data/CC-MAIN-2024-30/segments/1720763517846.73/robotstxt/CC-MAIN-20240722095039-20240722125039-00410.warc.gz", "redirect": "https://157.245.55.71/robots.txt"}
+0,100,59,139)/ 20240723213521 {"url": "https://139.59.100.0/", "mime": "text/html", "mime-detected": "text/html", "status": "200", "digest": "5JOQMMSNM6N7UCLGGYXDSPSB3FYAQS2C", "length": "16650", "offset": "64016172", "filename": "crawl-data/CC-MAIN-2024-30/segments/1720763518115.82/warc/CC-MAIN-20240723194208-20240723224208-00279.warc.gz", "charset": "UTF-8", "languages": "ind,eng"}"#;
+        let cdx: Vec<_> = content
+            .lines()
+            .map(|line| parse_cdx_line(line).unwrap())
+            .collect();
+        assert_eq!(cdx.len(), 3);
+    }
+
+    #[test]
+    fn reads_all_cdx_fields() {
+        let line = r#"com,example)/big 20240723213521 {"url": "https://example.com/big", "mime": "text/html", "mime-detected": "application/xhtml+xml", "status": "200", "digest": "5JOQMMSNM6N7UCLGGYXDSPSB3FYAQS2C", "length": "1048576", "offset": "5000000000", "filename": "crawl-data/CC-MAIN-2024-30/segments/1720763518115.82/warc/CC-MAIN-20240723194208-20240723224208-00279.warc.gz", "charset": "UTF-8", "languages": "eng", "truncated": "length"}"#;
+        let metadata = parse_cdx_line(line).unwrap().metadata;
+        assert_eq!(metadata.status, 200);
+        assert_eq!(metadata.length, 1 << 20);
+        assert_eq!(metadata.offset, 5_000_000_000);
+        assert_eq!(
+            metadata.mime_detected.as_deref(),
+            Some("application/xhtml+xml")
+        );
+        assert_eq!(metadata.charset.as_deref(), Some("UTF-8"));
+        assert_eq!(metadata.truncated.as_deref(), Some("length"));
+        assert_eq!(metadata.redirect, None);
+
+        // Batches carry the numbers as numbers, which read back the same.
+        let entry = parse_cdx_line(line).unwrap();
+        let reparsed = parse_cdx_line(&format_cdx_line(&entry).unwrap()).unwrap();
+        assert!(format_cdx_line(&entry)
+            .unwrap()
+            .contains(r#""offset":5000000000"#));
+        assert_eq!(reparsed.metadata.offset, 5_000_000_000);
+        assert_eq!(reparsed.metadata.truncated.as_deref(), Some("length"));
+
+        let status = line.replace(r#""status": "200""#, r#""status": "70000""#);
+        assert!(parse_cdx_line(&status).is_err());
+    }
+
+    #[test]
+    fn can_parse_cluster_idx_file() {
+        let content = r#"0,100,22,165)/ 20240722120756   cdx-00000.gz    0       188224  1
+101,141,199,66)/robots.txt 20240714155331       cdx-00000.gz    188224  178351  2
+104,223,1,100)/ 20240714230020  cdx-00000.gz    366575  178055  3
+107,128,254,23)/sites.asp?domain=hydrogenheaters.com 20240725183414     cdx-00000.gz    544630  181599  4"#;
+        let cdx_parts: Vec<_> = content.lines().map(parse_cluster_idx).collect();
+        assert_eq!(cdx_parts.len(), 4);
+        let empty = try_parse_cluster_idx(b"0,100,22,165)/ 20240722120756 cdx-00000.gz 0 0 1");
+        assert_eq!(
+            empty.unwrap_err().to_string(),
+            CdxParseError::ZeroLength.to_string()
+        );
+    }
+
+    #[test]
+    fn classifies_fetch_failures() {
+        let classify =
+            |code| FetchError::from_status("url", reqwest::StatusCode::from_u16(code).unwrap());
+        assert!(classify(404).is_permanent());
+        assert!(classify(410).is_permanent());
+        assert!(classify(403).is_permanent());
+        assert!(!classify(503).is_permanent());
+        assert!(!classify(429).is_permanent());
+    }
+
+    #[test]
+    fn skips_and_counts_malformed_lines() {
+        let reason = |line: &[u8]| try_parse_cdx_line(line).unwrap_err().reason();
+        assert_eq!(reason(b"\xff\xfe"), "invalid_utf8");
+        assert_eq!(reason(b"0,100,59,139)/ 20240723213521"), "missing_fields");
+        assert_eq!(
+            reason(br#"0,100,59,139)/ 20240723213521 {"url": "https://139.59.100.0/", "sta"#),
+            "invalid_metadata"
+        );
+        assert_eq!(
+            reason(br#"0,100,59,139)/ 20240723213521 {"url": "https://139.59.100.0/", "status": "200", "length": "0", "offset": "64016172", "filename": "x.warc.gz"}"#),
+            "zero_length"
+        );
+
+        let malformed = |reason: &str| {
+            CDX_DECODING
+                .malformed_lines()
+                .into_iter()
+                .find(|(r, _)| *r == reason)
+                .unwrap()
+                .1
+        };
+        let before = malformed("missing_fields");
+        assert!(parse_cdx_line_or_skip("url", 7, "truncated").is_none());
+        assert_eq!(malformed("missing_fields"), before + 1);
+        assert!(CDX_DECODING
+            .malformed_summary()
+            .unwrap()
+            .starts_with("Skipped "));
+        assert!(prometheus_text(&CDX_DECODING)
+            .contains("pipeline_cdx_malformed_lines_total{reason=\"missing_fields\"}"));
+    }
+
+    #[test]
+    fn can_get_crawl_from_filename() {
+        let entry = parse_cdx_line(
+            r#"0,100,59,139)/ 20240723213521 {"url": "https://139.59.100.0/", "status": "200", "length": "16650", "offset": "64016172", "filename": "crawl-data/CC-MAIN-2024-30/segments/1720763518115.82/warc/CC-MAIN-20240723194208-20240723224208-00279.warc.gz"}"#,
+        ).unwrap();
+        assert_eq!(entry.metadata.crawl(), Some("CC-MAIN-2024-30"));
+    }
+
+    #[test]
+    fn gunzips_all_members() {
+        let body = [gzip_member("first\n"), gzip_member("second\n")].concat();
+        assert_eq!(gunzip("url", &body).unwrap(), b"first\nsecond\n");
+        assert!(gunzip("url", b"not gzip").is_err());
+    }
+
+    #[tokio::test]
+    async fn streams_lines_of_all_members() {
+        let body = [gzip_member("first\r\nsec"), gzip_member("ond\n\nlast")].concat();
+        let lines = unzip_lines("url", body.as_slice(), Utf8Policy::Fail)
+            .map(|line| line.unwrap())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            lines,
+            [
+                (1, "first".to_string()),
+                (2, "second".to_string()),
+                (4, "last".to_string())
+            ]
+        );
+
+        let corrupt = unzip_lines("url", b"not gzip".as_slice(), Utf8Policy::Fail)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(corrupt.len(), 1);
+        let error = corrupt.into_iter().next().unwrap().unwrap_err();
+        assert!(fetch_error(&error).is_some_and(|e| e.is_permanent()));
+    }
+
+    #[test]
+    fn decodes_lines_by_utf8_policy() {
+        let data = b"first\r\nbad \xff\xfe line\n\nlast\n";
+        let decode = |policy| {
+            decode_lines(data, policy)
+                .map(|line| line.map(|line| line.into_owned()))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let error = decode(Utf8Policy::Fail).unwrap_err();
+        assert!(error.to_string().starts_with("CDX line 2:"));
+        let replaced_bytes = CDX_DECODING.replaced_bytes();
+        assert_eq!(
+            decode(Utf8Policy::Lossy).unwrap(),
+            ["first", "bad \u{fffd}\u{fffd} line", "last"]
+        );
+        assert!(CDX_DECODING.replaced_bytes() >= replaced_bytes + 2);
+        assert_eq!(decode(Utf8Policy::Skip).unwrap(), ["first", "last"]);
+    }
+
+    #[test]
+    fn builds_surt_urls() {
+        assert_eq!(
+            surt_url("https://www.Example.com/About?b=c").as_deref(),
+            Some("com,example)/about?b=c")
+        );
+        assert_eq!(
+            surt_url("http://165.22.100.0:8080/").as_deref(),
+            Some("0,100,22,165:8080)/")
+        );
+        assert_eq!(surt_url("not a url"), None);
+    }
+
+    proptest! {
+        #[test]
+        fn parsers_never_panic(line in proptest::collection::vec(any::<u8>(), 0..512)) {
+            let _ = try_parse_cdx_line(&line);
+            let _ = try_parse_cluster_idx(&line);
+        }
+
+        #[test]
+        fn cdx_lines_round_trip(
+            surt_url in "[a-z0-9,)/]{1,40}",
+            timestamp in "[0-9]{14}",
+            url in "\\PC{0,80}",
+            offset in any::<u32>(),
+            length in prop_oneof![Just(0), any::<u32>()],
+        ) {
+            let line = format!(
+                r#"{surt_url} {timestamp} {{"url": {}, "status": "200", "length": "{length}", "offset": "{offset}", "filename": "crawl-data/CC-MAIN-2024-30/x.warc.gz"}}"#,
+                serde_json::to_string(&url).unwrap()
             );
-            let mut decoder = flate2::read::GzDecoder::new(&body[..]);
-            let mut buffer = Vec::new();
-            decoder.read_to_end(&mut buffer).unwrap();
-            Ok(buffer)
-        }
-        _ => Err(anyhow::anyhow!(
-            "Failed to fetch index file {}: {}",
-            url,
-            res.status()
-        )),
+            if length == 0 {
+                let e = try_parse_cdx_line(line.as_bytes()).unwrap_err();
+                prop_assert_eq!(e.reason(), "zero_length");
+                return Ok(());
+            }
+            let entry = try_parse_cdx_line(line.as_bytes()).unwrap();
+            let reparsed = try_parse_cdx_line(format_cdx_line(&entry).unwrap().as_bytes()).unwrap();
+            prop_assert_eq!(reparsed.metadata.url, url);
+            prop_assert_eq!(reparsed.metadata.offset, offset as u64);
+            prop_assert_eq!(reparsed.metadata.length, length as u64);
+        }
     }
 }
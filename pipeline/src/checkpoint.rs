@@ -0,0 +1,244 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    batch::BatchKey,
+    state_file::{decode_state, encode_state, read_state_file, write_state_file, StateFile},
+    state_store::StateStore,
+};
+
+/// How far a batcher got through the cluster.idx of `crawl`.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Checkpoint {
+    pub crawl: String,
+    /// Leading cluster.idx chunks all of whose batches are published.
+    pub chunks_done: usize,
+    /// Batches per CDX file in the chunks that are done, where its batch indices continue.
+    pub batches_done: BTreeMap<String, usize>,
+    /// Batches published per CDX file, also of the chunks after `chunks_done`.
+    pub published: BTreeMap<String, usize>,
+    /// Batch indices per CDX file below `published` that an overflow policy dropped, so
+    /// they are not published. Their chunks stay pending until a resume publishes them.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub dropped: BTreeMap<String, BTreeSet<usize>>,
+}
+
+impl Checkpoint {
+    pub fn new(crawl: &str) -> Self {
+        Self {
+            crawl: crawl.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// `None` if there is no checkpoint at `path`.
+    pub fn read(path: &Path) -> Result<Option<Self>, anyhow::Error> {
+        read_state_file(path)
+    }
+
+    /// `None` if `store` has no checkpoint `name`.
+    pub fn read_from(store: &dyn StateStore, name: &str) -> Result<Option<Self>, anyhow::Error> {
+        store
+            .read_checkpoint(name)?
+            .map(|data| decode_state(&data))
+            .transpose()
+    }
+
+    pub fn is_published(&self, key: &BatchKey) -> bool {
+        self.published
+            .get(&key.shard)
+            .is_some_and(|published| key.batch_index < *published)
+            && !self
+                .dropped
+                .get(&key.shard)
+                .is_some_and(|dropped| dropped.contains(&key.batch_index))
+    }
+}
+
+impl StateFile for Checkpoint {
+    const KIND: &'static [u8; 4] = b"CKPT";
+    const VERSION: u32 = 1;
+
+    /// Version 1 only added the header.
+    fn migrate(_: u32, payload: serde_json::Value) -> Result<Self, anyhow::Error> {
+        Ok(serde_json::from_value(payload)?)
+    }
+}
+
+/// Where a [`CheckpointWriter`] keeps its checkpoint.
+enum Target {
+    File(PathBuf),
+    Store(Arc<dyn StateStore>, String),
+}
+
+/// Advances a [`Checkpoint`] as the batcher enqueues chunks and publishes their batches,
+/// and rewrites it whenever it changes. A file is replaced atomically, so a crash leaves
+/// either the old or the new checkpoint behind.
+pub struct CheckpointWriter {
+    target: Target,
+    checkpoint: Checkpoint,
+    /// The chunks enqueued after `chunks_done`, in order, each with its CDX file and the
+    /// number of batches of that file up to its end.
+    pending: VecDeque<(String, usize)>,
+}
+
+impl CheckpointWriter {
+    /// Starts from `checkpoint`, replacing whatever is at `path` already.
+    pub fn create(path: impl Into<PathBuf>, checkpoint: Checkpoint) -> Result<Self, anyhow::Error> {
+        Self::start(Target::File(path.into()), checkpoint)
+    }
+
+    /// Starts from `checkpoint`, kept in `store` as `name` instead of in a file, so a
+    /// batcher on another machine can resume.
+    pub fn create_in(
+        store: Arc<dyn StateStore>,
+        name: &str,
+        checkpoint: Checkpoint,
+    ) -> Result<Self, anyhow::Error> {
+        Self::start(Target::Store(store, name.to_string()), checkpoint)
+    }
+
+    fn start(target: Target, checkpoint: Checkpoint) -> Result<Self, anyhow::Error> {
+        let writer = Self {
+            target,
+            checkpoint,
+            pending: VecDeque::new(),
+        };
+        writer.persist()?;
+        Ok(writer)
+    }
+
+    pub fn checkpoint(&self) -> &Checkpoint {
+        &self.checkpoint
+    }
+
+    /// Records that all batches of the next chunk, of CDX file `shard`, are enqueued, and
+    /// that `shard` has `num_batches` batches up to them.
+    pub fn chunk_enqueued(&mut self, shard: &str, num_batches: usize) -> Result<(), anyhow::Error> {
+        self.pending.push_back((shard.to_string(), num_batches));
+        self.advance()
+    }
+
+    /// Records that the batch `key` was published, or does not need to be.
+    pub fn batch_done(&mut self, key: &BatchKey) -> Result<(), anyhow::Error> {
+        let published = self
+            .checkpoint
+            .published
+            .entry(key.shard.clone())
+            .or_default();
+        *published = (*published).max(key.batch_index + 1);
+        if let Some(dropped) = self.checkpoint.dropped.get_mut(&key.shard) {
+            dropped.remove(&key.batch_index);
+            if dropped.is_empty() {
+                self.checkpoint.dropped.remove(&key.shard);
+            }
+        }
+        self.advance()
+    }
+
+    /// Records that the batch `key` was dropped instead of published, so it does not
+    /// count as published when later batches of its CDX file are.
+    pub fn batch_dropped(&mut self, key: &BatchKey) -> Result<(), anyhow::Error> {
+        self.checkpoint
+            .dropped
+            .entry(key.shard.clone())
+            .or_default()
+            .insert(key.batch_index);
+        self.persist()
+    }
+
+    fn advance(&mut self) -> Result<(), anyhow::Error> {
+        while let Some((shard, num_batches)) = self.pending.front() {
+            let has_dropped = self
+                .checkpoint
+                .dropped
+                .get(shard)
+                .is_some_and(|dropped| dropped.range(..*num_batches).next().is_some());
+            if has_dropped
+                || self.checkpoint.published.get(shard).copied().unwrap_or(0) < *num_batches
+            {
+                break;
+            }
+            self.checkpoint
+                .batches_done
+                .insert(shard.clone(), *num_batches);
+            self.checkpoint.chunks_done += 1;
+            self.pending.pop_front();
+        }
+        self.persist()
+    }
+
+    fn persist(&self) -> Result<(), anyhow::Error> {
+        match &self.target {
+            Target::File(path) => write_state_file(path, &self.checkpoint, false),
+            Target::Store(store, name) => {
+                store.write_checkpoint(name, &encode_state(&self.checkpoint)?)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        batch::BatchKey,
+        checkpoint::{Checkpoint, CheckpointWriter},
+    };
+
+    fn key(shard: &str, batch_index: usize) -> BatchKey {
+        BatchKey {
+            crawl: "CC-MAIN-2024-30".to_string(),
+            shard: shard.to_string(),
+            batch_index,
+        }
+    }
+
+    #[test]
+    fn advances_over_fully_published_chunks() {
+        let path =
+            std::env::temp_dir().join(format!("pipeline-checkpoint-{}.json", std::process::id()));
+        let mut writer =
+            CheckpointWriter::create(&path, Checkpoint::new("CC-MAIN-2024-30")).unwrap();
+        assert_eq!(
+            Checkpoint::read(&path).unwrap().unwrap().crawl,
+            "CC-MAIN-2024-30"
+        );
+        // Chunk 0 has batches 0 and 1 of cdx-00000, chunk 1 none, chunk 2 batch 2.
+        writer.chunk_enqueued("cdx-00000.gz", 2).unwrap();
+        writer.chunk_enqueued("cdx-00000.gz", 2).unwrap();
+        writer.batch_done(&key("cdx-00000.gz", 0)).unwrap();
+        assert_eq!(writer.checkpoint().chunks_done, 0);
+        writer.chunk_enqueued("cdx-00000.gz", 3).unwrap();
+        writer.batch_done(&key("cdx-00000.gz", 1)).unwrap();
+        assert_eq!(writer.checkpoint().chunks_done, 2);
+
+        let checkpoint = Checkpoint::read(&path).unwrap().unwrap();
+        assert_eq!(&checkpoint, writer.checkpoint());
+        assert_eq!(checkpoint.batches_done["cdx-00000.gz"], 2);
+        assert!(checkpoint.is_published(&key("cdx-00000.gz", 1)));
+        assert!(!checkpoint.is_published(&key("cdx-00000.gz", 2)));
+        assert!(!checkpoint.is_published(&key("cdx-00001.gz", 0)));
+
+        writer.batch_done(&key("cdx-00000.gz", 2)).unwrap();
+        assert_eq!(writer.checkpoint().chunks_done, 3);
+
+        // Batch 3 is dropped, batch 4 published: the chunk waits for a resume.
+        writer.chunk_enqueued("cdx-00000.gz", 5).unwrap();
+        writer.batch_dropped(&key("cdx-00000.gz", 3)).unwrap();
+        writer.batch_done(&key("cdx-00000.gz", 4)).unwrap();
+        let checkpoint = Checkpoint::read(&path).unwrap().unwrap();
+        assert_eq!(checkpoint.chunks_done, 3);
+        assert!(!checkpoint.is_published(&key("cdx-00000.gz", 3)));
+        assert!(checkpoint.is_published(&key("cdx-00000.gz", 4)));
+        writer.batch_done(&key("cdx-00000.gz", 3)).unwrap();
+        assert_eq!(writer.checkpoint().chunks_done, 4);
+        assert!(writer.checkpoint().dropped.is_empty());
+        std::fs::remove_file(&path).unwrap();
+        assert!(Checkpoint::read(&path).unwrap().is_none());
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Named outcome counters of a run, e.g. `documents.eng` or `errors.warc_parse`, added up
+/// in the run DB by all workers.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RunCounters {
+    counts: BTreeMap<String, u64>,
+}
+
+impl RunCounters {
+    pub fn add(&mut self, name: &str, count: u64) {
+        if count > 0 {
+            *self.counts.entry(name.to_string()).or_default() += count;
+        }
+    }
+
+    pub fn get(&self, name: &str) -> u64 {
+        self.counts.get(name).copied().unwrap_or_default()
+    }
+
+    /// Returns the counters and starts over from zero.
+    pub fn take(&mut self) -> Self {
+        std::mem::take(self)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u64)> + '_ {
+        self.counts
+            .iter()
+            .map(|(name, count)| (name.as_str(), *count))
+    }
+}
+
+/// Groups command line arguments into flags with their values, e.g. `--extractor
+/// readability`, so two configs can be compared flag by flag.
+pub fn group_args(args: &[String]) -> Vec<String> {
+    let mut grouped = Vec::<String>::new();
+    for arg in args {
+        match grouped.last_mut() {
+            Some(flag)
+                if flag.starts_with('-')
+                    && !flag.contains(' ')
+                    && !flag.contains('=')
+                    && !arg.starts_with('-') =>
+            {
+                flag.push(' ');
+                flag.push_str(arg);
+            }
+            _ => grouped.push(arg.clone()),
+        }
+    }
+    grouped
+}
+
+/// A flag that only one of two runs was started with, by process, e.g. `worker`.
+#[derive(Debug, PartialEq)]
+pub struct ConfigDifference {
+    pub process: String,
+    pub only_in_a: Vec<String>,
+    pub only_in_b: Vec<String>,
+}
+
+/// Compares the command lines of the processes of two runs. Processes with the same
+/// flags in both runs are left out.
+pub fn compare_configs(
+    a: &[(String, Vec<String>)],
+    b: &[(String, Vec<String>)],
+) -> Vec<ConfigDifference> {
+    let flags = |configs: &[(String, Vec<String>)]| {
+        let mut flags = BTreeMap::<String, BTreeSet<String>>::new();
+        for (process, args) in configs {
+            flags
+                .entry(process.clone())
+                .or_default()
+                .extend(group_args(args));
+        }
+        flags
+    };
+    let (a, b) = (flags(a), flags(b));
+    let processes = a.keys().chain(b.keys()).collect::<BTreeSet<_>>();
+    let empty = BTreeSet::new();
+    processes
+        .into_iter()
+        .filter_map(|process| {
+            let (a, b) = (
+                a.get(process).unwrap_or(&empty),
+                b.get(process).unwrap_or(&empty),
+            );
+            let difference = ConfigDifference {
+                process: process.clone(),
+                only_in_a: a.difference(b).cloned().collect(),
+                only_in_b: b.difference(a).cloned().collect(),
+            };
+            (!difference.only_in_a.is_empty() || !difference.only_in_b.is_empty())
+                .then_some(difference)
+        })
+        .collect()
+}
+
+/// One row of a run comparison.
+#[derive(Debug, PartialEq)]
+pub struct CounterComparison {
+    pub name: String,
+    pub a: f64,
+    pub b: f64,
+}
+
+/// Lines up the counters of two runs, with the share of each language among the written
+/// documents and the dedup rate derived from them.
+pub fn compare_counters(a: &RunCounters, b: &RunCounters) -> Vec<CounterComparison> {
+    let names = a
+        .iter()
+        .chain(b.iter())
+        .map(|(name, _)| name.to_string())
+        .collect::<BTreeSet<_>>();
+    let mut rows = names
+        .iter()
+        .map(|name| CounterComparison {
+            name: name.clone(),
+            a: a.get(name) as f64,
+            b: b.get(name) as f64,
+        })
+        .collect::<Vec<_>>();
+    let total_documents = |counters: &RunCounters| {
+        counters
+            .iter()
+            .filter(|(name, _)| name.starts_with("documents."))
+            .map(|(_, count)| count as f64)
+            .sum::<f64>()
+    };
+    let (total_a, total_b) = (total_documents(a), total_documents(b));
+    let share = |count: u64, total: f64| {
+        if total > 0.0 {
+            count as f64 / total
+        } else {
+            0.0
+        }
+    };
+    for name in names.iter().filter(|name| name.starts_with("documents.")) {
+        rows.push(CounterComparison {
+            name: format!("share.{}", &name["documents.".len()..]),
+            a: share(a.get(name), total_a),
+            b: share(b.get(name), total_b),
+        });
+    }
+    rows.push(CounterComparison {
+        name: "dedup_rate".to_string(),
+        a: share(a.get("dedup.dropped"), a.get("dedup.checked") as f64),
+        b: share(b.get("dedup.dropped"), b.get("dedup.checked") as f64),
+    });
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compare::{compare_configs, compare_counters, group_args, RunCounters};
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn compares_configs_flag_by_flag() {
+        assert_eq!(
+            group_args(&strings(&[
+                "--extractor",
+                "tag-strip",
+                "--dedup-content",
+                "-c",
+                "4"
+            ])),
+            strings(&["--extractor tag-strip", "--dedup-content", "-c 4"])
+        );
+        let a = vec![(
+            "worker".to_string(),
+            strings(&["--extractor", "tag-strip", "--dedup-content"]),
+        )];
+        let b = vec![
+            (
+                "worker".to_string(),
+                strings(&["--extractor", "readability", "--dedup-content"]),
+            ),
+            ("batcher".to_string(), strings(&["-n", "10"])),
+        ];
+        let differences = compare_configs(&a, &b);
+        assert_eq!(differences.len(), 2);
+        assert_eq!(differences[0].process, "batcher");
+        assert_eq!(differences[0].only_in_b, ["-n 10"]);
+        assert_eq!(differences[1].only_in_a, ["--extractor tag-strip"]);
+        assert_eq!(differences[1].only_in_b, ["--extractor readability"]);
+    }
+
+    #[test]
+    fn derives_language_shares_and_dedup_rates() {
+        let mut a = RunCounters::default();
+        a.add("documents.eng", 3);
+        a.add("documents.deu", 1);
+        a.add("dedup.checked", 8);
+        a.add("dedup.dropped", 4);
+        let mut b = RunCounters::default();
+        b.add("documents.eng", 2);
+        b.add("errors.warc_parse", 1);
+        let rows = compare_counters(&a, &b.take());
+        assert_eq!(b, RunCounters::default());
+        let row = |name: &str| {
+            let row = rows.iter().find(|row| row.name == name).unwrap();
+            (row.a, row.b)
+        };
+        assert_eq!(row("documents.deu"), (1.0, 0.0));
+        assert_eq!(row("errors.warc_parse"), (0.0, 1.0));
+        assert_eq!(row("share.eng"), (0.75, 1.0));
+        assert_eq!(row("dedup_rate"), (0.5, 0.0));
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+/// Why a record was left out for compliance reasons, counted per run for the compliance
+/// report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DropReason {
+    /// On the worker's skip list.
+    Takedown,
+    /// From a domain on the opt-out list.
+    OptOut,
+    /// The page asked not to be indexed through robots meta tags or `X-Robots-Tag`.
+    RobotsMeta,
+}
+
+impl DropReason {
+    pub const ALL: [DropReason; 3] = [
+        DropReason::Takedown,
+        DropReason::OptOut,
+        DropReason::RobotsMeta,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DropReason::Takedown => "takedown",
+            DropReason::OptOut => "opt_out",
+            DropReason::RobotsMeta => "robots_meta",
+        }
+    }
+}
+
+/// Records dropped per reason since the counts were last taken.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DropCounts {
+    counts: BTreeMap<DropReason, u64>,
+}
+
+impl DropCounts {
+    pub fn add(&mut self, reason: DropReason, count: u64) {
+        if count > 0 {
+            *self.counts.entry(reason).or_default() += count;
+        }
+    }
+
+    pub fn get(&self, reason: DropReason) -> u64 {
+        self.counts.get(&reason).copied().unwrap_or_default()
+    }
+
+    /// Returns the counts and starts over from zero.
+    pub fn take(&mut self) -> Self {
+        std::mem::take(self)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (DropReason, u64)> + '_ {
+        self.counts.iter().map(|(reason, count)| (*reason, *count))
+    }
+}
+
+/// The summary of one run handed to legal review. It only holds counts, never URLs,
+/// document IDs or anything else that identifies a page or a publisher.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ComplianceReport {
+    pub run_id: String,
+    pub generated_at_unix: u64,
+    /// Every reason, with zero if nothing was dropped for it.
+    pub dropped: BTreeMap<&'static str, u64>,
+    pub total_dropped: u64,
+}
+
+impl ComplianceReport {
+    pub fn new(run_id: &str, counts: &DropCounts, generated_at_unix: u64) -> Self {
+        let dropped = DropReason::ALL
+            .iter()
+            .map(|reason| (reason.as_str(), counts.get(*reason)))
+            .collect::<BTreeMap<_, _>>();
+        Self {
+            run_id: run_id.to_string(),
+            generated_at_unix,
+            total_dropped: dropped.values().sum(),
+            dropped,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compliance::{ComplianceReport, DropCounts, DropReason};
+
+    #[test]
+    fn reports_every_reason() {
+        let mut counts = DropCounts::default();
+        counts.add(DropReason::Takedown, 2);
+        counts.add(DropReason::RobotsMeta, 1);
+        counts.add(DropReason::Takedown, 3);
+        counts.add(DropReason::OptOut, 0);
+        let report = ComplianceReport::new("run", &counts.take(), 1_700_000_000);
+        assert_eq!(counts, DropCounts::default());
+        assert_eq!(
+            serde_json::to_string(&report).unwrap(),
+            r#"{"run_id":"run","generated_at_unix":1700000000,"dropped":{"opt_out":0,"robots_meta":1,"takedown":5},"total_dropped":6}"#
+        );
+    }
+}
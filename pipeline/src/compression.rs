@@ -0,0 +1,302 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader, Read, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use flate2::{read::MultiGzDecoder, write::GzEncoder};
+
+/// Codec of an output sink, parsed from `none`, `gzip[:level]`, `zstd[:level]`, `lz4` or `auto`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip(u32),
+    Zstd(i32),
+    Lz4,
+    /// Picks one of the others by the CPU headroom when the sink is created, see
+    /// [`Compression::resolve`].
+    Auto,
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (codec, level) = match s.split_once(':') {
+            Some((codec, level)) => (codec, Some(level)),
+            None => (s, None),
+        };
+        Ok(match (codec, level) {
+            ("none", None) => Compression::None,
+            ("gzip", level) => Compression::Gzip(level.map_or(Ok(6), str::parse)?),
+            ("zstd", level) => Compression::Zstd(level.map_or(Ok(3), str::parse)?),
+            ("lz4", None) => Compression::Lz4,
+            ("auto", None) => Compression::Auto,
+            _ => anyhow::bail!(
+                "Unknown compression {s}, expected none, gzip[:level], zstd[:level], lz4 or auto"
+            ),
+        })
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Gzip(level) => write!(f, "gzip:{level}"),
+            Compression::Zstd(level) => write!(f, "zstd:{level}"),
+            Compression::Lz4 => write!(f, "lz4"),
+            Compression::Auto => write!(f, "auto"),
+        }
+    }
+}
+
+/// Share of the CPUs that is idle, from the one minute load average. `None` where there
+/// is no `/proc/loadavg`.
+pub fn cpu_headroom() -> Option<f64> {
+    let load = std::fs::read_to_string("/proc/loadavg").ok()?;
+    let load = load.split_whitespace().next()?.parse::<f64>().ok()?;
+    let cpus = std::thread::available_parallelism().ok()?.get() as f64;
+    Some((1.0 - load / cpus).clamp(0.0, 1.0))
+}
+
+impl Compression {
+    /// Replaces [`Compression::Auto`] by a concrete codec: zstd when more than half of the
+    /// CPUs are idle, lz4 when some are, and no compression on a saturated machine.
+    pub fn resolve(self, headroom: Option<f64>) -> Compression {
+        match (self, headroom) {
+            (Compression::Auto, Some(headroom)) if headroom > 0.5 => Compression::Zstd(3),
+            (Compression::Auto, Some(headroom)) if headroom > 0.1 => Compression::Lz4,
+            (Compression::Auto, Some(_)) => Compression::None,
+            (Compression::Auto, None) => Compression::Lz4,
+            (compression, _) => compression,
+        }
+    }
+
+    /// Suffix appended to the file name, including the dot.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::None | Compression::Auto => "",
+            Compression::Gzip(_) => ".gz",
+            Compression::Zstd(_) => ".zst",
+            Compression::Lz4 => ".lz4",
+        }
+    }
+}
+
+enum Encoder<W: Write> {
+    None(W),
+    Gzip(GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+    Lz4(lz4_flex::frame::FrameEncoder<W>),
+}
+
+impl<W: Write> Encoder<W> {
+    fn new(compression: Compression, writer: W) -> io::Result<Self> {
+        Ok(match compression {
+            Compression::None | Compression::Auto => Encoder::None(writer),
+            Compression::Gzip(level) => {
+                Encoder::Gzip(GzEncoder::new(writer, flate2::Compression::new(level)))
+            }
+            Compression::Zstd(level) => Encoder::Zstd(zstd::Encoder::new(writer, level)?),
+            Compression::Lz4 => Encoder::Lz4(lz4_flex::frame::FrameEncoder::new(writer)),
+        })
+    }
+
+    fn writer(&mut self) -> &mut dyn Write {
+        match self {
+            Encoder::None(w) => w,
+            Encoder::Gzip(w) => w,
+            Encoder::Zstd(w) => w,
+            Encoder::Lz4(w) => w,
+        }
+    }
+
+    fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::None(w) => Ok(w),
+            Encoder::Gzip(w) => w.finish(),
+            Encoder::Zstd(w) => w.finish(),
+            Encoder::Lz4(w) => w.finish().map_err(io::Error::other),
+        }
+    }
+}
+
+enum FrameState<W: Write> {
+    Idle(W),
+    Open(Encoder<W>),
+}
+
+/// Writes compressed data as a sequence of independent frames (gzip members, zstd or lz4
+/// frames), which all three formats allow to concatenate. A frame is only started by the
+/// first write after [`FrameWriter::finish_frame`], so truncating the file to its length
+/// at a frame boundary always leaves a valid file behind.
+pub struct FrameWriter<W: Write> {
+    compression: Compression,
+    state: Option<FrameState<W>>,
+}
+
+impl<W: Write> FrameWriter<W> {
+    pub fn new(compression: Compression, writer: W) -> Self {
+        Self {
+            compression,
+            state: Some(FrameState::Idle(writer)),
+        }
+    }
+
+    /// Ends the current frame, if any, and flushes the underlying writer.
+    pub fn finish_frame(&mut self) -> io::Result<()> {
+        let mut writer = match self.state.take().unwrap() {
+            FrameState::Idle(writer) => writer,
+            FrameState::Open(encoder) => encoder.finish()?,
+        };
+        let result = writer.flush();
+        self.state = Some(FrameState::Idle(writer));
+        result
+    }
+
+    /// The underlying writer, only available between frames.
+    pub fn get_ref(&self) -> Option<&W> {
+        match self.state.as_ref().unwrap() {
+            FrameState::Idle(writer) => Some(writer),
+            FrameState::Open(_) => None,
+        }
+    }
+}
+
+impl<W: Write> Write for FrameWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(FrameState::Idle(_)) = self.state {
+            let Some(FrameState::Idle(writer)) = self.state.take() else {
+                unreachable!()
+            };
+            self.state = Some(FrameState::Open(Encoder::new(self.compression, writer)?));
+        }
+        let Some(FrameState::Open(encoder)) = self.state.as_mut() else {
+            unreachable!()
+        };
+        encoder.writer().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.state.as_mut().unwrap() {
+            FrameState::Idle(writer) => writer.flush(),
+            FrameState::Open(encoder) => encoder.writer().flush(),
+        }
+    }
+}
+
+/// Reads all concatenated lz4 frames, lz4_flex's decoder stops after the first one.
+struct MultiLz4Decoder<R: BufRead> {
+    decoder: Option<lz4_flex::frame::FrameDecoder<R>>,
+}
+
+impl<R: BufRead> Read for MultiLz4Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let Some(decoder) = self.decoder.as_mut() else {
+                return Ok(0);
+            };
+            let n = decoder.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            let mut reader = self.decoder.take().unwrap().into_inner();
+            if !reader.fill_buf()?.is_empty() {
+                self.decoder = Some(lz4_flex::frame::FrameDecoder::new(reader));
+            }
+        }
+    }
+}
+
+/// The codec of a file written with [`Compression::extension`], at the default level.
+pub fn compression_of(path: &Path) -> Compression {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("gz") => Compression::Gzip(6),
+        Some("zst") => Compression::Zstd(3),
+        Some("lz4") => Compression::Lz4,
+        _ => Compression::None,
+    }
+}
+
+/// Opens a possibly compressed file, choosing the decoder by its extension.
+pub fn open_decompressed(path: &Path) -> Result<Box<dyn Read>, anyhow::Error> {
+    let file = BufReader::new(File::open(path)?);
+    Ok(match path.extension().and_then(|ext| ext.to_str()) {
+        Some("gz") => Box::new(MultiGzDecoder::new(file)),
+        Some("zst") => Box::new(zstd::Decoder::with_buffer(file)?),
+        Some("lz4") => Box::new(MultiLz4Decoder {
+            decoder: Some(lz4_flex::frame::FrameDecoder::new(file)),
+        }),
+        _ => Box::new(file),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::{Read, Write},
+    };
+
+    use crate::compression::{open_decompressed, Compression, FrameWriter};
+
+    #[test]
+    fn parses_and_resolves_codecs() {
+        assert_eq!(
+            "zstd:19".parse::<Compression>().unwrap(),
+            Compression::Zstd(19)
+        );
+        assert_eq!("gzip".parse::<Compression>().unwrap(), Compression::Gzip(6));
+        assert!("brotli".parse::<Compression>().is_err());
+        assert_eq!(Compression::Auto.resolve(Some(0.9)), Compression::Zstd(3));
+        assert_eq!(Compression::Auto.resolve(Some(0.3)), Compression::Lz4);
+        assert_eq!(Compression::Auto.resolve(Some(0.0)), Compression::None);
+        assert_eq!(
+            Compression::Gzip(1).resolve(Some(0.0)),
+            Compression::Gzip(1)
+        );
+    }
+
+    #[test]
+    fn concatenated_frames_survive_truncation() {
+        for compression in [
+            Compression::None,
+            Compression::Gzip(6),
+            Compression::Zstd(3),
+            Compression::Lz4,
+        ] {
+            let path = std::env::temp_dir().join(format!(
+                "pipeline-compression-{}.jsonl{}",
+                std::process::id(),
+                compression.extension()
+            ));
+            let mut writer = FrameWriter::new(compression, fs::File::create(&path).unwrap());
+            writer.write_all(b"first\n").unwrap();
+            writer.finish_frame().unwrap();
+            writer.write_all(b"second\n").unwrap();
+            writer.finish_frame().unwrap();
+            let committed = fs::metadata(&path).unwrap().len();
+            writer.write_all(b"partial\n").unwrap();
+            writer.flush().unwrap();
+            drop(writer);
+            fs::OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_len(committed)
+                .unwrap();
+
+            let mut text = String::new();
+            open_decompressed(&path)
+                .unwrap()
+                .read_to_string(&mut text)
+                .unwrap();
+            assert_eq!(text, "first\nsecond\n", "{compression}");
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
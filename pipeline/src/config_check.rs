@@ -0,0 +1,117 @@
+use std::{fmt::Display, ops::RangeInclusive, path::Path};
+
+/// Collects everything wrong with a configuration, so a binary can report all problems
+/// at startup instead of failing on the first one somewhere deep in a run.
+#[derive(Debug, Default)]
+pub struct ConfigCheck {
+    problems: Vec<String>,
+}
+
+impl ConfigCheck {
+    /// Records `problem` unless `ok`.
+    pub fn require(&mut self, ok: bool, problem: impl Into<String>) {
+        if !ok {
+            self.problems.push(problem.into());
+        }
+    }
+
+    pub fn in_range<T: PartialOrd + Display>(
+        &mut self,
+        flag: &str,
+        value: T,
+        range: RangeInclusive<T>,
+    ) {
+        if !range.contains(&value) {
+            self.problems.push(format!(
+                "{flag} is {value}, but has to be between {} and {}",
+                range.start(),
+                range.end()
+            ));
+        }
+    }
+
+    pub fn at_least<T: PartialOrd + Display>(&mut self, flag: &str, value: T, min: T) {
+        if value < min {
+            self.problems
+                .push(format!("{flag} is {value}, but has to be at least {min}"));
+        }
+    }
+
+    /// Records a problem if `path` is given but is not a readable file.
+    pub fn file_exists(&mut self, flag: &str, path: Option<&str>) {
+        if let Some(path) = path {
+            if !Path::new(path).is_file() {
+                self.problems
+                    .push(format!("{flag} {path} does not exist or is not a file"));
+            }
+        }
+    }
+
+    pub fn env_var(&mut self, name: &str) {
+        if std::env::var_os(name).is_none() {
+            self.problems
+                .push(format!("The environment variable {name} is not set"));
+        }
+    }
+
+    /// Records the error of `result`, e.g. of parsing a config file, as a problem of `flag`.
+    pub fn parses<T>(&mut self, flag: &str, result: Result<T, anyhow::Error>) {
+        if let Err(e) = result {
+            self.problems.push(format!("{flag}: {e:#}"));
+        }
+    }
+
+    pub fn problems(&self) -> &[String] {
+        &self.problems
+    }
+
+    /// Fails with every recorded problem on its own line.
+    pub fn finish(self) -> Result<(), anyhow::Error> {
+        match self.problems.len() {
+            0 => Ok(()),
+            n => anyhow::bail!(
+                "{n} problem{} with the configuration:\n  - {}",
+                if n == 1 { "" } else { "s" },
+                self.problems.join("\n  - ")
+            ),
+        }
+    }
+
+    /// Prints the problems and exits like clap does for invalid arguments.
+    pub fn exit_on_problems(self) {
+        if let Err(e) = self.finish() {
+            eprintln!("error: {e}");
+            std::process::exit(2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config_check::ConfigCheck;
+
+    #[test]
+    fn reports_all_problems_at_once() {
+        let mut check = ConfigCheck::default();
+        check.in_range("--ab-sample-rate", 0.5, 0.0..=1.0);
+        check.at_least("--workers", 1, 1);
+        assert!(check.problems().is_empty());
+        check.in_range("--ab-sample-rate", 1.5, 0.0..=1.0);
+        check.at_least("--workers", 0, 1);
+        check.file_exists("--skip-list", Some("/does/not/exist.txt"));
+        check.file_exists("--include-list", None);
+        check.require(false, "--ab-extractor compares the extractor with itself");
+        check.parses::<()>("--tunables-filename", Err(anyhow::anyhow!("bad JSON")));
+        let error = check.finish().unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "5 problems with the configuration:\n  \
+             - --ab-sample-rate is 1.5, but has to be between 0 and 1\n  \
+             - --workers is 0, but has to be at least 1\n  \
+             - --skip-list /does/not/exist.txt does not exist or is not a file\n  \
+             - --ab-extractor compares the extractor with itself\n  \
+             - --tunables-filename: bad JSON"
+        );
+        assert!(ConfigCheck::default().finish().is_ok());
+    }
+}
@@ -0,0 +1,348 @@
+//! The gRPC control API of the worker, the `Control` service of `proto/pipeline.proto`.
+//! Like the messages of [`crate::proto`], the service is written by hand rather than
+//! generated in a build script, so building does not need `protoc`.
+
+use std::{
+    convert::Infallible,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use anyhow::Context as _;
+use futures_util::{future::MapOk, TryFutureExt};
+use tokio::{net::TcpListener, sync::watch};
+use tonic::{
+    body::Body,
+    codegen::{http, BoxFuture, Service},
+    server::NamedService,
+    transport::{server::TcpIncoming, Channel, Server},
+    Status,
+};
+use tonic_prost::ProstCodec;
+
+use crate::{
+    batch::{batch_id, BatchEncoding},
+    cdx::CdxEntry,
+    proto::{self, ControlRequest, SubmittedJob, WorkerStatus},
+    queue::{QueueMessage, QueueProducer},
+};
+
+const STATUS: &str = "/pipeline.Control/Status";
+const PAUSE: &str = "/pipeline.Control/Pause";
+const RESUME: &str = "/pipeline.Control/Resume";
+const SUBMIT_JOB: &str = "/pipeline.Control/SubmitJob";
+
+/// What the control API sees and changes of a running worker, shared by its consumer
+/// tasks.
+pub struct ControlState {
+    worker: String,
+    tasks: u32,
+    paused: watch::Sender<bool>,
+    batches_completed: AtomicU64,
+    /// Publishes the submitted jobs.
+    producer: Box<dyn QueueProducer>,
+    priority_queue_name: String,
+}
+
+impl ControlState {
+    pub fn new(
+        worker: &str,
+        tasks: usize,
+        producer: Box<dyn QueueProducer>,
+        priority_queue_name: &str,
+    ) -> Self {
+        Self {
+            worker: worker.to_string(),
+            tasks: tasks.try_into().unwrap_or(u32::MAX),
+            paused: watch::Sender::new(false),
+            batches_completed: AtomicU64::new(0),
+            producer,
+            priority_queue_name: priority_queue_name.to_string(),
+        }
+    }
+
+    /// Returns once the worker is not paused, right away if it is running.
+    pub async fn wait_until_resumed(&self) {
+        // The sender lives as long as `self`, so waiting cannot fail.
+        let _ = self.paused.subscribe().wait_for(|paused| !paused).await;
+    }
+
+    pub fn batch_completed(&self) {
+        self.batches_completed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_paused(&self, paused: bool) -> WorkerStatus {
+        if self.paused.send_replace(paused) != paused {
+            match paused {
+                true => tracing::info!("Paused by the control API, taking no new batches"),
+                false => tracing::info!("Resumed by the control API"),
+            }
+        }
+        self.status()
+    }
+
+    pub fn status(&self) -> WorkerStatus {
+        WorkerStatus {
+            worker: self.worker.clone(),
+            paused: *self.paused.borrow(),
+            batches_completed: self.batches_completed.load(Ordering::Relaxed),
+            tasks: self.tasks,
+        }
+    }
+
+    /// Publishes the entries of `batch` as one batch to the priority queue, so the workers
+    /// take it before the batches of the crawl. It goes out as JSON, which every queue
+    /// backend takes.
+    pub async fn submit_job(&self, batch: proto::Batch) -> Result<SubmittedJob, Status> {
+        let entries = batch
+            .entries
+            .into_iter()
+            .map(CdxEntry::try_from)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| Status::invalid_argument(format!("{e:#}")))?;
+        if entries.is_empty() {
+            return Err(Status::invalid_argument("The job has no entries"));
+        }
+        if let Some(entry) = entries.iter().find(|entry| entry.metadata.length == 0) {
+            return Err(Status::invalid_argument(format!(
+                "Entry {} has a length of 0",
+                entry.metadata.url
+            )));
+        }
+        let encoding = BatchEncoding::Json;
+        let payload = encoding
+            .encode(&entries)
+            .map_err(|e| Status::internal(format!("{e:#}")))?;
+        let batch_id = batch_id(&payload);
+        let message = QueueMessage::new(payload).with_content_type(encoding.content_type());
+        self.producer
+            .publish(&self.priority_queue_name, &message)
+            .await
+            .map_err(|e| Status::unavailable(format!("{e:#}")))?;
+        tracing::info!(
+            "Published job {} of {} entries to {}",
+            batch_id,
+            entries.len(),
+            self.priority_queue_name
+        );
+        Ok(SubmittedJob {
+            batch_id,
+            queue_name: self.priority_queue_name.clone(),
+        })
+    }
+}
+
+/// Serves the control API of `state` on `listener` until the process ends.
+pub async fn run_control_server(
+    listener: TcpListener,
+    state: Arc<ControlState>,
+) -> Result<(), anyhow::Error> {
+    Server::builder()
+        .add_service(ControlServer { state })
+        .serve_with_incoming(TcpIncoming::from(listener))
+        .await?;
+    Ok(())
+}
+
+/// The `Control` service over a [`ControlState`].
+#[derive(Clone)]
+struct ControlServer {
+    state: Arc<ControlState>,
+}
+
+impl NamedService for ControlServer {
+    const NAME: &'static str = "pipeline.Control";
+}
+
+impl Service<http::Request<Body>> for ControlServer {
+    type Response = http::Response<Body>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<Body>) -> Self::Future {
+        let state = self.state.clone();
+        Box::pin(async move {
+            let response = match request.uri().path() {
+                STATUS => unary(request, |_: ControlRequest| async { Ok(state.status()) }).await,
+                PAUSE => {
+                    unary(request, |_: ControlRequest| async {
+                        Ok(state.set_paused(true))
+                    })
+                    .await
+                }
+                RESUME => {
+                    unary(request, |_: ControlRequest| async {
+                        Ok(state.set_paused(false))
+                    })
+                    .await
+                }
+                SUBMIT_JOB => unary(request, |batch| state.submit_job(batch)).await,
+                path => Status::unimplemented(format!("No method {path}")).into_http(),
+            };
+            Ok(response)
+        })
+    }
+}
+
+/// Answers `request` with `method`, decoding and encoding the messages as protobuf.
+async fn unary<M1, M2, F, Fut>(request: http::Request<Body>, method: F) -> http::Response<Body>
+where
+    M1: prost::Message + Default + Send + 'static,
+    M2: prost::Message + Send + 'static,
+    F: FnMut(M1) -> Fut,
+    Fut: Future<Output = Result<M2, Status>>,
+{
+    tonic::server::Grpc::new(ProstCodec::<M2, M1>::default())
+        .unary(Method(method), request)
+        .await
+}
+
+/// One unary method, as the service that [`tonic::server::Grpc`] calls.
+struct Method<F>(F);
+
+impl<M1, M2, F, Fut> Service<tonic::Request<M1>> for Method<F>
+where
+    F: FnMut(M1) -> Fut,
+    Fut: Future<Output = Result<M2, Status>>,
+{
+    type Response = tonic::Response<M2>;
+    type Error = Status;
+    type Future = MapOk<Fut, fn(M2) -> tonic::Response<M2>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: tonic::Request<M1>) -> Self::Future {
+        (self.0)(request.into_inner()).map_ok(tonic::Response::new)
+    }
+}
+
+/// A client of the `Control` service, e.g. `http://127.0.0.1:50051`.
+pub struct ControlClient {
+    grpc: tonic::client::Grpc<Channel>,
+}
+
+impl ControlClient {
+    pub async fn connect(url: &str) -> Result<Self, anyhow::Error> {
+        let channel = Channel::from_shared(url.to_string())?
+            .connect()
+            .await
+            .with_context(|| format!("Failed to connect to {url}"))?;
+        Ok(Self {
+            grpc: tonic::client::Grpc::new(channel),
+        })
+    }
+
+    pub async fn status(&mut self) -> Result<WorkerStatus, anyhow::Error> {
+        self.call(STATUS, ControlRequest {}).await
+    }
+
+    pub async fn pause(&mut self) -> Result<WorkerStatus, anyhow::Error> {
+        self.call(PAUSE, ControlRequest {}).await
+    }
+
+    pub async fn resume(&mut self) -> Result<WorkerStatus, anyhow::Error> {
+        self.call(RESUME, ControlRequest {}).await
+    }
+
+    pub async fn submit_job(
+        &mut self,
+        entries: &[CdxEntry],
+    ) -> Result<SubmittedJob, anyhow::Error> {
+        let batch = proto::Batch {
+            entries: entries.iter().map(Into::into).collect(),
+        };
+        self.call(SUBMIT_JOB, batch).await
+    }
+
+    async fn call<M1, M2>(&mut self, path: &'static str, request: M1) -> Result<M2, anyhow::Error>
+    where
+        M1: prost::Message + Send + Sync + 'static,
+        M2: prost::Message + Default + Send + Sync + 'static,
+    {
+        self.grpc.ready().await?;
+        let response = self
+            .grpc
+            .unary(
+                tonic::Request::new(request),
+                http::uri::PathAndQuery::from_static(path),
+                ProstCodec::<M1, M2>::default(),
+            )
+            .await?;
+        Ok(response.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use crate::{
+        batch::BatchEncoding,
+        cdx::parse_cdx_line,
+        control::{run_control_server, ControlClient, ControlState},
+        queue::QueueConsumer,
+        spool::Spool,
+    };
+
+    #[tokio::test]
+    async fn pauses_workers_and_submits_jobs() {
+        let dir = std::env::temp_dir().join(format!("pipeline-control-{}", std::process::id()));
+        let spool = Spool::new(&dir);
+        let state = Arc::new(ControlState::new(
+            "worker-1",
+            2,
+            Box::new(spool.clone()),
+            "batches-priority",
+        ));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(run_control_server(listener, state.clone()));
+        let mut client = ControlClient::connect(&url).await.unwrap();
+
+        state.batch_completed();
+        let status = client.status().await.unwrap();
+        assert_eq!(
+            (
+                status.worker.as_str(),
+                status.paused,
+                status.batches_completed,
+                status.tasks
+            ),
+            ("worker-1", false, 1, 2)
+        );
+        assert!(client.pause().await.unwrap().paused);
+        let resumed = tokio::time::timeout(Duration::from_millis(50), state.wait_until_resumed());
+        assert!(resumed.await.is_err());
+        assert!(!client.resume().await.unwrap().paused);
+        state.wait_until_resumed().await;
+
+        let entry = parse_cdx_line(
+            r#"0,100,59,139)/ 20240723213521 {"url": "https://139.59.100.0/", "status": "200", "length": "16650", "offset": "64016172", "filename": "x.warc.gz"}"#,
+        )
+        .unwrap();
+        let job = client
+            .submit_job(std::slice::from_ref(&entry))
+            .await
+            .unwrap();
+        assert_eq!(job.queue_name, "batches-priority");
+        let mut consumer = spool.consumer("batches-priority").unwrap();
+        let delivery = consumer.next().await.unwrap().unwrap();
+        let message = delivery.message();
+        let encoding = BatchEncoding::from_content_type(message.content_type.as_deref()).unwrap();
+        let batch = encoding.decode(&message.payload).unwrap();
+        assert_eq!(batch.len(), 1);
+        assert_eq!(batch[0].metadata.url, entry.metadata.url);
+        assert!(client.submit_job(&[]).await.is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
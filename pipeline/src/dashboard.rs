@@ -0,0 +1,407 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Instant,
+};
+
+/// Counters whose increase the dashboard shows as a recent error.
+pub const ERROR_COUNTERS: [(&str, &str); 6] = [
+    (
+        "pipeline_http_errors_total",
+        "failed requests to Common Crawl",
+    ),
+    ("pipeline_cdx_malformed_lines_total", "malformed CDX lines"),
+    (
+        "pipeline_publish_nacked_total",
+        "batches nacked by the broker",
+    ),
+    (
+        "pipeline_publish_confirm_timeouts_total",
+        "publish confirms timed out",
+    ),
+    (
+        "pipeline_handoff_dropped_total",
+        "batches dropped by the handoff",
+    ),
+    (
+        "pipeline_broker_blocked_total",
+        "times blocked by the broker",
+    ),
+];
+
+/// The samples of the Prometheus text format in `text` by name and labels, e.g.
+/// `pipeline_http_errors_total{kind="5xx"}`. Comments and unparsable lines are left out.
+pub fn parse_prometheus(text: &str) -> HashMap<String, f64> {
+    text.lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let (key, value) = line.trim().rsplit_once(' ')?;
+            Some((key.to_string(), value.parse().ok()?))
+        })
+        .collect()
+}
+
+/// The sum of all samples of `name`, over all its labels.
+fn sum_of(values: &HashMap<String, f64>, name: &str) -> f64 {
+    values
+        .iter()
+        .filter(|(key, _)| {
+            key.strip_prefix(name)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('{'))
+        })
+        .map(|(_, value)| value)
+        .sum()
+}
+
+struct Scrape {
+    at: Instant,
+    values: HashMap<String, f64>,
+}
+
+/// A batcher or worker whose `/metrics` the dashboard polls.
+pub struct Endpoint {
+    pub url: String,
+    /// Why the last poll failed, if it did.
+    pub error: Option<String>,
+    last: Option<Scrape>,
+    previous: Option<Scrape>,
+}
+
+impl Endpoint {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            error: None,
+            last: None,
+            previous: None,
+        }
+    }
+
+    /// The sum of all samples of `name` in the last poll.
+    pub fn total(&self, name: &str) -> f64 {
+        self.last
+            .as_ref()
+            .map_or(0.0, |scrape| sum_of(&scrape.values, name))
+    }
+
+    /// How fast `name` grew per second between the last two polls.
+    pub fn rate(&self, name: &str) -> Option<f64> {
+        let (last, previous) = (self.last.as_ref()?, self.previous.as_ref()?);
+        let secs = last.at.duration_since(previous.at).as_secs_f64();
+        (secs > 0.0)
+            .then(|| (sum_of(&last.values, name) - sum_of(&previous.values, name)).max(0.0) / secs)
+    }
+}
+
+/// What the dashboard shows, updated on every refresh.
+pub struct Dashboard {
+    pub endpoints: Vec<Endpoint>,
+    pub num_chunks: usize,
+    /// Leading cluster.idx chunks the batcher's checkpoint has as done.
+    pub chunks_done: usize,
+    /// Batches the checkpoint has as published per CDX file.
+    pub batches_published: usize,
+    /// Messages ready and consumers per queue.
+    pub queues: Vec<(String, u32, u32)>,
+    /// The newest errors last, at most `max_errors`.
+    pub errors: VecDeque<String>,
+    max_errors: usize,
+}
+
+impl Dashboard {
+    pub fn new(urls: &[String], num_chunks: usize, max_errors: usize) -> Self {
+        Self {
+            endpoints: urls.iter().map(|url| Endpoint::new(url)).collect(),
+            num_chunks,
+            chunks_done: 0,
+            batches_published: 0,
+            queues: Vec::new(),
+            errors: VecDeque::new(),
+            max_errors,
+        }
+    }
+
+    pub fn push_error(&mut self, error: String) {
+        if self.errors.len() == self.max_errors {
+            self.errors.pop_front();
+        }
+        self.errors.push_back(error);
+    }
+
+    /// Records the outcome of polling endpoint `index`, and any increase of the
+    /// [`ERROR_COUNTERS`] since the poll before as an error.
+    pub fn record_scrape(&mut self, index: usize, text: Result<String, anyhow::Error>) {
+        let endpoint = &mut self.endpoints[index];
+        let text = match text {
+            Ok(text) => text,
+            Err(e) => {
+                let message = format!("{}: {e:#}", endpoint.url);
+                let repeated = endpoint.error.as_ref() == Some(&message);
+                endpoint.error = Some(message.clone());
+                if !repeated {
+                    self.push_error(message);
+                }
+                return;
+            }
+        };
+        endpoint.error = None;
+        endpoint.previous = endpoint.last.take();
+        endpoint.last = Some(Scrape {
+            at: Instant::now(),
+            values: parse_prometheus(&text),
+        });
+        let Some(previous) = endpoint.previous.as_ref() else {
+            return;
+        };
+        let mut errors = Vec::new();
+        for (name, what) in ERROR_COUNTERS {
+            let increase = endpoint.total(name) - sum_of(&previous.values, name);
+            if increase > 0.0 {
+                errors.push(format!("{}: {increase:.0} {what}", endpoint.url));
+            }
+        }
+        for error in errors {
+            self.push_error(error);
+        }
+    }
+}
+
+/// How long one poll of a `/metrics` endpoint or a queue may take before the dashboard
+/// shows it as failed, so a hung endpoint does not freeze the UI.
+#[cfg(feature = "tui")]
+const POLL_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Runs the terminal UI until `q` or Esc is pressed, refreshing every `refresh`.
+#[cfg(feature = "tui")]
+pub async fn run(
+    http_client: &reqwest::Client,
+    dashboard: &mut Dashboard,
+    checkpoint_path: &std::path::Path,
+    queue_names: &[String],
+    refresh: std::time::Duration,
+) -> Result<(), anyhow::Error> {
+    use ratatui::crossterm::event::{self, Event, KeyCode};
+
+    #[cfg(feature = "rabbitmq")]
+    let channel = match std::env::var(crate::rabbitmq::RABBITMQ_CONNECTION_STRING) {
+        Ok(_) => {
+            let conn = tokio::time::timeout(POLL_TIMEOUT, crate::rabbitmq::rabbitmq_connection())
+                .await
+                .map_err(|_| anyhow::anyhow!("Connecting to RabbitMQ timed out"))??;
+            Some((crate::rabbitmq::rabbitmq_channel(&conn).await?, conn))
+        }
+        Err(_) => None,
+    };
+    #[cfg(not(feature = "rabbitmq"))]
+    let _ = queue_names;
+    let mut terminal = ratatui::init();
+    let result = async {
+        loop {
+            for index in 0..dashboard.endpoints.len() {
+                let url = dashboard.endpoints[index].url.clone();
+                let request = http_client.get(&url).timeout(POLL_TIMEOUT);
+                let text = async { Ok(request.send().await?.text().await?) };
+                dashboard.record_scrape(index, text.await);
+            }
+            match crate::checkpoint::Checkpoint::read(checkpoint_path) {
+                Ok(Some(checkpoint)) => {
+                    dashboard.chunks_done = checkpoint.chunks_done;
+                    dashboard.batches_published = checkpoint.published.values().sum();
+                }
+                Ok(None) => {}
+                Err(e) => dashboard.push_error(format!("{checkpoint_path:?}: {e:#}")),
+            }
+            #[cfg(feature = "rabbitmq")]
+            if let Some((channel, _)) = channel.as_ref() {
+                dashboard.queues.clear();
+                for queue_name in queue_names {
+                    let options = lapin::options::QueueDeclareOptions {
+                        passive: true,
+                        ..Default::default()
+                    };
+                    let declare = channel.queue_declare(queue_name, options, Default::default());
+                    match tokio::time::timeout(POLL_TIMEOUT, declare).await {
+                        Ok(Ok(queue)) => dashboard.queues.push((
+                            queue_name.clone(),
+                            queue.message_count(),
+                            queue.consumer_count(),
+                        )),
+                        Ok(Err(e)) => dashboard.push_error(format!("Queue {queue_name}: {e}")),
+                        Err(_) => dashboard.push_error(format!("Queue {queue_name}: timed out")),
+                    }
+                }
+            }
+            terminal.draw(|frame| draw(frame, dashboard))?;
+            let key = tokio::task::block_in_place(|| -> std::io::Result<_> {
+                if !event::poll(refresh)? {
+                    return Ok(None);
+                }
+                match event::read()? {
+                    Event::Key(key) => Ok(Some(key.code)),
+                    _ => Ok(None),
+                }
+            })?;
+            if matches!(key, Some(KeyCode::Char('q') | KeyCode::Esc)) {
+                return Ok(());
+            }
+        }
+    }
+    .await;
+    ratatui::restore();
+    result
+}
+
+#[cfg(feature = "tui")]
+fn draw(frame: &mut ratatui::Frame, dashboard: &Dashboard) {
+    use ratatui::{
+        layout::{Constraint, Layout},
+        widgets::{Block, Gauge, List, Row, Table},
+    };
+
+    let [progress, queues, endpoints, errors] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Length(dashboard.queues.len() as u16 + 3),
+        Constraint::Length(dashboard.endpoints.len() as u16 + 3),
+        Constraint::Min(3),
+    ])
+    .areas(frame.area());
+
+    let ratio = dashboard.chunks_done as f64 / dashboard.num_chunks.max(1) as f64;
+    frame.render_widget(
+        Gauge::default()
+            .block(Block::bordered().title("CDX chunks (q to quit)"))
+            .ratio(ratio.min(1.0))
+            .label(format!(
+                "{}/{} chunks, {} batches published",
+                dashboard.chunks_done, dashboard.num_chunks, dashboard.batches_published
+            )),
+        progress,
+    );
+
+    let rows = dashboard.queues.iter().map(|(name, messages, consumers)| {
+        Row::new([name.clone(), messages.to_string(), consumers.to_string()])
+    });
+    frame.render_widget(
+        Table::new(
+            rows,
+            [
+                Constraint::Fill(2),
+                Constraint::Fill(1),
+                Constraint::Fill(1),
+            ],
+        )
+        .header(Row::new(["Queue", "Ready", "Consumers"]))
+        .block(Block::bordered().title("Queues")),
+        queues,
+    );
+
+    let rate = |endpoint: &Endpoint, name: &str| {
+        endpoint
+            .rate(name)
+            .map_or("-".to_string(), |rate| format!("{rate:.1}"))
+    };
+    let rows = dashboard.endpoints.iter().map(|endpoint| {
+        let mib_per_sec = endpoint
+            .rate("pipeline_downloaded_bytes_total")
+            .map_or("-".to_string(), |rate| {
+                format!("{:.1}", rate / (1 << 20) as f64)
+            });
+        Row::new([
+            endpoint.url.clone(),
+            endpoint
+                .error
+                .as_ref()
+                .map_or("up".to_string(), |_| "down".to_string()),
+            rate(endpoint, "pipeline_cdx_lines_parsed_total"),
+            rate(endpoint, "pipeline_batches_published_total"),
+            rate(endpoint, "pipeline_batches_consumed_total"),
+            mib_per_sec,
+            format!("{:.0}", endpoint.total("pipeline_http_errors_total")),
+        ])
+    });
+    frame.render_widget(
+        Table::new(
+            rows,
+            [
+                Constraint::Fill(3),
+                Constraint::Fill(1),
+                Constraint::Fill(1),
+                Constraint::Fill(1),
+                Constraint::Fill(1),
+                Constraint::Fill(1),
+                Constraint::Fill(1),
+            ],
+        )
+        .header(Row::new([
+            "Process",
+            "Status",
+            "Lines/s",
+            "Published/s",
+            "Consumed/s",
+            "MiB/s",
+            "HTTP errors",
+        ]))
+        .block(Block::bordered().title("Batcher and workers")),
+        endpoints,
+    );
+
+    frame.render_widget(
+        List::new(dashboard.errors.iter().rev().map(String::as_str))
+            .block(Block::bordered().title("Recent errors, newest first")),
+        errors,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dashboard::{parse_prometheus, Dashboard};
+
+    #[test]
+    fn reports_rates_and_new_errors() {
+        let values = parse_prometheus(
+            "# TYPE pipeline_http_errors_total counter\n\
+             pipeline_http_errors_total{kind=\"5xx\"} 2\n\
+             pipeline_batches_consumed_total 10\n\
+             garbage\n",
+        );
+        assert_eq!(values.len(), 2);
+        assert_eq!(values["pipeline_http_errors_total{kind=\"5xx\"}"], 2.0);
+
+        let mut dashboard = Dashboard::new(&["http://127.0.0.1:9001/metrics".to_string()], 10, 2);
+        let metrics = |errors: u32, consumed: u32| {
+            Ok(format!(
+                "pipeline_http_errors_total{{kind=\"5xx\"}} {errors}\n\
+                 pipeline_http_errors_total{{kind=\"network\"}} 1\n\
+                 pipeline_batches_consumed_total {consumed}\n"
+            ))
+        };
+        dashboard.record_scrape(0, metrics(2, 10));
+        assert!(dashboard.errors.is_empty());
+        assert_eq!(
+            dashboard.endpoints[0].rate("pipeline_batches_consumed_total"),
+            None
+        );
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        dashboard.record_scrape(0, metrics(5, 12));
+        assert_eq!(
+            dashboard.endpoints[0].total("pipeline_http_errors_total"),
+            6.0
+        );
+        assert!(dashboard.endpoints[0]
+            .rate("pipeline_batches_consumed_total")
+            .is_some_and(|rate| rate > 0.0));
+        assert_eq!(
+            dashboard.errors,
+            ["http://127.0.0.1:9001/metrics: 3 failed requests to Common Crawl"]
+        );
+
+        dashboard.record_scrape(0, Err(anyhow::anyhow!("connection refused")));
+        dashboard.record_scrape(0, Err(anyhow::anyhow!("connection refused")));
+        dashboard.record_scrape(0, metrics(5, 12));
+        assert!(dashboard.endpoints[0].error.is_none());
+        assert_eq!(dashboard.errors.len(), 2);
+        assert_eq!(
+            dashboard.errors[1],
+            "http://127.0.0.1:9001/metrics: connection refused"
+        );
+    }
+}
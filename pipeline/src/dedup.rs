@@ -0,0 +1,673 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use crate::{output::Document, state_store::StateStore};
+
+/// The xxh3 of a document's text, the key of exact content dedup.
+pub fn content_hash(text: &str) -> u64 {
+    xxhash_rust::xxh3::xxh3_64(text.as_bytes())
+}
+
+/// Template skeletons with fewer lines are too generic to tell pages apart.
+pub const MIN_TEMPLATE_LINES: usize = 5;
+/// Template skeletons with fewer masked numbers and URLs are mostly prose, and unrelated
+/// articles of the same length would share them.
+pub const MIN_TEMPLATE_MASKS: usize = 3;
+
+/// Characters between two digits that are still one number, e.g. `1.234,56`, `12:30` or
+/// `22/07/2024`, so the formats of different locales mask the same way.
+const NUMBER_SEPARATORS: &[char] = &['.', ',', '\'', '’', '/', '-', ':'];
+/// Characters between two letters that are still one word, e.g. `l'homme` or `e-mail`.
+const WORD_JOINERS: &[char] = &['\'', '’', '-'];
+
+fn is_url(token: &str) -> bool {
+    let token = token.trim_start_matches(|c: char| !c.is_alphanumeric());
+    token.starts_with("http://")
+        || token.starts_with("https://")
+        || token.starts_with("www.")
+        || token
+            .split_once('@')
+            .is_some_and(|(_, host)| host.contains('.'))
+}
+
+/// Full-width and locale-specific punctuation as its ASCII counterpart.
+fn normalize_punctuation(c: char) -> char {
+    match c {
+        '，' | '、' | '٫' | '،' => ',',
+        '。' | '．' => '.',
+        '：' => ':',
+        '；' | '؛' => ';',
+        '！' => '!',
+        '？' | '؟' => '?',
+        '（' => '(',
+        '）' => ')',
+        '«' | '»' | '„' | '“' | '”' | '「' | '」' | '『' | '』' => '"',
+        '–' | '—' => '-',
+        c => c,
+    }
+}
+
+/// One line as `w` for each run of words, `#` for each number and `U` for each URL or
+/// email address, keeping the punctuation in between.
+fn line_shape(line: &str) -> String {
+    let mut shape = String::new();
+    for token in line.split_whitespace() {
+        if is_url(token) {
+            shape.push('U');
+            continue;
+        }
+        let chars = token.chars().collect::<Vec<_>>();
+        for (i, &c) in chars.iter().enumerate() {
+            let next = chars.get(i + 1).copied();
+            let class = if c.is_numeric() {
+                '#'
+            } else if c.is_alphabetic() {
+                'w'
+            } else if (shape.ends_with('#')
+                && NUMBER_SEPARATORS.contains(&c)
+                && next.is_some_and(char::is_numeric))
+                || (shape.ends_with('w')
+                    && WORD_JOINERS.contains(&c)
+                    && next.is_some_and(char::is_alphabetic))
+            {
+                continue;
+            } else {
+                normalize_punctuation(c)
+            };
+            if !(matches!(class, 'w' | '#') && shape.ends_with(class)) {
+                shape.push(class);
+            }
+        }
+    }
+    shape
+}
+
+/// The structure of `text` without its words: every line as its [`line_shape`]. The
+/// translations of a template page share it even though no word is the same.
+pub fn template_skeleton(text: &str) -> String {
+    text.lines()
+        .map(line_shape)
+        .filter(|shape| !shape.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The xxh3 of the [`template_skeleton`], or `None` if it is too generic to dedup on.
+pub fn template_hash(text: &str) -> Option<u64> {
+    let skeleton = template_skeleton(text);
+    let masks = skeleton.chars().filter(|c| matches!(c, '#' | 'U')).count();
+    (skeleton.lines().count() >= MIN_TEMPLATE_LINES && masks >= MIN_TEMPLATE_MASKS)
+        .then(|| content_hash(&skeleton))
+}
+
+/// Words per shingle of the near-duplicate signatures.
+pub const SHINGLE_WORDS: usize = 5;
+/// MinHash bands and rows per band. Two texts share a band with a probability of 50% at a
+/// Jaccard similarity of about 0.7, 92% at 0.8 and more than 99.9% at 0.9.
+pub const MINHASH_BANDS: usize = 14;
+pub const MINHASH_ROWS: usize = 8;
+/// SimHash bits two near duplicates may differ in.
+pub const SIMHASH_MAX_DISTANCE: u32 = 3;
+/// The 64 bits of a SimHash in 6 blocks. Two hashes at most 3 bits apart agree on at
+/// least 3 of them, so every combination of 3 blocks is a key.
+const SIMHASH_BLOCKS: [(u32, u32); 6] = [(0, 11), (11, 11), (22, 11), (33, 11), (44, 10), (54, 10)];
+
+/// The xxh3 of every window of [`SHINGLE_WORDS`] lowercase words of `text`, ignoring
+/// punctuation. A text with fewer words is one shingle.
+pub fn shingles(text: &str) -> Vec<u64> {
+    let words = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>();
+    if words.is_empty() {
+        return Vec::new();
+    }
+    words
+        .windows(SHINGLE_WORDS.min(words.len()))
+        .map(|window| content_hash(&window.join(" ")))
+        .collect()
+}
+
+/// `n` multiply-add hash functions on 64 bits, from a fixed seed so all workers agree.
+fn hash_functions(n: usize) -> Vec<(u64, u64)> {
+    let mut state = 0x5eed_u64;
+    let mut next = || {
+        // splitmix64
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    };
+    (0..n).map(|_| (next() | 1, next())).collect()
+}
+
+/// The MinHash signature of `text`, [`MINHASH_BANDS`] times [`MINHASH_ROWS`] values, or
+/// `None` for a text without words. The share of equal values estimates the Jaccard
+/// similarity of the shingles of two texts.
+pub fn minhash(text: &str) -> Option<Vec<u32>> {
+    let shingles = shingles(text);
+    if shingles.is_empty() {
+        return None;
+    }
+    let functions = hash_functions(MINHASH_BANDS * MINHASH_ROWS);
+    Some(
+        functions
+            .iter()
+            .map(|(a, b)| {
+                shingles
+                    .iter()
+                    .map(|shingle| (a.wrapping_mul(*shingle).wrapping_add(*b) >> 32) as u32)
+                    .min()
+                    .unwrap()
+            })
+            .collect(),
+    )
+}
+
+/// The 64-bit SimHash of the shingles of `text`, or `None` for a text without words.
+/// Similar texts have hashes that differ in few bits.
+pub fn simhash(text: &str) -> Option<u64> {
+    let shingles = shingles(text);
+    if shingles.is_empty() {
+        return None;
+    }
+    let mut weights = [0i64; 64];
+    for shingle in &shingles {
+        for (bit, weight) in weights.iter_mut().enumerate() {
+            *weight += if shingle >> bit & 1 == 1 { 1 } else { -1 };
+        }
+    }
+    Some(
+        weights
+            .iter()
+            .enumerate()
+            .filter(|(_, weight)| **weight > 0)
+            .fold(0, |hash, (bit, _)| hash | 1 << bit),
+    )
+}
+
+/// One key per MinHash band, equal for two texts if all rows of the band are.
+fn minhash_keys(text: &str) -> Vec<u64> {
+    let Some(signature) = minhash(text) else {
+        return Vec::new();
+    };
+    signature
+        .chunks(MINHASH_ROWS)
+        .enumerate()
+        .map(|(band, rows)| {
+            let bytes = rows
+                .iter()
+                .flat_map(|row| row.to_le_bytes())
+                .collect::<Vec<_>>();
+            xxhash_rust::xxh3::xxh3_64_with_seed(&bytes, band as u64)
+        })
+        .collect()
+}
+
+/// One key per combination of 3 of the 6 blocks of the SimHash, so two texts at most
+/// [`SIMHASH_MAX_DISTANCE`] bits apart share at least one.
+fn simhash_keys(text: &str) -> Vec<u64> {
+    let Some(hash) = simhash(text) else {
+        return Vec::new();
+    };
+    let masks = SIMHASH_BLOCKS.map(|(start, len)| ((1u64 << len) - 1) << start);
+    let mut keys = Vec::new();
+    for (i, first) in masks.iter().enumerate() {
+        for (j, second) in masks.iter().enumerate().skip(i + 1) {
+            for (k, third) in masks.iter().enumerate().skip(j + 1) {
+                let seed = (i * 36 + j * 6 + k) as u64;
+                keys.push(xxhash_rust::xxh3::xxh3_64_with_seed(
+                    &(hash & (first | second | third)).to_le_bytes(),
+                    seed,
+                ));
+            }
+        }
+    }
+    keys
+}
+
+/// How near duplicates are found, with `worker --near-dedup`.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NearDedupMethod {
+    /// Bands of a [`minhash`] signature, for texts sharing most of their shingles.
+    #[value(name = "minhash")]
+    MinHash,
+    /// Blocks of a [`simhash`], for texts whose hashes differ in at most
+    /// [`SIMHASH_MAX_DISTANCE`] bits.
+    #[value(name = "simhash")]
+    SimHash,
+}
+
+/// What happens to a near duplicate.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NearDedupAction {
+    /// Leave it out of the output.
+    #[default]
+    Drop,
+    /// Write it with the ID of the earlier document in `near_duplicate_of`.
+    Flag,
+}
+
+/// What a [`ContentDedup`] compares documents by.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DedupKey {
+    /// The exact text.
+    #[default]
+    Text,
+    /// The [`template_hash`], which also matches machine-translated copies of a page but
+    /// can match distinct pages with the same layout. Documents without one are kept.
+    Template,
+    /// The band keys of a [`minhash`] signature.
+    MinHash,
+    /// The block keys of a [`simhash`].
+    SimHash,
+}
+
+impl From<NearDedupMethod> for DedupKey {
+    fn from(method: NearDedupMethod) -> Self {
+        match method {
+            NearDedupMethod::MinHash => DedupKey::MinHash,
+            NearDedupMethod::SimHash => DedupKey::SimHash,
+        }
+    }
+}
+
+impl DedupKey {
+    /// The keys of `text`, a document matches an earlier one if any key does.
+    fn hashes(self, text: &str) -> Vec<u64> {
+        match self {
+            DedupKey::Text => vec![content_hash(text)],
+            DedupKey::Template => template_hash(text).into_iter().collect(),
+            DedupKey::MinHash => minhash_keys(text),
+            DedupKey::SimHash => simhash_keys(text),
+        }
+    }
+
+    /// The prefix of the keys in Redis and the state store.
+    fn key_prefix(self) -> &'static str {
+        match self {
+            DedupKey::Text => "pipeline:content",
+            DedupKey::Template => "pipeline:template",
+            DedupKey::MinHash => "pipeline:minhash",
+            DedupKey::SimHash => "pipeline:simhash",
+        }
+    }
+}
+
+/// The partition owning `hash`. Each of the `num_partitions` owns a contiguous range of
+/// hash prefixes, so adding stores splits the keyspace evenly.
+pub fn partition_of(hash: u64, num_partitions: usize) -> usize {
+    (((hash >> 32) * num_partitions as u64) >> 32) as usize
+}
+
+/// The ID of the document that first had each hash, forgetting the oldest hashes beyond
+/// `capacity`.
+#[derive(Default)]
+struct LocalKeys {
+    ids: HashMap<u64, String>,
+    order: VecDeque<u64>,
+    capacity: usize,
+}
+
+impl LocalKeys {
+    /// The ID of the first document with `hash`, which is `id` if the hash is new.
+    fn first_id(&mut self, hash: u64, id: &str) -> &str {
+        if !self.ids.contains_key(&hash) {
+            if self.order.len() >= self.capacity {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.ids.remove(&oldest);
+                }
+            }
+            self.order.push_back(hash);
+            self.ids.insert(hash, id.to_string());
+        }
+        &self.ids[&hash]
+    }
+}
+
+enum Partition {
+    Local(Mutex<LocalKeys>),
+    #[cfg(feature = "redis")]
+    Redis(redis::aio::MultiplexedConnection),
+    Store(Arc<dyn StateStore>),
+}
+
+/// Exact or near dedup of document texts across all consumer tasks, and with the `redis`
+/// feature or a shared [`StateStore`] across workers. The keyspace is split by hash prefix into
+/// partitions that live in memory or each in their own Redis instance, so no single store
+/// sees every lookup.
+/// Lookups of one batch are grouped per partition and sent as one pipeline each.
+///
+/// A store remembers which document ID first had a text, so a redelivered batch whose
+/// outputs were rolled back keeps its own documents.
+pub struct ContentDedup {
+    partitions: Vec<Partition>,
+    key: DedupKey,
+}
+
+impl ContentDedup {
+    /// `num_partitions` in-memory partitions, only shared within this process. They
+    /// remember any number of keys until [`ContentDedup::with_capacity`].
+    pub fn in_memory(num_partitions: usize) -> Self {
+        Self {
+            partitions: (0..num_partitions.max(1))
+                .map(|_| {
+                    Partition::Local(Mutex::new(LocalKeys {
+                        capacity: usize::MAX,
+                        ..LocalKeys::default()
+                    }))
+                })
+                .collect(),
+            key: DedupKey::default(),
+        }
+    }
+
+    /// Keeps at most `max_keys` keys in memory, split evenly over the partitions, and
+    /// forgets the oldest beyond that. A duplicate of a forgotten key is kept again.
+    /// Stores outside the process keep all keys.
+    pub fn with_capacity(self, max_keys: usize) -> Self {
+        let per_partition = (max_keys / self.partitions.len()).max(1);
+        for partition in &self.partitions {
+            if let Partition::Local(keys) = partition {
+                keys.lock().unwrap().capacity = per_partition;
+            }
+        }
+        self
+    }
+
+    /// One partition per Redis URL, in order. Every worker has to list the same URLs in
+    /// the same order to agree on which store owns a hash.
+    #[cfg(feature = "redis")]
+    pub async fn redis(redis_urls: &[String]) -> Result<Self, anyhow::Error> {
+        let mut partitions = Vec::new();
+        for redis_url in redis_urls {
+            let client = redis::Client::open(redis_url.as_str())?;
+            partitions.push(Partition::Redis(
+                client.get_multiplexed_async_connection().await?,
+            ));
+        }
+        anyhow::ensure!(!partitions.is_empty(), "No Redis URL for the dedup store");
+        Ok(Self {
+            partitions,
+            key: DedupKey::default(),
+        })
+    }
+
+    /// One partition in `store`, which also keeps the keys across runs.
+    pub fn in_store(store: Arc<dyn StateStore>) -> Self {
+        Self {
+            partitions: vec![Partition::Store(store)],
+            key: DedupKey::default(),
+        }
+    }
+
+    pub fn with_key(mut self, key: DedupKey) -> Self {
+        self.key = key;
+        self
+    }
+
+    pub fn num_partitions(&self) -> usize {
+        self.partitions.len()
+    }
+
+    /// Drops the documents whose key was first seen in another document, also earlier in
+    /// `documents`, and remembers the others.
+    pub async fn retain_new(
+        &self,
+        documents: Vec<Document>,
+    ) -> Result<Vec<Document>, anyhow::Error> {
+        let originals = self.originals(&documents).await?;
+        Ok(documents
+            .into_iter()
+            .zip(originals)
+            .filter_map(|(document, original)| original.is_none().then_some(document))
+            .collect())
+    }
+
+    /// For each document, the ID of another document that had one of its keys first,
+    /// also earlier in `documents`. All keys are remembered, also those of duplicates.
+    pub async fn originals(
+        &self,
+        documents: &[Document],
+    ) -> Result<Vec<Option<String>>, anyhow::Error> {
+        let mut by_partition = vec![Vec::new(); self.partitions.len()];
+        let mut originals = vec![None; documents.len()];
+        for (i, document) in documents.iter().enumerate() {
+            for hash in self.key.hashes(&document.text) {
+                by_partition[partition_of(hash, self.partitions.len())].push((i, hash));
+            }
+        }
+        let mut found = |i: usize, first_id: &str| {
+            if first_id != documents[i].id && originals[i].is_none() {
+                originals[i] = Some(first_id.to_string());
+            }
+        };
+        for (partition, lookups) in self.partitions.iter().zip(by_partition) {
+            if lookups.is_empty() {
+                continue;
+            }
+            match partition {
+                Partition::Local(seen) => {
+                    let mut seen = seen.lock().unwrap();
+                    for (i, hash) in lookups {
+                        found(i, seen.first_id(hash, &documents[i].id));
+                    }
+                }
+                #[cfg(feature = "redis")]
+                Partition::Redis(redis) => {
+                    let mut pipe = redis::pipe();
+                    // `SET NX GET` needs Redis 7, it returns the ID already stored.
+                    for (i, hash) in &lookups {
+                        pipe.cmd("SET")
+                            .arg(store_key(self.key, *hash))
+                            .arg(&documents[*i].id)
+                            .arg("NX")
+                            .arg("GET");
+                    }
+                    let replies = pipe
+                        .query_async::<Vec<Option<String>>>(&mut redis.clone())
+                        .await?;
+                    for ((i, _), first_id) in lookups.into_iter().zip(replies) {
+                        if let Some(first_id) = first_id {
+                            found(i, &first_id);
+                        }
+                    }
+                }
+                Partition::Store(store) => {
+                    let claims = lookups
+                        .iter()
+                        .map(|(i, hash)| (store_key(self.key, *hash), documents[*i].id.clone()))
+                        .collect::<Vec<_>>();
+                    for ((i, _), first_id) in lookups.into_iter().zip(store.claim_keys(&claims)?) {
+                        found(i, &first_id);
+                    }
+                }
+            }
+        }
+        Ok(originals)
+    }
+}
+
+fn store_key(key: DedupKey, hash: u64) -> String {
+    format!("{}:{hash:016x}", key.key_prefix())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        dedup::{
+            minhash, partition_of, simhash, template_hash, template_skeleton, ContentDedup,
+            DedupKey, SIMHASH_MAX_DISTANCE,
+        },
+        output::Document,
+    };
+
+    fn document(id: &str, text: &str) -> Document {
+        Document {
+            id: id.to_string(),
+            url: "https://example.com/".to_string(),
+            timestamp: "20240722120756".to_string(),
+            language: "eng".to_string(),
+            token_count: 1,
+            text: text.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn partitions_by_hash_prefix() {
+        assert_eq!(partition_of(0, 4), 0);
+        assert_eq!(partition_of(0x3fff_ffff_ffff_ffff, 4), 0);
+        assert_eq!(partition_of(0x4000_0000_0000_0000, 4), 1);
+        assert_eq!(partition_of(u64::MAX, 4), 3);
+        assert_eq!(partition_of(u64::MAX, 1), 0);
+    }
+
+    #[tokio::test]
+    async fn drops_repeated_texts_across_batches() {
+        let dedup = ContentDedup::in_memory(4);
+        let first = dedup
+            .retain_new(vec![
+                document("1", "a"),
+                document("2", "b"),
+                document("3", "a"),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(first.len(), 2);
+        let second = dedup
+            .retain_new(vec![document("4", "b"), document("5", "c")])
+            .await
+            .unwrap();
+        let ids =
+            |documents: &[Document]| documents.iter().map(|d| d.id.clone()).collect::<Vec<_>>();
+        assert_eq!(ids(&second), vec!["5"]);
+        // A redelivered batch keeps the documents it contributed first.
+        let redelivered = dedup
+            .retain_new(vec![document("1", "a"), document("3", "a")])
+            .await
+            .unwrap();
+        assert_eq!(ids(&redelivered), vec!["1"]);
+    }
+
+    #[tokio::test]
+    async fn forgets_the_oldest_keys_beyond_the_capacity() {
+        let dedup = ContentDedup::in_memory(1).with_capacity(2);
+        let ids =
+            |documents: Vec<Document>| documents.into_iter().map(|d| d.id).collect::<Vec<_>>();
+        let first = dedup
+            .retain_new(vec![
+                document("1", "a"),
+                document("2", "b"),
+                document("3", "c"),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(ids(first), vec!["1", "2", "3"]);
+        let second = dedup
+            .retain_new(vec![document("4", "c"), document("5", "a")])
+            .await
+            .unwrap();
+        assert_eq!(ids(second), vec!["5"]);
+    }
+
+    const ENGLISH_TEMPLATE: &str = "Hotel Bellevue in Lucerne\n\
+        Price per night: 1,234.50 CHF\n\
+        Rated 4.5 out of 5 by 312 guests\n\
+        Check-in from 14:00, check-out until 11:00\n\
+        Book now at https://example.com/book?id=17";
+    const GERMAN_TEMPLATE: &str = "Hotel Bellevue in Luzern\n\
+        Preis pro Nacht: 1.234,50 CHF\n\
+        Bewertet mit 4,5 von 5 von 312 Gästen\n\
+        Check-in ab 14:00, Check-out bis 11:00\n\
+        Jetzt buchen unter https://example.com/de/book?id=17";
+
+    #[test]
+    fn masks_words_numbers_and_urls() {
+        assert_eq!(
+            template_skeleton(ENGLISH_TEMPLATE),
+            "w\nw:#w\nw#w#w#w\nw#,w#\nwU"
+        );
+        assert_eq!(template_skeleton("「東京」まで２時間。"), "\"w\"w#w.");
+    }
+
+    #[tokio::test]
+    async fn drops_translated_templates() {
+        assert_eq!(
+            template_hash(ENGLISH_TEMPLATE),
+            template_hash(GERMAN_TEMPLATE)
+        );
+        let prose = "A first paragraph.\nA second one.\nThe third.\nA fourth.\nThe end.";
+        assert_eq!(template_hash(prose), None);
+
+        let dedup = ContentDedup::in_memory(2).with_key(DedupKey::Template);
+        let kept = dedup
+            .retain_new(vec![
+                document("1", ENGLISH_TEMPLATE),
+                document("2", GERMAN_TEMPLATE),
+                document("3", prose),
+                document("4", prose),
+            ])
+            .await
+            .unwrap();
+        let ids = kept.iter().map(|d| d.id.as_str()).collect::<Vec<_>>();
+        assert_eq!(ids, vec!["1", "3", "4"]);
+    }
+
+    /// 2000 words of text, the same for the same `seed`. SimHash needs long texts for a
+    /// small edit to move only a few bits.
+    fn article(seed: u64) -> String {
+        let words = [
+            "river", "market", "council", "school", "winter", "harbor", "bridge", "festival",
+            "library", "railway", "garden", "museum", "election", "budget",
+        ];
+        let mut state = seed;
+        (0..2000)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                words[(state >> 33) as usize % words.len()]
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    #[tokio::test]
+    async fn finds_near_duplicates() {
+        let original = article(1);
+        // A different first sentence and the end cut off.
+        let edited = format!("Updated: {}", &original[..original.len() - 40]);
+        let unrelated = article(2);
+
+        let similarity = |a: &str, b: &str| {
+            let (a, b) = (minhash(a).unwrap(), minhash(b).unwrap());
+            a.iter().zip(&b).filter(|(a, b)| a == b).count() as f64 / a.len() as f64
+        };
+        assert!(similarity(&original, &edited) > 0.9);
+        assert!(similarity(&original, &unrelated) < 0.1);
+        let distance = |a: &str, b: &str| (simhash(a).unwrap() ^ simhash(b).unwrap()).count_ones();
+        assert!(distance(&original, &edited) <= SIMHASH_MAX_DISTANCE);
+        assert!(distance(&original, &unrelated) > SIMHASH_MAX_DISTANCE);
+        assert_eq!(minhash(" ... "), None);
+
+        for key in [DedupKey::MinHash, DedupKey::SimHash] {
+            let dedup = ContentDedup::in_memory(4).with_key(key);
+            let documents = vec![
+                document("1", &original),
+                document("2", &edited),
+                document("3", &unrelated),
+                document("4", ""),
+            ];
+            let originals = dedup.originals(&documents).await.unwrap();
+            assert_eq!(originals, [None, Some("1".to_string()), None, None]);
+            // Redelivered, the original is still its own.
+            let originals = dedup.originals(&documents[..1]).await.unwrap();
+            assert_eq!(originals, [None]);
+        }
+    }
+}
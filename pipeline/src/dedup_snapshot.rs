@@ -0,0 +1,179 @@
+//! Snapshots of the content keys of a [`StateStore`], so the dedup of incremental runs can
+//! move to another machine or another kind of store.
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::Context;
+
+use crate::{http_client::HttpOptions, state_store::StateStore};
+
+/// Keys read or claimed per call to the store.
+const PAGE_SIZE: usize = 10_000;
+
+/// Writes a snapshot of `store` to `destination`, a file or with the `s3` feature an
+/// `s3://bucket/key` URL, and returns how many keys it has.
+pub async fn snapshot_to(
+    store: &dyn StateStore,
+    destination: &str,
+    http: &HttpOptions,
+) -> Result<u64, anyhow::Error> {
+    if !destination.starts_with("s3://") {
+        return write_snapshot(store, Path::new(destination));
+    }
+    #[cfg(feature = "s3")]
+    {
+        let path = temp_snapshot_path();
+        let num_keys = write_snapshot(store, &path)?;
+        let (bucket, key) = s3_location(destination)?;
+        let uploaded = s3_client(http)?
+            .upload_file(&path, &bucket, &key, crate::s3::MIN_PART_SIZE)
+            .await;
+        std::fs::remove_file(&path)?;
+        uploaded?;
+        Ok(num_keys)
+    }
+    #[cfg(not(feature = "s3"))]
+    {
+        let _ = http;
+        anyhow::bail!("Cannot write to {destination}, built without the s3 feature")
+    }
+}
+
+/// Restores the snapshot at `source`, a file or an `s3://bucket/key` URL, into `store`,
+/// see [`restore_snapshot`].
+pub async fn restore_from(
+    store: &dyn StateStore,
+    source: &str,
+    http: &HttpOptions,
+) -> Result<u64, anyhow::Error> {
+    if !source.starts_with("s3://") {
+        return restore_snapshot(store, Path::new(source));
+    }
+    #[cfg(feature = "s3")]
+    {
+        let path = temp_snapshot_path();
+        let (bucket, key) = s3_location(source)?;
+        let restored = match s3_client(http)?.download_file(&bucket, &key, &path).await {
+            Ok(()) => restore_snapshot(store, &path),
+            Err(e) => Err(e),
+        };
+        std::fs::remove_file(&path).ok();
+        restored
+    }
+    #[cfg(not(feature = "s3"))]
+    {
+        let _ = http;
+        anyhow::bail!("Cannot read {source}, built without the s3 feature")
+    }
+}
+
+#[cfg(feature = "s3")]
+fn temp_snapshot_path() -> std::path::PathBuf {
+    std::env::temp_dir().join(format!(
+        "pipeline-dedup-snapshot-{}.tsv.zst",
+        std::process::id()
+    ))
+}
+
+#[cfg(feature = "s3")]
+fn s3_location(url: &str) -> Result<(String, String), anyhow::Error> {
+    let destination = crate::s3::S3Destination::parse(url, "")?;
+    anyhow::ensure!(!destination.prefix.is_empty(), "{url} has no key");
+    Ok((destination.bucket, destination.prefix))
+}
+
+#[cfg(feature = "s3")]
+fn s3_client(http: &HttpOptions) -> Result<crate::s3::S3Client, anyhow::Error> {
+    Ok(
+        crate::s3::S3Client::from_env(crate::http_client::build_http_client(http)?)?
+            .with_retries(http.backoff(), 5),
+    )
+}
+
+/// Writes every content key of `store` and its document ID to `path`, as zstd-compressed
+/// tab-separated lines, and returns how many there were. The file is written under a
+/// hidden name first, so a broken off snapshot never replaces a good one.
+pub fn write_snapshot(store: &dyn StateStore, path: &Path) -> Result<u64, anyhow::Error> {
+    let staged = path.with_file_name(format!(
+        ".{}",
+        path.file_name().unwrap_or_default().to_string_lossy()
+    ));
+    let mut out = zstd::Encoder::new(BufWriter::new(File::create(&staged)?), 3)?;
+    let mut num_keys = 0;
+    let mut cursor = None;
+    loop {
+        let page = store.content_keys(cursor.as_deref(), PAGE_SIZE)?;
+        for (key, document_id) in page.keys {
+            writeln!(out, "{key}\t{document_id}")?;
+            num_keys += 1;
+        }
+        match page.next {
+            Some(next) => cursor = Some(next),
+            None => break,
+        }
+    }
+    out.finish()?.into_inner()?.sync_all()?;
+    std::fs::rename(staged, path)?;
+    Ok(num_keys)
+}
+
+/// Claims the keys of the snapshot at `path` in `store` and returns how many there were.
+/// Keys the store has already keep their document, so a snapshot can be restored into a
+/// store that is in use.
+pub fn restore_snapshot(store: &dyn StateStore, path: &Path) -> Result<u64, anyhow::Error> {
+    let reader = BufReader::new(zstd::Decoder::new(File::open(path)?)?);
+    let mut num_keys = 0;
+    let mut claims = Vec::with_capacity(PAGE_SIZE);
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        let (key, document_id) = line
+            .split_once('\t')
+            .with_context(|| format!("Line {} of {} has no tab", number + 1, path.display()))?;
+        claims.push((key.to_string(), document_id.to_string()));
+        if claims.len() == PAGE_SIZE {
+            store.claim_keys(&claims)?;
+            num_keys += claims.len() as u64;
+            claims.clear();
+        }
+    }
+    store.claim_keys(&claims)?;
+    num_keys += claims.len() as u64;
+    Ok(num_keys)
+}
+
+#[cfg(all(test, feature = "run-db"))]
+mod tests {
+    use crate::{
+        dedup_snapshot::{restore_snapshot, write_snapshot},
+        run_db::RunDb,
+        state_store::StateStore,
+    };
+
+    #[test]
+    fn restores_keys_without_replacing_claimed_ones() {
+        let dir = std::env::temp_dir().join(format!("pipeline-snapshot-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let claim = |key: &str, document_id: &str| (key.to_string(), document_id.to_string());
+        let source = RunDb::open(&dir.join("source.sqlite")).unwrap();
+        source
+            .claim_keys(&[claim("pipeline:content:01", "1"), claim("digest:02", "2")])
+            .unwrap();
+        let snapshot = dir.join("keys.tsv.zst");
+        assert_eq!(write_snapshot(&source, &snapshot).unwrap(), 2);
+
+        let target = RunDb::open(&dir.join("target.sqlite")).unwrap();
+        target.claim_keys(&[claim("digest:02", "3")]).unwrap();
+        assert_eq!(restore_snapshot(&target, &snapshot).unwrap(), 2);
+        assert_eq!(
+            target
+                .claim_keys(&[claim("pipeline:content:01", "4"), claim("digest:02", "4")])
+                .unwrap(),
+            vec!["1", "3"]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
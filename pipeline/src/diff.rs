@@ -0,0 +1,124 @@
+use std::collections::BTreeMap;
+
+use crate::cdx::CdxEntry;
+
+/// How a URL differs between two crawls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Change {
+    /// Only captured in crawl B.
+    New,
+    /// Only captured in crawl A.
+    Disappeared,
+    /// Captured in both, with different payload digests.
+    Changed,
+}
+
+impl Change {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Change::New => "new",
+            Change::Disappeared => "disappeared",
+            Change::Changed => "changed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UrlChange {
+    pub change: Change,
+    pub url: String,
+    pub digest_a: Option<String>,
+    pub digest_b: Option<String>,
+}
+
+/// The latest successful capture of every URL, keyed by SURT so `http`/`https` and `www`
+/// variants of a URL count as the same page.
+fn latest_by_surt(captures: &[CdxEntry]) -> BTreeMap<&str, &CdxEntry> {
+    let mut latest = BTreeMap::<&str, &CdxEntry>::new();
+    for capture in captures.iter().filter(|c| c.metadata.status == 200) {
+        let current = latest.entry(&capture.surt_url).or_insert(capture);
+        if capture.timestamp > current.timestamp {
+            *current = capture;
+        }
+    }
+    latest
+}
+
+/// Compares the captures of two crawls URL by URL, ordered by SURT. Only status 200
+/// captures count, so a page that turned into an error page has disappeared.
+pub fn diff_captures(a: &[CdxEntry], b: &[CdxEntry]) -> Vec<UrlChange> {
+    let latest_a = latest_by_surt(a);
+    let latest_b = latest_by_surt(b);
+    let mut surts = latest_a.keys().chain(latest_b.keys()).collect::<Vec<_>>();
+    surts.sort();
+    surts.dedup();
+    surts
+        .into_iter()
+        .filter_map(|surt| {
+            let a = latest_a.get(surt);
+            let b = latest_b.get(surt);
+            let change = match (a, b) {
+                (None, Some(_)) => Change::New,
+                (Some(_), None) => Change::Disappeared,
+                (Some(a), Some(b)) if a.metadata.digest != b.metadata.digest => Change::Changed,
+                _ => return None,
+            };
+            Some(UrlChange {
+                change,
+                url: b.or(a).unwrap().metadata.url.clone(),
+                digest_a: a.and_then(|a| a.metadata.digest.clone()),
+                digest_b: b.and_then(|b| b.metadata.digest.clone()),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        cdx::{parse_cdx_line, CdxEntry},
+        diff::{diff_captures, Change},
+    };
+
+    fn capture(host: &str, timestamp: &str, status: usize, digest: &str) -> CdxEntry {
+        parse_cdx_line(&format!(
+            r#"com,{host})/ {timestamp} {{"url": "https://{host}.com/", "status": "{status}", "digest": "{digest}", "length": "1", "offset": "0", "filename": "f.warc.gz"}}"#
+        )).unwrap()
+    }
+
+    #[test]
+    fn reports_new_disappeared_and_changed_urls() {
+        let a = [
+            capture("a", "20240601000000", 200, "SAME"),
+            capture("b", "20240601000000", 200, "OLD"),
+            capture("c", "20240601000000", 200, "GONE"),
+        ];
+        let b = [
+            capture("a", "20240701000000", 200, "SAME"),
+            capture("b", "20240701000000", 200, "OLD"),
+            capture("b", "20240702000000", 200, "NEW"),
+            capture("c", "20240701000000", 404, "ERROR"),
+            capture("d", "20240701000000", 200, "ADDED"),
+        ];
+        let changes = diff_captures(&a, &b)
+            .into_iter()
+            .map(|c| (c.change, c.url, c.digest_b))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            vec![
+                (
+                    Change::Changed,
+                    "https://b.com/".to_string(),
+                    Some("NEW".to_string())
+                ),
+                (Change::Disappeared, "https://c.com/".to_string(), None),
+                (
+                    Change::New,
+                    "https://d.com/".to_string(),
+                    Some("ADDED".to_string())
+                ),
+            ]
+        );
+    }
+}
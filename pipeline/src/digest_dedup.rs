@@ -0,0 +1,280 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use rusqlite::{params, Connection};
+
+use crate::{cdx::CdxEntry, compare::RunCounters, state_store::StateStore};
+
+/// Drops CDX entries whose payload digest an earlier entry of the run already had, the
+/// exact duplicate captures of a crawl. Digests are kept as 128-bit hashes in memory and,
+/// with a spill file, in SQLite once the memory is full, so a whole crawl fits. In a
+/// [`StateStore`] they outlive the process, for `--resume`.
+///
+/// Entries without a digest are always kept.
+pub struct DigestDedup {
+    seen: HashSet<u128>,
+    max_in_memory: usize,
+    spill: Option<Connection>,
+    /// A spill file of our own in the temp directory, removed with the dedup.
+    temp_spill: Option<PathBuf>,
+    store: Option<Arc<dyn StateStore>>,
+    checked: u64,
+    dropped: u64,
+}
+
+impl DigestDedup {
+    /// Keeps every digest in memory.
+    pub fn in_memory() -> Self {
+        Self {
+            seen: HashSet::new(),
+            max_in_memory: usize::MAX,
+            spill: None,
+            temp_spill: None,
+            store: None,
+            checked: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Keeps the digests in `store`, with the keys of the content dedup. Each claims its
+    /// digest for the WARC record of the entry, so after a restart the entries of a chunk
+    /// read again keep their own digests.
+    pub fn in_store(store: Arc<dyn StateStore>) -> Self {
+        let mut dedup = Self::in_memory();
+        dedup.store = Some(store);
+        dedup
+    }
+
+    /// Like [`DigestDedup::with_spill`] with a file in the temp directory, which is
+    /// removed again when the dedup is dropped.
+    pub fn with_temp_spill(self, max_in_memory: usize) -> Result<Self, anyhow::Error> {
+        let path = std::env::temp_dir().join(format!(
+            "pipeline-digest-spill-{}.sqlite",
+            std::process::id()
+        ));
+        let mut dedup = self.with_spill(&path, max_in_memory)?;
+        dedup.temp_spill = Some(path);
+        Ok(dedup)
+    }
+
+    /// Moves on to the SQLite file at `path` after `max_in_memory` digests. The file is
+    /// started over, the digests of earlier runs do not count.
+    pub fn with_spill(mut self, path: &Path, max_in_memory: usize) -> Result<Self, anyhow::Error> {
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        let conn = Connection::open(path)?;
+        // The file is thrown away after the run, so it does not need to survive a crash.
+        conn.execute_batch(
+            "PRAGMA journal_mode = OFF;
+             PRAGMA synchronous = OFF;
+             CREATE TABLE digests (hash BLOB PRIMARY KEY) WITHOUT ROWID;",
+        )?;
+        self.spill = Some(conn);
+        self.max_in_memory = max_in_memory;
+        Ok(self)
+    }
+
+    /// Keeps the first entry of every digest, in order. The entries of one call are
+    /// checked against each other as well.
+    pub fn retain_new<T>(
+        &mut self,
+        entries: Vec<(T, CdxEntry)>,
+    ) -> Result<Vec<(T, CdxEntry)>, anyhow::Error> {
+        if let Some(store) = self.store.as_ref() {
+            let claims = entries
+                .iter()
+                .filter_map(|(_, entry)| {
+                    let digest = entry.metadata.digest.as_deref()?;
+                    let hash = xxhash_rust::xxh3::xxh3_128(digest.as_bytes());
+                    Some((format!("digest:{hash:032x}"), record_id(entry)))
+                })
+                .collect::<Vec<_>>();
+            let mut first_ids = store.claim_keys(&claims)?.into_iter();
+            let mut kept = Vec::with_capacity(entries.len());
+            for (number, entry) in entries {
+                if entry.metadata.digest.is_none() {
+                    kept.push((number, entry));
+                    continue;
+                }
+                self.checked += 1;
+                if first_ids.next().is_some_and(|id| id == record_id(&entry)) {
+                    kept.push((number, entry));
+                } else {
+                    self.dropped += 1;
+                }
+            }
+            return Ok(kept);
+        }
+        let tx = self
+            .spill
+            .as_mut()
+            .map(Connection::transaction)
+            .transpose()?;
+        let mut kept = Vec::with_capacity(entries.len());
+        for (number, entry) in entries {
+            let Some(digest) = entry.metadata.digest.as_deref() else {
+                kept.push((number, entry));
+                continue;
+            };
+            self.checked += 1;
+            let hash = xxhash_rust::xxh3::xxh3_128(digest.as_bytes());
+            let is_new = if self.seen.contains(&hash) {
+                false
+            } else if self.seen.len() < self.max_in_memory {
+                self.seen.insert(hash)
+            } else {
+                let tx = tx.as_ref().expect("Only a spill file limits the memory");
+                let mut insert =
+                    tx.prepare_cached("INSERT OR IGNORE INTO digests (hash) VALUES (?1)")?;
+                insert.execute(params![hash.to_le_bytes()])? == 1
+            };
+            if is_new {
+                kept.push((number, entry));
+            } else {
+                self.dropped += 1;
+            }
+        }
+        if let Some(tx) = tx {
+            tx.commit()?;
+        }
+        Ok(kept)
+    }
+
+    /// `digest_dedup.checked` and `digest_dedup.dropped`, for the run DB.
+    pub fn counters(&self) -> RunCounters {
+        let mut counters = RunCounters::default();
+        counters.add("digest_dedup.checked", self.checked);
+        counters.add("digest_dedup.dropped", self.dropped);
+        counters
+    }
+}
+
+impl Drop for DigestDedup {
+    fn drop(&mut self) {
+        if let Some(path) = self.temp_spill.take() {
+            drop(self.spill.take());
+            if let Err(e) = std::fs::remove_file(&path) {
+                tracing::warn!(err.msg = %e, "Failed to remove {}", path.display());
+            }
+        }
+    }
+}
+
+/// The WARC record of a capture, which tells one entry from another with the same digest.
+fn record_id(entry: &CdxEntry) -> String {
+    format!("{}:{}", entry.metadata.filename, entry.metadata.offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        cdx::{parse_cdx_line, CdxEntry},
+        digest_dedup::DigestDedup,
+        run_db::RunDb,
+    };
+
+    fn entry(path: &str, digest: Option<&str>) -> CdxEntry {
+        record(path, digest, 0)
+    }
+
+    fn record(path: &str, digest: Option<&str>, offset: u64) -> CdxEntry {
+        let digest = digest.map_or(String::new(), |digest| format!(r#", "digest": "{digest}""#));
+        parse_cdx_line(&format!(
+            r#"com,example)/{path} 20240722120756 {{"url": "https://example.com/{path}", "status": "200", "length": "1", "offset": "{offset}", "filename": "a.warc.gz"{digest}}}"#
+        ))
+        .unwrap()
+    }
+
+    fn paths(entries: &[(usize, CdxEntry)]) -> Vec<String> {
+        entries
+            .iter()
+            .map(|(_, entry)| entry.metadata.url.clone())
+            .collect()
+    }
+
+    #[test]
+    fn drops_repeated_digests_in_memory_and_spilled() {
+        let path = std::env::temp_dir().join(format!(
+            "pipeline-digest-dedup-{}.sqlite",
+            std::process::id()
+        ));
+        let mut dedup = DigestDedup::in_memory().with_spill(&path, 2).unwrap();
+        let kept = dedup
+            .retain_new(vec![
+                (0, entry("a", Some("AAAA"))),
+                (1, entry("b", Some("BBBB"))),
+                (2, entry("a2", Some("AAAA"))),
+                (3, entry("c", Some("CCCC"))),
+                (4, entry("d", None)),
+            ])
+            .unwrap();
+        assert_eq!(
+            paths(&kept),
+            [
+                "https://example.com/a",
+                "https://example.com/b",
+                "https://example.com/c",
+                "https://example.com/d"
+            ]
+        );
+        // `CCCC` went to the spill file, the memory was full.
+        let kept = dedup
+            .retain_new(vec![
+                (5, entry("c2", Some("CCCC"))),
+                (6, entry("b2", Some("BBBB"))),
+                (7, entry("e", Some("EEEE"))),
+            ])
+            .unwrap();
+        assert_eq!(paths(&kept), ["https://example.com/e"]);
+        let counters = dedup.counters();
+        assert_eq!(counters.get("digest_dedup.checked"), 7);
+        assert_eq!(counters.get("digest_dedup.dropped"), 3);
+        drop(dedup);
+        std::fs::remove_file(path).unwrap();
+
+        let dedup = DigestDedup::in_memory().with_temp_spill(1).unwrap();
+        let spill_path = dedup.temp_spill.clone().unwrap();
+        assert!(spill_path.exists());
+        drop(dedup);
+        assert!(!spill_path.exists());
+    }
+
+    #[test]
+    fn keeps_digests_in_the_store_across_restarts() {
+        let path = std::env::temp_dir().join(format!(
+            "pipeline-digest-dedup-store-{}.sqlite",
+            std::process::id()
+        ));
+        let store = Arc::new(RunDb::open(&path).unwrap());
+        let chunk = || {
+            vec![
+                (0, record("a", Some("AAAA"), 0)),
+                (1, record("a2", Some("AAAA"), 100)),
+                (2, record("b", Some("BBBB"), 200)),
+            ]
+        };
+        let mut dedup = DigestDedup::in_store(store.clone());
+        assert_eq!(
+            paths(&dedup.retain_new(chunk()).unwrap()),
+            ["https://example.com/a", "https://example.com/b"]
+        );
+        // After a restart the chunk is read again and keeps the same entries, while a
+        // later chunk knows the digests of the earlier run.
+        let mut dedup = DigestDedup::in_store(store);
+        assert_eq!(
+            paths(&dedup.retain_new(chunk()).unwrap()),
+            ["https://example.com/a", "https://example.com/b"]
+        );
+        let later = vec![(3, record("b2", Some("BBBB"), 300))];
+        assert!(dedup.retain_new(later).unwrap().is_empty());
+        assert_eq!(dedup.counters().get("digest_dedup.dropped"), 2);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,307 @@
+use std::collections::BTreeMap;
+
+use anyhow::Context;
+use lapin::{
+    options::{BasicAckOptions, BasicGetOptions, BasicNackOptions},
+    types::{AMQPValue, FieldTable},
+    BasicProperties, Channel, Queue,
+};
+use serde::Serialize;
+
+use crate::{
+    batch::{batch_id, BatchEncoding, BatchKey},
+    error_code::{ErrorCode, ERROR_CODE_HEADER},
+    queue::QueueMessage,
+    rabbitmq::{header_value, rabbitmq_declare_queue, ReliablePublisher},
+};
+
+/// The dead letter queue of `queue_name`, e.g. `batches.dlq`.
+pub fn dlq_name(queue_name: &str) -> String {
+    format!("{queue_name}.dlq")
+}
+
+/// The queue whose dead letters `dlq_name` holds.
+pub fn source_queue(dlq_name: &str) -> Option<&str> {
+    dlq_name.strip_suffix(".dlq")
+}
+
+/// Arguments of a quorum queue that moves a message to its dead letter queue once it was
+/// delivered `max_deliveries` times without an ack, or is rejected without requeueing.
+/// RabbitMQ counts the deliveries itself, so batches that crash the worker count as well.
+pub fn dead_letter_arguments(queue_name: &str, max_deliveries: u32) -> FieldTable {
+    let mut arguments = FieldTable::default();
+    arguments.insert(
+        "x-queue-type".into(),
+        AMQPValue::LongString("quorum".into()),
+    );
+    arguments.insert(
+        "x-delivery-limit".into(),
+        AMQPValue::LongInt(max_deliveries.saturating_sub(1) as i32),
+    );
+    arguments.insert(
+        "x-dead-letter-exchange".into(),
+        AMQPValue::LongString("".into()),
+    );
+    arguments.insert(
+        "x-dead-letter-routing-key".into(),
+        AMQPValue::LongString(dlq_name(queue_name).as_str().into()),
+    );
+    arguments
+}
+
+/// Declares `queue_name`, and with `max_deliveries` its dead letter queue as well. All
+/// processes declaring a queue have to agree on `max_deliveries`, RabbitMQ refuses to
+/// declare it with other arguments.
+pub async fn declare_queue(
+    channel: &Channel,
+    queue_name: &str,
+    max_deliveries: Option<u32>,
+) -> Result<Queue, anyhow::Error> {
+    let Some(max_deliveries) = max_deliveries else {
+        return rabbitmq_declare_queue(channel, queue_name, FieldTable::default()).await;
+    };
+    let mut dlq_arguments = FieldTable::default();
+    dlq_arguments.insert(
+        "x-queue-type".into(),
+        AMQPValue::LongString("classic".into()),
+    );
+    rabbitmq_declare_queue(channel, &dlq_name(queue_name), dlq_arguments).await?;
+    rabbitmq_declare_queue(
+        channel,
+        queue_name,
+        dead_letter_arguments(queue_name, max_deliveries),
+    )
+    .await
+}
+
+/// Whether a message delivered `previous_deliveries` times before is delivered for the
+/// last time before RabbitMQ dead-letters it.
+pub fn is_last_delivery(previous_deliveries: u32, max_deliveries: u32) -> bool {
+    previous_deliveries + 1 >= max_deliveries
+}
+
+/// The copy of `message` a worker that gives up on it sends to the dead letter queue, with
+/// `code` in the [`ERROR_CODE_HEADER`]. Headers the broker added are left out.
+pub fn dead_letter_copy(message: &QueueMessage, code: ErrorCode) -> QueueMessage {
+    let mut headers = message
+        .headers
+        .iter()
+        .filter(|(key, _)| !key.starts_with("x-") && key != ERROR_CODE_HEADER)
+        .cloned()
+        .collect::<Vec<_>>();
+    headers.push((ERROR_CODE_HEADER.to_string(), code.to_string()));
+    QueueMessage {
+        headers,
+        ..message.clone()
+    }
+}
+
+/// Why RabbitMQ dead-lettered a message, from the first entry of its `x-death` header.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct Death {
+    /// `rejected`, `delivery_limit`, `expired` or `maxlen`.
+    pub reason: String,
+    pub queue: String,
+    pub count: i64,
+}
+
+pub fn death(headers: &Option<FieldTable>) -> Option<Death> {
+    let AMQPValue::FieldArray(deaths) = headers.as_ref()?.inner().get("x-death")? else {
+        return None;
+    };
+    let AMQPValue::FieldTable(first) = deaths.as_slice().first()? else {
+        return None;
+    };
+    let first = Some(first.clone());
+    let count = match first.as_ref()?.inner().get("count") {
+        Some(AMQPValue::LongLongInt(count)) => *count,
+        Some(AMQPValue::LongInt(count)) => *count as i64,
+        _ => 0,
+    };
+    Some(Death {
+        reason: header_value(&first, "reason")?,
+        queue: header_value(&first, "queue").unwrap_or_default(),
+        count,
+    })
+}
+
+/// One message of a dead letter queue, as `pipeline dlq list` prints it.
+#[derive(Debug, Serialize)]
+pub struct DeadLetter {
+    pub batch_id: String,
+    /// CDX file and batch index, if the batcher set them.
+    pub shard: Option<String>,
+    pub batch_index: Option<usize>,
+    /// `None` if the batch cannot be decoded, which is a reason to dead-letter it.
+    pub num_entries: Option<usize>,
+    pub death: Option<Death>,
+    /// Why the worker gave up on the batch, `None` if the broker dead-lettered it, e.g.
+    /// after the worker crashed on every delivery.
+    pub error_code: Option<String>,
+}
+
+/// Up to `limit` messages of `dlq_name`, which all stay in the queue.
+pub async fn list(
+    channel: &Channel,
+    dlq_name: &str,
+    limit: usize,
+) -> Result<Vec<DeadLetter>, anyhow::Error> {
+    let mut messages = Vec::new();
+    // Messages stay unacked until all are read, otherwise the first one would come back.
+    while messages.len() < limit {
+        let Some(message) = channel
+            .basic_get(dlq_name, BasicGetOptions::default())
+            .await
+            .context("rabbitmq basic get")?
+        else {
+            break;
+        };
+        messages.push(message);
+    }
+    let mut dead_letters = Vec::new();
+    for message in messages {
+        let headers = message.properties.headers();
+        let content_type = message.properties.content_type().as_ref();
+        let num_entries = BatchEncoding::from_content_type(content_type.map(|t| t.as_str()))
+            .and_then(|encoding| encoding.decode(&message.data))
+            .ok()
+            .map(|batch| batch.len());
+        let key = BatchKey::from_headers(|key| header_value(headers, key));
+        dead_letters.push(DeadLetter {
+            batch_id: batch_id(&message.data),
+            shard: key.as_ref().map(|key| key.shard.clone()),
+            batch_index: key.as_ref().map(|key| key.batch_index),
+            num_entries,
+            death: death(headers),
+            error_code: header_value(headers, ERROR_CODE_HEADER),
+        });
+        message
+            .nack(BasicNackOptions {
+                requeue: true,
+                ..Default::default()
+            })
+            .await?;
+    }
+    Ok(dead_letters)
+}
+
+/// Moves up to `limit` messages of `dlq_name` back to `queue_name` through `publisher`,
+/// without the headers the broker added, and returns how many were moved. A message is only
+/// removed from the dead letter queue once the broker confirmed its copy.
+pub async fn requeue(
+    channel: &Channel,
+    publisher: &ReliablePublisher,
+    dlq_name: &str,
+    queue_name: &str,
+    limit: usize,
+) -> Result<usize, anyhow::Error> {
+    let mut num_requeued = 0;
+    while num_requeued < limit {
+        let Some(message) = channel
+            .basic_get(dlq_name, BasicGetOptions::default())
+            .await
+            .context("rabbitmq basic get")?
+        else {
+            break;
+        };
+        let mut properties = BasicProperties::default();
+        if let Some(content_type) = message.properties.content_type() {
+            properties = properties.with_content_type(content_type.clone());
+        }
+        if let Some(headers) = message.properties.headers() {
+            // Drops `x-death`, `x-first-death-*` and the like set by the broker, and the
+            // error code of the last attempt.
+            let headers = headers
+                .inner()
+                .iter()
+                .filter(|(key, _)| {
+                    !key.as_str().starts_with("x-") && key.as_str() != ERROR_CODE_HEADER
+                })
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect::<BTreeMap<_, _>>();
+            properties = properties.with_headers(headers.into());
+        }
+        publisher
+            .publish("", queue_name, &message.data, properties)
+            .await?;
+        message.ack(BasicAckOptions::default()).await?;
+        num_requeued += 1;
+    }
+    Ok(num_requeued)
+}
+
+#[cfg(test)]
+mod tests {
+    use lapin::types::{AMQPValue, FieldArray, FieldTable};
+
+    use crate::{
+        dlq::{
+            dead_letter_arguments, dead_letter_copy, death, dlq_name, is_last_delivery,
+            source_queue, Death,
+        },
+        error_code::ErrorCode,
+        queue::QueueMessage,
+    };
+
+    #[test]
+    fn reads_why_a_batch_was_dead_lettered() {
+        assert_eq!(dlq_name("batches"), "batches.dlq");
+        assert_eq!(source_queue("batches.dlq"), Some("batches"));
+        assert_eq!(source_queue("batches"), None);
+        let arguments = dead_letter_arguments("batches", 3);
+        assert_eq!(
+            arguments.inner().get("x-delivery-limit"),
+            Some(&AMQPValue::LongInt(2))
+        );
+
+        let mut first = FieldTable::default();
+        first.insert(
+            "reason".into(),
+            AMQPValue::LongString("delivery_limit".into()),
+        );
+        first.insert("queue".into(), AMQPValue::LongString("batches".into()));
+        first.insert("count".into(), AMQPValue::LongLongInt(1));
+        let mut headers = FieldTable::default();
+        headers.insert(
+            "x-death".into(),
+            AMQPValue::FieldArray(FieldArray::from(vec![AMQPValue::FieldTable(first)])),
+        );
+        assert_eq!(
+            death(&Some(headers)),
+            Some(Death {
+                reason: "delivery_limit".to_string(),
+                queue: "batches".to_string(),
+                count: 1,
+            })
+        );
+        assert_eq!(death(&Some(FieldTable::default())), None);
+        assert_eq!(death(&None), None);
+    }
+
+    #[test]
+    fn tags_the_last_delivery_with_an_error_code() {
+        let message = QueueMessage::new(b"[]".to_vec())
+            .with_content_type("application/json")
+            .with_headers(vec![
+                ("shard".to_string(), "cdx-00000.gz".to_string()),
+                ("x-first-death-queue".to_string(), "batches".to_string()),
+            ]);
+        assert!(is_last_delivery(2, 3));
+        assert!(!is_last_delivery(2, 4));
+        assert!(!is_last_delivery(0, 2));
+
+        let copy = dead_letter_copy(&message, ErrorCode::FetchThrottled);
+        assert_eq!(
+            copy.headers,
+            [
+                ("shard".to_string(), "cdx-00000.gz".to_string()),
+                ("error-code".to_string(), "FETCH_THROTTLED".to_string()),
+            ]
+        );
+        assert_eq!(copy.payload, message.payload);
+        assert_eq!(copy.content_type, message.content_type);
+        let again = dead_letter_copy(&copy, ErrorCode::OutputIo);
+        assert_eq!(again.header("error-code"), Some("OUTPUT_IO"));
+        assert_eq!(again.headers.len(), 2);
+    }
+}
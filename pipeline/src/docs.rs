@@ -0,0 +1,185 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    path::Path,
+};
+
+use crate::{
+    batch::BatchEncoding,
+    compression::open_decompressed,
+    output::{output_files, read_documents, Document},
+};
+
+/// Writes every record of one output shard to `out` as a JSON line and returns how many
+/// there were. Document shards are decompressed as needed, metadata `.parquet` files
+/// need the `parquet` feature.
+pub fn cat_shard(path: &Path, out: &mut impl Write) -> Result<usize, anyhow::Error> {
+    if path.extension().is_some_and(|ext| ext == "parquet") {
+        return cat_parquet(path, out);
+    }
+    let mut num_records = 0;
+    for line in BufReader::new(open_decompressed(path)?).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        writeln!(out, "{line}")?;
+        num_records += 1;
+    }
+    Ok(num_records)
+}
+
+#[cfg(feature = "parquet")]
+fn cat_parquet(path: &Path, out: &mut impl Write) -> Result<usize, anyhow::Error> {
+    let reader = parquet::file::reader::SerializedFileReader::new(std::fs::File::open(path)?)?;
+    let mut num_records = 0;
+    for row in reader {
+        writeln!(out, "{}", row?.to_json_value())?;
+        num_records += 1;
+    }
+    Ok(num_records)
+}
+
+#[cfg(not(feature = "parquet"))]
+fn cat_parquet(path: &Path, _out: &mut impl Write) -> Result<usize, anyhow::Error> {
+    anyhow::bail!(
+        "Cannot read {}, built without the parquet feature",
+        path.display()
+    )
+}
+
+/// Writes the first `n` documents below `output_dir` to `out`, only those in `language`
+/// (an ISO 639-3 code like `deu`) if given. With [`BatchEncoding::Protobuf`] they are
+/// length-delimited `Document` messages of `proto/pipeline.proto` instead of JSON lines.
+pub fn head(
+    output_dir: &Path,
+    language: Option<&str>,
+    n: usize,
+    encoding: BatchEncoding,
+    out: &mut impl Write,
+) -> Result<usize, anyhow::Error> {
+    let mut num_written = 0;
+    for path in output_files(output_dir)? {
+        for document in read_documents(&path)? {
+            if num_written == n {
+                return Ok(num_written);
+            }
+            let document = document?;
+            if language.is_some_and(|language| document.language != language) {
+                continue;
+            }
+            write_document(&document, encoding, out)?;
+            num_written += 1;
+        }
+    }
+    Ok(num_written)
+}
+
+fn write_document(
+    document: &Document,
+    encoding: BatchEncoding,
+    out: &mut impl Write,
+) -> Result<(), anyhow::Error> {
+    match encoding {
+        BatchEncoding::Json => {
+            serde_json::to_writer(&mut *out, document)?;
+            writeln!(out)?;
+        }
+        #[cfg(feature = "protobuf")]
+        BatchEncoding::Protobuf => {
+            use prost::Message;
+            out.write_all(
+                &crate::proto::Document::from(document).encode_length_delimited_to_vec(),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        batch::BatchEncoding,
+        compression::Compression,
+        docs::{cat_shard, head},
+        output::{Document, LanguageRouter},
+    };
+
+    #[test]
+    fn prints_documents_of_compressed_shards() {
+        let dir = std::env::temp_dir().join(format!("pipeline-docs-{}", std::process::id()));
+        let mut router = LanguageRouter::new(&dir, "worker", Duration::from_secs(300))
+            .with_compression(Compression::Zstd(3));
+        for (id, language) in [("1", "eng"), ("2", "deu"), ("3", "deu")] {
+            router
+                .write(&Document {
+                    id: id.to_string(),
+                    url: "https://example.com/".to_string(),
+                    timestamp: "20240722120756".to_string(),
+                    language: language.to_string(),
+                    token_count: 1,
+                    text: "text".to_string(),
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+        router.flush().unwrap();
+        drop(router);
+
+        let mut out = Vec::new();
+        assert_eq!(
+            cat_shard(&dir.join("deu/worker.jsonl.zst"), &mut out).unwrap(),
+            2
+        );
+        assert!(String::from_utf8(out).unwrap().contains(r#""id":"3""#));
+
+        let mut out = Vec::new();
+        assert_eq!(
+            head(&dir, Some("deu"), 1, BatchEncoding::Json, &mut out).unwrap(),
+            1
+        );
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .contains(r#""language":"deu""#));
+        assert_eq!(
+            head(&dir, None, 10, BatchEncoding::Json, &mut Vec::new()).unwrap(),
+            3
+        );
+        #[cfg(feature = "protobuf")]
+        {
+            use prost::Message;
+
+            let mut out = Vec::new();
+            head(&dir, Some("eng"), 1, BatchEncoding::Protobuf, &mut out).unwrap();
+            let document = crate::proto::Document::decode_length_delimited(out.as_slice())
+                .unwrap()
+                .try_into()
+                .map(|document: Document| (document.id, document.structured_data))
+                .unwrap();
+            assert_eq!(document, ("1".to_string(), None));
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn prints_parquet_rows() {
+        use crate::{
+            cdx::parse_cdx_line,
+            parquet_output::{write_parquet, MetadataRecord},
+        };
+
+        let entry = parse_cdx_line(
+            r#"0,100,59,139)/ 20240723213521 {"url": "https://139.59.100.0/", "status": "200", "length": "16650", "offset": "64016172", "filename": "crawl-data/CC-MAIN-2024-30/segments/1720763518115.82/warc/CC-MAIN-20240723194208-20240723224208-00279.warc.gz"}"#,
+        ).unwrap();
+        let path =
+            std::env::temp_dir().join(format!("pipeline-docs-{}.parquet", std::process::id()));
+        write_parquet(&path, &[MetadataRecord::from(&entry)], 10, None).unwrap();
+        let mut out = Vec::new();
+        assert_eq!(cat_shard(&path, &mut out).unwrap(), 1);
+        let row = serde_json::from_slice::<serde_json::Value>(&out).unwrap();
+        assert_eq!(row["offset"], 64016172);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,287 @@
+use std::{fmt, fs, path::Path, process};
+
+use anyhow::Context;
+
+use crate::{
+    cdx::{cdx_chunk_url, download_range, try_parse_cluster_idx, CC_DATA_URL},
+    config_check::ConfigCheck,
+    http_client::HttpOptions,
+    index_api::IndexApiClient,
+    queue::{QueueBackend, QueueOptions},
+    spool::Spool,
+    state_store::StateStoreOptions,
+};
+
+/// The outcome of one check of `pipeline doctor`.
+#[derive(Debug)]
+pub struct Check {
+    pub name: String,
+    pub passed: bool,
+    /// What was found, or why the check failed.
+    pub detail: String,
+}
+
+/// The checks of a deployment, printed as one line per check.
+#[derive(Debug, Default)]
+pub struct DoctorReport {
+    pub checks: Vec<Check>,
+}
+
+impl DoctorReport {
+    pub fn add(&mut self, name: &str, outcome: Result<String, anyhow::Error>) {
+        let (passed, detail) = match outcome {
+            Ok(detail) => (true, detail),
+            Err(e) => (false, format!("{e:#}")),
+        };
+        self.checks.push(Check {
+            name: name.to_string(),
+            passed,
+            detail,
+        });
+    }
+
+    pub fn all_passed(&self) -> bool {
+        self.checks.iter().all(|check| check.passed)
+    }
+}
+
+impl fmt::Display for DoctorReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            let status = if check.passed { "PASS" } else { "FAIL" };
+            writeln!(f, "{status}  {}: {}", check.name, check.detail)?;
+        }
+        let num_failed = self.checks.iter().filter(|check| !check.passed).count();
+        write!(f, "{} checks, {} failed", self.checks.len(), num_failed)
+    }
+}
+
+/// Whether `name` is set in the environment, without showing its value, which may hold
+/// credentials.
+pub fn check_env_var(name: &str) -> Result<String, anyhow::Error> {
+    match std::env::var(name) {
+        Ok(value) if !value.is_empty() => Ok("set".to_string()),
+        _ => anyhow::bail!("{name} is not set"),
+    }
+}
+
+/// The config checks that the batcher and the workers run at startup on the options they
+/// share with `doctor`.
+pub fn check_config(http: &HttpOptions, queue: &QueueOptions) -> Result<String, anyhow::Error> {
+    let mut check = ConfigCheck::default();
+    http.check(&mut check);
+    queue.check(&mut check);
+    check.finish()?;
+    Ok("no problems".to_string())
+}
+
+/// Whether the store of `--state-store`, or the SQLite run DB, opens and answers a read.
+pub fn check_state_store(state: &StateStoreOptions) -> Result<String, anyhow::Error> {
+    let store = state.open()?;
+    store.content_keys(None, 1)?;
+    Ok(match state.state_store.as_deref() {
+        Some(url) => format!("{} answers", url.split_once("://").map_or(url, |(s, _)| s)),
+        None => format!("{} opens", state.run_db_filename),
+    })
+}
+
+/// Whether the broker of `--queue-backend` is reachable and has each of `queue_names`:
+/// declared on RabbitMQ, writable spool directories, existing Kafka topics or SQS queues.
+pub async fn check_queue_backend(
+    queue: &QueueOptions,
+    queue_names: &[&str],
+    http_client: &reqwest::Client,
+) -> Result<String, anyhow::Error> {
+    #[cfg(not(any(feature = "kafka", feature = "sqs")))]
+    let _ = http_client;
+    match queue.queue_backend {
+        #[cfg(feature = "rabbitmq")]
+        QueueBackend::Rabbitmq => check_broker(queue_names).await,
+        #[cfg(not(feature = "rabbitmq"))]
+        QueueBackend::Rabbitmq => anyhow::bail!("Built without the rabbitmq feature"),
+        QueueBackend::Fs => {
+            let spool = Spool::new(&queue.spool_dir);
+            for queue_name in queue_names {
+                check_writable(&spool.queue_dir(queue_name))?;
+            }
+            Ok(format!(
+                "may write {} in {:?}",
+                queue_names.join(", "),
+                queue.spool_dir
+            ))
+        }
+        #[cfg(feature = "kafka")]
+        QueueBackend::Kafka => queue.kafka_broker(http_client).check_topics(queue_names),
+        #[cfg(feature = "sqs")]
+        QueueBackend::Sqs => {
+            crate::sqs::SqsClient::from_env(http_client.clone())?
+                .with_retries(Default::default(), 1)
+                .check_queues(queue_names)
+                .await
+        }
+    }
+}
+
+/// Whether the cluster.idx at `path` can be read and parsed, returning its first entry.
+pub fn check_cluster_idx(path: &Path) -> Result<(String, usize), anyhow::Error> {
+    let idx = fs::read_to_string(path).with_context(|| format!("Cannot read {path:?}"))?;
+    let mut num_entries = 0;
+    let mut first = None;
+    for (number, line) in idx.lines().enumerate() {
+        let entry = try_parse_cluster_idx(line.as_bytes())
+            .with_context(|| format!("{path:?} line {}", number + 1))?;
+        first.get_or_insert(entry.cdx_filename);
+        num_entries += 1;
+    }
+    let first = first.with_context(|| format!("{path:?} is empty"))?;
+    Ok((first, num_entries))
+}
+
+/// Creates `dir` if needed and writes and removes a probe file in it.
+pub fn check_writable(dir: &Path) -> Result<String, anyhow::Error> {
+    fs::create_dir_all(dir).with_context(|| format!("Cannot create {dir:?}"))?;
+    let probe = dir.join(format!(".doctor-{}", process::id()));
+    fs::write(&probe, b"probe").with_context(|| format!("Cannot write to {dir:?}"))?;
+    fs::remove_file(&probe)?;
+    Ok(format!("{dir:?} is writable"))
+}
+
+/// The available KiB in the output of `df -Pk`, whose second line is the file system.
+pub fn parse_df_available_kib(output: &str) -> Option<u64> {
+    output
+        .lines()
+        .nth(1)?
+        .split_whitespace()
+        .nth(3)?
+        .parse()
+        .ok()
+}
+
+/// Whether the file system of `dir` has at least `min_free_gib` GiB available.
+pub fn check_disk_space(dir: &Path, min_free_gib: u64) -> Result<String, anyhow::Error> {
+    let output = process::Command::new("df")
+        .arg("-Pk")
+        .arg(dir)
+        .output()
+        .context("Cannot run df")?;
+    anyhow::ensure!(output.status.success(), "df failed on {dir:?}");
+    let available_kib = parse_df_available_kib(&String::from_utf8_lossy(&output.stdout))
+        .context("Cannot parse the output of df")?;
+    let available_gib = available_kib as f64 / (1 << 20) as f64;
+    anyhow::ensure!(
+        available_gib >= min_free_gib as f64,
+        "{available_gib:.1} GiB free on {dir:?}, below {min_free_gib} GiB"
+    );
+    Ok(format!("{available_gib:.1} GiB free on {dir:?}"))
+}
+
+/// Whether `crawl` is one of the crawls the index API knows.
+pub async fn check_index_api(
+    client: &IndexApiClient,
+    crawl: &str,
+) -> Result<String, anyhow::Error> {
+    let crawls = client.crawls().await?;
+    anyhow::ensure!(
+        crawls.iter().any(|known| known == crawl),
+        "{crawl} is not among the {} crawls of the index API",
+        crawls.len()
+    );
+    Ok(format!("{} crawls, including {crawl}", crawls.len()))
+}
+
+/// Whether the first CDX file of the cluster.idx can be fetched from the crawl, which also
+/// tells whether the cluster.idx belongs to it.
+pub async fn check_data_url(
+    client: &reqwest::Client,
+    crawl: &str,
+    cdx_filename: &str,
+) -> Result<String, anyhow::Error> {
+    let url = cdx_chunk_url(crawl, cdx_filename);
+    download_range(client, &url, 0, 1)
+        .await
+        .with_context(|| format!("Cannot fetch {url}"))?;
+    Ok(format!("{CC_DATA_URL} serves {cdx_filename} of {crawl}"))
+}
+
+/// Whether the broker accepts a connection and lets us declare each of `queue_names`.
+#[cfg(feature = "rabbitmq")]
+pub async fn check_broker(queue_names: &[&str]) -> Result<String, anyhow::Error> {
+    use crate::rabbitmq::{rabbitmq_channel, rabbitmq_connection, rabbitmq_declare_queue};
+
+    let conn = rabbitmq_connection().await?;
+    let channel = rabbitmq_channel(&conn).await?;
+    for queue_name in queue_names {
+        rabbitmq_declare_queue(&channel, queue_name, Default::default())
+            .await
+            .with_context(|| format!("Queue {queue_name}"))?;
+    }
+    conn.close(0, "doctor").await?;
+    Ok(format!("connected, may declare {}", queue_names.join(", ")))
+}
+
+/// Whether the credentials in the environment may write below `url`, e.g.
+/// `s3://corpus/runs`, as the worker does with `--s3-url`.
+#[cfg(feature = "s3")]
+pub async fn check_s3(http_client: &reqwest::Client, url: &str) -> Result<String, anyhow::Error> {
+    use crate::s3::{S3Client, S3Destination};
+
+    let destination = S3Destination::parse(url, "{shard}")?;
+    let client = S3Client::from_env(http_client.clone())?.with_retries(Default::default(), 1);
+    client
+        .check_access(&destination.bucket, &destination.prefix)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::doctor::{check_cluster_idx, check_writable, parse_df_available_kib, DoctorReport};
+
+    #[test]
+    fn reports_passed_and_failed_checks() {
+        let dir = std::env::temp_dir().join(format!("pipeline-doctor-{}", std::process::id()));
+        let mut report = DoctorReport::default();
+        report.add("output dir", check_writable(&dir));
+        assert!(report.all_passed());
+
+        let idx = dir.join("cluster.idx");
+        std::fs::write(
+            &idx,
+            "0,1,1,1)/ 20240718162950\tcdx-00000.gz\t0\t186839\t1\n\
+             0,1,1,1)/ 20240719162950\tcdx-00000.gz\t186839\t180000\t2\n",
+        )
+        .unwrap();
+        assert_eq!(
+            check_cluster_idx(&idx).unwrap(),
+            ("cdx-00000.gz".to_string(), 2)
+        );
+        std::fs::write(&idx, "not an index\n").unwrap();
+        report.add(
+            "cluster.idx",
+            check_cluster_idx(&idx).map(|_| String::new()),
+        );
+        assert!(!report.all_passed());
+        let text = report.to_string();
+        assert!(text.starts_with("PASS  output dir: "));
+        assert!(text.contains("FAIL  cluster.idx: "));
+        assert!(text.ends_with("2 checks, 1 failed"));
+
+        #[cfg(feature = "run-db")]
+        {
+            let state = crate::state_store::StateStoreOptions {
+                run_db_filename: dir.join("run.sqlite").to_string_lossy().into_owned(),
+                state_store: None,
+            };
+            assert!(crate::doctor::check_state_store(&state).is_ok());
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            parse_df_available_kib(
+                "Filesystem 1024-blocks Used Available Capacity Mounted on\n\
+                 /dev/sda1 102400 2048 100352 2% /\n"
+            ),
+            Some(100352)
+        );
+        assert_eq!(parse_df_available_kib(""), None);
+    }
+}
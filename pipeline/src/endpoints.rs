@@ -0,0 +1,260 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    cdx::{download_range, retry_delay},
+    retry::Backoff,
+};
+
+/// Bytes fetched per probe, enough to time the transfer and not only the first byte.
+const PROBE_BYTES: usize = 64 << 10;
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Another endpoint has to be this much faster before fetches move over, so two
+/// endpoints of about the same speed do not take turns.
+const SWITCH_SPEEDUP: f64 = 1.2;
+
+/// The endpoint to fetch from: the one with the shortest probe time among those that
+/// answered, `current` while it stays close to that, and `current` as well if none
+/// answered.
+pub fn choose_endpoint(current: usize, probe_times: &[Option<Duration>]) -> usize {
+    let fastest = probe_times
+        .iter()
+        .enumerate()
+        .filter_map(|(index, time)| time.map(|time| (index, time)))
+        .min_by_key(|(_, time)| *time);
+    match (fastest, probe_times.get(current).copied().flatten()) {
+        (None, _) => current,
+        (Some((fastest, _)), None) => fastest,
+        (Some((fastest, time)), Some(current_time)) => {
+            if current_time.as_secs_f64() > time.as_secs_f64() * SWITCH_SPEEDUP {
+                fastest
+            } else {
+                current
+            }
+        }
+    }
+}
+
+/// Mirrors of the Common Crawl data, e.g. CloudFront and S3 in other regions, that fetches
+/// are routed to. [`probe`](Self::probe) fetches the start of the last requested file from
+/// each of them and moves over to the fastest one that answered. A fetch that fails with
+/// a transient or network error moves over to the next endpoint right away, see
+/// [`mark_unhealthy`](Self::mark_unhealthy).
+pub struct DataEndpoints {
+    base_urls: Vec<String>,
+    current: AtomicUsize,
+    /// A path that exists on every endpoint, the one fetched last.
+    probe_path: Mutex<Option<String>>,
+}
+
+impl DataEndpoints {
+    pub fn new(base_urls: Vec<String>) -> Self {
+        assert!(!base_urls.is_empty(), "Needs at least one endpoint");
+        Self {
+            base_urls,
+            current: AtomicUsize::new(0),
+            probe_path: Mutex::new(None),
+        }
+    }
+
+    pub fn current(&self) -> &str {
+        &self.base_urls[self.current.load(Ordering::Relaxed)]
+    }
+
+    /// The URL of `path`, e.g. of a WARC file, on the current endpoint.
+    pub fn url(&self, path: &str) -> String {
+        if self.base_urls.len() > 1 {
+            *self.probe_path.lock().unwrap() = Some(path.to_string());
+        }
+        format!("{}/{path}", self.current())
+    }
+
+    /// Moves fetches from the endpoint at `index` to the next one, unless another fetch
+    /// moved them already. A probe moves them back once the endpoint answers again and is
+    /// the fastest.
+    pub fn mark_unhealthy(&self, index: usize) {
+        let next = (index + 1) % self.base_urls.len();
+        if next != index
+            && self
+                .current
+                .compare_exchange(index, next, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            tracing::warn!(
+                "Fetching from {} instead of {}, which failed",
+                self.base_urls[next],
+                self.base_urls[index]
+            );
+        }
+    }
+
+    /// Like [`crate::cdx::download_range_with_retries`] for `path` on the current endpoint,
+    /// but a transient failure marks the endpoint unhealthy, and the next attempt goes to
+    /// the next endpoint right away. The backoff only applies once every endpoint failed
+    /// in a row.
+    pub async fn download_range_with_retries(
+        &self,
+        client: &reqwest::Client,
+        path: &str,
+        offset: usize,
+        length: usize,
+        max_attempts: usize,
+        backoff: &Backoff,
+    ) -> Result<Vec<u8>, anyhow::Error> {
+        if self.base_urls.len() > 1 {
+            *self.probe_path.lock().unwrap() = Some(path.to_string());
+        }
+        let mut attempt = 1;
+        let mut failed_in_a_row = 0;
+        loop {
+            let index = self.current.load(Ordering::Relaxed);
+            let url = format!("{}/{path}", self.base_urls[index]);
+            match download_range(client, &url, offset, length).await {
+                Ok(data) => return Ok(data),
+                Err(e) => {
+                    let Some(delay) = retry_delay(&url, &e, attempt, max_attempts, backoff) else {
+                        return Err(e);
+                    };
+                    self.mark_unhealthy(index);
+                    failed_in_a_row += 1;
+                    if failed_in_a_row >= self.base_urls.len() {
+                        tokio::time::sleep(delay).await;
+                        failed_in_a_row = 0;
+                    }
+                }
+            }
+            attempt += 1;
+        }
+    }
+
+    /// Times a ranged fetch from every endpoint and switches to the one
+    /// [`choose_endpoint`] picks. Nothing is probed before the first fetch.
+    pub async fn probe(&self, client: &reqwest::Client) -> Vec<Option<Duration>> {
+        let Some(path) = self.probe_path.lock().unwrap().clone() else {
+            return Vec::new();
+        };
+        let probes = self.base_urls.iter().map(|base_url| {
+            let url = format!("{base_url}/{path}");
+            async move {
+                let start = Instant::now();
+                match tokio::time::timeout(
+                    PROBE_TIMEOUT,
+                    download_range(client, &url, 0, PROBE_BYTES),
+                )
+                .await
+                {
+                    Ok(Ok(_)) => Some(start.elapsed()),
+                    Ok(Err(e)) => {
+                        tracing::warn!(err.msg = %e, "Probing {} failed", base_url);
+                        None
+                    }
+                    Err(_) => {
+                        tracing::warn!("Probing {} timed out", base_url);
+                        None
+                    }
+                }
+            }
+        });
+        let probe_times = futures_util::future::join_all(probes).await;
+        let current = self.current.load(Ordering::Relaxed);
+        let chosen = choose_endpoint(current, &probe_times);
+        if chosen != current {
+            tracing::info!(
+                "Fetching from {} instead of {}, probed in {:?}",
+                self.base_urls[chosen],
+                self.base_urls[current],
+                probe_times
+            );
+            self.current.store(chosen, Ordering::Relaxed);
+        }
+        probe_times
+    }
+
+    /// Probes right after the first fetch and then every `interval`, until the process
+    /// ends.
+    pub async fn probe_every(self: Arc<Self>, client: reqwest::Client, interval: Duration) {
+        while self.probe(&client).await.is_empty() {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        let mut ticks = tokio::time::interval(interval);
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            self.probe(&client).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{
+        endpoints::{choose_endpoint, DataEndpoints},
+        retry::Backoff,
+    };
+
+    #[test]
+    fn moves_to_clearly_faster_healthy_endpoints() {
+        let ms = |ms: u64| Some(Duration::from_millis(ms));
+        assert_eq!(choose_endpoint(0, &[ms(300), ms(100), None]), 1);
+        // Not enough faster to switch.
+        assert_eq!(choose_endpoint(0, &[ms(110), ms(100)]), 0);
+        // The current endpoint failed.
+        assert_eq!(choose_endpoint(0, &[None, ms(900), ms(500)]), 2);
+        assert_eq!(choose_endpoint(1, &[None, None]), 1);
+        assert_eq!(choose_endpoint(0, &[]), 0);
+
+        let endpoints = DataEndpoints::new(vec![
+            "https://data.commoncrawl.org".to_string(),
+            "http://mirror.example".to_string(),
+        ]);
+        assert_eq!(
+            endpoints.url("crawl-data/a.warc.gz"),
+            "https://data.commoncrawl.org/crawl-data/a.warc.gz"
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_over_to_the_next_endpoint() {
+        // Answers every range request with the same five bytes.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let healthy = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).await.unwrap();
+                stream
+                    .write_all(
+                        b"HTTP/1.1 206 Partial Content\r\ncontent-length: 5\r\n\
+                          connection: close\r\n\r\nhello",
+                    )
+                    .await
+                    .unwrap();
+            }
+        });
+        // Nothing listens on port 1.
+        let endpoints = DataEndpoints::new(vec!["http://127.0.0.1:1".to_string(), healthy.clone()]);
+        let backoff = Backoff {
+            base: Duration::from_secs(60),
+            max: Duration::from_secs(60),
+        };
+        // Without waiting for the backoff.
+        let client = reqwest::Client::new();
+        let fetch = endpoints.download_range_with_retries(&client, "a.warc.gz", 0, 5, 2, &backoff);
+        let data = tokio::time::timeout(Duration::from_secs(10), fetch)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(data, b"hello");
+        assert_eq!(endpoints.current(), healthy);
+    }
+}
@@ -0,0 +1,92 @@
+use crate::cdx::FetchError;
+
+/// The message header of a dead-lettered batch that tells why the worker gave up on it.
+pub const ERROR_CODE_HEADER: &str = "error-code";
+
+/// Why a record or a batch failed, as a code that stays the same across versions while
+/// error messages change, so failure dashboards can add them up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The WARC file is not there, 404 or 410.
+    Fetch404,
+    /// The server asked to slow down, 429 or 503.
+    FetchThrottled,
+    /// Another 4xx, or data that cannot be decompressed.
+    FetchPermanent,
+    /// Another 5xx, a timeout or a network error.
+    FetchTransient,
+    WarcParse,
+    /// No text could be extracted from the record.
+    ExtractEmpty,
+    /// The extractor failed on the record, e.g. its subprocess crashed.
+    ExtractFailed,
+    /// Writing the outputs of a batch failed.
+    OutputIo,
+    /// The dedup store, e.g. Redis or the state store, failed.
+    DedupStore,
+    /// The batch itself cannot be decoded or does not match its manifest.
+    BatchCorrupt,
+}
+
+impl ErrorCode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::Fetch404 => "FETCH_404",
+            ErrorCode::FetchThrottled => "FETCH_THROTTLED",
+            ErrorCode::FetchPermanent => "FETCH_PERMANENT",
+            ErrorCode::FetchTransient => "FETCH_TRANSIENT",
+            ErrorCode::WarcParse => "WARC_PARSE",
+            ErrorCode::ExtractEmpty => "EXTRACT_EMPTY",
+            ErrorCode::ExtractFailed => "EXTRACT_FAILED",
+            ErrorCode::OutputIo => "OUTPUT_IO",
+            ErrorCode::DedupStore => "DEDUP_STORE",
+            ErrorCode::BatchCorrupt => "BATCH_CORRUPT",
+        }
+    }
+
+    pub fn of_fetch(error: &FetchError) -> Self {
+        match (error.status(), error.is_permanent()) {
+            (Some(404 | 410), _) => ErrorCode::Fetch404,
+            (Some(429 | 503), _) => ErrorCode::FetchThrottled,
+            (_, true) => ErrorCode::FetchPermanent,
+            (_, false) => ErrorCode::FetchTransient,
+        }
+    }
+
+    /// The run DB counter of the code, e.g. `failures.FETCH_404`.
+    pub fn counter(self) -> String {
+        format!("failures.{}", self.as_str())
+    }
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{cdx::FetchError, error_code::ErrorCode};
+
+    #[test]
+    fn classifies_fetch_errors() {
+        let code = |status: u16| {
+            let status = reqwest::StatusCode::from_u16(status).unwrap();
+            ErrorCode::of_fetch(&FetchError::from_status("https://x", status))
+        };
+        assert_eq!(code(404), ErrorCode::Fetch404);
+        assert_eq!(code(410), ErrorCode::Fetch404);
+        assert_eq!(code(429), ErrorCode::FetchThrottled);
+        assert_eq!(code(503), ErrorCode::FetchThrottled);
+        assert_eq!(code(403), ErrorCode::FetchPermanent);
+        assert_eq!(code(500), ErrorCode::FetchTransient);
+        let corrupt = FetchError::Permanent {
+            url: "https://x".to_string(),
+            reason: "failed to decompress".to_string(),
+            status: None,
+        };
+        assert_eq!(ErrorCode::of_fetch(&corrupt), ErrorCode::FetchPermanent);
+        assert_eq!(ErrorCode::WarcParse.counter(), "failures.WARC_PARSE");
+    }
+}
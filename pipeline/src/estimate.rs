@@ -0,0 +1,158 @@
+use futures_util::StreamExt;
+
+use crate::{
+    cdx::{
+        cdx_chunk_url, download_and_unzip_lines, parse_cdx_line_or_skip, ClusterIdxEntry,
+        Utf8Policy,
+    },
+    filter::CdxFilter,
+};
+
+/// Prices used to turn byte and request counts into an approximate bill.
+#[derive(Debug, Clone)]
+pub struct CostModel {
+    pub usd_per_gb_egress: f64,
+    pub usd_per_1000_requests: f64,
+}
+
+impl Default for CostModel {
+    fn default() -> Self {
+        // Public S3 list prices for the first egress tier and GET requests in us-east-1.
+        Self {
+            usd_per_gb_egress: 0.09,
+            usd_per_1000_requests: 0.0004,
+        }
+    }
+}
+
+impl CostModel {
+    pub fn cost_usd(&self, bytes: f64, requests: f64) -> f64 {
+        bytes / 1e9 * self.usd_per_gb_egress + requests / 1000.0 * self.usd_per_1000_requests
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ChunkSample {
+    pub total_entries: usize,
+    pub matching_entries: usize,
+    pub matching_warc_bytes: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Estimate {
+    pub num_chunks: usize,
+    pub num_sampled_chunks: usize,
+    pub total_entries: f64,
+    pub matching_entries: f64,
+    pub index_bytes: usize,
+    pub warc_bytes: f64,
+    pub requests: f64,
+    pub cost_usd: f64,
+}
+
+/// Picks `num_samples` chunk indices spread evenly over all chunks, so the sample
+/// covers the whole alphabetical SURT range instead of only its beginning.
+pub fn sample_indices(num_chunks: usize, num_samples: usize) -> Vec<usize> {
+    let num_samples = num_samples.min(num_chunks);
+    (0..num_samples)
+        .map(|i| i * num_chunks / num_samples)
+        .collect()
+}
+
+pub async fn sample_chunk(
+    client: &reqwest::Client,
+    crawl: &str,
+    chunk: &ClusterIdxEntry,
+    filter: &CdxFilter,
+) -> Result<ChunkSample, anyhow::Error> {
+    let url = cdx_chunk_url(crawl, &chunk.cdx_filename);
+    let lines = download_and_unzip_lines(
+        client,
+        &url,
+        chunk.cdx_offset,
+        chunk.cdx_length,
+        Utf8Policy::Lossy,
+    )
+    .await?;
+    let mut lines = std::pin::pin!(lines);
+    let mut sample = ChunkSample::default();
+    while let Some(line) = lines.next().await {
+        let (number, line) = line?;
+        let Some(entry) = parse_cdx_line_or_skip(&url, number, &line) else {
+            continue;
+        };
+        sample.total_entries += 1;
+        if filter.matches(&entry) {
+            sample.matching_entries += 1;
+            sample.matching_warc_bytes += entry.metadata.length as usize;
+        }
+    }
+    Ok(sample)
+}
+
+pub fn extrapolate(
+    chunks: &[ClusterIdxEntry],
+    samples: &[ChunkSample],
+    cost_model: &CostModel,
+) -> Estimate {
+    let scale = if samples.is_empty() {
+        0.0
+    } else {
+        chunks.len() as f64 / samples.len() as f64
+    };
+    let sum = |f: fn(&ChunkSample) -> usize| samples.iter().map(f).sum::<usize>() as f64 * scale;
+    let total_entries = sum(|s| s.total_entries);
+    let matching_entries = sum(|s| s.matching_entries);
+    let warc_bytes = sum(|s| s.matching_warc_bytes);
+    // The index size is known exactly from cluster.idx, only the WARC side is extrapolated.
+    let index_bytes = chunks.iter().map(|c| c.cdx_length).sum::<usize>();
+    let requests = chunks.len() as f64 + matching_entries;
+    let cost_usd = cost_model.cost_usd(index_bytes as f64 + warc_bytes, requests);
+    Estimate {
+        num_chunks: chunks.len(),
+        num_sampled_chunks: samples.len(),
+        total_entries,
+        matching_entries,
+        index_bytes,
+        warc_bytes,
+        requests,
+        cost_usd,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        cdx::parse_cluster_idx,
+        estimate::{extrapolate, sample_indices, ChunkSample, CostModel},
+    };
+
+    #[test]
+    fn samples_are_spread_over_all_chunks() {
+        assert_eq!(sample_indices(100, 4), vec![0, 25, 50, 75]);
+        assert_eq!(sample_indices(3, 10), vec![0, 1, 2]);
+        assert!(sample_indices(0, 10).is_empty());
+    }
+
+    #[test]
+    fn extrapolates_from_samples() {
+        let chunks: Vec<_> = [
+            "0,100,22,165)/ 20240722120756 cdx-00000.gz 0 1000 1",
+            "101,141,199,66)/robots.txt 20240714155331 cdx-00000.gz 1000 1000 2",
+        ]
+        .into_iter()
+        .filter_map(parse_cluster_idx)
+        .collect();
+        let samples = [ChunkSample {
+            total_entries: 10,
+            matching_entries: 4,
+            matching_warc_bytes: 400,
+        }];
+        let estimate = extrapolate(&chunks, &samples, &CostModel::default());
+        assert_eq!(estimate.total_entries, 20.0);
+        assert_eq!(estimate.matching_entries, 8.0);
+        assert_eq!(estimate.warc_bytes, 800.0);
+        assert_eq!(estimate.index_bytes, 2000);
+        assert_eq!(estimate.requests, 10.0);
+    }
+}
@@ -0,0 +1,370 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    sync::Mutex,
+};
+
+use anyhow::Context;
+#[cfg(feature = "extraction")]
+use scraper::{ElementRef, Html, Selector};
+use serde::{Deserialize, Serialize};
+
+/// Turns an HTML page into its plain text content.
+pub trait HtmlExtractor: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Returns `None` if the page has no content worth keeping.
+    fn extract(&self, html: &str) -> Result<Option<String>, anyhow::Error>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ExtractorKind {
+    /// Strips all tags, fast but keeps navigation and other boilerplate.
+    TagStrip,
+    /// Keeps only text blocks that look like main content.
+    #[cfg(feature = "extraction")]
+    Readability,
+    /// Runs trafilatura in-process through pyo3.
+    #[cfg(feature = "trafilatura")]
+    Trafilatura,
+    /// Talks to an external command over JSON lines on stdin/stdout.
+    Subprocess,
+}
+
+impl Default for ExtractorKind {
+    /// The best extractor this build was compiled with.
+    fn default() -> Self {
+        #[cfg(feature = "trafilatura")]
+        return ExtractorKind::Trafilatura;
+        #[cfg(all(feature = "extraction", not(feature = "trafilatura")))]
+        return ExtractorKind::Readability;
+        #[cfg(not(any(feature = "extraction", feature = "trafilatura")))]
+        return ExtractorKind::TagStrip;
+    }
+}
+
+/// Builds the extractor selected for this run. `subprocess_command` is only used by
+/// [`ExtractorKind::Subprocess`].
+pub fn build_extractor(
+    kind: ExtractorKind,
+    subprocess_command: &str,
+) -> Result<Box<dyn HtmlExtractor>, anyhow::Error> {
+    Ok(match kind {
+        ExtractorKind::TagStrip => Box::new(TagStripExtractor),
+        #[cfg(feature = "extraction")]
+        ExtractorKind::Readability => Box::new(ReadabilityExtractor::default()),
+        #[cfg(feature = "trafilatura")]
+        ExtractorKind::Trafilatura => Box::new(crate::trafilatura::Trafilatura),
+        ExtractorKind::Subprocess => Box::new(SubprocessExtractor::spawn(subprocess_command)?),
+    })
+}
+
+/// Extracts `html` with `primary`, falling back to [`TagStripExtractor`] if that yields
+/// fewer than `min_chars` characters while tag stripping yields more, e.g. on markup the
+/// primary extractor does not understand. Returns the text with the name of the extractor
+/// it came from.
+pub fn extract_with_fallback(
+    primary: &dyn HtmlExtractor,
+    html: &str,
+    min_chars: usize,
+) -> Result<Option<(String, &'static str)>, anyhow::Error> {
+    let text = primary.extract(html)?;
+    let num_chars = text.as_ref().map_or(0, |text| text.chars().count());
+    if num_chars >= min_chars || primary.name() == TagStripExtractor.name() {
+        return Ok(text.map(|text| (text, primary.name())));
+    }
+    let stripped = TagStripExtractor.extract(html)?;
+    Ok(match stripped {
+        Some(stripped) if stripped.chars().count() > num_chars => {
+            Some((stripped, TagStripExtractor.name()))
+        }
+        _ => text.map(|text| (text, primary.name())),
+    })
+}
+
+const BLOCK_TAGS: &[&str] = &[
+    "p",
+    "div",
+    "br",
+    "li",
+    "tr",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "pre",
+    "blockquote",
+    "section",
+    "article",
+    "table",
+    "ul",
+    "ol",
+    "title",
+];
+
+/// Removes all markup, dropping `<script>` and `<style>` contents.
+pub struct TagStripExtractor;
+
+impl HtmlExtractor for TagStripExtractor {
+    fn name(&self) -> &'static str {
+        "tag-strip"
+    }
+
+    fn extract(&self, html: &str) -> Result<Option<String>, anyhow::Error> {
+        let text = strip_tags(html);
+        Ok((!text.is_empty()).then_some(text))
+    }
+}
+
+pub fn strip_tags(html: &str) -> String {
+    let mut text = String::with_capacity(html.len() / 2);
+    let mut rest = html;
+    while let Some(tag_start) = rest.find('<') {
+        text.push_str(&decode_entities(&rest[..tag_start]));
+        rest = &rest[tag_start..];
+        let Some(tag_end) = rest.find('>') else {
+            rest = "";
+            break;
+        };
+        let is_closing_tag = rest[1..].starts_with('/');
+        let tag = rest[1..tag_end]
+            .trim_start_matches('/')
+            .to_ascii_lowercase();
+        let tag_name = tag
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default();
+        rest = &rest[tag_end + 1..];
+        if !is_closing_tag && (tag_name == "script" || tag_name == "style") {
+            let closing = format!("</{tag_name}");
+            rest = match rest.to_ascii_lowercase().find(&closing) {
+                Some(end) => &rest[end..],
+                None => "",
+            };
+        } else if BLOCK_TAGS.contains(&tag_name) {
+            text.push('\n');
+        } else {
+            text.push(' ');
+        }
+    }
+    text.push_str(&decode_entities(rest));
+    normalize_whitespace(&text)
+}
+
+fn decode_entities(text: &str) -> String {
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Collapses runs of spaces within lines and drops empty lines.
+pub fn normalize_whitespace(text: &str) -> String {
+    text.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(feature = "extraction")]
+/// Keeps text blocks that are long enough and not mostly links, outside of navigation,
+/// headers, footers and forms. Slower than [`TagStripExtractor`], but much less boilerplate.
+pub struct ReadabilityExtractor {
+    pub min_block_chars: usize,
+    pub max_link_density: f64,
+}
+
+#[cfg(feature = "extraction")]
+impl Default for ReadabilityExtractor {
+    fn default() -> Self {
+        Self {
+            min_block_chars: 25,
+            max_link_density: 0.5,
+        }
+    }
+}
+
+#[cfg(feature = "extraction")]
+const CONTENT_BLOCKS: &str = "p, li, h1, h2, h3, h4, h5, h6, pre, blockquote, td";
+#[cfg(feature = "extraction")]
+const BOILERPLATE_TAGS: &[&str] = &[
+    "nav", "header", "footer", "aside", "form", "script", "style", "noscript",
+];
+
+#[cfg(feature = "extraction")]
+impl HtmlExtractor for ReadabilityExtractor {
+    fn name(&self) -> &'static str {
+        "readability"
+    }
+
+    fn extract(&self, html: &str) -> Result<Option<String>, anyhow::Error> {
+        let document = Html::parse_document(html);
+        let blocks = Selector::parse(CONTENT_BLOCKS).unwrap();
+        let links = Selector::parse("a").unwrap();
+        let mut paragraphs = Vec::new();
+        for block in document.select(&blocks) {
+            let is_nested_or_boilerplate = block
+                .ancestors()
+                .filter_map(ElementRef::wrap)
+                .any(|a| BOILERPLATE_TAGS.contains(&a.value().name()) || blocks.matches(&a));
+            if is_nested_or_boilerplate {
+                continue;
+            }
+            let text = normalize_whitespace(&block.text().collect::<String>());
+            let text_chars = text.chars().count();
+            let link_chars = block
+                .select(&links)
+                .map(|a| a.text().map(|t| t.trim().chars().count()).sum::<usize>())
+                .sum::<usize>();
+            let is_heading = block.value().name().starts_with('h');
+            if (text_chars >= self.min_block_chars || (is_heading && text_chars > 0))
+                && (link_chars as f64) <= self.max_link_density * text_chars as f64
+            {
+                paragraphs.push(text);
+            }
+        }
+        let text = paragraphs.join("\n");
+        Ok((!text.is_empty()).then_some(text))
+    }
+}
+
+#[derive(Serialize)]
+struct SubprocessRequest<'a> {
+    html: &'a str,
+}
+
+#[derive(Deserialize)]
+struct SubprocessResponse {
+    text: Option<String>,
+}
+
+/// Bridges to an external extractor process, e.g. `python3 scripts/trafilatura_bridge.py`.
+/// The process is started once and receives one `{"html": ...}` JSON line per document
+/// on stdin, answering with one `{"text": ...}` line on stdout.
+pub struct SubprocessExtractor {
+    process: Mutex<(Child, ChildStdin, BufReader<ChildStdout>)>,
+}
+
+impl SubprocessExtractor {
+    pub fn spawn(command: &str) -> Result<Self, anyhow::Error> {
+        let mut parts = command.split_whitespace();
+        let program = parts.next().context("Empty extractor command")?;
+        let mut child = Command::new(program)
+            .args(parts)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to spawn extractor command {command}"))?;
+        let stdin = child.stdin.take().context("Extractor has no stdin")?;
+        let stdout = child.stdout.take().context("Extractor has no stdout")?;
+        Ok(Self {
+            process: Mutex::new((child, stdin, BufReader::new(stdout))),
+        })
+    }
+}
+
+impl HtmlExtractor for SubprocessExtractor {
+    fn name(&self) -> &'static str {
+        "subprocess"
+    }
+
+    fn extract(&self, html: &str) -> Result<Option<String>, anyhow::Error> {
+        let mut process = self.process.lock().unwrap();
+        let (_, stdin, stdout) = &mut *process;
+        serde_json::to_writer(&mut *stdin, &SubprocessRequest { html })?;
+        writeln!(stdin)?;
+        stdin.flush()?;
+        let mut line = String::new();
+        if stdout.read_line(&mut line)? == 0 {
+            anyhow::bail!("Extractor process exited");
+        }
+        Ok(serde_json::from_str::<SubprocessResponse>(&line)?.text)
+    }
+}
+
+impl Drop for SubprocessExtractor {
+    fn drop(&mut self) {
+        if let Ok(process) = self.process.get_mut() {
+            let _ = process.0.kill();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "extraction")]
+    use crate::extractor::ReadabilityExtractor;
+    use crate::extractor::{extract_with_fallback, HtmlExtractor, TagStripExtractor};
+
+    const PAGE: &str = r#"<html><head><title>Title</title><style>body { color: red; }</style></head>
+<body><nav><ul><li><a href="/">Home</a></li><li><a href="/about">About us and everything else</a></li></ul></nav>
+<article><h1>Headline</h1><p>This is the main content of the page &amp; it is long enough.</p>
+<script>var x = "<p>not text</p>";</script></article><footer><p>Copyright notice that is long enough</p></footer></body></html>"#;
+
+    #[test]
+    fn tag_strip_keeps_all_text_but_scripts() {
+        let text = TagStripExtractor.extract(PAGE).unwrap().unwrap();
+        assert!(text.contains("main content of the page & it is"));
+        assert!(text.contains("Home"));
+        assert!(!text.contains("color: red"));
+        assert!(!text.contains("not text"));
+    }
+
+    #[cfg(feature = "extraction")]
+    #[test]
+    fn readability_drops_boilerplate() {
+        let text = ReadabilityExtractor::default()
+            .extract(PAGE)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            text,
+            "Headline\nThis is the main content of the page & it is long enough."
+        );
+    }
+
+    struct Empty;
+
+    impl HtmlExtractor for Empty {
+        fn name(&self) -> &'static str {
+            "empty"
+        }
+
+        fn extract(&self, _html: &str) -> Result<Option<String>, anyhow::Error> {
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn falls_back_to_tag_strip_on_empty_text() {
+        let (text, extractor) = extract_with_fallback(&Empty, PAGE, 50).unwrap().unwrap();
+        assert_eq!(extractor, "tag-strip");
+        assert!(text.contains("main content of the page"));
+        assert_eq!(
+            extract_with_fallback(&Empty, "<html></html>", 50).unwrap(),
+            None
+        );
+
+        let (_, extractor) = extract_with_fallback(&TagStripExtractor, PAGE, 10_000)
+            .unwrap()
+            .unwrap();
+        assert_eq!(extractor, "tag-strip");
+        #[cfg(feature = "extraction")]
+        {
+            let readability = ReadabilityExtractor::default();
+            let (_, extractor) = extract_with_fallback(&readability, PAGE, 50)
+                .unwrap()
+                .unwrap();
+            assert_eq!(extractor, "readability");
+            let (_, extractor) = extract_with_fallback(&readability, PAGE, 100)
+                .unwrap()
+                .unwrap();
+            assert_eq!(extractor, "tag-strip");
+        }
+    }
+}
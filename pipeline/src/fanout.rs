@@ -0,0 +1,113 @@
+use std::collections::BTreeMap;
+
+use lapin::{types::AMQPValue, BasicProperties};
+
+use crate::batch::{batch_id, BatchEncoding, BatchManifest, MANIFEST_HEADERS};
+
+/// One message per entry of the batch in `payload`, for consumers that expect one URL
+/// per message. Each entry is sent as JSON, whatever the encoding of the batch, with the
+/// headers of the batch, i.e. its key and source, plus `batch_id` and `entry_index`, which
+/// identify the entry when the batch is fanned out again after a redelivery. The manifest
+/// headers describe the whole batch and are checked here instead of passed on, headers the
+/// broker added are dropped.
+pub fn entry_messages(
+    payload: &[u8],
+    properties: &BasicProperties,
+) -> Result<Vec<(Vec<u8>, BasicProperties)>, anyhow::Error> {
+    let header = |key: &str| crate::rabbitmq::header_value(properties.headers(), key);
+    let encoding =
+        BatchEncoding::from_content_type(properties.content_type().as_ref().map(|t| t.as_str()))?;
+    let batch = match BatchManifest::from_headers(header)? {
+        Some(manifest) => manifest.verify(payload, encoding)?,
+        None => encoding.decode(payload)?,
+    };
+    let batch_id = batch_id(payload);
+    let headers = properties
+        .headers()
+        .as_ref()
+        .map_or_else(BTreeMap::new, |headers| {
+            headers
+                .inner()
+                .iter()
+                .filter(|(key, _)| {
+                    !key.as_str().starts_with("x-") && !MANIFEST_HEADERS.contains(&key.as_str())
+                })
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect()
+        });
+    batch
+        .iter()
+        .enumerate()
+        .map(|(index, entry)| {
+            let mut headers = headers.clone();
+            headers.insert(
+                "batch_id".into(),
+                AMQPValue::LongString(batch_id.as_str().into()),
+            );
+            headers.insert(
+                "entry_index".into(),
+                AMQPValue::LongString(index.to_string().as_str().into()),
+            );
+            let properties = BasicProperties::default()
+                .with_content_type(BatchEncoding::Json.content_type().into())
+                .with_headers(headers.into());
+            Ok((serde_json::to_vec(entry)?, properties))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use lapin::{types::AMQPValue, BasicProperties};
+
+    use crate::{
+        batch::{BatchEncoding, BatchManifest},
+        cdx::{parse_cdx_line, CdxEntry},
+        fanout::entry_messages,
+        rabbitmq::{header_value, headers_field_table},
+    };
+
+    #[test]
+    fn fans_out_entries_with_batch_headers() {
+        let batch = (0..3)
+            .map(|i| {
+                parse_cdx_line(&format!(
+                    r#"com,example)/{i} 20240722120756 {{"url": "https://example.com/{i}", "mime": "text/html", "mime-detected": "text/html", "status": "200", "digest": "D", "length": "100", "offset": "{i}00", "filename": "crawl-data/x.warc.gz"}}"#
+                ))
+                .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let payload = BatchEncoding::Json.encode(&batch).unwrap();
+        let mut headers = vec![
+            ("crawl".to_string(), "CC-MAIN-2024-30".to_string()),
+            ("source_cdx_path".to_string(), "cdx-00000.gz".to_string()),
+        ];
+        headers.extend(BatchManifest::new(&batch, &payload).to_headers());
+        let mut headers = headers_field_table(&headers);
+        headers.insert("x-death".into(), AMQPValue::LongString("dead".into()));
+        let properties = BasicProperties::default()
+            .with_content_type("application/json".into())
+            .with_headers(headers);
+
+        let messages = entry_messages(&payload, &properties).unwrap();
+        assert_eq!(messages.len(), 3);
+        let (entry, properties) = &messages[2];
+        let entry: CdxEntry = serde_json::from_slice(entry).unwrap();
+        assert_eq!(entry.metadata.url, "https://example.com/2");
+        let header = |key| header_value(properties.headers(), key);
+        assert_eq!(header("crawl").as_deref(), Some("CC-MAIN-2024-30"));
+        assert_eq!(header("source_cdx_path").as_deref(), Some("cdx-00000.gz"));
+        assert_eq!(header("entry_index").as_deref(), Some("2"));
+        assert_eq!(
+            header("batch_id"),
+            header_value(messages[0].1.headers(), "batch_id")
+        );
+        assert_eq!(header("manifest_entries"), None);
+        assert_eq!(header("x-death"), None);
+
+        let corrupt = BasicProperties::default().with_headers(headers_field_table(
+            &BatchManifest::new(&batch[..1], &payload).to_headers(),
+        ));
+        assert!(entry_messages(&payload, &corrupt).is_err());
+    }
+}
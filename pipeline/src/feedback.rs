@@ -0,0 +1,231 @@
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cdx::CdxEntry,
+    metrics::{ExportMetrics, PrometheusText},
+};
+
+/// How many captures of one domain a worker fetched, and how many became documents.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct DomainOutcome {
+    pub domain: String,
+    pub fetched: u64,
+    pub kept: u64,
+}
+
+/// What a worker publishes to the feedback queue after each batch.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct FeedbackSummary {
+    pub run_id: String,
+    pub worker: String,
+    pub domains: Vec<DomainOutcome>,
+}
+
+fn domain_of(url: &str) -> Option<String> {
+    url::Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_ascii_lowercase))
+}
+
+/// Fetched and kept captures per domain since the last [`RejectionTally::take`].
+#[derive(Debug, Default)]
+pub struct RejectionTally {
+    counts: HashMap<String, (u64, u64)>,
+}
+
+impl RejectionTally {
+    pub fn fetched(&mut self, url: &str) {
+        if let Some(domain) = domain_of(url) {
+            self.counts.entry(domain).or_default().0 += 1;
+        }
+    }
+
+    pub fn kept(&mut self, url: &str) {
+        if let Some(domain) = domain_of(url) {
+            self.counts.entry(domain).or_default().1 += 1;
+        }
+    }
+
+    /// The summary of everything counted so far, `None` if nothing was fetched.
+    pub fn take(&mut self, run_id: &str, worker: &str) -> Option<FeedbackSummary> {
+        if self.counts.is_empty() {
+            return None;
+        }
+        let mut domains = self
+            .counts
+            .drain()
+            .map(|(domain, (fetched, kept))| DomainOutcome {
+                domain,
+                fetched,
+                kept,
+            })
+            .collect::<Vec<_>>();
+        domains.sort_by(|a, b| a.domain.cmp(&b.domain));
+        Some(FeedbackSummary {
+            run_id: run_id.to_string(),
+            worker: worker.to_string(),
+            domains,
+        })
+    }
+}
+
+/// Counters of [`DomainSampler`], exported on `/metrics`.
+pub struct SamplingStats {
+    summaries: AtomicU64,
+    down_sampled_entries: AtomicU64,
+}
+
+pub static ADAPTIVE_SAMPLING: SamplingStats = SamplingStats {
+    summaries: AtomicU64::new(0),
+    down_sampled_entries: AtomicU64::new(0),
+};
+
+impl SamplingStats {
+    pub fn summaries(&self) -> u64 {
+        self.summaries.load(Ordering::Relaxed)
+    }
+
+    pub fn down_sampled_entries(&self) -> u64 {
+        self.down_sampled_entries.load(Ordering::Relaxed)
+    }
+}
+
+impl ExportMetrics for SamplingStats {
+    fn export(&self, text: &mut PrometheusText) {
+        text.counter(
+            "pipeline_feedback_summaries_total",
+            "Feedback summaries of workers applied by the batcher.",
+            self.summaries(),
+        );
+        text.counter(
+            "pipeline_down_sampled_entries_total",
+            "CDX entries left out because their domain is mostly rejected.",
+            self.down_sampled_entries(),
+        );
+    }
+}
+
+/// Decides in the batcher which entries of a domain to enqueue, from the feedback of the
+/// workers of the same run. Once at least `min_fetched` captures of a domain were fetched
+/// and more than `max_rejection_rate` of them did not become documents, only `keep_rate`
+/// of its entries are enqueued. Those still report back, so a domain that turns out
+/// better later is enqueued in full again.
+#[derive(Debug)]
+pub struct DomainSampler {
+    run_id: String,
+    min_fetched: u64,
+    max_rejection_rate: f64,
+    keep_rate: f64,
+    totals: HashMap<String, (u64, u64)>,
+}
+
+impl DomainSampler {
+    pub fn new(run_id: &str, min_fetched: u64, max_rejection_rate: f64, keep_rate: f64) -> Self {
+        Self {
+            run_id: run_id.to_string(),
+            min_fetched,
+            max_rejection_rate,
+            keep_rate,
+            totals: HashMap::new(),
+        }
+    }
+
+    /// Adds the outcomes of `summary`, unless it belongs to another run.
+    pub fn add(&mut self, summary: &FeedbackSummary) {
+        if summary.run_id != self.run_id {
+            return;
+        }
+        ADAPTIVE_SAMPLING.summaries.fetch_add(1, Ordering::Relaxed);
+        for outcome in &summary.domains {
+            let totals = self.totals.entry(outcome.domain.clone()).or_default();
+            totals.0 += outcome.fetched;
+            totals.1 += outcome.kept.min(outcome.fetched);
+        }
+    }
+
+    /// The share of fetched captures of `domain` that were rejected, `None` until enough
+    /// were fetched to tell.
+    pub fn rejection_rate(&self, domain: &str) -> Option<f64> {
+        let (fetched, kept) = self.totals.get(domain)?;
+        (*fetched >= self.min_fetched.max(1)).then(|| 1.0 - *kept as f64 / *fetched as f64)
+    }
+
+    pub fn is_down_sampled(&self, domain: &str) -> bool {
+        self.rejection_rate(domain)
+            .is_some_and(|rate| rate > self.max_rejection_rate)
+    }
+
+    pub fn num_down_sampled_domains(&self) -> usize {
+        self.totals
+            .keys()
+            .filter(|domain| self.is_down_sampled(domain))
+            .count()
+    }
+
+    /// Whether to enqueue `entry`. Entries of a down-sampled domain are picked by the hash
+    /// of their URL, so the same ones are kept when the batcher runs again.
+    pub fn keep(&self, entry: &CdxEntry) -> bool {
+        let url = &entry.metadata.url;
+        if !domain_of(url).is_some_and(|domain| self.is_down_sampled(&domain)) {
+            return true;
+        }
+        let keep =
+            (xxhash_rust::xxh3::xxh3_64(url.as_bytes()) as f64) < self.keep_rate * u64::MAX as f64;
+        if !keep {
+            ADAPTIVE_SAMPLING
+                .down_sampled_entries
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        keep
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        cdx::{parse_cdx_line, CdxEntry},
+        feedback::{DomainSampler, RejectionTally},
+    };
+
+    fn entry(url: &str) -> CdxEntry {
+        parse_cdx_line(&format!(
+            r#"com,example)/ 20240723213521 {{"url": "{url}", "status": "200", "length": "100", "offset": "0", "filename": "crawl-data/CC-MAIN-2024-30/segments/1/warc/a.warc.gz"}}"#
+        )).unwrap()
+    }
+
+    #[test]
+    fn down_samples_mostly_rejected_domains() {
+        let mut tally = RejectionTally::default();
+        for i in 0..100 {
+            tally.fetched(&format!("https://spam.example/{i}"));
+            tally.fetched(&format!("https://Good.example/{i}"));
+            tally.kept(&format!("https://good.example/{i}"));
+        }
+        tally.kept("https://spam.example/0");
+        let summary = tally.take("run-1", "worker-1").unwrap();
+        assert_eq!(summary.domains.len(), 2);
+        assert!(tally.take("run-1", "worker-1").is_none());
+
+        let mut sampler = DomainSampler::new("run-1", 50, 0.95, 0.1);
+        assert!(!sampler.is_down_sampled("spam.example"));
+        sampler.add(&summary);
+        assert_eq!(sampler.rejection_rate("spam.example"), Some(0.99));
+        assert_eq!(sampler.rejection_rate("good.example"), Some(0.0));
+        assert_eq!(sampler.num_down_sampled_domains(), 1);
+
+        let kept = (0..1000)
+            .filter(|i| sampler.keep(&entry(&format!("https://spam.example/{i}"))))
+            .count();
+        assert!((50..150).contains(&kept), "kept {kept}");
+        assert!((0..100).all(|i| sampler.keep(&entry(&format!("https://good.example/{i}")))));
+
+        let mut other_run = DomainSampler::new("run-2", 50, 0.95, 0.1);
+        other_run.add(&summary);
+        assert_eq!(other_run.rejection_rate("spam.example"), None);
+    }
+}
@@ -0,0 +1,1109 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, fs,
+    net::IpAddr,
+    ops::RangeInclusive,
+    path::Path,
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use anyhow::Context;
+use regex::{Regex, RegexSet};
+
+use crate::{
+    cdx::CdxEntry,
+    compare::RunCounters,
+    output::{document_id, Document},
+    provenance::IpRanges,
+    warc_response::{http_header, peek_http_headers, ResponseHeaders},
+};
+
+/// The CDX languages to keep, parsed from `any` or a comma separated list of ISO 639-3
+/// codes such as `eng,deu,fra`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LanguageSelection {
+    Any,
+    Codes(Vec<String>),
+}
+
+impl LanguageSelection {
+    /// Whether an entry with the CDX `languages`, e.g. `ind,eng`, is kept. Common Crawl
+    /// lists the codes by their share of the page, so with `primary_only` only the first
+    /// one counts. Entries without languages are only kept by [`LanguageSelection::Any`].
+    pub fn matches(&self, languages: Option<&str>, primary_only: bool) -> bool {
+        let codes = match self {
+            LanguageSelection::Any => return true,
+            LanguageSelection::Codes(codes) => codes,
+        };
+        let Some(languages) = languages else {
+            return false;
+        };
+        let mut detected = languages.split(',').map(str::trim);
+        if primary_only {
+            detected
+                .next()
+                .is_some_and(|code| codes.iter().any(|c| c == code))
+        } else {
+            detected.any(|code| codes.iter().any(|c| c == code))
+        }
+    }
+}
+
+impl FromStr for LanguageSelection {
+    type Err = anyhow::Error;
+
+    fn from_str(selection: &str) -> Result<Self, Self::Err> {
+        if selection.trim() == "any" {
+            return Ok(LanguageSelection::Any);
+        }
+        let codes = selection
+            .split(',')
+            .map(str::trim)
+            .filter(|code| !code.is_empty())
+            .map(|code| {
+                anyhow::ensure!(
+                    code.len() == 3 && code.bytes().all(|b| b.is_ascii_lowercase()),
+                    "{code:?} is not an ISO 639-3 language code"
+                );
+                Ok(code.to_string())
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        anyhow::ensure!(!codes.is_empty(), "No language codes in {selection:?}");
+        Ok(LanguageSelection::Codes(codes))
+    }
+}
+
+impl fmt::Display for LanguageSelection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LanguageSelection::Any => f.write_str("any"),
+            LanguageSelection::Codes(codes) => f.write_str(&codes.join(",")),
+        }
+    }
+}
+
+/// MIME types such as `text/html`, whole types such as `image/*`, or `*` for any, parsed
+/// from a comma separated list.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MimeTypes(Vec<String>);
+
+impl MimeTypes {
+    /// The types text extraction can do something with.
+    pub fn html() -> Self {
+        Self(vec![
+            "text/html".to_string(),
+            "application/xhtml+xml".to_string(),
+        ])
+    }
+
+    /// Whether `mime` is one of the types, ignoring parameters like `; charset=utf-8`.
+    pub fn matches(&self, mime: &str) -> bool {
+        let mime = mime.split(';').next().unwrap_or_default();
+        let mime = mime.trim().to_ascii_lowercase();
+        self.0.iter().any(|pattern| {
+            pattern == "*"
+                || match pattern.strip_suffix("/*") {
+                    Some(top_level) => mime.split('/').next() == Some(top_level),
+                    None => *pattern == mime,
+                }
+        })
+    }
+}
+
+impl FromStr for MimeTypes {
+    type Err = anyhow::Error;
+
+    fn from_str(types: &str) -> Result<Self, Self::Err> {
+        let patterns = types
+            .split(',')
+            .map(|pattern| pattern.trim().to_ascii_lowercase())
+            .filter(|pattern| !pattern.is_empty())
+            .map(|pattern| {
+                let is_valid = pattern == "*"
+                    || pattern.split_once('/').is_some_and(|(top_level, subtype)| {
+                        !top_level.is_empty()
+                            && top_level != "*"
+                            && !subtype.is_empty()
+                            && !subtype.contains('/')
+                    });
+                anyhow::ensure!(is_valid, "{pattern:?} is not a MIME type");
+                Ok(pattern)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        anyhow::ensure!(!patterns.is_empty(), "No MIME types in {types:?}");
+        Ok(Self(patterns))
+    }
+}
+
+impl fmt::Display for MimeTypes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0.join(","))
+    }
+}
+
+/// The HTTP status codes to keep, parsed from a comma separated list of codes and
+/// ranges such as `200,301-308`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatusCodes(Vec<RangeInclusive<u16>>);
+
+impl StatusCodes {
+    pub fn contains(&self, status: u16) -> bool {
+        self.0.iter().any(|range| range.contains(&status))
+    }
+}
+
+impl Default for StatusCodes {
+    fn default() -> Self {
+        Self(vec![200..=200])
+    }
+}
+
+impl FromStr for StatusCodes {
+    type Err = anyhow::Error;
+
+    fn from_str(codes: &str) -> Result<Self, Self::Err> {
+        let parse_code = |code: &str| {
+            let code = code.trim();
+            match code.parse::<u16>() {
+                Ok(status) if (100..600).contains(&status) => Ok(status),
+                _ => Err(anyhow::anyhow!("{code:?} is not an HTTP status code")),
+            }
+        };
+        let ranges = codes
+            .split(',')
+            .filter(|range| !range.trim().is_empty())
+            .map(|range| {
+                let (first, last) = match range.split_once('-') {
+                    Some((first, last)) => (parse_code(first)?, parse_code(last)?),
+                    None => (parse_code(range)?, parse_code(range)?),
+                };
+                anyhow::ensure!(first <= last, "{:?} is an empty range", range.trim());
+                Ok(first..=last)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        anyhow::ensure!(!ranges.is_empty(), "No status codes in {codes:?}");
+        Ok(Self(ranges))
+    }
+}
+
+impl fmt::Display for StatusCodes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, range) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            match range.start() == range.end() {
+                true => write!(f, "{}", range.start())?,
+                false => write!(f, "{}-{}", range.start(), range.end())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Counts the CDX entries left out for their status, per class like `4xx`.
+#[derive(Debug, Default)]
+pub struct StatusExclusions {
+    /// `1xx` to `5xx`, then anything else.
+    classes: [AtomicU64; 6],
+}
+
+impl StatusExclusions {
+    pub fn record(&self, status: u16) {
+        let class = match status {
+            100..600 => status as usize / 100 - 1,
+            _ => 5,
+        };
+        self.classes[class].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The entries left out so far, as `status_filter.excluded.<class>`.
+    pub fn counters(&self) -> RunCounters {
+        let mut counters = RunCounters::default();
+        for (i, count) in self.classes.iter().enumerate() {
+            let class = match i {
+                5 => "other".to_string(),
+                _ => format!("{}xx", i + 1),
+            };
+            counters.add(
+                &format!("status_filter.excluded.{class}"),
+                count.load(Ordering::Relaxed),
+            );
+        }
+        counters
+    }
+}
+
+/// Decides which CDX entries are worth sending to the workers.
+#[derive(Debug, Clone)]
+pub struct CdxFilter {
+    pub languages: LanguageSelection,
+    /// Require the first of the CDX languages to be selected, not just any of them.
+    pub primary_language_only: bool,
+    pub status_codes: StatusCodes,
+    /// Checked against the `mime-detected` type of an entry, or its `mime` if Common
+    /// Crawl did not detect one. Entries without either pass.
+    pub mime_types: MimeTypes,
+    pub excluded_mime_types: Option<MimeTypes>,
+}
+
+impl Default for CdxFilter {
+    fn default() -> Self {
+        Self {
+            languages: LanguageSelection::Codes(vec!["eng".to_string()]),
+            primary_language_only: false,
+            status_codes: StatusCodes::default(),
+            mime_types: MimeTypes::html(),
+            excluded_mime_types: None,
+        }
+    }
+}
+
+impl CdxFilter {
+    pub fn matches(&self, entry: &CdxEntry) -> bool {
+        let mime = entry
+            .metadata
+            .mime_detected
+            .as_deref()
+            .or(entry.metadata.mime.as_deref())
+            .filter(|mime| !mime.trim().is_empty());
+        let mime_matches = mime.is_none_or(|mime| {
+            self.mime_types.matches(mime)
+                && !self
+                    .excluded_mime_types
+                    .as_ref()
+                    .is_some_and(|excluded| excluded.matches(mime))
+        });
+        self.status_codes.contains(entry.metadata.status)
+            && mime_matches
+            && self.languages.matches(
+                entry.metadata.languages.as_deref(),
+                self.primary_language_only,
+            )
+    }
+}
+
+/// A condition on an HTTP response header, parsed from `Name` for any value or
+/// `Name=value`, e.g. `Content-Type=text/html`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderRule {
+    name: String,
+    value: Option<String>,
+}
+
+impl FromStr for HeaderRule {
+    type Err = anyhow::Error;
+
+    fn from_str(rule: &str) -> Result<Self, Self::Err> {
+        let (name, value) = match rule.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim())),
+            None => (rule.trim(), None),
+        };
+        anyhow::ensure!(
+            !name.is_empty() && !name.contains(char::is_whitespace),
+            "{rule:?} is not a header name, optionally followed by =value"
+        );
+        anyhow::ensure!(
+            value.is_none_or(|value| !value.is_empty()),
+            "{rule:?} has an empty value, leave out the = to match any"
+        );
+        Ok(Self {
+            name: name.to_string(),
+            value: value.map(str::to_ascii_lowercase),
+        })
+    }
+}
+
+impl HeaderRule {
+    /// Whether the first header called `name` is there and one of its comma-separated
+    /// values is `value`, ignoring case and parameters. `Content-Type=text/html` matches
+    /// `text/html; charset=utf-8`, `Content-Encoding=br` matches `gzip, br`.
+    pub fn matches(&self, http_headers: &[u8]) -> bool {
+        let Some(header) = http_header(http_headers, &self.name) else {
+            return false;
+        };
+        let Some(value) = self.value.as_deref() else {
+            return true;
+        };
+        header.split(',').any(|item| {
+            let item = item.split(';').next().unwrap_or_default();
+            item.trim().eq_ignore_ascii_case(value)
+        })
+    }
+}
+
+/// Decides on the HTTP response headers of a fetched record. Records without a usable
+/// header pass.
+#[derive(Debug, Clone, Default)]
+pub struct HeaderFilter {
+    pub max_last_modified_age: Option<Duration>,
+    /// Rules a record has to match all of.
+    pub required: Vec<HeaderRule>,
+    /// Rules a record must not match any of.
+    pub dropped: Vec<HeaderRule>,
+}
+
+impl HeaderFilter {
+    /// Whether the raw HTTP headers of a response pass `required` and `dropped`.
+    pub fn admits(&self, http_headers: &[u8]) -> bool {
+        self.required.iter().all(|rule| rule.matches(http_headers))
+            && !self.dropped.iter().any(|rule| rule.matches(http_headers))
+    }
+
+    /// Like [`admits`](Self::admits) on the gzipped WARC record as fetched, which is only
+    /// decompressed up to the end of its HTTP headers, and not at all without rules.
+    pub fn admits_record(&self, gzipped: &[u8]) -> bool {
+        if self.required.is_empty() && self.dropped.is_empty() {
+            return true;
+        }
+        peek_http_headers(gzipped).is_none_or(|http_headers| self.admits(&http_headers))
+    }
+
+    pub fn matches(&self, headers: &ResponseHeaders, now_unix: u64) -> bool {
+        match (self.max_last_modified_age, headers.last_modified_unix()) {
+            (Some(max_age), Some(last_modified)) => {
+                now_unix.saturating_sub(last_modified) <= max_age.as_secs()
+            }
+            _ => true,
+        }
+    }
+}
+
+/// A set of WARC payload digests and document IDs, read from a file with one per line.
+/// Empty lines and lines starting with `#` are ignored.
+#[derive(Debug, Default)]
+pub struct RecordList {
+    keys: HashSet<String>,
+}
+
+fn normalize_digest(digest: &str) -> &str {
+    digest.strip_prefix("sha1:").unwrap_or(digest)
+}
+
+impl RecordList {
+    pub fn read(path: &Path) -> Result<Self, anyhow::Error> {
+        Ok(fs::read_to_string(path)?.lines().collect())
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Whether the digest or the document ID of `entry` is on the list.
+    pub fn contains(&self, entry: &CdxEntry) -> bool {
+        if let Some(digest) = entry.metadata.digest.as_deref() {
+            if self.keys.contains(normalize_digest(digest)) {
+                return true;
+            }
+        }
+        let id = document_id(
+            entry.metadata.crawl().unwrap_or_default(),
+            entry.metadata.digest.as_deref().unwrap_or_default(),
+            &entry.metadata.url,
+        );
+        self.keys.contains(&id)
+    }
+}
+
+impl<'a> FromIterator<&'a str> for RecordList {
+    fn from_iter<T: IntoIterator<Item = &'a str>>(lines: T) -> Self {
+        let keys = lines
+            .into_iter()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| normalize_digest(line).to_string())
+            .collect();
+        Self { keys }
+    }
+}
+
+/// Records the worker is told to leave out, e.g. for takedowns, or to process exclusively,
+/// e.g. to reprocess known problem documents.
+#[derive(Debug, Default)]
+pub struct RecordSelection {
+    pub skip: Option<RecordList>,
+    pub include: Option<RecordList>,
+}
+
+impl RecordSelection {
+    /// Whether `entry` is on the skip list.
+    pub fn skips(&self, entry: &CdxEntry) -> bool {
+        self.skip.as_ref().is_some_and(|skip| skip.contains(entry))
+    }
+
+    pub fn selects(&self, entry: &CdxEntry) -> bool {
+        !self.skips(entry)
+            && self
+                .include
+                .as_ref()
+                .is_none_or(|include| include.contains(entry))
+    }
+}
+
+/// Domains whose pages are left out, e.g. publishers that opted out of the dataset, read
+/// from a file with one domain per line. A domain also covers its subdomains. Empty lines
+/// and lines starting with `#` are ignored.
+#[derive(Debug, Default)]
+pub struct DomainList {
+    domains: HashSet<String>,
+}
+
+impl DomainList {
+    pub fn read(path: &Path) -> Result<Self, anyhow::Error> {
+        Ok(fs::read_to_string(path)?.lines().collect())
+    }
+
+    pub fn len(&self) -> usize {
+        self.domains.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.domains.is_empty()
+    }
+
+    /// Whether the host of `url` or one of its parent domains is on the list.
+    pub fn contains(&self, url: &str) -> bool {
+        let Some(host) = url::Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_ascii_lowercase))
+        else {
+            return false;
+        };
+        let mut domain = host.as_str();
+        loop {
+            if self.domains.contains(domain) {
+                return true;
+            }
+            match domain.split_once('.') {
+                Some((_, parent)) => domain = parent,
+                None => return false,
+            }
+        }
+    }
+}
+
+impl<'a> FromIterator<&'a str> for DomainList {
+    fn from_iter<T: IntoIterator<Item = &'a str>>(lines: T) -> Self {
+        let domains = lines
+            .into_iter()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| line.trim_end_matches('.').to_ascii_lowercase())
+            .collect();
+        Self { domains }
+    }
+}
+
+/// Decides on the IP address a record was fetched from. With an include list, records
+/// without an address are left out.
+#[derive(Debug, Default)]
+pub struct IpFilter {
+    pub include: Option<IpRanges>,
+    pub exclude: Option<IpRanges>,
+}
+
+impl IpFilter {
+    pub fn matches(&self, ip: Option<IpAddr>) -> bool {
+        match ip {
+            Some(ip) => {
+                !self
+                    .exclude
+                    .as_ref()
+                    .is_some_and(|exclude| exclude.contains(ip))
+                    && self
+                        .include
+                        .as_ref()
+                        .is_none_or(|include| include.contains(ip))
+            }
+            None => self.include.is_none(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Language,
+    TokenCount,
+    QualityScore,
+    ModelScore,
+    Domain,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Condition {
+    field: Field,
+    op: Op,
+    value: String,
+}
+
+impl Condition {
+    fn matches(&self, document: &Document) -> bool {
+        let number = match self.field {
+            Field::Language => return self.compare_str(&document.language),
+            Field::Domain => {
+                let domain = url::Url::parse(&document.url)
+                    .ok()
+                    .and_then(|url| url.host_str().map(str::to_string))
+                    .unwrap_or_default();
+                return self.compare_str(&domain);
+            }
+            Field::TokenCount => Some(document.token_count as f64),
+            Field::QualityScore => Some(document.quality_score),
+            Field::ModelScore => document.model_score,
+        };
+        // The value was checked to be a number when parsing, a missing model score never
+        // matches.
+        let (Some(number), Ok(value)) = (number, self.value.parse::<f64>()) else {
+            return false;
+        };
+        match self.op {
+            Op::Eq => number == value,
+            Op::Ne => number != value,
+            Op::Lt => number < value,
+            Op::Le => number <= value,
+            Op::Gt => number > value,
+            Op::Ge => number >= value,
+        }
+    }
+
+    fn compare_str(&self, text: &str) -> bool {
+        match self.op {
+            Op::Eq => text == self.value,
+            _ => text != self.value,
+        }
+    }
+}
+
+impl FromStr for Condition {
+    type Err = anyhow::Error;
+
+    fn from_str(condition: &str) -> Result<Self, Self::Err> {
+        let (start, symbol, op) = [
+            ("==", Op::Eq),
+            ("!=", Op::Ne),
+            ("<=", Op::Le),
+            (">=", Op::Ge),
+            ("<", Op::Lt),
+            (">", Op::Gt),
+        ]
+        .into_iter()
+        .find_map(|(symbol, op)| condition.find(symbol).map(|start| (start, symbol, op)))
+        .with_context(|| format!("No comparison in {condition:?}"))?;
+        let field = match condition[..start].trim() {
+            "language" | "detected_lang" => Field::Language,
+            "token_count" => Field::TokenCount,
+            "quality_score" => Field::QualityScore,
+            "model_score" => Field::ModelScore,
+            "domain" => Field::Domain,
+            other => anyhow::bail!("Unknown output filter field {other:?}"),
+        };
+        let value = condition[start + symbol.len()..]
+            .trim()
+            .trim_matches(|c| c == '"' || c == '\'')
+            .to_string();
+        match field {
+            Field::Language | Field::Domain => {
+                anyhow::ensure!(
+                    matches!(op, Op::Eq | Op::Ne),
+                    "{symbol} needs a numeric field in {condition:?}"
+                );
+            }
+            _ => {
+                value
+                    .parse::<f64>()
+                    .with_context(|| format!("Not a number in {condition:?}"))?;
+            }
+        }
+        Ok(Self { field, op, value })
+    }
+}
+
+/// Decides which extracted documents are written, after language detection, quality
+/// scoring and model scoring ran on all of them. Parsed from conditions on `language`
+/// (or `detected_lang`), `token_count`, `quality_score`, `model_score` and `domain`,
+/// combined with `&&` and `||`, where `&&` binds tighter, e.g.
+/// `language == eng && token_count >= 200 || domain == en.wikipedia.org`. An empty
+/// expression keeps everything.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OutputFilter {
+    /// Alternatives of conditions that all have to hold.
+    any_of: Vec<Vec<Condition>>,
+}
+
+impl OutputFilter {
+    pub fn matches(&self, document: &Document) -> bool {
+        self.any_of.is_empty()
+            || self
+                .any_of
+                .iter()
+                .any(|all_of| all_of.iter().all(|condition| condition.matches(document)))
+    }
+}
+
+impl FromStr for OutputFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        if expression.trim().is_empty() {
+            return Ok(Self::default());
+        }
+        let any_of = expression
+            .split("||")
+            .map(|all_of| all_of.split("&&").map(str::parse).collect())
+            .collect::<Result<_, _>>()?;
+        Ok(Self { any_of })
+    }
+}
+
+/// The host of a URL or domain as it starts a SURT, e.g. `com,example` for
+/// `www.example.com`.
+fn surt_host(domain: &str) -> String {
+    let domain = domain.trim().trim_matches('.').to_ascii_lowercase();
+    let domain = domain.strip_prefix("www.").unwrap_or(&domain);
+    domain.split('.').rev().collect::<Vec<_>>().join(",")
+}
+
+/// Rules matched against the SURT URLs of CDX entries: domains, which also cover their
+/// subdomains, and regexes. Each rule counts the entries it matched.
+#[derive(Debug, Default)]
+pub struct SurtRules {
+    /// The rule of each [`surt_host`].
+    domains: HashMap<String, usize>,
+    regexes: RegexSet,
+    /// The rule of each pattern of `regexes`.
+    regex_rules: Vec<usize>,
+    /// The rules as written in their files, for the counters.
+    rules: Vec<String>,
+    matched: Vec<AtomicU64>,
+}
+
+impl SurtRules {
+    fn add_rule(&mut self, rule: &str) -> usize {
+        self.rules.push(rule.to_string());
+        self.matched.push(AtomicU64::new(0));
+        self.rules.len() - 1
+    }
+
+    /// Adds the domains of a file with one per line, like `example.com` or `edu`. Empty
+    /// lines and lines starting with `#` are ignored.
+    pub fn with_domains(mut self, path: &Path) -> Result<Self, anyhow::Error> {
+        let text = fs::read_to_string(path).with_context(|| format!("Cannot read {path:?}"))?;
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let rule = self.add_rule(line);
+            self.domains.entry(surt_host(line)).or_insert(rule);
+        }
+        Ok(self)
+    }
+
+    /// Adds the regexes of a file with one per line, matched anywhere in the SURT URL,
+    /// e.g. `^(com|net),[^)]*casino` or `\)/wp-admin/`. Empty lines and lines starting
+    /// with `#` are ignored.
+    pub fn with_regexes(mut self, path: &Path) -> Result<Self, anyhow::Error> {
+        let text = fs::read_to_string(path).with_context(|| format!("Cannot read {path:?}"))?;
+        let mut patterns = self.regexes.patterns().to_vec();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            Regex::new(line).with_context(|| format!("Invalid regex in line {}", number + 1))?;
+            let rule = self.add_rule(line);
+            patterns.push(line.to_string());
+            self.regex_rules.push(rule);
+        }
+        self.regexes = RegexSet::new(patterns)?;
+        Ok(self)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// The rule matching `surt_url` and counts it. Domains go first, the most specific
+    /// one, then the first regex in file order.
+    fn matching_rule(&self, surt_url: &str) -> Option<usize> {
+        let host = surt_url.split(')').next().unwrap_or_default();
+        let mut host = host.split(':').next().unwrap_or_default();
+        let mut rule = None;
+        while rule.is_none() && !host.is_empty() {
+            rule = self.domains.get(host).copied();
+            host = host.rsplit_once(',').map_or("", |(parent, _)| parent);
+        }
+        let rule = rule.or_else(|| {
+            let pattern = self.regexes.matches(surt_url).into_iter().next()?;
+            Some(self.regex_rules[pattern])
+        })?;
+        self.matched[rule].fetch_add(1, Ordering::Relaxed);
+        Some(rule)
+    }
+
+    fn add_counters(&self, kind: &str, counters: &mut RunCounters) {
+        for (rule, matched) in self.rules.iter().zip(&self.matched) {
+            counters.add(
+                &format!("url_filter.{kind}.{rule}"),
+                matched.load(Ordering::Relaxed),
+            );
+        }
+    }
+}
+
+/// Decides on the SURT URL of a CDX entry before it is batched. An entry on the
+/// blocklist is left out. With an allowlist, so is an entry that is not on it.
+#[derive(Debug, Default)]
+pub struct UrlFilter {
+    pub allow: SurtRules,
+    pub block: SurtRules,
+    not_allowed: AtomicU64,
+}
+
+impl UrlFilter {
+    pub fn new(allow: SurtRules, block: SurtRules) -> Self {
+        Self {
+            allow,
+            block,
+            not_allowed: AtomicU64::new(0),
+        }
+    }
+
+    pub fn matches(&self, entry: &CdxEntry) -> bool {
+        if self.block.matching_rule(&entry.surt_url).is_some() {
+            return false;
+        }
+        if self.allow.is_empty() || self.allow.matching_rule(&entry.surt_url).is_some() {
+            return true;
+        }
+        self.not_allowed.fetch_add(1, Ordering::Relaxed);
+        false
+    }
+
+    /// The entries each rule matched so far, as `url_filter.blocked.<rule>` and
+    /// `url_filter.allowed.<rule>`, and the entries on no allowlist as
+    /// `url_filter.not_allowed`.
+    pub fn counters(&self) -> RunCounters {
+        let mut counters = RunCounters::default();
+        self.block.add_counters("blocked", &mut counters);
+        self.allow.add_counters("allowed", &mut counters);
+        counters.add(
+            "url_filter.not_allowed",
+            self.not_allowed.load(Ordering::Relaxed),
+        );
+        counters
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, time::Duration};
+
+    use flate2::write::GzEncoder;
+
+    use crate::{
+        cdx::parse_cdx_line,
+        filter::{
+            CdxFilter, DomainList, HeaderFilter, HeaderRule, LanguageSelection, MimeTypes,
+            OutputFilter, RecordList, RecordSelection, StatusCodes, StatusExclusions, SurtRules,
+            UrlFilter,
+        },
+        output::{document_id, Document},
+        warc_response::ResponseHeaders,
+    };
+
+    #[test]
+    fn selects_entries_by_any_or_primary_language() {
+        let entry = |languages: &str| {
+            parse_cdx_line(&format!(
+                r#"com,example)/ 20240723213521 {{"url": "https://example.com/", "status": "200", "length": "100", "offset": "0", "filename": "a.warc.gz"{languages}}}"#
+            )).unwrap()
+        };
+        let mixed = entry(r#", "languages": "ind,eng""#);
+        let bengali = entry(r#", "languages": "ben""#);
+        let unknown = entry("");
+
+        let mut filter = CdxFilter {
+            languages: "eng, deu,fra".parse().unwrap(),
+            ..CdxFilter::default()
+        };
+        assert_eq!(filter.languages.to_string(), "eng,deu,fra");
+        assert!(filter.matches(&mixed));
+        assert!(!filter.matches(&bengali));
+        assert!(!filter.matches(&unknown));
+        filter.primary_language_only = true;
+        assert!(!filter.matches(&mixed));
+        filter.languages = "ind".parse().unwrap();
+        assert!(filter.matches(&mixed));
+
+        filter.languages = "any".parse().unwrap();
+        assert_eq!(filter.languages, LanguageSelection::Any);
+        assert!(filter.matches(&bengali));
+        assert!(filter.matches(&unknown));
+        assert!("en".parse::<LanguageSelection>().is_err());
+        assert!(",".parse::<LanguageSelection>().is_err());
+    }
+
+    #[test]
+    fn selects_entries_by_mime_type() {
+        let entry = |mime: &str| {
+            parse_cdx_line(&format!(
+                r#"com,example)/ 20240723213521 {{"url": "https://example.com/", "status": "200", "length": "100", "offset": "0", "filename": "a.warc.gz", "languages": "eng"{mime}}}"#
+            )).unwrap()
+        };
+        let html = entry(r#", "mime": "text/html", "mime-detected": "application/xhtml+xml""#);
+        let disguised_pdf = entry(r#", "mime": "text/html", "mime-detected": "application/pdf""#);
+        let declared_png = entry(r#", "mime": "image/PNG; name=a.png""#);
+        let unknown = entry("");
+
+        let mut filter = CdxFilter::default();
+        assert!(filter.matches(&html));
+        assert!(!filter.matches(&disguised_pdf));
+        assert!(!filter.matches(&declared_png));
+        assert!(filter.matches(&unknown));
+
+        filter.mime_types = "*".parse().unwrap();
+        filter.excluded_mime_types = Some("image/*, application/pdf".parse().unwrap());
+        assert!(filter.matches(&html));
+        assert!(!filter.matches(&disguised_pdf));
+        assert!(!filter.matches(&declared_png));
+        assert!(filter.matches(&unknown));
+
+        assert_eq!(
+            MimeTypes::html().to_string(),
+            "text/html,application/xhtml+xml"
+        );
+        for invalid in ["", "html", "*/html", "text/", "text/html/x"] {
+            assert!(invalid.parse::<MimeTypes>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn parses_status_code_ranges() {
+        let codes = "200, 301-308".parse::<StatusCodes>().unwrap();
+        assert_eq!(codes.to_string(), "200,301-308");
+        assert!(codes.contains(200));
+        assert!(codes.contains(304));
+        assert!(!codes.contains(404));
+        assert_eq!(StatusCodes::default().to_string(), "200");
+        for invalid in ["", "ok", "299-200", "200-", "600"] {
+            assert!(invalid.parse::<StatusCodes>().is_err(), "{invalid}");
+        }
+
+        let exclusions = StatusExclusions::default();
+        for status in [404, 410, 503, 0] {
+            exclusions.record(status);
+        }
+        let counters = exclusions.counters();
+        assert_eq!(counters.get("status_filter.excluded.4xx"), 2);
+        assert_eq!(counters.get("status_filter.excluded.5xx"), 1);
+        assert_eq!(counters.get("status_filter.excluded.other"), 1);
+        assert_eq!(counters.iter().count(), 3);
+    }
+
+    #[test]
+    fn drops_records_last_modified_too_long_ago() {
+        let filter = HeaderFilter {
+            max_last_modified_age: Some(Duration::from_secs(86400)),
+            ..Default::default()
+        };
+        let headers = ResponseHeaders {
+            last_modified: Some("Thu, 01 Jan 1970 00:00:00 GMT".to_string()),
+            ..Default::default()
+        };
+        assert!(filter.matches(&headers, 86400));
+        assert!(!filter.matches(&headers, 86401));
+        assert!(filter.matches(&ResponseHeaders::default(), 86401));
+        assert!(HeaderFilter::default().matches(&headers, u64::MAX));
+    }
+
+    #[test]
+    fn filters_records_on_raw_response_headers() {
+        let record = |http_headers: &str| {
+            let body = format!("{http_headers}\r\n\r\n<html></html>");
+            let record = format!(
+                "WARC/1.0\r\nWARC-Type: response\r\nContent-Length: {}\r\n\r\n{body}\r\n\r\n",
+                body.len()
+            );
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(record.as_bytes()).unwrap();
+            encoder.finish().unwrap()
+        };
+        let html = record("HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8");
+        let brotli =
+            record("HTTP/1.1 200 OK\r\nContent-Type: TEXT/HTML\r\nContent-Encoding: gzip, br");
+        let pdf = record("HTTP/1.1 200 OK\r\nContent-Type: application/pdf");
+
+        let filter = HeaderFilter {
+            required: vec!["Content-Type=text/html".parse().unwrap()],
+            dropped: vec!["content-encoding=br".parse().unwrap()],
+            ..Default::default()
+        };
+        assert!(filter.admits_record(&html));
+        assert!(!filter.admits_record(&brotli));
+        assert!(!filter.admits_record(&pdf));
+        // Not a gzipped WARC record, so the headers cannot be checked.
+        assert!(filter.admits_record(b"garbage"));
+        assert!(HeaderFilter::default().admits_record(&brotli));
+
+        let any_encoding = HeaderFilter {
+            dropped: vec!["Content-Encoding".parse().unwrap()],
+            ..Default::default()
+        };
+        assert!(any_encoding.admits_record(&html));
+        assert!(!any_encoding.admits_record(&brotli));
+
+        assert!("Content-Type=".parse::<HeaderRule>().is_err());
+        assert!("=text/html".parse::<HeaderRule>().is_err());
+        assert!("Content Type".parse::<HeaderRule>().is_err());
+    }
+
+    #[test]
+    fn selects_records_by_digest_or_id() {
+        let entry = parse_cdx_line(
+            r#"0,100,59,139)/ 20240723213521 {"url": "https://139.59.100.0/", "status": "200", "length": "16650", "offset": "64016172", "filename": "crawl-data/CC-MAIN-2024-30/segments/1720763518115.82/warc/CC-MAIN-20240723194208-20240723224208-00279.warc.gz", "digest": "5JOQMMSNM6N7UCLGGYXDSPSB3FYAQS2C"}"#,
+        ).unwrap();
+        let id = document_id(
+            "CC-MAIN-2024-30",
+            "5JOQMMSNM6N7UCLGGYXDSPSB3FYAQS2C",
+            "https://139.59.100.0/",
+        );
+        let by_digest = [
+            "# takedown 2024-08-01",
+            "sha1:5JOQMMSNM6N7UCLGGYXDSPSB3FYAQS2C",
+        ]
+        .into_iter()
+        .collect::<RecordList>();
+        assert_eq!(by_digest.len(), 1);
+        assert!(by_digest.contains(&entry));
+        assert!([id.as_str()]
+            .into_iter()
+            .collect::<RecordList>()
+            .contains(&entry));
+        assert!(!["OTHER"]
+            .into_iter()
+            .collect::<RecordList>()
+            .contains(&entry));
+
+        assert!(RecordSelection::default().selects(&entry));
+        let skip = RecordSelection {
+            skip: Some(by_digest),
+            include: None,
+        };
+        assert!(!skip.selects(&entry));
+        let include = RecordSelection {
+            skip: None,
+            include: Some(["OTHER"].into_iter().collect()),
+        };
+        assert!(!include.selects(&entry));
+    }
+
+    #[test]
+    fn matches_opted_out_domains_and_subdomains() {
+        let list = ["# opt-outs", "Example.com", "news.example.org."]
+            .into_iter()
+            .collect::<DomainList>();
+        assert_eq!(list.len(), 2);
+        assert!(list.contains("https://example.com/about"));
+        assert!(list.contains("https://www.EXAMPLE.com/"));
+        assert!(list.contains("http://a.news.example.org/story"));
+        assert!(!list.contains("https://example.org/"));
+        assert!(!list.contains("https://notexample.com/"));
+        assert!(!list.contains("not a url"));
+    }
+
+    #[test]
+    fn filters_surt_urls_by_domains_and_regexes() {
+        let dir = std::env::temp_dir().join(format!("pipeline-url-filter-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("allow.txt"),
+            "# universities\nedu\nwww.Example.com\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("block.txt"), "spam.edu\n").unwrap();
+        std::fs::write(dir.join("block.re"), "\\)/wp-admin/\n").unwrap();
+        let filter = UrlFilter {
+            allow: SurtRules::default()
+                .with_domains(&dir.join("allow.txt"))
+                .unwrap(),
+            block: SurtRules::default()
+                .with_domains(&dir.join("block.txt"))
+                .unwrap()
+                .with_regexes(&dir.join("block.re"))
+                .unwrap(),
+            ..UrlFilter::default()
+        };
+        let entry = |surt_url: &str| {
+            parse_cdx_line(&format!(
+                r#"{surt_url} 20240723213521 {{"url": "https://example.com/", "status": "200", "length": "100", "offset": "0", "filename": "a.warc.gz"}}"#
+            ))
+            .unwrap()
+        };
+        assert!(filter.matches(&entry("edu,mit)/")));
+        assert!(filter.matches(&entry("edu,mit,news)/2024")));
+        assert!(filter.matches(&entry("com,example:8080)/about")));
+        assert!(filter.matches(&entry("com,example,blog)/")));
+        assert!(!filter.matches(&entry("edu,spam,www)/")));
+        assert!(!filter.matches(&entry("edu,mit)/wp-admin/login")));
+        assert!(!filter.matches(&entry("org,example)/")));
+
+        let counters = filter.counters();
+        assert_eq!(counters.get("url_filter.allowed.edu"), 2);
+        assert_eq!(counters.get("url_filter.allowed.www.Example.com"), 2);
+        assert_eq!(counters.get("url_filter.blocked.spam.edu"), 1);
+        assert_eq!(counters.get("url_filter.blocked.\\)/wp-admin/"), 1);
+        assert_eq!(counters.get("url_filter.not_allowed"), 1);
+        std::fs::write(dir.join("invalid.re"), "(unclosed\n").unwrap();
+        assert!(SurtRules::default()
+            .with_regexes(&dir.join("invalid.re"))
+            .is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn filters_documents_by_expression() {
+        let document = Document {
+            id: "id".to_string(),
+            url: "https://en.wikipedia.org/wiki/Rust".to_string(),
+            timestamp: "20240723213521".to_string(),
+            language: "eng".to_string(),
+            token_count: 150,
+            quality_score: 0.8,
+            text: "text".to_string(),
+            ..Default::default()
+        };
+        let matches = |expression: &str| {
+            expression
+                .parse::<OutputFilter>()
+                .unwrap()
+                .matches(&document)
+        };
+        assert!(matches(""));
+        assert!(matches("detected_lang == eng && quality_score > 0.5"));
+        assert!(!matches("language == eng && token_count >= 200"));
+        assert!(matches(
+            "language == eng && token_count >= 200 || domain == \"en.wikipedia.org\""
+        ));
+        assert!(!matches("model_score >= 0"));
+        assert!(matches("language != deu"));
+        assert!("language < eng".parse::<OutputFilter>().is_err());
+        assert!("token_count >= many".parse::<OutputFilter>().is_err());
+        assert!("length > 3".parse::<OutputFilter>().is_err());
+    }
+}
@@ -0,0 +1,289 @@
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use flate2::{write::GzEncoder, Compression};
+use sha1::{Digest, Sha1};
+
+use crate::{
+    cdx::{format_cdx_line, surt_url, CdxEntry, CdxMetadata},
+    extractor::strip_tags,
+    output::detect_language,
+};
+
+/// Capture time of all fixture records, so generated files are byte-for-byte reproducible.
+const FIXTURE_TIMESTAMP: &str = "20240722120756";
+const FIXTURE_WARC_DATE: &str = "2024-07-22T12:07:56Z";
+
+pub struct FixturePage {
+    pub url: String,
+    pub html: String,
+}
+
+/// A handful of small pages in different languages, used when no pages are given.
+pub fn sample_pages() -> Vec<FixturePage> {
+    [
+        (
+            "https://example.com/",
+            "<html><head><title>Example</title></head><body><nav><a href=\"/about\">About</a></nav>\
+             <article><h1>Welcome</h1><p>This is a small example page that is used to test the \
+             pipeline end to end. It has enough English text for language detection to be \
+             reliable, and a navigation bar that extractors should drop.</p></article></body></html>",
+        ),
+        (
+            "https://example.com/about",
+            "<html><head><title>About</title></head><body><article><h1>About us</h1><p>We write \
+             tiny web pages so that tests do not have to download anything from Common Crawl. \
+             Every page is stored as a proper WARC record with a matching index entry.</p>\
+             </article></body></html>",
+        ),
+        (
+            "https://beispiel.de/",
+            "<html><head><title>Beispiel</title></head><body><article><h1>Willkommen</h1><p>Dies \
+             ist eine kleine Beispielseite, mit der die Verarbeitung von Anfang bis Ende getestet \
+             wird. Sie enthält genug deutschen Text, damit die Spracherkennung zuverlässig \
+             funktioniert.</p></article></body></html>",
+        ),
+        (
+            "https://exemple.fr/",
+            "<html><head><title>Exemple</title></head><body><article><h1>Bienvenue</h1><p>Ceci est \
+             une petite page d'exemple qui sert à tester le traitement de bout en bout. Elle \
+             contient assez de texte français pour que la détection de la langue soit fiable.\
+             </p></article></body></html>",
+        ),
+    ]
+    .into_iter()
+    .map(|(url, html)| FixturePage {
+        url: url.to_string(),
+        html: html.to_string(),
+    })
+    .collect()
+}
+
+/// Reads every `*.html` file in `dir` as a page of `https://fixtures.example.com/<file name>`.
+pub fn read_pages(dir: &Path) -> Result<Vec<FixturePage>, anyhow::Error> {
+    let mut paths = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.retain(|path| path.extension().is_some_and(|ext| ext == "html"));
+    paths.sort();
+    paths
+        .into_iter()
+        .map(|path| {
+            let name = path.file_name().unwrap().to_string_lossy();
+            Ok(FixturePage {
+                url: format!("https://fixtures.example.com/{name}"),
+                html: fs::read_to_string(&path)?,
+            })
+        })
+        .collect()
+}
+
+/// The SHA-1 of `data` in the base32 form used by CDX digests and `WARC-Payload-Digest`.
+pub fn sha1_base32(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+    let digest = Sha1::digest(data);
+    let mut encoded = String::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for byte in digest {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    encoded
+}
+
+fn gzip_member(data: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+fn warc_response_record(url: &str, html: &str) -> Vec<u8> {
+    let payload = html.as_bytes();
+    let mut http = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=UTF-8\r\nContent-Length: {}\r\n\r\n",
+        payload.len()
+    )
+    .into_bytes();
+    http.extend_from_slice(payload);
+    let record_id = uuid::Uuid::new_v5(&uuid::Uuid::NAMESPACE_URL, url.as_bytes());
+    let mut record = format!(
+        "WARC/1.0\r\n\
+         WARC-Type: response\r\n\
+         WARC-Date: {FIXTURE_WARC_DATE}\r\n\
+         WARC-Record-ID: <urn:uuid:{record_id}>\r\n\
+         WARC-Target-URI: {url}\r\n\
+         WARC-Payload-Digest: sha1:{}\r\n\
+         WARC-Block-Digest: sha1:{}\r\n\
+         Content-Type: application/http; msgtype=response\r\n\
+         Content-Length: {}\r\n\r\n",
+        sha1_base32(payload),
+        sha1_base32(&http),
+        http.len()
+    )
+    .into_bytes();
+    record.extend_from_slice(&http);
+    record.extend_from_slice(b"\r\n\r\n");
+    record
+}
+
+/// Paths of the files written by [`generate_fixtures`].
+#[derive(Debug)]
+pub struct Fixtures {
+    pub warc_path: PathBuf,
+    pub cdx_path: PathBuf,
+    pub cluster_idx_path: PathBuf,
+    pub entries: Vec<CdxEntry>,
+}
+
+/// Writes `pages` as a tiny crawl below `output_dir`, laid out like data.commoncrawl.org:
+/// one WARC file with a gzip member per record, a CDX shard sorted by SURT with a gzip
+/// member per `lines_per_cluster` lines, and the `cluster.idx` pointing into it.
+pub fn generate_fixtures(
+    output_dir: &Path,
+    crawl: &str,
+    pages: &[FixturePage],
+    lines_per_cluster: usize,
+) -> Result<Fixtures, anyhow::Error> {
+    let warc_filename =
+        format!("crawl-data/{crawl}/segments/0000000000000.00/warc/fixtures-00000.warc.gz");
+    let warc_path = output_dir.join(&warc_filename);
+    let index_dir = output_dir.join(format!("cc-index/collections/{crawl}/indexes"));
+    fs::create_dir_all(warc_path.parent().unwrap())?;
+    fs::create_dir_all(&index_dir)?;
+
+    let mut warc = Vec::new();
+    let mut entries = Vec::new();
+    for page in pages {
+        let surt_url =
+            surt_url(&page.url).with_context(|| format!("Invalid fixture URL {}", page.url))?;
+        let member = gzip_member(&warc_response_record(&page.url, &page.html))?;
+        entries.push(CdxEntry {
+            surt_url,
+            timestamp: FIXTURE_TIMESTAMP.to_string(),
+            metadata: CdxMetadata {
+                url: page.url.clone(),
+                mime: Some("text/html".to_string()),
+                mime_detected: Some("text/html".to_string()),
+                status: 200,
+                length: member.len() as u64,
+                offset: warc.len() as u64,
+                filename: warc_filename.clone(),
+                digest: Some(sha1_base32(page.html.as_bytes())),
+                charset: Some("UTF-8".to_string()),
+                languages: Some(detect_language(&strip_tags(&page.html), None)),
+                redirect: None,
+                truncated: None,
+            },
+        });
+        warc.extend_from_slice(&member);
+    }
+    fs::write(&warc_path, warc)?;
+    entries.sort_by(|a, b| a.surt_url.cmp(&b.surt_url));
+
+    let cdx_path = index_dir.join("cdx-00000.gz");
+    let mut cdx = Vec::new();
+    let mut cluster_idx = String::new();
+    for (cluster_id, cluster) in entries.chunks(lines_per_cluster.max(1)).enumerate() {
+        let mut lines = String::new();
+        for entry in cluster {
+            lines.push_str(&format_cdx_line(entry)?);
+            lines.push('\n');
+        }
+        let member = gzip_member(lines.as_bytes())?;
+        cluster_idx.push_str(&format!(
+            "{} {}\tcdx-00000.gz\t{}\t{}\t{}\n",
+            cluster[0].surt_url,
+            cluster[0].timestamp,
+            cdx.len(),
+            member.len(),
+            cluster_id + 1
+        ));
+        cdx.extend_from_slice(&member);
+    }
+    fs::write(&cdx_path, cdx)?;
+    let cluster_idx_path = index_dir.join("cluster.idx");
+    fs::write(&cluster_idx_path, cluster_idx)?;
+
+    Ok(Fixtures {
+        warc_path,
+        cdx_path,
+        cluster_idx_path,
+        entries,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Read};
+
+    use flate2::read::MultiGzDecoder;
+
+    use crate::{
+        cdx::{parse_cdx_line, parse_cluster_idx},
+        fixtures::{generate_fixtures, sample_pages, sha1_base32},
+        warc_response::parse_warc_responses,
+    };
+
+    fn gunzip(data: &[u8]) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        MultiGzDecoder::new(data).read_to_end(&mut buffer).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn encodes_sha1_as_base32() {
+        assert_eq!(sha1_base32(b""), "3I42H3S6NNFQ2MSVX7XZKYAYSCX5QBYJ");
+    }
+
+    #[test]
+    fn generates_consistent_fixtures() {
+        let dir = std::env::temp_dir().join(format!("pipeline-fixtures-{}", std::process::id()));
+        let fixtures = generate_fixtures(&dir, "CC-MAIN-2024-30", &sample_pages(), 3).unwrap();
+        assert_eq!(fixtures.entries.len(), 4);
+
+        let cluster_idx = fs::read_to_string(&fixtures.cluster_idx_path).unwrap();
+        let clusters = cluster_idx
+            .lines()
+            .filter_map(parse_cluster_idx)
+            .collect::<Vec<_>>();
+        assert_eq!(clusters.len(), 2);
+
+        let cdx = fs::read(&fixtures.cdx_path).unwrap();
+        let warc = fs::read(&fixtures.warc_path).unwrap();
+        let mut num_entries = 0;
+        for cluster in clusters {
+            let lines = gunzip(&cdx[cluster.cdx_offset..cluster.cdx_offset + cluster.cdx_length]);
+            for line in String::from_utf8(lines).unwrap().lines() {
+                let entry = parse_cdx_line(line).unwrap();
+                let (offset, length) = (
+                    entry.metadata.offset as usize,
+                    entry.metadata.length as usize,
+                );
+                let record = gunzip(&warc[offset..offset + length]);
+                let responses = parse_warc_responses(&record).unwrap();
+                assert_eq!(
+                    responses[0].target_uri.as_deref(),
+                    Some(entry.metadata.url.as_str())
+                );
+                assert_eq!(
+                    entry.metadata.digest.unwrap(),
+                    sha1_base32(&responses[0].http_body)
+                );
+                num_entries += 1;
+            }
+        }
+        assert_eq!(num_entries, 4);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,236 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
+
+use tokio::sync::Notify;
+
+use crate::metrics::{ExportMetrics, PrometheusText};
+
+/// What [`Handoff::push`] does when the queue is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OverflowPolicy {
+    /// Wait for the consumer, so nothing is lost but the producer slows down.
+    #[default]
+    Block,
+    /// Drop the item that was about to be pushed.
+    DropNewest,
+    /// Drop the item that waited longest, for streaming where fresh data matters most.
+    DropOldest,
+}
+
+/// Counters of a [`Handoff`], exported on `/metrics`.
+pub struct HandoffStats {
+    pushed: AtomicU64,
+    dropped: AtomicU64,
+    popped: AtomicU64,
+    wait_micros: AtomicU64,
+    depth: AtomicU64,
+}
+
+/// Between CDX parsing and publishing in the batcher.
+pub static BATCHER_HANDOFF: HandoffStats = HandoffStats {
+    pushed: AtomicU64::new(0),
+    dropped: AtomicU64::new(0),
+    popped: AtomicU64::new(0),
+    wait_micros: AtomicU64::new(0),
+    depth: AtomicU64::new(0),
+};
+
+impl HandoffStats {
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl ExportMetrics for HandoffStats {
+    fn export(&self, text: &mut PrometheusText) {
+        text.counter(
+            "pipeline_handoff_pushed_total",
+            "Batches handed from parsing to publishing.",
+            self.pushed.load(Ordering::Relaxed),
+        );
+        text.counter(
+            "pipeline_handoff_dropped_total",
+            "Batches dropped because the handoff queue was full.",
+            self.dropped(),
+        );
+        text.counter(
+            "pipeline_handoff_popped_total",
+            "Batches taken out of the handoff queue for publishing.",
+            self.popped.load(Ordering::Relaxed),
+        );
+        text.counter(
+            "pipeline_handoff_wait_seconds_total",
+            "Time batches spent in the handoff queue.",
+            self.wait_micros.load(Ordering::Relaxed) as f64 / 1e6,
+        );
+        text.gauge(
+            "pipeline_handoff_depth",
+            "Batches currently waiting in the handoff queue.",
+            self.depth.load(Ordering::Relaxed),
+        );
+    }
+}
+
+struct State<T> {
+    items: VecDeque<(T, Instant)>,
+    closed: bool,
+}
+
+/// A bounded queue between one producer and one consumer task, so a slow consumer cannot
+/// make the producer buffer without limit.
+pub struct Handoff<T> {
+    state: Mutex<State<T>>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    stats: &'static HandoffStats,
+    not_empty: Notify,
+    not_full: Notify,
+}
+
+impl<T> Handoff<T> {
+    pub fn new(capacity: usize, policy: OverflowPolicy, stats: &'static HandoffStats) -> Self {
+        Self {
+            state: Mutex::new(State {
+                items: VecDeque::new(),
+                closed: false,
+            }),
+            capacity: capacity.max(1),
+            policy,
+            stats,
+            not_empty: Notify::new(),
+            not_full: Notify::new(),
+        }
+    }
+
+    /// Queues `item`, and returns the item an overflow policy dropped to keep the queue
+    /// bounded, `item` itself with [`OverflowPolicy::DropNewest`].
+    pub async fn push(&self, item: T) -> Option<T> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.items.len() < self.capacity {
+                    state.items.push_back((item, Instant::now()));
+                    break;
+                }
+                match self.policy {
+                    OverflowPolicy::Block => {}
+                    OverflowPolicy::DropNewest => {
+                        self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                        return Some(item);
+                    }
+                    OverflowPolicy::DropOldest => {
+                        let oldest = state.items.pop_front().map(|(oldest, _)| oldest);
+                        state.items.push_back((item, Instant::now()));
+                        self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                        self.stats.pushed.fetch_add(1, Ordering::Relaxed);
+                        self.not_empty.notify_one();
+                        return oldest;
+                    }
+                }
+            }
+            self.not_full.notified().await;
+        }
+        self.stats.pushed.fetch_add(1, Ordering::Relaxed);
+        self.stats.depth.fetch_add(1, Ordering::Relaxed);
+        self.not_empty.notify_one();
+        None
+    }
+
+    /// The next item, or `None` once the queue is closed and empty.
+    pub async fn pop(&self) -> Option<T> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some((item, pushed_at)) = state.items.pop_front() {
+                    self.stats.popped.fetch_add(1, Ordering::Relaxed);
+                    self.stats.depth.fetch_sub(1, Ordering::Relaxed);
+                    self.stats
+                        .wait_micros
+                        .fetch_add(pushed_at.elapsed().as_micros() as u64, Ordering::Relaxed);
+                    self.not_full.notify_one();
+                    return Some(item);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            self.not_empty.notified().await;
+        }
+    }
+
+    /// Tells the consumer that nothing more is coming.
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.not_empty.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::AtomicU64, Arc};
+
+    use crate::{
+        handoff::{Handoff, HandoffStats, OverflowPolicy},
+        metrics::prometheus_text,
+    };
+
+    fn stats() -> &'static HandoffStats {
+        Box::leak(Box::new(HandoffStats {
+            pushed: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            popped: AtomicU64::new(0),
+            wait_micros: AtomicU64::new(0),
+            depth: AtomicU64::new(0),
+        }))
+    }
+
+    #[tokio::test]
+    async fn applies_overflow_policies() {
+        for (policy, expected, expected_dropped) in [
+            (OverflowPolicy::DropNewest, vec![1, 2], vec![3, 4]),
+            (OverflowPolicy::DropOldest, vec![3, 4], vec![1, 2]),
+        ] {
+            let stats = stats();
+            let handoff = Handoff::new(2, policy, stats);
+            let mut dropped = Vec::new();
+            for i in 1..=4 {
+                dropped.extend(handoff.push(i).await);
+            }
+            handoff.close();
+            let mut items = Vec::new();
+            while let Some(item) = handoff.pop().await {
+                items.push(item);
+            }
+            assert_eq!(items, expected);
+            assert_eq!(dropped, expected_dropped);
+            assert_eq!(stats.dropped(), 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn blocks_until_consumed() {
+        let handoff = Arc::new(Handoff::new(1, OverflowPolicy::Block, stats()));
+        let producer = {
+            let handoff = handoff.clone();
+            tokio::spawn(async move {
+                for i in 0..100 {
+                    handoff.push(i).await;
+                }
+                handoff.close();
+            })
+        };
+        let mut items = Vec::new();
+        while let Some(item) = handoff.pop().await {
+            items.push(item);
+        }
+        producer.await.unwrap();
+        assert_eq!(items, (0..100).collect::<Vec<_>>());
+        assert!(prometheus_text(handoff.stats).contains("pipeline_handoff_pushed_total 100"));
+    }
+}
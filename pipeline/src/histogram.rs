@@ -0,0 +1,145 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::metrics::{ExportMetrics, PrometheusText};
+
+/// Upper bounds of the buckets of a [`SizeHistogram`], powers of four from 64 bytes to
+/// 64 MiB, which covers single CDX lines as well as whole batches and WARC records.
+pub const SIZE_BUCKETS: [u64; 11] = [
+    1 << 6,
+    1 << 8,
+    1 << 10,
+    1 << 12,
+    1 << 14,
+    1 << 16,
+    1 << 18,
+    1 << 20,
+    1 << 22,
+    1 << 24,
+    1 << 26,
+];
+
+/// A Prometheus histogram of sizes in bytes or characters with the bounds [`SIZE_BUCKETS`].
+pub struct SizeHistogram {
+    /// Observations per bucket, not cumulative, the last one for sizes above all bounds.
+    buckets: [AtomicU64; SIZE_BUCKETS.len() + 1],
+    sum: AtomicU64,
+}
+
+impl SizeHistogram {
+    pub const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; SIZE_BUCKETS.len() + 1],
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, size: u64) {
+        let bucket = SIZE_BUCKETS.partition_point(|bound| *bound < size);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(size, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().map(|b| b.load(Ordering::Relaxed)).sum()
+    }
+
+    pub fn sum(&self) -> u64 {
+        self.sum.load(Ordering::Relaxed)
+    }
+
+    /// The histogram `name` in the Prometheus text format.
+    pub fn to_prometheus(&self, name: &str, help: &str) -> String {
+        let mut text = format!("# HELP {name} {help}\n# TYPE {name} histogram\n");
+        let mut cumulative = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let le = SIZE_BUCKETS
+                .get(i)
+                .map_or("+Inf".to_string(), u64::to_string);
+            text.push_str(&format!("{name}_bucket{{le=\"{le}\"}} {cumulative}\n"));
+        }
+        text.push_str(&format!(
+            "{name}_sum {}\n{name}_count {cumulative}\n",
+            self.sum()
+        ));
+        text
+    }
+}
+
+impl Default for SizeHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Sizes of what flows through the pipeline, for tuning batch sizes, prefetch and memory
+/// limits from real numbers.
+pub struct PayloadSizes {
+    /// Bytes of each CDX line read from a shard.
+    pub cdx_entry: SizeHistogram,
+    /// Bytes of each encoded batch the batcher publishes.
+    pub batch_payload: SizeHistogram,
+    /// Compressed bytes of each WARC record a worker fetches.
+    pub warc_record: SizeHistogram,
+    /// Characters of each extracted text.
+    pub extracted_text: SizeHistogram,
+}
+
+pub static PAYLOAD_SIZES: PayloadSizes = PayloadSizes {
+    cdx_entry: SizeHistogram::new(),
+    batch_payload: SizeHistogram::new(),
+    warc_record: SizeHistogram::new(),
+    extracted_text: SizeHistogram::new(),
+};
+
+impl ExportMetrics for PayloadSizes {
+    fn export(&self, text: &mut PrometheusText) {
+        for (histogram, name, help) in [
+            (
+                &self.cdx_entry,
+                "pipeline_cdx_entry_bytes",
+                "Size of CDX lines read from shards.",
+            ),
+            (
+                &self.batch_payload,
+                "pipeline_batch_payload_bytes",
+                "Size of encoded batches published by the batcher.",
+            ),
+            (
+                &self.warc_record,
+                "pipeline_warc_record_bytes",
+                "Compressed size of WARC records fetched by workers.",
+            ),
+            (
+                &self.extracted_text,
+                "pipeline_extracted_text_chars",
+                "Length of extracted texts in characters.",
+            ),
+        ] {
+            text.histogram(name, help, histogram);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::histogram::SizeHistogram;
+
+    #[test]
+    fn buckets_are_cumulative() {
+        let histogram = SizeHistogram::new();
+        for size in [0, 64, 65, 1000, 1 << 30] {
+            histogram.observe(size);
+        }
+        assert_eq!(histogram.count(), 5);
+        assert_eq!(histogram.sum(), 64 + 65 + 1000 + (1 << 30));
+        let text = histogram.to_prometheus("sizes", "Sizes.");
+        assert!(text.contains("# TYPE sizes histogram\n"));
+        assert!(text.contains("sizes_bucket{le=\"64\"} 2\n"));
+        assert!(text.contains("sizes_bucket{le=\"256\"} 3\n"));
+        assert!(text.contains("sizes_bucket{le=\"1024\"} 4\n"));
+        assert!(text.contains("sizes_bucket{le=\"67108864\"} 4\n"));
+        assert!(text.contains("sizes_bucket{le=\"+Inf\"} 5\n"));
+        assert!(text.contains("sizes_count 5\n"));
+    }
+}
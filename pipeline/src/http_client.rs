@@ -0,0 +1,260 @@
+use std::{
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    sync::Arc,
+    time::Duration,
+};
+
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+
+use crate::{config_check::ConfigCheck, rate_limit::FetchLimits, retry::Backoff};
+
+/// Which address families to connect over.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum IpPreference {
+    /// Keep the resolver's order.
+    #[default]
+    Any,
+    /// Try IPv4 addresses first and fall back to IPv6.
+    PreferIpv4,
+    /// Try IPv6 addresses first and fall back to IPv4.
+    PreferIpv6,
+    Ipv4Only,
+    Ipv6Only,
+}
+
+impl IpPreference {
+    /// Filters and stably sorts resolved addresses. The connector races the first address
+    /// family against the other one (happy eyeballs), so the preferred family is tried
+    /// first and the other one takes over if it does not connect quickly.
+    pub fn order(&self, mut addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        match self {
+            IpPreference::Any => {}
+            IpPreference::PreferIpv4 => addrs.sort_by_key(|addr| addr.is_ipv6()),
+            IpPreference::PreferIpv6 => addrs.sort_by_key(|addr| addr.is_ipv4()),
+            IpPreference::Ipv4Only => addrs.retain(|addr| addr.is_ipv4()),
+            IpPreference::Ipv6Only => addrs.retain(|addr| addr.is_ipv6()),
+        }
+        addrs
+    }
+}
+
+/// How the HTTP client used for Common Crawl resolves names and connects.
+#[derive(Debug, Clone, clap::Args)]
+pub struct HttpOptions {
+    #[arg(long, value_enum, default_value_t = IpPreference::Any)]
+    pub ip_preference: IpPreference,
+
+    /// DNS server to query instead of the system resolver, can be given multiple times.
+    #[arg(long = "dns-server")]
+    pub dns_servers: Vec<IpAddr>,
+
+    /// Gives up on connecting after this long, split across all resolved addresses.
+    #[arg(long, default_value_t = 10)]
+    pub connect_timeout_secs: u64,
+
+    /// Wait before the first retry of a throttled or failed fetch, doubled for every
+    /// further one, with random jitter.
+    #[arg(long, default_value_t = 1000)]
+    pub retry_base_delay_ms: u64,
+
+    /// Longest wait between two attempts, also caps the server's `Retry-After`.
+    #[arg(long, default_value_t = 60)]
+    pub retry_max_delay_secs: u64,
+
+    /// Start at most this many requests per second to each host that Common Crawl data,
+    /// WARC records and CDX files, is fetched from. The index API has its own
+    /// `--requests-per-sec`.
+    #[arg(long)]
+    pub data_requests_per_sec: Option<f64>,
+
+    /// Requests that may start at once after a pause, before `--data-requests-per-sec`
+    /// spaces them out.
+    #[arg(long, default_value_t = 1, requires = "data_requests_per_sec")]
+    pub data_request_burst: u32,
+
+    /// Keep at most this many data requests open at once in this process.
+    #[arg(long)]
+    pub max_data_connections: Option<usize>,
+
+    /// Share `--data-requests-per-sec` and the index API rate between all processes
+    /// using this Redis, e.g. `redis://host:6379`, instead of each process having them
+    /// to itself.
+    #[cfg(feature = "redis")]
+    #[arg(long)]
+    pub data_rate_limit_redis_url: Option<String>,
+}
+
+impl HttpOptions {
+    pub fn backoff(&self) -> Backoff {
+        Backoff {
+            base: Duration::from_millis(self.retry_base_delay_ms),
+            max: Duration::from_secs(self.retry_max_delay_secs),
+        }
+    }
+
+    /// Adds the problems of these options to `check`.
+    pub fn check(&self, check: &mut ConfigCheck) {
+        if let Some(requests_per_sec) = self.data_requests_per_sec {
+            check.require(
+                requests_per_sec > 0.0,
+                "--data-requests-per-sec has to be more than 0",
+            );
+        }
+        check.at_least("--data-request-burst", self.data_request_burst, 1);
+        if let Some(max_data_connections) = self.max_data_connections {
+            check.at_least("--max-data-connections", max_data_connections, 1);
+        }
+    }
+
+    pub fn fetch_limits(&self) -> Result<FetchLimits, anyhow::Error> {
+        let limits = FetchLimits::new(
+            self.data_requests_per_sec,
+            self.data_request_burst,
+            self.max_data_connections,
+        );
+        #[cfg(feature = "redis")]
+        if let Some(redis_url) = self.data_rate_limit_redis_url.as_deref() {
+            return limits.with_redis(redis_url);
+        }
+        Ok(limits)
+    }
+
+    /// The limits of an index API client, which has its own rate and no connection limit
+    /// but shares the Redis of the data requests.
+    pub fn index_api_limits(&self, requests_per_sec: f64) -> Result<FetchLimits, anyhow::Error> {
+        let limits = FetchLimits::new(Some(requests_per_sec), 1, None);
+        #[cfg(feature = "redis")]
+        if let Some(redis_url) = self.data_rate_limit_redis_url.as_deref() {
+            return limits.with_redis(redis_url);
+        }
+        Ok(limits)
+    }
+}
+
+impl Default for HttpOptions {
+    fn default() -> Self {
+        Self {
+            ip_preference: IpPreference::Any,
+            dns_servers: Vec::new(),
+            connect_timeout_secs: 10,
+            retry_base_delay_ms: 1000,
+            retry_max_delay_secs: 60,
+            data_requests_per_sec: None,
+            data_request_burst: 1,
+            max_data_connections: None,
+            #[cfg(feature = "redis")]
+            data_rate_limit_redis_url: None,
+        }
+    }
+}
+
+enum Lookup {
+    System,
+    #[cfg(feature = "custom-dns")]
+    Servers(Box<hickory_resolver::TokioResolver>),
+}
+
+/// Resolves names with the system or the configured DNS servers and orders the
+/// addresses by [`IpPreference`].
+#[derive(Clone)]
+struct PreferenceResolver {
+    lookup: Arc<Lookup>,
+    preference: IpPreference,
+}
+
+impl PreferenceResolver {
+    fn new(options: &HttpOptions) -> Result<Self, anyhow::Error> {
+        let lookup = if options.dns_servers.is_empty() {
+            Lookup::System
+        } else {
+            #[cfg(feature = "custom-dns")]
+            {
+                use hickory_resolver::{
+                    config::{NameServerConfig, ResolverConfig},
+                    net::runtime::TokioRuntimeProvider,
+                    Resolver,
+                };
+                let config = ResolverConfig::from_name_servers(
+                    options
+                        .dns_servers
+                        .iter()
+                        .map(|ip| NameServerConfig::udp_and_tcp(*ip))
+                        .collect(),
+                );
+                Lookup::Servers(Box::new(
+                    Resolver::builder_with_config(config, TokioRuntimeProvider::default())
+                        .build()?,
+                ))
+            }
+            #[cfg(not(feature = "custom-dns"))]
+            anyhow::bail!("Custom DNS servers need the custom-dns feature");
+        };
+        Ok(Self {
+            lookup: Arc::new(lookup),
+            preference: options.ip_preference,
+        })
+    }
+
+    async fn resolve_addrs(&self, name: &str) -> Result<Vec<SocketAddr>, anyhow::Error> {
+        let addrs = match &*self.lookup {
+            Lookup::System => {
+                let name = name.to_string();
+                tokio::task::spawn_blocking(move || (name.as_str(), 0).to_socket_addrs())
+                    .await??
+                    .collect()
+            }
+            #[cfg(feature = "custom-dns")]
+            Lookup::Servers(resolver) => resolver
+                .lookup_ip(name)
+                .await?
+                .iter()
+                .map(|ip| SocketAddr::new(ip, 0))
+                .collect(),
+        };
+        let addrs = self.preference.order(addrs);
+        if addrs.is_empty() {
+            anyhow::bail!("No {:?} address for {}", self.preference, name);
+        }
+        Ok(addrs)
+    }
+}
+
+impl Resolve for PreferenceResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let resolver = self.clone();
+        Box::pin(async move {
+            let addrs = resolver.resolve_addrs(name.as_str()).await?;
+            tracing::debug!("Resolved {} to {:?}", name.as_str(), addrs);
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Builds the client for all requests to Common Crawl. Reuse it, so connections are kept
+/// alive across requests. The first client of a process also installs the
+/// [`FetchLimits`] of `options` for all data requests.
+pub fn build_http_client(options: &HttpOptions) -> Result<reqwest::Client, anyhow::Error> {
+    options.fetch_limits()?.install();
+    Ok(reqwest::Client::builder()
+        .dns_resolver(Arc::new(PreferenceResolver::new(options)?))
+        .connect_timeout(Duration::from_secs(options.connect_timeout_secs))
+        .build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use crate::http_client::IpPreference;
+
+    #[test]
+    fn orders_addresses_by_preference() {
+        let v6: SocketAddr = "[2001:db8::1]:0".parse().unwrap();
+        let v4: SocketAddr = "192.0.2.1:0".parse().unwrap();
+        assert_eq!(IpPreference::Any.order(vec![v6, v4]), vec![v6, v4]);
+        assert_eq!(IpPreference::PreferIpv4.order(vec![v6, v4]), vec![v4, v6]);
+        assert_eq!(IpPreference::PreferIpv6.order(vec![v4, v6]), vec![v6, v4]);
+        assert_eq!(IpPreference::Ipv4Only.order(vec![v6, v4]), vec![v4]);
+        assert_eq!(IpPreference::Ipv6Only.order(vec![v6, v4]), vec![v6]);
+    }
+}
@@ -0,0 +1,310 @@
+use serde::Deserialize;
+
+use crate::{
+    cdx::{CdxEntry, CdxMetadata},
+    http_client::{build_http_client, HttpOptions},
+    rate_limit::FetchLimits,
+    retry::{retry_after, Backoff},
+    traffic::{TrafficKind, TRAFFIC},
+};
+
+pub const CC_INDEX_API_URL: &str = "https://index.commoncrawl.org";
+
+/// One line of the index API's `output=json` response.
+#[derive(Debug, Deserialize)]
+struct IndexApiRecord {
+    urlkey: String,
+    timestamp: String,
+    #[serde(flatten)]
+    metadata: CdxMetadata,
+}
+
+impl From<IndexApiRecord> for CdxEntry {
+    fn from(record: IndexApiRecord) -> Self {
+        CdxEntry {
+            surt_url: record.urlkey,
+            timestamp: record.timestamp,
+            metadata: record.metadata,
+        }
+    }
+}
+
+/// How hard we may hit the index API, which throttles aggressively.
+#[derive(Debug, Clone)]
+pub struct Politeness {
+    pub requests_per_sec: f64,
+    /// How many pages of a paginated query are fetched at the same time.
+    pub page_concurrency: usize,
+    pub max_attempts: usize,
+}
+
+impl Default for Politeness {
+    fn default() -> Self {
+        Self {
+            requests_per_sec: 1.0,
+            page_concurrency: 1,
+            max_attempts: 5,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct NumPages {
+    pages: usize,
+}
+
+/// One crawl of the index API's `collinfo.json`.
+#[derive(Debug, Deserialize)]
+struct CollectionInfo {
+    id: String,
+}
+
+/// Client for the Common Crawl index API that rate limits per endpoint and retries
+/// throttled requests.
+pub struct IndexApiClient {
+    client: reqwest::Client,
+    api_url: String,
+    politeness: Politeness,
+    backoff: Backoff,
+    limits: FetchLimits,
+}
+
+impl IndexApiClient {
+    /// Sends requests with the client and the backoff of `http`, see
+    /// [`crate::http_client`], at `politeness.requests_per_sec` per host.
+    pub fn new(
+        api_url: &str,
+        politeness: Politeness,
+        http: &HttpOptions,
+    ) -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            client: build_http_client(http)?,
+            api_url: api_url.trim_end_matches('/').to_string(),
+            limits: http.index_api_limits(politeness.requests_per_sec)?,
+            politeness,
+            backoff: http.backoff(),
+        })
+    }
+
+    /// Sends a query to `<api-url>/<crawl>-index`, retrying on throttling and server errors.
+    /// Returns `None` on 404, which the index API uses for "no captures".
+    async fn get(
+        &self,
+        crawl: &str,
+        query: &[(&str, &str)],
+    ) -> Result<Option<String>, anyhow::Error> {
+        let url = format!("{}/{crawl}-index", self.api_url);
+        let mut attempt = 1;
+        loop {
+            let result = self
+                .limits
+                .send(self.client.get(&url).query(query))
+                .await
+                .map(|(res, _)| res);
+            let mut server_retry_after = None;
+            let retryable = match result {
+                Ok(res) if res.status() == reqwest::StatusCode::NOT_FOUND => {
+                    TRAFFIC.record_request(TrafficKind::IndexApi, 0);
+                    return Ok(None);
+                }
+                Ok(res) if res.status().is_success() => {
+                    let text = res.text().await?;
+                    TRAFFIC.record_request(TrafficKind::IndexApi, text.len() as u64);
+                    return Ok(Some(text));
+                }
+                Ok(res) => {
+                    TRAFFIC.record_request(TrafficKind::IndexApi, 0);
+                    server_retry_after = retry_after(&res);
+                    let status = res.status();
+                    if !(status.is_server_error()
+                        || status == reqwest::StatusCode::TOO_MANY_REQUESTS)
+                    {
+                        anyhow::bail!("Failed to query index API at {}: {}", url, status);
+                    }
+                    anyhow::anyhow!("Index API at {} answered {}", url, status)
+                }
+                Err(e) => e.into(),
+            };
+            if attempt >= self.politeness.max_attempts {
+                return Err(retryable);
+            }
+            let delay = self.backoff.delay(attempt, server_retry_after);
+            tracing::warn!(err.msg = %retryable, "Retrying index API query in {:?} (attempt {} of {})", delay, attempt, self.politeness.max_attempts);
+            TRAFFIC.record_retry(TrafficKind::IndexApi);
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Returns all captures of `url` within `crawl`.
+    pub async fn lookup_captures(
+        &self,
+        crawl: &str,
+        url: &str,
+    ) -> Result<Vec<CdxEntry>, anyhow::Error> {
+        match self.get(crawl, &[("url", url), ("output", "json")]).await? {
+            Some(body) => parse_index_api_response(&body),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// The IDs of all crawls the index API serves, e.g. `CC-MAIN-2024-30`.
+    pub async fn crawls(&self) -> Result<Vec<String>, anyhow::Error> {
+        let url = format!("{}/collinfo.json", self.api_url);
+        let (res, _) = self.limits.send(self.client.get(&url)).await?;
+        let body = res.error_for_status()?.text().await?;
+        TRAFFIC.record_request(TrafficKind::IndexApi, body.len() as u64);
+        Ok(serde_json::from_str::<Vec<CollectionInfo>>(&body)?
+            .into_iter()
+            .map(|info| info.id)
+            .collect())
+    }
+
+    /// All captures of `urls` in each of `crawls`, oldest first, e.g. to build a dataset
+    /// of how a set of pages changed over time.
+    pub async fn captures_across_crawls(
+        &self,
+        crawls: &[String],
+        urls: &[String],
+    ) -> Result<Vec<CdxEntry>, anyhow::Error> {
+        let mut captures = Vec::new();
+        for url in urls {
+            for crawl in crawls {
+                captures.extend(self.lookup_captures(crawl, url).await?);
+            }
+        }
+        captures
+            .sort_by(|a, b| (&a.timestamp, &a.metadata.url).cmp(&(&b.timestamp, &b.metadata.url)));
+        Ok(captures)
+    }
+
+    pub async fn num_pages(&self, crawl: &str, url_pattern: &str) -> Result<usize, anyhow::Error> {
+        match self
+            .get(crawl, &[("url", url_pattern), ("showNumPages", "true")])
+            .await?
+        {
+            Some(body) => Ok(serde_json::from_str::<NumPages>(&body)?.pages),
+            None => Ok(0),
+        }
+    }
+
+    /// Runs a paginated query for `url_pattern` (e.g. `*.example.com`), starting at
+    /// `first_page`. Pages are fetched `page_concurrency` at a time and handed to
+    /// `on_page` in order together with the next page to fetch, so the caller can
+    /// persist it and resume from there if the query fails later on.
+    pub async fn query<F>(
+        &self,
+        crawl: &str,
+        url_pattern: &str,
+        first_page: usize,
+        mut on_page: F,
+    ) -> Result<(), anyhow::Error>
+    where
+        F: FnMut(Vec<CdxEntry>, usize) -> Result<(), anyhow::Error>,
+    {
+        let num_pages = self.num_pages(crawl, url_pattern).await?;
+        tracing::info!(
+            "Query for {} in {} has {} pages",
+            url_pattern,
+            crawl,
+            num_pages
+        );
+        let mut page = first_page;
+        while page < num_pages {
+            let window = page..(page + self.politeness.page_concurrency.max(1)).min(num_pages);
+            let pages = futures_util::future::try_join_all(window.clone().map(|page| {
+                let page = page.to_string();
+                async move {
+                    let query = [("url", url_pattern), ("output", "json"), ("page", &page)];
+                    match self.get(crawl, &query).await? {
+                        Some(body) => parse_index_api_response(&body),
+                        None => Ok(Vec::new()),
+                    }
+                }
+            }))
+            .await?;
+            for (entries, page) in pages.into_iter().zip(window) {
+                on_page(entries, page + 1)?;
+            }
+            page = (page + self.politeness.page_concurrency.max(1)).min(num_pages);
+        }
+        Ok(())
+    }
+}
+
+/// The crawls from `from` to `to`, both included, in chronological order. Crawl IDs
+/// sort by year and week.
+pub fn crawl_range(crawls: &[String], from: &str, to: &str) -> Vec<String> {
+    let mut range = crawls
+        .iter()
+        .filter(|crawl| from <= crawl.as_str() && crawl.as_str() <= to)
+        .cloned()
+        .collect::<Vec<_>>();
+    range.sort();
+    range
+}
+
+pub fn parse_index_api_response(body: &str) -> Result<Vec<CdxEntry>, anyhow::Error> {
+    body.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| Ok(serde_json::from_str::<IndexApiRecord>(line)?.into()))
+        .collect()
+}
+
+/// Follows the `redirect` field of a 3xx capture through the index API until a capture
+/// with a non-redirect status is found, giving up after `max_hops` redirects.
+pub async fn resolve_redirect(
+    client: &IndexApiClient,
+    crawl: &str,
+    entry: &CdxEntry,
+    max_hops: usize,
+) -> Result<Option<CdxEntry>, anyhow::Error> {
+    let mut target = entry.metadata.redirect.clone();
+    for _ in 0..max_hops {
+        let Some(url) = target.take() else {
+            return Ok(None);
+        };
+        let (redirects, others): (Vec<_>, Vec<_>) = client
+            .lookup_captures(crawl, &url)
+            .await?
+            .into_iter()
+            .partition(|c| (300..400).contains(&c.metadata.status));
+        if let Some(capture) = others.into_iter().next() {
+            return Ok(Some(capture));
+        }
+        target = redirects.into_iter().find_map(|c| c.metadata.redirect);
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::index_api::{crawl_range, parse_index_api_response};
+
+    #[test]
+    fn selects_crawl_ranges() {
+        let crawls = [
+            "CC-MAIN-2024-30",
+            "CC-MAIN-2024-10",
+            "CC-MAIN-2023-50",
+            "CC-MAIN-2024-26",
+        ]
+        .map(str::to_string);
+        assert_eq!(
+            crawl_range(&crawls, "CC-MAIN-2024-10", "CC-MAIN-2024-26"),
+            vec!["CC-MAIN-2024-10", "CC-MAIN-2024-26"]
+        );
+        assert!(crawl_range(&crawls, "CC-MAIN-2025-05", "CC-MAIN-2025-13").is_empty());
+    }
+
+    #[test]
+    fn can_parse_index_api_response() {
+        let body = r#"{"urlkey": "com,example)/", "timestamp": "20240722120756", "url": "https://example.com/", "mime": "text/html", "mime-detected": "text/html", "status": "200", "digest": "5JOQMMSNM6N7UCLGGYXDSPSB3FYAQS2C", "length": "16650", "offset": "64016172", "filename": "crawl-data/CC-MAIN-2024-30/segments/1720763518115.82/warc/CC-MAIN-20240723194208-20240723224208-00279.warc.gz", "languages": "eng"}
+"#;
+        let entries = parse_index_api_response(body).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].surt_url, "com,example)/");
+        assert_eq!(entries[0].metadata.status, 200);
+        assert_eq!(entries[0].metadata.offset, 64016172);
+    }
+}
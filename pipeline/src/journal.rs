@@ -0,0 +1,132 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::state_file::{read_state_file, write_state_file, StateFile};
+
+/// The batch a worker is currently processing, and how long each output file it
+/// touches was before the batch started.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct JournalEntry {
+    pub batch_id: String,
+    pub files: BTreeMap<PathBuf, u64>,
+}
+
+impl StateFile for JournalEntry {
+    const KIND: &'static [u8; 4] = b"JRNL";
+    const VERSION: u32 = 1;
+
+    /// Version 0 could also be `null`, for no batch.
+    fn migrate(_: u32, payload: serde_json::Value) -> Result<Self, anyhow::Error> {
+        Ok(serde_json::from_value::<Option<Self>>(payload)?.unwrap_or_default())
+    }
+}
+
+/// On-disk journal of the in-flight batch. If the worker crashes, the journal is still
+/// there on the next start and [`Journal::recover`] truncates all output files back to
+/// their length before the batch, so the redelivered batch does not leave half-written
+/// duplicates behind.
+pub struct Journal {
+    path: PathBuf,
+    entry: Option<JournalEntry>,
+}
+
+impl Journal {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            entry: None,
+        }
+    }
+
+    pub fn begin(&mut self, batch_id: &str) -> Result<(), anyhow::Error> {
+        self.entry = Some(JournalEntry {
+            batch_id: batch_id.to_string(),
+            files: BTreeMap::new(),
+        });
+        self.persist()
+    }
+
+    /// Remembers the current length of `file`, unless it was already recorded in this batch.
+    pub fn record_file(&mut self, file: &Path) -> Result<(), anyhow::Error> {
+        let Some(entry) = self.entry.as_mut() else {
+            return Ok(());
+        };
+        if entry.files.contains_key(file) {
+            return Ok(());
+        }
+        let len = fs::metadata(file).map(|m| m.len()).unwrap_or(0);
+        entry.files.insert(file.to_path_buf(), len);
+        self.persist()
+    }
+
+    /// Marks the batch as fully written. Output files have to be flushed before.
+    pub fn commit(&mut self) -> Result<(), anyhow::Error> {
+        self.entry = None;
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn persist(&self) -> Result<(), anyhow::Error> {
+        match self.entry.as_ref() {
+            Some(entry) => write_state_file(&self.path, entry, true),
+            None => Ok(()),
+        }
+    }
+
+    /// Rolls back the outputs of a batch left over from a crash, returning its journal entry.
+    pub fn recover(path: &Path) -> Result<Option<JournalEntry>, anyhow::Error> {
+        let Some(entry) = read_state_file::<JournalEntry>(path)? else {
+            return Ok(None);
+        };
+        for (file, len) in &entry.files {
+            if !file.exists() {
+                continue;
+            }
+            tracing::warn!(
+                "Truncating {} to {} bytes, left over from batch {}",
+                file.display(),
+                len,
+                entry.batch_id
+            );
+            OpenOptions::new().write(true).open(file)?.set_len(*len)?;
+        }
+        fs::remove_file(path)?;
+        Ok(Some(entry))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::journal::Journal;
+
+    #[test]
+    fn recovers_partial_outputs() {
+        let dir = std::env::temp_dir().join(format!("pipeline-journal-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let output = dir.join("eng.jsonl");
+        let journal_path = dir.join("journal.json");
+        fs::write(&output, "complete\n").unwrap();
+
+        let mut journal = Journal::new(&journal_path);
+        journal.begin("batch-1").unwrap();
+        journal.record_file(&output).unwrap();
+        fs::write(&output, "complete\npartial").unwrap();
+        drop(journal);
+
+        let entry = Journal::recover(&journal_path).unwrap().unwrap();
+        assert_eq!(entry.batch_id, "batch-1");
+        assert_eq!(fs::read_to_string(&output).unwrap(), "complete\n");
+        assert!(!journal_path.exists());
+        assert!(Journal::recover(&journal_path).unwrap().is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
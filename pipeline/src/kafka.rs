@@ -0,0 +1,437 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use futures_util::future::BoxFuture;
+use rdkafka::{
+    consumer::{BaseConsumer, Consumer, StreamConsumer},
+    message::{Header, Headers, OwnedHeaders},
+    producer::{FutureProducer, FutureRecord},
+    ClientConfig, Message,
+};
+
+use crate::{
+    batch::PROTOBUF_CONTENT_TYPE,
+    queue::{QueueConsumer, QueueDelivery, QueueMessage, QueueProducer},
+    rate_limit::send_limited,
+};
+
+/// Kafka has no content type, it travels as a header of this name.
+const CONTENT_TYPE_HEADER: &str = "content-type";
+/// How long a publish may wait in the producer's queue, including its retries.
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(60);
+/// The schema registered for protobuf batches.
+const PIPELINE_PROTO: &str = include_str!("../proto/pipeline.proto");
+/// The message indexes of `Batch` in [`PIPELINE_PROTO`], the third message of the file,
+/// as zigzag varints: one index, 2.
+const BATCH_MESSAGE_INDEXES: &[u8] = &[2, 4];
+
+/// A Confluent Schema Registry. Protobuf batches are published in its wire format, so
+/// consumers outside the pipeline can decode them with the registered schema.
+#[derive(Debug)]
+pub struct SchemaRegistry {
+    url: String,
+    http_client: reqwest::Client,
+    ids: tokio::sync::Mutex<HashMap<String, u32>>,
+}
+
+#[derive(serde::Deserialize)]
+struct RegisteredSchema {
+    id: u32,
+}
+
+impl SchemaRegistry {
+    pub fn new(url: &str, http_client: reqwest::Client) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            http_client,
+            ids: tokio::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The ID of `proto/pipeline.proto` under the subject `<topic>-value`, registered on
+    /// the first publish to `topic`. Registering the same schema again returns its ID.
+    async fn schema_id(&self, topic: &str) -> Result<u32, anyhow::Error> {
+        let mut ids = self.ids.lock().await;
+        if let Some(id) = ids.get(topic) {
+            return Ok(*id);
+        }
+        let url = format!("{}/subjects/{topic}-value/versions", self.url);
+        let request = self
+            .http_client
+            .post(&url)
+            .header("content-type", "application/vnd.schemaregistry.v1+json")
+            .body(serde_json::to_vec(
+                &serde_json::json!({ "schemaType": "PROTOBUF", "schema": PIPELINE_PROTO }),
+            )?);
+        let (response, _) = send_limited(request).await?;
+        let body = response
+            .error_for_status()
+            .with_context(|| format!("Failed to register the batch schema at {url}"))?
+            .bytes()
+            .await?;
+        let id = serde_json::from_slice::<RegisteredSchema>(&body)?.id;
+        tracing::info!("Registered the batch schema of {} as {}", topic, id);
+        ids.insert(topic.to_string(), id);
+        Ok(id)
+    }
+}
+
+/// `payload`, a protobuf `Batch`, in the wire format of the schema registry: a zero magic
+/// byte, the schema ID and the message indexes before the message.
+pub fn frame_batch(schema_id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(5 + BATCH_MESSAGE_INDEXES.len() + payload.len());
+    framed.push(0);
+    framed.extend_from_slice(&schema_id.to_be_bytes());
+    framed.extend_from_slice(BATCH_MESSAGE_INDEXES);
+    framed.extend_from_slice(payload);
+    framed
+}
+
+/// The protobuf message of a `payload` in the wire format of the schema registry, or
+/// `payload` itself if it is a bare message. These never start with a zero byte, which
+/// is no valid protobuf tag.
+pub fn unframe_batch(payload: &[u8]) -> Result<&[u8], anyhow::Error> {
+    if payload.first() != Some(&0) {
+        return Ok(payload);
+    }
+    let mut position = 5;
+    let mut read_varint = || {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = *payload
+                .get(position)
+                .context("Schema registry framing breaks off")?;
+            position += 1;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok::<_, anyhow::Error>(value);
+            }
+        }
+        anyhow::bail!("Schema registry framing has a varint that is too long")
+    };
+    // Zigzag encoded, a count of 0 stands for the first message alone.
+    let count = read_varint()? >> 1;
+    for _ in 0..count {
+        read_varint()?;
+    }
+    Ok(&payload[position..])
+}
+
+/// The Kafka headers of `message`, its content type among them.
+pub fn kafka_headers(message: &QueueMessage) -> OwnedHeaders {
+    let content_type = message
+        .content_type
+        .as_deref()
+        .map(|content_type| (CONTENT_TYPE_HEADER, content_type));
+    content_type
+        .into_iter()
+        .chain(
+            message
+                .headers
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str())),
+        )
+        .fold(OwnedHeaders::new(), |headers, (key, value)| {
+            headers.insert(Header {
+                key,
+                value: Some(value),
+            })
+        })
+}
+
+/// The message of `payload` and its Kafka headers. Headers without a value or with one
+/// that is not UTF-8 are left out.
+pub fn queue_message(payload: Vec<u8>, headers: Option<&impl Headers>) -> QueueMessage {
+    let mut message = QueueMessage::new(payload);
+    for header in headers.into_iter().flat_map(|headers| headers.iter()) {
+        let Some(value) = header
+            .value
+            .and_then(|value| std::str::from_utf8(value).ok())
+        else {
+            continue;
+        };
+        if header.key == CONTENT_TYPE_HEADER {
+            message.content_type = Some(value.to_string());
+        } else {
+            message
+                .headers
+                .push((header.key.to_string(), value.to_string()));
+        }
+    }
+    message
+}
+
+/// Producers and consumers of one Kafka cluster. Queue names are topic names.
+#[derive(Debug, Clone)]
+pub struct KafkaBroker {
+    brokers: String,
+    group_id: String,
+    schema_registry: Option<Arc<SchemaRegistry>>,
+}
+
+impl KafkaBroker {
+    pub fn new(brokers: &str, group_id: &str) -> Self {
+        Self {
+            brokers: brokers.to_string(),
+            group_id: group_id.to_string(),
+            schema_registry: None,
+        }
+    }
+
+    /// Publishes protobuf batches in the wire format of `schema_registry`. Consumers read
+    /// batches with and without it.
+    pub fn with_schema_registry(mut self, schema_registry: SchemaRegistry) -> Self {
+        self.schema_registry = Some(Arc::new(schema_registry));
+        self
+    }
+
+    /// Waits for all in-sync replicas, so a published batch survives the loss of the
+    /// partition leader like a confirmed publish to RabbitMQ does.
+    pub fn producer(&self) -> Result<KafkaProducer, anyhow::Error> {
+        let producer = ClientConfig::new()
+            .set("bootstrap.servers", &self.brokers)
+            .set("acks", "all")
+            .set("enable.idempotence", "true")
+            .create()
+            .context("Failed to create the Kafka producer")?;
+        Ok(KafkaProducer {
+            producer,
+            schema_registry: self.schema_registry.clone(),
+        })
+    }
+
+    /// Whether the cluster answers and has each of `topics`, for `pipeline doctor`. Blocks
+    /// for up to 10 seconds.
+    pub fn check_topics(&self, topics: &[&str]) -> Result<String, anyhow::Error> {
+        let consumer: BaseConsumer = ClientConfig::new()
+            .set("bootstrap.servers", &self.brokers)
+            .create()
+            .context("Failed to create the Kafka consumer")?;
+        let metadata = consumer
+            .fetch_metadata(None, Duration::from_secs(10))
+            .with_context(|| format!("Cannot reach {}", self.brokers))?;
+        for topic in topics {
+            anyhow::ensure!(
+                metadata
+                    .topics()
+                    .iter()
+                    .any(|known| known.name() == *topic && known.error().is_none()),
+                "Topic {topic} does not exist"
+            );
+        }
+        Ok(format!(
+            "{} brokers, has {}",
+            metadata.brokers().len(),
+            topics.join(", ")
+        ))
+    }
+
+    /// Subscribes to `topic` in the consumer group. Offsets are only committed for
+    /// settled messages, so after a crash the unsettled ones are delivered again.
+    pub fn consumer(&self, topic: &str) -> Result<KafkaConsumer, anyhow::Error> {
+        let consumer: StreamConsumer = ClientConfig::new()
+            .set("bootstrap.servers", &self.brokers)
+            .set("group.id", &self.group_id)
+            .set("enable.auto.commit", "true")
+            .set("enable.auto.offset.store", "false")
+            .set("auto.offset.reset", "earliest")
+            .create()
+            .context("Failed to create the Kafka consumer")?;
+        consumer
+            .subscribe(&[topic])
+            .with_context(|| format!("Failed to subscribe to {topic}"))?;
+        Ok(KafkaConsumer {
+            consumer: Arc::new(consumer),
+            producer: Arc::new(self.producer()?),
+        })
+    }
+}
+
+pub struct KafkaProducer {
+    producer: FutureProducer,
+    schema_registry: Option<Arc<SchemaRegistry>>,
+}
+
+impl QueueProducer for KafkaProducer {
+    fn publish<'a>(
+        &'a self,
+        queue: &'a str,
+        message: &'a QueueMessage,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let framed;
+            let payload = match self.schema_registry.as_ref() {
+                Some(registry)
+                    if message.content_type.as_deref() == Some(PROTOBUF_CONTENT_TYPE) =>
+                {
+                    framed = frame_batch(registry.schema_id(queue).await?, &message.payload);
+                    &framed
+                }
+                _ => &message.payload,
+            };
+            let record = FutureRecord::<(), _>::to(queue)
+                .payload(payload)
+                .headers(kafka_headers(message));
+            self.producer
+                .send(record, PUBLISH_TIMEOUT)
+                .await
+                .map_err(|(e, _)| e)
+                .with_context(|| format!("Failed to publish to {queue}"))?;
+            Ok(())
+        })
+    }
+}
+
+pub struct KafkaConsumer {
+    consumer: Arc<StreamConsumer>,
+    /// Puts requeued messages back at the end of the topic.
+    producer: Arc<KafkaProducer>,
+}
+
+struct KafkaDelivery {
+    consumer: Arc<StreamConsumer>,
+    producer: Arc<KafkaProducer>,
+    topic: String,
+    partition: i32,
+    offset: i64,
+    message: QueueMessage,
+}
+
+impl QueueConsumer for KafkaConsumer {
+    fn next(&mut self) -> BoxFuture<'_, Option<Result<Box<dyn QueueDelivery>, anyhow::Error>>> {
+        Box::pin(async {
+            let received = match self.consumer.recv().await {
+                Ok(received) => received,
+                Err(e) => return Some(Err(e.into())),
+            };
+            let mut message = queue_message(
+                received.payload().unwrap_or_default().to_vec(),
+                received.headers(),
+            );
+            if message.content_type.as_deref() == Some(PROTOBUF_CONTENT_TYPE) {
+                match unframe_batch(&message.payload) {
+                    Ok(payload) => message.payload = payload.to_vec(),
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+            Some(Ok(Box::new(KafkaDelivery {
+                consumer: self.consumer.clone(),
+                producer: self.producer.clone(),
+                topic: received.topic().to_string(),
+                partition: received.partition(),
+                offset: received.offset(),
+                message,
+            }) as Box<dyn QueueDelivery>))
+        })
+    }
+}
+
+impl KafkaDelivery {
+    /// Lets the next commit of the group move past this message.
+    fn store_offset(&self) -> Result<(), anyhow::Error> {
+        self.consumer
+            .store_offset(&self.topic, self.partition, self.offset)
+            .context("Failed to store the Kafka offset")
+    }
+}
+
+impl QueueDelivery for KafkaDelivery {
+    fn message(&self) -> &QueueMessage {
+        &self.message
+    }
+
+    /// Offsets are committed in the background, so any message may have been received
+    /// before a rebalance or crash.
+    fn redelivered(&self) -> bool {
+        true
+    }
+
+    fn ack(self: Box<Self>) -> BoxFuture<'static, Result<(), anyhow::Error>> {
+        Box::pin(async move { self.store_offset() })
+    }
+
+    /// Kafka cannot put a message back, so it is published to the topic again.
+    fn requeue(self: Box<Self>) -> BoxFuture<'static, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            self.producer.publish(&self.topic, &self.message).await?;
+            self.store_offset()
+        })
+    }
+
+    fn reject(self: Box<Self>) -> BoxFuture<'static, Result<(), anyhow::Error>> {
+        Box::pin(async move { self.store_offset() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        kafka::{frame_batch, kafka_headers, queue_message, unframe_batch, SchemaRegistry},
+        queue::QueueMessage,
+    };
+
+    #[test]
+    fn carries_content_type_in_headers() {
+        let message = QueueMessage::new(b"[]".to_vec())
+            .with_content_type("application/json")
+            .with_headers(vec![("crawl".to_string(), "CC-MAIN-2024-30".to_string())]);
+        let headers = kafka_headers(&message);
+        assert_eq!(queue_message(b"[]".to_vec(), Some(&headers)), message);
+        assert_eq!(
+            queue_message(Vec::new(), None::<&rdkafka::message::OwnedHeaders>),
+            QueueMessage::default()
+        );
+    }
+
+    #[test]
+    fn frames_batches_for_the_schema_registry() {
+        let payload = b"\x0a\x02\x0a\x00";
+        let framed = frame_batch(258, payload);
+        assert_eq!(&framed[..7], &[0, 0, 0, 1, 2, 2, 4]);
+        assert_eq!(unframe_batch(&framed).unwrap(), payload);
+        assert_eq!(unframe_batch(payload).unwrap(), payload);
+        assert_eq!(unframe_batch(&[0, 0, 0, 0, 1, 0, 8]).unwrap(), &[8]);
+        assert!(unframe_batch(&[0, 0, 0, 0, 1, 2]).is_err());
+    }
+
+    #[tokio::test]
+    async fn registers_the_batch_schema_once_per_topic() {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        tokio::spawn({
+            let requests = requests.clone();
+            async move {
+                loop {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    let mut request = Vec::new();
+                    let mut buf = [0; 4096];
+                    while !request.ends_with(b"}") {
+                        let n = stream.read(&mut buf).await.unwrap();
+                        request.extend_from_slice(&buf[..n]);
+                    }
+                    assert!(request.starts_with(b"POST /subjects/batches-value/versions"));
+                    requests.fetch_add(1, Ordering::SeqCst);
+                    stream
+                        .write_all(
+                            b"HTTP/1.1 200 OK\r\ncontent-length: 8\r\n\
+                              connection: close\r\n\r\n{\"id\":7}",
+                        )
+                        .await
+                        .unwrap();
+                }
+            }
+        });
+        let registry = SchemaRegistry::new(&url, reqwest::Client::new());
+        assert_eq!(registry.schema_id("batches").await.unwrap(), 7);
+        assert_eq!(registry.schema_id("batches").await.unwrap(), 7);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+}
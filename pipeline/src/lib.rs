@@ -1,4 +1,91 @@
+pub mod ab;
+pub mod aggregate;
+pub mod batch;
+pub mod budget;
 pub mod cdx;
+pub mod checkpoint;
+pub mod compare;
+pub mod compliance;
+pub mod compression;
+pub mod config_check;
+#[cfg(feature = "grpc")]
+pub mod control;
+pub mod dashboard;
+pub mod dedup;
+pub mod dedup_snapshot;
+pub mod diff;
+#[cfg(feature = "run-db")]
+pub mod digest_dedup;
+#[cfg(feature = "rabbitmq")]
+pub mod dlq;
+pub mod docs;
+pub mod doctor;
+pub mod endpoints;
+pub mod error_code;
+pub mod estimate;
+pub mod extractor;
+#[cfg(feature = "rabbitmq")]
+pub mod fanout;
+pub mod feedback;
+pub mod filter;
+pub mod fixtures;
+pub mod handoff;
+pub mod histogram;
+pub mod http_client;
+pub mod index_api;
+pub mod journal;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+pub mod loadtest;
+#[cfg(feature = "extraction")]
+pub mod media;
+pub mod metrics;
+pub mod output;
+#[cfg(feature = "parquet")]
+pub mod parquet_output;
+#[cfg(feature = "postgres")]
+pub mod postgres_store;
+#[cfg(feature = "protobuf")]
+pub mod proto;
+pub mod provenance;
+pub mod quality;
+pub mod queue;
+#[cfg(feature = "run-db")]
+pub mod quota;
+#[cfg(feature = "rabbitmq")]
 pub mod rabbitmq;
+pub mod range_cache;
+pub mod rate_limit;
+pub mod redact;
+#[cfg(feature = "redis")]
+pub mod redis_store;
+pub mod resources;
+pub mod retry;
+pub mod robots;
+#[cfg(feature = "run-db")]
+pub mod run_db;
+#[cfg(feature = "s3")]
+pub mod s3;
+pub mod sample;
+pub mod scorer;
+#[cfg(any(feature = "s3", feature = "sqs"))]
+pub mod sigv4;
+pub mod sniff;
+pub mod spool;
+#[cfg(feature = "sqs")]
+pub mod sqs;
+pub mod stage_timings;
+pub mod state_file;
+pub mod state_store;
+#[cfg(feature = "extraction")]
+pub mod structured;
 pub mod tracing_and_metrics;
+pub mod traffic;
+#[cfg(feature = "trafilatura")]
 pub mod trafilatura;
+pub mod tunables;
+pub mod url_score;
+pub mod warc_response;
+pub mod warm_start;
+#[cfg(feature = "extraction")]
+pub mod web_graph;
@@ -0,0 +1,426 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    batch::BatchEncoding,
+    cdx::CdxEntry,
+    queue::{QueueConsumer, QueueMessage, QueueProducer},
+};
+
+/// Header of a synthetic batch naming the queue its worker reports the batch to.
+pub const REPLY_TO_HEADER: &str = "loadtest-reply-to";
+/// Header of a synthetic batch and its receipt with the number of the batch.
+pub const BATCH_NUMBER_HEADER: &str = "loadtest-batch";
+
+/// What a worker sends back for a processed synthetic batch.
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct Receipt {
+    pub worker: String,
+}
+
+/// The queue and message of the receipt for `batch`, if it came from `pipeline loadtest`.
+pub fn receipt(batch: &QueueMessage, worker: &str) -> Option<(String, QueueMessage)> {
+    let reply_to = batch.header(REPLY_TO_HEADER)?;
+    let number = batch.header(BATCH_NUMBER_HEADER)?;
+    let receipt = Receipt {
+        worker: worker.to_string(),
+    };
+    let message = QueueMessage::new(serde_json::to_vec(&receipt).unwrap())
+        .with_content_type("application/json")
+        .with_headers(vec![(BATCH_NUMBER_HEADER.to_string(), number.to_string())]);
+    Some((reply_to.to_string(), message))
+}
+
+/// How many batch numbers have a capture time of their own, see [`synthetic_timestamp`].
+pub const NUM_BATCH_NUMBERS: u64 = 28 * 86_400;
+
+/// A capture time in July 2024 for each batch number, so no two batches have the same
+/// payload, which the workers would take for redeliveries. Repeats after
+/// [`NUM_BATCH_NUMBERS`].
+fn synthetic_timestamp(batch: u64) -> String {
+    let batch = batch % NUM_BATCH_NUMBERS;
+    let seconds = batch % 86_400;
+    format!(
+        "202407{:02}{:02}{:02}{:02}",
+        1 + batch / 86_400,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// `num_batches` batches of `batch_size` entries taken round robin from `entries`, e.g.
+/// those of [`crate::fixtures::generate_fixtures`], numbered from `first_batch`. Each one
+/// carries its number and, if given, the queue for its receipt.
+pub fn synthetic_batches(
+    entries: &[CdxEntry],
+    first_batch: u64,
+    num_batches: usize,
+    batch_size: usize,
+    reply_to: Option<&str>,
+) -> Result<Vec<QueueMessage>, anyhow::Error> {
+    anyhow::ensure!(
+        !entries.is_empty(),
+        "There are no entries to build batches of"
+    );
+    let mut entries = entries.iter().cycle();
+    let mut batches = Vec::with_capacity(num_batches);
+    for number in first_batch..first_batch + num_batches as u64 {
+        let timestamp = synthetic_timestamp(number);
+        let batch = entries
+            .by_ref()
+            .take(batch_size)
+            .map(|entry| CdxEntry {
+                timestamp: timestamp.clone(),
+                ..entry.clone()
+            })
+            .collect::<Vec<_>>();
+        let mut headers = vec![(BATCH_NUMBER_HEADER.to_string(), number.to_string())];
+        if let Some(reply_to) = reply_to {
+            headers.push((REPLY_TO_HEADER.to_string(), reply_to.to_string()));
+        }
+        batches.push(
+            QueueMessage::new(BatchEncoding::Json.encode(&batch)?)
+                .with_content_type(BatchEncoding::Json.content_type())
+                .with_headers(headers),
+        );
+    }
+    Ok(batches)
+}
+
+/// How to run a load test.
+#[derive(Debug, Clone)]
+pub struct LoadPlan {
+    /// The queue the batches are published to.
+    pub queue: String,
+    /// The number of the first batch. A random one keeps the receipts of earlier runs
+    /// apart and the payloads different from theirs.
+    pub first_batch: u64,
+    pub batch_size: usize,
+    /// Batches per second, as fast as the broker takes them if `None`.
+    pub rate: Option<f64>,
+    /// Whether the consumer receives receipts of workers rather than the batches.
+    pub receipts: bool,
+    /// How long to wait for the last batch before reporting what was completed.
+    pub timeout: Duration,
+}
+
+/// The value below which `percentile` percent of the sorted `values` lie, by nearest rank.
+pub fn percentile(sorted: &[Duration], percentile: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (percentile / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Throughput and latencies of a load test. Latency is the time from publishing a batch
+/// to receiving its receipt, or the batch itself.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub num_published: usize,
+    pub num_completed: usize,
+    /// Receipts of batches that were completed before, e.g. after a redelivery.
+    pub num_duplicates: usize,
+    pub batch_size: usize,
+    pub publish_time: Duration,
+    pub total_time: Duration,
+    /// Sorted.
+    pub latencies: Vec<Duration>,
+    /// Completed batches per worker, empty without receipts.
+    pub per_worker: BTreeMap<String, usize>,
+}
+
+impl Report {
+    pub fn latency(&self, percentile: f64) -> Duration {
+        self::percentile(&self.latencies, percentile)
+    }
+}
+
+fn per_sec(count: usize, time: Duration) -> f64 {
+    count as f64 / time.as_secs_f64().max(f64::EPSILON)
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Published {} batches in {:.3}s ({:.1} batches/s)",
+            self.num_published,
+            self.publish_time.as_secs_f64(),
+            per_sec(self.num_published, self.publish_time)
+        )?;
+        writeln!(
+            f,
+            "Completed {} of {} batches in {:.3}s ({:.1} batches/s, {:.1} entries/s), {} duplicates",
+            self.num_completed,
+            self.num_published,
+            self.total_time.as_secs_f64(),
+            per_sec(self.num_completed, self.total_time),
+            per_sec(self.num_completed * self.batch_size, self.total_time),
+            self.num_duplicates
+        )?;
+        for (worker, num_completed) in &self.per_worker {
+            writeln!(f, "{worker}\t{num_completed} batches")?;
+        }
+        write!(
+            f,
+            "Latency p50 {:.3}s, p90 {:.3}s, p99 {:.3}s, max {:.3}s",
+            self.latency(50.0).as_secs_f64(),
+            self.latency(90.0).as_secs_f64(),
+            self.latency(99.0).as_secs_f64(),
+            self.latency(100.0).as_secs_f64()
+        )
+    }
+}
+
+/// Publishes `batches` with `producer` while receiving from `consumer` until every batch
+/// came back or `plan.timeout` passed since the last publish.
+pub async fn run_loadtest(
+    producer: Box<dyn QueueProducer>,
+    mut consumer: Box<dyn QueueConsumer>,
+    batches: Vec<QueueMessage>,
+    plan: &LoadPlan,
+) -> Result<Report, anyhow::Error> {
+    let num_batches = batches.len();
+    let first_batch = plan.first_batch;
+    let sent_at = Arc::new(Mutex::new(HashMap::<u64, Instant>::new()));
+    let start = Instant::now();
+    let publishing = tokio::spawn({
+        let sent_at = sent_at.clone();
+        let queue = plan.queue.clone();
+        let interval = plan.rate.map(|rate| Duration::from_secs_f64(1.0 / rate));
+        async move {
+            for (number, batch) in batches.iter().enumerate() {
+                if let Some(interval) = interval {
+                    tokio::time::sleep_until((start + interval * number as u32).into()).await;
+                }
+                sent_at
+                    .lock()
+                    .unwrap()
+                    .insert(first_batch + number as u64, Instant::now());
+                producer.publish(&queue, batch).await?;
+            }
+            Ok::<_, anyhow::Error>(start.elapsed())
+        }
+    });
+
+    let mut report = Report {
+        num_published: num_batches,
+        batch_size: plan.batch_size,
+        ..Report::default()
+    };
+    let mut completed = vec![false; num_batches];
+    let mut publish_time: Option<Duration> = None;
+    let mut publishing = Some(publishing);
+    while report.num_completed < num_batches {
+        let receiving = async {
+            match publish_time {
+                Some(publish_time) => {
+                    let deadline = start + publish_time + plan.timeout;
+                    tokio::time::timeout_at(deadline.into(), consumer.next())
+                        .await
+                        .ok()
+                }
+                None => Some(consumer.next().await),
+            }
+        };
+        let delivery = tokio::select! {
+            published = async { publishing.as_mut().unwrap().await }, if publishing.is_some() => {
+                publishing = None;
+                publish_time = Some(published??);
+                continue;
+            }
+            delivery = receiving => delivery,
+        };
+        let delivery = match delivery {
+            Some(Some(delivery)) => delivery?,
+            Some(None) => anyhow::bail!("The broker closed the subscription"),
+            None => {
+                tracing::warn!(
+                    "Gave up waiting for {} batches",
+                    num_batches - report.num_completed
+                );
+                break;
+            }
+        };
+        let message = delivery.message();
+        let number = message
+            .header(BATCH_NUMBER_HEADER)
+            .and_then(|number| number.parse::<u64>().ok())
+            .and_then(|number| number.checked_sub(first_batch))
+            .map(|number| number as usize)
+            .filter(|number| *number < num_batches);
+        let Some(number) = number else {
+            tracing::warn!("Dropping a message that is not of this load test");
+            delivery.reject().await?;
+            continue;
+        };
+        if completed[number] {
+            report.num_duplicates += 1;
+        } else if let Some(sent_at) = sent_at.lock().unwrap().get(&(first_batch + number as u64)) {
+            completed[number] = true;
+            report.num_completed += 1;
+            report.latencies.push(sent_at.elapsed());
+            if plan.receipts {
+                let worker = serde_json::from_slice::<Receipt>(&message.payload)
+                    .map(|receipt| receipt.worker)
+                    .unwrap_or_else(|_| "unknown".to_string());
+                *report.per_worker.entry(worker).or_default() += 1;
+            }
+        }
+        delivery.ack().await?;
+    }
+    report.total_time = start.elapsed();
+    report.publish_time = match publishing {
+        Some(publishing) => publishing.await??,
+        None => publish_time.unwrap_or_default(),
+    };
+    report.latencies.sort();
+    Ok(report)
+}
+
+/// The first and last byte of a `Range: bytes=<first>-<last>` header of a file of `len`
+/// bytes, `None` if it is not one or not satisfiable.
+pub fn parse_range(range: &str, len: usize) -> Option<(usize, usize)> {
+    let (first, last) = range.strip_prefix("bytes=")?.split_once('-')?;
+    let first = first.parse::<usize>().ok()?;
+    let last = match last {
+        "" => len.checked_sub(1)?,
+        last => last.parse::<usize>().ok()?.min(len.checked_sub(1)?),
+    };
+    (first <= last).then_some((first, last))
+}
+
+/// Serves the files of `dir` with range requests on `addr`, like data.commoncrawl.org
+/// serves the crawl, so workers can fetch the fixtures with `--data-url`.
+#[cfg(feature = "metrics")]
+pub async fn serve_fixtures(dir: std::path::PathBuf, addr: &str) -> Result<(), anyhow::Error> {
+    use axum::{
+        extract::State,
+        http::{header, HeaderMap, StatusCode, Uri},
+        response::{IntoResponse, Response},
+    };
+
+    async fn serve(
+        State(dir): State<Arc<std::path::PathBuf>>,
+        uri: Uri,
+        headers: HeaderMap,
+    ) -> Response {
+        let path = uri.path().trim_start_matches('/');
+        if path.split('/').any(|part| part == "..") {
+            return StatusCode::NOT_FOUND.into_response();
+        }
+        let Ok(data) = std::fs::read(dir.join(path)) else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        let Some(range) = headers.get(header::RANGE) else {
+            return data.into_response();
+        };
+        match range
+            .to_str()
+            .ok()
+            .and_then(|range| parse_range(range, data.len()))
+        {
+            Some((first, last)) => (
+                StatusCode::PARTIAL_CONTENT,
+                [(
+                    header::CONTENT_RANGE,
+                    format!("bytes {first}-{last}/{}", data.len()),
+                )],
+                data[first..=last].to_vec(),
+            )
+                .into_response(),
+            None => StatusCode::RANGE_NOT_SATISFIABLE.into_response(),
+        }
+    }
+
+    let app = axum::Router::new()
+        .fallback(serve)
+        .with_state(Arc::new(dir));
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        batch::{batch_id, BatchEncoding},
+        fixtures::{generate_fixtures, sample_pages},
+        loadtest::{
+            parse_range, percentile, receipt, run_loadtest, synthetic_batches, LoadPlan,
+            BATCH_NUMBER_HEADER,
+        },
+        spool::Spool,
+    };
+
+    #[test]
+    fn computes_percentiles_and_ranges() {
+        let latencies = (1..=10).map(Duration::from_secs).collect::<Vec<_>>();
+        assert_eq!(percentile(&latencies, 50.0), Duration::from_secs(5));
+        assert_eq!(percentile(&latencies, 99.0), Duration::from_secs(10));
+        assert_eq!(percentile(&latencies, 0.0), Duration::from_secs(1));
+        assert_eq!(percentile(&[], 50.0), Duration::ZERO);
+        assert_eq!(parse_range("bytes=10-19", 100), Some((10, 19)));
+        assert_eq!(parse_range("bytes=90-", 100), Some((90, 99)));
+        assert_eq!(parse_range("bytes=90-200", 100), Some((90, 99)));
+        assert_eq!(parse_range("bytes=100-200", 100), None);
+        assert_eq!(parse_range("items=0-1", 100), None);
+    }
+
+    #[tokio::test]
+    async fn measures_a_load_test_through_the_spool() {
+        let dir = std::env::temp_dir().join(format!("pipeline-loadtest-{}", std::process::id()));
+        let fixtures =
+            generate_fixtures(&dir.join("fixtures"), "CC-MAIN-2024-30", &sample_pages(), 3)
+                .unwrap();
+        let batches =
+            synthetic_batches(&fixtures.entries, 7, 5, 3, Some("loadtest-receipts")).unwrap();
+        assert_eq!(
+            BatchEncoding::Json
+                .decode(&batches[4].payload)
+                .unwrap()
+                .len(),
+            3
+        );
+        let mut ids = batches
+            .iter()
+            .map(|batch| batch_id(&batch.payload))
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), 5);
+        let (reply_to, receipt) = receipt(&batches[2], "worker-1").unwrap();
+        assert_eq!(reply_to, "loadtest-receipts");
+        assert_eq!(receipt.header(BATCH_NUMBER_HEADER), Some("9"));
+
+        let spool = Spool::new(&dir.join("spool"));
+        let consumer = spool.consumer("batches").unwrap();
+        let plan = LoadPlan {
+            queue: "batches".to_string(),
+            first_batch: 7,
+            batch_size: 3,
+            rate: Some(100.0),
+            receipts: false,
+            timeout: Duration::from_secs(10),
+        };
+        let report = run_loadtest(Box::new(spool), Box::new(consumer), batches, &plan)
+            .await
+            .unwrap();
+        assert_eq!(report.num_published, 5);
+        assert_eq!(report.num_completed, 5);
+        assert_eq!(report.latencies.len(), 5);
+        assert!(report.publish_time >= Duration::from_millis(40));
+        assert!(report.to_string().contains("Completed 5 of 5 batches"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
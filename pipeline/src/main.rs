@@ -1,3 +1,1197 @@
-fn main() {
-    println!("Hello, world!");
+use clap::{Parser, Subcommand};
+use pipeline::{
+    aggregate::aggregate_by_domain,
+    batch::{parse_line_ranges, BatchEncoding, BatchSource},
+    cdx::{format_cdx_line, parse_cluster_idx, CDX_DECODING},
+    diff::{diff_captures, Change},
+    docs::{cat_shard, head},
+    doctor::{
+        check_cluster_idx, check_data_url, check_disk_space, check_index_api, check_writable,
+        DoctorReport,
+    },
+    estimate::{extrapolate, sample_chunk, sample_indices, CostModel},
+    filter::{CdxFilter, LanguageSelection, MimeTypes, StatusCodes},
+    fixtures::{generate_fixtures, read_pages, sample_pages},
+    http_client::{build_http_client, HttpOptions},
+    index_api::{IndexApiClient, Politeness, CC_INDEX_API_URL},
+    redact::{redact_file, redact_output, Redactions},
+    sample::{sample_output, write_bundle, Bucket},
+    tracing_and_metrics::setup_tracing,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Estimate the size and cost of a run by sampling a few CDX chunks.
+    Estimate {
+        #[arg(short, long, default_value = "cluster.idx")]
+        cluster_idx_filename: String,
+
+        #[arg(long, default_value = "CC-MAIN-2024-30")]
+        crawl: String,
+
+        #[arg(short, long, default_value_t = 10)]
+        num_samples: usize,
+
+        #[arg(long, alias = "language", default_value_t = CdxFilter::default().languages)]
+        languages: LanguageSelection,
+
+        #[arg(long)]
+        primary_language_only: bool,
+
+        /// HTTP status codes and ranges to count, e.g. `200,301-308`.
+        #[arg(long, alias = "status", default_value_t = StatusCodes::default())]
+        status_codes: StatusCodes,
+
+        /// MIME types to count, e.g. `text/html,text/*`, or `*` for any.
+        #[arg(long, default_value_t = MimeTypes::html())]
+        mime_types: MimeTypes,
+
+        #[arg(long)]
+        exclude_mime_types: Option<MimeTypes>,
+
+        #[arg(long, default_value_t = CostModel::default().usd_per_gb_egress)]
+        usd_per_gb_egress: f64,
+
+        #[arg(long, default_value_t = CostModel::default().usd_per_1000_requests)]
+        usd_per_1000_requests: f64,
+
+        #[command(flatten)]
+        http: HttpOptions,
+    },
+    /// Query the index API for a URL pattern and write all captures as CDX lines.
+    Query {
+        #[arg(long, default_value = "CC-MAIN-2024-30")]
+        crawl: String,
+
+        /// URL or pattern as understood by the index API, e.g. `*.example.com`.
+        #[arg(short, long)]
+        url: String,
+
+        #[arg(short, long, default_value = "query.cdx")]
+        output_filename: String,
+
+        #[arg(long, default_value = CC_INDEX_API_URL)]
+        index_api_url: String,
+
+        #[arg(long, default_value_t = Politeness::default().requests_per_sec)]
+        requests_per_sec: f64,
+
+        #[arg(long, default_value_t = Politeness::default().page_concurrency)]
+        page_concurrency: usize,
+
+        #[arg(long, default_value_t = Politeness::default().max_attempts)]
+        max_attempts: usize,
+
+        /// Remembers the next page to fetch, so an interrupted query continues where it stopped.
+        #[arg(long, default_value = "query_resume.json")]
+        resume_filename: String,
+
+        #[command(flatten)]
+        http: HttpOptions,
+    },
+    /// Compare the captures of a URL pattern in two crawls, printed as tab-separated values
+    /// of URLs that are new in B, disappeared from A or changed their digest.
+    DiffCrawls {
+        /// The earlier crawl, e.g. `CC-MAIN-2024-26`.
+        #[arg(long)]
+        a: String,
+
+        /// The later crawl, e.g. `CC-MAIN-2024-30`.
+        #[arg(long)]
+        b: String,
+
+        /// URL or pattern as understood by the index API, e.g. `*.example.com`.
+        #[arg(short, long)]
+        url: String,
+
+        /// Also write the new and changed URLs to this file, to enqueue them with
+        /// `batcher --url-list <file> --crawls <b>`.
+        #[arg(long)]
+        enqueue_list: Option<String>,
+
+        #[arg(long, default_value = CC_INDEX_API_URL)]
+        index_api_url: String,
+
+        #[arg(long, default_value_t = Politeness::default().requests_per_sec)]
+        requests_per_sec: f64,
+
+        #[command(flatten)]
+        http: HttpOptions,
+    },
+    /// Summarize the worker output per domain, printed as tab-separated values.
+    Aggregate {
+        #[arg(short, long, default_value = "output")]
+        output_dir: String,
+    },
+    /// Draw random documents per bucket into a small bundle for manual review.
+    Sample {
+        #[arg(short, long, default_value = "output")]
+        output_dir: String,
+
+        #[arg(short, long, value_enum, default_value_t = Bucket::Language)]
+        bucket: Bucket,
+
+        #[arg(short = 'k', long, default_value_t = 10)]
+        per_bucket: usize,
+
+        #[arg(long, default_value_t = 42)]
+        seed: u64,
+
+        #[arg(long, default_value = "sample")]
+        bundle_dir: String,
+    },
+    /// Print output records without external tools.
+    Docs {
+        #[command(subcommand)]
+        command: DocsCommand,
+    },
+    /// Inspect the batches dead-lettered by `--dead-letter-after` and move them back.
+    #[cfg(feature = "rabbitmq")]
+    Dlq {
+        #[command(subcommand)]
+        command: DlqCommand,
+    },
+    /// Remove documents from existing output files, e.g. for takedown requests. Stop the
+    /// workers writing to the directory first.
+    Redact {
+        /// File of URLs and document IDs to remove, one per line.
+        redactions_filename: String,
+
+        #[arg(short, long, default_value = "output")]
+        output_dir: String,
+
+        /// A/B comparison files of the workers (`--ab-output-filename`), which are not
+        /// written to the output directory.
+        #[arg(long)]
+        ab_files: Vec<String>,
+
+        /// Leave the host graph in `<output-dir>/web_graph` as it is. Without this, redact
+        /// refuses to run when there is one, as its link counts cannot be redacted.
+        #[arg(long)]
+        keep_web_graph: bool,
+    },
+    /// Write a tiny crawl (WARC, CDX shard and cluster.idx) from sample HTML pages, for tests.
+    GenFixtures {
+        /// Directory of `*.html` files, the built-in sample pages are used if not given.
+        #[arg(long)]
+        pages_dir: Option<String>,
+
+        #[arg(short, long, default_value = "fixtures")]
+        output_dir: String,
+
+        #[arg(long, default_value = "CC-MAIN-2024-30")]
+        crawl: String,
+
+        #[arg(long, default_value_t = 3)]
+        lines_per_cluster: usize,
+    },
+    /// Publish synthetic batches of fixture records and report the throughput and the
+    /// latency of the broker and the workers, without fetching from Common Crawl.
+    #[cfg(feature = "rabbitmq")]
+    Loadtest {
+        #[arg(short, long, default_value_t = 1000)]
+        num_batches: usize,
+
+        #[arg(long, default_value_t = 10)]
+        batch_size: usize,
+
+        /// Batches per second, as fast as the broker takes them if not given.
+        #[arg(long)]
+        rate: Option<f64>,
+
+        #[arg(long, default_value = "batches")]
+        queue_name: String,
+
+        /// Where the workers send a receipt for each batch they processed.
+        #[arg(long, default_value = "loadtest-receipts")]
+        receipt_queue_name: String,
+
+        /// Receive the batches instead of receipts, to measure the broker alone. No
+        /// workers may consume `--queue-name` meanwhile.
+        #[arg(long)]
+        consume: bool,
+
+        /// Directory of `*.html` files, the built-in sample pages are used if not given.
+        #[arg(long)]
+        pages_dir: Option<String>,
+
+        #[arg(long, default_value = "loadtest-fixtures")]
+        fixtures_dir: String,
+
+        /// Serve the fixtures on this address, e.g. `0.0.0.0:8080`, for workers started
+        /// with `--data-url http://<host>:8080`.
+        #[cfg(feature = "metrics")]
+        #[arg(long)]
+        serve_fixtures: Option<String>,
+
+        /// How long to wait after the last publish before reporting the batches that
+        /// did not come back as lost.
+        #[arg(long, default_value_t = 300)]
+        timeout_secs: u64,
+
+        /// As given to the workers, RabbitMQ refuses to redeclare the queue otherwise.
+        #[arg(long)]
+        dead_letter_after: Option<u32>,
+
+        #[command(flatten)]
+        queue: pipeline::queue::QueueOptions,
+
+        #[command(flatten)]
+        http: HttpOptions,
+    },
+    /// Show the traffic and approximate cost of each run recorded in the run DB.
+    #[cfg(feature = "run-db")]
+    Cost {
+        #[command(flatten)]
+        state: pipeline::state_store::StateStoreOptions,
+
+        #[arg(long, default_value_t = CostModel::default().usd_per_gb_egress)]
+        usd_per_gb_egress: f64,
+
+        #[arg(long, default_value_t = CostModel::default().usd_per_1000_requests)]
+        usd_per_1000_requests: f64,
+    },
+    /// Print one JSON line per crawl with the latest capture time the workers have
+    /// completed, for downstream jobs that wait for a time range of a crawl.
+    #[cfg(feature = "run-db")]
+    Status {
+        #[command(flatten)]
+        state: pipeline::state_store::StateStoreOptions,
+    },
+    /// Compare the configs and outcomes of two runs recorded in the run DB, e.g. before
+    /// and after changing the extractor.
+    #[cfg(feature = "run-db")]
+    CompareRuns {
+        #[arg(long)]
+        a: String,
+
+        #[arg(long)]
+        b: String,
+
+        #[command(flatten)]
+        state: pipeline::state_store::StateStoreOptions,
+    },
+    /// Write the counts of records a run dropped for takedowns, opt-outs and robots meta
+    /// tags as JSON, without any URLs, for legal review.
+    #[cfg(feature = "run-db")]
+    ComplianceReport {
+        #[command(flatten)]
+        state: pipeline::state_store::StateStoreOptions,
+
+        #[arg(long, default_value = "CC-MAIN-2024-30")]
+        run_id: String,
+
+        /// Write the report to this file instead of stdout.
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Snapshot the content keys of the state store, or restore a snapshot, to move the
+    /// dedup of incremental runs to another machine.
+    #[cfg(feature = "run-db")]
+    Dedup {
+        #[command(subcommand)]
+        command: DedupCommand,
+    },
+    /// Check the broker, Common Crawl, the cluster.idx and the output disk before a run
+    /// and print a pass/fail report. Exits with 1 if any check fails.
+    Doctor {
+        #[arg(long, default_value = "CC-MAIN-2024-30")]
+        crawl: String,
+
+        #[arg(short, long, default_value = "cluster.idx")]
+        cluster_idx_filename: String,
+
+        /// Queues to declare on the broker, as the batcher and the workers do.
+        #[arg(long = "queue-name", default_values = ["batches", "batches-priority"])]
+        queue_names: Vec<String>,
+
+        #[arg(long, default_value = "output")]
+        output_dir: String,
+
+        /// Fail if the output directory has less space available.
+        #[arg(long, default_value_t = 10)]
+        min_free_gib: u64,
+
+        #[arg(long, default_value = CC_INDEX_API_URL)]
+        index_api_url: String,
+
+        /// Also check that the worker may upload to this `--s3-url`.
+        #[cfg(feature = "s3")]
+        #[arg(long)]
+        s3_url: Option<String>,
+
+        #[command(flatten)]
+        queue: pipeline::queue::QueueOptions,
+
+        #[command(flatten)]
+        state: pipeline::state_store::StateStoreOptions,
+
+        #[command(flatten)]
+        http: HttpOptions,
+    },
+    /// Print the CDX lines a batch was built from, given its `source_*` headers.
+    ReplayBatch {
+        /// `source_cdx_path`, e.g. cc-index/collections/CC-MAIN-2024-30/indexes/cdx-00000.gz.
+        #[arg(long)]
+        cdx_path: String,
+
+        #[arg(long)]
+        offset: usize,
+
+        #[arg(long)]
+        length: usize,
+
+        /// `source_lines`, e.g. `12-15,17,20-511`.
+        #[arg(long)]
+        lines: String,
+
+        #[command(flatten)]
+        http: HttpOptions,
+    },
+    /// Show the progress of a running batcher and its workers in the terminal, from their
+    /// `/metrics`, the batcher's checkpoint and the broker. Quit with `q`.
+    #[cfg(feature = "tui")]
+    Dashboard {
+        /// `/metrics` of the batcher and of each worker.
+        #[arg(long = "metrics-url", default_values = ["http://127.0.0.1:9000/metrics", "http://127.0.0.1:9001/metrics"])]
+        metrics_urls: Vec<String>,
+
+        #[arg(short, long, default_value = "cluster.idx")]
+        cluster_idx_filename: String,
+
+        /// The batcher's `--checkpoint-filename`.
+        #[arg(long, default_value = "batcher_checkpoint.json")]
+        checkpoint_filename: String,
+
+        /// Queues to show the depth of, if `RABBITMQ_CONNECTION_STRING` is set.
+        #[arg(long = "queue-name", default_values = ["batches", "batches-priority", "batches.dlq"])]
+        queue_names: Vec<String>,
+
+        #[arg(long, default_value_t = 2)]
+        refresh_secs: u64,
+
+        /// How many of the most recent errors to show.
+        #[arg(long, default_value_t = 20)]
+        max_errors: usize,
+    },
+    /// Call the gRPC control API of a worker started with `--control-addr`.
+    #[cfg(feature = "grpc")]
+    Control {
+        #[arg(long, default_value = "http://127.0.0.1:50051")]
+        url: String,
+
+        #[command(subcommand)]
+        command: ControlCommand,
+    },
+}
+
+#[cfg(feature = "grpc")]
+#[derive(Subcommand, Debug)]
+enum ControlCommand {
+    Status,
+    /// Stop taking new batches, the worker finishes the ones in progress.
+    Pause,
+    Resume,
+    /// Publish the CDX lines of a file as one batch to the priority queue, e.g. the output
+    /// of `replay-batch`.
+    Submit {
+        cdx_filename: String,
+    },
+}
+
+#[cfg(feature = "run-db")]
+#[derive(Subcommand, Debug)]
+enum DedupCommand {
+    /// Write every content key and its document ID to a file or an `s3://bucket/key`
+    /// (the `s3` feature).
+    Snapshot {
+        destination: String,
+
+        #[command(flatten)]
+        state: pipeline::state_store::StateStoreOptions,
+
+        #[command(flatten)]
+        http: HttpOptions,
+    },
+    /// Add the keys of a snapshot to the state store. Keys it has already keep their
+    /// document.
+    Restore {
+        source: String,
+
+        #[command(flatten)]
+        state: pipeline::state_store::StateStoreOptions,
+
+        #[command(flatten)]
+        http: HttpOptions,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum DocsCommand {
+    /// Print every record of one output shard (JSONL, compressed or not, or Parquet) as JSON lines.
+    Cat { shard: String },
+    /// Print the first documents of an output directory, as JSON lines by default.
+    Head {
+        #[arg(default_value = "output")]
+        output_dir: String,
+
+        /// Only documents in this language, as ISO 639-3 code like the output dirs (`deu`).
+        #[arg(long)]
+        lang: Option<String>,
+
+        #[arg(short, default_value_t = 10)]
+        n: usize,
+
+        /// `protobuf` writes length-delimited `Document` messages of
+        /// `proto/pipeline.proto` instead of JSON lines.
+        #[arg(long, value_enum, default_value_t = BatchEncoding::Json)]
+        encoding: BatchEncoding,
+    },
+}
+
+#[cfg(feature = "rabbitmq")]
+#[derive(Subcommand, Debug)]
+enum DlqCommand {
+    /// Print the dead letters of a queue as JSON lines, leaving them in place.
+    List {
+        #[arg(long, default_value = "batches.dlq")]
+        queue_name: String,
+
+        #[arg(short, default_value_t = 100)]
+        n: usize,
+    },
+    /// Publish dead letters to their queue again, e.g. after fixing the worker.
+    Requeue {
+        #[arg(long, default_value = "batches.dlq")]
+        queue_name: String,
+
+        /// The queue to move them to, the name without `.dlq` by default.
+        #[arg(long)]
+        to_queue_name: Option<String>,
+
+        #[arg(short, default_value_t = usize::MAX)]
+        n: usize,
+    },
+}
+
+/// The queues of a load test, and the RabbitMQ connection they use if any.
+#[cfg(feature = "rabbitmq")]
+struct LoadtestQueues {
+    _conn: Option<lapin::Connection>,
+    producer: Box<dyn pipeline::queue::QueueProducer>,
+    consumer: Box<dyn pipeline::queue::QueueConsumer>,
+}
+
+/// The producer of `queue_name` and the consumer of `consume_queue_name` of a load test.
+#[cfg(feature = "rabbitmq")]
+async fn loadtest_queues(
+    queue: &pipeline::queue::QueueOptions,
+    http: &HttpOptions,
+    queue_name: &str,
+    consume_queue_name: &str,
+    dead_letter_after: Option<u32>,
+) -> Result<LoadtestQueues, anyhow::Error> {
+    use pipeline::queue::QueueBackend;
+
+    #[cfg(not(feature = "sqs"))]
+    let _ = http;
+    let queues = match queue.queue_backend {
+        QueueBackend::Rabbitmq => {
+            use pipeline::{
+                dlq,
+                rabbitmq::{
+                    rabbitmq_channel, rabbitmq_connection, rabbitmq_consumer,
+                    rabbitmq_declare_queue, RabbitConsumer, RabbitProducer, ReliablePublisher,
+                },
+            };
+
+            let conn = rabbitmq_connection().await?;
+            let channel = rabbitmq_channel(&conn).await?;
+            dlq::declare_queue(&channel, queue_name, dead_letter_after).await?;
+            if consume_queue_name != queue_name {
+                rabbitmq_declare_queue(&channel, consume_queue_name, Default::default()).await?;
+            }
+            let consumer = rabbitmq_consumer(&channel, consume_queue_name, "loadtest").await?;
+            let publisher = ReliablePublisher::new(rabbitmq_channel(&conn).await?).await?;
+            LoadtestQueues {
+                _conn: Some(conn),
+                producer: Box::new(RabbitProducer::new(publisher)),
+                consumer: Box::new(RabbitConsumer(consumer)),
+            }
+        }
+        QueueBackend::Fs => {
+            let spool = pipeline::spool::Spool::new(&queue.spool_dir);
+            let consumer = spool.consumer(consume_queue_name)?;
+            LoadtestQueues {
+                _conn: None,
+                producer: Box::new(spool),
+                consumer: Box::new(consumer),
+            }
+        }
+        #[cfg(feature = "kafka")]
+        QueueBackend::Kafka => {
+            let broker = queue.kafka_broker(&build_http_client(http)?);
+            let consumer = broker.consumer(consume_queue_name)?;
+            LoadtestQueues {
+                _conn: None,
+                producer: Box::new(broker.producer()?),
+                consumer: Box::new(consumer),
+            }
+        }
+        #[cfg(feature = "sqs")]
+        QueueBackend::Sqs => {
+            let client = pipeline::sqs::SqsClient::from_env(build_http_client(http)?)?
+                .with_retries(http.backoff(), 5)
+                .with_visibility_timeout(queue.sqs_visibility_timeout_secs);
+            let consumer = client.consumer(consume_queue_name);
+            LoadtestQueues {
+                _conn: None,
+                producer: Box::new(client),
+                consumer: Box::new(consumer),
+            }
+        }
+    };
+    Ok(queues)
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct QueryResume {
+    crawl: String,
+    url: String,
+    next_page: usize,
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    setup_tracing();
+
+    match args.command {
+        Command::Estimate {
+            cluster_idx_filename,
+            crawl,
+            num_samples,
+            languages,
+            primary_language_only,
+            status_codes,
+            mime_types,
+            exclude_mime_types,
+            usd_per_gb_egress,
+            usd_per_1000_requests,
+            http,
+        } => {
+            let http_client = build_http_client(&http).unwrap();
+            let idx = fs::read_to_string(cluster_idx_filename)
+                .expect("Should have been able to read the file")
+                .lines()
+                .filter_map(parse_cluster_idx)
+                .collect::<Vec<_>>();
+            let filter = CdxFilter {
+                languages,
+                primary_language_only,
+                status_codes,
+                mime_types,
+                excluded_mime_types: exclude_mime_types,
+            };
+            let mut samples = Vec::new();
+            for i in sample_indices(idx.len(), num_samples) {
+                tracing::info!("Sampling CDX chunk {} of {}", i, idx.len());
+                samples.push(
+                    sample_chunk(&http_client, &crawl, &idx[i], &filter)
+                        .await
+                        .unwrap(),
+                );
+            }
+            let estimate = extrapolate(
+                &idx,
+                &samples,
+                &CostModel {
+                    usd_per_gb_egress,
+                    usd_per_1000_requests,
+                },
+            );
+            println!(
+                "Sampled {} of {} CDX chunks of {}",
+                estimate.num_sampled_chunks, estimate.num_chunks, crawl
+            );
+            println!("Total entries:    {:.0}", estimate.total_entries);
+            println!("Matching entries: {:.0}", estimate.matching_entries);
+            println!("Index bytes:      {}", estimate.index_bytes);
+            println!("WARC bytes:       {:.0}", estimate.warc_bytes);
+            println!("Requests:         {:.0}", estimate.requests);
+            println!("Approx. cost:     ${:.2}", estimate.cost_usd);
+            if let Some(summary) = CDX_DECODING.malformed_summary() {
+                println!("{summary}");
+            }
+        }
+        Command::Query {
+            crawl,
+            url,
+            output_filename,
+            index_api_url,
+            requests_per_sec,
+            page_concurrency,
+            max_attempts,
+            resume_filename,
+            http,
+        } => {
+            let resume = fs::read(&resume_filename)
+                .ok()
+                .and_then(|data| serde_json::from_slice::<QueryResume>(&data).ok())
+                .filter(|resume| resume.crawl == crawl && resume.url == url);
+            let first_page = resume.map(|resume| resume.next_page).unwrap_or(0);
+            if first_page > 0 {
+                tracing::info!("Resuming query at page {}", first_page);
+            }
+            let client = IndexApiClient::new(
+                &index_api_url,
+                Politeness {
+                    requests_per_sec,
+                    page_concurrency,
+                    max_attempts,
+                },
+                &http,
+            )
+            .unwrap();
+            let mut output = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&output_filename)
+                .unwrap();
+            client
+                .query(&crawl, &url, first_page, |entries, next_page| {
+                    for entry in &entries {
+                        writeln!(output, "{}", format_cdx_line(entry)?)?;
+                    }
+                    output.flush()?;
+                    let resume = QueryResume {
+                        crawl: crawl.clone(),
+                        url: url.clone(),
+                        next_page,
+                    };
+                    fs::write(&resume_filename, serde_json::to_vec(&resume)?)?;
+                    tracing::info!(
+                        "Wrote {} captures, next page is {}",
+                        entries.len(),
+                        next_page
+                    );
+                    Ok(())
+                })
+                .await
+                .unwrap();
+            fs::remove_file(&resume_filename).ok();
+        }
+        Command::DiffCrawls {
+            a,
+            b,
+            url,
+            enqueue_list,
+            index_api_url,
+            requests_per_sec,
+            http,
+        } => {
+            let client = IndexApiClient::new(
+                &index_api_url,
+                Politeness {
+                    requests_per_sec,
+                    ..Default::default()
+                },
+                &http,
+            )
+            .unwrap();
+            let mut captures = Vec::new();
+            for crawl in [&a, &b] {
+                let mut crawl_captures = Vec::new();
+                client
+                    .query(crawl, &url, 0, |entries, _| {
+                        crawl_captures.extend(entries);
+                        Ok(())
+                    })
+                    .await
+                    .unwrap();
+                captures.push(crawl_captures);
+            }
+            let changes = diff_captures(&captures[0], &captures[1]);
+            println!("change\turl\tdigest_a\tdigest_b");
+            for change in &changes {
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    change.change.as_str(),
+                    change.url,
+                    change.digest_a.as_deref().unwrap_or_default(),
+                    change.digest_b.as_deref().unwrap_or_default()
+                );
+            }
+            if let Some(enqueue_list) = enqueue_list {
+                let urls = changes
+                    .iter()
+                    .filter(|change| change.change != Change::Disappeared)
+                    .map(|change| format!("{}\n", change.url))
+                    .collect::<String>();
+                fs::write(enqueue_list, urls).unwrap();
+            }
+        }
+        Command::Aggregate { output_dir } => {
+            println!("domain\tnum_documents\ttotal_tokens\tmean_quality_score");
+            for rollup in aggregate_by_domain(Path::new(&output_dir)).unwrap() {
+                println!(
+                    "{}\t{}\t{}\t{:.3}",
+                    rollup.domain,
+                    rollup.num_documents,
+                    rollup.total_tokens,
+                    rollup.mean_quality_score
+                );
+            }
+        }
+        Command::Sample {
+            output_dir,
+            bucket,
+            per_bucket,
+            seed,
+            bundle_dir,
+        } => {
+            let samples = sample_output(Path::new(&output_dir), bucket, per_bucket, seed).unwrap();
+            for path in write_bundle(Path::new(&bundle_dir), &samples).unwrap() {
+                println!("Wrote {}", path.display());
+            }
+        }
+        Command::Docs { command } => {
+            let mut stdout = std::io::stdout().lock();
+            match command {
+                DocsCommand::Cat { shard } => {
+                    cat_shard(Path::new(&shard), &mut stdout).unwrap();
+                }
+                DocsCommand::Head {
+                    output_dir,
+                    lang,
+                    n,
+                    encoding,
+                } => {
+                    head(
+                        Path::new(&output_dir),
+                        lang.as_deref(),
+                        n,
+                        encoding,
+                        &mut stdout,
+                    )
+                    .unwrap();
+                }
+            }
+        }
+        #[cfg(feature = "rabbitmq")]
+        Command::Dlq { command } => {
+            use pipeline::{
+                dlq,
+                rabbitmq::{rabbitmq_channel, rabbitmq_connection, ReliablePublisher},
+            };
+
+            let conn = rabbitmq_connection().await.unwrap();
+            let channel = rabbitmq_channel(&conn).await.unwrap();
+            match command {
+                DlqCommand::List { queue_name, n } => {
+                    for dead_letter in dlq::list(&channel, &queue_name, n).await.unwrap() {
+                        println!("{}", serde_json::to_string(&dead_letter).unwrap());
+                    }
+                }
+                DlqCommand::Requeue {
+                    queue_name,
+                    to_queue_name,
+                    n,
+                } => {
+                    let to_queue_name = to_queue_name
+                        .or_else(|| dlq::source_queue(&queue_name).map(str::to_string))
+                        .expect("--to-queue-name is needed for a queue not ending in .dlq");
+                    let publisher = ReliablePublisher::new(rabbitmq_channel(&conn).await.unwrap())
+                        .await
+                        .unwrap();
+                    let num_requeued =
+                        dlq::requeue(&channel, &publisher, &queue_name, &to_queue_name, n)
+                            .await
+                            .unwrap();
+                    tracing::info!("Moved {} batches to {}", num_requeued, to_queue_name);
+                }
+            }
+        }
+        Command::Redact {
+            redactions_filename,
+            output_dir,
+            ab_files,
+            keep_web_graph,
+        } => {
+            let redactions = Redactions::read(Path::new(&redactions_filename)).unwrap();
+            let mut rewritten =
+                redact_output(Path::new(&output_dir), &redactions, keep_web_graph).unwrap();
+            for path in ab_files.iter().map(PathBuf::from) {
+                let num_removed = redact_file(&path, &redactions).unwrap();
+                if num_removed > 0 {
+                    rewritten.push((path, num_removed));
+                }
+            }
+            for (path, num_removed) in &rewritten {
+                println!("Removed {} documents from {}", num_removed, path.display());
+            }
+            println!("Rewrote {} files", rewritten.len());
+        }
+        Command::GenFixtures {
+            pages_dir,
+            output_dir,
+            crawl,
+            lines_per_cluster,
+        } => {
+            let pages = match pages_dir {
+                Some(dir) => read_pages(Path::new(&dir)).unwrap(),
+                None => sample_pages(),
+            };
+            let fixtures =
+                generate_fixtures(Path::new(&output_dir), &crawl, &pages, lines_per_cluster)
+                    .unwrap();
+            println!(
+                "Wrote {} records to {}",
+                fixtures.entries.len(),
+                fixtures.warc_path.display()
+            );
+            println!("Wrote {}", fixtures.cdx_path.display());
+            println!("Wrote {}", fixtures.cluster_idx_path.display());
+        }
+        #[cfg(feature = "rabbitmq")]
+        Command::Loadtest {
+            num_batches,
+            batch_size,
+            rate,
+            queue_name,
+            receipt_queue_name,
+            consume,
+            pages_dir,
+            fixtures_dir,
+            #[cfg(feature = "metrics")]
+            serve_fixtures,
+            timeout_secs,
+            dead_letter_after,
+            queue,
+            http,
+        } => {
+            use pipeline::loadtest::{
+                run_loadtest, synthetic_batches, LoadPlan, NUM_BATCH_NUMBERS,
+            };
+
+            let pages = match pages_dir {
+                Some(dir) => read_pages(Path::new(&dir)).unwrap(),
+                None => sample_pages(),
+            };
+            let fixtures =
+                generate_fixtures(Path::new(&fixtures_dir), "CC-MAIN-2024-30", &pages, 3).unwrap();
+            #[cfg(feature = "metrics")]
+            if let Some(addr) = serve_fixtures {
+                tracing::info!("Serving {} on {}", fixtures_dir, addr);
+                let dir = fixtures_dir.clone().into();
+                tokio::spawn(async move {
+                    if let Err(e) = pipeline::loadtest::serve_fixtures(dir, &addr).await {
+                        tracing::error!(err.msg = %e, "Failed to serve the fixtures");
+                    }
+                });
+            }
+            let plan = LoadPlan {
+                queue: queue_name.clone(),
+                first_batch: rand::random_range(0..NUM_BATCH_NUMBERS),
+                batch_size,
+                rate,
+                receipts: !consume,
+                timeout: std::time::Duration::from_secs(timeout_secs),
+            };
+            let reply_to = (!consume).then_some(receipt_queue_name.as_str());
+            let batches = synthetic_batches(
+                &fixtures.entries,
+                plan.first_batch,
+                num_batches,
+                batch_size,
+                reply_to,
+            )
+            .unwrap();
+            let consume_queue_name = reply_to.unwrap_or(&queue_name);
+            let queues = loadtest_queues(
+                &queue,
+                &http,
+                &queue_name,
+                consume_queue_name,
+                dead_letter_after,
+            )
+            .await
+            .unwrap();
+            let report = run_loadtest(queues.producer, queues.consumer, batches, &plan)
+                .await
+                .unwrap();
+            println!("{report}");
+        }
+        #[cfg(feature = "run-db")]
+        Command::Status { state } => {
+            let run_db = state.open().unwrap();
+            for watermark in run_db.watermarks().unwrap() {
+                println!("{}", serde_json::to_string(&watermark).unwrap());
+            }
+        }
+        #[cfg(feature = "run-db")]
+        Command::Cost {
+            state,
+            usd_per_gb_egress,
+            usd_per_1000_requests,
+        } => {
+            let cost_model = CostModel {
+                usd_per_gb_egress,
+                usd_per_1000_requests,
+            };
+            let run_db = state.open().unwrap();
+            println!("run_id\tindex_bytes\twarc_bytes\tindex_api_bytes\trequests\tretries\tusd");
+            for cost in run_db.costs().unwrap() {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{:.2}",
+                    cost.run_id,
+                    cost.index_bytes,
+                    cost.warc_bytes,
+                    cost.index_api_bytes,
+                    cost.requests,
+                    cost.retries,
+                    cost_model.cost_usd(
+                        (cost.index_bytes + cost.warc_bytes + cost.index_api_bytes) as f64,
+                        cost.requests as f64
+                    )
+                );
+            }
+        }
+        #[cfg(feature = "run-db")]
+        Command::CompareRuns { a, b, state } => {
+            let run_db = state.open().unwrap();
+            let differences = pipeline::compare::compare_configs(
+                &run_db.configs(&a).unwrap(),
+                &run_db.configs(&b).unwrap(),
+            );
+            println!("process\tonly in {a}\tonly in {b}");
+            for difference in differences {
+                println!(
+                    "{}\t{}\t{}",
+                    difference.process,
+                    difference.only_in_a.join(" "),
+                    difference.only_in_b.join(" ")
+                );
+            }
+            println!();
+            println!("counter\t{a}\t{b}\tchange");
+            let rows = pipeline::compare::compare_counters(
+                &run_db.counters(&a).unwrap(),
+                &run_db.counters(&b).unwrap(),
+            );
+            for row in rows {
+                let change = if row.a > 0.0 {
+                    format!("{:+.1}%", 100.0 * (row.b - row.a) / row.a)
+                } else {
+                    "-".to_string()
+                };
+                println!("{}\t{}\t{}\t{}", row.name, row.a, row.b, change);
+            }
+        }
+        #[cfg(feature = "run-db")]
+        Command::ComplianceReport {
+            state,
+            run_id,
+            output,
+        } => {
+            let run_db = state.open().unwrap();
+            let report = pipeline::compliance::ComplianceReport::new(
+                &run_id,
+                &run_db.drops(&run_id).unwrap(),
+                pipeline::budget::unix_now(),
+            );
+            let json = serde_json::to_string_pretty(&report).unwrap();
+            match output {
+                Some(output) => fs::write(output, json + "\n").unwrap(),
+                None => println!("{json}"),
+            }
+        }
+        #[cfg(feature = "run-db")]
+        Command::Dedup { command } => match command {
+            DedupCommand::Snapshot {
+                destination,
+                state,
+                http,
+            } => {
+                let store = state.open().unwrap();
+                let num_keys = pipeline::dedup_snapshot::snapshot_to(&*store, &destination, &http)
+                    .await
+                    .unwrap();
+                tracing::info!("Wrote {} keys to {}", num_keys, destination);
+            }
+            DedupCommand::Restore {
+                source,
+                state,
+                http,
+            } => {
+                let store = state.open().unwrap();
+                let num_keys = pipeline::dedup_snapshot::restore_from(&*store, &source, &http)
+                    .await
+                    .unwrap();
+                tracing::info!("Restored {} keys from {}", num_keys, source);
+            }
+        },
+        Command::Doctor {
+            crawl,
+            cluster_idx_filename,
+            queue_names,
+            output_dir,
+            min_free_gib,
+            index_api_url,
+            #[cfg(feature = "s3")]
+            s3_url,
+            queue,
+            state,
+            http,
+        } => {
+            let mut report = DoctorReport::default();
+            let config = pipeline::doctor::check_config(&http, &queue);
+            let config_passed = config.is_ok();
+            report.add("config", config);
+            report.add("state store", pipeline::doctor::check_state_store(&state));
+            let cluster_idx = check_cluster_idx(Path::new(&cluster_idx_filename));
+            let first_cdx_filename = cluster_idx.as_ref().ok().map(|(first, _)| first.clone());
+            report.add(
+                "cluster.idx",
+                cluster_idx.map(|(_, num_entries)| {
+                    format!("{num_entries} chunks in {cluster_idx_filename}")
+                }),
+            );
+            let output_dir = Path::new(&output_dir);
+            report.add("output dir", check_writable(output_dir));
+            report.add("disk space", check_disk_space(output_dir, min_free_gib));
+            match build_http_client(&http) {
+                Ok(http_client) => {
+                    // The backends expect their credentials, which the config check covers.
+                    if config_passed {
+                        let queue_names =
+                            queue_names.iter().map(String::as_str).collect::<Vec<_>>();
+                        report.add(
+                            "queue backend",
+                            pipeline::doctor::check_queue_backend(
+                                &queue,
+                                &queue_names,
+                                &http_client,
+                            )
+                            .await,
+                        );
+                    }
+                    let client =
+                        IndexApiClient::new(&index_api_url, Politeness::default(), &http).unwrap();
+                    report.add("index API", check_index_api(&client, &crawl).await);
+                    if let Some(cdx_filename) = first_cdx_filename {
+                        report.add(
+                            "Common Crawl data",
+                            check_data_url(&http_client, &crawl, &cdx_filename).await,
+                        );
+                    }
+                    #[cfg(feature = "s3")]
+                    if let Some(url) = s3_url.as_deref() {
+                        report.add("S3", pipeline::doctor::check_s3(&http_client, url).await);
+                    }
+                }
+                Err(e) => report.add("HTTP client", Err(e)),
+            }
+            println!("{report}");
+            if !report.all_passed() {
+                std::process::exit(1);
+            }
+        }
+        Command::ReplayBatch {
+            cdx_path,
+            offset,
+            length,
+            lines,
+            http,
+        } => {
+            let source = BatchSource {
+                cdx_path,
+                offset,
+                length,
+                lines: parse_line_ranges(&lines).unwrap(),
+            };
+            let http_client = build_http_client(&http).unwrap();
+            for line in source.replay(&http_client).await.unwrap() {
+                println!("{line}");
+            }
+        }
+        #[cfg(feature = "tui")]
+        Command::Dashboard {
+            metrics_urls,
+            cluster_idx_filename,
+            checkpoint_filename,
+            queue_names,
+            refresh_secs,
+            max_errors,
+        } => {
+            let num_chunks = fs::read_to_string(&cluster_idx_filename)
+                .expect("Should have been able to read the file")
+                .lines()
+                .filter_map(parse_cluster_idx)
+                .count();
+            let mut dashboard =
+                pipeline::dashboard::Dashboard::new(&metrics_urls, num_chunks, max_errors);
+            let http_client = reqwest::Client::builder()
+                .timeout(std::time::Duration::from_secs(1))
+                .build()
+                .unwrap();
+            pipeline::dashboard::run(
+                &http_client,
+                &mut dashboard,
+                Path::new(&checkpoint_filename),
+                &queue_names,
+                std::time::Duration::from_secs(refresh_secs),
+            )
+            .await
+            .unwrap();
+        }
+        #[cfg(feature = "grpc")]
+        Command::Control { url, command } => {
+            let mut client = pipeline::control::ControlClient::connect(&url)
+                .await
+                .unwrap();
+            let status = match command {
+                ControlCommand::Status => client.status().await.unwrap(),
+                ControlCommand::Pause => client.pause().await.unwrap(),
+                ControlCommand::Resume => client.resume().await.unwrap(),
+                ControlCommand::Submit { cdx_filename } => {
+                    let entries = fs::read_to_string(&cdx_filename)
+                        .expect("Should have been able to read the file")
+                        .lines()
+                        .filter(|line| !line.trim().is_empty())
+                        .map(pipeline::cdx::parse_cdx_line)
+                        .collect::<Result<Vec<_>, _>>()
+                        .unwrap();
+                    let job = client.submit_job(&entries).await.unwrap();
+                    println!(
+                        "Submitted {} entries as batch {} to {}",
+                        entries.len(),
+                        job.batch_id,
+                        job.queue_name
+                    );
+                    return;
+                }
+            };
+            println!(
+                "{}: {}, {} batches completed by {} tasks",
+                status.worker,
+                if status.paused { "paused" } else { "running" },
+                status.batches_completed,
+                status.tasks
+            );
+        }
+    }
 }
@@ -0,0 +1,154 @@
+use scraper::{ElementRef, Html, Selector};
+use serde::{Deserialize, Serialize};
+
+use crate::extractor::normalize_whitespace;
+
+/// Longest page context kept per media item, in characters.
+const MAX_CONTEXT_CHARS: usize = 300;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaKind {
+    Image,
+    Video,
+}
+
+/// One image or video referenced by a page, as written by the worker's media mode. The
+/// media itself is never downloaded.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct MediaRecord {
+    pub kind: MediaKind,
+    /// Absolute URL of the media, resolved against the page.
+    pub src: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alt: Option<String>,
+    /// The figure caption, or the text of the enclosing element.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<String>,
+    pub page_url: String,
+    pub page_id: String,
+    pub timestamp: String,
+}
+
+/// A media item found in a page, before it is tied to the capture.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaItem {
+    pub kind: MediaKind,
+    pub src: String,
+    pub alt: Option<String>,
+    pub context: Option<String>,
+}
+
+fn element_text(element: ElementRef) -> Option<String> {
+    let text = normalize_whitespace(&element.text().collect::<Vec<_>>().join(" "));
+    let text = text.replace('\n', " ");
+    (!text.is_empty()).then(|| text.chars().take(MAX_CONTEXT_CHARS).collect())
+}
+
+/// The caption of the enclosing `<figure>`, or else the text of the parent element unless
+/// that is the whole page.
+fn context(element: ElementRef) -> Option<String> {
+    let captions = Selector::parse("figcaption").unwrap();
+    let mut ancestors = element.ancestors().filter_map(ElementRef::wrap);
+    if let Some(figure) = ancestors.clone().find(|a| a.value().name() == "figure") {
+        if let Some(caption) = figure.select(&captions).next() {
+            return element_text(caption);
+        }
+    }
+    ancestors
+        .next()
+        .filter(|parent| !matches!(parent.value().name(), "body" | "html"))
+        .and_then(element_text)
+}
+
+/// Finds the `<img>` and `<video>` elements of a page with their alt text and context.
+/// Inline `data:` media and URLs that do not resolve are skipped.
+pub fn extract_media(html: &str, page_url: &str) -> Vec<MediaItem> {
+    let Ok(base) = url::Url::parse(page_url) else {
+        return Vec::new();
+    };
+    let document = Html::parse_document(html);
+    let media = Selector::parse("img, video").unwrap();
+    let sources = Selector::parse("source[src]").unwrap();
+    let mut items = Vec::new();
+    for element in document.select(&media) {
+        let value = element.value();
+        let (kind, src, alt) = if value.name() == "img" {
+            let src = value.attr("src").or_else(|| value.attr("data-src"));
+            (MediaKind::Image, src, value.attr("alt"))
+        } else {
+            let src = value
+                .attr("src")
+                .or_else(|| element.select(&sources).next()?.value().attr("src"));
+            let title = value.attr("title").or_else(|| value.attr("aria-label"));
+            (MediaKind::Video, src, title)
+        };
+        let Some(src) = src.map(str::trim).filter(|src| !src.starts_with("data:")) else {
+            continue;
+        };
+        let Ok(src) = base.join(src) else {
+            continue;
+        };
+        items.push(MediaItem {
+            kind,
+            src: src.to_string(),
+            alt: alt
+                .map(|alt| alt.trim().to_string())
+                .filter(|alt| !alt.is_empty()),
+            context: context(element),
+        });
+    }
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::media::{extract_media, MediaKind};
+
+    #[test]
+    fn extracts_images_and_videos_with_context() {
+        let items = extract_media(
+            r#"<html><body>
+<figure><img src="/cat.jpg" alt=" A cat "><figcaption>The office cat</figcaption></figure>
+<p>Look at this <img data-src="dog.png"> dog</p>
+<img src="data:image/png;base64,AAAA">
+<video title="Demo"><source src="https://cdn.example.com/demo.mp4"></video>
+</body></html>"#,
+            "https://example.com/pets/index.html",
+        );
+        let summary = items
+            .iter()
+            .map(|item| {
+                (
+                    item.kind,
+                    item.src.as_str(),
+                    item.alt.as_deref(),
+                    item.context.as_deref(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    MediaKind::Image,
+                    "https://example.com/cat.jpg",
+                    Some("A cat"),
+                    Some("The office cat")
+                ),
+                (
+                    MediaKind::Image,
+                    "https://example.com/pets/dog.png",
+                    None,
+                    Some("Look at this dog")
+                ),
+                (
+                    MediaKind::Video,
+                    "https://cdn.example.com/demo.mp4",
+                    Some("Demo"),
+                    None
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,279 @@
+use std::{
+    fmt::Display,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::histogram::SizeHistogram;
+
+/// The process-wide statistics served on `/metrics` next to those of autometrics, in this
+/// order.
+pub static EXPORTED_METRICS: &[&dyn ExportMetrics] = &[
+    &crate::traffic::TRAFFIC,
+    &crate::handoff::BATCHER_HANDOFF,
+    &crate::cdx::CDX_DECODING,
+    &crate::feedback::ADAPTIVE_SAMPLING,
+    &crate::histogram::PAYLOAD_SIZES,
+    &PIPELINE_METRICS,
+    #[cfg(feature = "rabbitmq")]
+    &crate::rabbitmq::BROKER_FLOW_CONTROL,
+    #[cfg(feature = "rabbitmq")]
+    &crate::rabbitmq::PUBLISH_CONFIRMS,
+];
+
+/// Statistics that write themselves to [`PrometheusText`]. Process-wide ones are listed
+/// in [`EXPORTED_METRICS`].
+pub trait ExportMetrics: Sync {
+    fn export(&self, text: &mut PrometheusText);
+}
+
+/// The Prometheus text format of [`EXPORTED_METRICS`].
+pub fn exported_metrics_text() -> String {
+    let mut text = PrometheusText::default();
+    for metrics in EXPORTED_METRICS {
+        metrics.export(&mut text);
+    }
+    text.into_string()
+}
+
+/// The Prometheus text format of `metrics` alone.
+pub fn prometheus_text(metrics: &dyn ExportMetrics) -> String {
+    let mut text = PrometheusText::default();
+    metrics.export(&mut text);
+    text.into_string()
+}
+
+/// Metrics in the Prometheus text format, each with its `HELP` and `TYPE` lines.
+#[derive(Debug, Default)]
+pub struct PrometheusText {
+    text: String,
+}
+
+impl PrometheusText {
+    pub fn counter(&mut self, name: &str, help: &str, value: impl Display) {
+        self.metric(name, "counter", help, value);
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, value: impl Display) {
+        self.metric(name, "gauge", help, value);
+    }
+
+    fn metric(&mut self, name: &str, kind: &str, help: &str, value: impl Display) {
+        self.text.push_str(&format!(
+            "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"
+        ));
+    }
+
+    /// A counter with one sample per value of `label`, e.g. `kind="5xx"`.
+    pub fn labeled_counter<'a>(
+        &mut self,
+        name: &str,
+        help: &str,
+        label: &str,
+        samples: impl IntoIterator<Item = (&'a str, u64)>,
+    ) {
+        self.text
+            .push_str(&format!("# HELP {name} {help}\n# TYPE {name} counter\n"));
+        for (value, count) in samples {
+            self.text
+                .push_str(&format!("{name}{{{label}=\"{value}\"}} {count}\n"));
+        }
+    }
+
+    pub fn histogram(&mut self, name: &str, help: &str, histogram: &SizeHistogram) {
+        self.text.push_str(&histogram.to_prometheus(name, help));
+    }
+
+    pub fn into_string(self) -> String {
+        self.text
+    }
+}
+
+/// What went wrong with a request to Common Crawl.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpErrorKind {
+    /// Answered with a 4xx status, including 429.
+    ClientError,
+    /// Answered with a 5xx status.
+    ServerError,
+    /// No answer, or the connection broke off while reading the body.
+    Network,
+}
+
+impl HttpErrorKind {
+    pub const ALL: [HttpErrorKind; 3] = [
+        HttpErrorKind::ClientError,
+        HttpErrorKind::ServerError,
+        HttpErrorKind::Network,
+    ];
+
+    pub fn of_status(status: reqwest::StatusCode) -> Self {
+        if status.is_server_error() {
+            HttpErrorKind::ServerError
+        } else {
+            HttpErrorKind::ClientError
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HttpErrorKind::ClientError => "4xx",
+            HttpErrorKind::ServerError => "5xx",
+            HttpErrorKind::Network => "network",
+        }
+    }
+}
+
+/// Process-wide counters of each pipeline stage, next to [`crate::traffic::TRAFFIC`] for
+/// the downloaded bytes. The batcher updates the CDX and publish counters, the worker the
+/// consumed batches, and both the HTTP errors.
+pub struct PipelineMetrics {
+    cdx_lines_parsed: AtomicU64,
+    cdx_lines_selected: AtomicU64,
+    batches_published: AtomicU64,
+    batches_consumed: AtomicU64,
+    http_errors: [AtomicU64; 3],
+    /// Microseconds from handing a batch to the broker until it was confirmed, the
+    /// buckets of [`SizeHistogram`] spanning 64 µs to about a minute.
+    publish_latency: SizeHistogram,
+}
+
+pub static PIPELINE_METRICS: PipelineMetrics = PipelineMetrics {
+    cdx_lines_parsed: AtomicU64::new(0),
+    cdx_lines_selected: AtomicU64::new(0),
+    batches_published: AtomicU64::new(0),
+    batches_consumed: AtomicU64::new(0),
+    http_errors: [const { AtomicU64::new(0) }; 3],
+    publish_latency: SizeHistogram::new(),
+};
+
+impl PipelineMetrics {
+    /// Records a CDX chunk of which `num_selected` of `num_parsed` lines passed the filters.
+    pub fn record_cdx_lines(&self, num_parsed: u64, num_selected: u64) {
+        self.cdx_lines_parsed
+            .fetch_add(num_parsed, Ordering::Relaxed);
+        self.cdx_lines_selected
+            .fetch_add(num_selected, Ordering::Relaxed);
+    }
+
+    pub fn record_published(&self, latency: Duration) {
+        self.batches_published.fetch_add(1, Ordering::Relaxed);
+        self.publish_latency.observe(latency.as_micros() as u64);
+    }
+
+    pub fn record_consumed(&self) {
+        self.batches_consumed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_http_error(&self, kind: HttpErrorKind) {
+        self.http_errors[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn cdx_lines_parsed(&self) -> u64 {
+        self.cdx_lines_parsed.load(Ordering::Relaxed)
+    }
+
+    pub fn cdx_lines_selected(&self) -> u64 {
+        self.cdx_lines_selected.load(Ordering::Relaxed)
+    }
+
+    pub fn batches_published(&self) -> u64 {
+        self.batches_published.load(Ordering::Relaxed)
+    }
+
+    pub fn batches_consumed(&self) -> u64 {
+        self.batches_consumed.load(Ordering::Relaxed)
+    }
+
+    pub fn http_errors(&self, kind: HttpErrorKind) -> u64 {
+        self.http_errors[kind as usize].load(Ordering::Relaxed)
+    }
+}
+
+impl ExportMetrics for PipelineMetrics {
+    fn export(&self, text: &mut PrometheusText) {
+        text.counter(
+            "pipeline_cdx_lines_parsed_total",
+            "CDX lines parsed by the batcher.",
+            self.cdx_lines_parsed(),
+        );
+        text.counter(
+            "pipeline_cdx_lines_filtered_total",
+            "CDX lines the batcher's filters rejected.",
+            self.cdx_lines_parsed() - self.cdx_lines_selected(),
+        );
+        text.counter(
+            "pipeline_batches_published_total",
+            "Batches the broker confirmed to the batcher.",
+            self.batches_published(),
+        );
+        text.counter(
+            "pipeline_batches_consumed_total",
+            "Batches workers took from the queue.",
+            self.batches_consumed(),
+        );
+        text.labeled_counter(
+            "pipeline_http_errors_total",
+            "Failed requests to Common Crawl.",
+            "kind",
+            HttpErrorKind::ALL.map(|kind| (kind.as_str(), self.http_errors(kind))),
+        );
+        text.histogram(
+            "pipeline_publish_latency_microseconds",
+            "Time until the broker confirmed a published batch.",
+            &self.publish_latency,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::metrics::{exported_metrics_text, prometheus_text, HttpErrorKind, PipelineMetrics};
+
+    #[test]
+    fn exports_stage_counters() {
+        let metrics = PipelineMetrics {
+            cdx_lines_parsed: Default::default(),
+            cdx_lines_selected: Default::default(),
+            batches_published: Default::default(),
+            batches_consumed: Default::default(),
+            http_errors: Default::default(),
+            publish_latency: Default::default(),
+        };
+        metrics.record_cdx_lines(10, 3);
+        metrics.record_published(Duration::from_millis(2));
+        metrics.record_http_error(HttpErrorKind::of_status(
+            reqwest::StatusCode::SERVICE_UNAVAILABLE,
+        ));
+        metrics.record_http_error(HttpErrorKind::of_status(
+            reqwest::StatusCode::TOO_MANY_REQUESTS,
+        ));
+        let text = prometheus_text(&metrics);
+        assert!(text.contains("pipeline_cdx_lines_parsed_total 10\n"));
+        assert!(text.contains("pipeline_cdx_lines_filtered_total 7\n"));
+        assert!(text.contains("pipeline_batches_published_total 1\n"));
+        assert!(text.contains("pipeline_batches_consumed_total 0\n"));
+        assert!(text.contains("pipeline_http_errors_total{kind=\"5xx\"} 1\n"));
+        assert!(text.contains("pipeline_http_errors_total{kind=\"4xx\"} 1\n"));
+        assert!(text.contains("pipeline_http_errors_total{kind=\"network\"} 0\n"));
+        assert!(text.contains("pipeline_publish_latency_microseconds_bucket{le=\"4096\"} 1\n"));
+
+        let text = exported_metrics_text();
+        for name in [
+            "pipeline_requests_total",
+            "pipeline_handoff_depth",
+            "pipeline_cdx_skipped_lines_total",
+            "pipeline_feedback_summaries_total",
+            "pipeline_warc_record_bytes",
+            "pipeline_batches_published_total",
+        ] {
+            assert_eq!(
+                text.matches(&format!("# TYPE {name} ")).count(),
+                1,
+                "{name}"
+            );
+        }
+    }
+}
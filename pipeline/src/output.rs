@@ -0,0 +1,848 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    compression::{cpu_headroom, open_decompressed, Compression, FrameWriter},
+    journal::Journal,
+    provenance::Provenance,
+    warc_response::ResponseHeaders,
+};
+
+/// Namespace for [`document_id`], fixed forever so IDs stay stable across releases.
+const DOCUMENT_ID_NAMESPACE: Uuid = uuid::uuid!("75406049-df74-4740-b7f3-6a8e29005139");
+
+/// An extracted document as written to the output files.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Document {
+    pub id: String,
+    pub url: String,
+    pub timestamp: String,
+    pub language: String,
+    #[serde(default)]
+    pub token_count: usize,
+    #[serde(default)]
+    pub quality_score: f64,
+    /// Set by the external scorer, see [`crate::scorer::BatchScorer`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model_score: Option<f64>,
+    /// Selected headers of the HTTP response the text was extracted from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub headers: Option<ResponseHeaders>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<Provenance>,
+    /// JSON-LD and microdata items of the page, with `--structured-data`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub structured_data: Option<Vec<serde_json::Value>>,
+    /// The extractor the text of an HTML page came from, see
+    /// [`crate::extractor::extract_with_fallback`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extractor: Option<String>,
+    /// The payload digest of the capture from the CDX index.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    /// The ID of an earlier document this one is a near duplicate of, with
+    /// `--near-dedup-action flag`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub near_duplicate_of: Option<String>,
+    pub text: String,
+}
+
+impl Document {
+    pub fn domain(&self) -> Option<String> {
+        url::Url::parse(&self.url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+    }
+}
+
+/// The directories of an output directory that hold other records than documents.
+const SIDECAR_DIRS: &[&str] = &["manifests", "media", "metadata", "robotstxt", "web_graph"];
+
+/// All document files below `dir`, JSONL compressed or not and Parquet, in a stable
+/// order. Hidden entries, such as staged Parquet rows, and the sidecar directories are
+/// skipped.
+pub fn output_files(dir: &Path) -> Result<Vec<PathBuf>, anyhow::Error> {
+    let mut files = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(current) = dirs.pop() {
+        for entry in fs::read_dir(&current)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if name.starts_with('.') {
+                continue;
+            }
+            if path.is_dir() {
+                if current != dir || !SIDECAR_DIRS.contains(&name.as_ref()) {
+                    dirs.push(path);
+                }
+            } else if name.contains(".jsonl") || name.ends_with(".parquet") {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Reads the documents of one output file written by [`LanguageRouter`] or, with the
+/// `parquet` feature, by `parquet_output::ParquetSink`.
+pub fn read_documents(
+    path: &Path,
+) -> Result<Box<dyn Iterator<Item = Result<Document, anyhow::Error>>>, anyhow::Error> {
+    if path.extension().is_some_and(|ext| ext == "parquet") {
+        return read_parquet_documents(path);
+    }
+    let reader = BufReader::new(open_decompressed(path)?);
+    Ok(Box::new(
+        reader
+            .lines()
+            .filter(|line| !line.as_ref().is_ok_and(|line| line.trim().is_empty()))
+            .map(|line| Ok(serde_json::from_str::<Document>(&line?)?)),
+    ))
+}
+
+#[cfg(feature = "parquet")]
+fn read_parquet_documents(
+    path: &Path,
+) -> Result<Box<dyn Iterator<Item = Result<Document, anyhow::Error>>>, anyhow::Error> {
+    let documents = crate::parquet_output::read_document_records(path)?;
+    Ok(Box::new(
+        documents.into_iter().map(|record| Ok(record.into())),
+    ))
+}
+
+#[cfg(not(feature = "parquet"))]
+fn read_parquet_documents(
+    path: &Path,
+) -> Result<Box<dyn Iterator<Item = Result<Document, anyhow::Error>>>, anyhow::Error> {
+    anyhow::bail!(
+        "Cannot read {}, built without the parquet feature",
+        path.display()
+    )
+}
+
+/// A deterministic UUIDv5 over crawl, digest and URL. Every run that processes the
+/// same capture produces the same ID, which makes it the key to join and dedup outputs on.
+pub fn document_id(crawl: &str, digest: &str, url: &str) -> String {
+    let name = format!("{crawl}\n{digest}\n{url}");
+    Uuid::new_v5(&DOCUMENT_ID_NAMESPACE, name.as_bytes()).to_string()
+}
+
+/// Detects the language of `text` as an ISO 639-3 code (the same codes the CDX
+/// `languages` field uses), falling back to the first CDX language if the detection
+/// is not reliable.
+pub fn detect_language(text: &str, cdx_languages: Option<&str>) -> String {
+    match whatlang::detect(text) {
+        Some(info) if info.is_reliable() => info.lang().code().to_string(),
+        _ => cdx_languages
+            .and_then(|languages| languages.split(',').next())
+            .unwrap_or("und")
+            .to_string(),
+    }
+}
+
+/// How the local output files are buffered, flushed and synced, trading durability
+/// against throughput. By default files are flushed at batch boundaries only and left to
+/// the OS to sync, except for the journaled document files, which are synced on commit.
+#[derive(Debug, Clone, clap::Args)]
+pub struct WriteOptions {
+    /// Size of the write buffer of each output file.
+    #[arg(long, default_value_t = 8)]
+    pub write_buffer_kib: usize,
+
+    /// Also flush a file once this long passed since its last flush, so a long batch
+    /// becomes visible to readers while it is written.
+    #[arg(long)]
+    pub flush_interval_secs: Option<u64>,
+
+    /// Sync each output file to disk when it is closed, e.g. as idle or as a full shard.
+    #[arg(long)]
+    pub fsync_on_close: bool,
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        Self {
+            write_buffer_kib: 8,
+            flush_interval_secs: None,
+            fsync_on_close: false,
+        }
+    }
+}
+
+impl WriteOptions {
+    /// Opens `path` for appending, with the configured write buffer.
+    pub fn append(&self, path: &Path) -> Result<BufWriter<File>, anyhow::Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(BufWriter::with_capacity(
+            self.write_buffer_kib.max(1) * 1024,
+            file,
+        ))
+    }
+
+    /// Whether a file last flushed at `last_flush` is due for a flush.
+    pub fn flush_due(&self, last_flush: Instant) -> bool {
+        self.flush_interval_secs
+            .is_some_and(|secs| last_flush.elapsed() >= Duration::from_secs(secs))
+    }
+}
+
+/// A buffered output file of JSON lines that follows [`WriteOptions`].
+pub struct OutputFile {
+    writer: BufWriter<File>,
+    options: WriteOptions,
+    last_flush: Instant,
+}
+
+impl OutputFile {
+    pub fn append(path: &Path, options: &WriteOptions) -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            writer: options.append(path)?,
+            options: options.clone(),
+            last_flush: Instant::now(),
+        })
+    }
+
+    /// Writes `record` as one line, flushing the file if the flush interval passed.
+    pub fn write_line(&mut self, record: &impl Serialize) -> Result<(), anyhow::Error> {
+        serde_json::to_writer(&mut self.writer, record)?;
+        writeln!(self.writer)?;
+        if self.options.flush_due(self.last_flush) {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), anyhow::Error> {
+        self.writer.flush()?;
+        self.last_flush = Instant::now();
+        Ok(())
+    }
+}
+
+impl Drop for OutputFile {
+    fn drop(&mut self) {
+        let result = self.flush().and_then(|()| {
+            if self.options.fsync_on_close {
+                self.writer.get_ref().sync_all()?;
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            tracing::warn!(err.msg = %e, "Failed to close an output file");
+        }
+    }
+}
+
+/// Appends records of another shape than [`Document`] as JSON lines, e.g. for the worker
+/// modes that write robots.txt rules or media URLs instead of documents.
+pub struct RecordWriter<T> {
+    file: OutputFile,
+    _record: std::marker::PhantomData<T>,
+}
+
+impl<T: Serialize> RecordWriter<T> {
+    /// Appends to the file at `path`, after cutting off a partial last line that a process
+    /// left when it died while appending.
+    pub fn create(path: &Path, options: &WriteOptions) -> Result<Self, anyhow::Error> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        truncate_partial_line(path)?;
+        Ok(Self {
+            file: OutputFile::append(path, options)?,
+            _record: std::marker::PhantomData,
+        })
+    }
+
+    pub fn write(&mut self, record: &T) -> Result<(), anyhow::Error> {
+        self.file.write_line(record)
+    }
+
+    pub fn flush(&mut self) -> Result<(), anyhow::Error> {
+        self.file.flush()
+    }
+}
+
+/// Truncates the file at `path`, if there is one, after its last newline.
+fn truncate_partial_line(path: &Path) -> Result<(), anyhow::Error> {
+    let mut file = match OpenOptions::new().read(true).write(true).open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let len = file.metadata()?.len();
+    let mut buffer = vec![0; 64 << 10];
+    let mut end = len;
+    let mut complete = 0;
+    while end > 0 {
+        let start = end.saturating_sub(buffer.len() as u64);
+        let chunk = &mut buffer[..(end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(chunk)?;
+        if let Some(newline) = chunk.iter().rposition(|b| *b == b'\n') {
+            complete = start + newline as u64 + 1;
+            break;
+        }
+        end = start;
+    }
+    if complete < len {
+        tracing::warn!(
+            "Cutting a partial line of {} bytes off {}",
+            len - complete,
+            path.display()
+        );
+        file.set_len(complete)?;
+    }
+    Ok(())
+}
+
+struct OpenWriter {
+    writer: FrameWriter<BufWriter<File>>,
+    last_used: Instant,
+    last_flush: Instant,
+}
+
+impl OpenWriter {
+    /// Ends the current frame and, with [`WriteOptions::fsync_on_close`], syncs the file.
+    fn close(mut self, options: &WriteOptions) -> Result<(), anyhow::Error> {
+        self.writer.finish_frame()?;
+        if options.fsync_on_close {
+            if let Some(writer) = self.writer.get_ref() {
+                writer.get_ref().sync_all()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The format of the document files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// JSON lines, one file per language, see [`LanguageRouter`].
+    Jsonl,
+    /// One Parquet file per batch and language, see `parquet_output::ParquetSink`.
+    Parquet,
+}
+
+/// Where the worker writes its documents, batch by batch. Documents written between
+/// `begin_batch` and `commit_batch` have to be durable once `commit_batch` returns.
+pub trait OutputSink: Send {
+    fn begin_batch(&mut self, batch_id: &str) -> Result<(), anyhow::Error>;
+
+    fn write(&mut self, document: &Document) -> Result<(), anyhow::Error>;
+
+    fn commit_batch(&mut self) -> Result<(), anyhow::Error>;
+
+    /// For sinks that keep files open between batches.
+    fn set_idle_timeout(&mut self, _idle_timeout: Duration) {}
+
+    /// The files closed for good since the last call, which will not be written again,
+    /// e.g. for uploads.
+    fn take_finished_files(&mut self) -> Vec<PathBuf>;
+
+    /// Closes all files for good at the end of a run, they are then finished files too.
+    fn finish(&mut self) -> Result<(), anyhow::Error>;
+}
+
+/// The shard of a language currently written to, see [`LanguageRouter::with_shard_text_bytes`].
+#[derive(Default)]
+struct Shard {
+    index: usize,
+    text_bytes: u64,
+    documents: u64,
+}
+
+/// Writes documents into one JSONL file per language, `<output_dir>/<language>/<name>.jsonl`.
+/// Files are opened on the first document of a language and closed again once they have
+/// not been written to for `idle_timeout`.
+pub struct LanguageRouter {
+    output_dir: PathBuf,
+    name: String,
+    idle_timeout: Duration,
+    writers: HashMap<String, OpenWriter>,
+    journal: Option<Journal>,
+    compression: Compression,
+    shard_text_bytes: Option<u64>,
+    shard_documents: Option<u64>,
+    sort_shards_by_length: bool,
+    shards: HashMap<String, Shard>,
+    write_options: WriteOptions,
+    finished: Vec<PathBuf>,
+}
+
+impl LanguageRouter {
+    pub fn new(output_dir: impl Into<PathBuf>, name: &str, idle_timeout: Duration) -> Self {
+        Self {
+            output_dir: output_dir.into(),
+            name: name.to_string(),
+            idle_timeout,
+            writers: HashMap::new(),
+            journal: None,
+            compression: Compression::None,
+            shard_text_bytes: None,
+            shard_documents: None,
+            sort_shards_by_length: false,
+            shards: HashMap::new(),
+            write_options: WriteOptions::default(),
+            finished: Vec::new(),
+        }
+    }
+
+    /// Buffers, flushes and syncs the document files as `options` say. Flushing midway
+    /// through a batch ends the current compression frame early, which readers handle.
+    pub fn with_write_options(mut self, options: WriteOptions) -> Self {
+        self.write_options = options;
+        self
+    }
+
+    /// Compresses the output files, with a new frame per batch so the journal can still
+    /// roll back to a batch boundary. [`Compression::Auto`] is resolved right away.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression.resolve(cpu_headroom());
+        if compression == Compression::Auto {
+            tracing::info!("Compressing outputs with {}", self.compression);
+        }
+        self
+    }
+
+    pub fn set_idle_timeout(&mut self, idle_timeout: Duration) {
+        self.idle_timeout = idle_timeout;
+    }
+
+    /// Records every output file touched by a batch in `journal`, see [`Journal`].
+    pub fn with_journal(mut self, journal: Journal) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
+    /// Packs the output into shards of about `text_bytes` of document text each,
+    /// `<name>-00000.jsonl`, `<name>-00001.jsonl` and so on, so training loaders get
+    /// evenly sized files. Shards are only closed at batch boundaries and can overshoot by
+    /// up to one batch.
+    pub fn with_shard_text_bytes(mut self, text_bytes: u64) -> Self {
+        self.shard_text_bytes = Some(text_bytes);
+        self
+    }
+
+    /// Packs the output into shards of about `documents` documents each, like
+    /// [`LanguageRouter::with_shard_text_bytes`]. With both, a shard is full at whichever
+    /// limit it reaches first.
+    pub fn with_shard_documents(mut self, documents: u64) -> Self {
+        self.shard_documents = Some(documents);
+        self
+    }
+
+    fn is_sharded(&self) -> bool {
+        self.shard_text_bytes.is_some() || self.shard_documents.is_some()
+    }
+
+    /// Rewrites every full shard with its documents ordered by text length, shortest
+    /// first, which lets loaders build batches with little padding. Needs
+    /// [`LanguageRouter::with_shard_text_bytes`].
+    pub fn with_sort_shards_by_length(mut self, sort: bool) -> Self {
+        self.sort_shards_by_length = sort;
+        self
+    }
+
+    fn path(&self, language: &str) -> PathBuf {
+        let name = match self.is_sharded() {
+            true => format!(
+                "{}-{:05}",
+                self.name,
+                self.shards.get(language).map_or(0, |shard| shard.index)
+            ),
+            false => self.name.clone(),
+        };
+        self.output_dir.join(language).join(format!(
+            "{}.jsonl{}",
+            name,
+            self.compression.extension()
+        ))
+    }
+
+    pub fn begin_batch(&mut self, batch_id: &str) -> Result<(), anyhow::Error> {
+        self.flush()?;
+        let paths = self
+            .writers
+            .keys()
+            .map(|language| self.path(language))
+            .collect::<Vec<_>>();
+        if let Some(journal) = self.journal.as_mut() {
+            journal.begin(batch_id)?;
+            for path in paths {
+                journal.record_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes all documents of the current batch durable and clears the journal.
+    pub fn commit_batch(&mut self) -> Result<(), anyhow::Error> {
+        self.flush()?;
+        if let Some(journal) = self.journal.as_mut() {
+            for open_writer in self.writers.values() {
+                if let Some(writer) = open_writer.writer.get_ref() {
+                    writer.get_ref().sync_data()?;
+                }
+            }
+            journal.commit()?;
+        }
+        self.close_full_shards()
+    }
+
+    /// Closes the shards that reached the configured text size or document count, the
+    /// next document of their language starts a new one.
+    fn close_full_shards(&mut self) -> Result<(), anyhow::Error> {
+        let max_text_bytes = self.shard_text_bytes.unwrap_or(u64::MAX);
+        let max_documents = self.shard_documents.unwrap_or(u64::MAX);
+        let full = self
+            .shards
+            .iter()
+            .filter(|(_, shard)| {
+                shard.text_bytes >= max_text_bytes || shard.documents >= max_documents
+            })
+            .map(|(language, _)| language.clone())
+            .collect::<Vec<_>>();
+        for language in full {
+            self.close_shard(&language)?;
+        }
+        Ok(())
+    }
+
+    fn close_shard(&mut self, language: &str) -> Result<(), anyhow::Error> {
+        if let Some(open_writer) = self.writers.remove(language) {
+            open_writer.close(&self.write_options)?;
+        }
+        let path = self.path(language);
+        if self.sort_shards_by_length {
+            sort_by_length(&path, self.compression)?;
+        }
+        tracing::info!("Closed full shard {}", path.display());
+        self.finished.push(path);
+        let shard = self.shards.get_mut(language).unwrap();
+        shard.index += 1;
+        shard.text_bytes = 0;
+        shard.documents = 0;
+        Ok(())
+    }
+
+    pub fn write(&mut self, document: &Document) -> Result<(), anyhow::Error> {
+        self.close_idle()?;
+        if !self.writers.contains_key(&document.language) {
+            let path = self.path(&document.language);
+            fs::create_dir_all(path.parent().unwrap())?;
+            if let Some(journal) = self.journal.as_mut() {
+                journal.record_file(&path)?;
+            }
+            let file = self.write_options.append(&path)?;
+            tracing::info!("Opened output for language {}", document.language);
+            self.writers.insert(
+                document.language.clone(),
+                OpenWriter {
+                    writer: FrameWriter::new(self.compression, file),
+                    last_used: Instant::now(),
+                    last_flush: Instant::now(),
+                },
+            );
+        }
+        let open_writer = self.writers.get_mut(&document.language).unwrap();
+        serde_json::to_writer(&mut open_writer.writer, document)?;
+        writeln!(open_writer.writer)?;
+        open_writer.last_used = Instant::now();
+        if self.write_options.flush_due(open_writer.last_flush) {
+            open_writer.writer.finish_frame()?;
+            open_writer.last_flush = Instant::now();
+        }
+        if self.is_sharded() {
+            let shard = self.shards.entry(document.language.clone()).or_default();
+            shard.text_bytes += document.text.len() as u64;
+            shard.documents += 1;
+        }
+        Ok(())
+    }
+
+    pub fn close_idle(&mut self) -> Result<(), anyhow::Error> {
+        let idle = self
+            .writers
+            .iter()
+            .filter(|(_, w)| w.last_used.elapsed() >= self.idle_timeout)
+            .map(|(language, _)| language.clone())
+            .collect::<Vec<_>>();
+        for language in idle {
+            if let Some(open_writer) = self.writers.remove(&language) {
+                open_writer.close(&self.write_options)?;
+                tracing::info!("Closed idle output for language {}", language);
+            }
+        }
+        Ok(())
+    }
+
+    /// Flushes all files, ending their current compression frame.
+    pub fn flush(&mut self) -> Result<(), anyhow::Error> {
+        for open_writer in self.writers.values_mut() {
+            open_writer.writer.finish_frame()?;
+            open_writer.last_flush = Instant::now();
+        }
+        Ok(())
+    }
+
+    pub fn num_open_writers(&self) -> usize {
+        self.writers.len()
+    }
+}
+
+impl OutputSink for LanguageRouter {
+    fn begin_batch(&mut self, batch_id: &str) -> Result<(), anyhow::Error> {
+        LanguageRouter::begin_batch(self, batch_id)
+    }
+
+    fn write(&mut self, document: &Document) -> Result<(), anyhow::Error> {
+        LanguageRouter::write(self, document)
+    }
+
+    fn commit_batch(&mut self) -> Result<(), anyhow::Error> {
+        LanguageRouter::commit_batch(self)
+    }
+
+    fn set_idle_timeout(&mut self, idle_timeout: Duration) {
+        LanguageRouter::set_idle_timeout(self, idle_timeout)
+    }
+
+    /// Without [`LanguageRouter::with_shard_text_bytes`], files are appended to again after
+    /// an idle close, so they are only finished by [`OutputSink::finish`].
+    fn take_finished_files(&mut self) -> Vec<PathBuf> {
+        std::mem::take(&mut self.finished)
+    }
+
+    fn finish(&mut self) -> Result<(), anyhow::Error> {
+        if self.is_sharded() {
+            let languages = self
+                .shards
+                .iter()
+                .filter(|(_, shard)| shard.documents > 0)
+                .map(|(language, _)| language.clone())
+                .collect::<Vec<_>>();
+            for language in languages {
+                self.close_shard(&language)?;
+            }
+            return Ok(());
+        }
+        for (language, open_writer) in std::mem::take(&mut self.writers) {
+            open_writer.close(&self.write_options)?;
+            self.finished.push(self.path(&language));
+        }
+        Ok(())
+    }
+}
+
+/// Rewrites a closed output file with its documents ordered by text length. Lines are
+/// copied as they are, so fields this version does not know survive.
+fn sort_by_length(path: &Path, compression: Compression) -> Result<(), anyhow::Error> {
+    let mut lines = Vec::new();
+    for line in BufReader::new(open_decompressed(path)?).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let text_len = serde_json::from_str::<Document>(&line)?.text.len();
+        lines.push((text_len, line));
+    }
+    lines.sort_by_key(|(text_len, _)| *text_len);
+    // Named after the file, so the tasks of one process sorting at once do not collide.
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp_path = path.with_file_name(format!(".{file_name}.sort"));
+    let mut writer = FrameWriter::new(compression, BufWriter::new(File::create(&tmp_path)?));
+    for (_, line) in &lines {
+        writeln!(writer, "{line}")?;
+    }
+    writer.finish_frame()?;
+    writer.get_ref().unwrap().get_ref().sync_all()?;
+    drop(writer);
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        compression::Compression,
+        output::{
+            detect_language, document_id, output_files, read_documents, Document, LanguageRouter,
+            OutputSink, RecordWriter, WriteOptions,
+        },
+    };
+
+    #[test]
+    fn document_ids_are_stable() {
+        let id = document_id(
+            "CC-MAIN-2024-30",
+            "5JOQMMSNM6N7UCLGGYXDSPSB3FYAQS2C",
+            "https://example.com/",
+        );
+        assert_eq!(
+            id,
+            document_id(
+                "CC-MAIN-2024-30",
+                "5JOQMMSNM6N7UCLGGYXDSPSB3FYAQS2C",
+                "https://example.com/"
+            )
+        );
+        assert_ne!(
+            id,
+            document_id(
+                "CC-MAIN-2024-26",
+                "5JOQMMSNM6N7UCLGGYXDSPSB3FYAQS2C",
+                "https://example.com/"
+            )
+        );
+    }
+
+    #[test]
+    fn detects_language_with_cdx_fallback() {
+        let text =
+            "This is a longer English sentence which should be detected without any problems, \
+            because it contains many very common English words and is written in plain language.";
+        assert_eq!(detect_language(text, Some("deu")), "eng");
+        assert_eq!(detect_language("", Some("deu,eng")), "deu");
+        assert_eq!(detect_language("", None), "und");
+    }
+
+    #[test]
+    fn routes_documents_per_language_and_closes_idle_writers() {
+        let dir = std::env::temp_dir().join(format!("pipeline-router-{}", std::process::id()));
+        let mut router = LanguageRouter::new(&dir, "worker", Duration::ZERO);
+        for language in ["eng", "deu"] {
+            router
+                .write(&Document {
+                    id: "id".to_string(),
+                    url: "https://example.com/".to_string(),
+                    timestamp: "20240722120756".to_string(),
+                    language: language.to_string(),
+                    token_count: 1,
+                    text: "text".to_string(),
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+        router.close_idle().unwrap();
+        assert_eq!(router.num_open_writers(), 0);
+        assert!(dir.join("eng/worker.jsonl").exists());
+        assert!(dir.join("deu/worker.jsonl").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reads_back_compressed_output() {
+        let dir = std::env::temp_dir().join(format!("pipeline-zstd-{}", std::process::id()));
+        let mut router = LanguageRouter::new(&dir, "worker", Duration::from_secs(300))
+            .with_compression(Compression::Zstd(3));
+        for batch in ["batch-1", "batch-2"] {
+            router.begin_batch(batch).unwrap();
+            router
+                .write(&Document {
+                    id: batch.to_string(),
+                    url: "https://example.com/".to_string(),
+                    timestamp: "20240722120756".to_string(),
+                    language: "eng".to_string(),
+                    token_count: 1,
+                    text: "text".to_string(),
+                    ..Default::default()
+                })
+                .unwrap();
+            router.commit_batch().unwrap();
+        }
+        let files = output_files(&dir).unwrap();
+        assert_eq!(files, vec![dir.join("eng/worker.jsonl.zst")]);
+        let ids = read_documents(&files[0])
+            .unwrap()
+            .map(|document| document.unwrap().id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec!["batch-1", "batch-2"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn packs_shards_by_text_bytes_and_sorts_them() {
+        let dir = std::env::temp_dir().join(format!("pipeline-shards-{}", std::process::id()));
+        let mut router = LanguageRouter::new(&dir, "worker", Duration::from_secs(300))
+            .with_shard_text_bytes(10)
+            .with_sort_shards_by_length(true);
+        for (batch, texts) in [("batch-1", ["long text", "tiny"]), ("batch-2", ["ab", "a"])] {
+            router.begin_batch(batch).unwrap();
+            for text in texts {
+                router
+                    .write(&Document {
+                        id: text.to_string(),
+                        url: "https://example.com/".to_string(),
+                        timestamp: "20240722120756".to_string(),
+                        language: "eng".to_string(),
+                        token_count: 1,
+                        text: text.to_string(),
+                        ..Default::default()
+                    })
+                    .unwrap();
+            }
+            router.commit_batch().unwrap();
+        }
+        assert_eq!(
+            router.take_finished_files(),
+            vec![dir.join("eng/worker-00000.jsonl")]
+        );
+        assert!(router.take_finished_files().is_empty());
+        router.finish().unwrap();
+        assert_eq!(
+            router.take_finished_files(),
+            vec![dir.join("eng/worker-00001.jsonl")]
+        );
+        let files = output_files(&dir).unwrap();
+        assert_eq!(
+            files,
+            vec![
+                dir.join("eng/worker-00000.jsonl"),
+                dir.join("eng/worker-00001.jsonl")
+            ]
+        );
+        let ids = read_documents(&files[0])
+            .unwrap()
+            .map(|document| document.unwrap().id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec!["tiny", "long text"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn flushes_records_by_interval_and_on_close() {
+        let dir = std::env::temp_dir().join(format!("pipeline-flush-{}", std::process::id()));
+        let path = dir.join("records.jsonl");
+        let mut writer = RecordWriter::create(&path, &WriteOptions::default()).unwrap();
+        writer.write(&"buffered").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+        drop(writer);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "\"buffered\"\n");
+
+        let options = WriteOptions {
+            write_buffer_kib: 64,
+            flush_interval_secs: Some(0),
+            fsync_on_close: true,
+        };
+        let mut writer = RecordWriter::create(&path, &options).unwrap();
+        writer.write(&"flushed").unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "\"buffered\"\n\"flushed\"\n"
+        );
+        drop(writer);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,648 @@
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use parquet::{
+    basic::{GzipLevel, ZstdLevel},
+    data_type::{ByteArray, ByteArrayType, Int64Type},
+    file::{
+        properties::WriterProperties,
+        reader::{FileReader, SerializedFileReader},
+        writer::SerializedFileWriter,
+    },
+    schema::parser::parse_message_type,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    cdx::CdxEntry,
+    compression::{cpu_headroom, Compression},
+    journal::Journal,
+    output::{read_documents, Document, LanguageRouter, OutputSink},
+};
+
+/// Values of one optional Parquet column, `None` is written as null.
+pub enum ColumnValues {
+    Utf8(Vec<Option<String>>),
+    Int64(Vec<Option<i64>>),
+}
+
+/// A row type that can be written with [`write_parquet`]. `SCHEMA` is a Parquet message
+/// type of optional columns, and `to_columns` has to return them in the same order.
+pub trait ParquetRecord: Sized {
+    const SCHEMA: &'static str;
+
+    fn to_columns(records: &[Self]) -> Vec<ColumnValues>;
+}
+
+/// The Parquet codec for `compression`, Snappy if none is given.
+fn parquet_codec(
+    compression: Option<Compression>,
+) -> Result<parquet::basic::Compression, anyhow::Error> {
+    use parquet::basic::Compression as Codec;
+    Ok(match compression.map(|c| c.resolve(cpu_headroom())) {
+        None => Codec::SNAPPY,
+        Some(Compression::None | Compression::Auto) => Codec::UNCOMPRESSED,
+        Some(Compression::Gzip(level)) => Codec::GZIP(GzipLevel::try_new(level)?),
+        Some(Compression::Zstd(level)) => Codec::ZSTD(ZstdLevel::try_new(level)?),
+        Some(Compression::Lz4) => Codec::LZ4_RAW,
+    })
+}
+
+pub fn write_parquet<R: ParquetRecord>(
+    path: &Path,
+    records: &[R],
+    row_group_size: usize,
+    compression: Option<Compression>,
+) -> Result<(), anyhow::Error> {
+    write_row_groups(path, records, row_group_size, parquet_codec(compression)?)
+}
+
+fn write_row_groups<R: ParquetRecord>(
+    path: &Path,
+    records: &[R],
+    row_group_size: usize,
+    codec: parquet::basic::Compression,
+) -> Result<(), anyhow::Error> {
+    let schema = Arc::new(parse_message_type(R::SCHEMA)?);
+    let props = Arc::new(WriterProperties::builder().set_compression(codec).build());
+    let mut writer = SerializedFileWriter::new(File::create(path)?, schema, props)?;
+    for chunk in records.chunks(row_group_size.max(1)) {
+        let mut row_group = writer.next_row_group()?;
+        for values in R::to_columns(chunk) {
+            let mut column = row_group
+                .next_column()?
+                .ok_or_else(|| anyhow::anyhow!("More columns than in the schema"))?;
+            match values {
+                ColumnValues::Utf8(values) => {
+                    let def_levels = def_levels(&values);
+                    let values = values
+                        .into_iter()
+                        .flatten()
+                        .map(|v| ByteArray::from(v.as_str()))
+                        .collect::<Vec<_>>();
+                    column.typed::<ByteArrayType>().write_batch(
+                        &values,
+                        Some(&def_levels),
+                        None,
+                    )?;
+                }
+                ColumnValues::Int64(values) => {
+                    let def_levels = def_levels(&values);
+                    let values = values.into_iter().flatten().collect::<Vec<_>>();
+                    column
+                        .typed::<Int64Type>()
+                        .write_batch(&values, Some(&def_levels), None)?;
+                }
+            }
+            column.close()?;
+        }
+        row_group.close()?;
+    }
+    writer.close()?;
+    Ok(())
+}
+
+fn def_levels<T>(values: &[Option<T>]) -> Vec<i16> {
+    values.iter().map(|v| i16::from(v.is_some())).collect()
+}
+
+/// The index metadata of one capture, as written by the worker's metadata-only mode.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MetadataRecord {
+    pub url: String,
+    pub domain: Option<String>,
+    pub surt_url: String,
+    pub timestamp: String,
+    pub languages: Option<String>,
+    pub mime: Option<String>,
+    pub status: i64,
+    pub digest: Option<String>,
+    pub filename: String,
+    pub offset: i64,
+    pub length: i64,
+}
+
+impl From<&CdxEntry> for MetadataRecord {
+    fn from(entry: &CdxEntry) -> Self {
+        Self {
+            url: entry.metadata.url.clone(),
+            domain: url::Url::parse(&entry.metadata.url)
+                .ok()
+                .and_then(|url| url.host_str().map(str::to_string)),
+            surt_url: entry.surt_url.clone(),
+            timestamp: entry.timestamp.clone(),
+            languages: entry.metadata.languages.clone(),
+            mime: entry
+                .metadata
+                .mime_detected
+                .clone()
+                .or_else(|| entry.metadata.mime.clone()),
+            status: entry.metadata.status.into(),
+            digest: entry.metadata.digest.clone(),
+            filename: entry.metadata.filename.clone(),
+            offset: entry.metadata.offset as i64,
+            length: entry.metadata.length as i64,
+        }
+    }
+}
+
+impl ParquetRecord for MetadataRecord {
+    const SCHEMA: &'static str = "
+        message metadata {
+            OPTIONAL BYTE_ARRAY url (UTF8);
+            OPTIONAL BYTE_ARRAY domain (UTF8);
+            OPTIONAL BYTE_ARRAY surt_url (UTF8);
+            OPTIONAL BYTE_ARRAY timestamp (UTF8);
+            OPTIONAL BYTE_ARRAY languages (UTF8);
+            OPTIONAL BYTE_ARRAY mime (UTF8);
+            OPTIONAL INT64 status;
+            OPTIONAL BYTE_ARRAY digest (UTF8);
+            OPTIONAL BYTE_ARRAY filename (UTF8);
+            OPTIONAL INT64 offset;
+            OPTIONAL INT64 length;
+        }
+    ";
+
+    fn to_columns(records: &[Self]) -> Vec<ColumnValues> {
+        let utf8 =
+            |f: fn(&Self) -> Option<String>| ColumnValues::Utf8(records.iter().map(f).collect());
+        let int64 =
+            |f: fn(&Self) -> i64| ColumnValues::Int64(records.iter().map(|r| Some(f(r))).collect());
+        vec![
+            utf8(|r| Some(r.url.clone())),
+            utf8(|r| r.domain.clone()),
+            utf8(|r| Some(r.surt_url.clone())),
+            utf8(|r| Some(r.timestamp.clone())),
+            utf8(|r| r.languages.clone()),
+            utf8(|r| r.mime.clone()),
+            int64(|r| r.status),
+            utf8(|r| r.digest.clone()),
+            utf8(|r| Some(r.filename.clone())),
+            int64(|r| r.offset),
+            int64(|r| r.length),
+        ]
+    }
+}
+
+/// The columns of a document in [`ParquetSink`] files.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DocumentRecord {
+    pub id: String,
+    pub url: String,
+    pub timestamp: String,
+    pub language: String,
+    pub digest: Option<String>,
+    pub near_duplicate_of: Option<String>,
+    pub token_count: i64,
+    pub text: String,
+}
+
+impl From<&Document> for DocumentRecord {
+    fn from(document: &Document) -> Self {
+        Self {
+            id: document.id.clone(),
+            url: document.url.clone(),
+            timestamp: document.timestamp.clone(),
+            language: document.language.clone(),
+            digest: document.digest.clone(),
+            near_duplicate_of: document.near_duplicate_of.clone(),
+            token_count: document.token_count as i64,
+            text: document.text.clone(),
+        }
+    }
+}
+
+/// The columns that are not stored are left empty.
+impl From<DocumentRecord> for Document {
+    fn from(record: DocumentRecord) -> Self {
+        Self {
+            id: record.id,
+            url: record.url,
+            timestamp: record.timestamp,
+            language: record.language,
+            token_count: record.token_count as usize,
+            quality_score: 0.0,
+            model_score: None,
+            headers: None,
+            provenance: None,
+            structured_data: None,
+            extractor: None,
+            digest: record.digest,
+            near_duplicate_of: record.near_duplicate_of,
+            text: record.text,
+        }
+    }
+}
+
+impl ParquetRecord for DocumentRecord {
+    const SCHEMA: &'static str = "
+        message document {
+            OPTIONAL BYTE_ARRAY id (UTF8);
+            OPTIONAL BYTE_ARRAY url (UTF8);
+            OPTIONAL BYTE_ARRAY timestamp (UTF8);
+            OPTIONAL BYTE_ARRAY language (UTF8);
+            OPTIONAL BYTE_ARRAY digest (UTF8);
+            OPTIONAL BYTE_ARRAY near_duplicate_of (UTF8);
+            OPTIONAL INT64 token_count;
+            OPTIONAL BYTE_ARRAY text (UTF8);
+        }
+    ";
+
+    fn to_columns(records: &[Self]) -> Vec<ColumnValues> {
+        let utf8 =
+            |f: fn(&Self) -> Option<String>| ColumnValues::Utf8(records.iter().map(f).collect());
+        vec![
+            utf8(|r| Some(r.id.clone())),
+            utf8(|r| Some(r.url.clone())),
+            utf8(|r| Some(r.timestamp.clone())),
+            utf8(|r| Some(r.language.clone())),
+            utf8(|r| r.digest.clone()),
+            utf8(|r| r.near_duplicate_of.clone()),
+            ColumnValues::Int64(records.iter().map(|r| Some(r.token_count)).collect()),
+            utf8(|r| Some(r.text.clone())),
+        ]
+    }
+}
+
+/// Where [`ParquetSink`] stages the committed documents, below the output directory.
+const STAGING_DIR: &str = ".parquet-staging";
+
+/// Writes the documents to `<output_dir>/<language>/<name>-00000.parquet` and so on, for
+/// loading the output straight into analytics tools. The committed documents of each
+/// language are staged in a JSONL shard below `<output_dir>/.parquet-staging/<name>`,
+/// which the journal rolls back like any other output. Once a shard holds
+/// `max_documents` documents or `max_text_bytes` of text, or at the end of the run, it is
+/// converted into a Parquet file under a temporary name first, so files span many
+/// batches and row groups fill up, and a Parquet file only ever holds whole batches.
+///
+/// The sink locks `<name>.lock` in the staging directory while it runs, so
+/// [`recover_staged`] leaves the shards of running workers alone.
+pub struct ParquetSink {
+    output_dir: PathBuf,
+    row_group_size: usize,
+    compression: Option<Compression>,
+    staging: LanguageRouter,
+    lock_path: PathBuf,
+    _lock: File,
+    finished: Vec<PathBuf>,
+}
+
+impl ParquetSink {
+    pub fn new(output_dir: impl Into<PathBuf>, name: &str) -> Result<Self, anyhow::Error> {
+        let output_dir = output_dir.into();
+        let staging_dir = output_dir.join(STAGING_DIR);
+        fs::create_dir_all(&staging_dir)?;
+        let lock_path = staging_dir.join(format!("{name}.lock"));
+        let lock = crate::spool::create_locked(&lock_path)?;
+        let staging = LanguageRouter::new(staging_dir.join(name), name, Duration::MAX)
+            .with_shard_documents(1_000_000)
+            .with_shard_text_bytes(1 << 30);
+        Ok(Self {
+            output_dir,
+            row_group_size: 10_000,
+            compression: None,
+            staging,
+            lock_path,
+            _lock: lock,
+            finished: Vec::new(),
+        })
+    }
+
+    /// Rows per row group, 10 000 by default.
+    pub fn with_row_group_size(mut self, row_group_size: usize) -> Self {
+        self.row_group_size = row_group_size;
+        self
+    }
+
+    /// The codec of the files, Snappy if none is given.
+    pub fn with_compression(mut self, compression: Option<Compression>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Documents per file, a million by default.
+    pub fn with_max_documents(mut self, documents: u64) -> Self {
+        self.staging = self.staging.with_shard_documents(documents);
+        self
+    }
+
+    /// Bytes of document text per file, 1 GiB by default.
+    pub fn with_max_text_bytes(mut self, text_bytes: u64) -> Self {
+        self.staging = self.staging.with_shard_text_bytes(text_bytes);
+        self
+    }
+
+    /// Records the staged shards touched by a batch in `journal`, see [`Journal`].
+    pub fn with_journal(mut self, journal: Journal) -> Self {
+        self.staging = self.staging.with_journal(journal);
+        self
+    }
+
+    fn convert_finished(&mut self) -> Result<(), anyhow::Error> {
+        for staged in self.staging.take_finished_files() {
+            let path = convert_staged(
+                &self.output_dir,
+                &staged,
+                self.row_group_size,
+                self.compression,
+            )?;
+            self.finished.push(path);
+        }
+        Ok(())
+    }
+}
+
+/// Converts one staged shard `<staging>/<name>/<language>/<shard>.jsonl` into
+/// `<output_dir>/<language>/<shard>.parquet` and removes it. A crash before the removal
+/// converts it again on the next start, into the same file.
+fn convert_staged(
+    output_dir: &Path,
+    staged: &Path,
+    row_group_size: usize,
+    compression: Option<Compression>,
+) -> Result<PathBuf, anyhow::Error> {
+    let language = staged
+        .parent()
+        .and_then(Path::file_name)
+        .ok_or_else(|| anyhow::anyhow!("Staged shard {} has no language", staged.display()))?;
+    let shard = staged.file_name().unwrap_or_default().to_string_lossy();
+    let path = output_dir
+        .join(language)
+        .join(shard.replace(".jsonl", ".parquet"));
+    let documents = read_documents(staged)?
+        .map(|document| Ok(DocumentRecord::from(&document?)))
+        .collect::<Result<Vec<_>, anyhow::Error>>()?;
+    fs::create_dir_all(path.parent().unwrap())?;
+    let partial = path.with_extension("parquet.partial");
+    write_parquet(&partial, &documents, row_group_size, compression)?;
+    File::open(&partial)?.sync_all()?;
+    fs::rename(&partial, &path)?;
+    fs::remove_file(staged)?;
+    tracing::info!("Wrote {} documents to {}", documents.len(), path.display());
+    Ok(path)
+}
+
+/// Converts the staged shards of every [`ParquetSink`] that is not running any more, e.g.
+/// after a crash, into Parquet files. Their journals have to be recovered first. Returns
+/// the files written.
+pub fn recover_staged(
+    output_dir: &Path,
+    row_group_size: usize,
+    compression: Option<Compression>,
+) -> Result<Vec<PathBuf>, anyhow::Error> {
+    let staging_dir = output_dir.join(STAGING_DIR);
+    if !staging_dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut written = Vec::new();
+    for entry in fs::read_dir(&staging_dir)? {
+        let lock_path = entry?.path();
+        if lock_path.extension().is_none_or(|ext| ext != "lock") {
+            continue;
+        }
+        let lock = File::open(&lock_path)?;
+        if lock.try_lock().is_err() {
+            continue;
+        }
+        let dir = lock_path.with_extension("");
+        if !dir.exists() {
+            continue;
+        }
+        for language in fs::read_dir(&dir)? {
+            for staged in fs::read_dir(language?.path())? {
+                let staged = staged?.path();
+                written.push(convert_staged(
+                    output_dir,
+                    &staged,
+                    row_group_size,
+                    compression,
+                )?);
+            }
+        }
+    }
+    Ok(written)
+}
+
+/// The documents of a file written by [`ParquetSink`].
+pub fn read_document_records(path: &Path) -> Result<Vec<DocumentRecord>, anyhow::Error> {
+    let reader = SerializedFileReader::new(File::open(path)?)?;
+    let mut records = Vec::new();
+    for row in reader {
+        records.push(serde_json::from_value(row?.to_json_value())?);
+    }
+    Ok(records)
+}
+
+/// Rewrites a file written by [`ParquetSink`] with only the documents `keep` accepts,
+/// with the same row group size and codec. The file is only replaced if something was
+/// removed, and returns how many documents were.
+pub fn retain_documents(
+    path: &Path,
+    keep: impl Fn(&Document) -> bool,
+) -> Result<usize, anyhow::Error> {
+    retain_records(path, |record: &DocumentRecord| {
+        keep(&Document::from(record.clone()))
+    })
+}
+
+/// Like [`retain_documents`], for any file written with [`write_parquet`], e.g. the
+/// metadata of the metadata-only mode.
+pub fn retain_records<R: ParquetRecord + DeserializeOwned>(
+    path: &Path,
+    keep: impl Fn(&R) -> bool,
+) -> Result<usize, anyhow::Error> {
+    let reader = SerializedFileReader::new(File::open(path)?)?;
+    let metadata = reader.metadata();
+    let (row_group_size, codec) = match metadata.row_groups().first() {
+        Some(row_group) => (
+            row_group.num_rows() as usize,
+            row_group.column(0).compression(),
+        ),
+        None => return Ok(0),
+    };
+    let mut records = Vec::new();
+    for row in reader {
+        records.push(serde_json::from_value::<R>(row?.to_json_value())?);
+    }
+    let num_records = records.len();
+    let kept = records.into_iter().filter(keep).collect::<Vec<_>>();
+    let num_removed = num_records - kept.len();
+    if num_removed == 0 {
+        return Ok(0);
+    }
+    let partial = path.with_extension("parquet.partial");
+    write_row_groups(&partial, &kept, row_group_size, codec)?;
+    File::open(&partial)?.sync_all()?;
+    fs::rename(&partial, path)?;
+    Ok(num_removed)
+}
+
+impl OutputSink for ParquetSink {
+    fn begin_batch(&mut self, batch_id: &str) -> Result<(), anyhow::Error> {
+        self.staging.begin_batch(batch_id)
+    }
+
+    fn write(&mut self, document: &Document) -> Result<(), anyhow::Error> {
+        self.staging.write(document)
+    }
+
+    fn commit_batch(&mut self) -> Result<(), anyhow::Error> {
+        self.staging.commit_batch()?;
+        self.convert_finished()
+    }
+
+    fn take_finished_files(&mut self) -> Vec<PathBuf> {
+        std::mem::take(&mut self.finished)
+    }
+
+    /// Converts every staged shard, and removes the staging directory of the sink.
+    fn finish(&mut self) -> Result<(), anyhow::Error> {
+        self.staging.finish()?;
+        self.convert_finished()?;
+        fs::remove_dir_all(self.lock_path.with_extension("")).ok();
+        fs::remove_file(&self.lock_path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    use std::path::Path;
+
+    use crate::{
+        cdx::parse_cdx_line,
+        compression::Compression,
+        output::{output_files, read_documents, Document, OutputSink},
+        parquet_output::{
+            read_document_records, recover_staged, retain_documents, write_parquet, DocumentRecord,
+            MetadataRecord, ParquetSink,
+        },
+    };
+
+    #[test]
+    fn writes_metadata_parquet() {
+        let entry = parse_cdx_line(
+            r#"0,100,59,139)/ 20240723213521 {"url": "https://139.59.100.0/", "status": "200", "length": "16650", "offset": "64016172", "filename": "crawl-data/CC-MAIN-2024-30/segments/1720763518115.82/warc/CC-MAIN-20240723194208-20240723224208-00279.warc.gz", "languages": "ind,eng"}"#,
+        ).unwrap();
+        let records = vec![MetadataRecord::from(&entry); 3];
+        assert_eq!(records[0].domain.as_deref(), Some("139.59.100.0"));
+        let path =
+            std::env::temp_dir().join(format!("pipeline-metadata-{}.parquet", std::process::id()));
+        write_parquet(&path, &records, 2, Some(Compression::Zstd(3))).unwrap();
+        let reader = SerializedFileReader::new(std::fs::File::open(&path).unwrap()).unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 3);
+        assert_eq!(reader.metadata().num_row_groups(), 2);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    fn document(id: &str, language: &str) -> Document {
+        Document {
+            id: id.to_string(),
+            url: format!("https://example.com/{id}"),
+            timestamp: "20240722120756".to_string(),
+            language: language.to_string(),
+            token_count: 1,
+            digest: Some("DCNYNIFG5SBRCVS5PCUY4YY2UM2WAQ4R".to_string()),
+            text: "text".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn ids(path: &Path) -> Vec<String> {
+        read_document_records(path)
+            .unwrap()
+            .into_iter()
+            .map(|record| record.id)
+            .collect()
+    }
+
+    #[test]
+    fn rolls_files_across_batches() {
+        let dir =
+            std::env::temp_dir().join(format!("pipeline-parquet-sink-{}", std::process::id()));
+        let mut sink = ParquetSink::new(&dir, "worker-1")
+            .unwrap()
+            .with_row_group_size(2)
+            .with_max_documents(3);
+        let path = |language: &str, shard: &str| dir.join(language).join(shard);
+        sink.begin_batch("batch-a").unwrap();
+        for (id, language) in [("1", "eng"), ("2", "eng"), ("3", "deu")] {
+            sink.write(&document(id, language)).unwrap();
+        }
+        sink.commit_batch().unwrap();
+        assert!(sink.take_finished_files().is_empty());
+        sink.begin_batch("batch-b").unwrap();
+        for (id, language) in [("4", "eng"), ("5", "eng"), ("6", "deu")] {
+            sink.write(&document(id, language)).unwrap();
+        }
+        sink.commit_batch().unwrap();
+        let eng = path("eng", "worker-1-00000.parquet");
+        assert_eq!(sink.take_finished_files(), vec![eng.clone()]);
+        let reader = SerializedFileReader::new(std::fs::File::open(&eng).unwrap()).unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 4);
+        assert_eq!(reader.metadata().num_row_groups(), 2);
+        // Staged rows are not output files yet.
+        assert_eq!(output_files(&dir).unwrap(), vec![eng.clone()]);
+
+        sink.finish().unwrap();
+        let deu = path("deu", "worker-1-00000.parquet");
+        assert_eq!(sink.take_finished_files(), vec![deu.clone()]);
+        assert_eq!(ids(&deu), vec!["3", "6"]);
+        assert!(!dir.join(".parquet-staging/worker-1").exists());
+        let documents = read_documents(&eng)
+            .unwrap()
+            .map(|document| document.unwrap().url)
+            .collect::<Vec<_>>();
+        assert_eq!(documents[0], "https://example.com/1");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn converts_the_staged_documents_of_stopped_sinks() {
+        let dir =
+            std::env::temp_dir().join(format!("pipeline-parquet-staged-{}", std::process::id()));
+        let mut stopped = ParquetSink::new(&dir, "worker-1").unwrap();
+        stopped.begin_batch("batch-a").unwrap();
+        stopped.write(&document("1", "eng")).unwrap();
+        stopped.commit_batch().unwrap();
+        let mut running = ParquetSink::new(&dir, "worker-2").unwrap();
+        running.begin_batch("batch-b").unwrap();
+        running.write(&document("2", "eng")).unwrap();
+        running.commit_batch().unwrap();
+        // Ends without finish, like a crashed worker.
+        drop(stopped);
+
+        let written = recover_staged(&dir, 10, None).unwrap();
+        assert_eq!(written, vec![dir.join("eng/worker-1-00000.parquet")]);
+        assert_eq!(ids(&written[0]), vec!["1"]);
+        assert!(recover_staged(&dir, 10, None).unwrap().is_empty());
+        running.finish().unwrap();
+        assert_eq!(ids(&dir.join("eng/worker-2-00000.parquet")), vec!["2"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn retains_documents_with_the_same_row_groups() {
+        let path =
+            std::env::temp_dir().join(format!("pipeline-retain-{}.parquet", std::process::id()));
+        let records = ["1", "2", "3"]
+            .map(|id| DocumentRecord::from(&document(id, "eng")))
+            .to_vec();
+        write_parquet(&path, &records, 2, Some(Compression::Zstd(3))).unwrap();
+        assert_eq!(retain_documents(&path, |_| true).unwrap(), 0);
+        assert_eq!(
+            retain_documents(&path, |document| document.id != "2").unwrap(),
+            1
+        );
+        assert_eq!(ids(&path), vec!["1", "3"]);
+        let reader = SerializedFileReader::new(std::fs::File::open(&path).unwrap()).unwrap();
+        assert_eq!(reader.metadata().num_row_groups(), 1);
+        std::fs::remove_file(path).unwrap();
+    }
+}
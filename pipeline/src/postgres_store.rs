@@ -0,0 +1,690 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::mpsc,
+};
+
+use postgres::{Client, NoTls};
+
+use crate::{
+    batch::BatchKey,
+    budget::{RunLimits, StopReason},
+    compare::RunCounters,
+    compliance::{DropCounts, DropReason},
+    state_store::{KeyPage, RunCost, RunStop, StateStore, Watermark, WATERMARKS},
+    traffic::{TrafficKind, TrafficTotals},
+};
+
+/// The current time as text, like `CURRENT_TIMESTAMP` in SQLite.
+const NOW: &str = "to_char(now() AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI:SS')";
+
+/// The tables of the SQLite run DB, so the same queries work on both.
+fn schema() -> String {
+    format!(
+        "
+    CREATE TABLE IF NOT EXISTS traffic (
+        run_id TEXT NOT NULL,
+        kind TEXT NOT NULL,
+        bytes BIGINT NOT NULL DEFAULT 0,
+        requests BIGINT NOT NULL DEFAULT 0,
+        retries BIGINT NOT NULL DEFAULT 0,
+        PRIMARY KEY (run_id, kind)
+    );
+    CREATE TABLE IF NOT EXISTS completed_batches (
+        crawl TEXT NOT NULL,
+        shard TEXT NOT NULL,
+        batch_index BIGINT NOT NULL,
+        run_id TEXT NOT NULL,
+        completed_at TEXT NOT NULL DEFAULT {NOW},
+        PRIMARY KEY (crawl, shard, batch_index)
+    );
+    CREATE TABLE IF NOT EXISTS runs (
+        run_id TEXT PRIMARY KEY,
+        deadline_unix BIGINT,
+        max_cost_bytes BIGINT,
+        stop_reason TEXT,
+        cut_shard TEXT,
+        cut_batch_index BIGINT,
+        stopped_at TEXT
+    );
+    CREATE TABLE IF NOT EXISTS compliance_drops (
+        run_id TEXT NOT NULL,
+        reason TEXT NOT NULL,
+        count BIGINT NOT NULL DEFAULT 0,
+        PRIMARY KEY (run_id, reason)
+    );
+    CREATE TABLE IF NOT EXISTS run_configs (
+        run_id TEXT NOT NULL,
+        process TEXT NOT NULL,
+        args TEXT NOT NULL,
+        PRIMARY KEY (run_id, process, args)
+    );
+    CREATE TABLE IF NOT EXISTS run_counters (
+        run_id TEXT NOT NULL,
+        name TEXT NOT NULL,
+        count BIGINT NOT NULL DEFAULT 0,
+        PRIMARY KEY (run_id, name)
+    );
+    CREATE TABLE IF NOT EXISTS published_batches (
+        crawl TEXT NOT NULL,
+        shard TEXT NOT NULL,
+        batch_index BIGINT NOT NULL,
+        min_timestamp TEXT NOT NULL,
+        max_timestamp TEXT NOT NULL,
+        PRIMARY KEY (crawl, shard, batch_index)
+    );
+    CREATE TABLE IF NOT EXISTS content_keys (
+        key TEXT PRIMARY KEY,
+        document_id TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS domain_documents (
+        domain TEXT NOT NULL,
+        document_id TEXT NOT NULL,
+        PRIMARY KEY (domain, document_id)
+    );
+    CREATE TABLE IF NOT EXISTS domain_counts (
+        domain TEXT PRIMARY KEY,
+        num_documents BIGINT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS checkpoints (
+        name TEXT PRIMARY KEY,
+        data BYTEA NOT NULL,
+        updated_at TEXT NOT NULL DEFAULT {NOW}
+    );
+    CREATE OR REPLACE VIEW cost AS
+        SELECT
+            run_id,
+            SUM(CASE WHEN kind = 'index' THEN bytes ELSE 0 END)::BIGINT AS index_bytes,
+            SUM(CASE WHEN kind = 'warc' THEN bytes ELSE 0 END)::BIGINT AS warc_bytes,
+            SUM(CASE WHEN kind = 'index_api' THEN bytes ELSE 0 END)::BIGINT AS index_api_bytes,
+            SUM(requests)::BIGINT AS requests,
+            SUM(retries)::BIGINT AS retries
+        FROM traffic
+        GROUP BY run_id;
+"
+    )
+}
+
+type Job = Box<dyn FnOnce(&mut Client) + Send>;
+
+/// The run state of a fleet in one Postgres database, in the tables of `RunDb`.
+///
+/// The `postgres` client runs a Tokio runtime of its own, which cannot block inside the
+/// runtime of the pipeline, so the connection lives on a thread of its own and every call
+/// waits for it there. Connections are not encrypted.
+pub struct PostgresStore {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl PostgresStore {
+    pub fn connect(url: &str) -> Result<Self, anyhow::Error> {
+        let url = url.to_string();
+        let (jobs, received) = mpsc::channel::<Job>();
+        let (connected, connection) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("postgres-store".to_string())
+            .spawn(move || {
+                let client = Client::connect(&url, NoTls).and_then(|mut client| {
+                    // Workers starting at the same time would race to create the tables.
+                    client.batch_execute(&format!(
+                        "BEGIN; SELECT pg_advisory_xact_lock(7261001); {} COMMIT;",
+                        schema()
+                    ))?;
+                    Ok(client)
+                });
+                let mut client = match client {
+                    Ok(client) => {
+                        connected.send(Ok(())).ok();
+                        client
+                    }
+                    Err(e) => {
+                        connected.send(Err(e)).ok();
+                        return;
+                    }
+                };
+                for job in received {
+                    job(&mut client);
+                }
+            })?;
+        connection
+            .recv()
+            .map_err(|_| anyhow::anyhow!("The Postgres thread stopped"))??;
+        Ok(Self { jobs })
+    }
+
+    /// Runs `query` on the connection thread and waits for its result.
+    fn call<T: Send + 'static>(
+        &self,
+        query: impl FnOnce(&mut Client) -> Result<T, anyhow::Error> + Send + 'static,
+    ) -> Result<T, anyhow::Error> {
+        let (result, received) = mpsc::sync_channel(1);
+        self.jobs
+            .send(Box::new(move |client| {
+                result.send(query(client)).ok();
+            }))
+            .map_err(|_| anyhow::anyhow!("The Postgres connection is closed"))?;
+        received
+            .recv()
+            .map_err(|_| anyhow::anyhow!("The Postgres connection is closed"))?
+    }
+}
+
+impl StateStore for PostgresStore {
+    fn add_traffic(
+        &self,
+        run_id: &str,
+        deltas: &[(TrafficKind, TrafficTotals)],
+    ) -> Result<(), anyhow::Error> {
+        let run_id = run_id.to_string();
+        let deltas = deltas
+            .iter()
+            .filter(|(_, delta)| *delta != TrafficTotals::default())
+            .map(|(kind, delta)| (kind.as_str(), *delta))
+            .collect::<Vec<_>>();
+        self.call(move |client| {
+            let mut tx = client.transaction()?;
+            for (kind, delta) in deltas {
+                tx.execute(
+                    "INSERT INTO traffic (run_id, kind, bytes, requests, retries)
+                     VALUES ($1, $2, $3, $4, $5)
+                     ON CONFLICT (run_id, kind) DO UPDATE SET
+                         bytes = traffic.bytes + excluded.bytes,
+                         requests = traffic.requests + excluded.requests,
+                         retries = traffic.retries + excluded.retries",
+                    &[
+                        &run_id,
+                        &kind,
+                        &(delta.bytes as i64),
+                        &(delta.requests as i64),
+                        &(delta.retries as i64),
+                    ],
+                )?;
+            }
+            tx.commit()?;
+            Ok(())
+        })
+    }
+
+    fn mark_batch_complete(&self, key: &BatchKey, run_id: &str) -> Result<(), anyhow::Error> {
+        let (key, run_id) = (key.clone(), run_id.to_string());
+        self.call(move |client| {
+            client.execute(
+                "INSERT INTO completed_batches (crawl, shard, batch_index, run_id)
+                 VALUES ($1, $2, $3, $4) ON CONFLICT DO NOTHING",
+                &[&key.crawl, &key.shard, &(key.batch_index as i64), &run_id],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn is_batch_complete(&self, key: &BatchKey) -> Result<bool, anyhow::Error> {
+        let key = key.clone();
+        self.call(move |client| {
+            Ok(client
+                .query_one(
+                    "SELECT EXISTS (SELECT 1 FROM completed_batches
+                     WHERE crawl = $1 AND shard = $2 AND batch_index = $3)",
+                    &[&key.crawl, &key.shard, &(key.batch_index as i64)],
+                )?
+                .get(0))
+        })
+    }
+
+    fn completed_batches(&self) -> Result<Vec<BatchKey>, anyhow::Error> {
+        self.call(|client| {
+            Ok(client
+                .query(
+                    "SELECT crawl, shard, batch_index FROM completed_batches",
+                    &[],
+                )?
+                .into_iter()
+                .map(|row| BatchKey {
+                    crawl: row.get(0),
+                    shard: row.get(1),
+                    batch_index: row.get::<_, i64>(2) as usize,
+                })
+                .collect())
+        })
+    }
+
+    fn register_batch(
+        &self,
+        key: &BatchKey,
+        min_timestamp: &str,
+        max_timestamp: &str,
+    ) -> Result<(), anyhow::Error> {
+        let key = key.clone();
+        let (min_timestamp, max_timestamp) = (min_timestamp.to_string(), max_timestamp.to_string());
+        self.call(move |client| {
+            client.execute(
+                "INSERT INTO published_batches
+                     (crawl, shard, batch_index, min_timestamp, max_timestamp)
+                 VALUES ($1, $2, $3, $4, $5)
+                 ON CONFLICT (crawl, shard, batch_index) DO UPDATE SET
+                     min_timestamp = excluded.min_timestamp,
+                     max_timestamp = excluded.max_timestamp",
+                &[
+                    &key.crawl,
+                    &key.shard,
+                    &(key.batch_index as i64),
+                    &min_timestamp,
+                    &max_timestamp,
+                ],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn watermarks(&self) -> Result<Vec<Watermark>, anyhow::Error> {
+        self.call(|client| {
+            Ok(client
+                .query(WATERMARKS, &[])?
+                .into_iter()
+                .map(|row| Watermark {
+                    crawl: row.get(0),
+                    low_watermark: row.get(1),
+                    pending_batches: row.get::<_, i64>(2) as u64,
+                    completed_batches: row.get::<_, i64>(3) as u64,
+                })
+                .collect())
+        })
+    }
+
+    fn set_limits(&self, run_id: &str, limits: &RunLimits) -> Result<(), anyhow::Error> {
+        let (run_id, limits) = (run_id.to_string(), *limits);
+        self.call(move |client| {
+            client.execute(
+                "INSERT INTO runs (run_id, deadline_unix, max_cost_bytes) VALUES ($1, $2, $3)
+                 ON CONFLICT (run_id) DO UPDATE SET
+                     deadline_unix = excluded.deadline_unix,
+                     max_cost_bytes = excluded.max_cost_bytes",
+                &[
+                    &run_id,
+                    &limits.deadline_unix.map(|d| d as i64),
+                    &limits.max_cost_bytes.map(|b| b as i64),
+                ],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn limits(&self, run_id: &str) -> Result<RunLimits, anyhow::Error> {
+        let run_id = run_id.to_string();
+        self.call(move |client| {
+            let Some(row) = client.query_opt(
+                "SELECT deadline_unix, max_cost_bytes FROM runs WHERE run_id = $1",
+                &[&run_id],
+            )?
+            else {
+                return Ok(RunLimits::default());
+            };
+            Ok(RunLimits {
+                deadline_unix: row.get::<_, Option<i64>>(0).map(|d| d as u64),
+                max_cost_bytes: row.get::<_, Option<i64>>(1).map(|b| b as u64),
+            })
+        })
+    }
+
+    fn mark_partial(
+        &self,
+        run_id: &str,
+        reason: StopReason,
+        cut: Option<&BatchKey>,
+    ) -> Result<(), anyhow::Error> {
+        let (run_id, cut) = (run_id.to_string(), cut.cloned());
+        self.call(move |client| {
+            let cut_shard = cut.as_ref().map(|key| key.shard.clone());
+            let cut_batch_index = cut.as_ref().map(|key| key.batch_index as i64);
+            let mut tx = client.transaction()?;
+            tx.execute(
+                &format!(
+                    "UPDATE runs SET
+                         stop_reason = $2,
+                         cut_shard = $3,
+                         cut_batch_index = $4,
+                         stopped_at = {NOW}
+                     WHERE run_id = $1 AND stop_reason IS NULL"
+                ),
+                &[&run_id, &reason.as_str(), &cut_shard, &cut_batch_index],
+            )?;
+            if cut.is_some() {
+                tx.execute(
+                    "UPDATE runs SET cut_shard = $2, cut_batch_index = $3
+                     WHERE run_id = $1 AND cut_shard IS NULL",
+                    &[&run_id, &cut_shard, &cut_batch_index],
+                )?;
+            }
+            tx.commit()?;
+            Ok(())
+        })
+    }
+
+    fn run_stop(&self, run_id: &str, crawl: &str) -> Result<Option<RunStop>, anyhow::Error> {
+        let (run_id, crawl) = (run_id.to_string(), crawl.to_string());
+        self.call(move |client| {
+            let Some(row) = client.query_opt(
+                "SELECT stop_reason, cut_shard, cut_batch_index, stopped_at FROM runs
+                 WHERE run_id = $1 AND stop_reason IS NOT NULL",
+                &[&run_id],
+            )?
+            else {
+                return Ok(None);
+            };
+            let cut_shard = row.get::<_, Option<String>>(1);
+            let cut_batch_index = row.get::<_, Option<i64>>(2);
+            Ok(Some(RunStop {
+                reason: row.get(0),
+                cut: cut_shard
+                    .zip(cut_batch_index)
+                    .map(|(shard, batch_index)| BatchKey {
+                        crawl,
+                        shard,
+                        batch_index: batch_index as usize,
+                    }),
+                stopped_at: row.get(3),
+            }))
+        })
+    }
+
+    fn add_drops(&self, run_id: &str, counts: &DropCounts) -> Result<(), anyhow::Error> {
+        let run_id = run_id.to_string();
+        let counts = counts
+            .iter()
+            .map(|(reason, count)| (reason.as_str(), count as i64))
+            .collect::<Vec<_>>();
+        self.call(move |client| {
+            let mut tx = client.transaction()?;
+            for (reason, count) in counts {
+                tx.execute(
+                    "INSERT INTO compliance_drops (run_id, reason, count) VALUES ($1, $2, $3)
+                     ON CONFLICT (run_id, reason) DO UPDATE SET
+                         count = compliance_drops.count + excluded.count",
+                    &[&run_id, &reason, &count],
+                )?;
+            }
+            tx.commit()?;
+            Ok(())
+        })
+    }
+
+    fn drops(&self, run_id: &str) -> Result<DropCounts, anyhow::Error> {
+        let run_id = run_id.to_string();
+        let rows = self.call(move |client| {
+            Ok(client.query(
+                "SELECT reason, count FROM compliance_drops WHERE run_id = $1",
+                &[&run_id],
+            )?)
+        })?;
+        let mut counts = DropCounts::default();
+        for row in rows {
+            let reason = row.get::<_, String>(0);
+            match DropReason::ALL.iter().find(|r| r.as_str() == reason) {
+                Some(reason) => counts.add(*reason, row.get::<_, i64>(1) as u64),
+                None => tracing::warn!("Ignoring unknown drop reason {}", reason),
+            }
+        }
+        Ok(counts)
+    }
+
+    fn record_config(
+        &self,
+        run_id: &str,
+        process: &str,
+        args: &[String],
+    ) -> Result<(), anyhow::Error> {
+        let (run_id, process) = (run_id.to_string(), process.to_string());
+        let args = serde_json::to_string(args)?;
+        self.call(move |client| {
+            client.execute(
+                "INSERT INTO run_configs (run_id, process, args) VALUES ($1, $2, $3)
+                 ON CONFLICT DO NOTHING",
+                &[&run_id, &process, &args],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn configs(&self, run_id: &str) -> Result<Vec<(String, Vec<String>)>, anyhow::Error> {
+        let run_id = run_id.to_string();
+        let rows = self.call(move |client| {
+            Ok(client.query(
+                "SELECT process, args FROM run_configs WHERE run_id = $1
+                 ORDER BY process COLLATE \"C\", args COLLATE \"C\"",
+                &[&run_id],
+            )?)
+        })?;
+        rows.into_iter()
+            .map(|row| {
+                let args = row.get::<_, String>(1);
+                Ok((row.get(0), serde_json::from_str(&args)?))
+            })
+            .collect()
+    }
+
+    fn add_counters(&self, run_id: &str, counters: &RunCounters) -> Result<(), anyhow::Error> {
+        let run_id = run_id.to_string();
+        let counters = counters
+            .iter()
+            .map(|(name, count)| (name.to_string(), count as i64))
+            .collect::<Vec<_>>();
+        self.call(move |client| {
+            let mut tx = client.transaction()?;
+            for (name, count) in counters {
+                tx.execute(
+                    "INSERT INTO run_counters (run_id, name, count) VALUES ($1, $2, $3)
+                     ON CONFLICT (run_id, name) DO UPDATE SET
+                         count = run_counters.count + excluded.count",
+                    &[&run_id, &name, &count],
+                )?;
+            }
+            tx.commit()?;
+            Ok(())
+        })
+    }
+
+    fn counters(&self, run_id: &str) -> Result<RunCounters, anyhow::Error> {
+        let run_id = run_id.to_string();
+        let rows = self.call(move |client| {
+            Ok(client.query(
+                "SELECT name, count FROM run_counters WHERE run_id = $1",
+                &[&run_id],
+            )?)
+        })?;
+        let mut counters = RunCounters::default();
+        for row in rows {
+            counters.add(row.get(0), row.get::<_, i64>(1) as u64);
+        }
+        Ok(counters)
+    }
+
+    fn costs(&self) -> Result<Vec<RunCost>, anyhow::Error> {
+        self.call(|client| {
+            Ok(client
+                .query(
+                    "SELECT run_id, index_bytes, warc_bytes, index_api_bytes, requests, retries
+                     FROM cost ORDER BY run_id COLLATE \"C\"",
+                    &[],
+                )?
+                .into_iter()
+                .map(|row| RunCost {
+                    run_id: row.get(0),
+                    index_bytes: row.get::<_, i64>(1) as u64,
+                    warc_bytes: row.get::<_, i64>(2) as u64,
+                    index_api_bytes: row.get::<_, i64>(3) as u64,
+                    requests: row.get::<_, i64>(4) as u64,
+                    retries: row.get::<_, i64>(5) as u64,
+                })
+                .collect())
+        })
+    }
+
+    fn claim_keys(&self, claims: &[(String, String)]) -> Result<Vec<String>, anyhow::Error> {
+        let claims = claims.to_vec();
+        self.call(move |client| {
+            let mut tx = client.transaction()?;
+            let mut ids = Vec::with_capacity(claims.len());
+            for (key, document_id) in claims {
+                ids.push(
+                    tx.query_one(
+                        "INSERT INTO content_keys (key, document_id) VALUES ($1, $2)
+                         ON CONFLICT (key) DO UPDATE SET key = content_keys.key
+                         RETURNING document_id",
+                        &[&key, &document_id],
+                    )?
+                    .get(0),
+                );
+            }
+            tx.commit()?;
+            Ok(ids)
+        })
+    }
+
+    /// Pages in key order, the cursor is the last key of a page.
+    fn content_keys(&self, cursor: Option<&str>, limit: usize) -> Result<KeyPage, anyhow::Error> {
+        let cursor = cursor.unwrap_or_default().to_string();
+        self.call(move |client| {
+            let keys = client
+                .query(
+                    "SELECT key, document_id FROM content_keys WHERE key > $1
+                     ORDER BY key LIMIT $2",
+                    &[&cursor, &(limit as i64)],
+                )?
+                .into_iter()
+                .map(|row| (row.get::<_, String>(0), row.get::<_, String>(1)))
+                .collect::<Vec<_>>();
+            let next = (keys.len() == limit)
+                .then(|| keys.last().map(|(key, _)| key.clone()))
+                .flatten();
+            Ok(KeyPage { keys, next })
+        })
+    }
+
+    /// Locks the count rows of all domains of the call in name order, so two workers
+    /// neither take the last document of a quota both nor deadlock.
+    fn admit_documents(
+        &self,
+        max_documents: u64,
+        documents: &[(String, String)],
+    ) -> Result<Vec<bool>, anyhow::Error> {
+        let documents = documents.to_vec();
+        self.call(move |client| {
+            let mut tx = client.transaction()?;
+            let domains = documents
+                .iter()
+                .map(|(domain, _)| domain.clone())
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .collect::<Vec<_>>();
+            tx.execute(
+                "INSERT INTO domain_counts (domain, num_documents)
+                 SELECT domain, 0 FROM UNNEST($1::TEXT[]) AS domain
+                 ORDER BY domain
+                 ON CONFLICT (domain) DO NOTHING",
+                &[&domains],
+            )?;
+            let mut counts = tx
+                .query(
+                    "SELECT domain, num_documents FROM domain_counts
+                     WHERE domain = ANY($1) ORDER BY domain FOR UPDATE",
+                    &[&domains],
+                )?
+                .into_iter()
+                .map(|row| (row.get::<_, String>(0), row.get::<_, i64>(1) as u64))
+                .collect::<HashMap<_, _>>();
+            let mut admitted = Vec::with_capacity(documents.len());
+            for (domain, document_id) in documents {
+                let admitted_before = tx
+                    .query_opt(
+                        "SELECT 1 FROM domain_documents WHERE domain = $1 AND document_id = $2",
+                        &[&domain, &document_id],
+                    )?
+                    .is_some();
+                let num_documents = counts.entry(domain.clone()).or_default();
+                if admitted_before {
+                    admitted.push(true);
+                } else if *num_documents >= max_documents {
+                    admitted.push(false);
+                } else {
+                    tx.execute(
+                        "INSERT INTO domain_documents (domain, document_id) VALUES ($1, $2)",
+                        &[&domain, &document_id],
+                    )?;
+                    *num_documents += 1;
+                    admitted.push(true);
+                }
+            }
+            for (domain, num_documents) in counts {
+                tx.execute(
+                    "UPDATE domain_counts SET num_documents = $2 WHERE domain = $1",
+                    &[&domain, &(num_documents as i64)],
+                )?;
+            }
+            tx.commit()?;
+            Ok(admitted)
+        })
+    }
+
+    fn read_checkpoint(&self, name: &str) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        let name = name.to_string();
+        self.call(move |client| {
+            Ok(client
+                .query_opt("SELECT data FROM checkpoints WHERE name = $1", &[&name])?
+                .map(|row| row.get(0)))
+        })
+    }
+
+    fn write_checkpoint(&self, name: &str, data: &[u8]) -> Result<(), anyhow::Error> {
+        let (name, data) = (name.to_string(), data.to_vec());
+        self.call(move |client| {
+            client.execute(
+                &format!(
+                    "INSERT INTO checkpoints (name, data) VALUES ($1, $2)
+                     ON CONFLICT (name) DO UPDATE SET
+                         data = excluded.data,
+                         updated_at = {NOW}"
+                ),
+                &[&name, &data],
+            )?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use postgres::{Client, NoTls};
+
+    use crate::{postgres_store::PostgresStore, state_store::conformance};
+
+    /// `url` with `schema` as the only schema to look tables up in and create them in.
+    fn with_search_path(url: &str, schema: &str) -> String {
+        if url.contains("://") {
+            let separator = if url.contains('?') { '&' } else { '?' };
+            format!("{url}{separator}options=-c%20search_path%3D{schema}")
+        } else {
+            format!("{url} options='-c search_path={schema}'")
+        }
+    }
+
+    /// Runs the assertions of the run DB on the Postgres database at
+    /// `PIPELINE_TEST_POSTGRES_URL`, each in a new schema that is dropped again. Passes
+    /// without a database if the variable is not set.
+    #[test]
+    fn passes_the_state_store_assertions() {
+        let Ok(url) = std::env::var("PIPELINE_TEST_POSTGRES_URL") else {
+            return;
+        };
+        let mut admin = Client::connect(&url, NoTls).unwrap();
+        for (index, check) in conformance::ALL.iter().enumerate() {
+            let schema = format!("pipeline_test_{}_{index}", std::process::id());
+            admin
+                .batch_execute(&format!("CREATE SCHEMA {schema}"))
+                .unwrap();
+            let store = PostgresStore::connect(&with_search_path(&url, &schema)).unwrap();
+            check(&store);
+            drop(store);
+            admin
+                .batch_execute(&format!("DROP SCHEMA {schema} CASCADE"))
+                .unwrap();
+        }
+    }
+}
@@ -0,0 +1,263 @@
+//! Protobuf messages of `proto/pipeline.proto`. They are derived by hand rather than
+//! generated in a build script, so building does not need `protoc`.
+
+use anyhow::Context;
+
+use crate::{cdx, output, provenance, warc_response};
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CdxMetadata {
+    #[prost(string, tag = "1")]
+    pub url: String,
+    #[prost(string, optional, tag = "2")]
+    pub mime: Option<String>,
+    #[prost(string, optional, tag = "3")]
+    pub mime_detected: Option<String>,
+    #[prost(uint32, tag = "4")]
+    pub status: u32,
+    #[prost(uint64, tag = "5")]
+    pub length: u64,
+    #[prost(uint64, tag = "6")]
+    pub offset: u64,
+    #[prost(string, tag = "7")]
+    pub filename: String,
+    #[prost(string, optional, tag = "8")]
+    pub digest: Option<String>,
+    #[prost(string, optional, tag = "9")]
+    pub languages: Option<String>,
+    #[prost(string, optional, tag = "10")]
+    pub redirect: Option<String>,
+    #[prost(string, optional, tag = "11")]
+    pub charset: Option<String>,
+    #[prost(string, optional, tag = "12")]
+    pub truncated: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CdxEntry {
+    #[prost(string, tag = "1")]
+    pub surt_url: String,
+    #[prost(string, tag = "2")]
+    pub timestamp: String,
+    #[prost(message, optional, tag = "3")]
+    pub metadata: Option<CdxMetadata>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Batch {
+    #[prost(message, repeated, tag = "1")]
+    pub entries: Vec<CdxEntry>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ResponseHeaders {
+    #[prost(string, optional, tag = "1")]
+    pub content_type: Option<String>,
+    #[prost(string, optional, tag = "2")]
+    pub last_modified: Option<String>,
+    #[prost(string, optional, tag = "3")]
+    pub server: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Provenance {
+    #[prost(string, tag = "1")]
+    pub ip: String,
+    #[prost(uint32, optional, tag = "2")]
+    pub asn: Option<u32>,
+    #[prost(string, optional, tag = "3")]
+    pub as_name: Option<String>,
+    #[prost(string, optional, tag = "4")]
+    pub country: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Document {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub url: String,
+    #[prost(string, tag = "3")]
+    pub timestamp: String,
+    #[prost(string, tag = "4")]
+    pub language: String,
+    #[prost(uint64, tag = "5")]
+    pub token_count: u64,
+    #[prost(double, tag = "6")]
+    pub quality_score: f64,
+    #[prost(double, optional, tag = "7")]
+    pub model_score: Option<f64>,
+    #[prost(message, optional, tag = "8")]
+    pub headers: Option<ResponseHeaders>,
+    #[prost(message, optional, tag = "9")]
+    pub provenance: Option<Provenance>,
+    /// Each item as JSON.
+    #[prost(string, repeated, tag = "10")]
+    pub structured_data: Vec<String>,
+    #[prost(string, optional, tag = "11")]
+    pub extractor: Option<String>,
+    #[prost(string, optional, tag = "12")]
+    pub digest: Option<String>,
+    #[prost(string, optional, tag = "13")]
+    pub near_duplicate_of: Option<String>,
+    #[prost(string, tag = "14")]
+    pub text: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ControlRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct WorkerStatus {
+    #[prost(string, tag = "1")]
+    pub worker: String,
+    #[prost(bool, tag = "2")]
+    pub paused: bool,
+    #[prost(uint64, tag = "3")]
+    pub batches_completed: u64,
+    #[prost(uint32, tag = "4")]
+    pub tasks: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SubmittedJob {
+    #[prost(string, tag = "1")]
+    pub batch_id: String,
+    #[prost(string, tag = "2")]
+    pub queue_name: String,
+}
+
+impl From<&cdx::CdxEntry> for CdxEntry {
+    fn from(entry: &cdx::CdxEntry) -> Self {
+        let metadata = &entry.metadata;
+        Self {
+            surt_url: entry.surt_url.clone(),
+            timestamp: entry.timestamp.clone(),
+            metadata: Some(CdxMetadata {
+                url: metadata.url.clone(),
+                mime: metadata.mime.clone(),
+                mime_detected: metadata.mime_detected.clone(),
+                status: metadata.status.into(),
+                length: metadata.length,
+                offset: metadata.offset,
+                filename: metadata.filename.clone(),
+                digest: metadata.digest.clone(),
+                languages: metadata.languages.clone(),
+                redirect: metadata.redirect.clone(),
+                charset: metadata.charset.clone(),
+                truncated: metadata.truncated.clone(),
+            }),
+        }
+    }
+}
+
+impl TryFrom<CdxEntry> for cdx::CdxEntry {
+    type Error = anyhow::Error;
+
+    fn try_from(entry: CdxEntry) -> Result<Self, Self::Error> {
+        let metadata = entry.metadata.context("CDX entry without metadata")?;
+        Ok(Self {
+            surt_url: entry.surt_url,
+            timestamp: entry.timestamp,
+            metadata: cdx::CdxMetadata {
+                url: metadata.url,
+                mime: metadata.mime,
+                mime_detected: metadata.mime_detected,
+                status: metadata
+                    .status
+                    .try_into()
+                    .context("HTTP status out of range")?,
+                length: metadata.length,
+                offset: metadata.offset,
+                filename: metadata.filename,
+                digest: metadata.digest,
+                languages: metadata.languages,
+                charset: metadata.charset,
+                redirect: metadata.redirect,
+                truncated: metadata.truncated,
+            },
+        })
+    }
+}
+
+impl From<&output::Document> for Document {
+    fn from(document: &output::Document) -> Self {
+        Self {
+            id: document.id.clone(),
+            url: document.url.clone(),
+            timestamp: document.timestamp.clone(),
+            language: document.language.clone(),
+            token_count: document.token_count as u64,
+            quality_score: document.quality_score,
+            model_score: document.model_score,
+            headers: document.headers.as_ref().map(|headers| ResponseHeaders {
+                content_type: headers.content_type.clone(),
+                last_modified: headers.last_modified.clone(),
+                server: headers.server.clone(),
+            }),
+            provenance: document.provenance.as_ref().map(|provenance| Provenance {
+                ip: provenance.ip.clone(),
+                asn: provenance.asn,
+                as_name: provenance.as_name.clone(),
+                country: provenance.country.clone(),
+            }),
+            structured_data: document
+                .structured_data
+                .iter()
+                .flatten()
+                .map(|item| item.to_string())
+                .collect(),
+            extractor: document.extractor.clone(),
+            digest: document.digest.clone(),
+            near_duplicate_of: document.near_duplicate_of.clone(),
+            text: document.text.clone(),
+        }
+    }
+}
+
+/// Documents without structured data come back with `None`, as written without
+/// `--structured-data`.
+impl TryFrom<Document> for output::Document {
+    type Error = anyhow::Error;
+
+    fn try_from(document: Document) -> Result<Self, Self::Error> {
+        let structured_data = document
+            .structured_data
+            .iter()
+            .map(|item| serde_json::from_str(item))
+            .collect::<Result<Vec<_>, _>>()
+            .context("Structured data item is no JSON")?;
+        Ok(Self {
+            id: document.id,
+            url: document.url,
+            timestamp: document.timestamp,
+            language: document.language,
+            token_count: document
+                .token_count
+                .try_into()
+                .context("Token count out of range")?,
+            quality_score: document.quality_score,
+            model_score: document.model_score,
+            headers: document
+                .headers
+                .map(|headers| warc_response::ResponseHeaders {
+                    content_type: headers.content_type,
+                    last_modified: headers.last_modified,
+                    server: headers.server,
+                }),
+            provenance: document
+                .provenance
+                .map(|provenance| provenance::Provenance {
+                    ip: provenance.ip,
+                    asn: provenance.asn,
+                    as_name: provenance.as_name,
+                    country: provenance.country,
+                }),
+            structured_data: (!structured_data.is_empty()).then_some(structured_data),
+            extractor: document.extractor,
+            digest: document.digest,
+            near_duplicate_of: document.near_duplicate_of,
+            text: document.text,
+        })
+    }
+}
@@ -0,0 +1,205 @@
+use std::{fs, net::IpAddr, path::Path};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+/// Where a capture was served from, see [`AsnDb`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Provenance {
+    /// `WARC-IP-Address` of the response record.
+    pub ip: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub asn: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub as_name: Option<String>,
+    /// ISO 3166 alpha-2 code of the AS registration.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub country: Option<String>,
+}
+
+/// IPv4 addresses are mapped into the IPv6 space, so both can be compared as one number.
+fn ip_to_u128(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u128::from(ip.to_ipv6_mapped()),
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+/// A set of CIDR ranges like `10.0.0.0/8` or `2001:db8::/32`, read from a file with one
+/// per line. A plain address is a range of one. Empty lines and lines starting with `#`
+/// are ignored.
+#[derive(Debug, Default)]
+pub struct IpRanges {
+    /// First and last address of each range.
+    ranges: Vec<(u128, u128)>,
+}
+
+impl IpRanges {
+    pub fn read(path: &Path) -> Result<Self, anyhow::Error> {
+        fs::read_to_string(path)?.parse()
+    }
+
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip_to_u128(ip);
+        self.ranges
+            .iter()
+            .any(|(first, last)| (*first..=*last).contains(&ip))
+    }
+}
+
+fn parse_cidr(line: &str) -> Result<(u128, u128), anyhow::Error> {
+    let (ip, prefix_len) = match line.split_once('/') {
+        Some((ip, prefix_len)) => (ip, Some(prefix_len.parse::<u32>()?)),
+        None => (line, None),
+    };
+    let ip = ip
+        .parse::<IpAddr>()
+        .with_context(|| format!("Invalid IP range {line}"))?;
+    // IPv4 prefixes count from the start of the mapped IPv4 part.
+    let max_prefix_len = if ip.is_ipv4() { 32 } else { 128 };
+    let prefix_len = prefix_len.unwrap_or(max_prefix_len);
+    anyhow::ensure!(prefix_len <= max_prefix_len, "Invalid IP range {line}");
+    let host_bits = max_prefix_len - prefix_len;
+    let mask = if host_bits == 128 {
+        u128::MAX
+    } else {
+        (1u128 << host_bits) - 1
+    };
+    let first = ip_to_u128(ip) & !mask;
+    Ok((first, first | mask))
+}
+
+impl std::str::FromStr for IpRanges {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ranges = s
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(parse_cidr)
+            .collect::<Result<_, _>>()?;
+        Ok(Self { ranges })
+    }
+}
+
+#[derive(Debug)]
+struct AsnRange {
+    first: u128,
+    last: u128,
+    asn: u32,
+    country: String,
+    as_name: String,
+}
+
+/// A local IP to ASN database in the tab-separated format of iptoasn.com
+/// (`ip2asn-combined.tsv`): first address, last address, AS number, country code and AS
+/// description per line. Ranges of AS number 0 are unrouted and skipped.
+#[derive(Debug, Default)]
+pub struct AsnDb {
+    ranges: Vec<AsnRange>,
+}
+
+impl AsnDb {
+    pub fn read(path: &Path) -> Result<Self, anyhow::Error> {
+        fs::read_to_string(path)?.parse()
+    }
+
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// The provenance of `ip`, with the AS fields left empty if no range covers it.
+    pub fn annotate(&self, ip: IpAddr) -> Provenance {
+        let value = ip_to_u128(ip);
+        let index = self.ranges.partition_point(|range| range.first <= value);
+        let range = index
+            .checked_sub(1)
+            .map(|i| &self.ranges[i])
+            .filter(|range| value <= range.last);
+        Provenance {
+            ip: ip.to_string(),
+            asn: range.map(|range| range.asn),
+            as_name: range.map(|range| range.as_name.clone()),
+            country: range.map(|range| range.country.clone()),
+        }
+    }
+}
+
+impl std::str::FromStr for AsnDb {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut ranges = Vec::new();
+        for line in s.lines().filter(|line| !line.trim().is_empty()) {
+            let fields = line.split('\t').collect::<Vec<_>>();
+            let [first, last, asn, country, as_name, ..] = fields[..] else {
+                anyhow::bail!("Invalid ASN database line {line}");
+            };
+            let asn = asn.parse::<u32>()?;
+            if asn == 0 {
+                continue;
+            }
+            ranges.push(AsnRange {
+                first: ip_to_u128(first.parse()?),
+                last: ip_to_u128(last.parse()?),
+                asn,
+                country: country.to_string(),
+                as_name: as_name.to_string(),
+            });
+        }
+        ranges.sort_by_key(|range| range.first);
+        Ok(Self { ranges })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::provenance::{AsnDb, IpRanges};
+
+    #[test]
+    fn matches_cidr_ranges() {
+        let ranges = "# private\n10.0.0.0/8\n192.168.1.1\n\n2001:db8::/32\n"
+            .parse::<IpRanges>()
+            .unwrap();
+        assert_eq!(ranges.len(), 3);
+        assert!(ranges.contains("10.255.0.1".parse().unwrap()));
+        assert!(!ranges.contains("11.0.0.1".parse().unwrap()));
+        assert!(ranges.contains("192.168.1.1".parse().unwrap()));
+        assert!(!ranges.contains("192.168.1.2".parse().unwrap()));
+        assert!(ranges.contains("2001:db8::1".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<IpRanges>().is_err());
+    }
+
+    #[test]
+    fn annotates_ips_with_their_as() {
+        let db = "1.0.0.0\t1.0.0.255\t13335\tUS\tCLOUDFLARENET\n\
+                  1.0.1.0\t1.0.3.255\t0\tNone\tNot routed\n\
+                  2606:4700::\t2606:4700:ffff:ffff:ffff:ffff:ffff:ffff\t13335\tUS\tCLOUDFLARENET\n"
+            .parse::<AsnDb>()
+            .unwrap();
+        assert_eq!(db.len(), 2);
+        let provenance = db.annotate("1.0.0.1".parse().unwrap());
+        assert_eq!(provenance.asn, Some(13335));
+        assert_eq!(provenance.country.as_deref(), Some("US"));
+        assert_eq!(
+            db.annotate("2606:4700::1".parse().unwrap()).asn,
+            Some(13335)
+        );
+        let unknown = db.annotate("1.0.2.1".parse().unwrap());
+        assert_eq!(unknown.ip, "1.0.2.1");
+        assert_eq!(unknown.asn, None);
+    }
+}
@@ -0,0 +1,55 @@
+/// Number of whitespace-separated tokens, a cheap stand-in for a real tokenizer.
+pub fn token_count(text: &str) -> usize {
+    text.split_whitespace().count()
+}
+
+/// Heuristic quality score in `[0, 1]`, loosely following the Gopher rules: documents
+/// score higher the more they look like running prose, with reasonable word lengths,
+/// few symbols and lines that end like sentences.
+pub fn quality_score(text: &str) -> f64 {
+    let words = text.split_whitespace().collect::<Vec<_>>();
+    if words.is_empty() {
+        return 0.0;
+    }
+    let mean_word_length =
+        words.iter().map(|w| w.chars().count()).sum::<usize>() as f64 / words.len() as f64;
+    let alphabetic_words = words
+        .iter()
+        .filter(|w| w.chars().any(char::is_alphabetic))
+        .count() as f64
+        / words.len() as f64;
+    let lines = text
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .collect::<Vec<_>>();
+    let sentence_lines = lines
+        .iter()
+        .filter(|l| l.trim_end().ends_with(['.', '!', '?', '"', '\'', ':']))
+        .count() as f64
+        / lines.len().max(1) as f64;
+
+    let length_score = (words.len() as f64 / 50.0).min(1.0);
+    let word_length_score = if (3.0..=10.0).contains(&mean_word_length) {
+        1.0
+    } else {
+        0.0
+    };
+    (length_score + word_length_score + alphabetic_words + sentence_lines) / 4.0
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::quality::{quality_score, token_count};
+
+    #[test]
+    fn prose_scores_higher_than_boilerplate() {
+        let prose = "This is a paragraph of normal running text. It has several sentences, \
+            and each of them ends with punctuation. Readers would enjoy it.\n"
+            .repeat(5);
+        let boilerplate = "Home | About | Contact\n© 2024\n>> >> >>";
+        assert_eq!(token_count("a b  c\nd"), 4);
+        assert!(quality_score(&prose) > 0.9);
+        assert!(quality_score(boilerplate) < 0.5);
+        assert_eq!(quality_score(""), 0.0);
+    }
+}
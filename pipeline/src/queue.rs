@@ -0,0 +1,184 @@
+use std::path::PathBuf;
+
+use futures_util::future::BoxFuture;
+
+use crate::config_check::ConfigCheck;
+
+/// A message as the batcher and worker see it, whatever broker carries it: the payload,
+/// its content type and string headers.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QueueMessage {
+    pub payload: Vec<u8>,
+    pub content_type: Option<String>,
+    pub headers: Vec<(String, String)>,
+}
+
+impl QueueMessage {
+    pub fn new(payload: Vec<u8>) -> Self {
+        Self {
+            payload,
+            ..Self::default()
+        }
+    }
+
+    pub fn with_content_type(mut self, content_type: &str) -> Self {
+        self.content_type = Some(content_type.to_string());
+        self
+    }
+
+    pub fn with_headers(mut self, headers: Vec<(String, String)>) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// The value of the first header named `key`.
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Sends messages to named queues, or topics for Kafka. A publish returns once the broker
+/// took over the message, so it is delivered at least once.
+pub trait QueueProducer: Send + Sync {
+    fn publish<'a>(
+        &'a self,
+        queue: &'a str,
+        message: &'a QueueMessage,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>>;
+}
+
+/// The messages of one queue, one at a time. `None` once the broker closed the
+/// subscription, an error for a message that could not be received.
+pub trait QueueConsumer: Send {
+    fn next(&mut self) -> BoxFuture<'_, Option<Result<Box<dyn QueueDelivery>, anyhow::Error>>>;
+}
+
+/// A received message that stays with its consumer until it is settled by one of `ack`,
+/// `requeue` or `reject`.
+pub trait QueueDelivery: Send {
+    fn message(&self) -> &QueueMessage;
+
+    /// Whether the message may have been delivered before, to this or another consumer.
+    /// Brokers that cannot tell answer `true`.
+    fn redelivered(&self) -> bool;
+
+    /// How many times the message was delivered before, where the broker counts it.
+    fn previous_deliveries(&self) -> Option<u32> {
+        None
+    }
+
+    /// Done with, the broker forgets the message.
+    fn ack(self: Box<Self>) -> BoxFuture<'static, Result<(), anyhow::Error>>;
+
+    /// Not processed, the message goes back to the queue for a later consumer.
+    fn requeue(self: Box<Self>) -> BoxFuture<'static, Result<(), anyhow::Error>>;
+
+    /// Cannot be processed. Dead-lettered where the queue is set up for it, dropped
+    /// otherwise.
+    fn reject(self: Box<Self>) -> BoxFuture<'static, Result<(), anyhow::Error>>;
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueBackend {
+    Rabbitmq,
+    /// Files in `--spool-dir`, for development without a broker.
+    Fs,
+    #[cfg(feature = "kafka")]
+    Kafka,
+    #[cfg(feature = "sqs")]
+    Sqs,
+}
+
+/// The broker of the batcher and the workers, which have to agree on it.
+#[derive(clap::Args, Debug, Clone)]
+pub struct QueueOptions {
+    /// `kafka` and `sqs` need the features of the same name. Queue names become topic and
+    /// queue names of those brokers, which have to exist.
+    #[arg(long, value_enum, default_value_t = QueueBackend::Rabbitmq)]
+    pub queue_backend: QueueBackend,
+
+    /// The queues of `--queue-backend fs`, one directory each.
+    #[arg(long, default_value = "spool")]
+    pub spool_dir: PathBuf,
+
+    /// Bootstrap servers, comma-separated `host:port`.
+    #[cfg(feature = "kafka")]
+    #[arg(long, default_value = "localhost:9092")]
+    pub kafka_brokers: String,
+
+    /// Consumer group of the workers, which share the partitions of a topic.
+    #[cfg(feature = "kafka")]
+    #[arg(long, default_value = "pipeline")]
+    pub kafka_group_id: String,
+
+    /// Register the batch schema with this Confluent Schema Registry, e.g.
+    /// `http://registry:8081`, and publish protobuf batches in its wire format.
+    #[cfg(feature = "kafka")]
+    #[arg(long)]
+    pub kafka_schema_registry_url: Option<String>,
+
+    /// How long a received message stays hidden from other workers. It has to cover the
+    /// processing of a batch, or the batch is delivered twice.
+    #[cfg(feature = "sqs")]
+    #[arg(long, default_value_t = 1800)]
+    pub sqs_visibility_timeout_secs: u32,
+}
+
+impl QueueOptions {
+    pub fn is_rabbitmq(&self) -> bool {
+        self.queue_backend == QueueBackend::Rabbitmq
+    }
+
+    /// Adds the problems of these options to `check`, e.g. missing credentials of the
+    /// backend.
+    pub fn check(&self, check: &mut ConfigCheck) {
+        match self.queue_backend {
+            #[cfg(feature = "rabbitmq")]
+            QueueBackend::Rabbitmq => check.env_var(crate::rabbitmq::RABBITMQ_CONNECTION_STRING),
+            #[cfg(not(feature = "rabbitmq"))]
+            QueueBackend::Rabbitmq => {
+                check.require(false, "--queue-backend rabbitmq needs the rabbitmq feature")
+            }
+            QueueBackend::Fs => {}
+            #[cfg(feature = "kafka")]
+            QueueBackend::Kafka => {}
+            #[cfg(feature = "sqs")]
+            QueueBackend::Sqs => {
+                check.env_var(crate::sigv4::AWS_ACCESS_KEY_ID);
+                check.env_var(crate::sigv4::AWS_SECRET_ACCESS_KEY);
+            }
+        }
+    }
+
+    /// The Kafka cluster of `--kafka-brokers`, with the schema registry if one is given.
+    #[cfg(feature = "kafka")]
+    pub fn kafka_broker(&self, http_client: &reqwest::Client) -> crate::kafka::KafkaBroker {
+        let broker = crate::kafka::KafkaBroker::new(&self.kafka_brokers, &self.kafka_group_id);
+        match self.kafka_schema_registry_url.as_deref() {
+            Some(url) => broker
+                .with_schema_registry(crate::kafka::SchemaRegistry::new(url, http_client.clone())),
+            None => broker,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::queue::QueueMessage;
+
+    #[test]
+    fn looks_up_headers() {
+        let message = QueueMessage::new(b"[]".to_vec())
+            .with_content_type("application/json")
+            .with_headers(vec![
+                ("crawl".to_string(), "CC-MAIN-2024-30".to_string()),
+                ("crawl".to_string(), "CC-MAIN-2024-33".to_string()),
+            ]);
+        assert_eq!(message.header("crawl"), Some("CC-MAIN-2024-30"));
+        assert_eq!(message.header("shard"), None);
+        assert_eq!(message.content_type.as_deref(), Some("application/json"));
+    }
+}
@@ -0,0 +1,130 @@
+use std::sync::Arc;
+
+use crate::{output::Document, state_store::StateStore};
+
+/// Caps the documents written per domain, counted in the state store that every consumer
+/// task, worker and run writing to the same corpus shares. The count survives restarts,
+/// so a later run or crawl only fills up what earlier ones left of a quota.
+///
+/// The documents of a batch are held back until the batch is committed and then admitted
+/// in one transaction, so a batch that fails before its commit counts nothing. The IDs of
+/// the admitted documents are stored too, so a redelivered batch whose outputs were
+/// rolled back is admitted again without counting twice.
+pub struct DomainQuota {
+    store: Arc<dyn StateStore>,
+    max_documents: u64,
+    pending: Vec<Document>,
+}
+
+impl DomainQuota {
+    pub fn new(store: Arc<dyn StateStore>, max_documents: u64) -> Self {
+        Self {
+            store,
+            max_documents,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Holds `document` back until [`DomainQuota::commit`].
+    pub fn push(&mut self, document: Document) {
+        self.pending.push(document);
+    }
+
+    /// Admits the documents held back since the last commit, in order, and returns them
+    /// with whether each may be written. Documents without a domain are always admitted.
+    pub fn commit(&mut self) -> Result<Vec<(Document, bool)>, anyhow::Error> {
+        let documents = std::mem::take(&mut self.pending);
+        let domains = documents.iter().map(Document::domain).collect::<Vec<_>>();
+        let claims = documents
+            .iter()
+            .zip(&domains)
+            .filter_map(|(document, domain)| Some((domain.clone()?, document.id.clone())))
+            .collect::<Vec<_>>();
+        let mut admitted = self
+            .store
+            .admit_documents(self.max_documents, &claims)?
+            .into_iter();
+        Ok(documents
+            .into_iter()
+            .zip(domains)
+            .map(|(document, domain)| {
+                let admit = domain.is_none() || admitted.next().unwrap_or(false);
+                (document, admit)
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{output::Document, quota::DomainQuota, run_db::RunDb};
+
+    fn document(id: &str, url: &str) -> Document {
+        Document {
+            id: id.to_string(),
+            url: url.to_string(),
+            timestamp: "20240722120756".to_string(),
+            language: "eng".to_string(),
+            token_count: 1,
+            text: "text".to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Pushes one document per `(id, url)` and commits them as one batch.
+    fn admit(quota: &mut DomainQuota, documents: &[(&str, &str)]) -> Vec<bool> {
+        for (id, url) in documents {
+            quota.push(document(id, url));
+        }
+        quota
+            .commit()
+            .unwrap()
+            .into_iter()
+            .map(|(_, admitted)| admitted)
+            .collect()
+    }
+
+    #[test]
+    fn caps_documents_per_domain_across_opens() {
+        let path =
+            std::env::temp_dir().join(format!("pipeline-quota-{}.sqlite", std::process::id()));
+        let store = Arc::new(RunDb::open(&path).unwrap());
+        let mut quota = DomainQuota::new(store.clone(), 2);
+        assert_eq!(
+            admit(
+                &mut quota,
+                &[
+                    ("1", "https://a.example/1"),
+                    ("2", "https://a.example/2"),
+                    ("3", "https://a.example/3"),
+                    ("4", "https://b.example/"),
+                ]
+            ),
+            vec![true, true, false, true]
+        );
+        // A redelivered document was counted already.
+        assert_eq!(
+            admit(
+                &mut quota,
+                &[("2", "https://a.example/2"), ("5", "not a url")]
+            ),
+            vec![true, true]
+        );
+        // A batch that is never committed counts nothing.
+        quota.push(document("6", "https://a.example/6"));
+        drop(quota);
+
+        // Another run writing to the same corpus.
+        let mut quota = DomainQuota::new(store, 3);
+        assert_eq!(
+            admit(
+                &mut quota,
+                &[("7", "https://a.example/7"), ("8", "https://a.example/8")]
+            ),
+            vec![true, false]
+        );
+        std::fs::remove_file(path).ok();
+    }
+}
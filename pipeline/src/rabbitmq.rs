@@ -1,18 +1,44 @@
-use std::time::Duration;
+use std::{
+    future::Future,
+    mem,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
 
 use anyhow::Context;
+use futures_util::{future::BoxFuture, StreamExt};
 use lapin::{
-    options::{BasicConsumeOptions, BasicQosOptions, QueueDeclareOptions},
-    types::FieldTable,
-    Channel, Connection, ConnectionProperties, Queue,
+    message::Delivery,
+    options::{
+        BasicAckOptions, BasicConsumeOptions, BasicNackOptions, BasicPublishOptions,
+        BasicQosOptions, BasicRejectOptions, ConfirmSelectOptions, ExchangeDeclareOptions,
+        QueueBindOptions, QueueDeclareOptions,
+    },
+    publisher_confirm::Confirmation,
+    types::{AMQPValue, FieldTable},
+    BasicProperties, Channel, Connection, ConnectionProperties, ExchangeKind, Queue,
+};
+
+use crate::{
+    metrics::{ExportMetrics, PrometheusText},
+    queue::{QueueConsumer, QueueDelivery, QueueMessage, QueueProducer},
 };
 
 pub const BATCH_SIZE: usize = 1000;
 pub const CC_QUEUE_NAME: &str = "batches";
+/// Workers take batches from this queue before any of [`CC_QUEUE_NAME`], so targeted
+/// jobs do not wait behind the backlog of a whole crawl.
+pub const CC_PRIORITY_QUEUE_NAME: &str = "batches-priority";
+/// Workers publish their per-domain rejection summaries here for `batcher --adaptive-sampling`.
+pub const CC_FEEDBACK_QUEUE_NAME: &str = "feedback";
 const RABBIT_MQ_TIMEOUT: Duration = Duration::from_secs(20);
+const BLOCKED_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The environment variable holding the AMQP URL of the broker.
+pub const RABBITMQ_CONNECTION_STRING: &str = "RABBITMQ_CONNECTION_STRING";
 
 pub fn get_rabbitmq_connection_string() -> String {
-    std::env::var("RABBITMQ_CONNECTION_STRING").expect("RABBITMQ_CONNECTION_STRING must be set.")
+    std::env::var(RABBITMQ_CONNECTION_STRING).expect("RABBITMQ_CONNECTION_STRING must be set.")
 }
 
 #[tracing::instrument]
@@ -37,14 +63,20 @@ pub async fn rabbitmq_channel_with_queue(
     Ok((channel, queue))
 }
 
+/// Declares `queue_name` with `arguments`, e.g. those of [`crate::dlq`]. Queues of an
+/// explicit `x-queue-type` are durable, which quorum queues have to be.
 pub async fn rabbitmq_declare_queue(
     channel: &Channel,
     queue_name: &str,
     arguments: FieldTable,
 ) -> Result<Queue, anyhow::Error> {
+    let options = QueueDeclareOptions {
+        durable: arguments.contains_key("x-queue-type"),
+        ..Default::default()
+    };
     let queue = tokio::time::timeout(
         RABBIT_MQ_TIMEOUT,
-        channel.queue_declare(queue_name, QueueDeclareOptions::default(), arguments),
+        channel.queue_declare(queue_name, options, arguments),
     )
     .await
     .context("Timed out while trying to declare a RabbitMQ queue")?
@@ -88,3 +120,585 @@ pub async fn rabbitmq_consumer(
 
     Ok(consumer)
 }
+
+/// Parses a `key=value` command line argument into a message header.
+pub fn parse_header(arg: &str) -> Result<(String, String), anyhow::Error> {
+    let (key, value) = arg
+        .split_once('=')
+        .with_context(|| format!("Header {arg} is not of the form key=value"))?;
+    Ok((key.to_string(), value.to_string()))
+}
+
+pub fn headers_field_table(headers: &[(String, String)]) -> FieldTable {
+    let mut table = FieldTable::default();
+    for (key, value) in headers {
+        table.insert(
+            key.as_str().into(),
+            AMQPValue::LongString(value.as_str().into()),
+        );
+    }
+    table
+}
+
+/// The value of a string header of a delivery.
+pub fn header_value(headers: &Option<FieldTable>, key: &str) -> Option<String> {
+    match headers.as_ref()?.inner().get(key)? {
+        AMQPValue::LongString(value) => {
+            Some(String::from_utf8_lossy(value.as_bytes()).into_owned())
+        }
+        AMQPValue::ShortString(value) => Some(value.as_str().to_string()),
+        _ => None,
+    }
+}
+
+/// Stable bucket of a domain, so consumers can subscribe to a fixed slice of all domains.
+pub fn domain_hash_bucket(domain: &str, num_buckets: u32) -> u32 {
+    crc32fast::hash(domain.as_bytes()) % num_buckets.max(1)
+}
+
+pub async fn rabbitmq_declare_headers_exchange(
+    channel: &Channel,
+    exchange_name: &str,
+) -> Result<(), anyhow::Error> {
+    tokio::time::timeout(
+        RABBIT_MQ_TIMEOUT,
+        channel.exchange_declare(
+            exchange_name,
+            ExchangeKind::Headers,
+            ExchangeDeclareOptions::default(),
+            FieldTable::default(),
+        ),
+    )
+    .await
+    .context("Timed out while trying to declare a RabbitMQ exchange")?
+    .context("Failed to declare RabbitMQ exchange")?;
+    Ok(())
+}
+
+/// Binds `queue_name` to a headers exchange so it only receives messages carrying all
+/// of `headers`. An empty list of headers receives every message.
+pub async fn rabbitmq_bind_queue_by_headers(
+    channel: &Channel,
+    queue_name: &str,
+    exchange_name: &str,
+    headers: &[(String, String)],
+) -> Result<(), anyhow::Error> {
+    let mut arguments = headers_field_table(headers);
+    arguments.insert("x-match".into(), AMQPValue::LongString("all".into()));
+    tokio::time::timeout(
+        RABBIT_MQ_TIMEOUT,
+        channel.queue_bind(
+            queue_name,
+            exchange_name,
+            "",
+            QueueBindOptions::default(),
+            arguments,
+        ),
+    )
+    .await
+    .context("Timed out while trying to bind a RabbitMQ queue")?
+    .context("Failed to bind RabbitMQ queue")?;
+    Ok(())
+}
+
+/// How often and how long the broker applied flow control to this process, exported on
+/// `/metrics`.
+pub struct FlowControlStats {
+    blocked: AtomicBool,
+    times_blocked: AtomicU64,
+    blocked_micros: AtomicU64,
+}
+
+/// Of the publishing connection of the batcher.
+pub static BROKER_FLOW_CONTROL: FlowControlStats = FlowControlStats::new();
+
+impl FlowControlStats {
+    pub const fn new() -> Self {
+        Self {
+            blocked: AtomicBool::new(false),
+            times_blocked: AtomicU64::new(0),
+            blocked_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn times_blocked(&self) -> u64 {
+        self.times_blocked.load(Ordering::Relaxed)
+    }
+
+    /// Waits as long as `is_blocked` holds, polling it, and records the pause.
+    pub async fn wait_while(&self, is_blocked: impl Fn() -> bool) {
+        if !is_blocked() {
+            return;
+        }
+        tracing::warn!("RabbitMQ blocked the connection, pausing publishing");
+        self.blocked.store(true, Ordering::Relaxed);
+        self.times_blocked.fetch_add(1, Ordering::Relaxed);
+        let start = Instant::now();
+        while is_blocked() {
+            tokio::time::sleep(BLOCKED_POLL_INTERVAL).await;
+        }
+        self.blocked_micros
+            .fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+        self.blocked.store(false, Ordering::Relaxed);
+        tracing::info!(
+            "RabbitMQ unblocked the connection after {:.1}s, resuming publishing",
+            start.elapsed().as_secs_f64()
+        );
+    }
+}
+
+impl ExportMetrics for FlowControlStats {
+    fn export(&self, text: &mut PrometheusText) {
+        text.gauge(
+            "pipeline_broker_blocked",
+            "Whether the broker currently blocks publishing.",
+            u8::from(self.blocked.load(Ordering::Relaxed)),
+        );
+        text.counter(
+            "pipeline_broker_blocked_total",
+            "Times the broker blocked publishing, e.g. on a memory or disk alarm.",
+            self.times_blocked(),
+        );
+        text.counter(
+            "pipeline_broker_blocked_seconds_total",
+            "Time publishing was paused because the broker blocked the connection.",
+            self.blocked_micros.load(Ordering::Relaxed) as f64 / 1e6,
+        );
+    }
+}
+
+impl Default for FlowControlStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Waits while the broker blocks `conn` because of a resource alarm. lapin holds back
+/// every frame of a blocked connection, so a publish would only hang until then.
+pub async fn wait_while_blocked(conn: &Connection) {
+    BROKER_FLOW_CONTROL
+        .wait_while(|| conn.status().blocked())
+        .await;
+}
+
+/// How publishes of a [`ReliablePublisher`] were confirmed, exported on `/metrics`.
+pub struct ConfirmStats {
+    acked: AtomicU64,
+    nacked: AtomicU64,
+    timed_out: AtomicU64,
+}
+
+/// Of the batches published by the batcher.
+pub static PUBLISH_CONFIRMS: ConfirmStats = ConfirmStats::new();
+
+impl ConfirmStats {
+    pub const fn new() -> Self {
+        Self {
+            acked: AtomicU64::new(0),
+            nacked: AtomicU64::new(0),
+            timed_out: AtomicU64::new(0),
+        }
+    }
+
+    pub fn acked(&self) -> u64 {
+        self.acked.load(Ordering::Relaxed)
+    }
+
+    pub fn nacked(&self) -> u64 {
+        self.nacked.load(Ordering::Relaxed)
+    }
+
+    pub fn timed_out(&self) -> u64 {
+        self.timed_out.load(Ordering::Relaxed)
+    }
+}
+
+impl ExportMetrics for ConfirmStats {
+    fn export(&self, text: &mut PrometheusText) {
+        text.counter(
+            "pipeline_publish_acked_total",
+            "Publishes the broker confirmed.",
+            self.acked(),
+        );
+        text.counter(
+            "pipeline_publish_nacked_total",
+            "Publishes the broker rejected, which were published again.",
+            self.nacked(),
+        );
+        text.counter(
+            "pipeline_publish_confirm_timeouts_total",
+            "Publishes without a confirmation in time, which were published again.",
+            self.timed_out(),
+        );
+    }
+}
+
+impl Default for ConfirmStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Runs `attempt`, a publish together with its confirmation, until the broker acks it,
+/// at most `max_attempts` times. Nacks and confirmations that take longer than
+/// `confirm_timeout` are retried, errors of the channel are returned right away.
+async fn publish_until_confirmed<F, Fut>(
+    stats: &ConfirmStats,
+    max_attempts: usize,
+    confirm_timeout: Duration,
+    mut attempt: F,
+) -> Result<(), anyhow::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<Confirmation, anyhow::Error>>,
+{
+    for number in 1..=max_attempts.max(1) {
+        match tokio::time::timeout(confirm_timeout, attempt()).await {
+            Ok(Ok(Confirmation::Ack(_) | Confirmation::NotRequested)) => {
+                stats.acked.fetch_add(1, Ordering::Relaxed);
+                return Ok(());
+            }
+            Ok(Ok(Confirmation::Nack(_))) => {
+                stats.nacked.fetch_add(1, Ordering::Relaxed);
+                tracing::warn!("RabbitMQ nacked publish attempt {}", number);
+            }
+            Ok(Err(e)) => return Err(e),
+            Err(_) => {
+                stats.timed_out.fetch_add(1, Ordering::Relaxed);
+                tracing::warn!(
+                    "No confirmation of publish attempt {} within {:?}",
+                    number,
+                    confirm_timeout
+                );
+            }
+        }
+    }
+    anyhow::bail!("RabbitMQ did not confirm the publish in {max_attempts} attempts")
+}
+
+/// Publishes on a channel in confirm mode and waits for the broker to take over each
+/// message, publishing it again on a nack or when the confirmation does not arrive in
+/// time. A message is therefore delivered at least once, and after a timeout possibly
+/// twice, which consumers have to tolerate.
+pub struct ReliablePublisher {
+    channel: Channel,
+    confirm_timeout: Duration,
+    max_attempts: usize,
+}
+
+impl ReliablePublisher {
+    /// Puts `channel` into confirm mode.
+    pub async fn new(channel: Channel) -> Result<Self, anyhow::Error> {
+        tokio::time::timeout(
+            RABBIT_MQ_TIMEOUT,
+            channel.confirm_select(ConfirmSelectOptions::default()),
+        )
+        .await
+        .context("Timed out while trying to enable publisher confirms")?
+        .context("Failed to enable publisher confirms")?;
+        Ok(Self {
+            channel,
+            confirm_timeout: RABBIT_MQ_TIMEOUT,
+            max_attempts: 5,
+        })
+    }
+
+    pub fn with_confirm_timeout(mut self, confirm_timeout: Duration) -> Self {
+        self.confirm_timeout = confirm_timeout;
+        self
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: usize) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    pub fn channel(&self) -> &Channel {
+        &self.channel
+    }
+
+    /// Publishes `payload` and returns once the broker confirmed it.
+    pub async fn publish(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        payload: &[u8],
+        properties: BasicProperties,
+    ) -> Result<(), anyhow::Error> {
+        publish_until_confirmed(
+            &PUBLISH_CONFIRMS,
+            self.max_attempts,
+            self.confirm_timeout,
+            || async {
+                let confirm = self
+                    .channel
+                    .basic_publish(
+                        exchange,
+                        routing_key,
+                        BasicPublishOptions::default(),
+                        payload,
+                        properties.clone(),
+                    )
+                    .await
+                    .context("rabbitmq basic publish")?;
+                confirm.await.context("rabbitmq publisher confirm")
+            },
+        )
+        .await
+    }
+}
+
+/// The properties RabbitMQ carries the content type and headers of `message` in.
+pub fn message_properties(message: &QueueMessage) -> BasicProperties {
+    let properties = BasicProperties::default().with_headers(headers_field_table(&message.headers));
+    match message.content_type.as_deref() {
+        Some(content_type) => properties.with_content_type(content_type.into()),
+        None => properties,
+    }
+}
+
+/// The message of `payload` and its properties. Headers that are not strings, like the
+/// `x-death` of the broker, are left out.
+pub fn queue_message(payload: Vec<u8>, properties: &BasicProperties) -> QueueMessage {
+    let headers = properties
+        .headers()
+        .as_ref()
+        .map_or_else(Vec::new, |table| {
+            table
+                .inner()
+                .keys()
+                .filter_map(|key| {
+                    let value = header_value(properties.headers(), key.as_str())?;
+                    Some((key.as_str().to_string(), value))
+                })
+                .collect()
+        });
+    QueueMessage {
+        payload,
+        content_type: properties
+            .content_type()
+            .as_ref()
+            .map(|content_type| content_type.as_str().to_string()),
+        headers,
+    }
+}
+
+/// Publishes through a [`ReliablePublisher`], to the queue directly or to an exchange
+/// with the queue name as routing key.
+pub struct RabbitProducer {
+    publisher: ReliablePublisher,
+    exchange: String,
+}
+
+impl RabbitProducer {
+    pub fn new(publisher: ReliablePublisher) -> Self {
+        Self {
+            publisher,
+            exchange: String::new(),
+        }
+    }
+
+    pub fn with_exchange(mut self, exchange: &str) -> Self {
+        self.exchange = exchange.to_string();
+        self
+    }
+}
+
+impl QueueProducer for RabbitProducer {
+    fn publish<'a>(
+        &'a self,
+        queue: &'a str,
+        message: &'a QueueMessage,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(self.publisher.publish(
+            &self.exchange,
+            queue,
+            &message.payload,
+            message_properties(message),
+        ))
+    }
+}
+
+pub struct RabbitConsumer(pub lapin::Consumer);
+
+struct RabbitDelivery {
+    delivery: Delivery,
+    message: QueueMessage,
+}
+
+impl QueueConsumer for RabbitConsumer {
+    fn next(&mut self) -> BoxFuture<'_, Option<Result<Box<dyn QueueDelivery>, anyhow::Error>>> {
+        Box::pin(async {
+            let mut delivery = match self.0.next().await? {
+                Ok(delivery) => delivery,
+                Err(e) => return Some(Err(e.into())),
+            };
+            let message = queue_message(mem::take(&mut delivery.data), &delivery.properties);
+            Some(Ok(
+                Box::new(RabbitDelivery { delivery, message }) as Box<dyn QueueDelivery>
+            ))
+        })
+    }
+}
+
+impl QueueDelivery for RabbitDelivery {
+    fn message(&self) -> &QueueMessage {
+        &self.message
+    }
+
+    fn redelivered(&self) -> bool {
+        self.delivery.redelivered
+    }
+
+    /// The `x-delivery-count` of quorum queues, which is missing on the first delivery.
+    fn previous_deliveries(&self) -> Option<u32> {
+        let headers = self.delivery.properties.headers().as_ref();
+        match headers.and_then(|headers| headers.inner().get("x-delivery-count")) {
+            Some(AMQPValue::LongLongInt(count)) => u32::try_from(*count).ok(),
+            Some(AMQPValue::LongInt(count)) => u32::try_from(*count).ok(),
+            _ => Some(0),
+        }
+    }
+
+    fn ack(self: Box<Self>) -> BoxFuture<'static, Result<(), anyhow::Error>> {
+        Box::pin(async move { Ok(self.delivery.ack(BasicAckOptions::default()).await?) })
+    }
+
+    fn requeue(self: Box<Self>) -> BoxFuture<'static, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let options = BasicNackOptions {
+                requeue: true,
+                ..Default::default()
+            };
+            Ok(self.delivery.nack(options).await?)
+        })
+    }
+
+    fn reject(self: Box<Self>) -> BoxFuture<'static, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let options = BasicRejectOptions { requeue: false };
+            Ok(self.delivery.reject(options).await?)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use lapin::{publisher_confirm::Confirmation, types::AMQPValue, BasicProperties};
+
+    use crate::{
+        metrics::prometheus_text,
+        queue::QueueMessage,
+        rabbitmq::{
+            domain_hash_bucket, header_value, headers_field_table, message_properties,
+            parse_header, publish_until_confirmed, queue_message, ConfirmStats, FlowControlStats,
+        },
+    };
+
+    #[tokio::test]
+    async fn republishes_until_confirmed() {
+        let stats = ConfirmStats::new();
+        let attempts = AtomicUsize::new(0);
+        let result = publish_until_confirmed(&stats, 5, Duration::from_millis(50), || async {
+            match attempts.fetch_add(1, Ordering::Relaxed) {
+                0 => Ok(Confirmation::Nack(None)),
+                1 => std::future::pending().await,
+                _ => Ok(Confirmation::Ack(None)),
+            }
+        })
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(attempts.load(Ordering::Relaxed), 3);
+        assert_eq!(
+            (stats.acked(), stats.nacked(), stats.timed_out()),
+            (1, 1, 1)
+        );
+
+        let result = publish_until_confirmed(&stats, 2, Duration::from_millis(50), || async {
+            Ok(Confirmation::Nack(None))
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(stats.nacked(), 3);
+
+        let result = publish_until_confirmed(&stats, 5, Duration::from_millis(50), || async {
+            attempts.fetch_add(1, Ordering::Relaxed);
+            Err(anyhow::anyhow!("channel closed"))
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::Relaxed), 4);
+    }
+
+    #[tokio::test]
+    async fn pauses_while_blocked() {
+        let stats = FlowControlStats::new();
+        stats.wait_while(|| false).await;
+        assert_eq!(stats.times_blocked(), 0);
+
+        let blocked = Arc::new(AtomicBool::new(true));
+        let unblock = {
+            let blocked = blocked.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                blocked.store(false, Ordering::Relaxed);
+            })
+        };
+        stats.wait_while(|| blocked.load(Ordering::Relaxed)).await;
+        unblock.await.unwrap();
+        assert_eq!(stats.times_blocked(), 1);
+        assert!(prometheus_text(&stats).contains("pipeline_broker_blocked 0\n"));
+    }
+
+    #[test]
+    fn can_parse_headers() {
+        assert_eq!(
+            parse_header("consumer=analytics").unwrap(),
+            ("consumer".to_string(), "analytics".to_string())
+        );
+        assert!(parse_header("no-value").is_err());
+        assert_eq!(
+            domain_hash_bucket("example.com", 16),
+            domain_hash_bucket("example.com", 16)
+        );
+        assert!(domain_hash_bucket("example.com", 16) < 16);
+        let headers = Some(headers_field_table(&[(
+            "crawl".to_string(),
+            "CC-MAIN-2024-30".to_string(),
+        )]));
+        assert_eq!(
+            header_value(&headers, "crawl").as_deref(),
+            Some("CC-MAIN-2024-30")
+        );
+        assert_eq!(header_value(&headers, "shard"), None);
+    }
+
+    #[test]
+    fn converts_queue_messages() {
+        let message = QueueMessage::new(b"[]".to_vec())
+            .with_content_type("application/json")
+            .with_headers(vec![("crawl".to_string(), "CC-MAIN-2024-30".to_string())]);
+        let mut properties = message_properties(&message);
+        assert_eq!(queue_message(b"[]".to_vec(), &properties), message);
+
+        let mut headers = properties.headers().clone().unwrap();
+        headers.insert("x-delivery-count".into(), AMQPValue::LongLongInt(2));
+        properties = properties.with_headers(headers);
+        assert_eq!(
+            queue_message(b"[]".to_vec(), &properties).headers,
+            message.headers
+        );
+        assert_eq!(
+            queue_message(Vec::new(), &BasicProperties::default()),
+            QueueMessage::default()
+        );
+    }
+}
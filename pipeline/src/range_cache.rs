@@ -0,0 +1,123 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+struct CachedRange {
+    data: Arc<[u8]>,
+    last_used: u64,
+}
+
+/// Recently fetched byte ranges of WARC files, so records fetched again by a nearby batch,
+/// e.g. after a redelivery or from an overlapping list of captures, are not downloaded
+/// twice. Holds at most `capacity_bytes` of data and evicts the least recently used
+/// ranges first. A request is served from a cached range that contains it.
+pub struct RangeCache {
+    capacity_bytes: usize,
+    size_bytes: usize,
+    /// Cached ranges per file by their offset.
+    files: HashMap<String, BTreeMap<usize, CachedRange>>,
+    /// File and offset of each cached range by when it was last used.
+    by_use: BTreeMap<u64, (String, usize)>,
+    clock: u64,
+}
+
+impl RangeCache {
+    pub fn new(capacity_bytes: usize) -> Self {
+        Self {
+            capacity_bytes,
+            size_bytes: 0,
+            files: HashMap::new(),
+            by_use: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    pub fn size_bytes(&self) -> usize {
+        self.size_bytes
+    }
+
+    /// The `length` bytes at `offset` of `filename`, if a cached range covers them.
+    pub fn get(&mut self, filename: &str, offset: usize, length: usize) -> Option<Vec<u8>> {
+        self.clock += 1;
+        let ranges = self.files.get_mut(filename)?;
+        let (start, range) = ranges.range_mut(..=offset).next_back()?;
+        let skip = offset - start;
+        if skip + length > range.data.len() {
+            return None;
+        }
+        self.by_use.remove(&range.last_used);
+        range.last_used = self.clock;
+        self.by_use
+            .insert(self.clock, (filename.to_string(), *start));
+        Some(range.data[skip..skip + length].to_vec())
+    }
+
+    /// Caches `data` fetched from `offset` of `filename`, evicting older ranges to make
+    /// room. Ranges larger than the whole cache are not kept.
+    pub fn insert(&mut self, filename: &str, offset: usize, data: &[u8]) {
+        if data.len() > self.capacity_bytes {
+            return;
+        }
+        self.remove(filename, offset);
+        while self.size_bytes + data.len() > self.capacity_bytes {
+            let Some((_, (filename, offset))) = self.by_use.pop_first() else {
+                break;
+            };
+            self.remove(&filename, offset);
+        }
+        self.clock += 1;
+        self.size_bytes += data.len();
+        self.by_use
+            .insert(self.clock, (filename.to_string(), offset));
+        self.files.entry(filename.to_string()).or_default().insert(
+            offset,
+            CachedRange {
+                data: data.into(),
+                last_used: self.clock,
+            },
+        );
+    }
+
+    fn remove(&mut self, filename: &str, offset: usize) {
+        let Some(ranges) = self.files.get_mut(filename) else {
+            return;
+        };
+        if let Some(range) = ranges.remove(&offset) {
+            self.size_bytes -= range.data.len();
+            self.by_use.remove(&range.last_used);
+        }
+        if ranges.is_empty() {
+            self.files.remove(filename);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::range_cache::RangeCache;
+
+    #[test]
+    fn serves_contained_ranges_and_evicts_least_recently_used() {
+        let mut cache = RangeCache::new(10);
+        cache.insert("a.warc.gz", 100, b"0123456");
+        assert_eq!(cache.get("a.warc.gz", 100, 7).unwrap(), b"0123456");
+        assert_eq!(cache.get("a.warc.gz", 102, 3).unwrap(), b"234");
+        assert!(cache.get("a.warc.gz", 105, 3).is_none());
+        assert!(cache.get("b.warc.gz", 100, 3).is_none());
+
+        cache.insert("b.warc.gz", 0, b"abc");
+        assert_eq!(cache.size_bytes(), 10);
+        // a was used last, so b goes first.
+        cache.get("a.warc.gz", 100, 1).unwrap();
+        cache.insert("c.warc.gz", 0, b"xy");
+        assert!(cache.get("b.warc.gz", 0, 3).is_none());
+        assert!(cache.get("a.warc.gz", 100, 7).is_some());
+        assert_eq!(cache.size_bytes(), 9);
+
+        cache.insert("d.warc.gz", 0, &[0; 11]);
+        assert!(cache.get("d.warc.gz", 0, 1).is_none());
+        cache.insert("a.warc.gz", 100, b"0123");
+        assert_eq!(cache.size_bytes(), 6);
+    }
+}
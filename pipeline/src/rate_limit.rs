@@ -0,0 +1,370 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::Instant,
+};
+
+/// Spaces out requests so that at most `requests_per_sec` of them start per second.
+pub struct RateLimiter {
+    interval: Duration,
+    next_slot: tokio::sync::Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(requests_per_sec: f64) -> Self {
+        let interval = if requests_per_sec > 0.0 {
+            Duration::from_secs_f64(1.0 / requests_per_sec)
+        } else {
+            Duration::ZERO
+        };
+        Self {
+            interval,
+            next_slot: tokio::sync::Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Waits until the next request may start.
+    pub async fn acquire(&self) {
+        let mut next_slot = self.next_slot.lock().await;
+        let now = Instant::now();
+        if *next_slot > now {
+            tokio::time::sleep_until(*next_slot).await;
+        }
+        *next_slot = (*next_slot).max(now) + self.interval;
+    }
+}
+
+/// One [`RateLimiter`] per host, so that slow endpoints do not hold back fast ones.
+pub struct HostRateLimiters {
+    requests_per_sec: f64,
+    limiters: Mutex<HashMap<String, Arc<RateLimiter>>>,
+}
+
+impl HostRateLimiters {
+    pub fn new(requests_per_sec: f64) -> Self {
+        Self {
+            requests_per_sec,
+            limiters: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn for_url(&self, url: &str) -> Arc<RateLimiter> {
+        let host = url::Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+            .unwrap_or_default();
+        self.limiters
+            .lock()
+            .unwrap()
+            .entry(host)
+            .or_insert_with(|| Arc::new(RateLimiter::new(self.requests_per_sec)))
+            .clone()
+    }
+}
+
+/// Takes one token from a bucket that held `tokens` `elapsed` ago and refills at `rate`
+/// per second up to `burst`. Tokens may go below zero, which reserves a later slot. The
+/// tokens left and how long to wait for the slot are returned.
+pub fn take_token(tokens: f64, elapsed: Duration, rate: f64, burst: f64) -> (f64, Duration) {
+    let tokens = (tokens + elapsed.as_secs_f64() * rate).min(burst) - 1.0;
+    let wait = if tokens < 0.0 {
+        Duration::from_secs_f64(-tokens / rate)
+    } else {
+        Duration::ZERO
+    };
+    (tokens, wait)
+}
+
+/// A token bucket that lets `burst` requests start at once and `rate` per second after.
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, burst: u32) -> Self {
+        let burst = f64::from(burst.max(1));
+        Self {
+            rate,
+            burst,
+            state: Mutex::new((burst, Instant::now())),
+        }
+    }
+
+    /// Waits until the next request may start.
+    pub async fn acquire(&self) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            let (tokens, wait) = take_token(state.0, now - state.1, self.rate, self.burst);
+            *state = (tokens, now);
+            wait
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// The token bucket of `KEYS[1]` with rate `ARGV[1]` and burst `ARGV[2]`, as in
+/// [`take_token`], on the clock of the Redis server so all machines agree. Returns the
+/// wait in microseconds.
+#[cfg(feature = "redis")]
+const TAKE_TOKEN: &str = "
+    local time = redis.call('TIME')
+    local now = tonumber(time[1]) * 1000000 + tonumber(time[2])
+    local rate = tonumber(ARGV[1])
+    local burst = tonumber(ARGV[2])
+    local state = redis.call('HMGET', KEYS[1], 'tokens', 'at')
+    local tokens = tonumber(state[1]) or burst
+    local at = tonumber(state[2]) or now
+    tokens = math.min(burst, tokens + (now - at) * rate / 1000000) - 1
+    redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'at', tostring(now))
+    redis.call('PEXPIRE', KEYS[1], math.ceil(burst / rate * 1000) + 60000)
+    if tokens < 0 then
+        return math.ceil(-tokens / rate * 1000000)
+    end
+    return 0
+";
+
+/// Token buckets of the whole fleet in Redis, one per host.
+#[cfg(feature = "redis")]
+struct RedisBuckets {
+    client: redis::Client,
+    conn: tokio::sync::OnceCell<redis::aio::MultiplexedConnection>,
+    script: redis::Script,
+}
+
+#[cfg(feature = "redis")]
+impl RedisBuckets {
+    async fn acquire(&self, host: &str, rate: f64, burst: u32) -> Result<(), anyhow::Error> {
+        let conn = self
+            .conn
+            .get_or_try_init(|| self.client.get_multiplexed_async_connection())
+            .await?;
+        let wait_micros: u64 = self
+            .script
+            .key(format!("pipeline:rate-limit:{host}"))
+            .arg(rate)
+            .arg(burst.max(1))
+            .invoke_async(&mut conn.clone())
+            .await?;
+        tokio::time::sleep(Duration::from_micros(wait_micros)).await;
+        Ok(())
+    }
+}
+
+/// How fast this process, or with Redis the whole fleet, sends requests to each host it
+/// fetches Common Crawl data from, and how many it keeps open at once.
+pub struct FetchLimits {
+    requests_per_sec: Option<f64>,
+    burst: u32,
+    buckets: Mutex<HashMap<String, Arc<TokenBucket>>>,
+    connections: Option<Arc<Semaphore>>,
+    #[cfg(feature = "redis")]
+    redis: Option<RedisBuckets>,
+}
+
+static FETCH_LIMITS: OnceLock<FetchLimits> = OnceLock::new();
+
+impl FetchLimits {
+    pub fn new(requests_per_sec: Option<f64>, burst: u32, max_connections: Option<usize>) -> Self {
+        Self {
+            requests_per_sec,
+            burst,
+            buckets: Mutex::new(HashMap::new()),
+            connections: max_connections.map(|max| Arc::new(Semaphore::new(max.max(1)))),
+            #[cfg(feature = "redis")]
+            redis: None,
+        }
+    }
+
+    /// Shares the request rate with every process using the same Redis, instead of each
+    /// process having it to itself. Connections are still limited per process.
+    #[cfg(feature = "redis")]
+    pub fn with_redis(mut self, redis_url: &str) -> Result<Self, anyhow::Error> {
+        self.redis = Some(RedisBuckets {
+            client: redis::Client::open(redis_url)?,
+            conn: tokio::sync::OnceCell::new(),
+            script: redis::Script::new(TAKE_TOKEN),
+        });
+        Ok(self)
+    }
+
+    /// Makes these the limits of all fetches of the process. Only the first call counts.
+    pub fn install(self) {
+        if FETCH_LIMITS.set(self).is_err() {
+            tracing::debug!("Fetch limits are installed already");
+        }
+    }
+
+    /// Waits until a request to `url` may start. The request counts as open until the
+    /// returned permit is dropped. The token is taken first, so no connection is held
+    /// while waiting for one.
+    pub async fn acquire(&self, url: &str) -> Result<Option<OwnedSemaphorePermit>, anyhow::Error> {
+        self.take_token(url).await?;
+        Ok(match self.connections.as_ref() {
+            Some(connections) => Some(connections.clone().acquire_owned().await?),
+            None => None,
+        })
+    }
+
+    async fn take_token(&self, url: &str) -> Result<(), anyhow::Error> {
+        let Some(rate) = self.requests_per_sec else {
+            return Ok(());
+        };
+        let host = url::Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+            .unwrap_or_default();
+        #[cfg(feature = "redis")]
+        if let Some(redis) = self.redis.as_ref() {
+            return redis.acquire(&host, rate, self.burst).await;
+        }
+        let bucket = self
+            .buckets
+            .lock()
+            .unwrap()
+            .entry(host)
+            .or_insert_with(|| Arc::new(TokenBucket::new(rate, self.burst)))
+            .clone();
+        bucket.acquire().await;
+        Ok(())
+    }
+
+    /// Sends `request` once these limits let it start, see [`FetchLimits::acquire`].
+    pub async fn send(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<(reqwest::Response, Option<OwnedSemaphorePermit>), SendError> {
+        let (client, request) = request.build_split();
+        let request = request.map_err(SendError::Request)?;
+        let permit = self
+            .acquire(request.url().as_str())
+            .await
+            .map_err(SendError::Limiter)?;
+        let response = client.execute(request).await.map_err(SendError::Request)?;
+        Ok((response, permit))
+    }
+}
+
+/// Why [`FetchLimits::send`] got no response.
+#[derive(Debug)]
+pub enum SendError {
+    /// The limiter itself failed, e.g. Redis is unreachable.
+    Limiter(anyhow::Error),
+    Request(reqwest::Error),
+}
+
+impl std::fmt::Display for SendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SendError::Limiter(e) => write!(f, "rate limiter failed: {e}"),
+            SendError::Request(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
+/// Sends `request` within the [`FetchLimits`] of the process, if any are installed. All
+/// requests to Common Crawl data, S3 and SQS go through here.
+pub async fn send_limited(
+    request: reqwest::RequestBuilder,
+) -> Result<(reqwest::Response, Option<OwnedSemaphorePermit>), SendError> {
+    match FETCH_LIMITS.get() {
+        Some(limits) => limits.send(request).await,
+        None => Ok((request.send().await.map_err(SendError::Request)?, None)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use crate::rate_limit::{take_token, FetchLimits, HostRateLimiters, RateLimiter, TokenBucket};
+
+    #[tokio::test(start_paused = true)]
+    async fn spaces_out_requests() {
+        let limiter = RateLimiter::new(2.0);
+        let start = tokio::time::Instant::now();
+        for _ in 0..5 {
+            limiter.acquire().await;
+        }
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn shares_limiters_per_host() {
+        let limiters = HostRateLimiters::new(1.0);
+        let a = limiters.for_url("https://index.commoncrawl.org/CC-MAIN-2024-30-index");
+        let b = limiters.for_url("https://index.commoncrawl.org/collinfo.json");
+        let c = limiters.for_url("https://data.commoncrawl.org/crawl-data/");
+        assert!(Arc::ptr_eq(&a, &b));
+        assert!(!Arc::ptr_eq(&a, &c));
+    }
+
+    #[test]
+    fn refills_tokens_up_to_the_burst() {
+        let secs = Duration::from_secs_f64;
+        assert_eq!(
+            take_token(3.0, Duration::ZERO, 2.0, 3.0),
+            (2.0, Duration::ZERO)
+        );
+        assert_eq!(take_token(0.0, Duration::ZERO, 2.0, 3.0), (-1.0, secs(0.5)));
+        assert_eq!(take_token(-1.0, secs(0.25), 2.0, 3.0), (-1.5, secs(0.75)));
+        assert_eq!(take_token(0.0, secs(60.0), 2.0, 3.0), (2.0, Duration::ZERO));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lets_a_burst_through_then_keeps_the_rate() {
+        let bucket = TokenBucket::new(2.0, 3);
+        let start = tokio::time::Instant::now();
+        for _ in 0..3 {
+            bucket.acquire().await;
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        for _ in 0..4 {
+            bucket.acquire().await;
+        }
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn limits_open_requests() {
+        let limits = FetchLimits::new(None, 1, Some(2));
+        let url = "https://data.commoncrawl.org/crawl-data/a.warc.gz";
+        let first = limits.acquire(url).await.unwrap();
+        let _second = limits.acquire(url).await.unwrap();
+        let third = tokio::time::timeout(Duration::from_secs(1), limits.acquire(url)).await;
+        assert!(third.is_err());
+        drop(first);
+        assert!(limits.acquire(url).await.unwrap().is_some());
+        assert!(FetchLimits::new(None, 1, None)
+            .acquire(url)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_for_a_token_before_taking_a_connection() {
+        let limits = Arc::new(FetchLimits::new(Some(1.0), 1, Some(1)));
+        let url = "https://data.commoncrawl.org/crawl-data/a.warc.gz";
+        drop(limits.acquire(url).await.unwrap());
+        let waiting = tokio::spawn({
+            let limits = limits.clone();
+            async move { limits.acquire(url).await.unwrap() }
+        });
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let connections = limits.connections.as_ref().unwrap();
+        assert_eq!(connections.available_permits(), 1);
+        let permit = waiting.await.unwrap();
+        assert!(permit.is_some());
+        assert_eq!(connections.available_permits(), 0);
+    }
+}
@@ -0,0 +1,238 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    compression::{compression_of, open_decompressed, FrameWriter},
+    output::{output_files, Document},
+};
+
+/// URLs and document IDs to remove from stored outputs, one per line. Empty lines and
+/// lines starting with `#` are ignored.
+#[derive(Debug, Default)]
+pub struct Redactions {
+    keys: HashSet<String>,
+}
+
+impl Redactions {
+    pub fn read(path: &Path) -> Result<Self, anyhow::Error> {
+        Ok(fs::read_to_string(path)?.lines().collect())
+    }
+
+    pub fn matches(&self, document: &Document) -> bool {
+        self.keys.contains(&document.id) || self.keys.contains(&document.url)
+    }
+
+    /// Whether any of the URL or ID fields of a JSON line is redacted: `id` and `url` of
+    /// documents, A/B comparisons and robots.txt records, and `src`, `page_id` and
+    /// `page_url` of media records.
+    fn matches_line(&self, line: &serde_json::Value) -> bool {
+        ["id", "url", "src", "page_id", "page_url"]
+            .into_iter()
+            .filter_map(|field| line.get(field)?.as_str())
+            .any(|key| self.keys.contains(key))
+    }
+}
+
+impl<'a> FromIterator<&'a str> for Redactions {
+    fn from_iter<T: IntoIterator<Item = &'a str>>(lines: T) -> Self {
+        let keys = lines
+            .into_iter()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_string)
+            .collect();
+        Self { keys }
+    }
+}
+
+/// Rewrites one output file without the redacted documents, keeping its compression.
+/// The file is only replaced if something matched, and returns how many documents were
+/// removed. JSON lines may also be media, robots.txt or A/B comparison records. Parquet
+/// files need the `parquet` feature.
+pub fn redact_file(path: &Path, redactions: &Redactions) -> Result<usize, anyhow::Error> {
+    if path.extension().is_some_and(|ext| ext == "parquet") {
+        return redact_parquet(path, redactions);
+    }
+    let tmp_path = path.with_file_name(format!(".redact-{}", std::process::id()));
+    let mut writer = FrameWriter::new(
+        compression_of(path),
+        BufWriter::new(File::create(&tmp_path)?),
+    );
+    let mut num_removed = 0;
+    for line in BufReader::new(open_decompressed(path)?).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        // Lines are copied as they are, so fields this version does not know survive.
+        if redactions.matches_line(&serde_json::from_str(&line)?) {
+            num_removed += 1;
+            continue;
+        }
+        writeln!(writer, "{line}")?;
+    }
+    writer.finish_frame()?;
+    if num_removed == 0 {
+        fs::remove_file(&tmp_path)?;
+        return Ok(0);
+    }
+    writer.get_ref().unwrap().get_ref().sync_all()?;
+    drop(writer);
+    fs::rename(&tmp_path, path)?;
+    Ok(num_removed)
+}
+
+#[cfg(feature = "parquet")]
+fn redact_parquet(path: &Path, redactions: &Redactions) -> Result<usize, anyhow::Error> {
+    use crate::parquet_output::{retain_documents, retain_records, MetadataRecord};
+
+    if path.parent().and_then(Path::file_name) == Some("metadata".as_ref()) {
+        return retain_records(path, |record: &MetadataRecord| {
+            !redactions.keys.contains(&record.url)
+        });
+    }
+    retain_documents(path, |document| !redactions.matches(document))
+}
+
+#[cfg(not(feature = "parquet"))]
+fn redact_parquet(path: &Path, _redactions: &Redactions) -> Result<usize, anyhow::Error> {
+    anyhow::bail!(
+        "Cannot redact {}, built without the parquet feature",
+        path.display()
+    )
+}
+
+/// The files of the sidecar directories that hold URLs or document IDs: the media and
+/// robots.txt records and the metadata of the metadata-only mode.
+fn sidecar_files(output_dir: &Path) -> Result<Vec<PathBuf>, anyhow::Error> {
+    let mut files = Vec::new();
+    for (dir, kind) in [
+        ("media", ".jsonl"),
+        ("robotstxt", ".jsonl"),
+        ("metadata", ".parquet"),
+    ] {
+        let dir = output_dir.join(dir);
+        if !dir.is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if !name.starts_with('.') && name.contains(kind) {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Removes the redacted documents from every output file below `output_dir`, and the
+/// matching records from the media, robots.txt and metadata sidecars. No worker may be
+/// writing to the directory at the same time.
+///
+/// The host graph only counts links between hosts, which cannot be taken apart by page,
+/// so `output_dir/web_graph` is refused unless `keep_web_graph` accepts it as it is.
+pub fn redact_output(
+    output_dir: &Path,
+    redactions: &Redactions,
+    keep_web_graph: bool,
+) -> Result<Vec<(PathBuf, usize)>, anyhow::Error> {
+    let web_graph = output_dir.join("web_graph");
+    if !keep_web_graph && web_graph.is_dir() && fs::read_dir(&web_graph)?.next().is_some() {
+        anyhow::bail!(
+            "{} holds link counts of the redacted pages, which cannot be removed; delete it \
+             or pass --keep-web-graph",
+            web_graph.display()
+        );
+    }
+    let mut rewritten = Vec::new();
+    for path in output_files(output_dir)?
+        .into_iter()
+        .chain(sidecar_files(output_dir)?)
+    {
+        let num_removed = redact_file(&path, redactions)?;
+        if num_removed > 0 {
+            rewritten.push((path, num_removed));
+        }
+    }
+    Ok(rewritten)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        compression::Compression,
+        output::{output_files, read_documents, Document, LanguageRouter},
+        redact::{redact_output, Redactions},
+    };
+
+    #[test]
+    fn removes_documents_by_url_or_id() {
+        let dir = std::env::temp_dir().join(format!("pipeline-redact-{}", std::process::id()));
+        let mut router = LanguageRouter::new(&dir, "worker", Duration::from_secs(300))
+            .with_compression(Compression::Gzip(6));
+        for (id, url) in [
+            ("1", "https://a.com/"),
+            ("2", "https://b.com/"),
+            ("3", "https://c.com/"),
+        ] {
+            router
+                .write(&Document {
+                    id: id.to_string(),
+                    url: url.to_string(),
+                    timestamp: "20240722120756".to_string(),
+                    language: "eng".to_string(),
+                    token_count: 1,
+                    text: "text".to_string(),
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+        router.flush().unwrap();
+        drop(router);
+        std::fs::create_dir_all(dir.join("media")).unwrap();
+        std::fs::write(
+            dir.join("media/worker.jsonl"),
+            concat!(
+                r#"{"kind":"image","src":"https://a.com/1.png","page_url":"https://a.com/","page_id":"1","timestamp":"1"}"#,
+                "\n",
+                r#"{"kind":"image","src":"https://b.com/2.png","page_url":"https://b.com/","page_id":"2","timestamp":"1"}"#,
+                "\n",
+            ),
+        )
+        .unwrap();
+        std::fs::create_dir_all(dir.join("web_graph")).unwrap();
+        std::fs::write(dir.join("web_graph/run-worker-000000.parquet"), "").unwrap();
+
+        let redactions = ["# takedown", "https://b.com/", "3"]
+            .into_iter()
+            .collect::<Redactions>();
+        assert!(redact_output(&dir, &redactions, false).is_err());
+        let rewritten = redact_output(&dir, &redactions, true).unwrap();
+        assert_eq!(
+            rewritten,
+            vec![
+                (dir.join("eng/worker.jsonl.gz"), 2),
+                (dir.join("media/worker.jsonl"), 1)
+            ]
+        );
+        let media = std::fs::read_to_string(dir.join("media/worker.jsonl")).unwrap();
+        assert!(media.contains("https://a.com/1.png") && !media.contains("b.com"));
+        let files = output_files(&dir).unwrap();
+        assert_eq!(files.len(), 1);
+        let ids = read_documents(&files[0])
+            .unwrap()
+            .map(|document| document.unwrap().id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec!["1"]);
+        assert!(redact_output(&dir, &redactions, true).unwrap().is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
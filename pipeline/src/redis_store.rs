@@ -0,0 +1,523 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Mutex,
+};
+
+use redis::{Commands, Connection, Script};
+
+use crate::{
+    batch::BatchKey,
+    budget::{civil_from_days, unix_now, RunLimits, StopReason},
+    compare::RunCounters,
+    compliance::{DropCounts, DropReason},
+    state_store::{KeyPage, RunCost, RunStop, StateStore, Watermark},
+    traffic::{TrafficKind, TrafficTotals},
+};
+
+const RUNS: &str = "pipeline:runs";
+const COMPLETED_BATCHES: &str = "pipeline:completed-batches";
+const COMPLETED_BATCH_COUNTS: &str = "pipeline:completed-batch-counts";
+/// The earliest and latest capture of each published batch, tab-separated.
+const PUBLISHED_BATCHES: &str = "pipeline:published-batches";
+
+/// The prefixes of the keys of [`StateStore::claim_keys`], those of the content dedup and
+/// of the digest dedup. Other keys are not content keys.
+const CONTENT_KEY_PREFIXES: &[&str] = &[
+    "pipeline:content:",
+    "pipeline:template:",
+    "pipeline:minhash:",
+    "pipeline:simhash:",
+    "digest:",
+];
+
+/// The documents written per domain, and the admitted documents as `domain\tid`.
+const DOMAIN_COUNTS: &str = "pipeline:domain-counts";
+const DOMAIN_DOCUMENTS: &str = "pipeline:domain-documents";
+
+/// Admits the `ARGV[i]` domain, `ARGV[i + 1]` document pairs while their domain has fewer
+/// than `ARGV[1]` documents, see [`StateStore::admit_documents`].
+const ADMIT_DOCUMENTS: &str = "
+    local admitted = {}
+    for i = 2, #ARGV, 2 do
+        local member = ARGV[i] .. '\t' .. ARGV[i + 1]
+        if redis.call('SISMEMBER', KEYS[2], member) == 1 then
+            admitted[#admitted + 1] = 1
+        elseif tonumber(redis.call('HGET', KEYS[1], ARGV[i]) or '0') >= tonumber(ARGV[1]) then
+            admitted[#admitted + 1] = 0
+        else
+            redis.call('SADD', KEYS[2], member)
+            redis.call('HINCRBY', KEYS[1], ARGV[i], 1)
+            admitted[#admitted + 1] = 1
+        end
+    end
+    return admitted
+";
+
+/// Keeps the first stop reason `ARGV[1]`, at time `ARGV[2]`, and the first cut
+/// `ARGV[3]`, `ARGV[4]` of a run, if the run has limits.
+const MARK_PARTIAL: &str = "
+    if redis.call('EXISTS', KEYS[1]) == 0 then
+        return
+    end
+    if redis.call('HSETNX', KEYS[1], 'stop_reason', ARGV[1]) == 1 then
+        redis.call('HSET', KEYS[1], 'stopped_at', ARGV[2])
+    end
+    if ARGV[3] ~= '' and redis.call('HSETNX', KEYS[1], 'cut_shard', ARGV[3]) == 1 then
+        redis.call('HSET', KEYS[1], 'cut_batch_index', ARGV[4])
+    end
+";
+
+fn run_key(run_id: &str) -> String {
+    format!("pipeline:run:{run_id}")
+}
+
+fn traffic_key(run_id: &str) -> String {
+    format!("pipeline:traffic:{run_id}")
+}
+
+fn drops_key(run_id: &str) -> String {
+    format!("pipeline:drops:{run_id}")
+}
+
+fn configs_key(run_id: &str) -> String {
+    format!("pipeline:configs:{run_id}")
+}
+
+fn counters_key(run_id: &str) -> String {
+    format!("pipeline:counters:{run_id}")
+}
+
+fn checkpoint_key(name: &str) -> String {
+    format!("pipeline:checkpoint:{name}")
+}
+
+/// A batch key as one hash field. Crawls and CDX file names have no tabs.
+fn batch_field(key: &BatchKey) -> String {
+    format!("{}\t{}\t{}", key.crawl, key.shard, key.batch_index)
+}
+
+fn parse_batch_field(field: &str) -> Option<BatchKey> {
+    let mut parts = field.split('\t');
+    Some(BatchKey {
+        crawl: parts.next()?.to_string(),
+        shard: parts.next()?.to_string(),
+        batch_index: parts.next()?.parse().ok()?,
+    })
+}
+
+/// The current time like `CURRENT_TIMESTAMP` in SQLite, `2024-07-22 12:07:56`.
+fn now() -> String {
+    let secs = unix_now() as i64;
+    let (year, month, day) = civil_from_days(secs.div_euclid(86_400));
+    let secs_of_day = secs.rem_euclid(86_400);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}",
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
+
+/// The run state of a fleet in one Redis instance, under `pipeline:` keys. Totals are
+/// hashes that every process increments, and the few updates that have to look at the
+/// stored value first run as Lua scripts, so concurrent workers do not lose any.
+///
+/// The dedup keys are the ones of `--dedup-redis-url`, so it needs Redis 7 as well.
+pub struct RedisStore {
+    conn: Mutex<Connection>,
+}
+
+impl RedisStore {
+    pub fn connect(url: &str) -> Result<Self, anyhow::Error> {
+        let conn = redis::Client::open(url)?.get_connection()?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn add_to_hash<'a>(
+        &self,
+        key: &str,
+        counts: impl Iterator<Item = (&'a str, u64)>,
+    ) -> Result<(), anyhow::Error> {
+        let mut pipe = redis::pipe();
+        for (field, count) in counts.filter(|(_, count)| *count > 0) {
+            pipe.hincr(key, field, count).ignore();
+        }
+        pipe.query::<()>(&mut self.conn.lock().unwrap())?;
+        Ok(())
+    }
+
+    fn hash_counts(&self, key: &str) -> Result<HashMap<String, u64>, anyhow::Error> {
+        Ok(self.conn.lock().unwrap().hgetall(key)?)
+    }
+}
+
+impl StateStore for RedisStore {
+    fn add_traffic(
+        &self,
+        run_id: &str,
+        deltas: &[(TrafficKind, TrafficTotals)],
+    ) -> Result<(), anyhow::Error> {
+        let fields = deltas
+            .iter()
+            .flat_map(|(kind, delta)| {
+                [
+                    (format!("{}.bytes", kind.as_str()), delta.bytes),
+                    (format!("{}.requests", kind.as_str()), delta.requests),
+                    (format!("{}.retries", kind.as_str()), delta.retries),
+                ]
+            })
+            .collect::<Vec<_>>();
+        if fields.iter().all(|(_, count)| *count == 0) {
+            return Ok(());
+        }
+        self.conn.lock().unwrap().sadd::<_, _, ()>(RUNS, run_id)?;
+        self.add_to_hash(
+            &traffic_key(run_id),
+            fields.iter().map(|(field, count)| (field.as_str(), *count)),
+        )
+    }
+
+    fn mark_batch_complete(&self, key: &BatchKey, run_id: &str) -> Result<(), anyhow::Error> {
+        let mut conn = self.conn.lock().unwrap();
+        let is_new: bool = conn.hset_nx(COMPLETED_BATCHES, batch_field(key), run_id)?;
+        if is_new {
+            conn.hincr::<_, _, _, ()>(COMPLETED_BATCH_COUNTS, &key.crawl, 1)?;
+        }
+        Ok(())
+    }
+
+    fn is_batch_complete(&self, key: &BatchKey) -> Result<bool, anyhow::Error> {
+        Ok(self
+            .conn
+            .lock()
+            .unwrap()
+            .hexists(COMPLETED_BATCHES, batch_field(key))?)
+    }
+
+    fn completed_batches(&self) -> Result<Vec<BatchKey>, anyhow::Error> {
+        let fields: Vec<String> = self.conn.lock().unwrap().hkeys(COMPLETED_BATCHES)?;
+        Ok(fields
+            .iter()
+            .filter_map(|field| parse_batch_field(field))
+            .collect())
+    }
+
+    fn register_batch(
+        &self,
+        key: &BatchKey,
+        min_timestamp: &str,
+        max_timestamp: &str,
+    ) -> Result<(), anyhow::Error> {
+        self.conn.lock().unwrap().hset::<_, _, _, ()>(
+            PUBLISHED_BATCHES,
+            batch_field(key),
+            format!("{min_timestamp}\t{max_timestamp}"),
+        )?;
+        Ok(())
+    }
+
+    fn watermarks(&self) -> Result<Vec<Watermark>, anyhow::Error> {
+        let mut conn = self.conn.lock().unwrap();
+        let published: HashMap<String, String> = conn.hgetall(PUBLISHED_BATCHES)?;
+        let completed: HashSet<String> = conn.hkeys(COMPLETED_BATCHES)?;
+        let counts: HashMap<String, u64> = conn.hgetall(COMPLETED_BATCH_COUNTS)?;
+        // The earliest capture of the pending batches and the latest of all, per crawl.
+        let mut crawls = BTreeMap::<String, (Option<String>, String, u64)>::new();
+        for (field, timestamps) in &published {
+            let (Some(key), Some((min_timestamp, max_timestamp))) =
+                (parse_batch_field(field), timestamps.split_once('\t'))
+            else {
+                continue;
+            };
+            let (pending_min, latest, pending) = crawls.entry(key.crawl).or_default();
+            if !completed.contains(field) {
+                if pending_min.as_deref().is_none_or(|min| min_timestamp < min) {
+                    *pending_min = Some(min_timestamp.to_string());
+                }
+                *pending += 1;
+            }
+            if max_timestamp > latest.as_str() {
+                *latest = max_timestamp.to_string();
+            }
+        }
+        Ok(crawls
+            .into_iter()
+            .map(
+                |(crawl, (pending_min, latest, pending_batches))| Watermark {
+                    completed_batches: counts.get(&crawl).copied().unwrap_or_default(),
+                    crawl,
+                    low_watermark: pending_min.unwrap_or(latest),
+                    pending_batches,
+                },
+            )
+            .collect())
+    }
+
+    fn set_limits(&self, run_id: &str, limits: &RunLimits) -> Result<(), anyhow::Error> {
+        let key = run_key(run_id);
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        // An empty hash would not exist, but a run with limits set has to.
+        pipe.hset(&key, "run_id", run_id).ignore();
+        for (field, value) in [
+            ("deadline_unix", limits.deadline_unix),
+            ("max_cost_bytes", limits.max_cost_bytes),
+        ] {
+            match value {
+                Some(value) => pipe.hset(&key, field, value).ignore(),
+                None => pipe.hdel(&key, field).ignore(),
+            };
+        }
+        pipe.query::<()>(&mut self.conn.lock().unwrap())?;
+        Ok(())
+    }
+
+    fn limits(&self, run_id: &str) -> Result<RunLimits, anyhow::Error> {
+        let (deadline_unix, max_cost_bytes) = self
+            .conn
+            .lock()
+            .unwrap()
+            .hmget(run_key(run_id), &["deadline_unix", "max_cost_bytes"])?;
+        Ok(RunLimits {
+            deadline_unix,
+            max_cost_bytes,
+        })
+    }
+
+    fn mark_partial(
+        &self,
+        run_id: &str,
+        reason: StopReason,
+        cut: Option<&BatchKey>,
+    ) -> Result<(), anyhow::Error> {
+        Script::new(MARK_PARTIAL)
+            .key(run_key(run_id))
+            .arg(reason.as_str())
+            .arg(now())
+            .arg(cut.map(|key| key.shard.as_str()).unwrap_or_default())
+            .arg(cut.map(|key| key.batch_index).unwrap_or_default())
+            .invoke::<()>(&mut self.conn.lock().unwrap())?;
+        Ok(())
+    }
+
+    fn run_stop(&self, run_id: &str, crawl: &str) -> Result<Option<RunStop>, anyhow::Error> {
+        let (reason, cut_shard, cut_batch_index, stopped_at): (
+            Option<String>,
+            Option<String>,
+            Option<usize>,
+            Option<String>,
+        ) = self.conn.lock().unwrap().hmget(
+            run_key(run_id),
+            &["stop_reason", "cut_shard", "cut_batch_index", "stopped_at"],
+        )?;
+        Ok(reason.map(|reason| RunStop {
+            reason,
+            cut: cut_shard
+                .zip(cut_batch_index)
+                .map(|(shard, batch_index)| BatchKey {
+                    crawl: crawl.to_string(),
+                    shard,
+                    batch_index,
+                }),
+            stopped_at: stopped_at.unwrap_or_default(),
+        }))
+    }
+
+    fn add_drops(&self, run_id: &str, counts: &DropCounts) -> Result<(), anyhow::Error> {
+        self.add_to_hash(
+            &drops_key(run_id),
+            counts
+                .iter()
+                .map(|(reason, count)| (reason.as_str(), count)),
+        )
+    }
+
+    fn drops(&self, run_id: &str) -> Result<DropCounts, anyhow::Error> {
+        let mut counts = DropCounts::default();
+        for (reason, count) in self.hash_counts(&drops_key(run_id))? {
+            match DropReason::ALL.iter().find(|r| r.as_str() == reason) {
+                Some(reason) => counts.add(*reason, count),
+                None => tracing::warn!("Ignoring unknown drop reason {}", reason),
+            }
+        }
+        Ok(counts)
+    }
+
+    fn record_config(
+        &self,
+        run_id: &str,
+        process: &str,
+        args: &[String],
+    ) -> Result<(), anyhow::Error> {
+        let config = serde_json::to_string(&(process, args))?;
+        self.conn
+            .lock()
+            .unwrap()
+            .sadd::<_, _, ()>(configs_key(run_id), config)?;
+        Ok(())
+    }
+
+    fn configs(&self, run_id: &str) -> Result<Vec<(String, Vec<String>)>, anyhow::Error> {
+        let members: Vec<String> = self.conn.lock().unwrap().smembers(configs_key(run_id))?;
+        let mut configs = members
+            .iter()
+            .map(|member| serde_json::from_str::<(String, Vec<String>)>(member))
+            .collect::<Result<Vec<_>, _>>()?;
+        // Ordered like the SQLite run DB, by process and then the JSON of the arguments.
+        configs.sort_by_cached_key(|(process, args)| {
+            (process.clone(), serde_json::to_string(args).unwrap())
+        });
+        Ok(configs)
+    }
+
+    fn add_counters(&self, run_id: &str, counters: &RunCounters) -> Result<(), anyhow::Error> {
+        self.add_to_hash(&counters_key(run_id), counters.iter())
+    }
+
+    fn counters(&self, run_id: &str) -> Result<RunCounters, anyhow::Error> {
+        let mut counters = RunCounters::default();
+        for (name, count) in self.hash_counts(&counters_key(run_id))? {
+            counters.add(&name, count);
+        }
+        Ok(counters)
+    }
+
+    fn costs(&self) -> Result<Vec<RunCost>, anyhow::Error> {
+        let mut run_ids: Vec<String> = self.conn.lock().unwrap().smembers(RUNS)?;
+        run_ids.sort();
+        let mut costs = Vec::new();
+        for run_id in run_ids {
+            let traffic = self.hash_counts(&traffic_key(&run_id))?;
+            let get = |field: &str| traffic.get(field).copied().unwrap_or_default();
+            let sum = |total: &str| {
+                TrafficKind::ALL
+                    .iter()
+                    .map(|kind| get(&format!("{}.{total}", kind.as_str())))
+                    .sum()
+            };
+            costs.push(RunCost {
+                index_bytes: get("index.bytes"),
+                warc_bytes: get("warc.bytes"),
+                index_api_bytes: get("index_api.bytes"),
+                requests: sum("requests"),
+                retries: sum("retries"),
+                run_id,
+            });
+        }
+        Ok(costs)
+    }
+
+    /// `SET NX GET` needs Redis 7, it returns the ID already stored.
+    fn claim_keys(&self, claims: &[(String, String)]) -> Result<Vec<String>, anyhow::Error> {
+        let mut pipe = redis::pipe();
+        for (key, document_id) in claims {
+            pipe.cmd("SET")
+                .arg(key)
+                .arg(document_id)
+                .arg("NX")
+                .arg("GET");
+        }
+        let replies = pipe.query::<Vec<Option<String>>>(&mut self.conn.lock().unwrap())?;
+        Ok(claims
+            .iter()
+            .zip(replies)
+            .map(|((_, document_id), first_id)| first_id.unwrap_or_else(|| document_id.clone()))
+            .collect())
+    }
+
+    /// Scans one prefix of [`CONTENT_KEY_PREFIXES`] after the other. The cursor is the
+    /// index of the prefix and the `SCAN` cursor within it, and pages may be shorter or
+    /// longer than `limit`.
+    fn content_keys(&self, cursor: Option<&str>, limit: usize) -> Result<KeyPage, anyhow::Error> {
+        let (prefix_index, scan_cursor) = match cursor {
+            Some(cursor) => {
+                let (prefix_index, scan_cursor) = cursor
+                    .split_once(':')
+                    .ok_or_else(|| anyhow::anyhow!("Invalid content key cursor {cursor}"))?;
+                (prefix_index.parse::<usize>()?, scan_cursor.parse::<u64>()?)
+            }
+            None => (0, 0),
+        };
+        let Some(prefix) = CONTENT_KEY_PREFIXES.get(prefix_index) else {
+            return Ok(KeyPage::default());
+        };
+        let mut conn = self.conn.lock().unwrap();
+        let (scan_cursor, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+            .arg(scan_cursor)
+            .arg("MATCH")
+            .arg(format!("{prefix}*"))
+            .arg("COUNT")
+            .arg(limit)
+            .query(&mut *conn)?;
+        let ids: Vec<Option<String>> = if keys.is_empty() {
+            Vec::new()
+        } else {
+            redis::cmd("MGET").arg(&keys).query(&mut *conn)?
+        };
+        let page = keys
+            .into_iter()
+            .zip(ids)
+            .filter_map(|(key, id)| Some((key, id?)))
+            .collect();
+        let next = match scan_cursor {
+            0 if prefix_index + 1 == CONTENT_KEY_PREFIXES.len() => None,
+            0 => Some(format!("{}:0", prefix_index + 1)),
+            scan_cursor => Some(format!("{prefix_index}:{scan_cursor}")),
+        };
+        Ok(KeyPage { keys: page, next })
+    }
+
+    fn admit_documents(
+        &self,
+        max_documents: u64,
+        documents: &[(String, String)],
+    ) -> Result<Vec<bool>, anyhow::Error> {
+        if documents.is_empty() {
+            return Ok(Vec::new());
+        }
+        let script = Script::new(ADMIT_DOCUMENTS);
+        let mut invocation = script.key(DOMAIN_COUNTS);
+        invocation.key(DOMAIN_DOCUMENTS).arg(max_documents);
+        for (domain, document_id) in documents {
+            invocation.arg(domain).arg(document_id);
+        }
+        let admitted = invocation.invoke::<Vec<i64>>(&mut self.conn.lock().unwrap())?;
+        Ok(admitted.into_iter().map(|admitted| admitted == 1).collect())
+    }
+
+    fn read_checkpoint(&self, name: &str) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        Ok(self.conn.lock().unwrap().get(checkpoint_key(name))?)
+    }
+
+    fn write_checkpoint(&self, name: &str, data: &[u8]) -> Result<(), anyhow::Error> {
+        self.conn
+            .lock()
+            .unwrap()
+            .set::<_, _, ()>(checkpoint_key(name), data)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{redis_store::RedisStore, state_store::conformance};
+
+    /// Runs the assertions of the run DB on the Redis database at
+    /// `PIPELINE_TEST_REDIS_URL`, which is flushed before each of them. Passes without a
+    /// database if the variable is not set.
+    #[test]
+    fn passes_the_state_store_assertions() {
+        let Ok(url) = std::env::var("PIPELINE_TEST_REDIS_URL") else {
+            return;
+        };
+        for check in conformance::ALL {
+            let store = RedisStore::connect(&url).unwrap();
+            redis::cmd("FLUSHDB")
+                .query::<()>(&mut store.conn.lock().unwrap())
+                .unwrap();
+            check(&store);
+        }
+    }
+}
@@ -0,0 +1,213 @@
+use std::{fmt, path::Path, sync::OnceLock};
+
+/// The CPUs and memory this process may use: the limits of its cgroup when it runs in a
+/// container, the machine otherwise. Defaults for concurrency and buffer sizes are derived
+/// from them, so a worker in a 2 CPU container does not size itself for the 64 CPU host.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResourceLimits {
+    /// Fractional for CPU quotas like 1.5 CPUs.
+    pub cpus: f64,
+    /// `None` if neither the cgroup nor `/proc/meminfo` tell.
+    pub memory_bytes: Option<u64>,
+    /// Whether a cgroup limit is lower than the machine.
+    pub from_cgroup: bool,
+}
+
+/// cgroup v1 reports no memory limit as a number close to `i64::MAX`.
+const UNLIMITED_V1: u64 = 1 << 62;
+
+/// The limits of the running process, detected once.
+pub fn limits() -> &'static ResourceLimits {
+    static LIMITS: OnceLock<ResourceLimits> = OnceLock::new();
+    LIMITS.get_or_init(|| {
+        let machine_memory = std::fs::read_to_string("/proc/meminfo")
+            .ok()
+            .and_then(|meminfo| mem_total(&meminfo));
+        ResourceLimits::read(Path::new("/sys/fs/cgroup"), machine_cpus(), machine_memory)
+    })
+}
+
+fn machine_cpus() -> f64 {
+    std::thread::available_parallelism().map_or(1, |cpus| cpus.get()) as f64
+}
+
+/// `MemTotal` of `/proc/meminfo`, in bytes.
+fn mem_total(meminfo: &str) -> Option<u64> {
+    let line = meminfo.lines().find(|line| line.starts_with("MemTotal:"))?;
+    let kib = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    Some(kib * 1024)
+}
+
+fn read_number(path: &Path) -> Option<i64> {
+    std::fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+impl ResourceLimits {
+    /// The limits under the cgroup filesystem mounted at `root`, v2 or v1, capped by the
+    /// machine's.
+    pub fn read(root: &Path, machine_cpus: f64, machine_memory: Option<u64>) -> Self {
+        let (cgroup_cpus, cgroup_memory) = if root.join("cgroup.controllers").exists() {
+            (cpus_v2(root), read_number(&root.join("memory.max")))
+        } else {
+            (
+                cpus_v1(root),
+                read_number(&root.join("memory/memory.limit_in_bytes")),
+            )
+        };
+        let cgroup_memory = cgroup_memory
+            .and_then(|bytes| u64::try_from(bytes).ok())
+            .filter(|bytes| *bytes < UNLIMITED_V1);
+        let cpus = cgroup_cpus.map_or(machine_cpus, |cpus| cpus.min(machine_cpus));
+        let memory_bytes = match (cgroup_memory, machine_memory) {
+            (Some(cgroup), Some(machine)) => Some(cgroup.min(machine)),
+            (cgroup, machine) => cgroup.or(machine),
+        };
+        Self {
+            cpus,
+            memory_bytes,
+            from_cgroup: cpus < machine_cpus || memory_bytes < machine_memory,
+        }
+    }
+
+    /// Whole CPUs, at least one.
+    pub fn whole_cpus(&self) -> usize {
+        (self.cpus.ceil() as usize).max(1)
+    }
+
+    /// Consumer tasks of a worker: one per CPU, each task is mostly waiting on fetches and
+    /// more of them only help while there are idle CPUs to extract with.
+    pub fn workers(&self) -> usize {
+        self.whole_cpus()
+    }
+
+    /// In-memory dedup partitions: four per CPU, so the consumer tasks rarely wait on the
+    /// same lock, and at least the 16 that used to be fixed.
+    pub fn dedup_partitions(&self) -> usize {
+        (self.whole_cpus() * 4).max(16)
+    }
+
+    /// Keys the in-memory content dedup remembers: one per KiB of memory, so the keys and
+    /// document IDs take about an eighth of it. Ten million if the memory is unknown.
+    pub fn dedup_capacity(&self) -> usize {
+        self.memory_bytes
+            .map_or(10_000_000, |bytes| (bytes >> 10) as usize)
+    }
+
+    /// CDX digests the batcher dedup keeps in memory before it spills: one per 512 bytes
+    /// of memory, about a tenth of it. Fifty million if the memory is unknown.
+    pub fn digest_capacity(&self) -> usize {
+        self.memory_bytes
+            .map_or(50_000_000, |bytes| (bytes >> 9) as usize)
+    }
+
+    /// CDX chunks the batcher downloads at the same time, each is decompressed and parsed
+    /// on its own CPU.
+    pub fn concurrent_downloads(&self) -> usize {
+        self.whole_cpus().clamp(2, 16)
+    }
+
+    /// Batches waiting between parsing and publishing in the batcher, one per 64 MiB of
+    /// memory, between 4 and 256. 16 if the memory is unknown.
+    pub fn handoff_capacity(&self) -> usize {
+        self.memory_bytes
+            .map_or(16, |bytes| ((bytes >> 26) as usize).clamp(4, 256))
+    }
+}
+
+/// `cpu.max` holds `<quota> <period>` in microseconds, or `max <period>` without limit.
+fn cpus_v2(root: &Path) -> Option<f64> {
+    let cpu_max = std::fs::read_to_string(root.join("cpu.max")).ok()?;
+    let mut fields = cpu_max.split_whitespace();
+    let quota = fields.next()?.parse::<f64>().ok()?;
+    let period = fields.next()?.parse::<f64>().ok()?;
+    (period > 0.0).then(|| quota / period)
+}
+
+/// A quota of -1 means no limit.
+fn cpus_v1(root: &Path) -> Option<f64> {
+    let quota = read_number(&root.join("cpu/cpu.cfs_quota_us"))?;
+    let period = read_number(&root.join("cpu/cpu.cfs_period_us"))?;
+    (quota > 0 && period > 0).then(|| quota as f64 / period as f64)
+}
+
+impl fmt::Display for ResourceLimits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} CPUs", self.cpus)?;
+        if let Some(bytes) = self.memory_bytes {
+            write!(f, ", {:.1} GiB memory", bytes as f64 / (1u64 << 30) as f64)?;
+        }
+        if self.from_cgroup {
+            write!(f, " (cgroup limit)")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::resources::{mem_total, ResourceLimits};
+
+    #[test]
+    fn reads_cgroup_v2_and_v1_limits() {
+        let root = std::env::temp_dir().join(format!("pipeline-cgroup-{}", std::process::id()));
+        let gib = 1u64 << 30;
+
+        std::fs::create_dir_all(root.join("v2")).unwrap();
+        std::fs::write(root.join("v2/cgroup.controllers"), "cpu memory\n").unwrap();
+        std::fs::write(root.join("v2/cpu.max"), "150000 100000\n").unwrap();
+        std::fs::write(root.join("v2/memory.max"), format!("{}\n", 2 * gib)).unwrap();
+        let limits = ResourceLimits::read(&root.join("v2"), 64.0, Some(256 * gib));
+        assert_eq!(
+            limits,
+            ResourceLimits {
+                cpus: 1.5,
+                memory_bytes: Some(2 * gib),
+                from_cgroup: true,
+            }
+        );
+        assert_eq!(limits.workers(), 2);
+        assert_eq!(limits.dedup_partitions(), 16);
+        assert_eq!(limits.dedup_capacity(), 2 << 20);
+        assert_eq!(limits.digest_capacity(), 4 << 20);
+        assert_eq!(limits.concurrent_downloads(), 2);
+        assert_eq!(limits.handoff_capacity(), 32);
+        assert_eq!(
+            limits.to_string(),
+            "1.5 CPUs, 2.0 GiB memory (cgroup limit)"
+        );
+
+        std::fs::write(root.join("v2/cpu.max"), "max 100000\n").unwrap();
+        std::fs::write(root.join("v2/memory.max"), "max\n").unwrap();
+        let limits = ResourceLimits::read(&root.join("v2"), 8.0, None);
+        assert_eq!(limits.cpus, 8.0);
+        assert_eq!(limits.memory_bytes, None);
+        assert!(!limits.from_cgroup);
+        assert_eq!(limits.handoff_capacity(), 16);
+
+        std::fs::create_dir_all(root.join("v1/cpu")).unwrap();
+        std::fs::create_dir_all(root.join("v1/memory")).unwrap();
+        std::fs::write(root.join("v1/cpu/cpu.cfs_quota_us"), "-1\n").unwrap();
+        std::fs::write(root.join("v1/cpu/cpu.cfs_period_us"), "100000\n").unwrap();
+        std::fs::write(
+            root.join("v1/memory/memory.limit_in_bytes"),
+            "9223372036854771712\n",
+        )
+        .unwrap();
+        let limits = ResourceLimits::read(&root.join("v1"), 8.0, Some(64 * gib));
+        assert_eq!(limits.memory_bytes, Some(64 * gib));
+        assert!(!limits.from_cgroup);
+        assert_eq!(limits.dedup_partitions(), 32);
+        assert_eq!(limits.handoff_capacity(), 256);
+
+        std::fs::write(root.join("v1/cpu/cpu.cfs_quota_us"), "400000\n").unwrap();
+        let limits = ResourceLimits::read(&root.join("v1"), 8.0, Some(64 * gib));
+        assert_eq!(limits.workers(), 4);
+        assert!(limits.from_cgroup);
+
+        assert_eq!(
+            mem_total("MemTotal:       16384 kB\nMemFree:  100 kB\n"),
+            Some(16 << 20)
+        );
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
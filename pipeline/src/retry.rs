@@ -0,0 +1,98 @@
+use std::time::{Duration, SystemTime};
+
+/// How long to wait between attempts of a fetch that failed transiently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    pub base: Duration,
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            base: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+        }
+    }
+}
+
+impl Backoff {
+    /// The longest wait after failed attempt number `attempt`: `base` doubled per attempt
+    /// before it, at most `max`.
+    pub fn ceiling(&self, attempt: usize) -> Duration {
+        let doublings = attempt.saturating_sub(1).min(31) as u32;
+        self.base.saturating_mul(1 << doublings).min(self.max)
+    }
+
+    /// The wait after failed attempt number `attempt`, a random point in the upper half of
+    /// [`Backoff::ceiling`], so clients throttled at the same moment do not all come back
+    /// at the same moment. A `Retry-After` of the server is honored up to `max`.
+    pub fn delay(&self, attempt: usize, retry_after: Option<Duration>) -> Duration {
+        let ceiling = self.ceiling(attempt);
+        let jittered = ceiling.mul_f64(rand::random_range(0.5..=1.0));
+        retry_after.map_or(jittered, |retry_after| {
+            jittered.max(retry_after.min(self.max))
+        })
+    }
+}
+
+/// Parses a `Retry-After` header, either delay seconds or an HTTP date after `now`.
+pub fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(now).unwrap_or_default())
+}
+
+/// The `Retry-After` of `response`, if it has a valid one.
+pub fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    let value = response.headers().get(reqwest::header::RETRY_AFTER)?;
+    parse_retry_after(value.to_str().ok()?, SystemTime::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use crate::retry::{parse_retry_after, Backoff};
+
+    #[test]
+    fn backs_off_exponentially_with_jitter() {
+        let backoff = Backoff {
+            base: Duration::from_millis(100),
+            max: Duration::from_secs(1),
+        };
+        assert_eq!(backoff.ceiling(1), Duration::from_millis(100));
+        assert_eq!(backoff.ceiling(3), Duration::from_millis(400));
+        assert_eq!(backoff.ceiling(10), Duration::from_secs(1));
+        assert_eq!(backoff.ceiling(usize::MAX), Duration::from_secs(1));
+        for _ in 0..100 {
+            let delay = backoff.delay(3, None);
+            assert!((Duration::from_millis(200)..=Duration::from_millis(400)).contains(&delay));
+        }
+        let delay = backoff.delay(1, Some(Duration::from_millis(700)));
+        assert_eq!(delay, Duration::from_millis(700));
+        let delay = backoff.delay(1, Some(Duration::from_secs(3600)));
+        assert_eq!(delay, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn parses_retry_after_seconds_and_dates() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_445_412_480);
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:29:00 GMT", now),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:27:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+}
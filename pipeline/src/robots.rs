@@ -0,0 +1,198 @@
+use serde::{Deserialize, Serialize};
+
+use crate::cdx::CdxEntry;
+
+/// Whether `entry` is one of the robots.txt captures, which Common Crawl stores in a
+/// `robotstxt` subset next to the `warc` files of each segment.
+pub fn is_robotstxt_capture(entry: &CdxEntry) -> bool {
+    entry.metadata.filename.contains("/robotstxt/")
+}
+
+/// The rules for one set of user agents.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct RobotsGroup {
+    pub user_agents: Vec<String>,
+    pub allow: Vec<String>,
+    pub disallow: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crawl_delay: Option<f64>,
+}
+
+/// A parsed robots.txt capture, as written by the worker's robots.txt mode.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct RobotsRecord {
+    pub host: String,
+    pub url: String,
+    pub timestamp: String,
+    pub groups: Vec<RobotsGroup>,
+    pub sitemaps: Vec<String>,
+}
+
+/// Parses robots.txt rules after RFC 9309. Consecutive `User-agent` lines open a group
+/// that the following rules belong to, rules before the first group are dropped and
+/// `Sitemap` lines count for the whole file. Unknown fields are ignored.
+pub fn parse_robotstxt(text: &str) -> (Vec<RobotsGroup>, Vec<String>) {
+    let mut groups = Vec::<RobotsGroup>::new();
+    let mut sitemaps = Vec::new();
+    let mut in_user_agents = false;
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim().to_string();
+        match key.trim().to_ascii_lowercase().as_str() {
+            "user-agent" => {
+                if !in_user_agents {
+                    groups.push(RobotsGroup::default());
+                    in_user_agents = true;
+                }
+                groups.last_mut().unwrap().user_agents.push(value);
+                continue;
+            }
+            "sitemap" => sitemaps.push(value),
+            key => {
+                if let Some(group) = groups.last_mut() {
+                    match key {
+                        "allow" => group.allow.push(value),
+                        "disallow" if !value.is_empty() => group.disallow.push(value),
+                        "crawl-delay" => group.crawl_delay = value.parse().ok(),
+                        _ => {}
+                    }
+                }
+            }
+        }
+        in_user_agents = false;
+    }
+    (groups, sitemaps)
+}
+
+/// User agents whose robots meta tags and `X-Robots-Tag` directives apply to this pipeline.
+const META_USER_AGENTS: &[&str] = &["robots", "ccbot"];
+
+/// The value of attribute `name` in the lowercased inside of an HTML tag.
+fn tag_attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = tag;
+    while let Some(start) = rest.find(name) {
+        let after = rest[start + name.len()..].trim_start();
+        let preceded_by_space = rest[..start].ends_with(|c: char| c.is_whitespace());
+        rest = &rest[start + name.len()..];
+        let Some(value) = after.strip_prefix('=').filter(|_| preceded_by_space) else {
+            continue;
+        };
+        let value = value.trim_start();
+        return Some(match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => value[1..].split(quote).next().unwrap_or_default(),
+            _ => value
+                .split(|c: char| c.is_whitespace() || c == '/')
+                .next()
+                .unwrap_or_default(),
+        });
+    }
+    None
+}
+
+fn forbids_indexing<'a>(mut directives: impl Iterator<Item = &'a str>) -> bool {
+    directives.any(|directive| matches!(directive.trim(), "noindex" | "none"))
+}
+
+/// Whether a page asks not to be indexed, with `noindex` or `none` in a
+/// `<meta name="robots">` or `<meta name="CCBot">` tag or in the `X-Robots-Tag` header.
+/// Header directives for other user agents, like `googlebot: noindex`, are ignored.
+pub fn is_noindex(html: &str, x_robots_tag: Option<&str>) -> bool {
+    if let Some(header) = x_robots_tag {
+        let header = header.to_ascii_lowercase();
+        let mut user_agent = None;
+        for directive in header.split(',') {
+            let directive = match directive.trim().split_once(':') {
+                Some((agent, rest)) if !agent.contains(' ') && agent != "unavailable_after" => {
+                    user_agent = Some(agent.to_string());
+                    rest
+                }
+                _ => directive,
+            };
+            let applies = user_agent
+                .as_deref()
+                .is_none_or(|agent| META_USER_AGENTS.contains(&agent));
+            if applies && forbids_indexing(std::iter::once(directive)) {
+                return true;
+            }
+        }
+    }
+    let html = html.to_ascii_lowercase();
+    let mut rest = html.as_str();
+    while let Some(start) = rest.find("<meta") {
+        rest = &rest[start + "<meta".len()..];
+        let tag = &rest[..rest.find('>').unwrap_or(rest.len())];
+        let applies =
+            tag_attribute(tag, "name").is_some_and(|name| META_USER_AGENTS.contains(&name.trim()));
+        if applies && forbids_indexing(tag_attribute(tag, "content").unwrap_or_default().split(','))
+        {
+            return true;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::robots::{is_noindex, parse_robotstxt, RobotsGroup};
+
+    #[test]
+    fn detects_noindex_in_meta_tags_and_headers() {
+        assert!(is_noindex(
+            r#"<html><head><META NAME="robots" CONTENT="noarchive, NoIndex"></head></html>"#,
+            None
+        ));
+        assert!(is_noindex("<meta name=ccbot content=none>", None));
+        assert!(!is_noindex(
+            r#"<meta name="googlebot" content="noindex"><meta name="robots" content="index">"#,
+            None
+        ));
+        assert!(!is_noindex(
+            r#"<meta property="og:title" content="noindex">"#,
+            None
+        ));
+        assert!(is_noindex("", Some("noindex, nofollow")));
+        assert!(is_noindex("", Some("googlebot: nofollow, CCBot: noindex")));
+        assert!(!is_noindex("", Some("googlebot: noindex, nofollow")));
+        assert!(!is_noindex(
+            "",
+            Some("unavailable_after: 25 Jun 2010 15:00:00 PST")
+        ));
+    }
+
+    #[test]
+    fn parses_groups_and_sitemaps() {
+        let (groups, sitemaps) = parse_robotstxt(
+            "Disallow: /ignored\n\
+             User-agent: GPTBot\n\
+             user-agent: CCBot # Common Crawl\n\
+             Disallow: /\n\
+             \n\
+             User-agent: *\n\
+             Allow: /public\n\
+             Disallow:\n\
+             Disallow: /private\n\
+             Crawl-delay: 2.5\n\
+             Sitemap: https://example.com/sitemap.xml\n",
+        );
+        assert_eq!(
+            groups,
+            vec![
+                RobotsGroup {
+                    user_agents: vec!["GPTBot".to_string(), "CCBot".to_string()],
+                    disallow: vec!["/".to_string()],
+                    ..Default::default()
+                },
+                RobotsGroup {
+                    user_agents: vec!["*".to_string()],
+                    allow: vec!["/public".to_string()],
+                    disallow: vec!["/private".to_string()],
+                    crawl_delay: Some(2.5),
+                },
+            ]
+        );
+        assert_eq!(sitemaps, vec!["https://example.com/sitemap.xml"]);
+    }
+}
@@ -0,0 +1,601 @@
+use std::{path::Path, sync::Mutex};
+
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+
+pub use crate::state_store::{RunCost, RunStop, Watermark};
+use crate::{
+    batch::BatchKey,
+    budget::{RunLimits, StopReason},
+    compare::RunCounters,
+    compliance::{DropCounts, DropReason},
+    state_store::{KeyPage, StateStore, WATERMARKS},
+    traffic::{TrafficKind, TrafficTotals},
+};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS traffic (
+        run_id TEXT NOT NULL,
+        kind TEXT NOT NULL,
+        bytes INTEGER NOT NULL DEFAULT 0,
+        requests INTEGER NOT NULL DEFAULT 0,
+        retries INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (run_id, kind)
+    );
+    CREATE TABLE IF NOT EXISTS completed_batches (
+        crawl TEXT NOT NULL,
+        shard TEXT NOT NULL,
+        batch_index INTEGER NOT NULL,
+        run_id TEXT NOT NULL,
+        completed_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        PRIMARY KEY (crawl, shard, batch_index)
+    );
+    CREATE TABLE IF NOT EXISTS runs (
+        run_id TEXT PRIMARY KEY,
+        deadline_unix INTEGER,
+        max_cost_bytes INTEGER,
+        stop_reason TEXT,
+        cut_shard TEXT,
+        cut_batch_index INTEGER,
+        stopped_at TEXT
+    );
+    CREATE TABLE IF NOT EXISTS compliance_drops (
+        run_id TEXT NOT NULL,
+        reason TEXT NOT NULL,
+        count INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (run_id, reason)
+    );
+    CREATE TABLE IF NOT EXISTS run_configs (
+        run_id TEXT NOT NULL,
+        process TEXT NOT NULL,
+        args TEXT NOT NULL,
+        PRIMARY KEY (run_id, process, args)
+    );
+    CREATE TABLE IF NOT EXISTS run_counters (
+        run_id TEXT NOT NULL,
+        name TEXT NOT NULL,
+        count INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (run_id, name)
+    );
+    CREATE TABLE IF NOT EXISTS published_batches (
+        crawl TEXT NOT NULL,
+        shard TEXT NOT NULL,
+        batch_index INTEGER NOT NULL,
+        min_timestamp TEXT NOT NULL,
+        max_timestamp TEXT NOT NULL,
+        PRIMARY KEY (crawl, shard, batch_index)
+    );
+    CREATE TABLE IF NOT EXISTS content_keys (
+        key TEXT PRIMARY KEY,
+        document_id TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS domain_documents (
+        domain TEXT NOT NULL,
+        document_id TEXT NOT NULL,
+        PRIMARY KEY (domain, document_id)
+    );
+    CREATE TABLE IF NOT EXISTS domain_counts (
+        domain TEXT PRIMARY KEY,
+        num_documents INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS checkpoints (
+        name TEXT PRIMARY KEY,
+        data BLOB NOT NULL,
+        updated_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+    );
+    CREATE VIEW IF NOT EXISTS cost AS
+        SELECT
+            run_id,
+            SUM(CASE WHEN kind = 'index' THEN bytes ELSE 0 END) AS index_bytes,
+            SUM(CASE WHEN kind = 'warc' THEN bytes ELSE 0 END) AS warc_bytes,
+            SUM(CASE WHEN kind = 'index_api' THEN bytes ELSE 0 END) AS index_api_bytes,
+            SUM(requests) AS requests,
+            SUM(retries) AS retries
+        FROM traffic
+        GROUP BY run_id;
+";
+
+/// SQLite database shared by the batcher and the workers of a run on one machine, the
+/// default [`StateStore`].
+pub struct RunDb {
+    conn: Mutex<Connection>,
+}
+
+impl RunDb {
+    pub fn open(path: &Path) -> Result<Self, anyhow::Error> {
+        let conn = Connection::open(path)?;
+        // Several processes write to the same file.
+        conn.busy_timeout(std::time::Duration::from_secs(10))?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+}
+
+impl StateStore for RunDb {
+    fn add_traffic(
+        &self,
+        run_id: &str,
+        deltas: &[(TrafficKind, TrafficTotals)],
+    ) -> Result<(), anyhow::Error> {
+        let conn = self.conn.lock().unwrap();
+        for (kind, delta) in deltas {
+            if *delta == TrafficTotals::default() {
+                continue;
+            }
+            conn.execute(
+                "INSERT INTO traffic (run_id, kind, bytes, requests, retries)
+                 VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT (run_id, kind) DO UPDATE SET
+                     bytes = bytes + excluded.bytes,
+                     requests = requests + excluded.requests,
+                     retries = retries + excluded.retries",
+                params![
+                    run_id,
+                    kind.as_str(),
+                    delta.bytes as i64,
+                    delta.requests as i64,
+                    delta.retries as i64
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn mark_batch_complete(&self, key: &BatchKey, run_id: &str) -> Result<(), anyhow::Error> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR IGNORE INTO completed_batches (crawl, shard, batch_index, run_id)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![key.crawl, key.shard, key.batch_index as i64, run_id],
+        )?;
+        Ok(())
+    }
+
+    fn is_batch_complete(&self, key: &BatchKey) -> Result<bool, anyhow::Error> {
+        let conn = self.conn.lock().unwrap();
+        Ok(conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM completed_batches
+             WHERE crawl = ?1 AND shard = ?2 AND batch_index = ?3)",
+            params![key.crawl, key.shard, key.batch_index as i64],
+            |row| row.get(0),
+        )?)
+    }
+
+    fn completed_batches(&self) -> Result<Vec<BatchKey>, anyhow::Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement =
+            conn.prepare("SELECT crawl, shard, batch_index FROM completed_batches")?;
+        let keys = statement
+            .query_map([], |row| {
+                Ok(BatchKey {
+                    crawl: row.get(0)?,
+                    shard: row.get(1)?,
+                    batch_index: row.get::<_, i64>(2)? as usize,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(keys)
+    }
+
+    fn register_batch(
+        &self,
+        key: &BatchKey,
+        min_timestamp: &str,
+        max_timestamp: &str,
+    ) -> Result<(), anyhow::Error> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO published_batches
+                 (crawl, shard, batch_index, min_timestamp, max_timestamp)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                key.crawl,
+                key.shard,
+                key.batch_index as i64,
+                min_timestamp,
+                max_timestamp
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn watermarks(&self) -> Result<Vec<Watermark>, anyhow::Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare(WATERMARKS)?;
+        let watermarks = statement
+            .query_map([], |row| {
+                Ok(Watermark {
+                    crawl: row.get(0)?,
+                    low_watermark: row.get(1)?,
+                    pending_batches: row.get::<_, i64>(2)? as u64,
+                    completed_batches: row.get::<_, i64>(3)? as u64,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(watermarks)
+    }
+
+    fn set_limits(&self, run_id: &str, limits: &RunLimits) -> Result<(), anyhow::Error> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO runs (run_id, deadline_unix, max_cost_bytes) VALUES (?1, ?2, ?3)
+             ON CONFLICT (run_id) DO UPDATE SET
+                 deadline_unix = excluded.deadline_unix,
+                 max_cost_bytes = excluded.max_cost_bytes",
+            params![
+                run_id,
+                limits.deadline_unix.map(|d| d as i64),
+                limits.max_cost_bytes.map(|b| b as i64)
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn limits(&self, run_id: &str) -> Result<RunLimits, anyhow::Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement =
+            conn.prepare("SELECT deadline_unix, max_cost_bytes FROM runs WHERE run_id = ?1")?;
+        let mut rows = statement.query(params![run_id])?;
+        let Some(row) = rows.next()? else {
+            return Ok(RunLimits::default());
+        };
+        Ok(RunLimits {
+            deadline_unix: row.get::<_, Option<i64>>(0)?.map(|d| d as u64),
+            max_cost_bytes: row.get::<_, Option<i64>>(1)?.map(|b| b as u64),
+        })
+    }
+
+    fn mark_partial(
+        &self,
+        run_id: &str,
+        reason: StopReason,
+        cut: Option<&BatchKey>,
+    ) -> Result<(), anyhow::Error> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE runs SET
+                 stop_reason = ?2,
+                 cut_shard = ?3,
+                 cut_batch_index = ?4,
+                 stopped_at = CURRENT_TIMESTAMP
+             WHERE run_id = ?1 AND stop_reason IS NULL",
+            params![
+                run_id,
+                reason.as_str(),
+                cut.map(|key| key.shard.as_str()),
+                cut.map(|key| key.batch_index as i64)
+            ],
+        )?;
+        if let Some(cut) = cut {
+            conn.execute(
+                "UPDATE runs SET cut_shard = ?2, cut_batch_index = ?3
+                 WHERE run_id = ?1 AND cut_shard IS NULL",
+                params![run_id, cut.shard, cut.batch_index as i64],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn run_stop(&self, run_id: &str, crawl: &str) -> Result<Option<RunStop>, anyhow::Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare(
+            "SELECT stop_reason, cut_shard, cut_batch_index, stopped_at FROM runs
+             WHERE run_id = ?1 AND stop_reason IS NOT NULL",
+        )?;
+        let mut rows = statement.query(params![run_id])?;
+        let Some(row) = rows.next()? else {
+            return Ok(None);
+        };
+        let cut_shard = row.get::<_, Option<String>>(1)?;
+        let cut_batch_index = row.get::<_, Option<i64>>(2)?;
+        Ok(Some(RunStop {
+            reason: row.get(0)?,
+            cut: cut_shard
+                .zip(cut_batch_index)
+                .map(|(shard, batch_index)| BatchKey {
+                    crawl: crawl.to_string(),
+                    shard,
+                    batch_index: batch_index as usize,
+                }),
+            stopped_at: row.get(3)?,
+        }))
+    }
+
+    fn add_drops(&self, run_id: &str, counts: &DropCounts) -> Result<(), anyhow::Error> {
+        let conn = self.conn.lock().unwrap();
+        for (reason, count) in counts.iter() {
+            conn.execute(
+                "INSERT INTO compliance_drops (run_id, reason, count) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (run_id, reason) DO UPDATE SET count = count + excluded.count",
+                params![run_id, reason.as_str(), count as i64],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn drops(&self, run_id: &str) -> Result<DropCounts, anyhow::Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement =
+            conn.prepare("SELECT reason, count FROM compliance_drops WHERE run_id = ?1")?;
+        let rows = statement
+            .query_map(params![run_id], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut counts = DropCounts::default();
+        for (reason, count) in rows {
+            match DropReason::ALL.iter().find(|r| r.as_str() == reason) {
+                Some(reason) => counts.add(*reason, count as u64),
+                None => tracing::warn!("Ignoring unknown drop reason {}", reason),
+            }
+        }
+        Ok(counts)
+    }
+
+    fn record_config(
+        &self,
+        run_id: &str,
+        process: &str,
+        args: &[String],
+    ) -> Result<(), anyhow::Error> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR IGNORE INTO run_configs (run_id, process, args) VALUES (?1, ?2, ?3)",
+            params![run_id, process, serde_json::to_string(args)?],
+        )?;
+        Ok(())
+    }
+
+    fn configs(&self, run_id: &str) -> Result<Vec<(String, Vec<String>)>, anyhow::Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare(
+            "SELECT process, args FROM run_configs WHERE run_id = ?1 ORDER BY process, args",
+        )?;
+        let rows = statement
+            .query_map(params![run_id], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        rows.into_iter()
+            .map(|(process, args)| Ok((process, serde_json::from_str(&args)?)))
+            .collect()
+    }
+
+    fn add_counters(&self, run_id: &str, counters: &RunCounters) -> Result<(), anyhow::Error> {
+        let conn = self.conn.lock().unwrap();
+        for (name, count) in counters.iter() {
+            conn.execute(
+                "INSERT INTO run_counters (run_id, name, count) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (run_id, name) DO UPDATE SET count = count + excluded.count",
+                params![run_id, name, count as i64],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn counters(&self, run_id: &str) -> Result<RunCounters, anyhow::Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement =
+            conn.prepare("SELECT name, count FROM run_counters WHERE run_id = ?1")?;
+        let mut counters = RunCounters::default();
+        for row in statement.query_map(params![run_id], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+        })? {
+            let (name, count) = row?;
+            counters.add(&name, count as u64);
+        }
+        Ok(counters)
+    }
+
+    fn costs(&self) -> Result<Vec<RunCost>, anyhow::Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare(
+            "SELECT run_id, index_bytes, warc_bytes, index_api_bytes, requests, retries
+             FROM cost ORDER BY run_id",
+        )?;
+        let costs = statement
+            .query_map([], |row| {
+                Ok(RunCost {
+                    run_id: row.get(0)?,
+                    index_bytes: row.get::<_, i64>(1)? as u64,
+                    warc_bytes: row.get::<_, i64>(2)? as u64,
+                    index_api_bytes: row.get::<_, i64>(3)? as u64,
+                    requests: row.get::<_, i64>(4)? as u64,
+                    retries: row.get::<_, i64>(5)? as u64,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(costs)
+    }
+
+    fn claim_keys(&self, claims: &[(String, String)]) -> Result<Vec<String>, anyhow::Error> {
+        let mut conn = self.conn.lock().unwrap();
+        // One transaction, so a batch of claims is synced once and not per key.
+        let tx = conn.transaction()?;
+        let mut ids = Vec::with_capacity(claims.len());
+        for (key, document_id) in claims {
+            ids.push(tx.query_row(
+                "INSERT INTO content_keys (key, document_id) VALUES (?1, ?2)
+                 ON CONFLICT (key) DO UPDATE SET key = key
+                 RETURNING document_id",
+                params![key, document_id],
+                |row| row.get(0),
+            )?);
+        }
+        tx.commit()?;
+        Ok(ids)
+    }
+
+    /// Pages in key order, the cursor is the last key of a page.
+    fn content_keys(&self, cursor: Option<&str>, limit: usize) -> Result<KeyPage, anyhow::Error> {
+        let conn = self.conn.lock().unwrap();
+        let keys = conn
+            .prepare(
+                "SELECT key, document_id FROM content_keys WHERE key > ?1 ORDER BY key LIMIT ?2",
+            )?
+            .query_map(params![cursor.unwrap_or_default(), limit as i64], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?
+            .collect::<Result<Vec<(String, String)>, _>>()?;
+        let next = (keys.len() == limit)
+            .then(|| keys.last().map(|(key, _)| key.clone()))
+            .flatten();
+        Ok(KeyPage { keys, next })
+    }
+
+    fn admit_documents(
+        &self,
+        max_documents: u64,
+        documents: &[(String, String)],
+    ) -> Result<Vec<bool>, anyhow::Error> {
+        let mut conn = self.conn.lock().unwrap();
+        // Immediate, so two workers cannot both take the last document of a quota.
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let mut admitted = Vec::with_capacity(documents.len());
+        for (domain, document_id) in documents {
+            let admitted_before = tx
+                .query_row(
+                    "SELECT 1 FROM domain_documents WHERE domain = ?1 AND document_id = ?2",
+                    params![domain, document_id],
+                    |_| Ok(()),
+                )
+                .optional()?
+                .is_some();
+            if admitted_before {
+                admitted.push(true);
+                continue;
+            }
+            let num_documents: i64 = tx
+                .query_row(
+                    "SELECT num_documents FROM domain_counts WHERE domain = ?1",
+                    params![domain],
+                    |row| row.get(0),
+                )
+                .optional()?
+                .unwrap_or(0);
+            if num_documents as u64 >= max_documents {
+                admitted.push(false);
+                continue;
+            }
+            tx.execute(
+                "INSERT INTO domain_documents (domain, document_id) VALUES (?1, ?2)",
+                params![domain, document_id],
+            )?;
+            tx.execute(
+                "INSERT INTO domain_counts (domain, num_documents) VALUES (?1, 1)
+                 ON CONFLICT (domain) DO UPDATE SET num_documents = num_documents + 1",
+                params![domain],
+            )?;
+            admitted.push(true);
+        }
+        tx.commit()?;
+        Ok(admitted)
+    }
+
+    fn read_checkpoint(&self, name: &str) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        let conn = self.conn.lock().unwrap();
+        Ok(conn
+            .query_row(
+                "SELECT data FROM checkpoints WHERE name = ?1",
+                params![name],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    fn write_checkpoint(&self, name: &str, data: &[u8]) -> Result<(), anyhow::Error> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO checkpoints (name, data) VALUES (?1, ?2)
+             ON CONFLICT (name) DO UPDATE SET
+                 data = excluded.data,
+                 updated_at = CURRENT_TIMESTAMP",
+            params![name, data],
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{run_db::RunDb, state_store::conformance};
+
+    #[test]
+    fn accumulates_traffic_per_run() {
+        let path =
+            std::env::temp_dir().join(format!("pipeline-run-db-{}.sqlite", std::process::id()));
+        conformance::accumulates_traffic_per_run(&RunDb::open(&path).unwrap());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn remembers_completed_batches() {
+        let path = std::env::temp_dir().join(format!(
+            "pipeline-run-db-batches-{}.sqlite",
+            std::process::id()
+        ));
+        conformance::remembers_completed_batches(&RunDb::open(&path).unwrap());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn stops_runs_over_budget_at_the_first_cut() {
+        let path = std::env::temp_dir().join(format!(
+            "pipeline-run-db-limits-{}.sqlite",
+            std::process::id()
+        ));
+        conformance::stops_runs_over_budget_at_the_first_cut(&RunDb::open(&path).unwrap());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn accumulates_compliance_drops_per_run() {
+        let path = std::env::temp_dir().join(format!(
+            "pipeline-run-db-drops-{}.sqlite",
+            std::process::id()
+        ));
+        conformance::accumulates_compliance_drops_per_run(&RunDb::open(&path).unwrap());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn records_configs_and_counters_per_run() {
+        let path = std::env::temp_dir().join(format!(
+            "pipeline-run-db-configs-{}.sqlite",
+            std::process::id()
+        ));
+        conformance::records_configs_and_counters_per_run(&RunDb::open(&path).unwrap());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn claims_keys_and_keeps_checkpoints() {
+        let path = std::env::temp_dir().join(format!(
+            "pipeline-run-db-keys-{}.sqlite",
+            std::process::id()
+        ));
+        conformance::claims_keys_and_keeps_checkpoints(&RunDb::open(&path).unwrap());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn admits_documents_per_domain() {
+        let path = std::env::temp_dir().join(format!(
+            "pipeline-run-db-quota-{}.sqlite",
+            std::process::id()
+        ));
+        conformance::admits_documents_per_domain(&RunDb::open(&path).unwrap());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn pages_content_keys() {
+        let path = std::env::temp_dir().join(format!(
+            "pipeline-run-db-key-pages-{}.sqlite",
+            std::process::id()
+        ));
+        conformance::pages_content_keys(&RunDb::open(&path).unwrap());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,426 @@
+use std::{
+    collections::BTreeSet,
+    fs::File,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cdx::FetchError,
+    rate_limit::send_limited,
+    retry::Backoff,
+    sigv4::{
+        amz_date, region_from_env, sha256_hex, signature_v4, uri_encode, AwsCredentials,
+        SignedRequest,
+    },
+    state_file::StateFile,
+};
+
+/// The smallest part S3 accepts in a multipart upload, except for the last one.
+pub const MIN_PART_SIZE: usize = 5 << 20;
+
+/// Where uploads go: `s3://<bucket>/<prefix>` and a key template with the placeholders
+/// `{crawl}`, `{date}` and `{shard}`, see [`S3Destination::key`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct S3Destination {
+    pub bucket: String,
+    pub prefix: String,
+    pub key_template: String,
+}
+
+const PLACEHOLDERS: [&str; 3] = ["{crawl}", "{date}", "{shard}"];
+
+impl S3Destination {
+    pub fn parse(url: &str, key_template: &str) -> Result<Self, anyhow::Error> {
+        let Some(rest) = url.strip_prefix("s3://") else {
+            anyhow::bail!("{url} does not start with s3://");
+        };
+        let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+        anyhow::ensure!(!bucket.is_empty(), "{url} has no bucket");
+        let mut unknown = key_template.to_string();
+        for placeholder in PLACEHOLDERS {
+            unknown = unknown.replace(placeholder, "");
+        }
+        anyhow::ensure!(
+            !unknown.contains(['{', '}']),
+            "{key_template} has placeholders other than {}",
+            PLACEHOLDERS.join(", ")
+        );
+        Ok(Self {
+            bucket: bucket.to_string(),
+            prefix: prefix.trim_matches('/').to_string(),
+            key_template: key_template.to_string(),
+        })
+    }
+
+    /// The object key of a file: the prefix and the template with `{crawl}`, `{date}` as
+    /// `2024-07-15` in UTC and `{shard}`, the path of the file in the output directory.
+    /// Use [`crawl_placeholder`] for a file with documents of several crawls.
+    pub fn key(&self, crawl: &str, date: &str, shard: &str) -> String {
+        let key = self
+            .key_template
+            .replace("{crawl}", crawl)
+            .replace("{date}", date)
+            .replace("{shard}", shard);
+        let key = key.trim_start_matches('/');
+        match self.prefix.as_str() {
+            "" => key.to_string(),
+            prefix => format!("{prefix}/{key}"),
+        }
+    }
+}
+
+/// The `{crawl}` of a file with documents of `crawls`: the crawl, several ones joined
+/// with `+`, or `unknown` for batches without a crawl.
+pub fn crawl_placeholder(crawls: &BTreeSet<String>) -> String {
+    if crawls.is_empty() {
+        return "unknown".to_string();
+    }
+    crawls.iter().cloned().collect::<Vec<_>>().join("+")
+}
+
+/// A finished file that still has to be uploaded, with the `{crawl}` of its key.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PendingUpload {
+    pub path: PathBuf,
+    pub crawl: String,
+}
+
+/// The finished files of a worker that are not uploaded yet, kept in a state file so a
+/// restart uploads them too.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PendingUploads {
+    pub files: Vec<PendingUpload>,
+}
+
+impl StateFile for PendingUploads {
+    const KIND: &'static [u8; 4] = b"S3UP";
+    const VERSION: u32 = 1;
+
+    fn migrate(_: u32, payload: serde_json::Value) -> Result<Self, anyhow::Error> {
+        Ok(serde_json::from_value(payload)?)
+    }
+}
+
+/// The text between `<tag>` and `</tag>` in an S3 XML response.
+fn xml_element<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{tag}>"))? + tag.len() + 2;
+    let end = start + xml[start..].find(&format!("</{tag}>"))?;
+    Some(&xml[start..end])
+}
+
+/// A client of the S3 API that uploads files, small ones in one request and large ones
+/// in parts. Transient failures are retried per request with backoff.
+pub struct S3Client {
+    http_client: reqwest::Client,
+    credentials: AwsCredentials,
+    region: String,
+    /// `None` for AWS, where buckets are addressed by host name.
+    endpoint: Option<url::Url>,
+    backoff: Backoff,
+    max_attempts: usize,
+}
+
+impl S3Client {
+    /// Talks to `endpoint` with path-style bucket addressing, e.g. MinIO, or to AWS in
+    /// `region` if there is none.
+    pub fn new(
+        http_client: reqwest::Client,
+        credentials: AwsCredentials,
+        region: &str,
+        endpoint: Option<url::Url>,
+    ) -> Self {
+        Self {
+            http_client,
+            credentials,
+            region: region.to_string(),
+            endpoint,
+            backoff: Backoff::default(),
+            max_attempts: 5,
+        }
+    }
+
+    pub fn with_retries(mut self, backoff: Backoff, max_attempts: usize) -> Self {
+        self.backoff = backoff;
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// The client of the region and `AWS_ENDPOINT_URL` in the environment, see
+    /// [`region_from_env`].
+    pub fn from_env(http_client: reqwest::Client) -> Result<Self, anyhow::Error> {
+        let region = region_from_env();
+        let endpoint = std::env::var("AWS_ENDPOINT_URL")
+            .ok()
+            .map(|endpoint| url::Url::parse(&endpoint))
+            .transpose()?;
+        Ok(Self::new(
+            http_client,
+            AwsCredentials::from_env()?,
+            &region,
+            endpoint,
+        ))
+    }
+
+    /// The host and path of `key` in `bucket`.
+    fn location(&self, bucket: &str, key: &str) -> (String, String) {
+        match &self.endpoint {
+            Some(endpoint) => {
+                let host = match endpoint.port() {
+                    Some(port) => format!("{}:{port}", endpoint.host_str().unwrap_or_default()),
+                    None => endpoint.host_str().unwrap_or_default().to_string(),
+                };
+                let base = endpoint.path().trim_end_matches('/');
+                (host, format!("{base}/{bucket}/{key}"))
+            }
+            None => (
+                format!("{bucket}.s3.{}.amazonaws.com", self.region),
+                format!("/{key}"),
+            ),
+        }
+    }
+
+    /// Sends a signed request, retrying transient failures, and returns the successful
+    /// response.
+    async fn send(
+        &self,
+        method: Method,
+        bucket: &str,
+        key: &str,
+        query: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<reqwest::Response, anyhow::Error> {
+        let (host, path) = self.location(bucket, key);
+        let scheme = self.endpoint.as_ref().map_or("https", |e| e.scheme());
+        let mut url = format!("{scheme}://{host}{}", uri_encode(&path, true));
+        if !query.is_empty() {
+            let query = query
+                .iter()
+                .map(|(name, value)| {
+                    format!("{}={}", uri_encode(name, false), uri_encode(value, false))
+                })
+                .collect::<Vec<_>>()
+                .join("&");
+            url = format!("{url}?{query}");
+        }
+        let payload_sha256 = sha256_hex(&body);
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let amz_date = amz_date(SystemTime::now());
+            let mut headers = vec![
+                ("host", host.as_str()),
+                ("x-amz-content-sha256", payload_sha256.as_str()),
+                ("x-amz-date", amz_date.as_str()),
+            ];
+            if let Some(token) = self.credentials.session_token.as_deref() {
+                headers.push(("x-amz-security-token", token));
+            }
+            let authorization = signature_v4(
+                &self.credentials,
+                &self.region,
+                "s3",
+                &amz_date,
+                &SignedRequest {
+                    method: method.as_str(),
+                    path: &path,
+                    query,
+                    headers: &headers,
+                    payload_sha256: &payload_sha256,
+                },
+            );
+            let mut request = self
+                .http_client
+                .request(method.clone(), &url)
+                .header("authorization", authorization)
+                .body(body.clone());
+            for (name, value) in headers.iter().filter(|(name, _)| *name != "host") {
+                request = request.header(*name, *value);
+            }
+            let error = match send_limited(request).await {
+                Ok((response, _)) if response.status().is_success() => return Ok(response),
+                Ok((response, _)) => {
+                    let error = FetchError::from_response(&url, &response);
+                    let body = response.text().await.unwrap_or_default();
+                    tracing::debug!("S3 answered {}", body);
+                    error
+                }
+                Err(e) => FetchError::Transient {
+                    url: url.clone(),
+                    reason: e.to_string(),
+                    status: None,
+                    retry_after: None,
+                },
+            };
+            if error.is_permanent() || attempt >= self.max_attempts {
+                return Err(error.into());
+            }
+            let delay = self.backoff.delay(attempt, error.retry_after());
+            tracing::warn!("{}, retrying in {:?}", error, delay);
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Whether the credentials may write below `prefix` of `bucket`, by writing and
+    /// deleting a small probe object there.
+    pub async fn check_access(&self, bucket: &str, prefix: &str) -> Result<String, anyhow::Error> {
+        let key = match prefix {
+            "" => format!(".doctor-{}", std::process::id()),
+            prefix => format!("{prefix}/.doctor-{}", std::process::id()),
+        };
+        self.send(Method::PUT, bucket, &key, &[], b"probe".to_vec())
+            .await?;
+        self.send(Method::DELETE, bucket, &key, &[], Vec::new())
+            .await?;
+        Ok(format!("may write to s3://{bucket}/{prefix}"))
+    }
+
+    /// Uploads the file at `path` to `key` in `bucket`, in parts of `part_size` bytes if it
+    /// is larger than that. A failed multipart upload is aborted, so S3 does not keep its
+    /// parts around.
+    pub async fn upload_file(
+        &self,
+        path: &Path,
+        bucket: &str,
+        key: &str,
+        part_size: usize,
+    ) -> Result<(), anyhow::Error> {
+        let part_size = part_size.max(MIN_PART_SIZE);
+        let size = std::fs::metadata(path)?.len();
+        if size <= part_size as u64 {
+            self.send(Method::PUT, bucket, key, &[], std::fs::read(path)?)
+                .await?;
+            return Ok(());
+        }
+        let response = self
+            .send(Method::POST, bucket, key, &[("uploads", "")], Vec::new())
+            .await?;
+        let xml = response.text().await?;
+        let Some(upload_id) = xml_element(&xml, "UploadId").map(str::to_string) else {
+            anyhow::bail!("S3 did not return an upload ID for {key}");
+        };
+        let result = self
+            .upload_parts(path, bucket, key, &upload_id, part_size)
+            .await;
+        if result.is_err() {
+            let aborted = self
+                .send(
+                    Method::DELETE,
+                    bucket,
+                    key,
+                    &[("uploadId", &upload_id)],
+                    Vec::new(),
+                )
+                .await;
+            if let Err(e) = aborted {
+                tracing::warn!(err.msg = %e, "Failed to abort the upload of {}", key);
+            }
+        }
+        result
+    }
+
+    /// Downloads `key` of `bucket` to the file at `path`, chunk by chunk.
+    pub async fn download_file(
+        &self,
+        bucket: &str,
+        key: &str,
+        path: &Path,
+    ) -> Result<(), anyhow::Error> {
+        let mut response = self.send(Method::GET, bucket, key, &[], Vec::new()).await?;
+        let mut file = std::io::BufWriter::new(File::create(path)?);
+        while let Some(chunk) = response.chunk().await? {
+            file.write_all(&chunk)?;
+        }
+        file.into_inner()?.sync_all()?;
+        Ok(())
+    }
+
+    async fn upload_parts(
+        &self,
+        path: &Path,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        part_size: usize,
+    ) -> Result<(), anyhow::Error> {
+        let mut file = File::open(path)?;
+        let mut complete = String::from("<CompleteMultipartUpload>");
+        for part_number in 1.. {
+            let mut part = Vec::with_capacity(part_size);
+            (&mut file).take(part_size as u64).read_to_end(&mut part)?;
+            if part.is_empty() {
+                break;
+            }
+            let part_number = part_number.to_string();
+            let response = self
+                .send(
+                    Method::PUT,
+                    bucket,
+                    key,
+                    &[("partNumber", &part_number), ("uploadId", upload_id)],
+                    part,
+                )
+                .await?;
+            let Some(etag) = response.headers().get("etag") else {
+                anyhow::bail!("S3 returned no ETag for part {part_number} of {key}");
+            };
+            complete.push_str(&format!(
+                "<Part><PartNumber>{part_number}</PartNumber><ETag>{}</ETag></Part>",
+                etag.to_str()?
+            ));
+        }
+        complete.push_str("</CompleteMultipartUpload>");
+        let response = self
+            .send(
+                Method::POST,
+                bucket,
+                key,
+                &[("uploadId", upload_id)],
+                complete.into_bytes(),
+            )
+            .await?;
+        // Completing can fail after the status line was sent, with an error in the body.
+        let xml = response.text().await?;
+        if let Some(code) = xml_element(&xml, "Code") {
+            anyhow::bail!("S3 failed to complete the upload of {key}: {code}");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use crate::s3::{crawl_placeholder, xml_element, S3Destination};
+
+    #[test]
+    fn renders_object_keys() {
+        assert_eq!(
+            xml_element("<R><UploadId>abc</UploadId></R>", "UploadId"),
+            Some("abc")
+        );
+        let destination =
+            S3Destination::parse("s3://corpus/runs/", "{crawl}/{date}/{shard}").unwrap();
+        assert_eq!(destination.bucket, "corpus");
+        assert_eq!(
+            destination.key("CC-MAIN-2024-30", "2024-08-02", "en/worker-1-00003.jsonl"),
+            "runs/CC-MAIN-2024-30/2024-08-02/en/worker-1-00003.jsonl"
+        );
+        let destination = S3Destination::parse("s3://corpus", "{shard}").unwrap();
+        assert_eq!(destination.key("c", "d", "en/a.parquet"), "en/a.parquet");
+        assert!(S3Destination::parse("https://corpus", "{shard}").is_err());
+        assert!(S3Destination::parse("s3://corpus", "{crawl}/{run}/{shard}").is_err());
+
+        let crawls = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+        assert_eq!(crawl_placeholder(&BTreeSet::new()), "unknown");
+        assert_eq!(
+            crawl_placeholder(&crawls(&["CC-MAIN-2024-33", "CC-MAIN-2024-30"])),
+            "CC-MAIN-2024-30+CC-MAIN-2024-33"
+        );
+    }
+}
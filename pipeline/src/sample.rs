@@ -0,0 +1,151 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use rand::{rngs::StdRng, RngExt, SeedableRng};
+
+use crate::output::{output_files, read_documents, Document};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Bucket {
+    Language,
+    Domain,
+}
+
+impl Bucket {
+    fn key(&self, document: &Document) -> String {
+        match self {
+            Bucket::Language => document.language.clone(),
+            Bucket::Domain => document.domain().unwrap_or_default(),
+        }
+    }
+}
+
+/// Draws up to `per_bucket` uniformly random documents per bucket in a single pass
+/// (reservoir sampling), so corpora larger than memory can be sampled.
+pub struct BucketSampler {
+    bucket: Bucket,
+    per_bucket: usize,
+    rng: StdRng,
+    reservoirs: BTreeMap<String, (usize, Vec<Document>)>,
+}
+
+impl BucketSampler {
+    pub fn new(bucket: Bucket, per_bucket: usize, seed: u64) -> Self {
+        Self {
+            bucket,
+            per_bucket,
+            rng: StdRng::seed_from_u64(seed),
+            reservoirs: BTreeMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, document: Document) {
+        let (seen, reservoir) = self
+            .reservoirs
+            .entry(self.bucket.key(&document))
+            .or_default();
+        *seen += 1;
+        if reservoir.len() < self.per_bucket {
+            reservoir.push(document);
+        } else {
+            let i = self.rng.random_range(0..*seen);
+            if i < self.per_bucket {
+                reservoir[i] = document;
+            }
+        }
+    }
+
+    pub fn finish(self) -> BTreeMap<String, Vec<Document>> {
+        self.reservoirs
+            .into_iter()
+            .map(|(key, (_, documents))| (key, documents))
+            .collect()
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Writes `sample.jsonl` and a browsable `sample.html` into `bundle_dir`.
+pub fn write_bundle(
+    bundle_dir: &Path,
+    samples: &BTreeMap<String, Vec<Document>>,
+) -> Result<Vec<PathBuf>, anyhow::Error> {
+    fs::create_dir_all(bundle_dir)?;
+    let jsonl_path = bundle_dir.join("sample.jsonl");
+    let mut jsonl = fs::File::create(&jsonl_path)?;
+    let html_path = bundle_dir.join("sample.html");
+    let mut html = fs::File::create(&html_path)?;
+    writeln!(
+        html,
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Corpus sample</title></head><body>"
+    )?;
+    for (bucket, documents) in samples {
+        writeln!(html, "<h1>{}</h1>", escape_html(bucket))?;
+        for document in documents {
+            serde_json::to_writer(&mut jsonl, document)?;
+            writeln!(jsonl)?;
+            writeln!(
+                html,
+                "<h2><a href=\"{url}\">{url}</a></h2>\n<p>id {} · {} · {} tokens · quality {:.2}</p>\n<pre style=\"white-space: pre-wrap\">{}</pre>",
+                escape_html(&document.id),
+                escape_html(&document.language),
+                document.token_count,
+                document.quality_score,
+                escape_html(&document.text),
+                url = escape_html(&document.url),
+            )?;
+        }
+    }
+    writeln!(html, "</body></html>")?;
+    Ok(vec![jsonl_path, html_path])
+}
+
+pub fn sample_output(
+    output_dir: &Path,
+    bucket: Bucket,
+    per_bucket: usize,
+    seed: u64,
+) -> Result<BTreeMap<String, Vec<Document>>, anyhow::Error> {
+    let mut sampler = BucketSampler::new(bucket, per_bucket, seed);
+    for path in output_files(output_dir)? {
+        for document in read_documents(&path)? {
+            sampler.add(document?);
+        }
+    }
+    Ok(sampler.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        output::Document,
+        sample::{Bucket, BucketSampler},
+    };
+
+    #[test]
+    fn samples_at_most_k_per_bucket() {
+        let mut sampler = BucketSampler::new(Bucket::Language, 2, 42);
+        for (i, language) in ["eng", "eng", "eng", "deu"].iter().enumerate() {
+            sampler.add(Document {
+                id: i.to_string(),
+                url: "https://example.com/".to_string(),
+                timestamp: "20240722120756".to_string(),
+                language: language.to_string(),
+                text: String::new(),
+                ..Default::default()
+            });
+        }
+        let samples = sampler.finish();
+        assert_eq!(samples["eng"].len(), 2);
+        assert_eq!(samples["deu"].len(), 1);
+    }
+}
@@ -0,0 +1,199 @@
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Write},
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::mpsc::{self, Receiver},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::output::Document;
+
+#[derive(Serialize)]
+struct ScoringRequest<'a> {
+    id: u64,
+    texts: Vec<&'a str>,
+}
+
+#[derive(Deserialize)]
+struct ScoringResponse {
+    id: u64,
+    scores: Vec<f64>,
+}
+
+/// Scores documents with an external model, e.g. `python3 scripts/scorer_bridge.py`.
+/// Documents are sent in batches of `batch_size` as one `{"id": ..., "texts": [...]}`
+/// JSON line, and the process answers each with `{"id": ..., "scores": [...]}`. Several
+/// batches can be in flight, so a GPU-backed scorer is kept busy while the worker goes
+/// on extracting. Scored documents come back with `model_score` set.
+pub struct BatchScorer {
+    child: Child,
+    stdin: ChildStdin,
+    responses: Receiver<Result<ScoringResponse, anyhow::Error>>,
+    batch_size: usize,
+    max_latency: Duration,
+    pending: Vec<Document>,
+    pending_since: Option<Instant>,
+    in_flight: HashMap<u64, Vec<Document>>,
+    next_id: u64,
+}
+
+impl BatchScorer {
+    pub fn spawn(
+        command: &str,
+        batch_size: usize,
+        max_latency: Duration,
+    ) -> Result<Self, anyhow::Error> {
+        let mut parts = command.split_whitespace();
+        let program = parts.next().context("Empty scorer command")?;
+        let mut child = Command::new(program)
+            .args(parts)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to spawn scorer command {command}"))?;
+        let stdin = child.stdin.take().context("Scorer has no stdin")?;
+        let stdout = child.stdout.take().context("Scorer has no stdout")?;
+        // Responses are read on their own thread, so a full stdout pipe can never block
+        // the scorer while the worker is still writing requests.
+        let (sender, responses) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let response = line
+                    .map_err(anyhow::Error::from)
+                    .and_then(|line| Ok(serde_json::from_str::<ScoringResponse>(&line)?));
+                if sender.send(response).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Self {
+            child,
+            stdin,
+            responses,
+            batch_size: batch_size.max(1),
+            max_latency,
+            pending: Vec::new(),
+            pending_since: None,
+            in_flight: HashMap::new(),
+            next_id: 0,
+        })
+    }
+
+    /// Queues `document` for scoring and returns the documents whose scores arrived.
+    pub fn push(&mut self, document: Document) -> Result<Vec<Document>, anyhow::Error> {
+        self.pending.push(document);
+        let pending_since = *self.pending_since.get_or_insert_with(Instant::now);
+        if self.pending.len() >= self.batch_size || pending_since.elapsed() >= self.max_latency {
+            self.send_pending()?;
+        }
+        let mut scored = Vec::new();
+        while let Ok(response) = self.responses.try_recv() {
+            scored.extend(self.match_response(response?)?);
+        }
+        Ok(scored)
+    }
+
+    /// Sends what is left and waits for all outstanding scores, e.g. before a batch is
+    /// committed.
+    pub fn finish(&mut self) -> Result<Vec<Document>, anyhow::Error> {
+        self.send_pending()?;
+        let mut scored = Vec::new();
+        while !self.in_flight.is_empty() {
+            let response = self.responses.recv().context("Scorer process exited")??;
+            scored.extend(self.match_response(response)?);
+        }
+        Ok(scored)
+    }
+
+    fn send_pending(&mut self) -> Result<(), anyhow::Error> {
+        self.pending_since = None;
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        let request = ScoringRequest {
+            id,
+            texts: self.pending.iter().map(|d| d.text.as_str()).collect(),
+        };
+        serde_json::to_writer(&mut self.stdin, &request)?;
+        writeln!(self.stdin)?;
+        self.stdin.flush()?;
+        self.in_flight.insert(id, std::mem::take(&mut self.pending));
+        Ok(())
+    }
+
+    fn match_response(
+        &mut self,
+        response: ScoringResponse,
+    ) -> Result<Vec<Document>, anyhow::Error> {
+        let mut documents = self
+            .in_flight
+            .remove(&response.id)
+            .with_context(|| format!("Scorer answered unknown request {}", response.id))?;
+        if documents.len() != response.scores.len() {
+            anyhow::bail!(
+                "Scorer returned {} scores for {} documents",
+                response.scores.len(),
+                documents.len()
+            );
+        }
+        for (document, score) in documents.iter_mut().zip(response.scores) {
+            document.model_score = Some(score);
+        }
+        Ok(documents)
+    }
+}
+
+impl Drop for BatchScorer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{output::Document, scorer::BatchScorer};
+
+    fn document(text: &str) -> Document {
+        Document {
+            id: text.to_string(),
+            url: "https://example.com/".to_string(),
+            timestamp: "20240722120756".to_string(),
+            language: "eng".to_string(),
+            token_count: 1,
+            text: text.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn scores_documents_in_batches() {
+        let mut scorer = BatchScorer::spawn(
+            "python3 scripts/scorer_bridge.py",
+            2,
+            Duration::from_secs(60),
+        )
+        .unwrap();
+        let mut scored = Vec::new();
+        for text in ["a", "bb", "ccc"] {
+            scored.extend(scorer.push(document(text)).unwrap());
+        }
+        scored.extend(scorer.finish().unwrap());
+        scored.sort_by(|a, b| a.id.cmp(&b.id));
+        let scores = scored
+            .iter()
+            .map(|d| (d.id.as_str(), d.model_score))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            scores,
+            vec![("a", Some(1.0)), ("bb", Some(2.0)), ("ccc", Some(3.0))]
+        );
+        assert!(scorer.finish().unwrap().is_empty());
+    }
+}
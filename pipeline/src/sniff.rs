@@ -0,0 +1,171 @@
+use std::borrow::Cow;
+
+use encoding_rs::Encoding;
+
+/// Coarse classification of a WARC record payload, used to route records to the
+/// right processing step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentKind {
+    Html,
+    Text,
+    Pdf,
+    Image,
+    Archive,
+    Unknown,
+}
+
+impl ContentKind {
+    pub fn from_mime(mime: &str) -> Self {
+        let mime = mime.trim().to_ascii_lowercase();
+        match mime.as_str() {
+            "text/html" | "application/xhtml+xml" => ContentKind::Html,
+            "application/pdf" => ContentKind::Pdf,
+            "application/zip" | "application/gzip" | "application/x-gzip" => ContentKind::Archive,
+            m if m.starts_with("image/") => ContentKind::Image,
+            m if m.starts_with("text/") => ContentKind::Text,
+            _ => ContentKind::Unknown,
+        }
+    }
+}
+
+/// Looks at the first bytes of a body to guess its type, for entries whose
+/// CDX `mime-detected` field is missing or unhelpful.
+pub fn sniff(body: &[u8]) -> ContentKind {
+    const MAGIC_NUMBERS: &[(&[u8], ContentKind)] = &[
+        (b"%PDF-", ContentKind::Pdf),
+        (b"\x89PNG\r\n\x1a\n", ContentKind::Image),
+        (b"\xff\xd8\xff", ContentKind::Image),
+        (b"GIF87a", ContentKind::Image),
+        (b"GIF89a", ContentKind::Image),
+        (b"PK\x03\x04", ContentKind::Archive),
+        (b"\x1f\x8b", ContentKind::Archive),
+    ];
+    for (magic, kind) in MAGIC_NUMBERS {
+        if body.starts_with(magic) {
+            return *kind;
+        }
+    }
+
+    let head_bytes = &body[..body.len().min(1024)];
+    let head = String::from_utf8_lossy(head_bytes).to_ascii_lowercase();
+    if [
+        "<!doctype html",
+        "<html",
+        "<head",
+        "<body",
+        "<meta",
+        "<title",
+    ]
+    .iter()
+    .any(|tag| head.contains(tag))
+    {
+        return ContentKind::Html;
+    }
+    // A multi-byte character cut off at the end of the sniffed prefix is still valid text.
+    let is_utf8 = match std::str::from_utf8(head_bytes) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    };
+    if is_utf8 && !head.chars().any(|c| c.is_control() && !c.is_whitespace()) {
+        return ContentKind::Text;
+    }
+    ContentKind::Unknown
+}
+
+/// The encoding of an HTML or text body: its byte order mark, else the `charset` of the
+/// HTTP `Content-Type`, else a `<meta>` charset in the first 1024 bytes, else what Common
+/// Crawl detected (the CDX `charset`). `None` if none of them names a known encoding.
+pub fn detect_charset(
+    body: &[u8],
+    content_type: Option<&str>,
+    cdx_charset: Option<&str>,
+) -> Option<&'static Encoding> {
+    if let Some((encoding, _)) = Encoding::for_bom(body) {
+        return Some(encoding);
+    }
+    let head = String::from_utf8_lossy(&body[..body.len().min(1024)]).to_ascii_lowercase();
+    let meta_charset = head
+        .find("<meta")
+        .and_then(|start| charset_parameter(&head[start..]));
+    let encoding = |label: &str| Encoding::for_label(label.as_bytes());
+    content_type
+        .and_then(charset_parameter)
+        .and_then(encoding)
+        .or_else(|| meta_charset.and_then(encoding))
+        .or_else(|| cdx_charset.and_then(encoding))
+}
+
+/// The value after the first `charset=`, without quotes.
+fn charset_parameter(text: &str) -> Option<&str> {
+    let start = text.to_ascii_lowercase().find("charset=")? + "charset=".len();
+    let value = text[start..].trim_start_matches(['"', '\'', ' ']);
+    let end = value
+        .find(|c: char| !(c.is_ascii_alphanumeric() || "-_:.".contains(c)))
+        .unwrap_or(value.len());
+    Some(&value[..end]).filter(|value| !value.is_empty())
+}
+
+/// Decodes an HTML or text body in the encoding [`detect_charset`] finds, UTF-8 if it
+/// finds none. Invalid bytes become U+FFFD. UTF-8 bodies are not copied.
+pub fn decode_body<'a>(
+    body: &'a [u8],
+    content_type: Option<&str>,
+    cdx_charset: Option<&str>,
+) -> Cow<'a, str> {
+    let encoding = detect_charset(body, content_type, cdx_charset).unwrap_or(encoding_rs::UTF_8);
+    encoding.decode(body).0
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sniff::{decode_body, detect_charset, sniff, ContentKind};
+
+    #[test]
+    fn sniffs_magic_numbers_and_html() {
+        assert_eq!(sniff(b"%PDF-1.7 ..."), ContentKind::Pdf);
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\0\0"), ContentKind::Image);
+        assert_eq!(sniff(b"  <!DOCTYPE html><html>"), ContentKind::Html);
+        assert_eq!(sniff(b"<?xml version=\"1.0\"?><html>"), ContentKind::Html);
+        assert_eq!(sniff(b"User-agent: *\nDisallow: /"), ContentKind::Text);
+        assert_eq!(sniff(b"\x00\x01\x02\x03"), ContentKind::Unknown);
+    }
+
+    #[test]
+    fn decodes_bodies_in_their_charset() {
+        let latin1 = b"<html><head><meta charset=\"iso-8859-1\"></head><body>Gr\xfc\xdfe</body>";
+        assert_eq!(
+            detect_charset(latin1, None, None).map(|e| e.name()),
+            Some("windows-1252")
+        );
+        assert!(decode_body(latin1, None, None).contains("Grüße"));
+        let http_equiv =
+            b"<meta http-equiv=\"Content-Type\" content=\"text/html; charset=Shift_JIS\">";
+        assert_eq!(
+            detect_charset(http_equiv, None, None).map(|e| e.name()),
+            Some("Shift_JIS")
+        );
+
+        // The HTTP header wins over the page, the page over Common Crawl.
+        assert_eq!(
+            detect_charset(latin1, Some("text/html; charset=UTF-8"), Some("GBK")).map(|e| e.name()),
+            Some("UTF-8")
+        );
+        assert_eq!(
+            detect_charset(b"<p>plain</p>", None, Some("GBK")).map(|e| e.name()),
+            Some("GBK")
+        );
+        assert_eq!(
+            detect_charset(b"<p>plain</p>", Some("text/html"), None),
+            None
+        );
+        assert_eq!(
+            detect_charset(b"\xef\xbb\xbf<p>", Some("text/html; charset=latin1"), None)
+                .map(|e| e.name()),
+            Some("UTF-8")
+        );
+        assert_eq!(
+            decode_body(b"caf\xc3\xa9 \xff", None, None),
+            "café \u{fffd}"
+        );
+    }
+}
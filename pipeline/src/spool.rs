@@ -0,0 +1,351 @@
+use std::{
+    fs::{self, File},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use futures_util::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+
+use crate::queue::{QueueConsumer, QueueDelivery, QueueMessage, QueueProducer};
+
+/// How often an empty queue directory is listed again.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// Marks a message that was claimed before, in its file name.
+const REDELIVERED: &str = ".redelivered";
+
+/// Names made by this process so far, so they are unique.
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// One message per file, the payload as the JSON it is.
+#[derive(Deserialize, Serialize)]
+struct SpoolFile {
+    content_type: Option<String>,
+    headers: Vec<(String, String)>,
+    payload: Box<RawValue>,
+}
+
+/// A queue backend of plain files, for running the pipeline without a broker. Each
+/// queue is a directory of the spool with one JSON file per message:
+///
+/// - `<queue>/*.json` wait to be consumed, oldest first by name.
+/// - `<queue>/claimed/<consumer>/` holds the messages a consumer is working on. It keeps
+///   `<queue>/claimed/<consumer>.lock` locked while it runs.
+/// - `<queue>/rejected/` keeps rejected messages for a look.
+///
+/// Files are moved with renames, so a message belongs to one consumer at a time. A new
+/// consumer puts the claimed messages of consumers that are gone back into the queue.
+#[derive(Debug, Clone)]
+pub struct Spool {
+    dir: PathBuf,
+}
+
+impl Spool {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    pub fn queue_dir(&self, queue: &str) -> PathBuf {
+        self.dir.join(queue)
+    }
+
+    pub fn consumer(&self, queue: &str) -> Result<SpoolConsumer, anyhow::Error> {
+        let queue_dir = self.queue_dir(queue);
+        let claimed_dir = queue_dir.join("claimed");
+        fs::create_dir_all(&claimed_dir)
+            .with_context(|| format!("Failed to create {}", claimed_dir.display()))?;
+        let name = format!("{}-{}", std::process::id(), unique_suffix());
+        let lock = create_locked(&claimed_dir.join(format!("{name}.lock")))?;
+        let num_recovered = recover_claims(&queue_dir, &claimed_dir)?;
+        if num_recovered > 0 {
+            tracing::info!(
+                "Put {} messages of stopped consumers back into {}",
+                num_recovered,
+                queue_dir.display()
+            );
+        }
+        let claims = claimed_dir.join(&name);
+        fs::create_dir_all(&claims)?;
+        Ok(SpoolConsumer {
+            queue_dir,
+            claims,
+            _lock: lock,
+        })
+    }
+}
+
+/// Creates the lock file at `path` already locked. It is locked under a temporary name
+/// first, so whoever finds a lock file and gets its lock knows that its owner is gone.
+pub fn create_locked(path: &Path) -> Result<File, anyhow::Error> {
+    let tmp_path = path.with_extension("lock.tmp");
+    let lock = File::create(&tmp_path)?;
+    lock.lock()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(lock)
+}
+
+/// Time and sequence number, so names sort in publish order within a process.
+fn unique_suffix() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!(
+        "{nanos:020}-{:06}",
+        SEQUENCE.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// Moves the messages of every consumer whose lock is free back to `queue_dir`, marked as
+/// redelivered. Returns how many there were.
+fn recover_claims(queue_dir: &Path, claimed_dir: &Path) -> Result<usize, anyhow::Error> {
+    let mut num_recovered = 0;
+    for entry in fs::read_dir(claimed_dir)? {
+        let lock_path = entry?.path();
+        if lock_path.extension().is_none_or(|ext| ext != "lock") {
+            continue;
+        }
+        let lock = match File::open(&lock_path) {
+            Ok(lock) => lock,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if lock.try_lock().is_err() {
+            continue;
+        }
+        let claims = lock_path.with_extension("");
+        if claims.is_dir() {
+            for claim in fs::read_dir(&claims)? {
+                let claim = claim?.path();
+                let Some(name) = claim.file_name().and_then(|name| name.to_str()) else {
+                    continue;
+                };
+                fs::rename(&claim, queue_dir.join(redelivered_name(name)))?;
+                num_recovered += 1;
+            }
+            fs::remove_dir(&claims)?;
+        }
+        // Another consumer that got the lock right after this one finds the file gone.
+        match fs::remove_file(&lock_path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    Ok(num_recovered)
+}
+
+fn redelivered_name(name: &str) -> String {
+    match name.contains(REDELIVERED) {
+        true => name.to_string(),
+        false => name.replace(".json", &format!("{REDELIVERED}.json")),
+    }
+}
+
+/// The content of the spool file of `message`. Fails for payloads that are not JSON,
+/// like `--encoding protobuf` batches.
+pub fn spool_file(message: &QueueMessage) -> Result<Vec<u8>, anyhow::Error> {
+    let payload = String::from_utf8(message.payload.clone())?;
+    let file = SpoolFile {
+        content_type: message.content_type.clone(),
+        headers: message.headers.clone(),
+        payload: RawValue::from_string(payload).context("The spool only takes JSON payloads")?,
+    };
+    Ok(serde_json::to_vec_pretty(&file)?)
+}
+
+/// The message of a spool file, with the payload byte for byte as it was published.
+pub fn parse_spool_file(data: &[u8]) -> Result<QueueMessage, anyhow::Error> {
+    let file: SpoolFile = serde_json::from_slice(data)?;
+    Ok(QueueMessage {
+        payload: file.payload.get().as_bytes().to_vec(),
+        content_type: file.content_type,
+        headers: file.headers,
+    })
+}
+
+impl QueueProducer for Spool {
+    /// Writes the file under a hidden name first, consumers only see complete files.
+    fn publish<'a>(
+        &'a self,
+        queue: &'a str,
+        message: &'a QueueMessage,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let queue_dir = self.queue_dir(queue);
+            fs::create_dir_all(&queue_dir)?;
+            let name = format!("{}-{}.json", unique_suffix(), std::process::id());
+            let partial = queue_dir.join(format!(".{name}"));
+            fs::write(&partial, spool_file(message)?)?;
+            fs::rename(partial, queue_dir.join(name))?;
+            Ok(())
+        })
+    }
+}
+
+pub struct SpoolConsumer {
+    queue_dir: PathBuf,
+    /// `claimed/<consumer>/` of this consumer.
+    claims: PathBuf,
+    /// Tells other consumers this one is still running.
+    _lock: File,
+}
+
+struct SpoolDelivery {
+    queue_dir: PathBuf,
+    claim: PathBuf,
+    name: String,
+    message: QueueMessage,
+}
+
+impl SpoolConsumer {
+    /// Claims the oldest message, `None` if the queue is empty.
+    fn claim(&self) -> Result<Option<SpoolDelivery>, anyhow::Error> {
+        let mut names = fs::read_dir(&self.queue_dir)?
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .filter(|name| !name.starts_with('.') && name.ends_with(".json"))
+            .collect::<Vec<_>>();
+        names.sort();
+        for name in names {
+            let claim = self.claims.join(&name);
+            match fs::rename(self.queue_dir.join(&name), &claim) {
+                Ok(()) => {}
+                // Another consumer was faster.
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            }
+            let message = match parse_spool_file(&fs::read(&claim)?) {
+                Ok(message) => message,
+                Err(e) => {
+                    let rejected = self.queue_dir.join("rejected");
+                    fs::create_dir_all(&rejected)?;
+                    fs::rename(&claim, rejected.join(&name))?;
+                    return Err(
+                        e.context(format!("Moved unreadable {name} to {}", rejected.display()))
+                    );
+                }
+            };
+            return Ok(Some(SpoolDelivery {
+                queue_dir: self.queue_dir.clone(),
+                claim,
+                name,
+                message,
+            }));
+        }
+        Ok(None)
+    }
+}
+
+impl QueueConsumer for SpoolConsumer {
+    fn next(&mut self) -> BoxFuture<'_, Option<Result<Box<dyn QueueDelivery>, anyhow::Error>>> {
+        Box::pin(async {
+            loop {
+                match self.claim() {
+                    Ok(Some(delivery)) => {
+                        return Some(Ok(Box::new(delivery) as Box<dyn QueueDelivery>))
+                    }
+                    Ok(None) => tokio::time::sleep(POLL_INTERVAL).await,
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+        })
+    }
+}
+
+impl QueueDelivery for SpoolDelivery {
+    fn message(&self) -> &QueueMessage {
+        &self.message
+    }
+
+    fn redelivered(&self) -> bool {
+        self.name.contains(REDELIVERED)
+    }
+
+    fn ack(self: Box<Self>) -> BoxFuture<'static, Result<(), anyhow::Error>> {
+        Box::pin(async move { Ok(fs::remove_file(&self.claim)?) })
+    }
+
+    /// Back under its old name, so it is the next message again.
+    fn requeue(self: Box<Self>) -> BoxFuture<'static, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let name = redelivered_name(&self.name);
+            Ok(fs::rename(&self.claim, self.queue_dir.join(name))?)
+        })
+    }
+
+    fn reject(self: Box<Self>) -> BoxFuture<'static, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let rejected = self.queue_dir.join("rejected");
+            fs::create_dir_all(&rejected)?;
+            Ok(fs::rename(&self.claim, rejected.join(&self.name))?)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::{
+        queue::{QueueConsumer, QueueMessage, QueueProducer},
+        spool::{parse_spool_file, spool_file, Spool},
+    };
+
+    #[tokio::test]
+    async fn moves_messages_through_the_spool() {
+        let dir = std::env::temp_dir().join(format!("pipeline-spool-{}", std::process::id()));
+        let spool = Spool::new(&dir);
+        let message = |payload: &str| {
+            QueueMessage::new(payload.as_bytes().to_vec())
+                .with_content_type("application/json")
+                .with_headers(vec![("crawl".to_string(), "CC-MAIN-2024-30".to_string())])
+        };
+        let first = message(r#"[{"url": "https://example.com/"}]"#);
+        let second = message("[]");
+        assert_eq!(
+            parse_spool_file(&spool_file(&first).unwrap()).unwrap(),
+            first
+        );
+        assert!(spool_file(&QueueMessage::new(vec![0, 159])).is_err());
+        spool.publish("batches", &first).await.unwrap();
+        spool.publish("batches", &second).await.unwrap();
+
+        let mut consumer = spool.consumer("batches").unwrap();
+        let locks: Vec<_> = fs::read_dir(dir.join("batches/claimed"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "lock"))
+            .collect();
+        assert_eq!(locks.len(), 1);
+        assert!(fs::File::open(&locks[0]).unwrap().try_lock().is_err());
+        let delivery = consumer.next().await.unwrap().unwrap();
+        assert_eq!(delivery.message(), &first);
+        assert!(!delivery.redelivered());
+        delivery.requeue().await.unwrap();
+        let delivery = consumer.next().await.unwrap().unwrap();
+        assert_eq!(delivery.message(), &first);
+        assert!(delivery.redelivered());
+        delivery.ack().await.unwrap();
+
+        // A consumer that stops without settling leaves its claim to the next one.
+        let delivery = consumer.next().await.unwrap().unwrap();
+        assert_eq!(delivery.message(), &second);
+        drop(delivery);
+        drop(consumer);
+        let mut consumer = spool.consumer("batches").unwrap();
+        let delivery = consumer.next().await.unwrap().unwrap();
+        assert_eq!(delivery.message(), &second);
+        assert!(delivery.redelivered());
+        delivery.reject().await.unwrap();
+        assert_eq!(
+            fs::read_dir(dir.join("batches/rejected")).unwrap().count(),
+            1
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
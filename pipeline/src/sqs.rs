@@ -0,0 +1,392 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures_util::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::task::JoinHandle;
+
+use crate::{
+    cdx::FetchError,
+    queue::{QueueConsumer, QueueDelivery, QueueMessage, QueueProducer},
+    rate_limit::send_limited,
+    retry::Backoff,
+    sigv4::{amz_date, region_from_env, sha256_hex, signature_v4, AwsCredentials, SignedRequest},
+};
+
+/// The longest a receive may wait for a message, SQS does not allow more.
+const MAX_WAIT_SECS: u32 = 20;
+
+/// A message in the body of an SQS message. SQS bodies are text and a message has at most
+/// ten attributes, fewer than a batch has headers, so everything goes into the body.
+#[derive(Debug, Deserialize, Serialize)]
+struct Envelope {
+    payload: String,
+    content_type: Option<String>,
+    headers: Vec<(String, String)>,
+}
+
+/// The body of the SQS message of `message`, JSON with the payload in base64.
+pub fn message_body(message: &QueueMessage) -> String {
+    serde_json::to_string(&Envelope {
+        payload: STANDARD.encode(&message.payload),
+        content_type: message.content_type.clone(),
+        headers: message.headers.clone(),
+    })
+    .unwrap()
+}
+
+pub fn parse_message_body(body: &str) -> Result<QueueMessage, anyhow::Error> {
+    let envelope: Envelope = serde_json::from_str(body)?;
+    Ok(QueueMessage {
+        payload: STANDARD.decode(envelope.payload)?,
+        content_type: envelope.content_type,
+        headers: envelope.headers,
+    })
+}
+
+/// A client of the SQS JSON API. Queues are addressed by name, their URLs are looked up
+/// once. Transient failures are retried per request with backoff.
+#[derive(Clone)]
+pub struct SqsClient {
+    http_client: reqwest::Client,
+    credentials: AwsCredentials,
+    region: String,
+    endpoint: url::Url,
+    backoff: Backoff,
+    max_attempts: usize,
+    visibility_timeout_secs: u32,
+    queue_urls: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl SqsClient {
+    /// Talks to `endpoint`, e.g. ElasticMQ or LocalStack, or to AWS in `region` if there
+    /// is none.
+    pub fn new(
+        http_client: reqwest::Client,
+        credentials: AwsCredentials,
+        region: &str,
+        endpoint: Option<url::Url>,
+    ) -> Result<Self, anyhow::Error> {
+        let endpoint = match endpoint {
+            Some(endpoint) => endpoint,
+            None => url::Url::parse(&format!("https://sqs.{region}.amazonaws.com/"))?,
+        };
+        Ok(Self {
+            http_client,
+            credentials,
+            region: region.to_string(),
+            endpoint,
+            backoff: Backoff::default(),
+            max_attempts: 5,
+            visibility_timeout_secs: 1800,
+            queue_urls: Arc::default(),
+        })
+    }
+
+    pub fn with_retries(mut self, backoff: Backoff, max_attempts: usize) -> Self {
+        self.backoff = backoff;
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    pub fn with_visibility_timeout(mut self, visibility_timeout_secs: u32) -> Self {
+        self.visibility_timeout_secs = visibility_timeout_secs;
+        self
+    }
+
+    /// The client of the region and `AWS_ENDPOINT_URL` in the environment, see
+    /// [`region_from_env`].
+    pub fn from_env(http_client: reqwest::Client) -> Result<Self, anyhow::Error> {
+        let endpoint = std::env::var("AWS_ENDPOINT_URL")
+            .ok()
+            .map(|endpoint| url::Url::parse(&endpoint))
+            .transpose()?;
+        Self::new(
+            http_client,
+            AwsCredentials::from_env()?,
+            &region_from_env(),
+            endpoint,
+        )
+    }
+
+    /// Calls `action` with the JSON `request`, retrying transient failures, and returns
+    /// the JSON response.
+    async fn call(
+        &self,
+        action: &str,
+        request: serde_json::Value,
+    ) -> Result<serde_json::Value, anyhow::Error> {
+        let url = self.endpoint.as_str();
+        let host = match self.endpoint.port() {
+            Some(port) => format!("{}:{port}", self.endpoint.host_str().unwrap_or_default()),
+            None => self.endpoint.host_str().unwrap_or_default().to_string(),
+        };
+        let target = format!("AmazonSQS.{action}");
+        let body = serde_json::to_vec(&request)?;
+        let payload_sha256 = sha256_hex(&body);
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let amz_date = amz_date(SystemTime::now());
+            let mut headers = vec![
+                ("content-type", "application/x-amz-json-1.0"),
+                ("host", host.as_str()),
+                ("x-amz-date", amz_date.as_str()),
+                ("x-amz-target", target.as_str()),
+            ];
+            if let Some(token) = self.credentials.session_token.as_deref() {
+                headers.push(("x-amz-security-token", token));
+            }
+            let authorization = signature_v4(
+                &self.credentials,
+                &self.region,
+                "sqs",
+                &amz_date,
+                &SignedRequest {
+                    method: "POST",
+                    path: self.endpoint.path(),
+                    query: &[],
+                    headers: &headers,
+                    payload_sha256: &payload_sha256,
+                },
+            );
+            let mut request = self
+                .http_client
+                .post(url)
+                .header("authorization", authorization)
+                .body(body.clone());
+            for (name, value) in headers.iter().filter(|(name, _)| *name != "host") {
+                request = request.header(*name, *value);
+            }
+            let error = match send_limited(request).await {
+                Ok((response, _)) if response.status().is_success() => {
+                    return Ok(serde_json::from_slice(&response.bytes().await?)?);
+                }
+                Ok((response, _)) => {
+                    let error = FetchError::from_response(url, &response);
+                    let body = response.text().await.unwrap_or_default();
+                    tracing::debug!("SQS answered {}", body);
+                    error
+                }
+                Err(e) => FetchError::Transient {
+                    url: url.to_string(),
+                    reason: e.to_string(),
+                    status: None,
+                    retry_after: None,
+                },
+            };
+            if error.is_permanent() || attempt >= self.max_attempts {
+                return Err(anyhow::Error::from(error).context(format!("SQS {action} failed")));
+            }
+            let delay = self.backoff.delay(attempt, error.retry_after());
+            tracing::warn!("{}, retrying in {:?}", error, delay);
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    async fn queue_url(&self, queue: &str) -> Result<String, anyhow::Error> {
+        if let Some(queue_url) = self.queue_urls.lock().unwrap().get(queue) {
+            return Ok(queue_url.clone());
+        }
+        let response = self
+            .call("GetQueueUrl", json!({ "QueueName": queue }))
+            .await?;
+        let Some(queue_url) = response["QueueUrl"].as_str() else {
+            anyhow::bail!("SQS returned no URL for {queue}");
+        };
+        self.queue_urls
+            .lock()
+            .unwrap()
+            .insert(queue.to_string(), queue_url.to_string());
+        Ok(queue_url.to_string())
+    }
+
+    /// Whether the credentials may look up each of `queues`, for `pipeline doctor`.
+    pub async fn check_queues(&self, queues: &[&str]) -> Result<String, anyhow::Error> {
+        for queue in queues {
+            self.queue_url(queue).await?;
+        }
+        Ok(format!("has {}", queues.join(", ")))
+    }
+
+    pub fn consumer(&self, queue: &str) -> SqsConsumer {
+        SqsConsumer {
+            client: self.clone(),
+            queue: queue.to_string(),
+            receiving: None,
+        }
+    }
+}
+
+impl QueueProducer for SqsClient {
+    fn publish<'a>(
+        &'a self,
+        queue: &'a str,
+        message: &'a QueueMessage,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let queue_url = self.queue_url(queue).await?;
+            self.call(
+                "SendMessage",
+                json!({ "QueueUrl": queue_url, "MessageBody": message_body(message) }),
+            )
+            .await?;
+            Ok(())
+        })
+    }
+}
+
+/// Long-polls one queue for a message at a time. The receive runs in a task of its own,
+/// so dropping `next`, e.g. in a `select!`, does not lose a message that was already
+/// on its way, it is returned by the next call.
+pub struct SqsConsumer {
+    client: SqsClient,
+    queue: String,
+    receiving: Option<JoinHandle<Result<Box<dyn QueueDelivery>, anyhow::Error>>>,
+}
+
+struct SqsDelivery {
+    client: SqsClient,
+    queue_url: String,
+    receipt_handle: String,
+    receive_count: u32,
+    message: QueueMessage,
+}
+
+impl SqsConsumer {
+    async fn receive(
+        client: SqsClient,
+        queue: String,
+    ) -> Result<Box<dyn QueueDelivery>, anyhow::Error> {
+        let queue_url = client.queue_url(&queue).await?;
+        loop {
+            let response = client
+                .call(
+                    "ReceiveMessage",
+                    json!({
+                        "QueueUrl": queue_url,
+                        "MaxNumberOfMessages": 1,
+                        "WaitTimeSeconds": MAX_WAIT_SECS,
+                        "VisibilityTimeout": client.visibility_timeout_secs,
+                        "MessageSystemAttributeNames": ["ApproximateReceiveCount"],
+                    }),
+                )
+                .await?;
+            let Some(received) = response["Messages"].get(0) else {
+                continue;
+            };
+            let Some(receipt_handle) = received["ReceiptHandle"].as_str() else {
+                anyhow::bail!("SQS returned a message without receipt handle");
+            };
+            let receive_count = received["Attributes"]["ApproximateReceiveCount"]
+                .as_str()
+                .and_then(|count| count.parse().ok())
+                .unwrap_or(1);
+            let message = parse_message_body(received["Body"].as_str().unwrap_or_default());
+            let delivery = SqsDelivery {
+                client: client.clone(),
+                queue_url: queue_url.clone(),
+                receipt_handle: receipt_handle.to_string(),
+                receive_count,
+                message: QueueMessage::default(),
+            };
+            return match message {
+                Ok(message) => Ok(Box::new(SqsDelivery {
+                    message,
+                    ..delivery
+                })),
+                Err(e) => {
+                    // Not one of ours, it would come back on every receive.
+                    delivery.delete().await?;
+                    Err(e.context("Dropped an SQS message that is not a pipeline message"))
+                }
+            };
+        }
+    }
+}
+
+impl QueueConsumer for SqsConsumer {
+    fn next(&mut self) -> BoxFuture<'_, Option<Result<Box<dyn QueueDelivery>, anyhow::Error>>> {
+        Box::pin(async {
+            let receiving = self.receiving.get_or_insert_with(|| {
+                tokio::spawn(Self::receive(self.client.clone(), self.queue.clone()))
+            });
+            let received = receiving.await;
+            self.receiving = None;
+            Some(received.unwrap_or_else(|e| Err(e.into())))
+        })
+    }
+}
+
+impl SqsDelivery {
+    async fn delete(&self) -> Result<(), anyhow::Error> {
+        self.client
+            .call(
+                "DeleteMessage",
+                json!({ "QueueUrl": self.queue_url, "ReceiptHandle": self.receipt_handle }),
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+impl QueueDelivery for SqsDelivery {
+    fn message(&self) -> &QueueMessage {
+        &self.message
+    }
+
+    fn redelivered(&self) -> bool {
+        self.receive_count > 1
+    }
+
+    fn ack(self: Box<Self>) -> BoxFuture<'static, Result<(), anyhow::Error>> {
+        Box::pin(async move { self.delete().await })
+    }
+
+    /// Makes the message visible again right away.
+    fn requeue(self: Box<Self>) -> BoxFuture<'static, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            self.client
+                .call(
+                    "ChangeMessageVisibility",
+                    json!({
+                        "QueueUrl": self.queue_url,
+                        "ReceiptHandle": self.receipt_handle,
+                        "VisibilityTimeout": 0,
+                    }),
+                )
+                .await?;
+            Ok(())
+        })
+    }
+
+    /// SQS moves messages to a dead-letter queue by its redrive policy after too many
+    /// receives, a rejected message is deleted.
+    fn reject(self: Box<Self>) -> BoxFuture<'static, Result<(), anyhow::Error>> {
+        Box::pin(async move { self.delete().await })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        queue::QueueMessage,
+        sqs::{message_body, parse_message_body},
+    };
+
+    #[test]
+    fn wraps_messages_in_json() {
+        let message = QueueMessage::new(vec![0, 159, 146, 150])
+            .with_content_type("application/x-protobuf")
+            .with_headers(vec![("crawl".to_string(), "CC-MAIN-2024-30".to_string())]);
+        let body = message_body(&message);
+        assert!(body.contains(r#""payload":"AJ+Slg==""#));
+        assert_eq!(parse_message_body(&body).unwrap(), message);
+        assert!(parse_message_body("[]").is_err());
+    }
+}
@@ -0,0 +1,82 @@
+use std::{path::Path, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::output::{OutputFile, WriteOptions};
+
+/// How long each stage took for one document, in milliseconds. Fetch and decompress
+/// cover the whole WARC record, which usually holds a single document.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct StageTimings {
+    pub id: String,
+    pub url: String,
+    /// The detected MIME type of the capture, to compare stages across content types.
+    pub mime: Option<String>,
+    pub fetch_ms: f64,
+    pub decompress_ms: f64,
+    pub extract_ms: f64,
+    /// Content sniffing, language detection and quality scoring.
+    pub filter_ms: f64,
+    pub write_ms: f64,
+}
+
+pub fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Appends [`StageTimings`] as JSON lines, next to the documents they describe.
+pub struct StageTimingsWriter {
+    file: OutputFile,
+}
+
+impl StageTimingsWriter {
+    pub fn create(filename: &str, options: &WriteOptions) -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            file: OutputFile::append(Path::new(filename), options)?,
+        })
+    }
+
+    pub fn write(&mut self, timings: &StageTimings) -> Result<(), anyhow::Error> {
+        self.file.write_line(timings)
+    }
+
+    pub fn flush(&mut self) -> Result<(), anyhow::Error> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        output::WriteOptions,
+        stage_timings::{millis, StageTimings, StageTimingsWriter},
+    };
+
+    #[test]
+    fn appends_timings() {
+        let path = std::env::temp_dir().join(format!("pipeline-timings-{}", std::process::id()));
+        let mut writer =
+            StageTimingsWriter::create(path.to_str().unwrap(), &WriteOptions::default()).unwrap();
+        for id in ["a", "b"] {
+            writer
+                .write(&StageTimings {
+                    id: id.to_string(),
+                    fetch_ms: millis(Duration::from_micros(1500)),
+                    ..StageTimings::default()
+                })
+                .unwrap();
+        }
+        writer.flush().unwrap();
+        let timings = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<StageTimings>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(timings.len(), 2);
+        assert_eq!(timings[1].id, "b");
+        assert_eq!(timings[1].fetch_ms, 1.5);
+        std::fs::remove_file(path).unwrap();
+    }
+}
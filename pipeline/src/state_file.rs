@@ -0,0 +1,203 @@
+use std::{
+    fs::{self, File},
+    io::{ErrorKind, Write},
+    path::Path,
+};
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Starts every state file of the pipeline.
+const MAGIC: &[u8; 4] = b"CCPS";
+/// Magic, kind, version, CRC32 and length of the payload.
+const HEADER_LEN: usize = 4 + 4 + 4 + 4 + 8;
+
+/// State the pipeline keeps on disk between runs, like the batcher checkpoint and the
+/// worker journal. A state file is a header followed by the state as JSON:
+///
+/// | Bytes | Content                                          |
+/// |-------|--------------------------------------------------|
+/// | 4     | `CCPS`                                           |
+/// | 4     | [`KIND`](StateFile::KIND), e.g. `CKPT`           |
+/// | 4     | [`VERSION`](StateFile::VERSION), little-endian   |
+/// | 4     | CRC32 of the payload, little-endian              |
+/// | 8     | Length of the payload, little-endian             |
+///
+/// Files of older versions are passed to [`StateFile::migrate`] when they are read, so
+/// the state of a run survives an upgrade. Corrupt or truncated files, files of another
+/// kind and files of newer versions are errors instead of being ignored.
+pub trait StateFile: Serialize + DeserializeOwned {
+    const KIND: &'static [u8; 4];
+    /// Increase it whenever the JSON of a version cannot be read as the next one.
+    const VERSION: u32;
+
+    /// The state of the payload of a file written as `version`. Version 0 is the plain
+    /// JSON of the pipeline from before state files had a header.
+    fn migrate(version: u32, payload: serde_json::Value) -> Result<Self, anyhow::Error>;
+}
+
+pub fn encode_state<T: StateFile>(state: &T) -> Result<Vec<u8>, anyhow::Error> {
+    let payload = serde_json::to_vec(state)?;
+    let mut data = Vec::with_capacity(HEADER_LEN + payload.len());
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(T::KIND);
+    data.extend_from_slice(&T::VERSION.to_le_bytes());
+    data.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    data.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    data.extend_from_slice(&payload);
+    Ok(data)
+}
+
+pub fn decode_state<T: StateFile>(data: &[u8]) -> Result<T, anyhow::Error> {
+    if !data.starts_with(MAGIC) {
+        let payload = serde_json::from_slice(data).context("Not a state file")?;
+        return T::migrate(0, payload);
+    }
+    if data.len() < HEADER_LEN {
+        anyhow::bail!("Truncated state file header");
+    }
+    let kind = &data[4..8];
+    if kind != T::KIND {
+        anyhow::bail!(
+            "Expected a {} state file, found {}",
+            String::from_utf8_lossy(T::KIND),
+            String::from_utf8_lossy(kind)
+        );
+    }
+    let version = u32::from_le_bytes(data[8..12].try_into().unwrap());
+    let crc = u32::from_le_bytes(data[12..16].try_into().unwrap());
+    let len = u64::from_le_bytes(data[16..24].try_into().unwrap());
+    let payload = &data[HEADER_LEN..];
+    if payload.len() as u64 != len {
+        anyhow::bail!(
+            "Truncated state file, {} of {} payload bytes",
+            payload.len(),
+            len
+        );
+    }
+    if crc32fast::hash(payload) != crc {
+        anyhow::bail!("Corrupt state file, the checksum does not match");
+    }
+    if version > T::VERSION {
+        anyhow::bail!(
+            "State file version {} is newer than {}, written by a newer pipeline",
+            version,
+            T::VERSION
+        );
+    }
+    if version < T::VERSION {
+        tracing::info!(
+            "Migrating {} state file from version {} to {}",
+            String::from_utf8_lossy(T::KIND),
+            version,
+            T::VERSION
+        );
+        return T::migrate(version, serde_json::from_slice(payload)?);
+    }
+    Ok(serde_json::from_slice(payload)?)
+}
+
+/// `None` if there is no file at `path`.
+pub fn read_state_file<T: StateFile>(path: &Path) -> Result<Option<T>, anyhow::Error> {
+    match fs::read(path) {
+        Ok(data) => decode_state(&data)
+            .map(Some)
+            .with_context(|| format!("Failed to read {}", path.display())),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Replaces the file at `path` atomically, synced to disk with `sync`.
+pub fn write_state_file<T: StateFile>(
+    path: &Path,
+    state: &T,
+    sync: bool,
+) -> Result<(), anyhow::Error> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&encode_state(state)?)?;
+    if sync {
+        file.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use crate::state_file::{decode_state, encode_state, StateFile};
+
+    #[derive(Debug, PartialEq, Deserialize, Serialize)]
+    struct Progress {
+        chunks: Vec<usize>,
+    }
+
+    impl StateFile for Progress {
+        const KIND: &'static [u8; 4] = b"TEST";
+        const VERSION: u32 = 2;
+
+        /// Version 1 had a single chunk, version 0 a `chunk` field.
+        fn migrate(version: u32, payload: serde_json::Value) -> Result<Self, anyhow::Error> {
+            let field = if version == 0 { "chunk" } else { "chunks" };
+            let Some(chunk) = payload[field].as_u64() else {
+                anyhow::bail!("No {field} in version {version}");
+            };
+            Ok(Self {
+                chunks: vec![chunk as usize],
+            })
+        }
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Other;
+
+    impl StateFile for Other {
+        const KIND: &'static [u8; 4] = b"OTHR";
+        const VERSION: u32 = 1;
+
+        fn migrate(_: u32, payload: serde_json::Value) -> Result<Self, anyhow::Error> {
+            Ok(serde_json::from_value(payload)?)
+        }
+    }
+
+    fn with_header(version: u32, payload: &[u8]) -> Vec<u8> {
+        let mut data = b"CCPSTEST".to_vec();
+        data.extend_from_slice(&version.to_le_bytes());
+        data.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+        data.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+        data.extend_from_slice(payload);
+        data
+    }
+
+    #[test]
+    fn checks_and_migrates_state_files() {
+        let progress = Progress { chunks: vec![3, 4] };
+        let data = encode_state(&progress).unwrap();
+        assert_eq!(data, with_header(2, br#"{"chunks":[3,4]}"#));
+        assert_eq!(decode_state::<Progress>(&data).unwrap(), progress);
+
+        let migrated = Progress { chunks: vec![7] };
+        assert_eq!(
+            decode_state::<Progress>(br#"{"chunk": 7}"#).unwrap(),
+            migrated
+        );
+        assert_eq!(
+            decode_state::<Progress>(&with_header(1, br#"{"chunks": 7}"#)).unwrap(),
+            migrated
+        );
+
+        let mut corrupt = data.clone();
+        *corrupt.last_mut().unwrap() = b' ';
+        assert!(
+            format!("{:#}", decode_state::<Progress>(&corrupt).unwrap_err()).contains("checksum")
+        );
+        assert!(decode_state::<Progress>(&data[..data.len() - 1]).is_err());
+        assert!(decode_state::<Progress>(&data[..10]).is_err());
+        assert!(decode_state::<Progress>(&with_header(3, b"{}")).is_err());
+        assert!(decode_state::<Other>(&data).is_err());
+        assert!(decode_state::<Progress>(b"garbage").is_err());
+    }
+}
@@ -0,0 +1,156 @@
+use scraper::{ElementRef, Html, Selector};
+use serde_json::{Map, Value};
+
+fn short_type(t: &str) -> &str {
+    t.rsplit('/').next().unwrap_or(t)
+}
+
+/// The schema.org types of a JSON-LD node or microdata item, without the vocabulary URL.
+fn schema_types(item: &Value) -> Vec<&str> {
+    match item.get("@type") {
+        Some(Value::String(t)) => vec![short_type(t)],
+        Some(Value::Array(types)) => types
+            .iter()
+            .filter_map(Value::as_str)
+            .map(short_type)
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Pulls JSON-LD blocks and microdata items out of an HTML page, e.g. schema.org
+/// products, articles or recipes. JSON-LD `@graph`s and arrays are flattened into their
+/// nodes. With `types`, only items of one of these schema.org types are kept.
+pub fn extract_structured_data(html: &str, types: &[String]) -> Vec<Value> {
+    let document = Html::parse_document(html);
+    let mut items = Vec::new();
+    let json_ld = Selector::parse(r#"script[type="application/ld+json"]"#).unwrap();
+    for script in document.select(&json_ld) {
+        let Ok(value) = serde_json::from_str::<Value>(&script.text().collect::<String>()) else {
+            continue;
+        };
+        let mut pending = vec![value];
+        while let Some(value) = pending.pop() {
+            match value {
+                Value::Array(values) => pending.extend(values.into_iter().rev()),
+                Value::Object(mut object) if object.contains_key("@graph") => {
+                    pending.push(object.remove("@graph").unwrap());
+                }
+                value @ Value::Object(_) => items.push(value),
+                _ => {}
+            }
+        }
+    }
+    let top_level_items = Selector::parse("[itemscope]:not([itemprop])").unwrap();
+    for element in document.select(&top_level_items) {
+        items.push(microdata_item(element));
+    }
+    if !types.is_empty() {
+        items.retain(|item| {
+            schema_types(item)
+                .iter()
+                .any(|t| types.iter().any(|w| w == t))
+        });
+    }
+    items
+}
+
+fn microdata_item(item: ElementRef) -> Value {
+    let mut object = Map::new();
+    if let Some(item_type) = item.value().attr("itemtype") {
+        object.insert("@type".to_string(), Value::String(item_type.to_string()));
+    }
+    let properties = Selector::parse("[itemprop]").unwrap();
+    for property in item.select(&properties) {
+        // Properties of nested items belong to those.
+        let owner = property
+            .ancestors()
+            .filter_map(ElementRef::wrap)
+            .find(|a| a.value().attr("itemscope").is_some());
+        if owner.map(|owner| owner.id()) != Some(item.id()) {
+            continue;
+        }
+        let value = if property.value().attr("itemscope").is_some() {
+            microdata_item(property)
+        } else {
+            Value::String(property_value(property))
+        };
+        for name in property
+            .value()
+            .attr("itemprop")
+            .unwrap()
+            .split_whitespace()
+        {
+            match object.get_mut(name) {
+                Some(Value::Array(values)) => values.push(value.clone()),
+                Some(existing) => *existing = Value::Array(vec![existing.take(), value.clone()]),
+                None => {
+                    object.insert(name.to_string(), value.clone());
+                }
+            }
+        }
+    }
+    Value::Object(object)
+}
+
+/// The value of a microdata property after the HTML spec, from the attribute its element
+/// type carries it in, and from the text otherwise.
+fn property_value(property: ElementRef) -> String {
+    let element = property.value();
+    let attr = match element.name() {
+        "meta" => "content",
+        "a" | "area" | "link" => "href",
+        "img" | "audio" | "video" | "source" | "iframe" | "embed" => "src",
+        "object" => "data",
+        "time" => "datetime",
+        "data" | "meter" => "value",
+        _ => "",
+    };
+    match element.attr(attr) {
+        Some(value) => value.to_string(),
+        None => property
+            .text()
+            .collect::<String>()
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" "),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::structured::extract_structured_data;
+
+    const PAGE: &str = r#"<html><head>
+<script type="application/ld+json">{"@context": "https://schema.org", "@graph": [{"@type": "Article", "headline": "News"}, {"@type": "WebSite", "name": "Site"}]}</script>
+<script type="application/ld+json">not json</script>
+</head><body>
+<div itemscope itemtype="https://schema.org/Product">
+  <span itemprop="name">Kettle</span>
+  <img itemprop="image" src="/kettle.jpg">
+  <div itemprop="offers" itemscope itemtype="https://schema.org/Offer">
+    <meta itemprop="price" content="19.99"><span itemprop="priceCurrency">EUR</span>
+  </div>
+</div></body></html>"#;
+
+    #[test]
+    fn extracts_json_ld_and_microdata() {
+        let items = extract_structured_data(PAGE, &[]);
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[0], json!({"@type": "Article", "headline": "News"}));
+        assert_eq!(
+            items[2],
+            json!({
+                "@type": "https://schema.org/Product",
+                "name": "Kettle",
+                "image": "/kettle.jpg",
+                "offers": {"@type": "https://schema.org/Offer", "price": "19.99", "priceCurrency": "EUR"}
+            })
+        );
+        let products =
+            extract_structured_data(PAGE, &["Product".to_string(), "Article".to_string()]);
+        assert_eq!(products.len(), 2);
+    }
+}
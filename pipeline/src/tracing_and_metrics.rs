@@ -1,11 +1,19 @@
-use autometrics::prometheus_exporter::{self, PrometheusResponse};
-use tracing_subscriber::EnvFilter;
+use tracing_subscriber::{
+    layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Registry,
+};
 
+#[cfg(feature = "metrics")]
 pub async fn run_metrics_server(port: u16) {
+    use autometrics::prometheus_exporter::{self, PrometheusResponse};
+
     prometheus_exporter::init();
 
     async fn metrics() -> PrometheusResponse {
-        prometheus_exporter::encode_http_response()
+        let mut response = prometheus_exporter::encode_http_response();
+        response
+            .body_mut()
+            .push_str(&crate::metrics::exported_metrics_text());
+        response
     }
 
     let app = axum::Router::new().route("/metrics", axum::routing::get(metrics));
@@ -15,11 +23,25 @@ pub async fn run_metrics_server(port: u16) {
     axum::serve(listener, app).await.unwrap();
 }
 
-pub fn setup_tracing() {
-    // construct a subscriber that prints formatted traces to stdout
-    let filter = EnvFilter::from_default_env();
-    let subscriber = tracing_subscriber::fmt().with_env_filter(filter).finish();
-    // use that subscriber to process traces emitted after this point
-    tracing::subscriber::set_global_default(subscriber).unwrap();
+/// Changes the log filter of a running process, see [`setup_tracing`].
+pub struct LogFilterHandle(reload::Handle<EnvFilter, Registry>);
+
+impl LogFilterHandle {
+    /// Replaces the filter by `directives` in `RUST_LOG` syntax, e.g. `info,pipeline=debug`.
+    pub fn set(&self, directives: &str) -> Result<(), anyhow::Error> {
+        self.0.reload(EnvFilter::try_new(directives)?)?;
+        Ok(())
+    }
+}
+
+pub fn setup_tracing() -> LogFilterHandle {
+    // filter by RUST_LOG, reloadable so the log level can change without a restart
+    let (filter, handle) = reload::Layer::new(EnvFilter::from_default_env());
+    // print formatted traces to stdout for everything emitted after this point
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer())
+        .init();
     tracing::info!("Tracing initialized");
+    LogFilterHandle(handle)
 }
@@ -0,0 +1,204 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+use crate::metrics::{ExportMetrics, PrometheusText};
+
+/// What a request to Common Crawl was for, so index and WARC bandwidth can be told apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrafficKind {
+    /// CDX shards from `cc-index/`.
+    Index,
+    /// WARC records from `crawl-data/`.
+    Warc,
+    /// Queries to the index API.
+    IndexApi,
+}
+
+impl TrafficKind {
+    pub const ALL: [TrafficKind; 3] =
+        [TrafficKind::Index, TrafficKind::Warc, TrafficKind::IndexApi];
+
+    /// Classifies a data.commoncrawl.org URL.
+    pub fn of_data_url(url: &str) -> Self {
+        if url.contains("/cc-index/") {
+            TrafficKind::Index
+        } else {
+            TrafficKind::Warc
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TrafficKind::Index => "index",
+            TrafficKind::Warc => "warc",
+            TrafficKind::IndexApi => "index_api",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TrafficTotals {
+    pub bytes: u64,
+    pub requests: u64,
+    pub retries: u64,
+}
+
+impl TrafficTotals {
+    /// What was added since `earlier`.
+    pub fn since(&self, earlier: &TrafficTotals) -> TrafficTotals {
+        TrafficTotals {
+            bytes: self.bytes - earlier.bytes,
+            requests: self.requests - earlier.requests,
+            retries: self.retries - earlier.retries,
+        }
+    }
+}
+
+struct Counters {
+    bytes: AtomicU64,
+    requests: AtomicU64,
+    retries: AtomicU64,
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Self {
+            bytes: AtomicU64::new(0),
+            requests: AtomicU64::new(0),
+            retries: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Process-wide counters of downloaded bytes, requests and retries per [`TrafficKind`].
+pub struct Traffic {
+    counters: [Counters; 3],
+}
+
+/// Updated by all fetches in this crate.
+pub static TRAFFIC: Traffic = Traffic {
+    counters: [Counters::new(), Counters::new(), Counters::new()],
+};
+
+impl Traffic {
+    fn counters(&self, kind: TrafficKind) -> &Counters {
+        &self.counters[kind as usize]
+    }
+
+    pub fn record_request(&self, kind: TrafficKind, bytes: u64) {
+        let counters = self.counters(kind);
+        counters.requests.fetch_add(1, Ordering::Relaxed);
+        counters.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Adds `bytes` to a request already recorded, for bodies that are streamed.
+    pub fn record_bytes(&self, kind: TrafficKind, bytes: u64) {
+        self.counters(kind)
+            .bytes
+            .fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn record_retry(&self, kind: TrafficKind) {
+        self.counters(kind).retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn totals(&self, kind: TrafficKind) -> TrafficTotals {
+        let counters = self.counters(kind);
+        TrafficTotals {
+            bytes: counters.bytes.load(Ordering::Relaxed),
+            requests: counters.requests.load(Ordering::Relaxed),
+            retries: counters.retries.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl ExportMetrics for Traffic {
+    fn export(&self, text: &mut PrometheusText) {
+        for (name, help, field) in [
+            (
+                "pipeline_downloaded_bytes_total",
+                "Bytes downloaded from Common Crawl.",
+                (|t: &TrafficTotals| t.bytes) as fn(&TrafficTotals) -> u64,
+            ),
+            (
+                "pipeline_requests_total",
+                "Requests sent to Common Crawl.",
+                |t| t.requests,
+            ),
+            (
+                "pipeline_retries_total",
+                "Requests to Common Crawl that were retried.",
+                |t| t.retries,
+            ),
+        ] {
+            text.labeled_counter(
+                name,
+                help,
+                "kind",
+                TrafficKind::ALL.map(|kind| (kind.as_str(), field(&self.totals(kind)))),
+            );
+        }
+    }
+}
+
+/// Remembers what was already written to the run DB, so each flush only adds the delta.
+#[derive(Default)]
+pub struct TrafficFlusher {
+    flushed: [TrafficTotals; 3],
+}
+
+impl TrafficFlusher {
+    /// Returns the traffic of each kind since the last call.
+    pub fn take_deltas(&mut self) -> Vec<(TrafficKind, TrafficTotals)> {
+        TrafficKind::ALL
+            .into_iter()
+            .map(|kind| {
+                let totals = TRAFFIC.totals(kind);
+                let delta = totals.since(&self.flushed[kind as usize]);
+                self.flushed[kind as usize] = totals;
+                (kind, delta)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::traffic::{TrafficKind, TrafficTotals};
+
+    #[test]
+    fn classifies_and_subtracts() {
+        assert_eq!(
+            TrafficKind::of_data_url(
+                "https://data.commoncrawl.org/cc-index/collections/CC-MAIN-2024-30/indexes/cdx-00000.gz"
+            ),
+            TrafficKind::Index
+        );
+        assert_eq!(
+            TrafficKind::of_data_url(
+                "https://data.commoncrawl.org/crawl-data/CC-MAIN-2024-30/x.warc.gz"
+            ),
+            TrafficKind::Warc
+        );
+        let later = TrafficTotals {
+            bytes: 10,
+            requests: 3,
+            retries: 1,
+        };
+        let earlier = TrafficTotals {
+            bytes: 4,
+            requests: 1,
+            retries: 0,
+        };
+        assert_eq!(
+            later.since(&earlier),
+            TrafficTotals {
+                bytes: 6,
+                requests: 2,
+                retries: 1,
+            }
+        );
+    }
+}
@@ -4,6 +4,8 @@ use pyo3::{
     Py, PyAny, PyObject, Python,
 };
 
+use crate::extractor::HtmlExtractor;
+
 static PYTHON_SCRIPT: &str = r"
 from trafilatura import extract
 
@@ -40,3 +42,16 @@ pub fn extract(html: &str) -> Result<Option<String>, anyhow::Error> {
             .map_err(Into::into)
     })
 }
+
+/// [`HtmlExtractor`] running trafilatura in the worker process.
+pub struct Trafilatura;
+
+impl HtmlExtractor for Trafilatura {
+    fn name(&self) -> &'static str {
+        "trafilatura"
+    }
+
+    fn extract(&self, html: &str) -> Result<Option<String>, anyhow::Error> {
+        extract(html)
+    }
+}
@@ -0,0 +1,82 @@
+use std::{fs, path::Path};
+
+use serde::Deserialize;
+use tokio::sync::watch;
+
+/// Worker settings that are safe to change mid-crawl, read from a JSON file. Fields
+/// that are left out keep their current value.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tunables {
+    /// In `RUST_LOG` syntax, e.g. `info,pipeline=debug`.
+    pub log_filter: Option<String>,
+    pub ab_sample_rate: Option<f64>,
+    pub max_fetch_attempts: Option<usize>,
+    pub idle_writer_timeout_secs: Option<u64>,
+    /// See `--output-filter` of the worker, an empty string writes everything again.
+    pub output_filter: Option<String>,
+}
+
+impl Tunables {
+    pub fn read(path: &Path) -> Result<Self, anyhow::Error> {
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+}
+
+/// Reads the tunables from `path` now and again on every SIGHUP. The receiver starts out
+/// as changed, so the first values are applied like any reload. A file that does not
+/// parse is logged and skipped, a typo must not take down a long-running worker.
+pub fn watch_tunables(path: &Path) -> Result<watch::Receiver<Tunables>, anyhow::Error> {
+    let (sender, mut receiver) = watch::channel(Tunables::read(path)?);
+    receiver.mark_changed();
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let path = path.to_path_buf();
+        let mut hangups = signal(SignalKind::hangup())?;
+        tokio::task::spawn(async move {
+            while hangups.recv().await.is_some() {
+                match Tunables::read(&path) {
+                    Ok(tunables) => {
+                        tracing::info!("Reloaded tunables from {}", path.display());
+                        if sender.send(tunables).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => tracing::warn!(err.msg = %e, "Failed to reload tunables"),
+                }
+            }
+        });
+    }
+    #[cfg(not(unix))]
+    {
+        drop(sender);
+        tracing::warn!("Tunables are only read once, there is no SIGHUP on this platform");
+    }
+    Ok(receiver)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tunables::{watch_tunables, Tunables};
+
+    #[tokio::test]
+    async fn reads_partial_tunables() {
+        let path =
+            std::env::temp_dir().join(format!("pipeline-tunables-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"ab_sample_rate": 0.5}"#).unwrap();
+        let mut receiver = watch_tunables(&path).unwrap();
+        assert!(receiver.has_changed().unwrap());
+        assert_eq!(
+            *receiver.borrow_and_update(),
+            Tunables {
+                ab_sample_rate: Some(0.5),
+                ..Tunables::default()
+            }
+        );
+        std::fs::write(&path, r#"{"ab_sampel_rate": 0.5}"#).unwrap();
+        assert!(Tunables::read(&path).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
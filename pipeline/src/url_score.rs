@@ -0,0 +1,201 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::Path,
+};
+
+use crate::cdx::CdxEntry;
+
+/// Rates how valuable a capture is expected to be, so the batcher can enqueue the best
+/// entries of a CDX chunk first. Higher is better.
+pub trait UrlScorer: Send + Sync {
+    fn score(&self, entry: &CdxEntry) -> f64;
+}
+
+/// Scores entries by the popularity rank of their domain, e.g. from the Tranco list:
+/// `1 / rank` for ranked domains and their subdomains, 0 for all others.
+#[derive(Debug, Default)]
+pub struct DomainRankScorer {
+    ranks: HashMap<String, usize>,
+}
+
+impl DomainRankScorer {
+    /// Reads one domain per line, most popular first, or `rank,domain` lines as in the
+    /// Tranco CSV. Empty lines and lines starting with `#` are ignored.
+    pub fn read(path: &Path) -> Result<Self, anyhow::Error> {
+        Ok(fs::read_to_string(path)?.lines().collect())
+    }
+
+    pub fn len(&self) -> usize {
+        self.ranks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranks.is_empty()
+    }
+
+    /// The rank of the host of `url` or of its closest ranked parent domain.
+    pub fn rank(&self, url: &str) -> Option<usize> {
+        let host = url::Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_ascii_lowercase))?;
+        let mut domain = host.as_str();
+        loop {
+            if let Some(rank) = self.ranks.get(domain) {
+                return Some(*rank);
+            }
+            domain = domain.split_once('.')?.1;
+        }
+    }
+}
+
+impl<'a> FromIterator<&'a str> for DomainRankScorer {
+    fn from_iter<T: IntoIterator<Item = &'a str>>(lines: T) -> Self {
+        let mut ranks = HashMap::new();
+        let domains = lines
+            .into_iter()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| line.rsplit(',').next().unwrap_or(line).trim());
+        for (index, domain) in domains.enumerate() {
+            ranks
+                .entry(domain.to_ascii_lowercase())
+                .or_insert(index + 1);
+        }
+        Self { ranks }
+    }
+}
+
+impl UrlScorer for DomainRankScorer {
+    fn score(&self, entry: &CdxEntry) -> f64 {
+        self.rank(&entry.metadata.url)
+            .map_or(0.0, |rank| 1.0 / rank as f64)
+    }
+}
+
+/// Orders `items` by the descending score of the entry `entry` picks from each. Items of
+/// the same score keep their order, so the result, and with it the batches, stay the same
+/// across runs.
+pub fn order_by_score<T>(
+    items: &mut Vec<T>,
+    scorer: &dyn UrlScorer,
+    entry: impl Fn(&T) -> &CdxEntry,
+) {
+    let mut scored = items
+        .drain(..)
+        .map(|item| (scorer.score(entry(&item)), item))
+        .collect::<Vec<_>>();
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+    items.extend(scored.into_iter().map(|(_, item)| item));
+}
+
+/// Orders `items` like [`order_by_score`] and cuts them into batches of up to
+/// `batch_size` items of the same `bucket`, so what holds for the first entry of a batch,
+/// like its domain hash, holds for all. The batches are ordered by the score of their
+/// first entry, best first, and keep their order otherwise.
+pub fn batches_by_score<T>(
+    mut items: Vec<T>,
+    batch_size: usize,
+    scorer: &dyn UrlScorer,
+    entry: impl Fn(&T) -> &CdxEntry,
+    bucket: impl Fn(&CdxEntry) -> u32,
+) -> Vec<Vec<T>> {
+    order_by_score(&mut items, scorer, &entry);
+    let mut by_bucket = BTreeMap::<u32, Vec<T>>::new();
+    for item in items {
+        by_bucket
+            .entry(bucket(entry(&item)))
+            .or_default()
+            .push(item);
+    }
+    let mut batches = Vec::new();
+    for items in by_bucket.into_values() {
+        let mut items = items.into_iter().peekable();
+        while items.peek().is_some() {
+            batches.push(items.by_ref().take(batch_size.max(1)).collect::<Vec<_>>());
+        }
+    }
+    batches.sort_by(|a, b| {
+        let best = |batch: &[T]| scorer.score(entry(&batch[0]));
+        best(b).total_cmp(&best(a))
+    });
+    batches
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        cdx::{parse_cdx_line, CdxEntry},
+        url_score::{batches_by_score, order_by_score, DomainRankScorer},
+    };
+
+    fn entry(url: &str) -> CdxEntry {
+        parse_cdx_line(&format!(
+            r#"com,example)/ 20240723213521 {{"url": "{url}", "status": "200", "length": "100", "offset": "0", "filename": "a.warc.gz"}}"#
+        )).unwrap()
+    }
+
+    #[test]
+    fn orders_entries_by_domain_rank() {
+        let scorer = [
+            "# rank,domain",
+            "1,google.com",
+            "2,Wikipedia.org",
+            "3,google.com",
+        ]
+        .into_iter()
+        .collect::<DomainRankScorer>();
+        assert_eq!(scorer.len(), 2);
+        assert_eq!(scorer.rank("https://en.wikipedia.org/wiki/Rust"), Some(2));
+        assert_eq!(scorer.rank("https://unknown.example/"), None);
+        assert_eq!(
+            ["wikipedia.org"]
+                .into_iter()
+                .collect::<DomainRankScorer>()
+                .rank("https://wikipedia.org/"),
+            Some(1)
+        );
+
+        let mut entries = vec![
+            entry("https://unknown.example/a"),
+            entry("https://en.wikipedia.org/"),
+            entry("https://unknown.example/b"),
+            entry("https://www.google.com/"),
+        ];
+        order_by_score(&mut entries, &scorer, |entry| entry);
+        let urls = entries
+            .iter()
+            .map(|entry| entry.metadata.url.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            urls,
+            [
+                "https://www.google.com/",
+                "https://en.wikipedia.org/",
+                "https://unknown.example/a",
+                "https://unknown.example/b",
+            ]
+        );
+
+        // Wikipedia gets a bucket of its own, so it cannot share a batch with Google.
+        let bucket = |entry: &CdxEntry| entry.metadata.url.contains("wikipedia") as u32;
+        let batches = batches_by_score(entries, 2, &scorer, |entry| entry, bucket);
+        let urls = batches
+            .iter()
+            .map(|batch| {
+                batch
+                    .iter()
+                    .map(|entry| entry.metadata.url.as_str())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            urls,
+            [
+                vec!["https://www.google.com/", "https://unknown.example/a"],
+                vec!["https://en.wikipedia.org/"],
+                vec!["https://unknown.example/b"],
+            ]
+        );
+    }
+}
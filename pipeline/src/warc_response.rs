@@ -0,0 +1,267 @@
+use std::io::{BufReader, Read};
+
+use flate2::read::MultiGzDecoder;
+use serde::{Deserialize, Serialize};
+use warc::{BufferedBody, Record, WarcHeader};
+
+/// An HTTP response record from a WARC file, split into HTTP headers and payload.
+#[derive(Debug)]
+pub struct WarcResponse {
+    pub target_uri: Option<String>,
+    pub ip_address: Option<String>,
+    pub http_headers: Vec<u8>,
+    pub http_body: Vec<u8>,
+}
+
+/// A WARC record of any type with the headers the pipeline looks at.
+#[derive(Debug)]
+pub struct WarcRecord {
+    /// `WARC-Type`, e.g. `response`, `request`, `metadata` or `warcinfo`.
+    pub warc_type: String,
+    pub target_uri: Option<String>,
+    pub ip_address: Option<String>,
+    /// `Content-Length`, the length of `block`.
+    pub content_length: u64,
+    /// Everything after the WARC headers, for a response the whole HTTP message.
+    pub block: Vec<u8>,
+}
+
+impl WarcRecord {
+    fn new(record: Record<BufferedBody>) -> Self {
+        Self {
+            warc_type: record.warc_type().to_string(),
+            target_uri: record
+                .header(WarcHeader::TargetURI)
+                .map(|uri| uri.into_owned()),
+            ip_address: record
+                .header(WarcHeader::IPAddress)
+                .map(|ip| ip.into_owned()),
+            content_length: record.content_length(),
+            block: record.body().to_vec(),
+        }
+    }
+
+    /// The HTTP response of a `response` record, `None` for other records and for responses
+    /// without an HTTP body.
+    pub fn response(&self) -> Option<WarcResponse> {
+        if self.warc_type != "response" {
+            return None;
+        }
+        let (http_headers, http_body) = split_http_message(&self.block)?;
+        Some(WarcResponse {
+            target_uri: self.target_uri.clone(),
+            ip_address: self.ip_address.clone(),
+            http_headers: http_headers.to_vec(),
+            http_body: http_body.to_vec(),
+        })
+    }
+}
+
+/// Reads the records of a gzipped WARC file, e.g. a whole Common Crawl segment, one at a
+/// time, so only the current record is held in memory. Each record is its own gzip member.
+pub fn read_warc_records(
+    gzipped: impl Read,
+) -> impl Iterator<Item = Result<WarcRecord, anyhow::Error>> {
+    warc::WarcReader::new(BufReader::new(MultiGzDecoder::new(gzipped)))
+        .iter_records()
+        .map(|record| Ok(WarcRecord::new(record?)))
+}
+
+/// At most this much of a record is decompressed to find its HTTP headers.
+const MAX_PEEK_BYTES: usize = 64 << 10;
+
+/// The HTTP headers of the `response` record at the start of `gzipped`, decompressing
+/// only as much as it takes to reach their end. `None` for other records, corrupt data
+/// and headers that do not end within the first 64 KiB.
+pub fn peek_http_headers(gzipped: &[u8]) -> Option<Vec<u8>> {
+    let mut decoder = MultiGzDecoder::new(gzipped);
+    let mut data = Vec::new();
+    let mut chunk = [0; 4096];
+    while data.len() < MAX_PEEK_BYTES {
+        let n = decoder.read(&mut chunk).ok()?;
+        if n == 0 {
+            return None;
+        }
+        data.extend_from_slice(&chunk[..n]);
+        let Some((warc_headers, message)) = split_http_message(&data) else {
+            continue;
+        };
+        if http_header(warc_headers, "WARC-Type").as_deref() != Some("response") {
+            return None;
+        }
+        if let Some((http_headers, _)) = split_http_message(message) {
+            return Some(http_headers.to_vec());
+        }
+    }
+    None
+}
+
+/// Splits an HTTP message at the blank line ending its headers.
+pub fn split_http_message(message: &[u8]) -> Option<(&[u8], &[u8])> {
+    let end = message.windows(4).position(|w| w == b"\r\n\r\n")?;
+    Some((&message[..end], &message[end + 4..]))
+}
+
+/// The value of the first HTTP header called `name`, compared case-insensitively.
+pub fn http_header(http_headers: &[u8], name: &str) -> Option<String> {
+    String::from_utf8_lossy(http_headers)
+        .split("\r\n")
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim().to_string())
+}
+
+/// The response headers that are kept in the output metadata.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ResponseHeaders {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server: Option<String>,
+}
+
+impl ResponseHeaders {
+    pub fn parse(http_headers: &[u8]) -> Self {
+        Self {
+            content_type: http_header(http_headers, "Content-Type"),
+            last_modified: http_header(http_headers, "Last-Modified"),
+            server: http_header(http_headers, "Server"),
+        }
+    }
+
+    /// `Last-Modified` in seconds since the Unix epoch, if it is a valid HTTP date.
+    pub fn last_modified_unix(&self) -> Option<u64> {
+        self.last_modified.as_deref().and_then(parse_http_date)
+    }
+}
+
+/// Parses an HTTP date in the preferred format, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`,
+/// into seconds since the Unix epoch.
+pub fn parse_http_date(date: &str) -> Option<u64> {
+    let [_, day, month, year, time, "GMT"] = date.split_whitespace().collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let month = MONTHS.iter().position(|m| *m == month)? as i64 + 1;
+    let day = day.parse::<i64>().ok().filter(|d| (1..=31).contains(d))?;
+    let year = year.parse::<i64>().ok().filter(|y| *y >= 1970)?;
+    let mut hms = time.split(':').map(|part| part.parse::<i64>().ok());
+    let (hours, minutes, seconds) = (hms.next()??, hms.next()??, hms.next()??);
+    if hms.next().is_some() || hours > 23 || minutes > 59 || seconds > 60 {
+        return None;
+    }
+    // Days since the epoch of a proleptic Gregorian date, after Howard Hinnant.
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    u64::try_from(days * 86400 + hours * 3600 + minutes * 60 + seconds).ok()
+}
+
+/// Reads all `response` records from uncompressed WARC data, skipping other record types
+/// and responses without an HTTP body. Never panics, so it can be fed arbitrary input.
+pub fn parse_warc_responses(data: &[u8]) -> Result<Vec<WarcResponse>, anyhow::Error> {
+    let mut responses = Vec::new();
+    for record in warc::WarcReader::new(data).iter_records() {
+        let record = WarcRecord::new(record?);
+        if record.warc_type != "response" {
+            continue;
+        }
+        let Some(response) = record.response() else {
+            tracing::warn!("Failed to find HTTP body in WARC entry");
+            continue;
+        };
+        responses.push(response);
+    }
+    Ok(responses)
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use std::io::Write;
+
+    use flate2::write::GzEncoder;
+
+    use crate::warc_response::{
+        parse_http_date, parse_warc_responses, read_warc_records, split_http_message,
+        ResponseHeaders,
+    };
+
+    const RECORD: &[u8] = b"WARC/1.0\r\nWARC-Type: response\r\nWARC-Date: 2024-07-22T12:07:56Z\r\nWARC-Record-ID: <urn:uuid:00000000-0000-0000-0000-000000000000>\r\nWARC-Target-URI: https://example.com/\r\nContent-Length: 23\r\n\r\nHTTP/1.1 200 OK\r\n\r\nbody\r\n\r\n";
+
+    #[test]
+    fn parses_response_records() {
+        let responses = parse_warc_responses(RECORD).unwrap();
+        assert_eq!(responses.len(), 1);
+        assert_eq!(
+            responses[0].target_uri.as_deref(),
+            Some("https://example.com/")
+        );
+        assert_eq!(responses[0].http_headers, b"HTTP/1.1 200 OK");
+        assert_eq!(responses[0].http_body, b"body");
+        assert_eq!(split_http_message(b"no body"), None);
+    }
+
+    #[test]
+    fn streams_records_of_gzipped_segment() {
+        let warcinfo = b"WARC/1.0\r\nWARC-Type: warcinfo\r\nWARC-Date: 2024-07-22T12:07:56Z\r\nWARC-Record-ID: <urn:uuid:00000000-0000-0000-0000-000000000001>\r\nContent-Length: 9\r\n\r\nsoftware:\r\n\r\n";
+        let mut segment = Vec::new();
+        for record in [&warcinfo[..], RECORD, RECORD] {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(record).unwrap();
+            segment.extend(encoder.finish().unwrap());
+        }
+        let records = read_warc_records(&segment[..])
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].warc_type, "warcinfo");
+        assert!(records[0].response().is_none());
+        assert_eq!(records[1].content_length, 23);
+        let response = records[2].response().unwrap();
+        assert_eq!(response.target_uri.as_deref(), Some("https://example.com/"));
+        assert_eq!(response.http_body, b"body");
+
+        assert!(read_warc_records(&segment[..segment.len() - 10]).any(|record| record.is_err()));
+    }
+
+    #[test]
+    fn reads_selected_response_headers() {
+        let headers = ResponseHeaders::parse(
+            b"HTTP/1.1 200 OK\r\ncontent-type: text/html; charset=utf-8\r\nServer: nginx\r\nLast-Modified: Sun, 06 Nov 1994 08:49:37 GMT",
+        );
+        assert_eq!(
+            headers.content_type.as_deref(),
+            Some("text/html; charset=utf-8")
+        );
+        assert_eq!(headers.server.as_deref(), Some("nginx"));
+        assert_eq!(headers.last_modified_unix(), Some(784111777));
+        assert_eq!(parse_http_date("Thu, 01 Jan 1970 00:00:00 GMT"), Some(0));
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+        assert_eq!(
+            ResponseHeaders::parse(b"HTTP/1.1 200 OK"),
+            ResponseHeaders::default()
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn parser_never_panics(data in proptest::collection::vec(any::<u8>(), 0..1024)) {
+            let _ = parse_warc_responses(&data);
+        }
+
+        #[test]
+        fn parser_never_panics_on_truncated_records(len in 0..RECORD.len()) {
+            let _ = parse_warc_responses(&RECORD[..len]);
+        }
+    }
+}
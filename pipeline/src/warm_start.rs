@@ -0,0 +1,173 @@
+use std::{
+    collections::HashSet,
+    fs,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::batch::BatchKey;
+
+/// A line of a worker's output manifest, appended once the outputs of a batch are
+/// committed. Unlike the run DB, the manifests travel with the output directory.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CompletedBatch {
+    pub batch_id: String,
+    /// `None` for batches without a key, e.g. from a URL list.
+    pub crawl: Option<String>,
+    pub shard: Option<String>,
+    pub batch_index: Option<usize>,
+}
+
+impl CompletedBatch {
+    pub fn new(batch_id: &str, key: Option<&BatchKey>) -> Self {
+        Self {
+            batch_id: batch_id.to_string(),
+            crawl: key.map(|key| key.crawl.clone()),
+            shard: key.map(|key| key.shard.clone()),
+            batch_index: key.map(|key| key.batch_index),
+        }
+    }
+
+    pub fn key(&self) -> Option<BatchKey> {
+        Some(BatchKey {
+            crawl: self.crawl.clone()?,
+            shard: self.shard.clone()?,
+            batch_index: self.batch_index?,
+        })
+    }
+}
+
+/// The manifest of worker `name` in `output_dir`. Manifests are not `.jsonl`, so readers of
+/// the documents skip them.
+pub fn manifest_path(output_dir: &Path, name: &str) -> PathBuf {
+    output_dir
+        .join("manifests")
+        .join(format!("{name}.manifest"))
+}
+
+/// All manifest lines in `output_dir`, of every worker that ever wrote there.
+pub fn read_manifests(output_dir: &Path) -> Result<Vec<CompletedBatch>, anyhow::Error> {
+    let dir = output_dir.join("manifests");
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut completed = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "manifest") {
+            continue;
+        }
+        for line in BufReader::new(fs::File::open(&path)?).lines() {
+            let line = line?;
+            // A worker that died while appending leaves a partial last line.
+            if let Ok(batch) = serde_json::from_str(&line) {
+                completed.push(batch);
+            }
+        }
+    }
+    Ok(completed)
+}
+
+/// Batches that are known to be complete when a worker starts, from the run DB and the
+/// output manifests, so a batch that is delivered again, e.g. after a mistaken requeue
+/// from the dead letter queue, is acked without writing its documents twice.
+#[derive(Debug, Default)]
+pub struct CompletedBatches {
+    keys: HashSet<BatchKey>,
+    batch_ids: HashSet<String>,
+}
+
+impl CompletedBatches {
+    pub fn len(&self) -> usize {
+        self.keys.len() + self.batch_ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty() && self.batch_ids.is_empty()
+    }
+
+    pub fn add_key(&mut self, key: BatchKey) {
+        self.keys.insert(key);
+    }
+
+    pub fn add(&mut self, batch: CompletedBatch) {
+        match batch.key() {
+            Some(key) => self.add_key(key),
+            None => {
+                self.batch_ids.insert(batch.batch_id);
+            }
+        }
+    }
+
+    /// Whether the batch with `key`, or with `batch_id` if it has no key, is complete.
+    /// Batches with a key are matched by it, since the same batch gets another ID when the
+    /// batcher runs again.
+    pub fn contains(&self, batch_id: &str, key: Option<&BatchKey>) -> bool {
+        match key {
+            Some(key) => self.keys.contains(key),
+            None => self.batch_ids.contains(batch_id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        batch::BatchKey,
+        output::{RecordWriter, WriteOptions},
+        warm_start::{manifest_path, read_manifests, CompletedBatch, CompletedBatches},
+    };
+
+    #[test]
+    fn reads_completed_batches_from_manifests() {
+        let dir = std::env::temp_dir().join(format!("pipeline-warm-{}", std::process::id()));
+        assert!(read_manifests(&dir).unwrap().is_empty());
+        let key = BatchKey {
+            crawl: "CC-MAIN-2024-30".to_string(),
+            shard: "cdx-00000.gz".to_string(),
+            batch_index: 3,
+        };
+        let path = manifest_path(&dir, "worker-1");
+        let mut manifest = RecordWriter::create(&path, &WriteOptions::default()).unwrap();
+        manifest
+            .write(&CompletedBatch::new("id-a", Some(&key)))
+            .unwrap();
+        manifest.write(&CompletedBatch::new("id-b", None)).unwrap();
+        drop(manifest);
+        std::fs::write(
+            manifest_path(&dir, "worker-2"),
+            "{\"batch_id\": \"id-c\", \"cra",
+        )
+        .unwrap();
+        // A worker-3 that restarts after dying mid-line starts on a line of its own.
+        let path = manifest_path(&dir, "worker-3");
+        std::fs::write(
+            &path,
+            "{\"batch_id\": \"id-d\"}\n{\"batch_id\": \"id-e\", \"cra",
+        )
+        .unwrap();
+        let mut manifest = RecordWriter::create(&path, &WriteOptions::default()).unwrap();
+        manifest.write(&CompletedBatch::new("id-f", None)).unwrap();
+        drop(manifest);
+
+        let mut completed = CompletedBatches::default();
+        for batch in read_manifests(&dir).unwrap() {
+            completed.add(batch);
+        }
+        assert_eq!(completed.len(), 4);
+        assert!(completed.contains("another id", Some(&key)));
+        assert!(completed.contains("id-b", None));
+        assert!(!completed.contains("id-c", None));
+        assert!(completed.contains("id-d", None));
+        assert!(!completed.contains("id-e", None));
+        assert!(completed.contains("id-f", None));
+        let next = BatchKey {
+            batch_index: 4,
+            ..key
+        };
+        assert!(!completed.contains("id-a", Some(&next)));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+
+use scraper::{Html, Selector};
+use serde::{Deserialize, Serialize};
+
+/// The hosts the `<a href>` links of a page point to, one per link. Links that do not
+/// resolve against `page_url` or are not HTTP(S) are skipped.
+pub fn outlink_hosts(html: &str, page_url: &url::Url) -> Vec<String> {
+    let document = Html::parse_document(html);
+    let links = Selector::parse("a[href]").unwrap();
+    document
+        .select(&links)
+        .filter_map(|link| page_url.join(link.value().attr("href")?.trim()).ok())
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+        .filter_map(|url| url.host_str().map(str::to_string))
+        .collect()
+}
+
+/// One edge of the host graph: how many links of the pages of `source_host` that were
+/// processed in a run point to `target_host`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct HostEdge {
+    pub source_host: String,
+    pub target_host: String,
+    pub count: i64,
+}
+
+/// Outlinks aggregated by host, a by-product of extraction that is much smaller than the
+/// link graph of the pages. Links within a host are left out. Write it out in parts with
+/// [`HostGraph::write_part`] to keep it from growing over a whole run.
+#[derive(Debug, Default)]
+pub struct HostGraph {
+    edges: HashMap<(String, String), i64>,
+}
+
+impl HostGraph {
+    pub fn len(&self) -> usize {
+        self.edges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.edges.is_empty()
+    }
+
+    pub fn add_page(&mut self, page_url: &str, html: &str) {
+        let Ok(page_url) = url::Url::parse(page_url) else {
+            return;
+        };
+        let Some(source_host) = page_url.host_str() else {
+            return;
+        };
+        for target_host in outlink_hosts(html, &page_url) {
+            if target_host != source_host {
+                *self
+                    .edges
+                    .entry((source_host.to_string(), target_host))
+                    .or_default() += 1;
+            }
+        }
+    }
+
+    /// All edges, ordered by source and target host.
+    pub fn edges(&self) -> Vec<HostEdge> {
+        let mut edges = self
+            .edges
+            .iter()
+            .map(|((source_host, target_host), count)| HostEdge {
+                source_host: source_host.clone(),
+                target_host: target_host.clone(),
+                count: *count,
+            })
+            .collect::<Vec<_>>();
+        edges.sort_by(|a, b| {
+            (&a.source_host, &a.target_host).cmp(&(&b.source_host, &b.target_host))
+        });
+        edges
+    }
+
+    /// Writes the edges counted since the last part to `<dir>/<name>-<part>.parquet`, with
+    /// the next part number after those already in `dir`, and starts counting over. The
+    /// counts of an edge add up across the parts. Returns the file, `None` without edges.
+    #[cfg(feature = "parquet")]
+    pub fn write_part(
+        &mut self,
+        dir: &std::path::Path,
+        name: &str,
+    ) -> Result<Option<std::path::PathBuf>, anyhow::Error> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        let path = dir.join(format!("{name}-{:06}.parquet", next_part(dir, name)?));
+        self.write_parquet(&path)?;
+        self.edges.clear();
+        Ok(Some(path))
+    }
+
+    /// Replaces the Parquet file at `path` with all edges so far. The file is written next
+    /// to it first, so readers never see a partial graph.
+    #[cfg(feature = "parquet")]
+    pub fn write_parquet(&self, path: &std::path::Path) -> Result<(), anyhow::Error> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let partial = path.with_extension("parquet.partial");
+        crate::parquet_output::write_parquet(&partial, &self.edges(), 1 << 20, None)?;
+        std::fs::rename(partial, path)?;
+        Ok(())
+    }
+}
+
+/// The number after the highest of the parts `<name>-<part>.parquet` in `dir`, so a
+/// restarted worker does not overwrite the parts of its earlier runs.
+#[cfg(feature = "parquet")]
+fn next_part(dir: &std::path::Path, name: &str) -> Result<usize, anyhow::Error> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    let mut next = 0;
+    for entry in entries {
+        let file_name = entry?.file_name();
+        let part = file_name
+            .to_str()
+            .and_then(|file_name| file_name.strip_prefix(name)?.strip_prefix('-'))
+            .and_then(|rest| rest.strip_suffix(".parquet")?.parse::<usize>().ok());
+        if let Some(part) = part {
+            next = next.max(part + 1);
+        }
+    }
+    Ok(next)
+}
+
+#[cfg(feature = "parquet")]
+impl crate::parquet_output::ParquetRecord for HostEdge {
+    const SCHEMA: &'static str = "
+        message host_edge {
+            OPTIONAL BYTE_ARRAY source_host (UTF8);
+            OPTIONAL BYTE_ARRAY target_host (UTF8);
+            OPTIONAL INT64 count;
+        }
+    ";
+
+    fn to_columns(records: &[Self]) -> Vec<crate::parquet_output::ColumnValues> {
+        use crate::parquet_output::ColumnValues;
+
+        vec![
+            ColumnValues::Utf8(
+                records
+                    .iter()
+                    .map(|r| Some(r.source_host.clone()))
+                    .collect(),
+            ),
+            ColumnValues::Utf8(
+                records
+                    .iter()
+                    .map(|r| Some(r.target_host.clone()))
+                    .collect(),
+            ),
+            ColumnValues::Int64(records.iter().map(|r| Some(r.count)).collect()),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::web_graph::{HostEdge, HostGraph};
+
+    #[test]
+    fn counts_links_between_hosts() {
+        let mut graph = HostGraph::default();
+        graph.add_page(
+            "https://blog.example.com/post",
+            r#"<a href="/about">About</a> <a href="https://www.rust-lang.org/learn">Rust</a>
+            <a href="//docs.rs/serde">serde</a> <a href="mailto:me@example.com">Mail</a>
+            <a href="https://www.rust-lang.org/">Rust again</a> <a href="http://[invalid">x</a>"#,
+        );
+        graph.add_page(
+            "https://news.example.org/",
+            r#"<a href="https://docs.rs/">docs</a>"#,
+        );
+        graph.add_page("not a url", r#"<a href="https://docs.rs/">docs</a>"#);
+        let edge = |source: &str, target: &str, count| HostEdge {
+            source_host: source.to_string(),
+            target_host: target.to_string(),
+            count,
+        };
+        assert_eq!(
+            graph.edges(),
+            [
+                edge("blog.example.com", "docs.rs", 1),
+                edge("blog.example.com", "www.rust-lang.org", 2),
+                edge("news.example.org", "docs.rs", 1),
+            ]
+        );
+
+        #[cfg(feature = "parquet")]
+        {
+            use parquet::file::reader::{FileReader, SerializedFileReader};
+
+            let dir =
+                std::env::temp_dir().join(format!("pipeline-web-graph-{}", std::process::id()));
+            let path = dir.join("run.parquet");
+            graph.write_parquet(&path).unwrap();
+            graph.write_parquet(&path).unwrap();
+            let reader = SerializedFileReader::new(std::fs::File::open(&path).unwrap()).unwrap();
+            assert_eq!(reader.metadata().file_metadata().num_rows(), 3);
+
+            // Parts go on after those of an earlier process and leave the graph empty.
+            std::fs::write(dir.join("run-worker-000004.parquet"), b"").unwrap();
+            let part = graph.write_part(&dir, "run-worker").unwrap().unwrap();
+            assert_eq!(part, dir.join("run-worker-000005.parquet"));
+            assert!(graph.is_empty());
+            assert_eq!(graph.write_part(&dir, "run-worker").unwrap(), None);
+            graph.add_page(
+                "https://a.example/",
+                r#"<a href="https://b.example/">b</a>"#,
+            );
+            let part = graph.write_part(&dir, "run-worker").unwrap().unwrap();
+            assert_eq!(part, dir.join("run-worker-000006.parquet"));
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+}